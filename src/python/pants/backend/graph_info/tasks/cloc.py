@@ -79,6 +79,12 @@ class CountLinesOfCode(ConsoleTask):
       directory_digest,
       ('ignored', 'report'),
       (),
+      (),
+      0,
+      0,
+      True,
+      '',
+      '',
       15 * 60,
       'cloc'
     )
@@ -69,6 +69,11 @@ class JavacCompile(JvmCompile):
   @classmethod
   def register_options(cls, register):
     super(JavacCompile, cls).register_options(register)
+    register('--hermetic-replace-classes-dir', type=bool, advanced=True, default=True,
+             help='When compiling hermetically, replace the classes directory of each target with '
+                  'the outputs of its compile as a whole, so that an interrupted run never leaves '
+                  'a partially written classes directory. Otherwise, the outputs are written into '
+                  'the existing classes directory.')
 
   @classmethod
   def subsystem_dependencies(cls):
@@ -227,4 +232,4 @@ class JavacCompile(JvmCompile):
     classes_directory = ctx.classes_dir
     self.context._scheduler.materialize_directories((
       DirectoryToMaterialize(text_type(classes_directory), exec_result.output_directory_digest),
-    ))
+    ), replace=self.get_options().hermetic_replace_classes_dir)
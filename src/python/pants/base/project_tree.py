@@ -210,11 +210,24 @@ class Stat(AbstractClass):
     """:returns: The string path for this Stat."""
 
 
-class File(datatype(['path']), Stat):
-  """A file."""
+class File(datatype(['path', 'size']), Stat):
+  """A file.
 
-  def __new__(cls, path):
-    return super(File, cls).__new__(cls, six.binary_type(path))
+  :param size: The size of the file in bytes as of when it was stat'd, if known. It is only a
+    hint, and so is not part of the identity of the File.
+  """
+
+  def __new__(cls, path, size=None):
+    return super(File, cls).__new__(cls, six.binary_type(path), size)
+
+  def __eq__(self, other):
+    return type(self) == type(other) and self.path == other.path
+
+  def __ne__(self, other):
+    return not (self == other)
+
+  def __hash__(self):
+    return hash((type(self), self.path))
 
 
 class Dir(datatype(['path']), Stat):
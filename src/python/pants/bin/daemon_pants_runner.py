@@ -189,7 +189,13 @@ class DaemonPantsRunner(ProcessManager):
   def run(self):
     """Fork, daemonize and invoke self.post_fork_child() (via ProcessManager)."""
     with self._fork_lock:
-      self.daemonize(write_pid=False)
+      try:
+        self.daemonize(write_pid=False)
+      finally:
+        # Only pantsd itself returns from `daemonize`: resume any engine services (such as the query
+        # server) which `pre_fork` stopped.
+        if self._graph_helper:
+          self._graph_helper.scheduler_session.post_fork_in_parent()
 
   def pre_fork(self):
     """Pre-fork callback executed via ProcessManager.daemonize().
@@ -203,6 +209,15 @@ class DaemonPantsRunner(ProcessManager):
 
   def post_fork_child(self):
     """Post-fork child process callback executed via ProcessManager.daemonize()."""
+    # Discard any engine resources which were re-created by other threads of pantsd between
+    # `pre_fork` and the fork, before anything else can use them. If that fails, the engine is
+    # unusable in this process, so report the failure to the client and exit.
+    if self._graph_helper:
+      try:
+        self._graph_helper.scheduler_session.post_fork_in_child()
+      except Exception as e:
+        self._exiter.exit(1, msg='Failed to initialize the engine after forking: {}\n'.format(e))
+
     # Set the Exiter exception hook post-fork so as not to affect the pantsd processes exception
     # hook with socket-specific behavior. Note that this intentionally points the faulthandler
     # trace stream to sys.stderr, which at this point is still a _LoggerStream object writing to
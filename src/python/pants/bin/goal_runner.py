@@ -80,6 +80,9 @@ class GoalRunnerFactory(object):
     if not graph_helper:
       native = Native.create(self._global_options)
       native.set_panic_handler()
+      native.set_graph_dump_signal_handler()
+      native.set_cpu_profile_signal_handler()
+      native.set_module_log_levels(self._global_options.native_engine_log_levels)
       graph_scheduler_helper = EngineInitializer.setup_legacy_graph(native,
                                                                     self._global_options,
                                                                     self._build_config)
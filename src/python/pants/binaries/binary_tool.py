@@ -11,7 +11,7 @@ from builtins import str
 from future.utils import text_type
 
 from pants.binaries.binary_util import BinaryRequest, BinaryUtil
-from pants.engine.fs import PathGlobs, PathGlobsAndRoot
+from pants.engine.fs import PathGlobs, PathGlobsAndRoot, Snapshot
 from pants.fs.archive import XZCompressedTarArchiver, create_archiver
 from pants.subsystem.subsystem import Subsystem
 from pants.util.memo import memoized_method, memoized_property
@@ -115,6 +115,15 @@ class BinaryToolBase(Subsystem):
       version_registration_kwargs['fingerprint'] = True
     register('--version', **version_registration_kwargs)
 
+    register('--sha256', type=str, default=None, advanced=True, fingerprint=True,
+             help='The expected sha256 of the {} for the configured --version (and the current '
+                  'platform), used to verify it when it is fetched by the engine.'
+                  .format('archive' if cls.archive_type else 'binary'))
+    register('--size-bytes', type=int, default=None, advanced=True, fingerprint=True,
+             help='The expected size in bytes of the {} for the configured --version (and the '
+                  'current platform), used to verify it when it is fetched by the engine.'
+                  .format('archive' if cls.archive_type else 'binary'))
+
   @memoized_method
   def select(self, context=None):
     """Returns the path to the specified binary tool.
@@ -179,6 +188,32 @@ class BinaryToolBase(Subsystem):
     binary_request = self._make_binary_request(version)
     return self._binary_util.select(binary_request)
 
+  class DigestNotConfigured(ValueError): pass
+
+  @memoized_method
+  def snapshot(self, scheduler, context=None):
+    """Fetches the tool with the engine, and returns a Snapshot of it.
+
+    The Snapshot contains either the tool itself (as an executable file named after the tool), or
+    the extracted contents of its archive. Unlike `select`, the download is verified using the
+    --sha256 and --size-bytes options, which must be set.
+
+    See `select` for the use of `context`.
+
+    :API: public
+    """
+    options = self.get_options()
+    if options.sha256 is None or options.size_bytes is None:
+      raise self.DigestNotConfigured(
+        'Both --sha256 and --size-bytes must be set in scope {} to fetch {} with the engine.'
+        .format(self.options_scope, self._get_name()))
+    binary_tool_to_fetch = self._binary_util.binary_tool_to_fetch(
+      self._make_binary_request(self.version(context)),
+      self.archive_type,
+      options.sha256,
+      options.size_bytes)
+    return scheduler.product_request(Snapshot, [binary_tool_to_fetch])[0]
+
 
 class NativeTool(BinaryToolBase):
   """A base class for native-code tools.
@@ -15,6 +15,7 @@ from twitter.common.collections import OrderedSet
 
 from pants.base.build_environment import get_buildroot
 from pants.base.exceptions import TaskError
+from pants.engine.fs import BinaryToolToFetch
 from pants.net.http.fetcher import Fetcher
 from pants.subsystem.subsystem import Subsystem
 from pants.util.contextutil import temporary_file
@@ -175,6 +176,10 @@ class BinaryToolFetcher(object):
     self._fetcher = fetcher or self._default_http_fetcher()
     self._ignore_cached_download = ignore_cached_download
 
+  @property
+  def timeout_secs(self):
+    return self._timeout_secs
+
   class BinaryNotFound(TaskError):
 
     def __init__(self, name, accumulated_errors):
@@ -414,6 +419,36 @@ class BinaryUtil(object):
       archiver.extract(downloaded_file, unpacked_dirname, concurrency_safe=True)
     return unpacked_dirname
 
+  def binary_tool_to_fetch(self, binary_request, archive_type, fingerprint, size_bytes):
+    """Creates a request for the engine to fetch a binary, and verify that it has the given digest.
+
+    Unlike `select`, this does not download anything: the result should be requested as a Snapshot.
+
+    :param binary_request: The binary to fetch.
+    :param string archive_type: The archive type of the binary, or None if it is not an archive.
+    :param string fingerprint: The expected sha256 of the downloaded file, as a hex string.
+    :param int size_bytes: The expected size of the downloaded file.
+    :rtype: :class:`pants.engine.fs.BinaryToolToFetch`
+    """
+    try:
+      host_platform = self._host_platform()
+      url_generator = self._get_url_generator(binary_request)
+    except (self.MissingMachineInfo, self.NoBaseUrlsError) as e:
+      raise self.BinaryResolutionError(binary_request, e)
+
+    platform = ''
+    if binary_request.platform_dependent:
+      platform = '/'.join(host_platform.binary_path_components())
+    return BinaryToolToFetch(
+      name=binary_request.name,
+      version=binary_request.version,
+      platform=platform,
+      urls=self._get_urls(url_generator, binary_request),
+      fingerprint=fingerprint,
+      size_bytes=size_bytes,
+      archive_type=archive_type,
+      timeout_secs=self._binary_tool_fetcher.timeout_secs)
+
   def _make_deprecated_binary_request(self, supportdir, version, name):
     return BinaryRequest(
       supportdir=supportdir,
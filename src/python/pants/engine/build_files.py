@@ -18,7 +18,8 @@ from pants.build_graph.address import Address, BuildFileAddress
 from pants.build_graph.address_lookup_error import AddressLookupError
 from pants.engine.addressable import AddressableDescriptor, BuildFileAddresses
 from pants.engine.fs import DirectoryDigest, FilesContent, PathGlobs, Snapshot
-from pants.engine.mapper import AddressFamily, AddressMap, AddressMapper, ResolveError
+from pants.engine.mapper import (AddressFamily, AddressMap, AddressMapper, AddressNamesRequest,
+                                 ResolveError)
 from pants.engine.objects import Locatable, SerializableFactory, Validatable
 from pants.engine.rules import RootRule, SingletonRule, TaskRule, rule
 from pants.engine.selectors import Get, Select
@@ -329,6 +330,7 @@ def create_graph_rules(address_mapper, symbol_table):
     addresses_from_address_families,
     # Root rules representing parameters that might be provided via root subjects.
     RootRule(Address),
+    RootRule(AddressNamesRequest),
     RootRule(BuildFileAddress),
     RootRule(BuildFileAddresses),
     RootRule(Specs),
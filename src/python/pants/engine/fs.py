@@ -9,7 +9,7 @@ from future.utils import text_type
 from pants.base.project_tree import Dir, File
 from pants.engine.rules import RootRule
 from pants.option.global_options import GlobMatchErrorBehavior
-from pants.util.objects import Collection, datatype
+from pants.util.objects import Collection, Exactly, datatype
 
 
 class FileContent(datatype(['path', 'content'])):
@@ -22,6 +22,14 @@ class FileContent(datatype(['path', 'content'])):
     return repr(self)
 
 
+class FileRange(datatype([('path', text_type), ('offset', int), ('length', int)])):
+  """A request for at most `length` bytes of the file at `path`, starting at `offset`.
+
+  A FileContent can be requested for a FileRange in order to inspect a prefix (or other small part)
+  of a file without loading the entire file into memory.
+  """
+
+
 class Path(datatype(['path', 'stat'])):
   """A filesystem path, holding both its symbolic path name, and underlying canonical Stat.
 
@@ -67,6 +75,101 @@ class PathGlobsAndRoot(datatype([('path_globs', PathGlobs), ('root', text_type)]
   pass
 
 
+class PathGlobsAtRevision(datatype([('path_globs', PathGlobs), ('revision', text_type)])):
+  """PathGlobs to be matched against a revision in the git repository containing the buildroot.
+
+  Requesting a Snapshot for a PathGlobsAtRevision reads directories and files from the git object
+  database rather than the working copy, so the Snapshot reflects the state of the buildroot at
+  `revision` (which may be any tree-ish: a commit id, branch, tag, `HEAD~1`, etc), regardless of
+  what is currently checked out.
+  """
+
+
+class BinaryToolToFetch(datatype([
+    ('name', text_type),
+    ('version', text_type),
+    ('platform', text_type),
+    ('urls', tuple),
+    ('fingerprint', text_type),
+    ('size_bytes', int),
+    ('archive_type', text_type),
+    ('timeout_secs', int),
+])):
+  """A request to fetch a binary tool (or an archive containing it), which is verified and stored.
+
+  The urls (generally mirrors of one another) are tried in order, and the first whose content has
+  the given sha256 `fingerprint` and `size_bytes` is used. A tool is only downloaded if it has not
+  already been stored, and concurrent requests for the same tool share a single download.
+
+  A Snapshot can be requested for a BinaryToolToFetch: if `archive_type` is empty, it contains the
+  tool as an executable file named `name`, and otherwise it contains the extracted contents of the
+  archive (the 'tar' and 'tgz' archive types are supported).
+
+  Downloads are streamed to disk rather than into memory, and fail if connecting to a url or any
+  read from it takes longer than `timeout_secs`.
+  """
+
+  def __new__(cls, name, version, platform, urls, fingerprint, size_bytes, archive_type='',
+              timeout_secs=30):
+    return super(BinaryToolToFetch, cls).__new__(
+      cls, name, version, platform, tuple(urls), fingerprint, size_bytes, archive_type or '',
+      timeout_secs)
+
+
+class ChangedFilesRequest(datatype([('changes_since', text_type)])):
+  """A request for the files in the buildroot which have changed since a git revision.
+
+  Requesting ChangedFiles for a ChangedFilesRequest computes (using the git repository containing
+  the buildroot) the files changed on the current branch since it diverged from `changes_since`,
+  as well as any uncommitted changes, including untracked files which are not ignored.
+  """
+
+
+class ChangedFiles(datatype([('paths', tuple)])):
+  """The paths (relative to the buildroot) of files which have changed, including deleted files."""
+
+  def __new__(cls, paths):
+    return super(ChangedFiles, cls).__new__(cls, tuple(paths))
+
+
+class Digest(datatype([('hash', text_type), ('size_bytes', int)])):
+  """A digest of some content, in the form of the REAPI `Digest` proto.
+
+  The hash is the hex SHA-256 fingerprint of the content, and size_bytes is its length. The engine
+  accepts any object with these two fields (including the REAPI proto itself) where it expects a
+  digest.
+  """
+
+  @classmethod
+  def create(cls, digest):
+    """Creates a Digest from any object with REAPI `hash` and `size_bytes` fields."""
+    return cls(text_type(digest.hash), int(digest.size_bytes))
+
+
+class LazyBytes(datatype([('hash', text_type), ('size_bytes', int)])):
+  """Bytes in the store of a Scheduler, which are only loaded into Python when they are read.
+
+  The engine produces these (for example, for the outputs of processes) with a subclass which is
+  bound to the Scheduler whose store holds the bytes: see `bound_to`. Each call to `read` loads the
+  bytes again, so callers which read them more than once should hold on to the result.
+  """
+
+  _load = None
+
+  @classmethod
+  def bound_to(cls, load):
+    """Returns a subclass whose instances read their bytes using the given function.
+
+    :param load: A function from a LazyBytes to its bytes, such as `Scheduler.load_bytes`.
+    """
+    return type(cls.__name__, (cls,), {'_load': staticmethod(load)})
+
+  def read(self):
+    if self._load is None:
+      raise ValueError('{!r} is not bound to a store, and so cannot be read.'.format(self))
+    return self._load(self)
+
+
 class DirectoryDigest(datatype([('fingerprint', text_type), ('serialized_bytes_length', int)])):
   """A DirectoryDigest is an opaque handle to a set of files known about by the engine.
 
@@ -81,6 +184,27 @@ class DirectoryDigest(datatype([('fingerprint', text_type), ('serialized_bytes_l
   https://github.com/pantsbuild/pants/issues/5802
   """
 
+  @classmethod
+  def create(cls, digest):
+    """Creates a DirectoryDigest from the REAPI-form digest of a Directory.
+
+    :param digest: Any object with REAPI `hash` and `size_bytes` fields, such as a `Digest`.
+    """
+    return cls(text_type(digest.hash), int(digest.size_bytes))
+
+  @property
+  def hash(self):
+    return self.fingerprint
+
+  @property
+  def size_bytes(self):
+    return self.serialized_bytes_length
+
+  @property
+  def digest(self):
+    """This DirectoryDigest in REAPI form."""
+    return Digest(self.fingerprint, self.serialized_bytes_length)
+
   def __repr__(self):
     return '''DirectoryDigest(fingerprint={}, serialized_bytes_length={})'''.format(
       self.fingerprint[:8],
@@ -97,6 +221,10 @@ class Snapshot(datatype([('directory_digest', DirectoryDigest), ('path_stats', t
   Snapshots are used to make it easier to isolate process execution by fixing the contents
   of the files being operated on and easing their movement to and from isolated execution
   sandboxes.
+
+  The path_stats of a Snapshot are sorted by path (component by component, comparing the bytes of
+  each component), regardless of the order of the globs that matched them, or of the order in
+  which the filesystem listed them.
   """
 
   @property
@@ -120,9 +248,76 @@ class DirectoryToMaterialize(datatype([('path', text_type), ('directory_digest',
   """A request to materialize the contents of a directory digest at the provided path."""
   pass
 
+# NB: The FileContents in a FilesContent are sorted by path, in the same order as the path_stats of
+# a Snapshot.
 FilesContent = Collection.of(FileContent)
 
 
+class SourceImportsRequest(datatype([('directory_digest', DirectoryDigest),
+                                     ('language', text_type)])):
+  """A request to scan the files of a directory digest for the names that they import.
+
+  Requesting SourceImports for a SourceImportsRequest scans the files in parallel, using regular
+  expressions for the import statements of the given `language`, which may be 'python' (for
+  `import a.b` and `from a.b import c`, which are reported as `a.b`) or 'jvm' (for Java and Scala
+  `import a.b.C` statements, including static and wildcard imports).
+
+  Because scanning does not parse the files, it is approximate: import statements inside of
+  multi-line strings are reported, and imports which are computed at runtime are not.
+  """
+
+
+class SourceImports(datatype([('imports_by_path', tuple)])):
+  """The names imported by each file, as a tuple of (path, tuple of imported names) pairs."""
+
+  def __new__(cls, imports_by_path):
+    return super(SourceImports, cls).__new__(cls, tuple(imports_by_path))
+
+
+class FileSizeSummary(datatype([('directories', tuple)])):
+  """The number and total size of the files matched by some PathGlobs, per directory.
+
+  Requesting a FileSizeSummary for PathGlobs expands the globs (respecting ignore patterns) and
+  stats the matched files without reading or storing their content. `directories` is a tuple of
+  (path, file_count, total_bytes) tuples, sorted by path, for each directory which contains a
+  matched file at any depth: the counts for a directory include the files of its subdirectories,
+  and the entry for the build root has the path ''.
+  """
+
+  def __new__(cls, directories):
+    return super(FileSizeSummary, cls).__new__(cls, tuple(directories))
+
+
+class ChecksumsRequest(datatype([('directory_digest', DirectoryDigest)])):
+  """A request for a manifest of the fingerprints of the files of a directory digest.
+
+  Requesting a Snapshot for a ChecksumsRequest produces a Snapshot containing a single file named
+  `SHA256SUMS`, which lists the SHA-256 fingerprint of each file in the directory in the format of
+  `sha256sum` (and so can be verified with `sha256sum --check`). Files are listed in the same order
+  as the path_stats of a Snapshot, so the manifest is identical on every machine: diffing the
+  manifests of two digests which were expected to be equal shows which files differ.
+  """
+
+
+class DigestEqualityRequest(datatype([
+  ('expected', DirectoryDigest),
+  ('actual', Exactly(DirectoryDigest, PathGlobs)),
+])):
+  """A request to compare a directory digest against another, or against a capture of PathGlobs.
+
+  Requesting DigestEquality for a DigestEqualityRequest compares the digests without loading the
+  contents of either, which makes it a cheap way for a rule to ask "did anything change?". Capturing
+  PathGlobs is memoized like requesting a Snapshot for them is.
+  """
+
+
+class DigestEquality(datatype([('equal', bool)])):
+  """Whether the digests of a DigestEqualityRequest were equal."""
+
+  def __new__(cls, equal):
+    return super(DigestEquality, cls).__new__(cls, bool(equal))
+
+
 # TODO(cosmicexplorer): don't recreate this in python, get this from
 # fs::EMPTY_DIGEST somehow.
 _EMPTY_FINGERPRINT = 'e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855'
@@ -142,7 +337,14 @@ EMPTY_SNAPSHOT = Snapshot(
 def create_fs_rules():
   """Creates rules that consume the intrinsic filesystem types."""
   return [
+    RootRule(BinaryToolToFetch),
+    RootRule(ChangedFilesRequest),
+    RootRule(ChecksumsRequest),
+    RootRule(DigestEqualityRequest),
     RootRule(DirectoryDigest),
+    RootRule(FileRange),
     RootRule(PathGlobs),
+    RootRule(PathGlobsAtRevision),
     RootRule(Snapshot),
+    RootRule(SourceImportsRequest),
   ]
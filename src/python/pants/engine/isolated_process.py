@@ -7,8 +7,9 @@ from __future__ import absolute_import, division, print_function, unicode_litera
 import logging
 
 import six
+from future.utils import text_type
 
-from pants.engine.fs import EMPTY_SNAPSHOT, DirectoryDigest
+from pants.engine.fs import EMPTY_SNAPSHOT, Digest, DirectoryDigest, LazyBytes
 from pants.engine.rules import RootRule, rule
 from pants.engine.selectors import Select
 from pants.util.objects import Exactly, SubclassesOf, TypeCheckError, datatype
@@ -25,6 +26,28 @@ class ExecuteProcessRequest(datatype([
   ('input_files', DirectoryDigest),
   ('output_files', tuple),
   ('output_directories', tuple),
+  # Directories within the input_files which the process will not modify: these may be shared
+  # read-only between processes rather than being materialized into each sandbox.
+  ('immutable_input_directories', tuple),
+  # The maximum number of bytes of each of stdout and stderr to capture, which may only lower the
+  # limit set by --process-execution-max-output-bytes. 0 means that no further limit is applied.
+  ('max_output_bytes', int),
+  # The maximum number of bytes that the process may write into its sandbox when it runs locally,
+  # beyond which it is terminated and its execution fails. 0 means that the bytes it writes are not
+  # limited.
+  ('max_disk_bytes', int),
+  # Whether a result with a non-zero exit code may be reused by later runs. When False, failed
+  # processes are re-run in each session (and skip the remote action cache), while successful
+  # results are reused as usual: this suits processes such as tests, whose failures may be flaky.
+  ('cache_failures', bool),
+  # The container image to execute the process in remotely, which is part of the cache key. Empty
+  # means that the process may execute in any image. A process which sets an image cannot be
+  # executed locally.
+  ('container_image', SubclassesOf(*six.string_types)),
+  # The name of an environment variable to set to the execution seed of the process: a number
+  # derived from its cache key, which tools that need a random seed may use to be deterministic.
+  # Empty means that no seed is set.
+  ('execution_seed_env_var', SubclassesOf(*six.string_types)),
   # NB: timeout_seconds covers the whole remote operation including queuing and setup.
   ('timeout_seconds', Exactly(float, int)),
   ('description', SubclassesOf(*six.string_types)),
@@ -41,6 +64,12 @@ class ExecuteProcessRequest(datatype([
     output_files=(),
     output_directories=(),
     timeout_seconds=_default_timeout_seconds,
+    immutable_input_directories=(),
+    max_output_bytes=0,
+    cache_failures=True,
+    container_image='',
+    max_disk_bytes=0,
+    execution_seed_env_var='',
   ):
     if env is None:
       env = ()
@@ -54,6 +83,12 @@ class ExecuteProcessRequest(datatype([
       input_files=snapshot.directory_digest,
       output_files=output_files,
       output_directories=output_directories,
+      immutable_input_directories=immutable_input_directories,
+      max_output_bytes=max_output_bytes,
+      max_disk_bytes=max_disk_bytes,
+      cache_failures=cache_failures,
+      container_image=container_image,
+      execution_seed_env_var=execution_seed_env_var,
       timeout_seconds=timeout_seconds,
       description=description,
     )
@@ -67,6 +102,12 @@ class ExecuteProcessRequest(datatype([
     output_files=(),
     output_directories=(),
     timeout_seconds=_default_timeout_seconds,
+    immutable_input_directories=(),
+    max_output_bytes=0,
+    cache_failures=True,
+    container_image='',
+    max_disk_bytes=0,
+    execution_seed_env_var='',
   ):
     return cls.create_from_snapshot(
       argv,
@@ -76,6 +117,12 @@ class ExecuteProcessRequest(datatype([
       output_files,
       output_directories,
       timeout_seconds,
+      immutable_input_directories,
+      max_output_bytes,
+      cache_failures,
+      container_image,
+      max_disk_bytes,
+      execution_seed_env_var,
     )
 
   @classmethod
@@ -90,16 +137,134 @@ class ExecuteProcessRequest(datatype([
       )
 
 
-class ExecuteProcessResult(datatype(['stdout', 'stderr', 'output_directory_digest'])):
+class ExecuteProcessRequestDigests(datatype([
+  ('action_digest', Digest),
+  ('command_digest', Digest),
+])):
+  """The digests of the remote execution Action and Command that an ExecuteProcessRequest maps to.
+
+  The action digest is the key under which a remote cache stores the result of a process, so
+  comparing it against the contents of the cache is useful when diagnosing unexpected cache misses.
+  """
+
+
+class FakeProcessInvocation(datatype([
+  ('argv', tuple),
+  ('description', text_type),
+  ('action_digest', Digest),
+])):
+  """A request that a Scheduler created with `fake_command_runner=True` was asked to execute.
+
+  Requests are identified by their action digest: see `ExecuteProcessRequestDigests`.
+  """
+
+
+class Platform(datatype([('os', text_type), ('arch', text_type), ('libc', text_type)])):
+  """The platform that processes are executed on locally.
+
+  This is computed by the engine, and may be selected by any rule, which allows rules to key
+  per-platform caches consistently rather than consulting the python `platform` module. The os is
+  normalized as by `pants.util.osutil.normalize_os_name` (for example 'linux' or 'darwin'), and
+  libc is one of 'glibc', 'musl' or 'system'.
+  """
+
+
+class EnvironmentRequest(datatype([('names', tuple)])):
+  """A request for the values of some variables in the environment of the engine.
+
+  Rules must not read `os.environ` directly, because the engine cannot tell when the environment
+  changes, and so would reuse their memoized results (for example, in a daemon whose environment
+  has changed). Instead, a rule declares the variables that it needs by requesting an Environment
+  for an EnvironmentRequest, which the engine re-reads for each session: rules which depend on it
+  are only re-run if the values of the variables that they declared changed.
+  """
+
+  def __new__(cls, names):
+    return super(EnvironmentRequest, cls).__new__(cls, tuple(names))
+
+
+class Environment(datatype([('entries', tuple)])):
+  """The (name, value) pairs of the requested variables which were set, in the requested order."""
+
+  def __new__(cls, entries):
+    return super(Environment, cls).__new__(cls, tuple(tuple(entry) for entry in entries))
+
+  def get(self, name, default=None):
+    return self.as_dict().get(name, default)
+
+  def as_dict(self):
+    return dict(self.entries)
+
+
+class _ProcessOutputs(object):
+  """Reads the stdout and stderr of a process result, which may be LazyBytes.
+
+  The engine constructs results with LazyBytes for large outputs (and with bytes for small ones), so
+  that large outputs are only copied into python if they are read: the `stdout` and `stderr`
+  properties read them (again) on each access, while `stored_stdout` and `stored_stderr` return
+  them as they were constructed.
+  """
+
+  @staticmethod
+  def _read(output):
+    return output.read() if isinstance(output, LazyBytes) else output
+
+  @property
+  def stored_stdout(self):
+    return super(_ProcessOutputs, self).stdout
+
+  @property
+  def stored_stderr(self):
+    return super(_ProcessOutputs, self).stderr
+
+  @property
+  def stdout(self):
+    return self._read(self.stored_stdout)
+
+  @property
+  def stderr(self):
+    return self._read(self.stored_stderr)
+
+
+class ExecuteProcessResult(_ProcessOutputs,
+                           datatype(['stdout', 'stderr', 'output_directory_digest',
+                                     'stdout_overflowed', 'stderr_overflowed', 'source'])):
   """Result of successfully executing a process.
 
-  Requesting one of these will raise an exception if the exit code is non-zero."""
+  Requesting one of these will raise an exception if the exit code is non-zero. See
+  `FallibleExecuteProcessResult` for the meaning of `source`."""
 
+  def __new__(cls, stdout, stderr, output_directory_digest, stdout_overflowed=False,
+              stderr_overflowed=False, source=None):
+    return super(ExecuteProcessResult, cls).__new__(cls, stdout, stderr, output_directory_digest,
+                                                    stdout_overflowed, stderr_overflowed, source)
 
-class FallibleExecuteProcessResult(datatype(['stdout', 'stderr', 'exit_code', 'output_directory_digest'])):
+
+class FallibleExecuteProcessResult(_ProcessOutputs,
+                                   datatype(['stdout', 'stderr', 'exit_code',
+                                             'output_directory_digest',
+                                             'stdout_overflowed', 'stderr_overflowed',
+                                             'source'])):
   """Result of executing a process.
 
-  Requesting one of these will not raise an exception if the exit code is non-zero."""
+  Requesting one of these will not raise an exception if the exit code is non-zero.
+
+  If `stdout_overflowed` or `stderr_overflowed` is True, that output exceeded the limit on how much
+  of it is captured, and was truncated (see `--process-execution-max-output-bytes`).
+
+  `source` is where the engine got the result from when it was computed: one of
+  'executed_locally', 'executed_remotely', 'remote_cache' (the action cache of the remote execution
+  service), 'local_cache' (a replayed archive of results) or 'fake'. The engine memoizes results,
+  so a result which is requested again keeps the source that it was computed from: the
+  `process_results.*` metrics of a run count how many requests were memoized instead.
+  """
+
+  def __new__(cls, stdout, stderr, exit_code, output_directory_digest, stdout_overflowed=False,
+              stderr_overflowed=False, source=None):
+    return super(FallibleExecuteProcessResult, cls).__new__(cls, stdout, stderr, exit_code,
+                                                            output_directory_digest,
+                                                            stdout_overflowed, stderr_overflowed,
+                                                            source)
 
 
 class ProcessExecutionFailure(Exception):
@@ -133,9 +298,12 @@ def fallible_to_exec_result_or_raise(fallible_result, request):
 
   if fallible_result.exit_code == 0:
     return ExecuteProcessResult(
-      fallible_result.stdout,
-      fallible_result.stderr,
-      fallible_result.output_directory_digest
+      fallible_result.stored_stdout,
+      fallible_result.stored_stderr,
+      fallible_result.output_directory_digest,
+      fallible_result.stdout_overflowed,
+      fallible_result.stderr_overflowed,
+      fallible_result.source,
     )
   else:
     raise ProcessExecutionFailure(
@@ -149,6 +317,7 @@ def fallible_to_exec_result_or_raise(fallible_result, request):
 def create_process_rules():
   """Creates rules that consume the intrinsic filesystem types."""
   return [
+    RootRule(EnvironmentRequest),
     RootRule(ExecuteProcessRequest),
     fallible_to_exec_result_or_raise
   ]
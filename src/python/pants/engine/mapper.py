@@ -8,6 +8,7 @@ import re
 from collections import OrderedDict
 
 from pants.build_graph.address import BuildFileAddress
+from pants.engine.fs import DirectoryDigest
 from pants.engine.objects import Serializable
 from pants.util.memo import memoized_property
 from pants.util.objects import datatype
@@ -146,6 +147,46 @@ class AddressFamily(datatype(['namespace', 'objects_by_name'])):
         self.namespace, self.objects_by_name.keys())
 
 
+class AddressNamesRequest(datatype([('directory_digest', DirectoryDigest), ('symbols', tuple)])):
+  """A request to parse the names of the targets defined by the BUILD files of a directory.
+
+  The `directory_digest` should contain the BUILD files of a single directory, and `symbols` the
+  aliases of the target types which may be used to define targets.
+
+  Rather than evaluating the BUILD files, the engine finds top-level calls to the given symbols
+  and extracts their `name` arguments, which is much faster than constructing an AddressFamily.
+  BUILD files which compute target names, or which call anything other than the given symbols at
+  the top level (such as macros which define targets), cannot be parsed this way, and cause
+  requests for AddressNames to fail with an error naming the BUILD file and the callable.
+  """
+
+  def __new__(cls, directory_digest, symbols):
+    return super(AddressNamesRequest, cls).__new__(cls, directory_digest, tuple(sorted(symbols)))
+
+
+class AddressNames(datatype(['namespace', ('names_by_path', tuple)])):
+  """The names of the targets defined in a namespace, parsed without evaluating BUILD files.
+
+  :param namespace: The namespace path of these addresses.
+  :param names_by_path: A tuple of (BUILD file path, tuple of target names) pairs.
+  """
+
+  def __new__(cls, namespace, names_by_path):
+    if namespace == b'.':
+      namespace = ''
+    return super(AddressNames, cls).__new__(cls, namespace, tuple(names_by_path))
+
+  @memoized_property
+  def addresses(self):
+    """Return the BuildFileAddresses defined in this namespace.
+
+    :rtype: list of :class:`pants.build_graph.address.BuildFileAddress`
+    """
+    return [BuildFileAddress(rel_path=path, target_name=name)
+            for path, names in self.names_by_path
+            for name in names]
+
+
 class ResolveError(MappingError):
   """Indicates an error resolving targets."""
 
@@ -7,11 +7,13 @@ from __future__ import absolute_import, division, print_function, unicode_litera
 import importlib
 import logging
 import os
+import signal
 import sys
 import sysconfig
+import time
 import traceback
 from builtins import str
-from contextlib import closing
+from contextlib import closing, contextmanager
 
 import cffi
 import pkg_resources
@@ -75,6 +77,12 @@ typedef struct {
   Handle      handle_;
 } TypeIdBuffer;
 
+typedef struct {
+  TypeConstraint*  constraints_ptr;
+  uint64_t         constraints_len;
+  Handle           handle_;
+} TypeConstraintBuffer;
+
 typedef struct {
   Buffer*     bufs_ptr;
   uint64_t    bufs_len;
@@ -104,6 +112,7 @@ typedef void                (*extern_ptr_log)(ExternContext*, uint8_t, uint8_t*,
 typedef uint8_t             extern_log_level;
 typedef Ident               (*extern_ptr_identify)(ExternContext*, Handle*);
 typedef _Bool               (*extern_ptr_equals)(ExternContext*, Handle*, Handle*);
+typedef _Bool               (*extern_ptr_truthy)(ExternContext*, Handle*);
 typedef Handle              (*extern_ptr_clone_val)(ExternContext*, Handle*);
 typedef void                (*extern_ptr_drop_handles)(ExternContext*, Handle*, uint64_t);
 typedef Buffer              (*extern_ptr_type_to_str)(ExternContext*, TypeId);
@@ -125,10 +134,9 @@ typedef void Tasks;
 typedef void Scheduler;
 typedef void Session;
 typedef void ExecutionRequest;
+typedef void QueryServer;
 
 typedef struct {
-  Key             subject;
-  TypeConstraint  product;
   uint8_t         state_tag;
   Handle          state_value;
 } RawNode;
@@ -150,6 +158,7 @@ void externs_set(ExternContext*,
                  extern_ptr_eval,
                  extern_ptr_identify,
                  extern_ptr_equals,
+                 extern_ptr_truthy,
                  extern_ptr_clone_val,
                  extern_ptr_drop_handles,
                  extern_ptr_type_to_str,
@@ -163,18 +172,22 @@ void externs_set(ExternContext*,
                  extern_ptr_project_ignoring_type,
                  extern_ptr_project_multi,
                  extern_ptr_create_exception,
-                 TypeId);
+                 TypeId,
+                 Handle,
+                 Handle);
 
 Key key_for(Handle);
 Handle val_for(Key);
 
 Tasks* tasks_create(void);
-void tasks_task_begin(Tasks*, Function, TypeConstraint);
+void tasks_task_begin(Tasks*, Function, TypeConstraint, _Bool, _Bool, Buffer, Buffer);
 void tasks_add_get(Tasks*, TypeConstraint, TypeId);
 void tasks_add_select(Tasks*, TypeConstraint);
 void tasks_add_select_variant(Tasks*, TypeConstraint, Buffer);
 void tasks_task_end(Tasks*);
 void tasks_singleton_add(Tasks*, Handle, TypeConstraint);
+void tasks_plugin_intrinsic_add(Tasks*, Buffer, TypeConstraint, TypeConstraint, Function);
+PyResult tasks_load_plugins(Tasks*, BufferBuffer);
 void tasks_destroy(Tasks*);
 
 Scheduler* scheduler_create(Tasks*,
@@ -187,6 +200,31 @@ Scheduler* scheduler_create(Tasks*,
                             Function,
                             Function,
                             Function,
+                            Function,
+                            Function,
+                            Function,
+                            Function,
+                            Function,
+                            Function,
+                            Function,
+                            Function,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
+                            TypeConstraint,
                             TypeConstraint,
                             TypeConstraint,
                             TypeConstraint,
@@ -212,31 +250,96 @@ Scheduler* scheduler_create(Tasks*,
                             uint64_t,
                             uint64_t,
                             uint64_t,
+                            Buffer,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            _Bool,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            Buffer,
+                            BufferBuffer,
+                            _Bool,
+                            BufferBuffer,
+                            Buffer,
+                            Buffer,
+                            Buffer,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            double,
+                            uint64_t,
+                            _Bool,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            uint64_t,
+                            Buffer,
+                            Buffer,
+                            Buffer,
+                            Buffer,
+                            _Bool,
+                            Buffer,
                             _Bool);
+void scheduler_terminate_processes(Scheduler*);
+Handle scheduler_shutdown(Scheduler*, uint64_t);
+Handle scheduler_trim_memory(Scheduler*, uint64_t);
+Handle scheduler_snapshot_cache_generation(Scheduler*);
+void scheduler_set_snapshot_cache_generation(Scheduler*, Buffer);
 void scheduler_pre_fork(Scheduler*);
+void scheduler_post_fork_in_parent(Scheduler*);
+PyResult scheduler_post_fork_in_child(Scheduler*);
+Handle constructor_schemas(void);
 Handle scheduler_metrics(Scheduler*, Session*);
+Handle scheduler_heap_usage(Scheduler*);
+Handle scheduler_workunit_events(Scheduler*);
+PyResult scheduler_fake_process_result(Scheduler*, Handle, Buffer, Buffer, int32_t, Handle);
+PyResult scheduler_fake_process_invocations(Scheduler*);
 RawNodes* scheduler_execute(Scheduler*, Session*, ExecutionRequest*);
+RawNodes* scheduler_execute_streaming(Scheduler*, Session*, ExecutionRequest*, Handle);
 void scheduler_destroy(Scheduler*);
 
-Session* session_create(Scheduler*);
+Session* session_create(Scheduler*, _Bool, uint64_t);
 void session_destroy(Session*);
 
 ExecutionRequest* execution_request_create(void);
 void execution_request_destroy(ExecutionRequest*);
 
+QueryServer* query_server_create(Scheduler*, Handle);
+PyResult query_server_start(QueryServer*, Buffer);
+void query_server_stop(QueryServer*);
+void query_server_pre_fork(QueryServer*);
+PyResult query_server_post_fork_in_parent(QueryServer*);
+PyResult query_server_post_fork_in_child(QueryServer*);
+void query_server_destroy(QueryServer*);
+
 uint64_t graph_len(Scheduler*);
 uint64_t graph_invalidate(Scheduler*, BufferBuffer);
+uint64_t graph_invalidate_prefixes(Scheduler*, BufferBuffer);
 uint64_t graph_invalidate_all_paths(Scheduler*);
-PyResult graph_visualize(Scheduler*, Session*, char*);
-void graph_trace(Scheduler*, ExecutionRequest*, char*);
+uint64_t graph_invalidate_all(Scheduler*);
+PyResult graph_visualize(Scheduler*, Session*, char*, uint64_t);
+void graph_trace(Scheduler*, ExecutionRequest*, char*, uint64_t, BufferBuffer, _Bool);
 
 PyResult  execution_add_root_select(Scheduler*, ExecutionRequest*, Key, TypeConstraint);
+PyResult  execution_add_root_selects(Scheduler*, ExecutionRequest*, Handle, TypeConstraint);
+PyResult  execution_add_root_select_multiple(Scheduler*,
+                                             ExecutionRequest*,
+                                             Key,
+                                             TypeConstraintBuffer);
 
 PyResult capture_snapshots(Scheduler*, Handle);
 
 PyResult merge_directories(Scheduler*, Handle);
 
 PyResult materialize_directories(Scheduler*, Handle);
+PyResult replace_directories(Scheduler*, Handle);
+
+PyResult execute_process_request_digests(Handle);
+PyResult explain_process_request_diff(Buffer, Buffer, Buffer, Buffer);
 
 PyResult validator_run(Scheduler*);
 
@@ -245,11 +348,26 @@ void rule_subgraph_visualize(Scheduler*, TypeId, TypeConstraint, char*);
 
 void nodes_destroy(RawNodes*);
 
-void set_panic_handler(void);
+PyResult set_panic_handler(Buffer);
+
+PyResult set_thread_name(Buffer);
+
+PyResult graph_dump_all(Buffer);
+
+PyResult cpu_profile_start(uint64_t);
+PyResult cpu_profile_stop(Buffer);
+
+PyResult set_module_log_level(Buffer, uint8_t);
+
+PyResult init_log_file(Buffer, uint64_t, uint64_t, uint64_t, uint8_t);
 
 void lease_files_in_graph(Scheduler*);
+PyResult lease_digests(Scheduler*, Handle, uint64_t);
+PyResult release_digests(Scheduler*, Handle);
+PyResult scheduler_load_bytes(Scheduler*, Handle);
 
 void garbage_collect_store(Scheduler*);
+PyResult scheduler_flush_uploads(Scheduler*);
 '''
 
 CFFI_EXTERNS = '''
@@ -260,6 +378,7 @@ extern "Python" {
   PyResult            extern_eval(ExternContext*, uint8_t*, uint64_t);
   Ident               extern_identify(ExternContext*, Handle*);
   _Bool               extern_equals(ExternContext*, Handle*, Handle*);
+  _Bool               extern_truthy(ExternContext*, Handle*);
   Handle              extern_clone_val(ExternContext*, Handle*);
   void                extern_drop_handles(ExternContext*, Handle*, uint64_t);
   Buffer              extern_type_to_str(ExternContext*, TypeId);
@@ -379,6 +498,11 @@ def _initialize_externs(ffi):
     """Return true if the given Handles are __eq__."""
     return ffi.from_handle(val1[0]) == ffi.from_handle(val2[0])
 
+  @ffi.def_extern()
+  def extern_truthy(context_handle, val):
+    """Return the truthiness of the given Handle."""
+    return bool(ffi.from_handle(val[0]))
+
   @ffi.def_extern()
   def extern_clone_val(context_handle, val):
     """Clone the given Handle."""
@@ -581,6 +705,10 @@ class ExternContext(object):
     buf = self._ffi.new('TypeId[]', types)
     return (buf, len(types), self.to_value(buf))
 
+  def type_constraints_buf(self, constraints):
+    buf = self._ffi.new('TypeConstraint[]', constraints)
+    return (buf, len(constraints), self.to_value(buf))
+
   def to_value(self, obj):
     handle = self._ffi.new_handle(obj)
     self._handles.add(handle)
@@ -623,19 +751,38 @@ class Native(object):
   @staticmethod
   def create(bootstrap_options):
     """:param options: Any object that provides access to bootstrap option values."""
-    return Native(bootstrap_options.native_engine_visualize_to)
-
-  def __init__(self, visualize_to_dir):
+    native = Native(bootstrap_options.native_engine_visualize_to,
+                    bootstrap_options.native_engine_visualize_min_duration_ms,
+                    os.path.join(bootstrap_options.pants_workdir, 'native-engine-crashes'))
+    native.init_log_file(os.path.join(bootstrap_options.pants_workdir, 'native-engine.log'),
+                         bootstrap_options.native_engine_log_file_max_bytes,
+                         bootstrap_options.native_engine_log_file_max_age_seconds,
+                         bootstrap_options.native_engine_log_file_count,
+                         bootstrap_options.native_engine_log_file_level)
+    return native
+
+  def __init__(self, visualize_to_dir, visualize_min_duration_ms=0, crash_report_dir=None):
     """
     :param visualize_to_dir: An existing directory (or None) to visualize executions to.
+    :param visualize_min_duration_ms: Nodes that ran for less than this many milliseconds are
+      omitted from visualizations.
+    :param crash_report_dir: A directory (or None) to write a crash report to for each panic in the
+      native engine.
     """
     # TODO: This should likely be a per-session property... ie, not a bootstrap option.
     self._visualize_to_dir = visualize_to_dir
+    self._visualize_min_duration_ms = visualize_min_duration_ms
+    self._crash_report_dir = crash_report_dir
+    self._module_log_levels = {}
 
   @property
   def visualize_to_dir(self):
     return self._visualize_to_dir
 
+  @property
+  def visualize_min_duration_ms(self):
+    return self._visualize_min_duration_ms
+
   @memoized_property
   def binary(self):
     """Load and return the path to the native engine binary."""
@@ -690,6 +837,7 @@ class Native(object):
                            self.ffi_lib.extern_eval,
                            self.ffi_lib.extern_identify,
                            self.ffi_lib.extern_equals,
+                           self.ffi_lib.extern_truthy,
                            self.ffi_lib.extern_clone_val,
                            self.ffi_lib.extern_drop_handles,
                            self.ffi_lib.extern_type_to_str,
@@ -703,7 +851,9 @@ class Native(object):
                            self.ffi_lib.extern_project_ignoring_type,
                            self.ffi_lib.extern_project_multi,
                            self.ffi_lib.extern_create_exception,
-                           TypeId(context.to_id(str)))
+                           TypeId(context.to_id(str)),
+                           context.to_value(True),
+                           context.to_value(False))
       return context
 
     return self.ffi.init_once(init_externs, 'ExternContext singleton')
@@ -734,8 +884,14 @@ class Native(object):
   def new_execution_request(self):
     return self.gc(self.lib.execution_request_create(), self.lib.execution_request_destroy)
 
-  def new_session(self, scheduler):
-    return self.gc(self.lib.session_create(scheduler), self.lib.session_destroy)
+  def new_session(self, scheduler, background=False, timeout_seconds=None):
+    # We can't currently pass Options to the rust side, so we pass 0 for None.
+    return self.gc(self.lib.session_create(scheduler, background, timeout_seconds or 0),
+                   self.lib.session_destroy)
+
+  def new_query_server(self, scheduler, handler):
+    return self.gc(self.lib.query_server_create(scheduler, handler),
+                   self.lib.query_server_destroy)
 
   def new_scheduler(self,
                     tasks,
@@ -753,20 +909,70 @@ class Native(object):
                     construct_file,
                     construct_link,
                     construct_process_result,
+                    construct_lazy_bytes,
+                    construct_changed_files,
+                    construct_address_names,
+                    construct_source_imports,
+                    construct_file_size_summary,
+                    construct_environment,
+                    construct_digest_equality,
+                    construct_platform,
                     constraint_has_products,
                     constraint_address,
                     constraint_variants,
                     constraint_path_globs,
                     constraint_directory_digest,
                     constraint_snapshot,
+                    constraint_file_content,
                     constraint_files_content,
+                    constraint_file_range,
+                    constraint_binary_tool_to_fetch,
+                    constraint_changed_files_request,
+                    constraint_changed_files,
+                    constraint_path_globs_at_revision,
+                    constraint_address_names_request,
+                    constraint_address_names,
+                    constraint_source_imports_request,
+                    constraint_source_imports,
+                    constraint_file_size_summary,
+                    constraint_environment_request,
+                    constraint_environment,
+                    constraint_checksums_request,
+                    constraint_digest_equality_request,
+                    constraint_digest_equality,
+                    constraint_platform,
                     constraint_dir,
                     constraint_file,
                     constraint_link,
                     constraint_process_request,
                     constraint_process_result,
-                    constraint_generator):
-    """Create and return an ExternContext and native Scheduler."""
+                    constraint_generator,
+                    fake_command_runner=False):
+    """Create and return an ExternContext and native Scheduler.
+
+    :param fake_command_runner: True to run processes with a fake that responds with canned results
+      (see `Scheduler.fake_process_result`), rather than executing them.
+    """
+
+    self._validate_constructors({
+      'construct_directory_digest': construct_directory_digest,
+      'construct_snapshot': construct_snapshot,
+      'construct_file_content': construct_file_content,
+      'construct_files_content': construct_files_content,
+      'construct_path_stat': construct_path_stat,
+      'construct_dir': construct_dir,
+      'construct_file': construct_file,
+      'construct_link': construct_link,
+      'construct_process_result': construct_process_result,
+      'construct_lazy_bytes': construct_lazy_bytes,
+      'construct_changed_files': construct_changed_files,
+      'construct_address_names': construct_address_names,
+      'construct_source_imports': construct_source_imports,
+      'construct_file_size_summary': construct_file_size_summary,
+      'construct_environment': construct_environment,
+      'construct_digest_equality': construct_digest_equality,
+      'construct_platform': construct_platform,
+    })
 
     def func(constraint):
       return Function(self.context.to_key(constraint))
@@ -785,6 +991,14 @@ class Native(object):
         func(construct_file),
         func(construct_link),
         func(construct_process_result),
+        func(construct_lazy_bytes),
+        func(construct_changed_files),
+        func(construct_address_names),
+        func(construct_source_imports),
+        func(construct_file_size_summary),
+        func(construct_environment),
+        func(construct_digest_equality),
+        func(construct_platform),
         # TypeConstraints.
         tc(constraint_address),
         tc(constraint_has_products),
@@ -792,7 +1006,24 @@ class Native(object):
         tc(constraint_path_globs),
         tc(constraint_directory_digest),
         tc(constraint_snapshot),
+        tc(constraint_file_content),
         tc(constraint_files_content),
+        tc(constraint_file_range),
+        tc(constraint_binary_tool_to_fetch),
+        tc(constraint_changed_files_request),
+        tc(constraint_changed_files),
+        tc(constraint_path_globs_at_revision),
+        tc(constraint_address_names_request),
+        tc(constraint_address_names),
+        tc(constraint_source_imports_request),
+        tc(constraint_source_imports),
+        tc(constraint_file_size_summary),
+        tc(constraint_environment_request),
+        tc(constraint_environment),
+        tc(constraint_checksums_request),
+        tc(constraint_digest_equality_request),
+        tc(constraint_digest_equality),
+        tc(constraint_platform),
         tc(constraint_dir),
         tc(constraint_file),
         tc(constraint_link),
@@ -814,13 +1045,229 @@ class Native(object):
         execution_options.remote_store_thread_count,
         execution_options.remote_store_chunk_bytes,
         execution_options.remote_store_chunk_upload_timeout_seconds,
+        execution_options.remote_store_metadata_timeout_seconds,
+        self.context.utf8_buf(execution_options.remote_store_write_through),
+        execution_options.remote_store_max_concurrent_transfers,
+        execution_options.remote_store_max_bytes_per_second,
         execution_options.process_execution_parallelism,
-        execution_options.process_execution_cleanup_local_dirs
+        execution_options.process_execution_cleanup_local_dirs,
+        execution_options.process_termination_grace_period_seconds,
+        execution_options.process_execution_sandbox_pool_size,
+        execution_options.process_execution_max_output_bytes,
+        self.context.utf8_buf(execution_options.process_execution_output_overflow),
+        self.context.utf8_buf_buf(execution_options.process_execution_inherited_env),
+        execution_options.process_execution_macos_sandbox,
+        self.context.utf8_buf_buf(execution_options.process_execution_macos_sandbox_cache_dirs),
+        self.context.utf8_buf(execution_options.process_execution_record_dir or ""),
+        self.context.utf8_buf(execution_options.process_execution_replay_dir or ""),
+        self.context.utf8_buf(execution_options.process_execution_cache_dir or ""),
+        execution_options.remote_execution_speculation_delay_ms,
+        execution_options.transient_failure_retry_attempts,
+        execution_options.transient_failure_retry_backoff_ms,
+        execution_options.nondeterministic_rule_check_rate,
+        execution_options.stall_timeout_seconds,
+        execution_options.cancel_stalled_executions,
+        execution_options.background_process_execution_parallelism,
+        execution_options.background_io_parallelism,
+        execution_options.graph_node_count_warning,
+        execution_options.graph_node_count_limit,
+        execution_options.graph_depth_warning,
+        execution_options.graph_depth_limit,
+        self.context.utf8_buf(execution_options.special_file_behavior),
+        self.context.utf8_buf(execution_options.link_escape_behavior),
+        self.context.utf8_buf(execution_options.snapshot_cache_dir or ""),
+        self.context.utf8_buf(execution_options.engine_temp_dir or ""),
+        execution_options.materialize_hardlinks,
+        execution_options.local_store_shard_count,
+        execution_options.local_store_max_shard_bytes,
+        self.context.utf8_buf(execution_options.thread_name_prefix),
+        fake_command_runner,
       )
     return self.gc(scheduler, self.lib.scheduler_destroy)
 
+  # The python types of the kinds of fields that the engine passes to constructors.
+  _CONSTRUCTOR_FIELD_TYPES = {
+    'bytes': six.binary_type,
+    'utf8': six.text_type,
+    'int': int,
+    'bool': bool,
+    'tuple': tuple,
+  }
+
+  def _validate_constructors(self, constructors):
+    """Validates the given constructors against the fields that the engine constructs them with.
+
+    The engine constructs intrinsic products by calling their types with positional arguments, so
+    the fields of each type must be declared with the names (and in the order) that the engine
+    expects, and any type constraints on them must be satisfied by the values that it passes.
+
+    :param dict constructors: A dict from constructor name to the datatype that it constructs.
+    :raises: :class:`TypeError` describing every constructor which does not match.
+    """
+    errors = []
+    for name, fields in self.context.from_value(self.lib.constructor_schemas()):
+      constructor = constructors[name]
+      expected_field_names = tuple(field_name for field_name, _, _ in fields)
+      actual_field_names = tuple(getattr(constructor, '_fields', ()))
+      if actual_field_names != expected_field_names:
+        errors.append('{} ({}) has fields {}, but the engine constructs it with fields {}.'
+                      .format(name, constructor.__name__, actual_field_names, expected_field_names))
+        continue
+
+      field_type_constraints = getattr(constructor, '_field_type_constraints', {})
+      for field_name, kind, kind_constructors in fields:
+        constraint = field_type_constraints.get(field_name)
+        if constraint is None:
+          continue
+        if kind == 'product':
+          field_types = [constructors[c] for c in kind_constructors]
+        else:
+          field_types = [self._CONSTRUCTOR_FIELD_TYPES[kind]]
+        for field_type in field_types:
+          if not constraint.satisfied_by_type(field_type):
+            errors.append(
+              '{} ({}) constrains field {!r} to {}, but the engine constructs it with {}.'
+              .format(name, constructor.__name__, field_name, constraint, field_type.__name__))
+    if errors:
+      raise TypeError('Types passed as constructors did not match the engine:\n  {}'
+                      .format('\n  '.join(errors)))
+
   def set_panic_handler(self):
-    if os.getenv("RUST_BACKTRACE", "0") == "0":
-      # The panic handler hides a lot of rust tracing which may be useful.
-      # Don't activate it when the user explicitly asks for rust backtraces.
-      self.lib.set_panic_handler()
+    """Installs a panic handler which logs engine panics, and writes crash reports for them.
+
+    Panics while running a Node are additionally surfaced as `Throw`s that carry the Rust backtrace,
+    rather than as failures of the whole process.
+    """
+    self.context.raise_or_return(
+      self.lib.set_panic_handler(self.context.utf8_buf(self._crash_report_dir or '')))
+
+  def set_thread_name(self, role):
+    """Names the calling thread for the given role, as seen by OS tools such as `top -H`.
+
+    The name is prefixed by `--thread-name-prefix`, once a scheduler has been created.
+    """
+    self.context.raise_or_return(self.lib.set_thread_name(self.context.utf8_buf(role)))
+
+  def dump_graphs(self, dump_dir=None):
+    """Dumps the running and recently completed nodes of each live Scheduler's graph to a file.
+
+    :param dump_dir: The directory to write dumps to: defaults to the crash report directory.
+    """
+    dump_dir = dump_dir or self._crash_report_dir
+    if not dump_dir:
+      raise ValueError('No directory was configured to dump the native engine graphs to.')
+    self.context.raise_or_return(self.lib.graph_dump_all(self.context.utf8_buf(dump_dir)))
+
+  def set_graph_dump_signal_handler(self):
+    """Dumps the graphs (see `dump_graphs`) whenever this process receives SIGUSR2.
+
+    This allows for post-mortem analysis of a process that appears to be stuck. Must be called from
+    the main thread.
+    """
+    def handle_sigusr2(signum, frame):
+      logger.info('Received SIGUSR2: dumping native engine graphs to {}.'
+                  .format(self._crash_report_dir))
+      self.dump_graphs()
+    signal.signal(signal.SIGUSR2, handle_sigusr2)
+
+  def start_cpu_profile(self, frequency_hz=99):
+    """Samples the functions executed by the native engine's threads, until `stop_cpu_profile`.
+
+    :param int frequency_hz: The number of samples to take per second of CPU time.
+    """
+    self.context.raise_or_return(self.lib.cpu_profile_start(frequency_hz))
+
+  def stop_cpu_profile(self, path):
+    """Stops the running CPU profile, and writes it to the given path.
+
+    The profile is flat: each line names a function, followed by the number of samples which were
+    taken while it was executing. Samples are not unwound while they are taken (which would not be
+    safe inside a signal handler), so time is not attributed to the callers of a function.
+
+    :returns: The number of samples in the profile.
+    """
+    return self.context.raise_or_return(self.lib.cpu_profile_stop(self.context.utf8_buf(path)))
+
+  @contextmanager
+  def cpu_profiled(self, path, frequency_hz=99):
+    """Profiles the native engine for the duration of the context: see `start_cpu_profile`."""
+    self.start_cpu_profile(frequency_hz)
+    try:
+      yield
+    finally:
+      self.stop_cpu_profile(path)
+
+  def set_cpu_profile_signal_handler(self):
+    """Starts a CPU profile when this process receives SIGUSR1, and stops it on the next SIGUSR1.
+
+    This allows for profiling a window of a slow run (or of pantsd) without rebuilding the engine.
+    Profiles are written to the crash report directory. Must be called from the main thread.
+    """
+    if not self._crash_report_dir:
+      raise ValueError('No directory was configured to write native engine CPU profiles to.')
+    profile_paths = []
+
+    def handle_sigusr1(signum, frame):
+      if not profile_paths:
+        path = os.path.join(self._crash_report_dir,
+                            'cpu-profile-{}-{}.txt'.format(os.getpid(), int(time.time())))
+        logger.info('Received SIGUSR1: starting a native engine CPU profile.')
+        self.start_cpu_profile()
+        profile_paths.append(path)
+      else:
+        path = profile_paths.pop()
+        safe_mkdir(os.path.dirname(path))
+        samples = self.stop_cpu_profile(path)
+        logger.info('Received SIGUSR1: wrote a native engine CPU profile with {} samples to {}.'
+                    .format(samples, path))
+    signal.signal(signal.SIGUSR1, handle_sigusr1)
+
+  def init_log_file(self, path, max_bytes, max_age_seconds, max_rotated_files, level):
+    """Opens a log file that the native engine writes to directly, independent of python logging.
+
+    The file captures messages (including panics) that are logged before python logging is wired up
+    to the engine. It is rotated when it exceeds `max_bytes` or is older than `max_age_seconds`,
+    and at most `max_rotated_files` rotated files are kept alongside it.
+
+    :param string path: The path of the log file.
+    :param string level: One of the values of the global `--level` option.
+    """
+    level_number = self._LOG_FILE_LEVELS.get(level)
+    if level_number is None:
+      raise ValueError('Unrecognized log level for the native engine log file: {}'.format(level))
+    self.context.raise_or_return(
+      self.lib.init_log_file(self.context.utf8_buf(path),
+                             max_bytes,
+                             max_age_seconds,
+                             max_rotated_files,
+                             level_number))
+
+  # Trace doesn't exist in python logging: the engine treats 5 as "a bit lower than Debug".
+  _LOG_FILE_LEVELS = {
+    'trace': 5,
+    'debug': logging.DEBUG,
+    'info': logging.INFO,
+    'warn': logging.WARN,
+  }
+
+  def set_module_log_levels(self, module_levels):
+    """Replaces the log level overrides for native engine modules (and their submodules).
+
+    Overrides take effect immediately, and any override that was previously set via this method but
+    is not present in `module_levels` is removed.
+
+    :param dict module_levels: A dict from a native module path (such as
+      `process_execution::remote`) to a python logging level name or number.
+    """
+    levels = {}
+    for module, level in module_levels.items():
+      level_number = level if isinstance(level, int) else logging.getLevelName(level.upper())
+      if not isinstance(level_number, int):
+        raise ValueError('Unrecognized log level for module {}: {}'.format(module, level))
+      levels[module] = level_number
+    for module in set(self._module_log_levels) - set(levels):
+      levels[module] = logging.NOTSET
+    for module, level_number in levels.items():
+      self.context.raise_or_return(
+        self.lib.set_module_log_level(self.context.utf8_buf(module), level_number))
+    self._module_log_levels = {m: l for m, l in levels.items() if l != logging.NOTSET}
@@ -49,8 +49,38 @@ class Return(datatype(['value']), State):
     return (self.value,)
 
 
-class Throw(datatype(['exc']), State):
-  """Indicates that a Node should have been able to return a value, but failed."""
+class ErrorCategory(object):
+  """The categories that the engine assigns to failures: see `Throw.category`."""
+
+  # A rule raised an exception, or the installed rules could not satisfy a request.
+  USER_RULE = 'user_rule'
+  # The engine failed to interact with the filesystem, the Store, or a process runner.
+  INFRASTRUCTURE = 'infrastructure'
+  # A file or directory that was expected to exist did not.
+  MISSING_FILE = 'missing_file'
+  # The inputs of a request changed while it was running.
+  INVALIDATION = 'invalidation'
+  # A request was cancelled before it completed.
+  CANCELLATION = 'cancellation'
+
+
+class Throw(datatype(['exc', 'engine_traceback', 'category']), State):
+  """Indicates that a Node should have been able to return a value, but failed.
+
+  :param exc: The exception that caused the failure.
+  :param engine_traceback: The formatted engine Nodes that the failure propagated through, from
+    the root Node to the Node that raised.
+  :param category: One of the `ErrorCategory` values, or None if the failure did not originate in
+    the engine.
+  """
+
+  def __new__(cls, exc, engine_traceback=(), category=None):
+    return super(Throw, cls).__new__(cls, exc, tuple(engine_traceback), category)
+
+  def render_engine_traceback(self):
+    """Renders the engine traceback, in the style of a python traceback."""
+    return '\n'.join(['Engine traceback:'] +
+                     ['  in {}'.format(node) for node in self.engine_traceback])
 
 
 class Runnable(datatype(['func', 'args', 'cacheable']), State):
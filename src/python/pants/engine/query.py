@@ -0,0 +1,57 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+import threading
+
+from pants.base.cmd_line_spec_parser import CmdLineSpecParser
+from pants.base.specs import Specs
+from pants.engine.selectors import constraint_for
+from pants.util.objects import datatype
+
+
+class QueryRoots(datatype(['product', 'subjects'])):
+  """The product constraint and subjects that a query request was resolved to.
+
+  There must be exactly one subject per subject spec of the request.
+  """
+
+
+class QueryError(Exception):
+  """Indicates that a query request could not be resolved."""
+
+
+class QueryHandler(object):
+  """Resolves and renders the requests of a `pants.engine.scheduler.QueryServer`.
+
+  Requests name a product type, and a series of target specs, each of which is resolved to a
+  `Specs` subject. Computed products are rendered with `repr`.
+
+  The engine holds `lock` while it executes a request: pantsd replaces it with its fork lock, so
+  that it doesn't fork while a request is using the Graph.
+  """
+
+  def __init__(self, build_root, products):
+    """
+    :param string build_root: The build root, relative to which specs are parsed.
+    :param list products: The product types which may be requested.
+    """
+    self._spec_parser = CmdLineSpecParser(build_root)
+    self._products = {product.__name__: product for product in products}
+    self.lock = threading.RLock()
+
+  def resolve(self, product, subject_specs):
+    """Resolves the product name and subject specs of a request to `QueryRoots`."""
+    product_type = self._products.get(product)
+    if product_type is None:
+      raise QueryError('Unrecognized product `{}`: expected one of: {}'.format(
+        product, ', '.join(sorted(self._products))))
+    subjects = tuple(Specs(dependencies=(self._spec_parser.parse_spec(spec),))
+                     for spec in subject_specs)
+    return QueryRoots(constraint_for(product_type), subjects)
+
+  def render(self, value):
+    """Renders a computed product for a client."""
+    return repr(value)
@@ -32,13 +32,18 @@ class _RuleVisitor(ast.NodeVisitor):
     self.gets.append(Get.extract_constraints(node))
 
 
-def rule(output_type, input_selectors):
+def rule(output_type, input_selectors, cacheable=True, provider=False, name=None,
+         description=None):
   """A @decorator that declares that a particular static function may be used as a TaskRule.
 
   :param Constraint output_type: The return/output type for the Rule. This may be either a
     concrete Python type, or an instance of `Exactly` representing a union of multiple types.
   :param list input_selectors: A list of Selector instances that matches the number of arguments
     to the @decorated function.
+  :param bool cacheable: See `TaskRule`.
+  :param bool provider: See `TaskRule`.
+  :param string name: See `TaskRule`.
+  :param string description: See `TaskRule`.
   """
 
   def wrapper(func):
@@ -63,7 +68,14 @@ def rule(output_type, input_selectors):
         rule_visitor.visit(node)
         gets.update(Get(resolve_type(p), resolve_type(s)) for p, s in rule_visitor.gets)
 
-    func._rule = TaskRule(output_type, input_selectors, func, input_gets=list(gets))
+    func._rule = TaskRule(output_type,
+                          input_selectors,
+                          func,
+                          input_gets=list(gets),
+                          cacheable=cacheable,
+                          provider=provider,
+                          name=name,
+                          description=description)
     return func
   return wrapper
 
@@ -84,13 +96,44 @@ class Rule(AbstractClass):
     """Collection of input selectors."""
 
 
-class TaskRule(datatype(['output_constraint', 'input_selectors', 'input_gets', 'func']), Rule):
+class TaskRule(datatype([
+  'output_constraint',
+  'input_selectors',
+  'input_gets',
+  'func',
+  'cacheable',
+  'provider',
+  'name',
+  'description',
+]), Rule):
   """A Rule that runs a task function when all of its input selectors are satisfied.
 
+  A rule that is not `cacheable` (because it depends on state that the engine cannot observe, such
+  as the environment) has its results memoized only for the duration of a session: it re-runs at
+  most once per session.
+
+  A `provider` rule computes its product independent of any subject: it is the only rule for its
+  product, and runs (lazily) at most once per session, however many subjects depend on it. Unlike a
+  `SingletonRule`, whose value is fixed when it is registered, a provider may select other products
+  which are independent of the subject (singletons and other providers), and may request any
+  product via `Get`. Its result is invalidated along with theirs.
+
+  The `name` (which defaults to the name of the task function) and `description` (which defaults
+  to the first line of its docstring) are used by the engine when rendering the rule, for example
+  in traces and visualizations.
+
   TODO: Make input_gets non-optional when more/all rules are using them.
   """
 
-  def __new__(cls, output_type, input_selectors, func, input_gets=None):
+  def __new__(cls,
+              output_type,
+              input_selectors,
+              func,
+              input_gets=None,
+              cacheable=True,
+              provider=False,
+              name=None,
+              description=None):
     # Validate result type.
     if isinstance(output_type, Exactly):
       constraint = output_type
@@ -111,17 +154,34 @@ class TaskRule(datatype(['output_constraint', 'input_selectors', 'input_gets', '
       raise TypeError("Expected a list of Gets for rule `{}`, got: {}".format(
         func.__name__, type(input_gets)))
 
+    # Default the name and description from the function.
+    name = name or func.__name__
+    if description is None and inspect.isfunction(func) and func.__doc__:
+      description = func.__doc__.strip().splitlines()[0]
+
     # Create.
-    return super(TaskRule, cls).__new__(cls, constraint, tuple(input_selectors), tuple(input_gets), func)
+    return super(TaskRule, cls).__new__(cls,
+                                        constraint,
+                                        tuple(input_selectors),
+                                        tuple(input_gets),
+                                        func,
+                                        cacheable,
+                                        provider,
+                                        name,
+                                        description)
 
   def __str__(self):
     return '({}, {!r}, {})'.format(type_or_constraint_repr(self.output_constraint),
                                    self.input_selectors,
-                                   self.func.__name__)
+                                   self.name)
 
 
 class SingletonRule(datatype(['output_constraint', 'value']), Rule):
-  """A default rule for a product, which is thus a singleton for that product."""
+  """A provider of a product whose value is fixed when it is registered.
+
+  Like a `provider` TaskRule, it is the only rule for its product, which is independent of any
+  subject.
+  """
 
   @classmethod
   def from_instance(cls, obj):
@@ -147,6 +207,46 @@ class SingletonRule(datatype(['output_constraint', 'value']), Rule):
     return '{}({}, {})'.format(type(self).__name__, type_or_constraint_repr(self.output_constraint), self.value)
 
 
+class IntrinsicPluginRule(datatype(['output_constraint', 'name', 'input_type']), Rule):
+  """A rule which computes a product from a Directory using an intrinsic from a plugin.
+
+  Plugins are native libraries which are loaded via `--intrinsic-plugins`, and which provide
+  intrinsics by name. The named intrinsic is run on the files in the Directory of the input, and
+  the product is constructed by calling the output type with the bytes that the intrinsic returns.
+
+  The input type is either DirectoryDigest (the default), or a datatype with a `directory_digest`
+  field.
+  """
+
+  def __new__(cls, output_type, name, input_type=None):
+    if not isinstance(output_type, type):
+      raise TypeError("Expected an output_type for rule; got: {}".format(output_type))
+    # NB: Imported here, because pants.engine.fs depends on this module.
+    from pants.engine.fs import DirectoryDigest
+    if input_type is None:
+      input_type = DirectoryDigest
+    if not isinstance(input_type, type) or not (
+        input_type is DirectoryDigest or
+        'directory_digest' in getattr(input_type, '_fields', ())):
+      raise TypeError("Expected an input_type for rule which is DirectoryDigest or has a "
+                      "`directory_digest` field; got: {}".format(input_type))
+    return super(IntrinsicPluginRule, cls).__new__(cls, Exactly(output_type), name, input_type)
+
+  @property
+  def output_type(self):
+    return self.output_constraint.types[0]
+
+  @property
+  def input_selectors(self):
+    return tuple()
+
+  def __repr__(self):
+    return '{}({}, {}, {})'.format(type(self).__name__,
+                                   type_or_constraint_repr(self.output_constraint),
+                                   self.name,
+                                   self.input_type.__name__)
+
+
 class RootRule(datatype(['output_constraint']), Rule):
   """Represents a root input to an execution of a rule graph.
 
@@ -159,6 +259,10 @@ class RootRule(datatype(['output_constraint']), Rule):
     return []
 
 
+def _is_provider(rule):
+  return isinstance(rule, SingletonRule) or (isinstance(rule, TaskRule) and rule.provider)
+
+
 class RuleIndex(datatype(['rules', 'roots'])):
   """Holds an index of Tasks and Singletons used to instantiate Nodes."""
 
@@ -198,4 +302,9 @@ class RuleIndex(datatype(['rules', 'roots'])):
                         "Rules either extend Rule, or are static functions "
                         "decorated with @rule.".format(type(entry)))
 
+    for product_type, rules in serializable_rules.items():
+      if len(rules) > 1 and any(_is_provider(rule) for rule in rules):
+        raise ValueError('A singleton or provider rule must be the only rule for its product, but '
+                         '{} has rules: {}'.format(product_type, ', '.join(str(r) for r in rules)))
+
     return cls(serializable_rules, serializable_roots)
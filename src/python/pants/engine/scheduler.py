@@ -4,21 +4,31 @@
 
 from __future__ import absolute_import, division, print_function, unicode_literals
 
+import itertools
 import logging
 import os
 import time
-from collections import defaultdict
+import weakref
+from collections import OrderedDict, defaultdict
 from types import GeneratorType
 
 from pants.base.exceptions import TaskError
 from pants.base.project_tree import Dir, File, Link
 from pants.build_graph.address import Address
-from pants.engine.fs import (DirectoryDigest, DirectoryToMaterialize, FileContent, FilesContent,
-                             Path, PathGlobs, PathGlobsAndRoot, Snapshot)
-from pants.engine.isolated_process import ExecuteProcessRequest, FallibleExecuteProcessResult
+from pants.engine.fs import (BinaryToolToFetch, ChangedFiles, ChangedFilesRequest,
+                             ChecksumsRequest, Digest, DigestEquality, DigestEqualityRequest,
+                             DirectoryDigest, DirectoryToMaterialize, FileContent, FileRange,
+                             FileSizeSummary, FilesContent, LazyBytes, Path, PathGlobs,
+                             PathGlobsAndRoot, PathGlobsAtRevision, Snapshot, SourceImports,
+                             SourceImportsRequest)
+from pants.engine.isolated_process import (Environment, EnvironmentRequest, ExecuteProcessRequest,
+                                           ExecuteProcessRequestDigests,
+                                           FallibleExecuteProcessResult, FakeProcessInvocation,
+                                           Platform)
+from pants.engine.mapper import AddressNames, AddressNamesRequest
 from pants.engine.native import Function, TypeConstraint, TypeId
 from pants.engine.nodes import Return, State, Throw
-from pants.engine.rules import RuleIndex, SingletonRule, TaskRule
+from pants.engine.rules import IntrinsicPluginRule, RuleIndex, SingletonRule, TaskRule
 from pants.engine.selectors import Select, SelectVariant, constraint_for
 from pants.engine.struct import HasProducts, Variants
 from pants.util.contextutil import temporary_file_path
@@ -81,6 +91,7 @@ class Scheduler(object):
     execution_options,
     include_trace_on_error=True,
     validate=True,
+    fake_command_runner=False,
   ):
     """
     :param native: An instance of engine.native.Native.
@@ -91,13 +102,23 @@ class Scheduler(object):
     :param include_trace_on_error: Include the trace through the graph upon encountering errors.
     :type include_trace_on_error: bool
     :param validate: True to assert that the ruleset is valid.
+    :param fake_command_runner: True to respond to ExecuteProcessRequests with canned results (see
+      `fake_process_result`) rather than executing them, for hermetic tests of rules which execute
+      processes.
     """
 
     if execution_options.remote_execution_server and not execution_options.remote_store_server:
       raise ValueError("Cannot set remote execution server without setting remote store server")
+    if (execution_options.remote_execution_server and
+        execution_options.remote_store_write_through == 'never'):
+      raise ValueError("Cannot set remote execution server with a read-only remote store "
+                       "(--remote-store-write-through=never)")
 
     self._native = native
     self.include_trace_on_error = include_trace_on_error
+    self._session_timeout_seconds = execution_options.remote_store_session_timeout_seconds
+    # QueryServers of this Scheduler, which must be stopped around forks.
+    self._query_servers = weakref.WeakSet()
 
     # TODO: The only (?) case where we use inheritance rather than exact type unions.
     has_products_constraint = SubclassesOf(HasProducts)
@@ -111,6 +132,11 @@ class Scheduler(object):
     # after construction.
     self._tasks = native.new_tasks()
     self._register_rules(rule_index)
+    # Load plugins once all of the intrinsics that they must provide have been registered.
+    self._raise_or_return(
+      native.lib.tasks_load_plugins(
+        self._tasks,
+        native.context.utf8_buf_buf(execution_options.intrinsic_plugins)))
 
     self._scheduler = native.new_scheduler(
       self._tasks,
@@ -128,19 +154,46 @@ class Scheduler(object):
       File,
       Link,
       FallibleExecuteProcessResult,
+      # Bound to this Scheduler, whose store holds the bytes.
+      LazyBytes.bound_to(self.load_bytes),
+      ChangedFiles,
+      AddressNames,
+      SourceImports,
+      FileSizeSummary,
+      Environment,
+      DigestEquality,
+      Platform,
       has_products_constraint,
       constraint_for(Address),
       constraint_for(Variants),
       constraint_for(PathGlobs),
       constraint_for(DirectoryDigest),
       constraint_for(Snapshot),
+      constraint_for(FileContent),
       constraint_for(FilesContent),
+      constraint_for(FileRange),
+      constraint_for(BinaryToolToFetch),
+      constraint_for(ChangedFilesRequest),
+      constraint_for(ChangedFiles),
+      constraint_for(PathGlobsAtRevision),
+      constraint_for(AddressNamesRequest),
+      constraint_for(AddressNames),
+      constraint_for(SourceImportsRequest),
+      constraint_for(SourceImports),
+      constraint_for(FileSizeSummary),
+      constraint_for(EnvironmentRequest),
+      constraint_for(Environment),
+      constraint_for(ChecksumsRequest),
+      constraint_for(DigestEqualityRequest),
+      constraint_for(DigestEquality),
+      constraint_for(Platform),
       constraint_for(Dir),
       constraint_for(File),
       constraint_for(Link),
       constraint_for(ExecuteProcessRequest),
       constraint_for(FallibleExecuteProcessResult),
       constraint_for(GeneratorType),
+      fake_command_runner=fake_command_runner,
     )
 
     # If configured, visualize the rule graph before asserting that it is valid.
@@ -154,9 +207,22 @@ class Scheduler(object):
   def _root_type_ids(self):
     return self._to_ids_buf(sorted(self._root_subject_types))
 
-  def graph_trace(self, execution_request):
+  def graph_trace(self, execution_request, max_depth=0, node_types=(), first_failure_only=False):
+    """Yields the lines of a trace of the paths from the roots of the request to its failures.
+
+    :param int max_depth: The maximum number of Nodes to render on each path (omitting those
+                          furthest from the failure), or 0 to render all of them.
+    :param node_types: If non-empty, the types of Nodes (such as `Task` or `ExecuteProcess`) to
+                       render on each path, in addition to the failure itself.
+    :param bool first_failure_only: Render only the path to the first failure.
+    """
     with temporary_file_path() as path:
-      self._native.lib.graph_trace(self._scheduler, execution_request, bytes(path))
+      self._native.lib.graph_trace(self._scheduler,
+                                   execution_request,
+                                   bytes(path),
+                                   max_depth,
+                                   self._native.context.utf8_buf_buf(node_types),
+                                   first_failure_only)
       with open(path) as fd:
         for line in fd.readlines():
           yield line.rstrip()
@@ -191,6 +257,10 @@ class Scheduler(object):
   def _to_ids_buf(self, types):
     return self._native.to_ids_buf(types)
 
+  def _to_constraints_buf(self, types_or_constraints):
+    return self._native.context.type_constraints_buf(
+      [self._to_constraint(t) for t in types_or_constraints])
+
   def _to_utf8_buf(self, string):
     return self._native.context.utf8_buf(string)
 
@@ -212,6 +282,8 @@ class Scheduler(object):
           self._register_singleton(output_constraint, rule)
         elif type(rule) is TaskRule:
           self._register_task(output_constraint, rule)
+        elif type(rule) is IntrinsicPluginRule:
+          self._register_intrinsic_plugin(output_constraint, rule)
         else:
           raise ValueError('Unexpected Rule type: {}'.format(rule))
 
@@ -224,10 +296,24 @@ class Scheduler(object):
                                          self._to_value(rule.value),
                                          output_constraint)
 
+  def _register_intrinsic_plugin(self, output_constraint, rule):
+    """Register the given IntrinsicPluginRule with the native scheduler."""
+    self._native.lib.tasks_plugin_intrinsic_add(self._tasks,
+                                                self._to_utf8_buf(rule.name),
+                                                output_constraint,
+                                                self._to_constraint(rule.input_type),
+                                                Function(self._to_key(rule.output_type)))
+
   def _register_task(self, output_constraint, rule):
     """Register the given TaskRule with the native scheduler."""
     func = rule.func
-    self._native.lib.tasks_task_begin(self._tasks, Function(self._to_key(func)), output_constraint)
+    self._native.lib.tasks_task_begin(self._tasks,
+                                      Function(self._to_key(func)),
+                                      output_constraint,
+                                      rule.cacheable,
+                                      rule.provider,
+                                      self._to_utf8_buf(rule.name),
+                                      self._to_utf8_buf(rule.description or ''))
     for selector in rule.input_selectors:
       selector_type = type(selector)
       product_constraint = self._to_constraint(selector.product)
@@ -246,8 +332,11 @@ class Scheduler(object):
                                      TypeId(self._to_id(get.subject)))
     self._native.lib.tasks_task_end(self._tasks)
 
-  def visualize_graph_to_file(self, session, filename):
-    res = self._native.lib.graph_visualize(self._scheduler, session, bytes(filename))
+  def visualize_graph_to_file(self, session, filename, min_duration_ms=0):
+    res = self._native.lib.graph_visualize(self._scheduler,
+                                           session,
+                                           bytes(filename),
+                                           min_duration_ms)
     self._raise_or_return(res)
 
   def visualize_rule_graph_to_file(self, filename):
@@ -287,11 +376,22 @@ class Scheduler(object):
     logger.info('invalidated %d nodes for: %s', invalidated, filenames)
     return invalidated
 
+  def invalidate_prefixes(self, prefixes):
+    prefixes_buf = self._native.context.utf8_buf_buf(prefixes)
+    invalidated = self._native.lib.graph_invalidate_prefixes(self._scheduler, prefixes_buf)
+    logger.info('invalidated %d nodes under: %s', invalidated, prefixes)
+    return invalidated
+
   def invalidate_all_files(self):
     invalidated =  self._native.lib.graph_invalidate_all_paths(self._scheduler)
     logger.info('invalidated all %d nodes', invalidated)
     return invalidated
 
+  def invalidate_all(self):
+    invalidated = self._native.lib.graph_invalidate_all(self._scheduler)
+    logger.info('cleared all %d nodes', invalidated)
+    return invalidated
+
   def graph_len(self):
     return self._native.lib.graph_len(self._scheduler)
 
@@ -302,29 +402,202 @@ class Scheduler(object):
                                                      self._to_constraint(product))
     self._raise_or_return(res)
 
+  def add_root_selections(self, execution_request, subjects, product):
+    """Adds a root selecting the product for each subject, sharing rule graph lookups."""
+    res = self._native.lib.execution_add_root_selects(self._scheduler,
+                                                      execution_request,
+                                                      self._to_value(_RootSubjects(subjects)),
+                                                      self._to_constraint(product))
+    self._raise_or_return(res)
+
+  def add_root_multiple_selection(self, execution_request, subject, products):
+    res = self._native.lib.execution_add_root_select_multiple(self._scheduler,
+                                                              execution_request,
+                                                              self._to_key(subject),
+                                                              self._to_constraints_buf(products))
+    self._raise_or_return(res)
+
   def visualize_to_dir(self):
     return self._native.visualize_to_dir
 
+  def visualize_min_duration_ms(self):
+    return self._native.visualize_min_duration_ms
+
   def _metrics(self, session):
     metrics_val = self._native.lib.scheduler_metrics(self._scheduler, session)
     return {k: v for k, v in self._from_value(metrics_val)}
 
+  def heap_usage(self):
+    """Returns estimates of the bytes of memory held by the engine, as a dict keyed by subsystem.
+
+    Keys include `graph.entries`, `graph.results.$type` (per type of Node result), `store.$purpose`
+    and `interned.$table`. Memory held by python objects is not included.
+    """
+    heap_usage_val = self._native.lib.scheduler_heap_usage(self._scheduler)
+    return {k: v for k, v in self._from_value(heap_usage_val)}
+
+  def workunit_events(self):
+    """Takes the events for processes run by the engine since the last call, in the order they
+    occurred.
+
+    Returns a tuple of a list of events and the count of events which were dropped because they
+    were not taken in time. Each event is a dict with a `kind` (one of `started`, `first_output`,
+    `completed` or `failed`) and the `id` of its process, plus `description`, `input_digest` and
+    `parent_id` (the id of the process on whose behalf a speculative run was made, or None) for
+    `started`, `exit_code`, `source`, `disk_bytes_written` and `cache_bytes_written` (both None
+    unless the process was executed locally) for `completed`, and `error` for `failed`. The growth
+    of shared caches is reported separately because other processes may have contributed to it.
+    """
+    events_val = self._native.lib.scheduler_workunit_events(self._scheduler)
+    events, dropped = self._from_value(events_val)
+    return [self._workunit_event(event) for event in events], dropped
+
+  @staticmethod
+  def _workunit_event(event):
+    kind, workunit_id = event[0], event[1]
+    result = {'kind': kind, 'id': workunit_id}
+    if kind == 'started':
+      result['description'] = event[2]
+      result['input_digest'] = (event[3], event[4])
+      result['parent_id'] = event[5] if event[5] >= 0 else None
+    elif kind == 'completed':
+      result['exit_code'] = event[2]
+      result['source'] = event[3]
+      result['disk_bytes_written'] = event[4] if event[4] >= 0 else None
+      result['cache_bytes_written'] = event[5] if event[5] >= 0 else None
+    elif kind == 'failed':
+      result['error'] = event[2]
+    return result
+
+  def terminate_processes(self):
+    """Terminates any processes that are being executed, giving them a grace period to exit.
+
+    See `--process-termination-grace-period-seconds`.
+    """
+    self._native.lib.scheduler_terminate_processes(self._scheduler)
+
+  def shutdown(self, timeout_seconds):
+    """Shuts down the scheduler, releasing its resources before the process exits.
+
+    Executions which begin after this call are cancelled, and running executions are given
+    `timeout_seconds` to complete before they are cancelled. Then any processes that are still
+    executing are terminated (see `terminate_processes`), uploads to the remote store are given
+    `timeout_seconds` to complete, and the local store is flushed to disk.
+
+    Returns a dict of the number of `cancelled_executions`, the ids of the `killed_processes` which
+    did not exit gracefully, and any `errors` that were encountered.
+    """
+    report_val = self._native.lib.scheduler_shutdown(self._scheduler, int(timeout_seconds * 1000))
+    cancelled_executions, killed_processes, errors = self._from_value(report_val)
+    return {
+      'cancelled_executions': cancelled_executions,
+      'killed_processes': list(killed_processes),
+      'errors': list(errors),
+    }
+
+  def trim_memory(self, min_idle_seconds):
+    """Releases memory held by the scheduler, if it has been idle for at least `min_idle_seconds`.
+
+    The results of rules are evicted from the graph (to be recomputed if they are requested again),
+    the in-memory caches of the store are released, and the allocator is asked to return free
+    memory to the OS. A scheduler is trimmed at most once per idle period.
+
+    Returns None if the scheduler was not trimmed, and otherwise a dict of the number of
+    `evicted_nodes` and `released_values`, estimates of the `graph_bytes` and `store_bytes`
+    released, and the reduction in the resident set size of the process (`rss_bytes`, which is None
+    where it cannot be measured).
+    """
+    report_val = self._native.lib.scheduler_trim_memory(self._scheduler,
+                                                        int(min_idle_seconds * 1000))
+    report = self._from_value(report_val)
+    if not report:
+      return None
+    evicted_nodes, released_values, graph_bytes, store_bytes, rss_bytes = report
+    return {
+      'evicted_nodes': evicted_nodes,
+      'released_values': released_values,
+      'graph_bytes': graph_bytes,
+      'store_bytes': store_bytes,
+      'rss_bytes': rss_bytes[0] if rss_bytes else None,
+    }
+
+  def snapshot_cache_generation(self):
+    """Returns the generation that was persisted along with the snapshot cache, or None.
+
+    See `--snapshot-cache-dir`.
+    """
+    generation_val = self._native.lib.scheduler_snapshot_cache_generation(self._scheduler)
+    return self._from_value(generation_val) or None
+
+  def set_snapshot_cache_generation(self, generation):
+    """Sets (and persists) the generation under which Snapshots are cached.
+
+    Caching is disabled whenever files are invalidated, until the next generation is set. Has no
+    effect if `--snapshot-cache-dir` is not set.
+
+    :param generation: An opaque token which changes whenever files under the build root might
+                       have changed, such as a watchman clock.
+    """
+    self._native.lib.scheduler_set_snapshot_cache_generation(
+      self._scheduler,
+      self._native.context.utf8_buf(generation),
+    )
+
   def pre_fork(self):
+    """Drops the engine's thread pools and connections, which are unsafe to use after a fork.
+
+    Also locks the engine's shared state until `post_fork_in_parent` or `post_fork_in_child` is
+    called, so the fork must immediately follow on the same thread. Any QueryServers of this
+    Scheduler are stopped until `post_fork_in_parent` is called.
+    """
+    for query_server in self._query_servers:
+      query_server.pre_fork()
     self._native.lib.scheduler_pre_fork(self._scheduler)
 
-  def _run_and_return_roots(self, session, execution_request):
-    raw_roots = self._native.lib.scheduler_execute(self._scheduler, session, execution_request)
+  def post_fork_in_child(self):
+    """Must be called in a forked child immediately after the fork: see `pre_fork`.
+
+    Raises if the engine was in a state at the time of the fork which it cannot recover from in the
+    child, in which case the child should exit.
+    """
+    self._raise_or_return(self._native.lib.scheduler_post_fork_in_child(self._scheduler))
+    for query_server in self._query_servers:
+      query_server.post_fork_in_child()
+
+  def post_fork_in_parent(self):
+    """Must be called in the parent after a fork: see `pre_fork`."""
+    self._native.lib.scheduler_post_fork_in_parent(self._scheduler)
+    for query_server in self._query_servers:
+      query_server.post_fork_in_parent()
+
+  def set_module_log_levels(self, module_levels):
+    """See `Native.set_module_log_levels`."""
+    self._native.set_module_log_levels(module_levels)
+
+  @staticmethod
+  def _to_state(state_tag, state_value):
+    if state_tag == 1:
+      return Return(state_value)
+    elif state_tag in (2, 3, 4, 5):
+      exc, engine_traceback, category = state_value
+      return Throw(exc, engine_traceback, category)
+    else:
+      raise ValueError('Unrecognized State type `{}` for: {}'.format(state_tag, state_value))
+
+  def _run_and_return_roots(self, session, execution_request, on_root=None):
+    if on_root is None:
+      raw_roots = self._native.lib.scheduler_execute(self._scheduler, session, execution_request)
+    else:
+      def callback(root_index, state_tag, state_value):
+        on_root(root_index, self._to_state(state_tag, state_value))
+      raw_roots = self._native.lib.scheduler_execute_streaming(self._scheduler,
+                                                               session,
+                                                               execution_request,
+                                                               self._to_value(callback))
     try:
       roots = []
       for raw_root in self._native.unpack(raw_roots.nodes_ptr, raw_roots.nodes_len):
-        if raw_root.state_tag is 1:
-          state = Return(self._from_value(raw_root.state_value))
-        elif raw_root.state_tag in (2, 3, 4):
-          state = Throw(self._from_value(raw_root.state_value))
-        else:
-          raise ValueError(
-            'Unrecognized State type `{}` on: {}'.format(raw_root.state_tag, raw_root))
-        roots.append(state)
+        roots.append(self._to_state(raw_root.state_tag, self._from_value(raw_root.state_value)))
     finally:
       self._native.lib.nodes_destroy(raw_roots)
     return roots
@@ -334,8 +607,12 @@ class Scheduler(object):
 
     This is a blocking operation, and should be avoided where possible.
 
-    :param path_globs_and_roots tuple<PathGlobsAndRoot>: The PathGlobs to capture, and the root
-           directory relative to which each should be captured.
+    Roots may be outside of the buildroot (for example, a system toolchain in /usr/lib/jvm), but
+    are not watched for changes, so the captured files should be immutable. Absolute symlinks are
+    supported if they point inside of their root. This API is deliberately not available to @rules.
+
+    :param path_globs_and_roots tuple<PathGlobsAndRoot>: The PathGlobs to capture, and the absolute
+           path of the root directory relative to which each should be captured.
     :returns: A tuple of Snapshots.
     """
     result = self._native.lib.capture_snapshots(
@@ -356,32 +633,214 @@ class Scheduler(object):
     )
     return self._raise_or_return(result)
 
-  def materialize_directories(self, directories_paths_and_digests):
+  def materialize_directories(self, directories_paths_and_digests, replace=False):
     """Creates the specified directories on the file system.
 
     :param directories_paths_and_digests tuple<DirectoryToMaterialize>: Tuple of the path and
            digest of the directories to materialize.
+    :param bool replace: If True, any existing contents of the directories are deleted, and each
+           directory is replaced atomically: it is materialized beside its path and then renamed
+           into place, so an interruption never leaves it partially written.
     :returns: Nothing or an error.
     """
     # Ensure there isn't more than one of the same directory paths and paths do not have the same prefix.
     dir_list = [dpad.path for dpad in directories_paths_and_digests]
     check_no_overlapping_paths(dir_list)
 
-    result = self._native.lib.materialize_directories(
+    materialize = (self._native.lib.replace_directories if replace
+                   else self._native.lib.materialize_directories)
+    result = materialize(
       self._scheduler,
       self._to_value(_DirectoriesToMaterialize(directories_paths_and_digests)),
     )
     return self._raise_or_return(result)
 
+  def execute_process_request_digests(self, execute_process_request):
+    """Computes the digests that an ExecuteProcessRequest is executed (and cached) as remotely.
+
+    :param execute_process_request: An ExecuteProcessRequest.
+    :returns: An ExecuteProcessRequestDigests.
+    """
+    result = self._native.lib.execute_process_request_digests(
+      self._to_value(execute_process_request),
+    )
+    action_digest, command_digest = self._raise_or_return(result)
+    return ExecuteProcessRequestDigests(Digest(*action_digest), Digest(*command_digest))
+
+  def explain_process_request_diff(self, left_archive_dir, left_action_fingerprint,
+                                   right_archive_dir, right_action_fingerprint):
+    """Describes the differences between two requests recorded by `process_execution_record_dir`.
+
+    This helps to explain why two requests (for example, one run locally and one run in CI) which
+    were expected to share a cache entry did not.
+
+    :param left_archive_dir: The directory that the first request was recorded to.
+    :param left_action_fingerprint: The fingerprint of the action digest of the first request (see
+           `execute_process_request_digests`).
+    :param right_archive_dir: The directory that the second request was recorded to.
+    :param right_action_fingerprint: The fingerprint of the action digest of the second request.
+    :returns: A human readable description of the differences.
+    """
+    result = self._native.lib.explain_process_request_diff(
+      self._native.context.utf8_buf(left_archive_dir),
+      self._native.context.utf8_buf(left_action_fingerprint),
+      self._native.context.utf8_buf(right_archive_dir),
+      self._native.context.utf8_buf(right_action_fingerprint),
+    )
+    return self._raise_or_return(result)
+
+  def fake_process_result(self, execute_process_request, result):
+    """Responds to executions of the given request with the given result.
+
+    Only available if this Scheduler was created with `fake_command_runner=True`.
+
+    :param execute_process_request: An ExecuteProcessRequest.
+    :param result: A FallibleExecuteProcessResult.
+    """
+    res = self._native.lib.scheduler_fake_process_result(
+      self._scheduler,
+      self._to_value(execute_process_request),
+      self._native.context.buf(result.stdout),
+      self._native.context.buf(result.stderr),
+      result.exit_code,
+      self._to_value(result.output_directory_digest),
+    )
+    self._raise_or_return(res)
+
+  def fake_process_invocations(self):
+    """Returns the requests that have been executed so far, in the order they were executed.
+
+    Only available if this Scheduler was created with `fake_command_runner=True`.
+
+    :returns: A tuple of FakeProcessInvocation.
+    """
+    res = self._native.lib.scheduler_fake_process_invocations(self._scheduler)
+    return tuple(FakeProcessInvocation(tuple(argv), description, Digest(*action_digest))
+                 for argv, description, action_digest in self._raise_or_return(res))
+
   def lease_files_in_graph(self):
     self._native.lib.lease_files_in_graph(self._scheduler)
 
+  def lease_digests(self, digests, duration_seconds):
+    """Pins the given digests in the local store for a duration, during which they will not be
+    garbage collected.
+
+    Directories are leased along with everything that they contain.
+
+    :param digests: Tuple of DirectoryDigests, or of Digests (which may identify files).
+    :param duration_seconds: The number of seconds to lease the digests for.
+    """
+    result = self._native.lib.lease_digests(
+      self._scheduler,
+      self._to_value(_DirectoryDigests(digests)),
+      int(duration_seconds),
+    )
+    return self._raise_or_return(result)
+
+  def release_digests(self, digests):
+    """Releases one pin (taken by `lease_digests`) of each of the given digests (and of the
+    contents of Directories) in the local store. Once all of its pins have been released, content
+    may be garbage collected.
+
+    :param digests: Tuple of DirectoryDigests, or of Digests (which may identify files).
+    """
+    result = self._native.lib.release_digests(
+      self._scheduler,
+      self._to_value(_DirectoryDigests(digests)),
+    )
+    return self._raise_or_return(result)
+
+  def load_bytes(self, digest):
+    """Loads the content of the given file digest from the store.
+
+    :param digest: A LazyBytes, or a Digest which identifies a file.
+    :returns: The bytes of the file.
+    """
+    return self._raise_or_return(
+      self._native.lib.scheduler_load_bytes(self._scheduler, self._to_value(digest)))
+
   def garbage_collect_store(self):
     self._native.lib.garbage_collect_store(self._scheduler)
 
-  def new_session(self):
-    """Creates a new SchedulerSession for this Scheduler."""
-    return SchedulerSession(self, self._native.new_session(self._scheduler))
+  def flush_uploads(self):
+    """Waits for outputs which are being written through to the remote store (see
+    `--remote-store-write-through`) to be uploaded.
+    """
+    self._raise_or_return(self._native.lib.scheduler_flush_uploads(self._scheduler))
+
+  def new_session(self, background=False, timeout_seconds=None):
+    """Creates a new SchedulerSession for this Scheduler.
+
+    :param background: True if the session will run speculative work on behalf of an idle client
+      (such as rebuilding when files change). While only background sessions are executing, their
+      process executions and IO are throttled according to the `--background-*` options, so that
+      they don't starve interactive work on the same machine. Unlike a foreground session, a
+      background session does not reset session-scoped state (such as the results of uncacheable
+      rules), because it may run alongside a foreground session.
+    :param int timeout_seconds: If set, the session has a deadline this many seconds after its
+      creation, beyond which its executions will not wait for RPCs to the remote store. Defaults to
+      `--remote-store-session-timeout-seconds`.
+    """
+    if timeout_seconds is None:
+      timeout_seconds = self._session_timeout_seconds
+    return SchedulerSession(self, self._native.new_session(self._scheduler, background,
+                                                           timeout_seconds))
+
+  def new_query_server(self, handler):
+    """Creates a QueryServer which serves products computed by this Scheduler over gRPC.
+
+    :param handler: A `pants.engine.query.QueryHandler` to resolve and render requests.
+    """
+    query_server = QueryServer(self, self._native.new_query_server(self._scheduler,
+                                                                   self._to_value(handler)))
+    self._query_servers.add(query_server)
+    return query_server
+
+
+class QueryServer(object):
+  """A gRPC server for local clients (such as IDEs) which computes products using a Scheduler.
+
+  Requests are resolved and rendered by a `pants.engine.query.QueryHandler`, and each request is
+  executed in a new Session of the Scheduler. See `src/rust/engine/query_protos/proto/query.proto`
+  for the protocol.
+
+  The server is stopped by `Scheduler.pre_fork`, and restarted on the same socket by
+  `Scheduler.post_fork_in_parent`.
+  """
+
+  def __init__(self, scheduler, query_server):
+    self._scheduler = scheduler
+    self._query_server = query_server
+
+  def start(self, socket_dir):
+    """Starts serving on a unix socket in the given directory.
+
+    The directory is created if necessary, and both it and the socket are made accessible only to
+    the current user.
+
+    :param string socket_dir: The directory to create the socket in.
+    :returns: The path of the socket.
+    """
+    res = self._scheduler._native.lib.query_server_start(
+      self._query_server,
+      self._scheduler._native.context.utf8_buf(socket_dir),
+    )
+    return self._scheduler._raise_or_return(res)
+
+  def stop(self):
+    """Stops serving, cancels any in-flight requests, and removes the socket."""
+    self._scheduler._native.lib.query_server_stop(self._query_server)
+
+  def pre_fork(self):
+    self._scheduler._native.lib.query_server_pre_fork(self._query_server)
+
+  def post_fork_in_parent(self):
+    res = self._scheduler._native.lib.query_server_post_fork_in_parent(self._query_server)
+    self._scheduler._raise_or_return(res)
+
+  def post_fork_in_child(self):
+    res = self._scheduler._native.lib.query_server_post_fork_in_child(self._query_server)
+    self._scheduler._raise_or_return(res)
 
 
 _PathGlobsAndRootCollection = Collection.of(PathGlobsAndRoot)
@@ -393,6 +852,9 @@ _DirectoryDigests = Collection.of(DirectoryDigest)
 _DirectoriesToMaterialize = Collection.of(DirectoryToMaterialize)
 
 
+_RootSubjects = Collection.of(object)
+
+
 class SchedulerSession(object):
   """A handle to a shared underlying Scheduler and a unique Session.
 
@@ -408,25 +870,44 @@ class SchedulerSession(object):
   def graph_len(self):
     return self._scheduler.graph_len()
 
-  def trace(self, execution_request):
-    """Yields a stringified 'stacktrace' starting from the scheduler's roots."""
-    for line in self._scheduler.graph_trace(execution_request.native):
+  def trace(self, execution_request, **kwargs):
+    """Yields a stringified 'stacktrace' starting from the scheduler's roots.
+
+    See `Scheduler.graph_trace` for the accepted options.
+    """
+    for line in self._scheduler.graph_trace(execution_request.native, **kwargs):
       yield line
 
-  def visualize_graph_to_file(self, filename):
+  def visualize_graph_to_file(self, filename, min_duration_ms=0):
     """Visualize a graph walk by writing graphviz `dot` output to a file.
 
+    Nodes are labeled with their duration and (for filesystem results) output size, and colored by
+    how their duration ranks relative to the other visualized Nodes.
+
     :param str filename: The filename to output the graphviz output to.
+    :param int min_duration_ms: Nodes that ran for less than this many milliseconds are omitted.
     """
-    self._scheduler.visualize_graph_to_file(self._session, filename)
+    self._scheduler.visualize_graph_to_file(self._session, filename, min_duration_ms)
 
   def visualize_rule_graph_to_file(self, filename):
     self._scheduler.visualize_rule_graph_to_file(filename)
 
   def execution_request_literal(self, request_specs):
+    """Create and return an ExecutionRequest for the given subject -> product tuples.
+
+    If the product of a request spec is a tuple of products, its root will select all of them for
+    the subject in a single Node, and its result will be a tuple of the products in the same order.
+    """
     native_execution_request = self._scheduler._native.new_execution_request()
-    for subject, product in request_specs:
-      self._scheduler.add_root_selection(native_execution_request, subject, product)
+    # Consecutive roots which select the same product (such as those of a request for thousands of
+    # Specs) are added in one batch, in order.
+    for product, specs in itertools.groupby(request_specs, key=lambda spec: spec[1]):
+      if isinstance(product, tuple):
+        for subject, _ in specs:
+          self._scheduler.add_root_multiple_selection(native_execution_request, subject, product)
+      else:
+        subjects = tuple(subject for subject, _ in specs)
+        self._scheduler.add_root_selections(native_execution_request, subjects, product)
     return ExecutionRequest(request_specs, native_execution_request)
 
   def execution_request(self, products, subjects):
@@ -454,12 +935,28 @@ class SchedulerSession(object):
     self._maybe_visualize()
     return invalidated
 
+  def invalidate_prefixes(self, prefixes):
+    """Invalidates all filesystem Nodes under the given directory prefixes.
+
+    :param prefixes: Buildroot-relative directory paths. A prefix matches whole path components, so
+                     `a/b` matches `a/b/c` but not `a/bc`.
+    """
+    invalidated = self._scheduler.invalidate_prefixes(prefixes)
+    self._maybe_visualize()
+    return invalidated
+
   def invalidate_all_files(self):
     """Invalidates all filenames in an internal product Graph instance."""
     invalidated = self._scheduler.invalidate_all_files()
     self._maybe_visualize()
     return invalidated
 
+  def invalidate_all(self):
+    """Clears every Node in the internal product Graph, forcing a full recomputation."""
+    invalidated = self._scheduler.invalidate_all()
+    self._maybe_visualize()
+    return invalidated
+
   def node_count(self):
     return self._scheduler.graph_len()
 
@@ -467,25 +964,48 @@ class SchedulerSession(object):
     """Returns metrics for this SchedulerSession as a dict of metric name to metric value."""
     return self._scheduler._metrics(self._session)
 
+  def heap_usage(self):
+    """See `Scheduler.heap_usage`."""
+    return self._scheduler.heap_usage()
+
+  def workunit_events(self):
+    """See `Scheduler.workunit_events`."""
+    return self._scheduler.workunit_events()
+
   def pre_fork(self):
     self._scheduler.pre_fork()
 
+  def post_fork_in_child(self):
+    self._scheduler.post_fork_in_child()
+
+  def post_fork_in_parent(self):
+    self._scheduler.post_fork_in_parent()
+
   def _maybe_visualize(self):
     if self._scheduler.visualize_to_dir() is not None:
       name = 'graph.{0:03d}.dot'.format(self._run_count)
       self._run_count += 1
-      self.visualize_graph_to_file(os.path.join(self._scheduler.visualize_to_dir(), name))
+      self.visualize_graph_to_file(os.path.join(self._scheduler.visualize_to_dir(), name),
+                                   self._scheduler.visualize_min_duration_ms())
 
-  def schedule(self, execution_request):
+  def schedule(self, execution_request, on_root=None):
     """Yields batches of Steps until the roots specified by the request have been completed.
 
     This method should be called by exactly one scheduling thread, but the Step objects returned
     by this method are intended to be executed in multiple threads, and then satisfied by the
     scheduling thread.
+
+    :param on_root: See `execute`.
     """
     start_time = time.time()
+    native_on_root = None
+    if on_root is not None:
+      def native_on_root(root_index, state):
+        on_root(execution_request.roots[root_index], state)
     roots = zip(execution_request.roots,
-                self._scheduler._run_and_return_roots(self._session, execution_request.native))
+                self._scheduler._run_and_return_roots(self._session,
+                                                      execution_request.native,
+                                                      on_root=native_on_root))
 
     self._maybe_visualize()
 
@@ -498,7 +1018,7 @@ class SchedulerSession(object):
 
     return roots
 
-  def execute(self, execution_request):
+  def execute(self, execution_request, on_root=None):
     """Executes the requested build and returns the resulting root entries.
 
     TODO: Merge with `schedule`.
@@ -506,19 +1026,27 @@ class SchedulerSession(object):
 
     :param execution_request: The description of the goals to achieve.
     :type execution_request: :class:`ExecutionRequest`
+    :param on_root: An optional callable that will be called with `((subject, product), state)` for
+      each root as soon as it completes (and before this method returns). It is called from an
+      engine thread, so it should be threadsafe and should not block.
     :returns: The result of the run.
     :rtype: :class:`Engine.Result`
     """
     try:
-      return ExecutionResult.finished(self.schedule(execution_request))
+      return ExecutionResult.finished(self.schedule(execution_request, on_root=on_root))
     except TaskError as e:
       return ExecutionResult.failure(e)
 
-  def products_request(self, products, subjects):
+  def products_request(self, products, subjects, keep_going=False):
     """Executes a request for multiple products for some subjects, and returns the products.
 
+    Each root of the request is computed independently, so a failure for one root does not prevent
+    the others from completing. By default, any failure is raised once all roots have completed.
+
     :param list products: A list of product type for the request.
     :param list subjects: A list of subjects for the request.
+    :param bool keep_going: If True, failed roots do not raise: instead, the `Throw` state for each
+      failed root is returned in its position in the output lists.
     :returns: A dict from product type to lists of products each with length matching len(subjects).
     """
     request = self.execution_request(products, subjects)
@@ -536,42 +1064,67 @@ class SchedulerSession(object):
     # Throw handling.
     # TODO: See https://github.com/pantsbuild/pants/issues/3912
     throw_root_states = tuple(state for root, state in result.root_products if type(state) is Throw)
-    if throw_root_states:
+    if throw_root_states and not keep_going:
       if self._scheduler.include_trace_on_error:
         cumulative_trace = '\n'.join(self.trace(request))
         raise ExecutionError('Received unexpected Throw state(s):\n{}'.format(cumulative_trace))
 
-      unique_exceptions = set(t.exc for t in throw_root_states)
-      if len(unique_exceptions) == 1:
+      unique_throws = OrderedDict()
+      for t in throw_root_states:
+        unique_throws.setdefault(t.exc, t)
+      if len(unique_throws) == 1:
         raise throw_root_states[0].exc
       else:
-        raise ExecutionError('Multiple exceptions encountered:\n  {}'
-                             .format('\n  '.join('{}: {}'.format(type(t).__name__, str(t))
-                                                                 for t in unique_exceptions)))
-
-    # Everything is a Return: we rely on the fact that roots are ordered to preserve subject
-    # order in output lists.
+        def render(t):
+          lines = t.render_engine_traceback().split('\n')
+          lines.append('{}: {}'.format(type(t.exc).__name__, str(t.exc)))
+          return '\n'.join('  {}'.format(line) for line in lines)
+        raise ExecutionError('Multiple exceptions encountered:\n{}'
+                             .format('\n'.join(render(t) for t in unique_throws.values())))
+
+    # Everything is a Return (or we are keeping going): we rely on the fact that roots are ordered
+    # to preserve subject order in output lists.
     product_results = defaultdict(list)
     for (_, product), state in result.root_products:
-      product_results[product].append(state.value)
+      product_results[product].append(state if type(state) is Throw else state.value)
     return product_results
 
-  def product_request(self, product, subjects):
+  def product_request(self, product, subjects, keep_going=False):
     """Executes a request for a single product for some subjects, and returns the products.
 
     :param class product: A product type for the request.
     :param list subjects: A list of subjects for the request.
+    :param bool keep_going: See `products_request`.
     :returns: A list of the requested products, with length match len(subjects).
     """
-    return self.products_request([product], subjects)[product]
+    return self.products_request([product], subjects, keep_going=keep_going)[product]
+
+  def product_tuple_request(self, products, subjects, keep_going=False):
+    """Executes a request for several products for each of some subjects, in a single root each.
+
+    Because all of the products for a subject are computed by one root, any rules that they have in
+    common for the subject run only once, and a failure to compute any of them fails the root.
+
+    :param tuple products: The product types to request for each subject.
+    :param list subjects: A list of subjects for the request.
+    :param bool keep_going: See `products_request`.
+    :returns: A list with length matching len(subjects), of tuples of the requested products in the
+      order they were given.
+    """
+    products = tuple(products)
+    return self.products_request([products], subjects, keep_going=keep_going)[products]
 
   def capture_snapshots(self, path_globs_and_roots):
     """Synchronously captures Snapshots for each matching PathGlobs rooted at a its root directory.
 
     This is a blocking operation, and should be avoided where possible.
 
-    :param path_globs_and_roots tuple<PathGlobsAndRoot>: The PathGlobs to capture, and the root
-           directory relative to which each should be captured.
+    Roots may be outside of the buildroot (for example, a system toolchain in /usr/lib/jvm), but
+    are not watched for changes, so the captured files should be immutable. Absolute symlinks are
+    supported if they point inside of their root. This API is deliberately not available to @rules.
+
+    :param path_globs_and_roots tuple<PathGlobsAndRoot>: The PathGlobs to capture, and the absolute
+           path of the root directory relative to which each should be captured.
     :returns: A tuple of Snapshots.
     """
     return self._scheduler.capture_snapshots(path_globs_and_roots)
@@ -579,17 +1132,45 @@ class SchedulerSession(object):
   def merge_directories(self, directory_digests):
     return self._scheduler.merge_directories(directory_digests)
 
-  def materialize_directories(self, directories_paths_and_digests):
+  def execute_process_request_digests(self, execute_process_request):
+    return self._scheduler.execute_process_request_digests(execute_process_request)
+
+  def explain_process_request_diff(self, left_archive_dir, left_action_fingerprint,
+                                   right_archive_dir, right_action_fingerprint):
+    return self._scheduler.explain_process_request_diff(
+      left_archive_dir, left_action_fingerprint, right_archive_dir, right_action_fingerprint)
+
+  def materialize_directories(self, directories_paths_and_digests, replace=False):
     """Creates the specified directories on the file system.
 
     :param directories_paths_and_digests tuple<DirectoryToMaterialize>: Tuple of the path and
            digest of the directories to materialize.
+    :param bool replace: If True, atomically replaces any existing contents of the directories:
+           see `Scheduler.materialize_directories`.
     :returns: Nothing or an error.
     """
-    return self._scheduler.materialize_directories(directories_paths_and_digests)
+    return self._scheduler.materialize_directories(directories_paths_and_digests, replace=replace)
+
+  def fake_process_result(self, execute_process_request, result):
+    self._scheduler.fake_process_result(execute_process_request, result)
+
+  def fake_process_invocations(self):
+    return self._scheduler.fake_process_invocations()
 
   def lease_files_in_graph(self):
     self._scheduler.lease_files_in_graph()
 
+  def lease_digests(self, digests, duration_seconds):
+    return self._scheduler.lease_digests(digests, duration_seconds)
+
+  def release_digests(self, digests):
+    return self._scheduler.release_digests(digests)
+
+  def load_bytes(self, digest):
+    return self._scheduler.load_bytes(digest)
+
   def garbage_collect_store(self):
     self._scheduler.garbage_collect_store()
+
+  def flush_uploads(self):
+    self._scheduler.flush_uploads()
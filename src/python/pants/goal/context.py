@@ -160,6 +160,9 @@ class Context(object):
     """A contextmanager that sets metrics in the context of a (v1) engine execution."""
     self._set_target_root_count_in_runtracker()
     yield
+    # Outputs are written through to the remote store (if any) in the background, so wait for them
+    # to be uploaded before the run completes.
+    self._scheduler.flush_uploads()
     self.run_tracker.pantsd_stats.set_scheduler_metrics(self._scheduler.metrics())
     self._set_affected_target_count_in_runtracker()
 
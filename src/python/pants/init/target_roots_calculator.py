@@ -15,6 +15,7 @@ from pants.base.cmd_line_spec_parser import CmdLineSpecParser
 from pants.base.specs import DescendantAddresses, SingleAddress, Specs
 from pants.base.target_roots import TargetRoots
 from pants.build_graph.address import Address
+from pants.engine.fs import ChangedFiles, ChangedFilesRequest
 from pants.engine.legacy.graph import TransitiveHydratedTargets, target_types_from_symbol_table
 from pants.engine.legacy.source_mapper import EngineSourceMapper
 from pants.goal.workspace import ScmWorkspace
@@ -187,11 +188,14 @@ class ChangeCalculator(object):
     :param scheduler: The `Scheduler` instance to use for computing file to target mappings.
     :param symbol_table: The symbol table.
     :param scm: The `Scm` instance to use for change determination.
+    :param workspace: The `Workspace` to use to determine changed files. If not specified, the
+                      files changed since a revision are computed by the engine.
     """
     self._scm = scm or get_scm()
     self._scheduler = scheduler
     self._symbol_table = symbol_table
     self._mapper = EngineSourceMapper(self._scheduler)
+    self._explicit_workspace = workspace is not None
     self._workspace = workspace or ScmWorkspace(scm)
     self._changes_since = changes_since
     self._diffspec = diffspec
@@ -203,7 +207,11 @@ class ChangeCalculator(object):
       return self._workspace.changes_in(diffspec)
 
     changes_since = changes_since or self._changes_since or self._scm.current_rev_identifier()
-    return self._workspace.touched_files(changes_since)
+    if self._explicit_workspace:
+      return self._workspace.touched_files(changes_since)
+    changed_files, = self._scheduler.product_request(ChangedFiles,
+                                                     [ChangedFilesRequest(changes_since)])
+    return set(changed_files.paths)
 
   def iter_changed_target_addresses(self, changed_request):
     """Given a `ChangedRequest`, compute and yield all affected target addresses."""
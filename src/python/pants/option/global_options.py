@@ -64,8 +64,44 @@ class ExecutionOptions(datatype([
   'remote_execution_server',
   'remote_store_chunk_bytes',
   'remote_store_chunk_upload_timeout_seconds',
+  'remote_store_metadata_timeout_seconds',
+  'remote_store_session_timeout_seconds',
+  'remote_store_write_through',
+  'remote_store_max_concurrent_transfers',
+  'remote_store_max_bytes_per_second',
   'process_execution_parallelism',
   'process_execution_cleanup_local_dirs',
+  'process_termination_grace_period_seconds',
+  'process_execution_sandbox_pool_size',
+  'process_execution_max_output_bytes',
+  'process_execution_output_overflow',
+  'process_execution_inherited_env',
+  'process_execution_macos_sandbox',
+  'process_execution_macos_sandbox_cache_dirs',
+  'process_execution_record_dir',
+  'process_execution_replay_dir',
+  'process_execution_cache_dir',
+  'remote_execution_speculation_delay_ms',
+  'transient_failure_retry_attempts',
+  'transient_failure_retry_backoff_ms',
+  'nondeterministic_rule_check_rate',
+  'stall_timeout_seconds',
+  'cancel_stalled_executions',
+  'background_process_execution_parallelism',
+  'background_io_parallelism',
+  'graph_node_count_warning',
+  'graph_node_count_limit',
+  'graph_depth_warning',
+  'graph_depth_limit',
+  'special_file_behavior',
+  'link_escape_behavior',
+  'snapshot_cache_dir',
+  'engine_temp_dir',
+  'materialize_hardlinks',
+  'local_store_shard_count',
+  'local_store_max_shard_bytes',
+  'intrinsic_plugins',
+  'thread_name_prefix',
 ])):
   """A collection of all options related to (remote) execution of processes.
 
@@ -81,8 +117,44 @@ class ExecutionOptions(datatype([
       remote_store_thread_count=bootstrap_options.remote_store_thread_count,
       remote_store_chunk_bytes=bootstrap_options.remote_store_chunk_bytes,
       remote_store_chunk_upload_timeout_seconds=bootstrap_options.remote_store_chunk_upload_timeout_seconds,
+      remote_store_metadata_timeout_seconds=bootstrap_options.remote_store_metadata_timeout_seconds,
+      remote_store_session_timeout_seconds=bootstrap_options.remote_store_session_timeout_seconds,
+      remote_store_write_through=bootstrap_options.remote_store_write_through,
+      remote_store_max_concurrent_transfers=bootstrap_options.remote_store_max_concurrent_transfers,
+      remote_store_max_bytes_per_second=bootstrap_options.remote_store_max_bytes_per_second,
       process_execution_parallelism=bootstrap_options.process_execution_parallelism,
       process_execution_cleanup_local_dirs=bootstrap_options.process_execution_cleanup_local_dirs,
+      process_termination_grace_period_seconds=bootstrap_options.process_termination_grace_period_seconds,
+      process_execution_sandbox_pool_size=bootstrap_options.process_execution_sandbox_pool_size,
+      process_execution_max_output_bytes=bootstrap_options.process_execution_max_output_bytes,
+      process_execution_output_overflow=bootstrap_options.process_execution_output_overflow,
+      process_execution_inherited_env=tuple(bootstrap_options.process_execution_inherited_env),
+      process_execution_macos_sandbox=bootstrap_options.process_execution_macos_sandbox,
+      process_execution_macos_sandbox_cache_dirs=tuple(bootstrap_options.process_execution_macos_sandbox_cache_dirs),
+      process_execution_record_dir=bootstrap_options.process_execution_record_dir,
+      process_execution_replay_dir=bootstrap_options.process_execution_replay_dir,
+      process_execution_cache_dir=bootstrap_options.process_execution_cache_dir,
+      remote_execution_speculation_delay_ms=bootstrap_options.remote_execution_speculation_delay_ms,
+      transient_failure_retry_attempts=bootstrap_options.transient_failure_retry_attempts,
+      transient_failure_retry_backoff_ms=bootstrap_options.transient_failure_retry_backoff_ms,
+      nondeterministic_rule_check_rate=bootstrap_options.nondeterministic_rule_check_rate,
+      stall_timeout_seconds=bootstrap_options.stall_timeout_seconds,
+      cancel_stalled_executions=bootstrap_options.cancel_stalled_executions,
+      background_process_execution_parallelism=bootstrap_options.background_process_execution_parallelism,
+      background_io_parallelism=bootstrap_options.background_io_parallelism,
+      graph_node_count_warning=bootstrap_options.graph_node_count_warning,
+      graph_node_count_limit=bootstrap_options.graph_node_count_limit,
+      graph_depth_warning=bootstrap_options.graph_depth_warning,
+      graph_depth_limit=bootstrap_options.graph_depth_limit,
+      special_file_behavior=bootstrap_options.special_file_behavior,
+      link_escape_behavior=bootstrap_options.link_escape_behavior,
+      snapshot_cache_dir=bootstrap_options.snapshot_cache_dir,
+      engine_temp_dir=bootstrap_options.engine_temp_dir,
+      materialize_hardlinks=bootstrap_options.materialize_hardlinks,
+      local_store_shard_count=bootstrap_options.local_store_shard_count,
+      local_store_max_shard_bytes=bootstrap_options.local_store_max_shard_bytes,
+      intrinsic_plugins=tuple(bootstrap_options.intrinsic_plugins),
+      thread_name_prefix=bootstrap_options.thread_name_prefix,
     )
 
 
@@ -92,8 +164,44 @@ DEFAULT_EXECUTION_OPTIONS = ExecutionOptions(
     remote_execution_server=None,
     remote_store_chunk_bytes=1024*1024,
     remote_store_chunk_upload_timeout_seconds=60,
+    remote_store_metadata_timeout_seconds=30,
+    remote_store_session_timeout_seconds=None,
+    remote_store_write_through='lazy',
+    remote_store_max_concurrent_transfers=0,
+    remote_store_max_bytes_per_second=0,
     process_execution_parallelism=multiprocessing.cpu_count()*2,
     process_execution_cleanup_local_dirs=True,
+    process_termination_grace_period_seconds=3,
+    process_execution_sandbox_pool_size=4,
+    process_execution_max_output_bytes=256*1024*1024,
+    process_execution_output_overflow='truncate',
+    process_execution_inherited_env=(),
+    process_execution_macos_sandbox=False,
+    process_execution_macos_sandbox_cache_dirs=(),
+    process_execution_record_dir=None,
+    process_execution_replay_dir=None,
+    process_execution_cache_dir=None,
+    remote_execution_speculation_delay_ms=0,
+    transient_failure_retry_attempts=2,
+    transient_failure_retry_backoff_ms=100,
+    nondeterministic_rule_check_rate=0.0,
+    stall_timeout_seconds=300,
+    cancel_stalled_executions=False,
+    background_process_execution_parallelism=1,
+    background_io_parallelism=2,
+    graph_node_count_warning=5000000,
+    graph_node_count_limit=0,
+    graph_depth_warning=1000,
+    graph_depth_limit=0,
+    special_file_behavior='warn',
+    link_escape_behavior='warn',
+    snapshot_cache_dir=None,
+    engine_temp_dir=None,
+    materialize_hardlinks=False,
+    local_store_shard_count=16,
+    local_store_max_shard_bytes=1024 * 1024 * 1024 * 1024 // 10,
+    intrinsic_plugins=(),
+    thread_name_prefix='',
   )
 
 
@@ -206,7 +314,8 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              default=['.*/', default_rel_distdir],
              help='Paths to ignore for all filesystem operations performed by pants '
                   '(e.g. BUILD file scanning, glob matching, etc). '
-                  'Patterns use the gitignore syntax (https://git-scm.com/docs/gitignore).')
+                  'Patterns use the gitignore syntax (https://git-scm.com/docs/gitignore), except '
+                  'that paths within an ignored directory may be re-included by `!` patterns.')
     register('--glob-expansion-failure', type=str,
              choices=GlobMatchErrorBehavior.allowed_values,
              default=GlobMatchErrorBehavior.default_option_value,
@@ -240,6 +349,26 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--native-engine-visualize-to', advanced=True, default=None, type=dir_option, daemon=False,
              help='A directory to write execution and rule graphs to as `dot` files. The contents '
                   'of the directory will be overwritten if any filenames collide.')
+    register('--native-engine-log-levels', advanced=True, type=dict, default={}, daemon=False,
+             help='A dict from native engine module path (such as `process_execution::remote`) to '
+                  'the log level to use for that module and its submodules. Changes take effect '
+                  'for a running pantsd without restarting it.')
+    register('--native-engine-log-file-level', advanced=True,
+             choices=['trace', 'debug', 'info', 'warn'], default='info',
+             help='The log level for the native engine log file, which is written to '
+                  '<pants-workdir>/native-engine.log independent of python logging.')
+    register('--native-engine-log-file-max-bytes', advanced=True, type=int,
+             default=10 * 1024 * 1024,
+             help='Rotate the native engine log file when it would exceed this many bytes.')
+    register('--native-engine-log-file-max-age-seconds', advanced=True, type=int,
+             default=24 * 60 * 60,
+             help='Rotate the native engine log file when it is older than this many seconds.')
+    register('--native-engine-log-file-count', advanced=True, type=int, default=3,
+             help='The number of rotated native engine log files to keep.')
+    register('--native-engine-visualize-min-duration-ms', advanced=True, type=int, default=0,
+             daemon=False,
+             help='When visualizing executions with --native-engine-visualize-to, omit nodes that '
+                  'ran for less than this many milliseconds.')
     register('--print-exception-stacktrace', advanced=True, type=bool,
              help='Print to console the full exception stack trace if encountered.')
 
@@ -266,12 +395,26 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              help='The host to bind the pants nailgun server to.')
     register('--pantsd-pailgun-port', advanced=True, type=int, default=0,
              help='The port to bind the pants nailgun server to. Defaults to a random port.')
+    register('--pantsd-query-server', advanced=True, type=bool, default=False,
+             help='If set, serve engine products to local clients (such as IDEs) over gRPC on a '
+                  'unix socket under the pantsd workdir, which only the current user may connect '
+                  'to. The path of the socket is recorded as the `query` socket in the pantsd '
+                  'metadata directory.')
+    register('--pantsd-idle-memory-trim-minutes', advanced=True, type=float, default=10,
+             help='After pantsd has been idle for this many minutes, release the memory that it '
+                  'holds to speed up subsequent runs (and which would otherwise remain at its '
+                  'peak after a large build). 0 disables trimming.')
     register('--pantsd-log-dir', advanced=True, default=None,
              help='The directory to log pantsd output to.')
     register('--pantsd-fs-event-workers', advanced=True, type=int, default=4,
              help='The number of workers to use for the filesystem event service executor pool.')
     register('--pantsd-invalidation-globs', advanced=True, type=list, fromfile=True, default=[],
              help='Filesystem events matching any of these globs will trigger a daemon restart.')
+    register('--pantsd-invalidation-batch-window', advanced=True, type=float, default=0.05,
+             help='The number of seconds to wait for further filesystem events after an event '
+                  'before invalidating, so that a burst of changes (such as an editor saving many '
+                  'files, or a branch switch) is invalidated in a single pass. Events which are '
+                  'already queued are always invalidated together, even if this is 0.')
 
     # Watchman options.
     register('--watchman-version', advanced=True, default='4.9.0-pants1', help='Watchman version.')
@@ -305,7 +448,33 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
              help='Size in bytes of chunks transferred to/from the remote file store.')
     register('--remote-store-chunk-upload-timeout-seconds', type=int, advanced=True,
              default=DEFAULT_EXECUTION_OPTIONS.remote_store_chunk_upload_timeout_seconds,
-             help='Timeout (in seconds) for uploads of individual chunks to the remote file store.')
+             help='Timeout (in seconds) for uploads and downloads of individual blobs to and from '
+                  'the remote file store.')
+    register('--remote-store-metadata-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_metadata_timeout_seconds,
+             help='Timeout (in seconds) for calls to the remote file store which do not transfer '
+                  'file content, such as checking which blobs it is missing.')
+    register('--remote-store-session-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_session_timeout_seconds,
+             help='If set, calls to the remote file store on behalf of a run (or of each request '
+                  'to pantsd) do not wait beyond this many seconds after it started, regardless of '
+                  'their individual timeouts.')
+    register('--remote-store-write-through', advanced=True, choices=['eager', 'lazy', 'never'],
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_write_through,
+             help='When files which are stored locally (such as captured snapshots and the outputs '
+                  'of local processes) are uploaded to the remote file store. `eager` uploads them '
+                  'in the background as soon as they are produced (and waits for the uploads at '
+                  'the end of each run), `lazy` only when a remote execution needs them, '
+                  'and `never` treats the remote file store as a read-only cache, which cannot be '
+                  'combined with remote execution.')
+    register('--remote-store-max-concurrent-transfers', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_concurrent_transfers,
+             help='The maximum number of files which may be uploaded to or downloaded from the '
+                  'remote file store at once. 0 means unlimited.')
+    register('--remote-store-max-bytes-per-second', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_store_max_bytes_per_second,
+             help='The maximum combined rate (in bytes per second) at which files may be uploaded '
+                  'to and downloaded from the remote file store. 0 means unlimited.')
 
     # This should eventually deprecate the RunTracker worker count, which is used for legacy cache
     # lookups via CacheSetup in TaskBase.
@@ -315,6 +484,171 @@ class GlobalOptionsRegistrar(SubsystemClientMixin, Optionable):
     register('--process-execution-cleanup-local-dirs', type=bool, default=True,
              help='Whether or not to cleanup directories used for local process execution '
                   '(primarily useful for e.g. debugging).')
+    register('--process-termination-grace-period-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_termination_grace_period_seconds,
+             help='When a process that is being executed locally is cancelled, or pantsd shuts '
+                  'down, the process is sent SIGTERM, and then SIGKILL if it has not exited after '
+                  'this many seconds. This gives tools a chance to flush reports and caches.')
+    register('--process-execution-sandbox-pool-size', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_sandbox_pool_size,
+             help='The number of empty sandboxes to keep ready for processes that are executed '
+                  'locally, and the number of copies to keep ready of each input directory (such '
+                  'as a toolchain) which is used by many processes. 0 disables the pool.')
+    register('--process-execution-max-output-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_max_output_bytes,
+             help='The maximum number of bytes of each of the stdout and stderr of a process to '
+                  'capture (or, for a remotely executed process, to keep). A process may lower '
+                  'this limit for itself. See --process-execution-output-overflow for what happens '
+                  'to the rest. 0 disables the limit.')
+    register('--process-execution-output-overflow', type=str, advanced=True,
+             choices=['truncate', 'store'],
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_output_overflow,
+             help='What to do with the output of a process beyond --process-execution-max-output-'
+                  'bytes: drop it, or spill it to disk and then store the complete output, so that '
+                  'it can be inspected by digest. In both cases the captured output ends with a '
+                  'marker saying how much was dropped.')
+    register('--process-execution-inherited-env', type=list, advanced=True,
+             default=list(DEFAULT_EXECUTION_OPTIONS.process_execution_inherited_env),
+             help='The names of environment variables which processes that are executed locally '
+                  'inherit from the environment of pants, unless they declare values for them. '
+                  'Otherwise, a process sees only the variables that it declares. Inherited '
+                  'variables are not part of the cache key of a process, so this is an escape hatch '
+                  'for tools which misbehave without (for example) HOME, at the cost of '
+                  'hermeticity.')
+    register('--process-execution-macos-sandbox', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.process_execution_macos_sandbox,
+             help='On macOS, run processes that are executed locally under a sandbox-exec profile '
+                  'which denies them access to the filesystem outside of their sandbox, the '
+                  'system, and --process-execution-macos-sandbox-cache-dirs. A process which '
+                  'reads an undeclared input then fails, rather than silently depending on it.')
+    register('--process-execution-macos-sandbox-cache-dirs', type=list, advanced=True,
+             default=list(DEFAULT_EXECUTION_OPTIONS.process_execution_macos_sandbox_cache_dirs),
+             help='Directories which processes that are executed under '
+                  '--process-execution-macos-sandbox may read and write, such as caches that are '
+                  'shared between processes.')
+    register('--process-execution-record-dir', advanced=True, default=None,
+             help='If set, record each process that is executed, along with its result and '
+                  'outputs, to an archive in this directory. The archive can be replayed with '
+                  '--process-execution-replay-dir, in order to reproduce a build on a machine '
+                  'without the tools that it used.')
+    register('--process-execution-replay-dir', advanced=True, default=None,
+             help='If set, never execute processes: instead, use the results recorded in this '
+                  'directory by --process-execution-record-dir. Processes that were not recorded '
+                  'fail.')
+    register('--process-execution-cache-dir', advanced=True, default=None,
+             help='If set, serve processes from the results recorded in this directory when '
+                  'possible, and record the results of the processes that are executed to it. '
+                  'Unlike --process-execution-replay-dir, processes that were not recorded are '
+                  'executed.')
+    register('--remote-execution-speculation-delay-ms', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.remote_execution_speculation_delay_ms,
+             help='If remote execution is enabled, also execute each process locally if it has '
+                  'not completed remotely within this many milliseconds, and use whichever result '
+                  'is available first. 0 disables local speculation.')
+    register('--transient-failure-retry-attempts', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.transient_failure_retry_attempts,
+             help='Number of times the engine will retry an operation that failed for a transient '
+                  'reason (such as a remote execution RPC error or an interrupted filesystem '
+                  'operation) before failing. Failures of rules themselves are never retried.')
+    register('--transient-failure-retry-backoff-ms', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.transient_failure_retry_backoff_ms,
+             help='Delay (in milliseconds) before the first retry of a transient failure. The delay '
+                  'doubles for each subsequent retry.')
+    register('--nondeterministic-rule-check-rate', type=float, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.nondeterministic_rule_check_rate,
+             help='The fraction (between 0.0 and 1.0) of rule executions that the engine will '
+                  're-run with identical inputs in order to detect nondeterministic rules, which '
+                  'are reported as warnings. Rules that request further dependencies are not '
+                  'checked.')
+    register('--stall-timeout-seconds', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.stall_timeout_seconds,
+             help='If the engine is running work but none of it has completed for this many '
+                  'seconds, log the in-flight work (including remote operations). 0 disables the '
+                  'check.')
+    register('--cancel-stalled-executions', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.cancel_stalled_executions,
+             help='After logging a stall (see --stall-timeout-seconds), fail the stalled request '
+                  'rather than continuing to wait for it. The work of the request (including any '
+                  'processes that it is running) is stopped.')
+    register('--background-process-execution-parallelism', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.background_process_execution_parallelism,
+             help='The number of processes that may be executed concurrently while only '
+                  'background sessions (which speculatively rebuild on behalf of an idle client) '
+                  'are running. The limit is lifted while a foreground request runs. 0 disables '
+                  'the limit.')
+    register('--background-io-parallelism', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.background_io_parallelism,
+             help='The number of filesystem operations that may run concurrently while only '
+                  'background sessions are running: see '
+                  '--background-process-execution-parallelism. 0 disables the limit.')
+    register('--graph-node-count-warning', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_node_count_warning,
+             help='Warn (once) when the product graph grows to this many nodes, naming the '
+                  'roots that were being computed. 0 disables the warning.')
+    register('--graph-node-count-limit', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_node_count_limit,
+             help='Fail requests which would grow the product graph past this many nodes, '
+                  'rather than running out of memory. 0 disables the limit.')
+    register('--graph-depth-warning', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_depth_warning,
+             help='Warn (once) when a chain of dependencies in the product graph grows deeper '
+                  'than this, which usually indicates that a rule recursively requests itself. '
+                  '0 disables the warning.')
+    register('--graph-depth-limit', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.graph_depth_limit,
+             help='Fail requests whose chains of dependencies in the product graph grow deeper '
+                  'than this, naming the root that they were computed for. 0 disables the limit.')
+    register('--special-file-behavior', type=str, advanced=True,
+             choices=['ignore', 'warn', 'error'],
+             default=DEFAULT_EXECUTION_OPTIONS.special_file_behavior,
+             help='What to do when capturing files encounters a FIFO, socket or device node, '
+                  'which cannot be captured: skip it silently, skip it with a warning naming it, '
+                  'or fail.')
+    register('--link-escape-behavior', type=str, advanced=True,
+             choices=['follow', 'warn', 'error'],
+             default=DEFAULT_EXECUTION_OPTIONS.link_escape_behavior,
+             help='What to do when capturing files encounters a symlink which points outside of '
+                  'the build root: follow it and capture its destination (which is not watched '
+                  'for changes), skip it with a warning naming it (once per run), or fail any '
+                  'glob which matches it.')
+    register('--snapshot-cache-dir', advanced=True, default=None,
+             help='If set, pantsd persists the Snapshots that it captures for globs to this '
+                  'directory, keyed by the state of the filesystem as reported by watchman. A '
+                  'restarted pantsd reuses them if watchman reports that no files have changed '
+                  'since, rather than expanding the globs again.')
+    register('--engine-temp-dir', advanced=True, default=None,
+             help='The directory beneath which the engine creates its temporary directories (such '
+                  'as the sandboxes of local processes), which are deleted when pants exits, or '
+                  'by the next run if pants crashed. Defaults to a directory in the workdir: '
+                  'setting it to a directory on a fast local disk can speed up local process '
+                  'execution.')
+    register('--materialize-hardlinks', type=bool, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.materialize_hardlinks,
+             help='Materialize files (into process sandboxes, for example) as hardlinks to '
+                  'previously materialized files with the same content and permissions where '
+                  'possible, to keep the size of materialized files down. Because hardlinks share '
+                  'their content, this is only safe if materialized files are not modified in '
+                  'place.')
+    register('--local-store-shard-count', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_shard_count,
+             help='The number of files (a power of two, at most 256) that the local file store is '
+                  'split into. Each can be written by one process at a time, so more allow for '
+                  'more concurrent writes. Changing this starts an empty local file store.')
+    register('--local-store-max-shard-bytes', type=int, advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.local_store_max_shard_bytes,
+             help='The size (in bytes) that each file of the local file store may grow to. This is '
+                  'address space rather than disk space, but may need lowering where virtual '
+                  'memory is limited.')
+    register('--intrinsic-plugins', type=list, advanced=True,
+             default=list(DEFAULT_EXECUTION_OPTIONS.intrinsic_plugins),
+             help='Paths to native libraries which provide intrinsics to the engine. Rules refer '
+                  'to the intrinsics that a plugin provides by name, via IntrinsicPluginRule.')
+    register('--thread-name-prefix', advanced=True,
+             default=DEFAULT_EXECUTION_OPTIONS.thread_name_prefix,
+             help='A prefix for the names of the threads of the engine and of pantsd, which are '
+                  'named by role (such as `cpu-pool-N`, `io-pool-N`, `watcher` and `store-gc`) so '
+                  'that OS tools can show which subsystem is busy. Because OS tools may show only '
+                  'the first 15 characters of a thread name, the prefix should be short.')
 
   @classmethod
   def register_options(cls, register):
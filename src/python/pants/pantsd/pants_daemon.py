@@ -15,6 +15,8 @@ from setproctitle import setproctitle as set_process_title
 from pants.base.build_environment import get_buildroot
 from pants.base.exiter import Exiter
 from pants.bin.daemon_pants_runner import DaemonExiter, DaemonPantsRunner
+from pants.engine.addressable import BuildFileAddresses
+from pants.engine.legacy.graph import HydratedTargets, TransitiveHydratedTargets
 from pants.engine.native import Native
 from pants.init.engine_initializer import EngineInitializer
 from pants.init.logging import setup_logging
@@ -25,7 +27,9 @@ from pants.option.options_bootstrapper import OptionsBootstrapper
 from pants.option.options_fingerprinter import OptionsFingerprinter
 from pants.pantsd.process_manager import FingerprintedProcessManager
 from pants.pantsd.service.fs_event_service import FSEventService
+from pants.pantsd.service.memory_trim_service import MemoryTrimService
 from pants.pantsd.service.pailgun_service import PailgunService
+from pants.pantsd.service.query_service import QueryService
 from pants.pantsd.service.scheduler_service import SchedulerService
 from pants.pantsd.service.store_gc_service import StoreGCService
 from pants.pantsd.watchman_launcher import WatchmanLauncher
@@ -184,6 +188,7 @@ class PantsDaemon(FingerprintedProcessManager):
         build_root,
         bootstrap_options.pantsd_invalidation_globs,
         pidfile,
+        invalidation_batch_window=bootstrap_options.pantsd_invalidation_batch_window,
       )
 
       pailgun_service = PailgunService(
@@ -196,11 +201,27 @@ class PantsDaemon(FingerprintedProcessManager):
 
       store_gc_service = StoreGCService(legacy_graph_scheduler.scheduler)
 
+      services = [fs_event_service, scheduler_service, pailgun_service, store_gc_service]
+      if bootstrap_options.pantsd_idle_memory_trim_minutes > 0:
+        services.append(MemoryTrimService(legacy_graph_scheduler.scheduler,
+                                          bootstrap_options.pantsd_idle_memory_trim_minutes))
+      port_map = dict(pailgun=pailgun_service.pailgun_port)
+
+      if bootstrap_options.pantsd_query_server:
+        query_service = QueryService(
+          legacy_graph_scheduler.scheduler,
+          build_root,
+          os.path.join(bootstrap_options.pants_workdir, 'pantsd', 'query'),
+          (BuildFileAddresses, HydratedTargets, TransitiveHydratedTargets),
+        )
+        services.append(query_service)
+        port_map['query'] = query_service.query_socket
+
       return (
         # Services.
-        (fs_event_service, scheduler_service, pailgun_service, store_gc_service),
+        tuple(services),
         # Port map.
-        dict(pailgun=pailgun_service.pailgun_port)
+        port_map
       )
 
   def __init__(self, native, build_root, work_dir, log_level, services, socket_map,
@@ -309,9 +330,17 @@ class PantsDaemon(FingerprintedProcessManager):
       self._logger.info('setting up service {}'.format(service))
       service.setup(self._lifecycle_lock, self._fork_lock)
 
-  @staticmethod
-  def _make_thread(target):
-    t = threading.Thread(target=target)
+  def _make_thread(self, service):
+    """Creates a daemon thread to run the given service, which is named for the service's role."""
+    def target():
+      if self._native:
+        try:
+          self._native.set_thread_name(service.thread_role)
+        except Exception as e:
+          self._logger.warn('failed to name the thread of {}: {!r}'.format(service, e))
+      service.run()
+
+    t = threading.Thread(target=target, name=service.thread_role)
     t.daemon = True
     return t
 
@@ -321,7 +350,7 @@ class PantsDaemon(FingerprintedProcessManager):
       self._logger.critical('no services to run, bailing!')
       return
 
-    service_thread_map = {service: self._make_thread(service.run) for service in services}
+    service_thread_map = {service: self._make_thread(service) for service in services}
 
     # Start services.
     for service, service_thread in service_thread_map.items():
@@ -360,6 +389,8 @@ class PantsDaemon(FingerprintedProcessManager):
       self._logger.info('pantsd starting, log level is {}'.format(self._log_level))
 
       self._native.set_panic_handler()
+      self._native.set_graph_dump_signal_handler()
+      self._native.set_cpu_profile_signal_handler()
 
       # Set the process name in ps output to 'pantsd' vs './pants compile src/etc:: -ldebug'.
       set_process_title('pantsd [{}]'.format(self._build_root))
@@ -24,6 +24,8 @@ class FSEventService(PantsService):
 
   ZERO_DEPTH = ['depth', 'eq', 0]
 
+  thread_role = 'watcher'
+
   PANTS_PID_SUBSCRIPTION_NAME = 'pantsd_pid'
 
   def __init__(self, watchman, build_root, worker_count):
@@ -52,34 +54,36 @@ class FSEventService(PantsService):
       self._executor.shutdown()
     super(FSEventService, self).terminate()
 
-  def register_all_files_handler(self, callback, name='all_files'):
+  def register_all_files_handler(self, callback, name='all_files', since=None):
     """Registers a subscription for all files under a given watch path.
 
     :param func callback: the callback to execute on each filesystem event
     :param str name:      the subscription name as used by watchman
+    :param str since:     if set, a watchman clock: the first event will then list the files that
+                          changed since that clock, rather than being a fresh instance listing all
+                          files (unless watchman can no longer answer for that clock).
     """
-    self.register_handler(
-      name,
-      dict(
-        fields=['name'],
-        # Request events for all file types.
-        # NB: Touching a file invalidates its parent directory due to:
-        #   https://github.com/facebook/watchman/issues/305
-        # ...but if we were to skip watching directories, we'd still have to invalidate
-        # the parents of any changed files, and we wouldn't see creation/deletion of
-        # empty directories.
-        expression=[
-          'allof',  # All of the below rules must be true to match.
-          ['not', ['dirname', 'dist', self.ZERO_DEPTH]],  # Exclude the ./dist dir.
-          # N.B. 'wholename' ensures we match against the absolute ('x/y/z') vs base path ('z').
-          ['not', ['pcre', r'^\..*', 'wholename']],  # Exclude files in hidden dirs (.pants.d etc).
-          ['not', ['match', '*.pyc']]  # Exclude .pyc files.
-          # TODO(kwlzn): Make exclusions here optionable.
-          # Related: https://github.com/pantsbuild/pants/issues/2956
-        ]
-      ),
-      callback
+    metadata = dict(
+      fields=['name'],
+      # Request events for all file types.
+      # NB: Touching a file invalidates its parent directory due to:
+      #   https://github.com/facebook/watchman/issues/305
+      # ...but if we were to skip watching directories, we'd still have to invalidate
+      # the parents of any changed files, and we wouldn't see creation/deletion of
+      # empty directories.
+      expression=[
+        'allof',  # All of the below rules must be true to match.
+        ['not', ['dirname', 'dist', self.ZERO_DEPTH]],  # Exclude the ./dist dir.
+        # N.B. 'wholename' ensures we match against the absolute ('x/y/z') vs base path ('z').
+        ['not', ['pcre', r'^\..*', 'wholename']],  # Exclude files in hidden dirs (.pants.d etc).
+        ['not', ['match', '*.pyc']]  # Exclude .pyc files.
+        # TODO(kwlzn): Make exclusions here optionable.
+        # Related: https://github.com/pantsbuild/pants/issues/2956
+      ]
     )
+    if since:
+      metadata['since'] = since
+    self.register_handler(name, metadata, callback)
 
   def register_pidfile_handler(self, pidfile_path, callback):
     """
@@ -0,0 +1,48 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+import logging
+
+from pants.pantsd.service.pants_service import PantsService
+
+
+class MemoryTrimService(PantsService):
+  """Memory Trimming Service.
+
+  The engine holds on to memory which speeds up subsequent runs, so after a large build pantsd would
+  otherwise remain at its peak size for as long as it runs. Once pantsd has been idle for a while,
+  this service releases the memory that can be recomputed (see `Scheduler.trim_memory`).
+  """
+
+  thread_role = 'memory-trim'
+
+  # The most often that idleness is checked for, and the least often.
+  _MIN_CHECK_INTERVAL_SECONDS = 1
+  _MAX_CHECK_INTERVAL_SECONDS = 60
+
+  def __init__(self, scheduler, idle_minutes):
+    super(MemoryTrimService, self).__init__()
+    self._scheduler = scheduler
+    self._min_idle_seconds = idle_minutes * 60
+    self._check_interval_seconds = min(
+      max(self._min_idle_seconds / 10, self._MIN_CHECK_INTERVAL_SECONDS),
+      self._MAX_CHECK_INTERVAL_SECONDS,
+    )
+    self._logger = logging.getLogger(__name__)
+
+  def _maybe_trim(self):
+    # Use the fork lock to ensure this thread isn't cloned via fork while holding the graph lock.
+    with self.fork_lock:
+      report = self._scheduler.trim_memory(self._min_idle_seconds)
+    if report:
+      self._logger.info('Trimmed memory after {} idle minutes: {}'.format(
+        self._min_idle_seconds / 60, report))
+
+  def run(self):
+    """Main service entrypoint. Called via Thread.start() via PantsDaemon.run()."""
+    while not self.is_killed:
+      self._maybe_trim()
+      self._kill_switch.wait(self._check_interval_seconds)
@@ -19,6 +19,8 @@ from pants.pantsd.service.pants_service import PantsService
 class PailgunService(PantsService):
   """A service that runs the Pailgun server."""
 
+  thread_role = 'pailgun'
+
   def __init__(self, bind_addr, exiter_class, runner_class, target_roots_calculator, scheduler_service):
     """
     :param tuple bind_addr: The (hostname, port) tuple to bind the Pailgun server to.
@@ -15,6 +15,9 @@ class PantsService(AbstractClass):
 
   class ServiceError(Exception): pass
 
+  # The role that the thread which runs this service is named for: see `Native.set_thread_name`.
+  thread_role = 'service'
+
   def __init__(self):
     super(PantsService, self).__init__()
     self.name = self.__class__.__name__
@@ -0,0 +1,58 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+import logging
+
+from pants.engine.query import QueryHandler
+from pants.pantsd.service.pants_service import PantsService
+
+
+class QueryService(PantsService):
+  """A service that serves products computed by the resident scheduler over gRPC.
+
+  This allows local clients (such as IDEs and editors) to query the engine without spawning a
+  pants process per query. The server listens on a unix socket which only the current user may
+  connect to.
+  """
+
+  thread_role = 'query-server'
+
+  def __init__(self, scheduler, build_root, socket_dir, products):
+    """
+    :param Scheduler scheduler: The resident scheduler.
+    :param string build_root: The build root.
+    :param string socket_dir: The directory to create the query server's socket in.
+    :param list products: The product types which may be queried.
+    """
+    super(QueryService, self).__init__()
+    self._handler = QueryHandler(build_root, products)
+    self._query_server = scheduler.new_query_server(self._handler)
+    self._socket_dir = socket_dir
+    self._logger = logging.getLogger(__name__)
+    self._query_socket = None
+
+  @property
+  def query_socket(self):
+    if self._query_socket is None:
+      self._query_socket = self._query_server.start(self._socket_dir)
+    return self._query_socket
+
+  def setup(self, lifecycle_lock, fork_lock):
+    super(QueryService, self).setup(lifecycle_lock, fork_lock)
+    # Prevent forks while a query is using the Graph.
+    self._handler.lock = fork_lock
+
+  def run(self):
+    """Main service entrypoint. Called via Thread.start() via PantsDaemon.run()."""
+    self._logger.info('starting query server on {}'.format(self.query_socket))
+    # Requests are served by the engine's threads: wait to be terminated.
+    while not self.is_killed:
+      self._kill_switch.wait(1)
+
+  def terminate(self):
+    """Override of PantsService.terminate() that stops the query server."""
+    self._query_server.stop()
+    super(QueryService, self).terminate()
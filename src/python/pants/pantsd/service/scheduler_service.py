@@ -8,6 +8,7 @@ import logging
 import os
 import Queue
 import threading
+import time
 
 from twitter.common.dirutil import Fileset
 
@@ -22,7 +23,11 @@ class SchedulerService(PantsService):
   in memory.
   """
 
+  thread_role = 'scheduler'
+
   QUEUE_SIZE = 64
+  # How long running executions are given to complete when the service is terminated.
+  SHUTDOWN_TIMEOUT_SECONDS = 5
 
   def __init__(
     self,
@@ -31,6 +36,7 @@ class SchedulerService(PantsService):
     build_root,
     invalidation_globs,
     pantsd_pidfile,
+    invalidation_batch_window=0,
   ):
     """
     :param FSEventService fs_event_service: An unstarted FSEventService instance for setting up
@@ -40,6 +46,9 @@ class SchedulerService(PantsService):
     :param str build_root: The current build root.
     :param list invalidation_globs: A list of `globs` that when encountered in filesystem event
                                     subscriptions will tear down the daemon.
+    :param float invalidation_batch_window: The number of seconds to wait for further filesystem
+                                            events after an event, before invalidating all of
+                                            their files at once.
     """
     super(SchedulerService, self).__init__()
     self._fs_event_service = fs_event_service
@@ -47,12 +56,15 @@ class SchedulerService(PantsService):
     self._invalidation_globs = invalidation_globs
     self._build_root = build_root
     self._pantsd_pidfile = pantsd_pidfile
+    self._invalidation_batch_window = invalidation_batch_window
 
     self._scheduler = legacy_graph_scheduler.scheduler
     self._logger = logging.getLogger(__name__)
     self._event_queue = Queue.Queue(maxsize=self.QUEUE_SIZE)
     self._watchman_is_running = threading.Event()
     self._invalidating_files = set()
+    self._subscriptions_seen = set()
+    self._persisted_snapshot_cache_generation = None
 
   @staticmethod
   def _combined_invalidating_fileset_from_globs(glob_strs, root):
@@ -61,8 +73,14 @@ class SchedulerService(PantsService):
   def setup(self, lifecycle_lock, fork_lock):
     """Service setup."""
     super(SchedulerService, self).setup(lifecycle_lock, fork_lock)
+    # If a previous pantsd persisted Snapshots, subscribe to the files which have changed since the
+    # generation that they were persisted under (see `--snapshot-cache-dir`).
+    self._persisted_snapshot_cache_generation = self._scheduler.snapshot_cache_generation()
     # Register filesystem event handlers on an FSEventService instance.
-    self._fs_event_service.register_all_files_handler(self._enqueue_fs_event)
+    self._fs_event_service.register_all_files_handler(
+      self._enqueue_fs_event,
+      since=self._persisted_snapshot_cache_generation,
+    )
 
     # N.B. We compute this combined set eagerly at launch with an assumption that files
     # that exist at startup are the only ones that can affect the running daemon.
@@ -119,35 +137,94 @@ class SchedulerService(PantsService):
     with self.fork_lock:
       self._scheduler.invalidate_files(files)
 
+  def _update_snapshot_cache_generation(self, event, is_initial_event, files):
+    """Sets the generation under which Snapshots are cached to the clock of a watchman event.
+
+    Invalidating files disables the snapshot cache until the next generation is set, so this must
+    be called after any invalidation for the event. If nothing has changed since the persisted
+    generation, it is kept, so that the Snapshots persisted under it are reused.
+    """
+    if is_initial_event and not event['is_fresh_instance'] and not files:
+      generation = self._persisted_snapshot_cache_generation
+    else:
+      generation = event.get('clock')
+    if generation:
+      self._scheduler.set_snapshot_cache_generation(generation)
+
+  def _await_event_batch(self, first_event):
+    """Collects the events which are queued within the batch window after the given event.
+
+    Events which are already queued are collected even if the window has passed.
+    """
+    events = [first_event]
+    deadline = time.time() + self._invalidation_batch_window
+    while True:
+      remaining = deadline - time.time()
+      try:
+        if remaining > 0:
+          events.append(self._event_queue.get(timeout=remaining))
+        else:
+          events.append(self._event_queue.get_nowait())
+      except Queue.Empty:
+        return events
+
   def _process_event_queue(self):
-    """File event notification queue processor."""
+    """File event notification queue processor.
+
+    Events are processed in batches (see `_await_event_batch`): the changed files of all of the
+    events in a batch are deduplicated and invalidated in a single pass over the graph.
+    """
     try:
       event = self._event_queue.get(timeout=1)
     except Queue.Empty:
       return
 
-    try:
-      subscription, is_initial_event, files = (event['subscription'],
-                                               event['is_fresh_instance'],
-                                               [f.decode('utf-8') for f in event['files']])
-    except (KeyError, UnicodeDecodeError) as e:
-      self._logger.warn('%r raised by invalid watchman event: %s', e, event)
-      return
+    events = self._await_event_batch(event)
+    changed_files = set()
+    check_pidfile = False
+    # Updates to the snapshot cache generation, which must be applied after invalidating.
+    generation_updates = []
+    for event in events:
+      try:
+        subscription, is_initial_event, files = (event['subscription'],
+                                                 event['is_fresh_instance'],
+                                                 [f.decode('utf-8') for f in event['files']])
+      except (KeyError, UnicodeDecodeError) as e:
+        self._logger.warn('%r raised by invalid watchman event: %s', e, event)
+        continue
+
+      self._logger.debug('processing {} files for subscription {} (first_event={})'
+                         .format(len(files), subscription, is_initial_event))
+
+      # The first watchman event is a listing of all files (or, if the subscription was made
+      # `since` a persisted snapshot cache generation, of the files which changed before pantsd
+      # started) - ignore it.
+      if subscription not in self._subscriptions_seen:
+        self._subscriptions_seen.add(subscription)
+        is_initial_event = True
 
-    self._logger.debug('processing {} files for subscription {} (first_event={})'
-                       .format(len(files), subscription, is_initial_event))
-
-    # The first watchman event is a listing of all files - ignore it.
-    if not is_initial_event:
       if subscription == self._fs_event_service.PANTS_PID_SUBSCRIPTION_NAME:
-        self._maybe_invalidate_scheduler_pidfile()
+        if not is_initial_event:
+          check_pidfile = True
       else:
-        self._handle_batch_event(files)
+        if not is_initial_event:
+          changed_files.update(files)
+        generation_updates.append((event, is_initial_event, files))
+
+    if check_pidfile:
+      self._maybe_invalidate_scheduler_pidfile()
+    if changed_files:
+      self._logger.debug('invalidating {} files for {} events'
+                         .format(len(changed_files), len(events)))
+      self._handle_batch_event(sorted(changed_files))
+    for event, is_initial_event, files in generation_updates:
+      self._update_snapshot_cache_generation(event, is_initial_event, files)
 
     if not self._watchman_is_running.is_set():
       self._watchman_is_running.set()
 
-    self._event_queue.task_done()
+    for _ in events:
+      self._event_queue.task_done()
 
   def product_graph_len(self):
     """Provides the size of the captive product graph.
@@ -162,12 +239,16 @@ class SchedulerService(PantsService):
     :returns: `(LegacyGraphSession, TargetRoots)`
     """
     # If any nodes exist in the product graph, wait for the initial watchman event to avoid
-    # racing watchman startup vs invalidation events.
+    # racing watchman startup vs invalidation events. Likewise, persisted Snapshots can only be
+    # used once the initial event has established that they are still valid.
     graph_len = self._scheduler.graph_len()
-    if graph_len > 0:
+    if graph_len > 0 or self._persisted_snapshot_cache_generation:
       self._logger.debug('graph len was {}, waiting for initial watchman event'.format(graph_len))
       self._watchman_is_running.wait()
 
+    # Apply the client's engine log levels to the daemon, where they persist until the next run.
+    self._scheduler.set_module_log_levels(options.for_global_scope().native_engine_log_levels)
+
     session = self._graph_helper.new_session()
     with self.fork_lock:
       target_roots = target_roots_calculator.create(
@@ -180,6 +261,13 @@ class SchedulerService(PantsService):
       session.warm_product_graph(target_roots)
       return session, target_roots
 
+  def terminate(self):
+    """Override of PantsService.terminate() that shuts down the scheduler."""
+    report = self._scheduler.shutdown(self.SHUTDOWN_TIMEOUT_SECONDS)
+    if report['cancelled_executions'] or report['killed_processes'] or report['errors']:
+      self._logger.warn('scheduler shutdown was forced: {}'.format(report))
+    super(SchedulerService, self).terminate()
+
   def run(self):
     """Main service entrypoint."""
     while not self.is_killed:
@@ -18,6 +18,8 @@ class StoreGCService(PantsService):
   performs occasional garbage collection to bound the size of the engine's Store.
   """
 
+  thread_role = 'store-gc'
+
   _LEASE_EXTENSION_INTERVAL_SECONDS = 30 * 60
   _GARBAGE_COLLECTION_INTERVAL_SECONDS = 4 * 60 * 60
 
@@ -237,18 +237,36 @@ def register_rmtree(directory, cleaner=_mkdtemp_atexit_cleaner):
   return directory
 
 
+def _make_writable_and_retry(func, path, exc_info):
+  """An `onerror` handler for `shutil.rmtree` which retries after making the path (and the directory
+  containing it) writable, because the engine makes some of the trees that it creates read-only.
+
+  Re-raises the original error if the path still cannot be removed.
+  """
+  try:
+    for p in (os.path.dirname(path), path):
+      if not os.path.islink(p):
+        os.chmod(p, os.stat(p).st_mode | stat.S_IWUSR)
+    func(path)
+  except (IOError, OSError):
+    raise exc_info[1]
+
+
 def safe_rmtree(directory):
   """Delete a directory if it's present. If it's not present, no-op.
 
   Note that if the directory argument is a symlink, only the symlink will
-  be deleted.
+  be deleted. Read-only contents are made writable in order to delete them.
 
   :API: public
   """
   if os.path.islink(directory):
     safe_delete(directory)
   else:
-    shutil.rmtree(directory, ignore_errors=True)
+    try:
+      shutil.rmtree(directory, onerror=_make_writable_and_retry)
+    except (IOError, OSError):
+      pass
 
 
 def safe_open(filename, *args, **kwargs):
@@ -480,7 +498,7 @@ def rm_rf(name):
 
   try:
     # Avoid using safe_rmtree so we can detect failures.
-    shutil.rmtree(name)
+    shutil.rmtree(name, onerror=_make_writable_and_retry)
   except OSError as e:
     if e.errno == errno.ENOTDIR:
       # 'Not a directory', but a file. Attempt to os.unlink the file, raising OSError on failure.
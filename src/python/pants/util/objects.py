@@ -54,6 +54,9 @@ def datatype(field_decls, superclass_name=None, **kwargs):
   namedtuple_cls = namedtuple(superclass_name, field_names, **kwargs)
 
   class DataType(namedtuple_cls):
+    # The TypeConstraints of the fields which were declared with one, by field name.
+    _field_type_constraints = fields_with_constraints
+
     @classmethod
     def make_type_error(cls, msg, *args, **kwargs):
       return TypeCheckError(cls.__name__, msg, *args, **kwargs)
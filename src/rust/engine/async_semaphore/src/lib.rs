@@ -9,7 +9,19 @@ use futures::{Async, Poll};
 
 struct Inner {
   waiters: VecDeque<Task>,
-  available_permits: usize,
+  permits: usize,
+  limit: Option<usize>,
+  acquired: usize,
+}
+
+impl Inner {
+  fn available_permits(&self) -> usize {
+    let permits = match self.limit {
+      Some(limit) => ::std::cmp::min(limit, self.permits),
+      None => self.permits,
+    };
+    permits.saturating_sub(self.acquired)
+  }
 }
 
 #[derive(Clone)]
@@ -22,11 +34,43 @@ impl AsyncSemaphore {
     AsyncSemaphore {
       inner: Arc::new(Mutex::new(Inner {
         waiters: VecDeque::new(),
-        available_permits: permits,
+        permits: permits,
+        limit: None,
+        acquired: 0,
       })),
     }
   }
 
+  ///
+  /// Temporarily limits the number of permits that may be held at once to fewer than this
+  /// semaphore was created with, or (given None) removes any such limit.
+  ///
+  /// Lowering the limit does not revoke permits that are already held: new acquisitions wait
+  /// until enough of them have been released.
+  ///
+  pub fn set_limit(&self, limit: Option<usize>) {
+    let tasks = {
+      let mut inner = self.inner.lock().unwrap();
+      inner.limit = limit;
+      let available = inner.available_permits();
+      let wake = ::std::cmp::min(available, inner.waiters.len());
+      inner.waiters.drain(..wake).collect::<Vec<_>>()
+    };
+    for task in tasks {
+      task.notify();
+    }
+  }
+
+  ///
+  /// Acquires a permit, which is held until it is dropped. Prefer `with_acquired` where possible:
+  /// this is for holding a permit for the lifetime of something other than a Future.
+  ///
+  pub fn acquire(&self) -> PermitFuture {
+    PermitFuture {
+      inner: Some(self.inner.clone()),
+    }
+  }
+
   ///
   /// Runs the given Future-creating function (and the Future it returns) under the semaphore.
   ///
@@ -35,11 +79,9 @@ impl AsyncSemaphore {
     F: FnOnce() -> B + Send + 'static,
     B: Future<Item = T, Error = E> + Send + 'static,
   {
-    let permit = PermitFuture {
-      inner: Some(self.inner.clone()),
-    };
     Box::new(
-      permit
+      self
+        .acquire()
         .map_err(|()| panic!("Acquisition is infalliable."))
         .and_then(|permit| {
           f().map(move |t| {
@@ -59,7 +101,10 @@ impl Drop for Permit {
   fn drop(&mut self) {
     let task = {
       let mut inner = self.inner.lock().unwrap();
-      inner.available_permits += 1;
+      inner.acquired -= 1;
+      if inner.available_permits() == 0 {
+        return;
+      }
       if let Some(task) = inner.waiters.pop_front() {
         task
       } else {
@@ -82,11 +127,11 @@ impl Future for PermitFuture {
     let inner = self.inner.take().expect("cannot poll PermitFuture twice");
     let acquired = {
       let mut inner = inner.lock().unwrap();
-      if inner.available_permits == 0 {
+      if inner.available_permits() == 0 {
         inner.waiters.push_back(task::current());
         false
       } else {
-        inner.available_permits -= 1;
+        inner.acquired += 1;
         true
       }
     };
@@ -167,4 +212,54 @@ mod tests {
       .recv_timeout(Duration::from_secs(5))
       .expect("thread2 didn't acquire.");
   }
+
+  #[test]
+  fn at_most_limit_acquisitions() {
+    let sema = AsyncSemaphore::new(2);
+    sema.set_limit(Some(1));
+    let handle1 = sema.clone();
+    let handle2 = sema.clone();
+
+    let (tx_thread1, acquired_thread1) = mpsc::channel();
+    let (unblock_thread1, rx_thread1) = mpsc::channel();
+    let (tx_thread2, acquired_thread2) = mpsc::channel();
+
+    thread::spawn(move || {
+      handle1
+        .with_acquired(move || {
+          tx_thread1.send(()).unwrap();
+          rx_thread1.recv().unwrap();
+          future::ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    });
+
+    acquired_thread1
+      .recv_timeout(Duration::from_secs(5))
+      .expect("thread1 didn't acquire.");
+
+    thread::spawn(move || {
+      handle2
+        .with_acquired(move || {
+          tx_thread2.send(()).unwrap();
+          future::ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    });
+
+    // thread2 should not acquire the second permit while the limit is in place.
+    match acquired_thread2.recv_timeout(Duration::from_millis(100)) {
+      Err(_) => (),
+      Ok(_) => panic!("thread2 should not have acquired beyond the limit."),
+    }
+
+    // Removing the limit should allow thread2 to acquire, while thread1 still holds its permit.
+    sema.set_limit(None);
+    acquired_thread2
+      .recv_timeout(Duration::from_secs(5))
+      .expect("thread2 didn't acquire.");
+    unblock_thread1.send(()).unwrap();
+  }
 }
@@ -635,7 +635,10 @@ fn main() {
       address.to_owned(),
       1,
       4 * 1024 * 1024,
-      std::time::Duration::from_secs(5 * 60),
+      fs::RemoteTimeouts {
+        metadata: std::time::Duration::from_secs(30),
+        transfer: std::time::Duration::from_secs(5 * 60),
+      },
     ),
     None => fs::Store::local_only(&store_path, pool),
   }.expect("Error making store");
@@ -8,7 +8,7 @@ extern crate protobuf;
 
 use bytes::Bytes;
 use clap::{App, Arg, SubCommand};
-use fs::{GlobMatching, ResettablePool, Snapshot, Store, StoreFileByDigest};
+use fs::{GlobMatching, RemoteTimeouts, ResettablePool, Snapshot, Store, StoreFileByDigest};
 use futures::future::Future;
 use hashing::{Digest, Fingerprint};
 use protobuf::Message;
@@ -155,6 +155,7 @@ to this directory.",
 
 fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
   let store_dir = top_match.value_of("local-store-path").unwrap();
+  fs::fd_budget::initialize()?;
   let pool = Arc::new(ResettablePool::new("fsutil-pool-".to_string()));
   let (store, store_has_remote) = {
     let (store_result, store_has_remote) = match top_match.value_of("server-address") {
@@ -165,7 +166,10 @@ fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
           cas_address.to_owned(),
           1,
           10 * 1024 * 1024,
-          Duration::from_secs(30),
+          RemoteTimeouts {
+            metadata: Duration::from_secs(30),
+            transfer: Duration::from_secs(30),
+          },
         ),
         true,
       ),
@@ -365,5 +369,5 @@ fn execute(top_match: &clap::ArgMatches) -> Result<(), ExitError> {
 }
 
 fn make_posix_fs<P: AsRef<Path>>(root: P, pool: Arc<ResettablePool>) -> fs::PosixFS {
-  fs::PosixFS::new(&root, pool, &[]).unwrap()
+  fs::PosixFS::new(&root, pool, &[], fs::SpecialFileBehavior::Warn).unwrap()
 }
@@ -0,0 +1,393 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use hashing::Digest;
+use os;
+
+// The maximum number of files remembered as clone sources: beyond this, the oldest are forgotten
+// (and the next materialization of their digests will write bytes again).
+const MAX_CLONE_SOURCES: usize = 100_000;
+
+///
+/// Remembers files which were materialized from the Store, so that later materializations of the
+/// same Digest can clone them (using a copy-on-write reflink on filesystems which support it:
+/// APFS, Btrfs, XFS, etc) rather than writing out their bytes again.
+///
+/// A file is only used as a clone source if its size and modification time are unchanged since
+/// it was materialized, so modifications to materialized files are (generally) detected. Any
+/// failure to clone (most commonly because the filesystem does not support it, or because the
+/// source and destination are on different filesystems) causes the caller to fall back to writing
+/// bytes.
+///
+/// Optionally, files may instead be materialized as hardlinks to their clone sources, which works
+/// on any filesystem (as long as the source and destination are on the same one), and keeps the
+/// total size of materialized files down. But because hardlinks share their content, a later
+/// modification of any one of the links is visible via all of the others.
+///
+pub struct CloneSources {
+  inner: Mutex<Inner>,
+  hardlink: bool,
+}
+
+struct Inner {
+  sources: HashMap<Digest, CloneSource>,
+  // Incremented for each recorded source, to allow for evicting the oldest sources.
+  next_sequence: u64,
+  // The total length of the paths of the sources, maintained as they are recorded and removed.
+  path_bytes: usize,
+}
+
+impl Inner {
+  fn remove(&mut self, digest: &Digest) {
+    if let Some(source) = self.sources.remove(digest) {
+      self.path_bytes -= source.path.as_os_str().len();
+    }
+  }
+
+  fn insert(&mut self, digest: Digest, source: CloneSource) {
+    self.path_bytes += source.path.as_os_str().len();
+    if let Some(replaced) = self.sources.insert(digest, source) {
+      self.path_bytes -= replaced.path.as_os_str().len();
+    }
+  }
+}
+
+struct CloneSource {
+  path: PathBuf,
+  len: u64,
+  modified: SystemTime,
+  sequence: u64,
+}
+
+impl CloneSources {
+  pub fn new() -> CloneSources {
+    CloneSources {
+      inner: Mutex::new(Inner {
+        sources: HashMap::new(),
+        next_sequence: 0,
+        path_bytes: 0,
+      }),
+      hardlink: false,
+    }
+  }
+
+  ///
+  /// Like `new`, but materializes files as hardlinks to their clone sources where possible.
+  ///
+  pub fn new_hardlinking() -> CloneSources {
+    CloneSources {
+      hardlink: true,
+      ..CloneSources::new()
+    }
+  }
+
+  ///
+  /// Attempts to materialize the given Digest at the destination by cloning a file that it was
+  /// previously materialized to. Returns false if there was no usable clone source, or if cloning
+  /// failed, in which case the caller should write the file itself.
+  ///
+  pub fn try_clone(&self, digest: Digest, destination: &Path, is_executable: bool) -> bool {
+    let source = {
+      let mut inner = self.inner.lock().unwrap();
+      let is_valid = match inner.sources.get(&digest) {
+        Some(source) => source.is_unchanged(),
+        None => return false,
+      };
+      if !is_valid {
+        inner.remove(&digest);
+        return false;
+      }
+      inner.sources[&digest].path.clone()
+    };
+    if self.hardlink {
+      match link_file(&source, destination, is_executable) {
+        Ok(()) => return true,
+        Err(e) => debug!(
+          "Falling back to cloning {:?}: could not link {:?}: {}",
+          destination, source, e
+        ),
+      }
+    }
+    match clone_file(&source, destination, is_executable) {
+      Ok(()) => true,
+      Err(e) => {
+        debug!(
+          "Falling back to writing {:?}: could not clone {:?}: {}",
+          destination, source, e
+        );
+        false
+      }
+    }
+  }
+
+  ///
+  /// Records that the given Digest was materialized (by writing its bytes) at the given path.
+  ///
+  pub fn record(&self, digest: Digest, path: PathBuf) {
+    let metadata = match fs::metadata(&path).and_then(|m| Ok((m.len(), m.modified()?))) {
+      Ok(metadata) => metadata,
+      Err(_) => return,
+    };
+    let mut inner = self.inner.lock().unwrap();
+    if inner.sources.len() >= MAX_CLONE_SOURCES {
+      // Evict the oldest half of the sources, rather than tracking recency precisely.
+      let cutoff = inner.next_sequence - (MAX_CLONE_SOURCES / 2) as u64;
+      let evicted = inner
+        .sources
+        .iter()
+        .filter(|&(_, s)| s.sequence < cutoff)
+        .map(|(&digest, _)| digest)
+        .collect::<Vec<_>>();
+      for digest in evicted {
+        inner.remove(&digest);
+      }
+    }
+    let sequence = inner.next_sequence;
+    inner.next_sequence += 1;
+    inner.insert(
+      digest,
+      CloneSource {
+        path: path,
+        len: metadata.0,
+        modified: metadata.1,
+        sequence: sequence,
+      },
+    );
+  }
+
+  ///
+  /// An estimate of the bytes of heap memory held by the recorded sources.
+  ///
+  pub fn heap_size(&self) -> usize {
+    let inner = self.inner.lock().unwrap();
+    inner.sources.capacity() * mem::size_of::<(Digest, CloneSource)>() + inner.path_bytes
+  }
+}
+
+impl CloneSource {
+  fn is_unchanged(&self) -> bool {
+    match fs::metadata(&self.path) {
+      Ok(ref m) if m.is_file() => {
+        m.len() == self.len && m.modified().ok().map_or(false, |t| t == self.modified)
+      }
+      _ => false,
+    }
+  }
+}
+
+///
+/// Creates (or replaces) the destination as a copy-on-write clone of the source file.
+///
+fn clone_file(source: &Path, destination: &Path, is_executable: bool) -> io::Result<()> {
+  let result = reflink(source, destination);
+  match result {
+    Ok(()) => os::set_mode(destination, is_executable),
+    Err(e) => {
+      // Don't leave a partial destination behind for the caller to trip over.
+      let _ = fs::remove_file(destination);
+      Err(e)
+    }
+  }
+}
+
+///
+/// Creates (or replaces) the destination as a hardlink to the source file. Because a hardlink
+/// shares the permissions of its source, this fails if the source has the wrong permissions.
+///
+fn link_file(source: &Path, destination: &Path, is_executable: bool) -> io::Result<()> {
+  let source_is_executable = os::is_executable(&fs::metadata(source)?);
+  if source_is_executable != is_executable {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      "The source has different permissions than the destination should.",
+    ));
+  }
+  match fs::remove_file(destination) {
+    Ok(()) => {}
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e),
+  }
+  fs::hard_link(source, destination)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+  use std::os::unix::fs::OpenOptionsExt;
+  use std::os::unix::io::AsRawFd;
+  // From linux/fs.h: _IOW(0x94, 9, int).
+  const FICLONE: libc::c_ulong = 0x4004_9409;
+
+  let source = fs::File::open(source)?;
+  let destination = fs::OpenOptions::new()
+    .create(true)
+    .write(true)
+    .truncate(true)
+    .mode(0o644)
+    .open(destination)?;
+  let res = unsafe { libc::ioctl(destination.as_raw_fd(), FICLONE, source.as_raw_fd()) };
+  if res == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+  use std::ffi::CString;
+  use std::os::unix::ffi::OsStrExt;
+
+  extern "C" {
+    fn clonefile(src: *const libc::c_char, dst: *const libc::c_char, flags: u32) -> libc::c_int;
+  }
+
+  let to_cstring = |path: &Path| {
+    CString::new(path.as_os_str().as_bytes())
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+  };
+  let source = to_cstring(source)?;
+  let destination_cstring = to_cstring(destination)?;
+  // Unlike FICLONE, clonefile requires that the destination not exist.
+  match fs::remove_file(destination) {
+    Ok(()) => {}
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e),
+  }
+  let res = unsafe { clonefile(source.as_ptr(), destination_cstring.as_ptr(), 0) };
+  if res == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_source: &Path, _destination: &Path) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Other,
+    "Cloning files is not supported on this platform.",
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+
+  use super::CloneSources;
+  use hashing::{Digest, Fingerprint};
+  use std::fs;
+  use std::io::Write;
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
+  use testutil::make_file;
+
+  fn digest() -> Digest {
+    Digest(
+      Fingerprint::from_hex_string(
+        "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d",
+      ).unwrap(),
+      16,
+    )
+  }
+
+  #[test]
+  fn no_source() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let clone_sources = CloneSources::new();
+    assert!(!clone_sources.try_clone(digest(), &dir.path().join("roland"), false));
+  }
+
+  #[test]
+  fn heap_size() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    make_file(&source, b"European Burmese", 0o644);
+
+    let clone_sources = CloneSources::new();
+    let empty = clone_sources.heap_size();
+    clone_sources.record(digest(), source.clone());
+    assert!(clone_sources.heap_size() >= empty + source.as_os_str().len());
+  }
+
+  #[test]
+  fn modified_source_is_not_used() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    make_file(&source, b"European Burmese", 0o644);
+
+    let clone_sources = CloneSources::new();
+    clone_sources.record(digest(), source.clone());
+    fs::OpenOptions::new()
+      .append(true)
+      .open(&source)
+      .unwrap()
+      .write_all(b" cat")
+      .unwrap();
+
+    let destination = dir.path().join("susannah");
+    assert!(!clone_sources.try_clone(digest(), &destination, false));
+    assert!(!destination.exists());
+  }
+
+  #[test]
+  fn clone_or_fall_back() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    make_file(&source, b"European Burmese", 0o644);
+
+    let clone_sources = CloneSources::new();
+    clone_sources.record(digest(), source);
+    let destination = dir.path().join("susannah");
+    // Whether cloning is possible depends on the filesystem that the test runs on, but if it
+    // claims to have succeeded, the clone must have the expected content.
+    if clone_sources.try_clone(digest(), &destination, true) {
+      assert_eq!(fs::read(&destination).unwrap(), b"European Burmese".to_vec());
+    } else {
+      assert!(!destination.exists());
+    }
+  }
+
+  #[test]
+  fn hardlink() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    make_file(&source, b"European Burmese", 0o644);
+
+    let clone_sources = CloneSources::new_hardlinking();
+    clone_sources.record(digest(), source.clone());
+    let destination = dir.path().join("susannah");
+    assert!(clone_sources.try_clone(digest(), &destination, false));
+    assert_eq!(fs::read(&destination).unwrap(), b"European Burmese".to_vec());
+    assert_eq!(
+      fs::metadata(&source).unwrap().ino(),
+      fs::metadata(&destination).unwrap().ino()
+    );
+  }
+
+  #[test]
+  fn hardlink_not_used_for_different_permissions() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = dir.path().join("roland");
+    make_file(&source, b"European Burmese", 0o644);
+
+    let clone_sources = CloneSources::new_hardlinking();
+    clone_sources.record(digest(), source.clone());
+    let destination = dir.path().join("susannah");
+    // The destination may still be a clone, but must not share the source's inode (or its
+    // permissions would change).
+    if clone_sources.try_clone(digest(), &destination, true) {
+      assert_ne!(
+        fs::metadata(&source).unwrap().ino(),
+        fs::metadata(&destination).unwrap().ino()
+      );
+    }
+    assert_eq!(fs::metadata(&source).unwrap().permissions().mode() & 0o777, 0o644);
+  }
+}
@@ -0,0 +1,150 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use bazel_protos::remote_execution::Directory;
+use hashing::Digest;
+
+///
+/// A bounded cache of parsed Directory protos, keyed by their Digests.
+///
+/// Operations which walk trees (merging, materializing, and listing the contents of Directories)
+/// load the same Directories many times over a run, and each load from the local store would
+/// otherwise read and parse the proto again. Directories are content-addressed, so a cached
+/// Directory never needs to be invalidated: when the cache is full, the Directories which were
+/// cached first are forgotten.
+///
+pub struct DirectoryCache {
+  inner: Mutex<Inner>,
+  // The maximum total size of the serialized forms of the cached Directories, which is a proxy for
+  // the memory that they hold.
+  capacity_bytes: usize,
+}
+
+struct Inner {
+  directories: HashMap<Digest, Arc<Directory>>,
+  order: VecDeque<Digest>,
+  bytes: usize,
+}
+
+impl DirectoryCache {
+  pub fn new(capacity_bytes: usize) -> DirectoryCache {
+    DirectoryCache {
+      inner: Mutex::new(Inner {
+        directories: HashMap::new(),
+        order: VecDeque::new(),
+        bytes: 0,
+      }),
+      capacity_bytes,
+    }
+  }
+
+  pub fn get(&self, digest: &Digest) -> Option<Arc<Directory>> {
+    self.inner.lock().unwrap().directories.get(digest).cloned()
+  }
+
+  pub fn insert(&self, digest: Digest, directory: Arc<Directory>) {
+    if digest.1 > self.capacity_bytes {
+      return;
+    }
+    let mut inner = self.inner.lock().unwrap();
+    if inner.directories.insert(digest, directory).is_some() {
+      return;
+    }
+    inner.order.push_back(digest);
+    inner.bytes += digest.1;
+    while inner.bytes > self.capacity_bytes {
+      match inner.order.pop_front() {
+        Some(evicted) => {
+          inner.directories.remove(&evicted);
+          inner.bytes -= evicted.1;
+        }
+        None => break,
+      }
+    }
+  }
+
+  ///
+  /// An estimate of the bytes of heap memory held by the cached Directories.
+  ///
+  pub fn heap_size(&self) -> usize {
+    self.inner.lock().unwrap().heap_size()
+  }
+
+  ///
+  /// Forgets all of the cached Directories, and releases the memory that the cache had grown to
+  /// hold. Returns an estimate of the bytes of heap memory released.
+  ///
+  pub fn clear(&self) -> usize {
+    let mut inner = self.inner.lock().unwrap();
+    let released = inner.heap_size();
+    *inner = Inner {
+      directories: HashMap::new(),
+      order: VecDeque::new(),
+      bytes: 0,
+    };
+    released
+  }
+}
+
+impl Inner {
+  fn heap_size(&self) -> usize {
+    self.directories.capacity() * mem::size_of::<(Digest, Arc<Directory>)>()
+      + self.order.capacity() * mem::size_of::<Digest>()
+      + self.bytes
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::DirectoryCache;
+  use bazel_protos::remote_execution::Directory;
+  use hashing::{Digest, Fingerprint};
+  use std::sync::Arc;
+
+  fn digest(byte: u8, len: usize) -> Digest {
+    Digest(Fingerprint([byte; 32]), len)
+  }
+
+  #[test]
+  fn get_inserted() {
+    let cache = DirectoryCache::new(100);
+    assert!(cache.get(&digest(1, 10)).is_none());
+    cache.insert(digest(1, 10), Arc::new(Directory::new()));
+    assert!(cache.get(&digest(1, 10)).is_some());
+  }
+
+  #[test]
+  fn evicts_oldest_when_full() {
+    let cache = DirectoryCache::new(100);
+    cache.insert(digest(1, 40), Arc::new(Directory::new()));
+    cache.insert(digest(2, 40), Arc::new(Directory::new()));
+    cache.insert(digest(3, 40), Arc::new(Directory::new()));
+    assert!(cache.get(&digest(1, 40)).is_none());
+    assert!(cache.get(&digest(2, 40)).is_some());
+    assert!(cache.get(&digest(3, 40)).is_some());
+  }
+
+  #[test]
+  fn does_not_cache_directories_larger_than_capacity() {
+    let cache = DirectoryCache::new(100);
+    cache.insert(digest(1, 40), Arc::new(Directory::new()));
+    cache.insert(digest(2, 101), Arc::new(Directory::new()));
+    assert!(cache.get(&digest(1, 40)).is_some());
+    assert!(cache.get(&digest(2, 101)).is_none());
+  }
+
+  #[test]
+  fn clear_releases_memory() {
+    let cache = DirectoryCache::new(100);
+    let empty = cache.heap_size();
+    cache.insert(digest(1, 40), Arc::new(Directory::new()));
+    let full = cache.heap_size();
+    assert_eq!(cache.clear(), full);
+    assert!(cache.get(&digest(1, 40)).is_none());
+    assert_eq!(cache.heap_size(), empty);
+  }
+}
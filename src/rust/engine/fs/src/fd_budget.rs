@@ -0,0 +1,228 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#[cfg(unix)]
+use std::io;
+
+use async_semaphore::{AsyncSemaphore, PermitFuture};
+use futures::Future;
+#[cfg(unix)]
+use libc;
+
+// The most file descriptors that a single operation under the budget may hold at once (cloning a
+// file holds both the source and the destination open).
+const FDS_PER_OPERATION: u64 = 2;
+
+// The fraction of the file descriptor limit which is left for everything other than operations
+// under the budget: the Store, process execution, sockets, and the Python interpreter itself.
+const RESERVED_FRACTION: u64 = 2;
+
+// Below this limit, large builds are likely to be slowed down by queueing for file descriptors.
+const RECOMMENDED_FD_LIMIT: u64 = 1024;
+
+// macOS refuses to raise the soft limit above OPEN_MAX, regardless of the hard limit. Elsewhere,
+// the hard limit may be unlimited (or huge), and subprocesses inherit the soft limit: a process
+// which uses `select` misbehaves once it has descriptors numbered above FD_SETSIZE, so the soft
+// limit is not raised further than a build needs.
+#[cfg(target_os = "macos")]
+const MAX_FD_LIMIT: u64 = 10240;
+#[cfg(not(target_os = "macos"))]
+const MAX_FD_LIMIT: u64 = 65536;
+
+lazy_static! {
+  static ref FD_BUDGET: FdBudget = FdBudget::new();
+}
+
+///
+/// Limits the number of filesystem operations (scanning directories, reading files and
+/// materializing them) which may hold file descriptors at once. Operations beyond the budget are
+/// queued until earlier operations complete, rather than failing with EMFILE ("Too many open
+/// files") as they would if the process ran out of file descriptors.
+///
+/// A single budget is shared by the whole process (see `FdBudget::global`), because the file
+/// descriptor limit is per-process. It is unlimited until `initialize` is called.
+///
+pub struct FdBudget {
+  semaphore: AsyncSemaphore,
+}
+
+impl FdBudget {
+  fn new() -> FdBudget {
+    FdBudget {
+      semaphore: AsyncSemaphore::new(usize::max_value()),
+    }
+  }
+
+  pub fn global() -> &'static FdBudget {
+    &FD_BUDGET
+  }
+
+  ///
+  /// Limits the number of operations which may run under the budget at once (or given None,
+  /// removes the limit).
+  ///
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self.semaphore.set_limit(limit);
+  }
+
+  ///
+  /// Acquires a share of the budget for something which holds file descriptors open other than a
+  /// Future (such as a Stream), which is held until the resulting Permit is dropped.
+  ///
+  pub fn acquire(&self) -> PermitFuture {
+    self.semaphore.acquire()
+  }
+
+  ///
+  /// Runs the given Future-creating function (and the Future it returns) under the budget. The
+  /// Future must not itself wait for another operation under the budget, or it could deadlock.
+  ///
+  pub fn with_fds<F, B, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send>
+  where
+    F: FnOnce() -> B + Send + 'static,
+    B: Future<Item = T, Error = E> + Send + 'static,
+  {
+    self.semaphore.with_acquired(f)
+  }
+}
+
+///
+/// Raises the soft limit on open file descriptors for this process as far as the hard limit allows
+/// (but no further than MAX_FD_LIMIT), and returns the resulting soft limit. A soft limit which is
+/// already higher is left alone. A failure to raise the limit is not fatal: the current soft limit
+/// is returned.
+///
+#[cfg(unix)]
+pub fn raise_fd_limit() -> Result<u64, String> {
+  let mut limit = libc::rlimit {
+    rlim_cur: 0,
+    rlim_max: 0,
+  };
+  if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+    return Err(format!(
+      "Failed to get the limit on open files: {}",
+      io::Error::last_os_error()
+    ));
+  }
+  let current = limit.rlim_cur as u64;
+  let desired = ::std::cmp::min(limit.rlim_max as u64, MAX_FD_LIMIT);
+  if desired <= current {
+    return Ok(current);
+  }
+  limit.rlim_cur = desired as libc::rlim_t;
+  if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+    debug!(
+      "Failed to raise the limit on open files from {} to {}: {}",
+      current,
+      desired,
+      io::Error::last_os_error()
+    );
+    return Ok(current);
+  }
+  Ok(desired)
+}
+
+#[cfg(windows)]
+pub fn raise_fd_limit() -> Result<u64, String> {
+  // Files are opened as Windows handles rather than C runtime descriptors, which are not limited.
+  Ok(MAX_FD_LIMIT)
+}
+
+///
+/// Computes the number of operations which may run under the budget for the given limit on open
+/// file descriptors.
+///
+pub fn budget_for_fd_limit(fd_limit: u64) -> usize {
+  let budget = fd_limit / RESERVED_FRACTION / FDS_PER_OPERATION;
+  ::std::cmp::max(1, ::std::cmp::min(budget, usize::max_value() as u64) as usize)
+}
+
+///
+/// Checks the limit on open file descriptors at startup (raising it if possible), and sizes the
+/// global budget to fit within it. Returns the size of the budget.
+///
+pub fn initialize() -> Result<usize, String> {
+  let fd_limit = raise_fd_limit()?;
+  if fd_limit < RECOMMENDED_FD_LIMIT {
+    warn!(
+      "The limit on open files is {}, which may slow down large builds. Consider raising it to at \
+       least {} (using `ulimit -n`, for example).",
+      fd_limit, RECOMMENDED_FD_LIMIT
+    );
+  }
+  let budget = budget_for_fd_limit(fd_limit);
+  debug!(
+    "Limiting filesystem operations to {} at once, for a limit of {} open files.",
+    budget, fd_limit
+  );
+  FdBudget::global().set_limit(Some(budget));
+  Ok(budget)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{budget_for_fd_limit, raise_fd_limit, FdBudget, MAX_FD_LIMIT};
+  use futures::{future, Future};
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn budget_sizes() {
+    assert_eq!(budget_for_fd_limit(256), 64);
+    assert_eq!(budget_for_fd_limit(10240), 2560);
+    assert_eq!(budget_for_fd_limit(1), 1);
+    assert!(budget_for_fd_limit(u64::max_value()) > 0);
+  }
+
+  #[test]
+  fn raise_limit_does_not_lower_it() {
+    let before = raise_fd_limit().unwrap();
+    assert!(before > 0);
+    assert!(raise_fd_limit().unwrap() >= before);
+  }
+
+  #[test]
+  fn raise_limit_is_capped() {
+    let before = raise_fd_limit().unwrap();
+    // Raising the limit (again) never takes it beyond the cap, unless it was already beyond it.
+    let after = raise_fd_limit().unwrap();
+    assert!(after <= ::std::cmp::max(before, MAX_FD_LIMIT));
+  }
+
+  #[test]
+  fn operations_beyond_the_budget_are_queued() {
+    let budget = FdBudget::new();
+    budget.set_limit(Some(1));
+
+    let (acquired_tx, acquired_rx) = mpsc::channel();
+    let (release_tx, release_rx) = mpsc::channel::<()>();
+    let first = budget.with_fds(move || {
+      acquired_tx.send(()).unwrap();
+      release_rx.recv().unwrap();
+      future::ok::<_, ()>(())
+    });
+    let holder = thread::spawn(move || first.wait());
+    acquired_rx
+      .recv_timeout(Duration::from_secs(5))
+      .expect("First operation didn't run.");
+
+    let (second_tx, second_rx) = mpsc::channel();
+    let second = budget.with_fds(move || {
+      second_tx.send(()).unwrap();
+      future::ok::<_, ()>(())
+    });
+    let waiter = thread::spawn(move || second.wait());
+    assert!(
+      second_rx.recv_timeout(Duration::from_millis(100)).is_err(),
+      "Second operation should have been queued."
+    );
+
+    release_tx.send(()).unwrap();
+    second_rx
+      .recv_timeout(Duration::from_secs(5))
+      .expect("Second operation didn't run once the first completed.");
+    holder.join().unwrap().unwrap();
+    waiter.join().unwrap().unwrap();
+  }
+}
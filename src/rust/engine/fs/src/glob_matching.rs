@@ -1,44 +1,410 @@
 // Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use boxfuture::{BoxFuture, Boxable};
 use futures::future;
+use futures::stream::{self, Stream};
 use futures::Future;
 use glob::Pattern;
 use indexmap::{map::Entry::Occupied, IndexMap, IndexSet};
 
 use {
-  Dir, GitignoreStyleExcludes, GlobParsedSource, GlobSource, GlobWithSource, Link, PathGlob,
-  PathGlobs, PathStat, Stat, VFS,
+  link_escape_message, matches_wildcard, Dir, DirectoryListing, GitignoreStyleExcludes,
+  GlobParsedSource, GlobSource, GlobWithSource, Link, LinkEscapeBehavior, ListingFilter, PathGlob,
+  PathGlobs, PathStat, Stat, DOUBLE_STAR_GLOB, SINGLE_STAR_GLOB, VFS,
 };
 
+// The maximum number of Links that may be followed to canonicalize a path, as for `MAXSYMLINKS` on
+// Linux. A chain of Links longer than this is almost certainly a cycle.
+const MAX_LINK_DEPTH: usize = 40;
+
+///
+/// The default for `VFS::glob_expansion_parallelism`.
+///
+pub const DEFAULT_GLOB_EXPANSION_PARALLELISM: usize = 64;
+
+// The number of bits in the bloom filter of a NegativeMatchFilter, and the number of them which are
+// set for each directory.
+const NEGATIVE_MATCH_FILTER_BITS: usize = 1 << 20;
+const NEGATIVE_MATCH_FILTER_HASHES: u64 = 4;
+
 pub trait GlobMatching<E: Send + Sync + 'static>: VFS<E> {
   ///
   /// Canonicalize the Link for the given Path to an underlying File or Dir. May result
   /// in None if the PathStat represents a broken Link.
   ///
-  /// Skips ignored paths both before and after expansion.
-  ///
-  /// TODO: Should handle symlink loops (which would exhibit as an infinite loop in expand).
+  /// Skips ignored paths both before and after expansion. Fails if more than `MAX_LINK_DEPTH`
+  /// Links must be followed, which is usually caused by a cycle of Links.
   ///
   fn canonicalize(&self, symbolic_path: PathBuf, link: &Link) -> BoxFuture<Option<PathStat>, E> {
-    GlobMatchingImplementation::canonicalize(self, symbolic_path, link)
+    let links = FollowedLinks::none().follow(false);
+    GlobMatchingImplementation::canonicalize(self, symbolic_path, link, links)
   }
 
   ///
-  /// Recursively expands PathGlobs into PathStats while applying excludes.
+  /// Expands PathGlobs into PathStats while applying excludes.
+  ///
+  /// Expansion proceeds level by level from a queue of PathGlobs rather than recursively, so the
+  /// depth of a directory tree (or of a `**` pattern) affects neither the stack nor the nesting of
+  /// futures. At most `VFS::glob_expansion_parallelism` PathGlobs are expanded concurrently.
   ///
   fn expand(&self, path_globs: PathGlobs) -> BoxFuture<Vec<PathStat>, E> {
-    GlobMatchingImplementation::expand(self, path_globs)
+    GlobMatchingImplementation::expand(self, path_globs, FollowedLinks::none())
   }
 }
 
 impl<E: Send + Sync + 'static, T: VFS<E>> GlobMatching<E> for T {}
 
+///
+/// A memo of the matches of individual PathGlobs against directory listings, which is shared
+/// between the expansions of PathGlobs that overlap (such as `src/**/*.py` with many different
+/// excludes), so that they share the work of matching and traversing directories rather than
+/// repeating it.
+///
+/// Entries are keyed by PathGlob, and are only used while the listing of the directory that the
+/// PathGlob applies to is unchanged. Matches are memoized before local excludes are applied,
+/// because those differ between PathGlobs. Matches which include Links are not memoized, because
+/// their canonicalization depends on more than the listing.
+///
+#[derive(Default)]
+pub struct GlobExpansionMemo {
+  entries: Mutex<HashMap<PathGlob, MemoizedMatches>>,
+}
+
+struct MemoizedMatches {
+  listing: Arc<DirectoryListing>,
+  // The Stats of the listing which matched, paired with their PathStats.
+  matches: Arc<Vec<(Stat, PathStat)>>,
+}
+
+impl GlobExpansionMemo {
+  pub fn new() -> GlobExpansionMemo {
+    GlobExpansionMemo::default()
+  }
+
+  ///
+  /// Forgets all memoized matches.
+  ///
+  pub fn clear(&self) {
+    self.entries.lock().unwrap().clear();
+  }
+
+  fn get(
+    &self,
+    path_glob: &PathGlob,
+    listing: &Arc<DirectoryListing>,
+  ) -> Option<Arc<Vec<(Stat, PathStat)>>> {
+    let entries = self.entries.lock().unwrap();
+    match entries.get(path_glob) {
+      Some(entry) if Arc::ptr_eq(&entry.listing, listing) || entry.listing == *listing => {
+        Some(entry.matches.clone())
+      }
+      _ => None,
+    }
+  }
+
+  fn insert(
+    &self,
+    path_glob: PathGlob,
+    listing: Arc<DirectoryListing>,
+    matches: Arc<Vec<(Stat, PathStat)>>,
+  ) {
+    self
+      .entries
+      .lock()
+      .unwrap()
+      .insert(path_glob, MemoizedMatches { listing, matches });
+  }
+}
+
+///
+/// Records which directories are known not to contain any matches (at any depth) for recursive
+/// suffix globs such as `**/BUILD`, so that repeated expansions of those globs skip the subtrees
+/// which have been proven empty rather than listing them again. It is cleared when a Session begins
+/// and when files are invalidated.
+///
+/// The directories whose subtrees were completely expanded for a suffix are recorded exactly, but
+/// the (far more numerous) directories beneath them whose subtrees contained matches are recorded
+/// in a bloom filter. A directory beneath a completely expanded one which the bloom filter does not
+/// contain is certainly empty: a false positive only causes an empty subtree to be listed again,
+/// never a match to be missed.
+///
+pub struct NegativeMatchFilter {
+  inner: Mutex<NegativeMatchFilterInner>,
+}
+
+struct NegativeMatchFilterInner {
+  // The directories and suffixes whose subtrees have been completely expanded.
+  expanded: HashSet<(PathBuf, String)>,
+  // A bloom filter of the directories and suffixes whose subtrees contain matches.
+  matched: Vec<u64>,
+}
+
+impl NegativeMatchFilter {
+  pub fn new() -> NegativeMatchFilter {
+    NegativeMatchFilter {
+      inner: Mutex::new(NegativeMatchFilterInner {
+        expanded: HashSet::new(),
+        matched: vec![0; NEGATIVE_MATCH_FILTER_BITS / 64],
+      }),
+    }
+  }
+
+  ///
+  /// Forgets all recorded directories.
+  ///
+  pub fn clear(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.expanded.clear();
+    for word in inner.matched.iter_mut() {
+      *word = 0;
+    }
+  }
+
+  ///
+  /// True if the subtree of the given directory is known not to contain any matches for the given
+  /// suffix.
+  ///
+  fn is_empty(&self, dir: &Dir, suffix: &Pattern) -> bool {
+    let inner = self.inner.lock().unwrap();
+    if inner.expanded.is_empty() {
+      return false;
+    }
+    let suffix = suffix.as_str();
+    let mut ancestor = Some(dir.0.as_path());
+    while let Some(path) = ancestor {
+      if inner
+        .expanded
+        .contains(&(path.to_owned(), suffix.to_owned()))
+      {
+        return !Self::bits(dir.0.as_path(), suffix)
+          .into_iter()
+          .all(|bit| inner.matched[bit / 64] & (1 << (bit % 64)) != 0);
+      }
+      ancestor = path.parent();
+    }
+    false
+  }
+
+  ///
+  /// Records the directories whose subtrees were completely expanded for a suffix, and those whose
+  /// subtrees contained matches.
+  ///
+  fn record(&self, expanded: Vec<(&Dir, &Pattern)>, matched: Vec<(&Dir, &Pattern)>) {
+    let mut inner = self.inner.lock().unwrap();
+    for (dir, suffix) in matched {
+      for bit in Self::bits(dir.0.as_path(), suffix.as_str()) {
+        inner.matched[bit / 64] |= 1 << (bit % 64);
+      }
+    }
+    inner.expanded.extend(
+      expanded
+        .into_iter()
+        .map(|(dir, suffix)| (dir.0.to_path_buf(), suffix.as_str().to_owned())),
+    );
+  }
+
+  fn bits(dir: &Path, suffix: &str) -> Vec<usize> {
+    (0..NEGATIVE_MATCH_FILTER_HASHES)
+      .map(|seed| {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        dir.hash(&mut hasher);
+        suffix.hash(&mut hasher);
+        (hasher.finish() as usize) % NEGATIVE_MATCH_FILTER_BITS
+      })
+      .collect()
+  }
+}
+
+impl Default for NegativeMatchFilter {
+  fn default() -> NegativeMatchFilter {
+    NegativeMatchFilter::new()
+  }
+}
+
+///
+/// If the given PathGlob is the DirWildcard which a recursive suffix glob like `**/BUILD` expands
+/// to in each directory, returns the directory and the suffix.
+///
+fn recursive_suffix(path_glob: &PathGlob) -> Option<(&Dir, &Pattern)> {
+  match path_glob {
+    &PathGlob::DirWildcard {
+      ref canonical_dir,
+      ref wildcard,
+      ref remainder,
+      ..
+    } if *wildcard == *SINGLE_STAR_GLOB
+      && remainder.len() == 2
+      && remainder[0] == *DOUBLE_STAR_GLOB =>
+    {
+      Some((canonical_dir, &remainder[1]))
+    }
+    _ => None,
+  }
+}
+
+///
+/// If matches for the given PathGlob prove that its directory contains matches for a suffix (as for
+/// the pair of PathGlobs that `**/BUILD` expands to in each directory), returns the directory and
+/// the suffix.
+///
+fn matched_suffix(path_glob: &PathGlob) -> Option<(&Dir, &Pattern)> {
+  match path_glob {
+    &PathGlob::Wildcard {
+      ref canonical_dir,
+      ref wildcard,
+      ..
+    } => Some((canonical_dir, wildcard)),
+    path_glob => recursive_suffix(path_glob),
+  }
+}
+
+///
+/// Propagates the matches of each completed PathGlob to the PathGlobs which (transitively) expanded
+/// to it, and returns the inputs which matched.
+///
+fn propagate_matches(
+  completed: &mut IndexMap<PathGlob, GlobExpansionCacheEntry>,
+) -> HashSet<GlobParsedSource> {
+  // Each `GlobExpansionCacheEntry` stored in `completed` for some `PathGlob` has the field
+  // `matched` to denote whether that specific `PathGlob` matched any files. We propagate a
+  // positive `matched` condition to all transitive "parents" of any glob which expands to
+  // some non-empty set of `PathStat`s. The `sources` field contains the parents (see the enum
+  // `GlobSource`), which may be another glob, or it might be a `GlobParsedSource`. We record
+  // all `GlobParsedSource` inputs which transitively expanded to some file here.
+  let mut inputs_with_matches: HashSet<GlobParsedSource> = HashSet::new();
+
+  // `completed` is an IndexMap, and we immediately insert every glob we expand into
+  // `completed`, recording any `PathStat`s and `PathGlob`s it expanded to (and then expanding
+  // those child globs in the next iteration of the loop_fn). If we iterate in
+  // reverse order of expansion (using .rev()), we ensure that we have already visited every
+  // "child" glob of the glob we are operating on while iterating. This is a reverse
+  // "topological ordering" which preserves the partial order from parent to child globs.
+  let all_globs: Vec<PathGlob> = completed.keys().rev().cloned().collect();
+  for cur_glob in all_globs {
+    // Note that we talk of "parents" and "childen", but this structure is actually a DAG,
+    // because different `DirWildcard`s can potentially expand (transitively) to the same
+    // intermediate glob. The "parents" of each glob are stored in the `sources` field of its
+    // `GlobExpansionCacheEntry` (which is mutably updated with any new parents on each
+    // iteration of the loop_fn above). This can be considered "amortized" and/or "memoized",
+    // because we only traverse every parent -> child link once.
+    let new_matched_source_globs = match completed.get(&cur_glob).unwrap() {
+      &GlobExpansionCacheEntry {
+        ref matched,
+        ref sources,
+        ..
+      } => match matched {
+        // Neither this glob, nor any of its children, expanded to any `PathStat`s, so we have
+        // nothing to propagate.
+        &GlobMatch::DidNotMatchAnyFiles => vec![],
+        &GlobMatch::SuccessfullyMatchedSomeFiles => sources
+          .iter()
+          .filter_map(|src| match src {
+            // This glob matched some files, so its parent also matched some files.
+            &GlobSource::ParentGlob(ref path_glob) => Some(path_glob.clone()),
+            // We've found one of the root inputs, coming from a glob which transitively
+            // matched some child -- record it (this may already exist in the set).
+            &GlobSource::ParsedInput(ref parsed_source) => {
+              inputs_with_matches.insert(parsed_source.clone());
+              None
+            }
+          })
+          .collect(),
+      },
+    };
+    new_matched_source_globs.into_iter().for_each(|path_glob| {
+      // Overwrite whatever was in there before -- we now know these globs transitively
+      // expanded to some non-empty set of `PathStat`s.
+      let entry = completed.get_mut(&path_glob).unwrap();
+      entry.matched = GlobMatch::SuccessfullyMatchedSomeFiles;
+    });
+  }
+  inputs_with_matches
+}
+
+///
+/// Records the outcome of a completed expansion (whose matches have been propagated) in the given
+/// NegativeMatchFilter.
+///
+/// A recursive suffix glob only covers the subdirectories of its directory, so it completely
+/// expanded the subtree of its directory only if the sibling Wildcard for the same suffix (as for
+/// `X/**/BUILD`, but not for `X/*/**/BUILD`) was expanded in the same run. Each such complete
+/// expansion whose parent was not also a complete expansion for the same suffix was the root of a
+/// complete expansion of its subtree.
+///
+fn record_negative_matches(
+  filter: &NegativeMatchFilter,
+  completed: &IndexMap<PathGlob, GlobExpansionCacheEntry>,
+) {
+  let wildcards: HashSet<(&Path, &str)> = completed
+    .keys()
+    .filter_map(|path_glob| match path_glob {
+      &PathGlob::Wildcard {
+        ref canonical_dir,
+        ref wildcard,
+        ..
+      } => Some((canonical_dir.0.as_path(), wildcard.as_str())),
+      _ => None,
+    })
+    .collect();
+  let expanded = completed
+    .iter()
+    .filter_map(|(path_glob, entry)| {
+      let (dir, suffix) = complete_suffix(path_glob, &wildcards)?;
+      let is_root = entry.sources.iter().any(|source| match source {
+        &GlobSource::ParentGlob(ref parent) => {
+          complete_suffix(parent, &wildcards).map(|(_, s)| s) != Some(suffix)
+        }
+        &GlobSource::ParsedInput(_) => true,
+      });
+      if is_root {
+        Some((dir, suffix))
+      } else {
+        None
+      }
+    })
+    .collect();
+  let matched = completed
+    .iter()
+    .filter(|&(_, entry)| entry.matched == GlobMatch::SuccessfullyMatchedSomeFiles)
+    .filter_map(|(path_glob, _)| matched_suffix(path_glob))
+    .collect();
+  filter.record(expanded, matched);
+}
+
+///
+/// As for `recursive_suffix`, but only if the given PathGlob was part of a complete expansion of
+/// the subtree of its directory, as determined by the given expanded Wildcards.
+///
+fn complete_suffix<'a>(
+  path_glob: &'a PathGlob,
+  wildcards: &HashSet<(&Path, &str)>,
+) -> Option<(&'a Dir, &'a Pattern)> {
+  recursive_suffix(path_glob)
+    .filter(|&(dir, suffix)| wildcards.contains(&(dir.0.as_path(), suffix.as_str())))
+}
+
+///
+/// Returns the PathStats of the given matches which are not excluded.
+///
+fn exclude_matches(
+  matches: &[(Stat, PathStat)],
+  exclude: &GitignoreStyleExcludes,
+) -> Vec<PathStat> {
+  matches
+    .iter()
+    .filter(|&&(ref stat, _)| !exclude.is_ignored(stat))
+    .map(|&(_, ref path_stat)| path_stat.clone())
+    .collect()
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 enum GlobMatch {
   SuccessfullyMatchedSomeFiles,
@@ -59,9 +425,38 @@ struct SingleExpansionResult {
   globs: Vec<PathGlob>,
 }
 
+///
+/// The Links which were followed to reach a path.
+///
+#[derive(Clone, Copy, Debug)]
+struct FollowedLinks {
+  depth: usize,
+  // Whether any of the Links were matched by a directory wildcard, which only traverses them
+  // rather than matching them (see `LinkEscapeBehavior::Error`).
+  traversed: bool,
+}
+
+impl FollowedLinks {
+  fn none() -> FollowedLinks {
+    FollowedLinks {
+      depth: 0,
+      traversed: false,
+    }
+  }
+
+  fn follow(self, traverses: bool) -> FollowedLinks {
+    FollowedLinks {
+      depth: self.depth + 1,
+      traversed: self.traversed || traverses,
+    }
+  }
+}
+
 #[derive(Debug)]
 struct PathGlobsExpansion<T: Sized> {
   context: T,
+  // The Links that were followed to begin this expansion.
+  links: FollowedLinks,
   // Globs that have yet to be expanded, in order.
   todo: Vec<GlobWithSource>,
   // Paths to exclude.
@@ -79,71 +474,116 @@ struct PathGlobsExpansion<T: Sized> {
 trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
   fn directory_listing(
     &self,
+    path_glob: PathGlob,
     canonical_dir: Dir,
     symbolic_path: PathBuf,
     wildcard: Pattern,
     exclude: &Arc<GitignoreStyleExcludes>,
+    links: FollowedLinks,
   ) -> BoxFuture<Vec<PathStat>, E> {
     // List the directory.
     let context = self.clone();
     let exclude = exclude.clone();
+    let traverses = match path_glob {
+      PathGlob::DirWildcard { .. } => true,
+      PathGlob::Wildcard { .. } => false,
+    };
 
+    let filter = ListingFilter {
+      wildcard: wildcard.clone(),
+      traverses,
+    };
     self
-      .scandir(canonical_dir)
+      .scandir_matching(canonical_dir, &filter)
       .and_then(move |dir_listing| {
+        if let Some(matches) = context
+          .glob_expansion_memo()
+          .and_then(|memo| memo.get(&path_glob, &dir_listing))
+        {
+          return future::ok(exclude_matches(&matches, &exclude)).to_boxed();
+        }
+
         // Match any relevant Stats, and join them into PathStats.
+        let matches = dir_listing
+          .0
+          .iter()
+          // Match relevant filenames.
+          .filter(|stat| matches_wildcard(&wildcard, stat))
+          .filter_map(|stat| {
+            // Append matched filenames.
+            stat
+              .path()
+              .file_name()
+              .map(|file_name| symbolic_path.join(file_name))
+              .map(|symbolic_stat_path| (symbolic_stat_path, stat))
+          })
+          .filter(|&(_, stat)| !context.is_ignored(stat))
+          .collect::<Vec<_>>();
+
+        // Without Links, the matches depend only on the listing, so they are memoized before local
+        // excludes are applied.
+        let memoizable = matches
+          .iter()
+          .map(|&(ref stat_symbolic_path, stat)| match stat {
+            &Stat::Link(_) => None,
+            &Stat::Dir(ref d) => Some((
+              stat.clone(),
+              PathStat::dir(stat_symbolic_path.to_owned(), d.clone()),
+            )),
+            &Stat::File(ref f) => Some((
+              stat.clone(),
+              PathStat::file(stat_symbolic_path.to_owned(), f.clone()),
+            )),
+          })
+          .collect::<Option<Vec<_>>>();
+        if let Some(memoizable) = memoizable {
+          let memoizable = Arc::new(memoizable);
+          let path_stats = exclude_matches(&memoizable, &exclude);
+          if let Some(memo) = context.glob_expansion_memo() {
+            memo.insert(path_glob, dir_listing.clone(), memoizable);
+          }
+          return future::ok(path_stats).to_boxed();
+        }
+
         future::join_all(
-          dir_listing
-            .0
-            .iter()
-            .filter(|stat| {
-              // Match relevant filenames.
-              stat
-                .path()
-                .file_name()
-                .map(|file_name| wildcard.matches_path(Path::new(file_name)))
-                .unwrap_or(false)
-            })
-            .filter_map(|stat| {
-              // Append matched filenames.
-              stat
-                .path()
-                .file_name()
-                .map(|file_name| symbolic_path.join(file_name))
-                .map(|symbolic_stat_path| (symbolic_stat_path, stat))
-            })
+          matches
+            .into_iter()
             .map(|(stat_symbolic_path, stat)| {
-              // Canonicalize matched PathStats, and filter paths that are ignored by either the
-              // context, or by local excludes. Note that we apply context ignore patterns to both
-              // the symbolic and canonical names of Links, but only apply local excludes to their
-              // symbolic names.
-              if context.is_ignored(&stat) || exclude.is_ignored(&stat) {
+              // Canonicalize matched PathStats, and filter paths that are ignored by local
+              // excludes. Note that we apply context ignore patterns to both the symbolic and
+              // canonical names of Links, but only apply local excludes to their symbolic names.
+              if exclude.is_ignored(stat) {
                 future::ok(None).to_boxed()
               } else {
                 match stat {
-                  Stat::Link(l) => context.canonicalize(stat_symbolic_path, l),
-                  Stat::Dir(d) => future::ok(Some(PathStat::dir(
-                    stat_symbolic_path.to_owned(),
-                    d.clone(),
-                  ))).to_boxed(),
-                  Stat::File(f) => future::ok(Some(PathStat::file(
-                    stat_symbolic_path.to_owned(),
-                    f.clone(),
-                  ))).to_boxed(),
+                  &Stat::Link(ref l) => {
+                    GlobMatchingImplementation::canonicalize(
+                      &context,
+                      stat_symbolic_path,
+                      l,
+                      links.follow(traverses),
+                    )
+                  }
+                  &Stat::Dir(ref d) => {
+                    future::ok(Some(PathStat::dir(stat_symbolic_path, d.clone()))).to_boxed()
+                  }
+                  &Stat::File(ref f) => {
+                    future::ok(Some(PathStat::file(stat_symbolic_path, f.clone()))).to_boxed()
+                  }
                 }
               }
             })
             .collect::<Vec<_>>(),
-        )
-      })
-      .map(|path_stats| {
-        // See the note above.
-        path_stats.into_iter().filter_map(|pso| pso).collect()
+        ).map(|path_stats| {
+          // See the note above.
+          path_stats.into_iter().filter_map(|pso| pso).collect()
+        })
+          .to_boxed()
       })
       .to_boxed()
   }
 
-  fn expand(&self, path_globs: PathGlobs) -> BoxFuture<Vec<PathStat>, E> {
+  fn expand(&self, path_globs: PathGlobs, links: FollowedLinks) -> BoxFuture<Vec<PathStat>, E> {
     let PathGlobs {
       include,
       exclude,
@@ -154,8 +594,11 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
       return future::ok(vec![]).to_boxed();
     }
 
+    let parallelism = self.glob_expansion_parallelism();
+    let context = self.clone();
     let init = PathGlobsExpansion {
       context: self.clone(),
+      links,
       todo: include
         .iter()
         .flat_map(|entry| entry.to_sourced_globs())
@@ -164,17 +607,17 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
       completed: IndexMap::default(),
       outputs: IndexSet::default(),
     };
-    future::loop_fn(init, |mut expansion| {
-      // Request the expansion of all outstanding PathGlobs as a batch.
-      let round = future::join_all({
-        let exclude = &expansion.exclude;
-        let context = &expansion.context;
-        expansion
-          .todo
-          .drain(..)
-          .map(|sourced_glob| context.expand_single(sourced_glob, exclude))
-          .collect::<Vec<_>>()
-      });
+    future::loop_fn(init, move |mut expansion| {
+      // Expand all outstanding PathGlobs (which form the next level of the expansion), in order.
+      let round = {
+        let exclude = expansion.exclude.clone();
+        let context = expansion.context.clone();
+        let links = expansion.links;
+        stream::iter_ok(expansion.todo.drain(..).collect::<Vec<_>>())
+          .map(move |sourced_glob| context.expand_single(sourced_glob, &exclude, links))
+          .buffered(parallelism)
+          .collect()
+      };
       round.map(move |single_expansion_results| {
         // Collect distinct new PathStats and PathGlobs
         for exp in single_expansion_results {
@@ -234,62 +677,22 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
 
       let match_results: Vec<_> = outputs.into_iter().collect();
 
-      if strict_match_behavior.should_check_glob_matches() {
-        // Each `GlobExpansionCacheEntry` stored in `completed` for some `PathGlob` has the field
-        // `matched` to denote whether that specific `PathGlob` matched any files. We propagate a
-        // positive `matched` condition to all transitive "parents" of any glob which expands to
-        // some non-empty set of `PathStat`s. The `sources` field contains the parents (see the enum
-        // `GlobSource`), which may be another glob, or it might be a `GlobParsedSource`. We record
-        // all `GlobParsedSource` inputs which transitively expanded to some file here, and below we
-        // warn or error if some of the inputs were not found.
-        let mut inputs_with_matches: HashSet<GlobParsedSource> = HashSet::new();
-
-        // `completed` is an IndexMap, and we immediately insert every glob we expand into
-        // `completed`, recording any `PathStat`s and `PathGlob`s it expanded to (and then expanding
-        // those child globs in the next iteration of the loop_fn). If we iterate in
-        // reverse order of expansion (using .rev()), we ensure that we have already visited every
-        // "child" glob of the glob we are operating on while iterating. This is a reverse
-        // "topological ordering" which preserves the partial order from parent to child globs.
-        let all_globs: Vec<PathGlob> = completed.keys().rev().cloned().collect();
-        for cur_glob in all_globs {
-          // Note that we talk of "parents" and "childen", but this structure is actually a DAG,
-          // because different `DirWildcard`s can potentially expand (transitively) to the same
-          // intermediate glob. The "parents" of each glob are stored in the `sources` field of its
-          // `GlobExpansionCacheEntry` (which is mutably updated with any new parents on each
-          // iteration of the loop_fn above). This can be considered "amortized" and/or "memoized",
-          // because we only traverse every parent -> child link once.
-          let new_matched_source_globs = match completed.get(&cur_glob).unwrap() {
-            &GlobExpansionCacheEntry {
-              ref matched,
-              ref sources,
-              ..
-            } => match matched {
-              // Neither this glob, nor any of its children, expanded to any `PathStat`s, so we have
-              // nothing to propagate.
-              &GlobMatch::DidNotMatchAnyFiles => vec![],
-              &GlobMatch::SuccessfullyMatchedSomeFiles => sources
-                .iter()
-                .filter_map(|src| match src {
-                  // This glob matched some files, so its parent also matched some files.
-                  &GlobSource::ParentGlob(ref path_glob) => Some(path_glob.clone()),
-                  // We've found one of the root inputs, coming from a glob which transitively
-                  // matched some child -- record it (this may already exist in the set).
-                  &GlobSource::ParsedInput(ref parsed_source) => {
-                    inputs_with_matches.insert(parsed_source.clone());
-                    None
-                  }
-                })
-                .collect(),
-            },
-          };
-          new_matched_source_globs.into_iter().for_each(|path_glob| {
-            // Overwrite whatever was in there before -- we now know these globs transitively
-            // expanded to some non-empty set of `PathStat`s.
-            let entry = completed.get_mut(&path_glob).unwrap();
-            entry.matched = GlobMatch::SuccessfullyMatchedSomeFiles;
-          });
-        }
+      // Matches are only recorded without local excludes, which might hide them.
+      let negative_match_filter = context
+        .negative_match_filter()
+        .filter(|_| exclude.exclude_patterns().is_empty());
+      let inputs_with_matches = if strict_match_behavior.should_check_glob_matches()
+        || negative_match_filter.is_some()
+      {
+        propagate_matches(&mut completed)
+      } else {
+        HashSet::new()
+      };
+      if let Some(filter) = negative_match_filter {
+        record_negative_matches(filter, &completed);
+      }
 
+      if strict_match_behavior.should_check_glob_matches() {
         // Get all the inputs which didn't transitively expand to any files.
         let non_matching_inputs: Vec<GlobParsedSource> = include
           .into_iter()
@@ -331,11 +734,28 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
     &self,
     sourced_glob: GlobWithSource,
     exclude: &Arc<GitignoreStyleExcludes>,
+    links: FollowedLinks,
   ) -> BoxFuture<SingleExpansionResult, E> {
-    match sourced_glob.path_glob.clone() {
+    let path_glob = sourced_glob.path_glob.clone();
+    let known_empty = self
+      .negative_match_filter()
+      .and_then(|filter| {
+        matched_suffix(&path_glob).map(|(dir, suffix)| filter.is_empty(dir, suffix))
+      })
+      .unwrap_or(false);
+    if known_empty {
+      return future::ok(SingleExpansionResult {
+        sourced_glob,
+        path_stats: vec![],
+        globs: vec![],
+      }).to_boxed();
+    }
+    match path_glob.clone() {
       PathGlob::Wildcard { canonical_dir, symbolic_path, wildcard } =>
       // Filter directory listing to return PathStats, with no continuation.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(
+          path_glob, canonical_dir, symbolic_path, wildcard, exclude, links
+        )
         .map(move |path_stats| SingleExpansionResult {
           sourced_glob,
           path_stats,
@@ -344,13 +764,15 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
         .to_boxed(),
       PathGlob::DirWildcard { canonical_dir, symbolic_path, wildcard, remainder } =>
       // Filter directory listing and request additional PathGlobs for matched Dirs.
-        self.directory_listing(canonical_dir, symbolic_path, wildcard, exclude)
+        self.directory_listing(
+          path_glob, canonical_dir, symbolic_path, wildcard, exclude, links
+        )
         .and_then(move |path_stats| {
           path_stats.into_iter()
             .filter_map(|ps| match ps {
               PathStat::Dir { path, stat } =>
                 Some(
-                  PathGlob::parse_globs(stat, path, &remainder)
+                  PathGlob::parse_globs(stat, path.to_path_buf(), &remainder)
                     .map_err(|e| Self::mk_error(e.as_str()))
                 ),
               PathStat::File { .. } => None,
@@ -372,25 +794,70 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
     }
   }
 
-  fn canonicalize(&self, symbolic_path: PathBuf, link: &Link) -> BoxFuture<Option<PathStat>, E> {
+  ///
+  /// Canonicalizes the given Link, which is the last of the given Links followed to reach a path.
+  ///
+  fn canonicalize(
+    &self,
+    symbolic_path: PathBuf,
+    link: &Link,
+    links: FollowedLinks,
+  ) -> BoxFuture<Option<PathStat>, E> {
+    if links.depth > MAX_LINK_DEPTH {
+      return future::err(Self::mk_error(&format!(
+        "Too many levels of symbolic links while canonicalizing {:?}: more than {} links were \
+         followed.",
+        symbolic_path, MAX_LINK_DEPTH
+      ))).to_boxed();
+    }
+
     // Read the link, which may result in PathGlob(s) that match 0 or 1 Path.
     let context = self.clone();
+    let link_escape_behavior = self.link_escape_behavior();
+    let link_path = link.0.to_path_buf();
     self
       .read_link(link)
-      .map(|dest_path| {
-        // If the link destination can't be parsed as PathGlob(s), it is broken.
-        dest_path
-          .to_str()
-          .and_then(|dest_str| {
-            // Escape any globs in the parsed dest, which should guarantee one output PathGlob.
-            PathGlob::create(&[Pattern::escape(dest_str)]).ok()
-          })
-          .unwrap_or_else(|| vec![])
+      .and_then(move |dest_path| {
+        if dest_path.is_absolute() {
+          // The link points outside of the root. Unless the VFS follows such links, it is skipped,
+          // or fails if it was matched itself rather than traversed (see `LinkEscapeBehavior`).
+          match link_escape_behavior {
+            LinkEscapeBehavior::Follow => {}
+            LinkEscapeBehavior::Error if !links.traversed => {
+              return Err(Self::mk_error(&link_escape_message(
+                &link_path, &dest_path,
+              )))
+            }
+            LinkEscapeBehavior::Warn | LinkEscapeBehavior::Error => return Ok(vec![]),
+          }
+          // Match its destination within its absolute parent directory.
+          return Ok(match (dest_path.parent(), dest_path.file_name()) {
+            (Some(parent), Some(name)) => Pattern::new(&Pattern::escape(&name.to_string_lossy()))
+              .map(|wildcard| {
+                vec![PathGlob::wildcard(
+                  Dir(parent.to_owned().into()),
+                  parent.to_owned(),
+                  wildcard,
+                )]
+              })
+              .unwrap_or_else(|_| vec![]),
+            _ => vec![],
+          });
+        }
+        // If the link destination can't be parsed as PathGlob(s), it is broken. Escape any globs
+        // in the dest, which should guarantee one output PathGlob. A dest which is not UTF8 is
+        // matched in its lossy form, in the same way as scandir'd names.
+        Ok(
+          PathGlob::create(&[Pattern::escape(&dest_path.to_string_lossy())])
+            .unwrap_or_else(|_| vec![]),
+        )
       })
       .and_then(|link_globs| {
         let new_path_globs =
           future::result(PathGlobs::from_globs(link_globs)).map_err(|e| Self::mk_error(e.as_str()));
-        new_path_globs.and_then(move |path_globs| context.expand(path_globs))
+        new_path_globs.and_then(move |path_globs| {
+          GlobMatchingImplementation::expand(&context, path_globs, links)
+        })
       })
       .map(|mut path_stats| {
         // Since we've escaped any globs in the parsed path, expect either 0 or 1 destination.
@@ -404,3 +871,372 @@ trait GlobMatchingImplementation<E: Send + Sync + 'static>: VFS<E> {
 }
 
 impl<E: Send + Sync + 'static, T: VFS<E>> GlobMatchingImplementation<E> for T {}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+  extern crate testutil;
+
+  use super::{GlobExpansionMemo, GlobMatching, NegativeMatchFilter};
+  use boxfuture::BoxFuture;
+  use futures::Future;
+  use std::collections::BTreeSet;
+  use std::io;
+  use std::os::unix::fs::symlink;
+  use std::path::{Path, PathBuf};
+  use std::sync::{Arc, Mutex};
+  use testutil::make_file;
+  use {
+    Dir, DirectoryListing, Link, LinkEscapeBehavior, ListingFilter, PathGlobs, PathStat, PosixFS,
+    ResettablePool, SpecialFileBehavior, Stat, StrictGlobMatching, VFS,
+  };
+
+  ///
+  /// A VFS which shares a GlobExpansionMemo and a NegativeMatchFilter between its expansions, and
+  /// records the directories that it lists.
+  ///
+  #[derive(Clone)]
+  struct MemoizingFS {
+    posix_fs: Arc<PosixFS>,
+    memo: Arc<GlobExpansionMemo>,
+    filter: Arc<NegativeMatchFilter>,
+    listed: Arc<Mutex<BTreeSet<PathBuf>>>,
+  }
+
+  impl VFS<io::Error> for MemoizingFS {
+    fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
+      VFS::read_link(&self.posix_fs, link)
+    }
+
+    fn scandir_matching(
+      &self,
+      dir: Dir,
+      filter: &ListingFilter,
+    ) -> BoxFuture<Arc<DirectoryListing>, io::Error> {
+      self.listed.lock().unwrap().insert(dir.0.to_path_buf());
+      VFS::scandir_matching(&self.posix_fs, dir, filter)
+    }
+
+    fn is_ignored(&self, stat: &Stat) -> bool {
+      VFS::is_ignored(&self.posix_fs, stat)
+    }
+
+    fn mk_error(msg: &str) -> io::Error {
+      io::Error::new(io::ErrorKind::Other, msg)
+    }
+
+    fn link_escape_behavior(&self) -> LinkEscapeBehavior {
+      VFS::link_escape_behavior(&self.posix_fs)
+    }
+
+    fn glob_expansion_memo(&self) -> Option<&GlobExpansionMemo> {
+      Some(&self.memo)
+    }
+
+    fn negative_match_filter(&self) -> Option<&NegativeMatchFilter> {
+      Some(&self.filter)
+    }
+  }
+
+  #[test]
+  fn overlapping_globs_share_matches() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for file in &["a/1.py", "a/2.py", "a/b/3.py", "a/b/4.txt"] {
+      make(dir.path(), file);
+    }
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(expand(&fs, "**/*.py", &[]), vec!["a/1.py", "a/2.py", "a/b/3.py"]);
+    let memoized = memoized_globs(&fs);
+    assert!(memoized > 0);
+
+    // Expansions with different excludes reuse the memoized matches, and apply their own excludes.
+    assert_eq!(expand(&fs, "**/*.py", &["a/b"]), vec!["a/1.py", "a/2.py"]);
+    assert_eq!(expand(&fs, "**/*.py", &["2.py"]), vec!["a/1.py", "a/b/3.py"]);
+    assert_eq!(memoized_globs(&fs), memoized);
+  }
+
+  #[test]
+  fn changed_listings_are_not_reused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    make(dir.path(), "a/1.py");
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(expand(&fs, "a/*.py", &[]), vec!["a/1.py"]);
+    make(dir.path(), "a/2.py");
+    assert_eq!(expand(&fs, "a/*.py", &[]), vec!["a/1.py", "a/2.py"]);
+  }
+
+  #[test]
+  fn links_are_not_memoized() {
+    let dir = tempfile::TempDir::new().unwrap();
+    make(dir.path(), "a/1.py");
+    make(dir.path(), "a/2.py");
+    let link = dir.path().join("link.py");
+    symlink("a/1.py", &link).unwrap();
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(expand_canonical(&fs, "*.py"), vec!["a/1.py"]);
+    // Retargeting the link does not change the listing of its directory, but the link is
+    // canonicalized again.
+    ::std::fs::remove_file(&link).unwrap();
+    symlink("a/2.py", &link).unwrap();
+    assert_eq!(expand_canonical(&fs, "*.py"), vec!["a/2.py"]);
+  }
+
+  #[test]
+  fn deep_directory_trees() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let deep = (0..200).map(|i| i.to_string()).collect::<Vec<_>>().join("/");
+    make(dir.path(), &format!("{}/deep.py", deep));
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(
+      expand(&fs, "**/*.py", &[]),
+      vec![format!("{}/deep.py", deep)]
+    );
+  }
+
+  #[test]
+  fn link_cycles_fail() {
+    let dir = tempfile::TempDir::new().unwrap();
+    symlink("b", dir.path().join("a")).unwrap();
+    symlink("a", dir.path().join("b")).unwrap();
+    let fs = new_memoizing_fs(dir.path());
+
+    let path_globs =
+      PathGlobs::create(&["a".to_owned()], &[], StrictGlobMatching::Ignore).unwrap();
+    let err = fs.expand(path_globs).wait().unwrap_err();
+    assert!(
+      err.to_string().contains("Too many levels of symbolic links"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn recursive_globs_skip_subtrees_without_matches() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for file in &["a/BUILD", "a/b/1.py", "c/d/BUILD", "c/d/e/2.py", "f/g/h/3.py"] {
+      make(dir.path(), file);
+    }
+    let fs = new_memoizing_fs(dir.path());
+    let expected = vec!["a/BUILD", "c/d/BUILD"];
+
+    assert_eq!(expand(&fs, "**/BUILD", &[]), expected);
+    assert_eq!(take_listed(&fs).len(), 9);
+
+    // Only the directories which contain matches are listed again.
+    assert_eq!(expand(&fs, "**/BUILD", &[]), expected);
+    assert_eq!(take_listed(&fs), vec!["", "a", "c", "c/d"]);
+    assert_eq!(expand(&fs, "c/**/BUILD", &[]), vec!["c/d/BUILD"]);
+    assert_eq!(take_listed(&fs), vec!["", "c", "c/d"]);
+
+    // Other suffixes are not affected.
+    assert_eq!(expand(&fs, "**/*.py", &[]), vec!["a/b/1.py", "c/d/e/2.py", "f/g/h/3.py"]);
+    assert_eq!(take_listed(&fs).len(), 9);
+
+    // Once cleared, new matches are found.
+    make(dir.path(), "f/g/BUILD");
+    fs.filter.clear();
+    assert_eq!(
+      expand(&fs, "**/BUILD", &[]),
+      vec!["a/BUILD", "c/d/BUILD", "f/g/BUILD"]
+    );
+  }
+
+  #[test]
+  fn subdirectory_expansions_do_not_prove_directories_empty() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for file in &["x/BUILD", "x/y/1.py"] {
+      make(dir.path(), file);
+    }
+    let fs = new_memoizing_fs(dir.path());
+
+    // Only the subdirectories of `x` are expanded, so `x` itself is not known to be empty.
+    assert_eq!(expand(&fs, "x/*/**/BUILD", &[]), Vec::<String>::new());
+    assert_eq!(expand(&fs, "x/BUILD", &[]), vec!["x/BUILD"]);
+    assert_eq!(expand(&fs, "x/**/BUILD", &[]), vec!["x/BUILD"]);
+
+    // But its subdirectories are.
+    take_listed(&fs);
+    assert_eq!(expand(&fs, "x/*/**/BUILD", &[]), Vec::<String>::new());
+    assert_eq!(take_listed(&fs), vec!["", "x"]);
+  }
+
+  #[test]
+  fn excluded_expansions_do_not_prove_subtrees_empty() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for file in &["a/BUILD", "b/BUILD"] {
+      make(dir.path(), file);
+    }
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(expand(&fs, "**/BUILD", &["b"]), vec!["a/BUILD"]);
+    assert_eq!(expand(&fs, "**/BUILD", &[]), vec!["a/BUILD", "b/BUILD"]);
+  }
+
+  #[test]
+  fn escaping_links_are_skipped() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside = tempfile::TempDir::new().unwrap();
+    make(dir.path(), "a.py");
+    make(outside.path(), "b.py");
+    symlink(outside.path().join("b.py"), dir.path().join("absolute.py")).unwrap();
+    symlink(relative_path_to(dir.path(), "b.py", outside.path()), dir.path().join("relative.py"))
+      .unwrap();
+    let fs = new_memoizing_fs(dir.path());
+
+    assert_eq!(expand(&fs, "*.py", &[]), vec!["a.py"]);
+  }
+
+  #[test]
+  fn escaping_links_fail_when_configured() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside = tempfile::TempDir::new().unwrap();
+    make(outside.path(), "b.py");
+    symlink(outside.path().join("b.py"), dir.path().join("absolute.py")).unwrap();
+    let fs = new_memoizing_fs_with(dir.path(), LinkEscapeBehavior::Error);
+
+    let path_globs =
+      PathGlobs::create(&["*.py".to_owned()], &[], StrictGlobMatching::Ignore).unwrap();
+    let err = fs.expand(path_globs).wait().unwrap_err();
+    assert!(
+      err.to_string().contains("\"absolute.py\" is a symlink to")
+        && err.to_string().contains("which is outside of the root"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn escaping_links_only_fail_globs_which_match_them() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside = tempfile::TempDir::new().unwrap();
+    make(dir.path(), "a/1.txt");
+    make(dir.path(), "a/BUILD");
+    make(outside.path(), "BUILD");
+    symlink(outside.path(), dir.path().join("a/venv")).unwrap();
+    let fs = new_memoizing_fs_with(dir.path(), LinkEscapeBehavior::Error);
+
+    assert_eq!(expand(&fs, "a/*.txt", &[]), vec!["a/1.txt"]);
+    assert_eq!(expand(&fs, "**/BUILD", &[]), vec!["a/BUILD"]);
+    assert_eq!(expand(&fs, "a/*/BUILD", &[]), Vec::<String>::new());
+
+    let path_globs =
+      PathGlobs::create(&["a/*".to_owned()], &[], StrictGlobMatching::Ignore).unwrap();
+    let err = fs.expand(path_globs).wait().unwrap_err();
+    assert!(
+      err.to_string().contains("\"a/venv\" is a symlink to"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn escaping_links_are_followed_when_configured() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside = tempfile::TempDir::new().unwrap();
+    let outside_path = outside.path().canonicalize().unwrap();
+    make(&outside_path, "lib/c.py");
+    make(&outside_path, "b.py");
+    symlink(outside_path.join("b.py"), dir.path().join("absolute.py")).unwrap();
+    symlink(relative_path_to(dir.path(), "lib", &outside_path), dir.path().join("lib")).unwrap();
+    let fs = new_memoizing_fs_with(dir.path(), LinkEscapeBehavior::Follow);
+
+    assert_eq!(
+      expand(&fs, "**/*.py", &[]),
+      vec!["absolute.py", "lib/c.py"]
+    );
+    assert_eq!(
+      expand_canonical(&fs, "**/*.py"),
+      vec![
+        outside_path.join("b.py").to_string_lossy().into_owned(),
+        outside_path.join("lib/c.py").to_string_lossy().into_owned(),
+      ]
+    );
+  }
+
+  ///
+  /// A relative path from the root `from` to `name` in the directory `to`.
+  ///
+  fn relative_path_to(from: &Path, name: &str, to: &Path) -> PathBuf {
+    let depth = from.canonicalize().unwrap().components().count() - 1;
+    let mut path = (0..depth).map(|_| "..").collect::<PathBuf>();
+    for component in to.canonicalize().unwrap().components().skip(1) {
+      path.push(component.as_os_str());
+    }
+    path.join(name)
+  }
+
+  fn make(root: &Path, file: &str) {
+    let path = root.join(file);
+    ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    make_file(&path, &[], 0o600);
+  }
+
+  fn new_memoizing_fs(root: &Path) -> MemoizingFS {
+    new_memoizing_fs_with(root, LinkEscapeBehavior::Warn)
+  }
+
+  fn new_memoizing_fs_with(root: &Path, link_escape_behavior: LinkEscapeBehavior) -> MemoizingFS {
+    let posix_fs = PosixFS::new(
+      root,
+      Arc::new(ResettablePool::new("test-pool-".to_string())),
+      &[],
+      SpecialFileBehavior::Error,
+    ).unwrap()
+      .with_link_escape_behavior(link_escape_behavior);
+    MemoizingFS {
+      posix_fs: Arc::new(posix_fs),
+      memo: Arc::new(GlobExpansionMemo::new()),
+      filter: Arc::new(NegativeMatchFilter::new()),
+      listed: Arc::new(Mutex::new(BTreeSet::new())),
+    }
+  }
+
+  fn take_listed(fs: &MemoizingFS) -> Vec<String> {
+    let listed = ::std::mem::replace(&mut *fs.listed.lock().unwrap(), BTreeSet::new());
+    sorted_paths(listed.iter().map(|path| path.as_path()))
+  }
+
+  fn memoized_globs(fs: &MemoizingFS) -> usize {
+    fs.memo.entries.lock().unwrap().len()
+  }
+
+  fn expand(fs: &MemoizingFS, include: &str, exclude: &[&str]) -> Vec<String> {
+    sorted_paths(
+      expand_path_stats(fs, include, exclude)
+        .iter()
+        .map(|path_stat| path_stat.path()),
+    )
+  }
+
+  fn expand_canonical(fs: &MemoizingFS, include: &str) -> Vec<String> {
+    sorted_paths(
+      expand_path_stats(fs, include, &[])
+        .iter()
+        .map(|path_stat| match path_stat {
+          &PathStat::Dir { ref stat, .. } => stat.0.as_path(),
+          &PathStat::File { ref stat, .. } => stat.path.as_path(),
+        }),
+    )
+  }
+
+  fn expand_path_stats(fs: &MemoizingFS, include: &str, exclude: &[&str]) -> Vec<PathStat> {
+    let path_globs = PathGlobs::create(
+      &[include.to_owned()],
+      &exclude.iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+      StrictGlobMatching::Ignore,
+    ).unwrap();
+    fs.expand(path_globs).wait().unwrap()
+  }
+
+  fn sorted_paths<'a, I: Iterator<Item = &'a Path>>(paths: I) -> Vec<String> {
+    let mut paths = paths
+      .map(|path| path.to_string_lossy().into_owned())
+      .collect::<Vec<_>>();
+    paths.sort();
+    paths
+  }
+}
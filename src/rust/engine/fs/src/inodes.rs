@@ -0,0 +1,272 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::sync::oneshot;
+use futures::{future, Future};
+use hashing::Digest;
+use os;
+
+///
+/// Identifies a file on disk which has more than one link (ie, a file which is hardlinked to from
+/// more than one path).
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Inode {
+  dev: u64,
+  ino: u64,
+}
+
+impl Inode {
+  ///
+  /// Returns the Inode for the given file metadata, if the file has more than one link. Files with
+  /// only one link cannot share their content with any other path, so they are not identified.
+  ///
+  pub fn for_metadata(metadata: &fs::Metadata) -> Option<Inode> {
+    os::linked_inode(metadata).map(|(dev, ino)| Inode { dev, ino })
+  }
+}
+
+enum InodeState {
+  Digested(Digest),
+  // The first path for the inode is being digested, and these callers are waiting for it.
+  Digesting(Vec<oneshot::Sender<Digest>>),
+}
+
+///
+/// Memoizes the Digests of files which share an inode, so that a file which is hardlinked to from
+/// many paths (as is common in toolchains that use "hardlink farms") is only read and digested
+/// once.
+///
+/// Files are assumed not to change while they are memoized, so an InodeDigests should either not
+/// outlive a capture, or be cleared whenever files might have changed (see `clear`).
+///
+#[derive(Clone)]
+pub struct InodeDigests {
+  inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+  // Incremented by each `clear`, so that digests which began before it are not memoized.
+  generation: u64,
+  states: HashMap<Inode, InodeState>,
+}
+
+impl InodeDigests {
+  pub fn new() -> InodeDigests {
+    InodeDigests {
+      inner: Arc::new(Mutex::new(Inner {
+        generation: 0,
+        states: HashMap::new(),
+      })),
+    }
+  }
+
+  ///
+  /// Forgets the Digests of all files. Any callers which are waiting for a file to be digested
+  /// digest their own files instead.
+  ///
+  pub fn clear(&self) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.generation += 1;
+    inner.states.clear();
+  }
+
+  ///
+  /// Digests a file using the given function, unless another file with the same Inode has already
+  /// been (or is being) digested, in which case its Digest is used. If digesting the other file
+  /// fails, the given function is used after all.
+  ///
+  pub fn digest<F, E>(&self, inode: Option<Inode>, digest_file: F) -> BoxFuture<Digest, E>
+  where
+    F: FnOnce() -> BoxFuture<Digest, E> + Send + 'static,
+    E: Send + 'static,
+  {
+    let inode = match inode {
+      Some(inode) => inode,
+      None => return digest_file(),
+    };
+
+    let (generation, waiting) = {
+      let mut inner = self.inner.lock().unwrap();
+      let generation = inner.generation;
+      let waiting = match inner.states.entry(inode) {
+        Entry::Occupied(mut entry) => match entry.get_mut() {
+          &mut InodeState::Digested(digest) => return future::ok(digest).to_boxed(),
+          &mut InodeState::Digesting(ref mut waiters) => {
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(sender);
+            Some(receiver)
+          }
+        },
+        Entry::Vacant(entry) => {
+          entry.insert(InodeState::Digesting(Vec::new()));
+          None
+        }
+      };
+      (generation, waiting)
+    };
+    if let Some(receiver) = waiting {
+      return receiver
+        .then(move |res| match res {
+          Ok(digest) => future::ok(digest).to_boxed(),
+          // The other file failed to be digested: try this one.
+          Err(oneshot::Canceled) => digest_file(),
+        })
+        .to_boxed();
+    }
+
+    let inner = self.inner.clone();
+    digest_file()
+      .then(move |res| {
+        let mut inner = inner.lock().unwrap();
+        if inner.generation != generation {
+          // The file might have changed since it was digested.
+          return res;
+        }
+        match res {
+          Ok(digest) => {
+            let previous = inner.states.insert(inode, InodeState::Digested(digest));
+            if let Some(InodeState::Digesting(waiters)) = previous {
+              for waiter in waiters {
+                let _ = waiter.send(digest);
+              }
+            }
+          }
+          Err(_) => {
+            // Dropping the waiters causes each of them to digest their own file.
+            inner.states.remove(&inode);
+          }
+        }
+        res
+      })
+      .to_boxed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+
+  use super::{Inode, InodeDigests};
+  use boxfuture::{BoxFuture, Boxable};
+  use futures::{future, Future};
+  use hashing::{Digest, Fingerprint};
+  use std::fs;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use testutil::make_file;
+
+  fn digest() -> Digest {
+    Digest(
+      Fingerprint::from_hex_string(
+        "693d8db7b05e99c6b7a7c0616456039d89c555029026936248085193559a0b5d",
+      ).unwrap(),
+      16,
+    )
+  }
+
+  fn counting_digester(
+    calls: &Arc<AtomicUsize>,
+    result: Result<Digest, String>,
+  ) -> impl FnOnce() -> BoxFuture<Digest, String> + Send + 'static {
+    let calls = calls.clone();
+    move || {
+      calls.fetch_add(1, Ordering::SeqCst);
+      future::result(result).to_boxed()
+    }
+  }
+
+  #[test]
+  fn only_linked_files_have_inodes() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roland = dir.path().join("roland");
+    make_file(&roland, b"European Burmese", 0o644);
+    assert_eq!(None, Inode::for_metadata(&fs::metadata(&roland).unwrap()));
+
+    let susannah = dir.path().join("susannah");
+    fs::hard_link(&roland, &susannah).unwrap();
+    let inode = Inode::for_metadata(&fs::metadata(&roland).unwrap());
+    assert!(inode.is_some());
+    assert_eq!(inode, Inode::for_metadata(&fs::metadata(&susannah).unwrap()));
+  }
+
+  #[test]
+  fn linked_files_are_digested_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roland = dir.path().join("roland");
+    make_file(&roland, b"European Burmese", 0o644);
+    let susannah = dir.path().join("susannah");
+    fs::hard_link(&roland, &susannah).unwrap();
+
+    let inode_digests = InodeDigests::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    for path in vec![roland, susannah] {
+      let inode = Inode::for_metadata(&fs::metadata(&path).unwrap());
+      let digested = inode_digests
+        .digest(inode, counting_digester(&calls, Ok(digest())))
+        .wait();
+      assert_eq!(Ok(digest()), digested);
+    }
+    assert_eq!(1, calls.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn unlinked_files_are_digested_separately() {
+    let inode_digests = InodeDigests::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+      let digested = inode_digests
+        .digest(None, counting_digester(&calls, Ok(digest())))
+        .wait();
+      assert_eq!(Ok(digest()), digested);
+    }
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn failure_is_not_memoized() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roland = dir.path().join("roland");
+    make_file(&roland, b"European Burmese", 0o644);
+    fs::hard_link(&roland, dir.path().join("susannah")).unwrap();
+    let inode = Inode::for_metadata(&fs::metadata(&roland).unwrap());
+
+    let inode_digests = InodeDigests::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let failed = inode_digests
+      .digest(inode, counting_digester(&calls, Err("Gone".to_string())))
+      .wait();
+    assert_eq!(Err("Gone".to_string()), failed);
+    let digested = inode_digests
+      .digest(inode, counting_digester(&calls, Ok(digest())))
+      .wait();
+    assert_eq!(Ok(digest()), digested);
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn clear_forgets_digests() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roland = dir.path().join("roland");
+    make_file(&roland, b"European Burmese", 0o644);
+    fs::hard_link(&roland, dir.path().join("susannah")).unwrap();
+    let inode = Inode::for_metadata(&fs::metadata(&roland).unwrap());
+
+    let inode_digests = InodeDigests::new();
+    let calls = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+      let digested = inode_digests
+        .digest(inode, counting_digester(&calls, Ok(digest())))
+        .wait();
+      assert_eq!(Ok(digest()), digested);
+      inode_digests.clear();
+    }
+    assert_eq!(2, calls.load(Ordering::SeqCst));
+  }
+}
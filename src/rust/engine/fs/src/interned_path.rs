@@ -0,0 +1,202 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// The number of independently locked shards of the table, so that threads interning different
+// paths rarely contend for a lock.
+const SHARD_COUNT: usize = 16;
+
+// The minimum number of paths in a shard of the table before it is pruned of unused paths.
+const MIN_PRUNE_SIZE: usize = 1024;
+
+struct Interner {
+  paths: HashSet<Arc<Path>>,
+  prune_size: usize,
+  // The bytes allocated for the paths in the table, maintained as they are added and pruned.
+  path_bytes: usize,
+}
+
+impl Interner {
+  fn new() -> Interner {
+    Interner {
+      paths: HashSet::new(),
+      prune_size: MIN_PRUNE_SIZE,
+      path_bytes: 0,
+    }
+  }
+
+  fn intern(&mut self, path: &Path) -> Arc<Path> {
+    if let Some(interned) = self.paths.get(path) {
+      return interned.clone();
+    }
+    if self.paths.len() >= self.prune_size {
+      // Drop the paths which are only referenced by the table, and then allow the table to double
+      // in size before pruning it again, so that the cost of pruning is amortized.
+      let mut pruned_bytes = 0;
+      self.paths.retain(|p| {
+        let keep = Arc::strong_count(p) > 1;
+        if !keep {
+          pruned_bytes += Interner::allocated_size(p);
+        }
+        keep
+      });
+      self.path_bytes -= pruned_bytes;
+      self.prune_size = ::std::cmp::max(MIN_PRUNE_SIZE, self.paths.len() * 2);
+    }
+    let interned: Arc<Path> = Arc::from(path);
+    self.path_bytes += Interner::allocated_size(&interned);
+    self.paths.insert(interned.clone());
+    interned
+  }
+
+  fn allocated_size(path: &Path) -> usize {
+    // Each path is allocated along with the strong and weak counts of its Arc.
+    2 * mem::size_of::<usize>() + path.as_os_str().len()
+  }
+
+  fn heap_size(&self) -> usize {
+    self.paths.capacity() * mem::size_of::<Arc<Path>>() + self.path_bytes
+  }
+}
+
+lazy_static! {
+  static ref INTERNERS: Vec<Mutex<Interner>> =
+    (0..SHARD_COUNT).map(|_| Mutex::new(Interner::new())).collect();
+}
+
+///
+/// Returns the shard of the table which holds the given path.
+///
+fn shard_for(path: &Path) -> &'static Mutex<Interner> {
+  let mut hasher = DefaultHasher::new();
+  path.hash(&mut hasher);
+  &INTERNERS[(hasher.finish() % SHARD_COUNT as u64) as usize]
+}
+
+///
+/// An immutable path which shares its storage with every other live InternedPath that is equal to
+/// it.
+///
+/// The Stats and PathStats of a large repository are held in many places at once (in the
+/// DirectoryListings, Snapshots and Nodes of the Graph, for example), and holding a copy of each
+/// path in each of them would be very expensive. Cloning an InternedPath is cheap, and
+/// constructing one for a path that is already interned does not allocate.
+///
+#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct InternedPath(Arc<Path>);
+
+impl InternedPath {
+  pub fn new<P: AsRef<Path>>(path: P) -> InternedPath {
+    let path = path.as_ref();
+    InternedPath(shard_for(path).lock().unwrap().intern(path))
+  }
+
+  pub fn as_path(&self) -> &Path {
+    &self.0
+  }
+
+  ///
+  /// An estimate of the bytes of heap memory held by the table of interned paths, including paths
+  /// which are no longer in use but have not yet been pruned.
+  ///
+  pub fn heap_size() -> usize {
+    INTERNERS
+      .iter()
+      .map(|interner| interner.lock().unwrap().heap_size())
+      .sum()
+  }
+}
+
+impl Deref for InternedPath {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl AsRef<Path> for InternedPath {
+  fn as_ref(&self) -> &Path {
+    &self.0
+  }
+}
+
+impl fmt::Debug for InternedPath {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    self.0.fmt(f)
+  }
+}
+
+impl From<PathBuf> for InternedPath {
+  fn from(path: PathBuf) -> InternedPath {
+    InternedPath::new(path)
+  }
+}
+
+impl<'a> From<&'a Path> for InternedPath {
+  fn from(path: &'a Path) -> InternedPath {
+    InternedPath::new(path)
+  }
+}
+
+impl From<InternedPath> for PathBuf {
+  fn from(path: InternedPath) -> PathBuf {
+    path.0.to_path_buf()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::InternedPath;
+  use std::path::{Path, PathBuf};
+  use std::sync::Arc;
+
+  #[test]
+  fn equal_paths_are_shared() {
+    let a = InternedPath::new("a/b/c");
+    let b = InternedPath::from(PathBuf::from("a/b").join("c"));
+    assert_eq!(a, b);
+    assert!(Arc::ptr_eq(&a.0, &b.0));
+    assert_eq!(a.as_path(), Path::new("a/b/c"));
+  }
+
+  #[test]
+  fn different_paths_are_distinct() {
+    let a = InternedPath::new("a/b");
+    let b = InternedPath::new("a/c");
+    assert_ne!(a, b);
+    assert!(a < b);
+  }
+
+  #[test]
+  fn unused_paths_are_pruned() {
+    let kept = InternedPath::new("kept/path");
+    for i in 0..(super::MIN_PRUNE_SIZE * super::SHARD_COUNT * 2) {
+      InternedPath::new(format!("pruned/{}", i));
+    }
+    // Pruning must not affect live paths.
+    assert!(Arc::ptr_eq(&kept.0, &InternedPath::new("kept/path").0));
+  }
+
+  #[test]
+  fn heap_size() {
+    let path = InternedPath::new("heap/size/of/a/path");
+    assert!(InternedPath::heap_size() >= path.as_os_str().len());
+  }
+
+  #[test]
+  fn debug() {
+    assert_eq!(
+      format!("{:?}", InternedPath::new("a/b")),
+      format!("{:?}", Path::new("a/b"))
+    );
+  }
+}
@@ -1,17 +1,35 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+mod clone;
+mod directory_cache;
+pub mod fd_budget;
+pub use fd_budget::FdBudget;
 mod glob_matching;
-pub use glob_matching::GlobMatching;
+mod inodes;
+pub use inodes::{Inode, InodeDigests};
+mod interned_path;
+pub mod os;
+pub use interned_path::InternedPath;
+pub use glob_matching::{
+  GlobExpansionMemo, GlobMatching, NegativeMatchFilter, DEFAULT_GLOB_EXPANSION_PARALLELISM,
+};
 mod snapshot;
 pub use snapshot::{
-  OneOffStoreFileByDigest, Snapshot, StoreFileByDigest, EMPTY_DIGEST, EMPTY_FINGERPRINT,
+  OneOffStoreFileByDigest, Snapshot, SnapshotMemo, StoreFileByDigest, EMPTY_DIGEST,
+  EMPTY_FINGERPRINT,
 };
 mod store;
-pub use store::Store;
+pub use store::{
+  ExternalCopies, LocalStoreLimits, RemoteTimeouts, RemoteTransferLimits, Store, WriteThrough,
+};
 mod pool;
-pub use pool::ResettablePool;
+pub use pool::{Instrumented, PoolCounters, PoolInstrumentation, ResettablePool};
+mod size_summary;
+pub use size_summary::{summarize_sizes, DirectorySize};
+pub mod threads;
 
+extern crate async_semaphore;
 extern crate bazel_protos;
 #[macro_use]
 extern crate boxfuture;
@@ -20,6 +38,7 @@ extern crate bytes;
 extern crate digest;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate futures_timer;
 extern crate glob;
 extern crate grpcio;
 extern crate hashing;
@@ -28,11 +47,14 @@ extern crate indexmap;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 extern crate lmdb;
+extern crate lmdb_sys;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
 extern crate mock;
+extern crate num_cpus;
 extern crate protobuf;
 extern crate resettable;
 extern crate sha2;
@@ -41,16 +63,19 @@ extern crate tempfile;
 #[cfg(test)]
 extern crate testutil;
 
-use std::cmp::min;
-use std::io::{self, Read};
-use std::os::unix::fs::PermissionsExt;
+use std::cmp::{min, Ordering};
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
 use std::path::{Component, Path, PathBuf};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{fmt, fs};
 
 use bytes::Bytes;
 use futures::future::{self, Future};
-use glob::Pattern;
+use futures::stream::{self, Stream};
+pub use glob::Pattern;
 use ignore::gitignore::{Gitignore, GitignoreBuilder};
 
 use boxfuture::{BoxFuture, Boxable};
@@ -72,45 +97,67 @@ impl Stat {
   }
 }
 
+// The paths of Stats and PathStats are interned: see `InternedPath`.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Link(pub PathBuf);
+pub struct Link(pub InternedPath);
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Dir(pub PathBuf);
+pub struct Dir(pub InternedPath);
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+///
+/// The size of a File is not part of its identity: it is only a hint as of when the file was
+/// stat'd, and Nodes and Snapshots which contain the File are identified by its path and mode.
+///
+#[derive(Clone, Debug)]
 pub struct File {
-  pub path: PathBuf,
+  pub path: InternedPath,
   pub is_executable: bool,
+  // The size of the file in bytes, as of when it was stat'd.
+  pub size: u64,
+}
+
+impl PartialEq for File {
+  fn eq(&self, other: &File) -> bool {
+    self.path == other.path && self.is_executable == other.is_executable
+  }
+}
+
+impl Eq for File {}
+
+impl Hash for File {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.path.hash(state);
+    self.is_executable.hash(state);
+  }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum PathStat {
   Dir {
     // The symbolic name of some filesystem Path, which is context specific.
-    path: PathBuf,
+    path: InternedPath,
     // The canonical Stat that underlies the Path.
     stat: Dir,
   },
   File {
     // The symbolic name of some filesystem Path, which is context specific.
-    path: PathBuf,
+    path: InternedPath,
     // The canonical Stat that underlies the Path.
     stat: File,
   },
 }
 
 impl PathStat {
-  pub fn dir(path: PathBuf, stat: Dir) -> PathStat {
+  pub fn dir<P: Into<InternedPath>>(path: P, stat: Dir) -> PathStat {
     PathStat::Dir {
-      path: path,
+      path: path.into(),
       stat: stat,
     }
   }
 
-  pub fn file(path: PathBuf, stat: File) -> PathStat {
+  pub fn file<P: Into<InternedPath>>(path: P, stat: File) -> PathStat {
     PathStat::File {
-      path: path,
+      path: path.into(),
       stat: stat,
     }
   }
@@ -123,6 +170,94 @@ impl PathStat {
   }
 }
 
+///
+/// Compares paths in the canonical order of every collection of paths that this crate produces:
+/// `DirectoryListing`s, `Snapshot::path_stats`, and the `FileContent`s returned by
+/// `Store::contents_for_directory`.
+///
+/// Paths are compared component by component, and components are compared by their raw bytes,
+/// without regard for locale or case (so `B` sorts before `a`, and `a/b` before `a.b`). This order
+/// is independent of the order in which the platform's readdir returns entries, which means that
+/// rule outputs and Digests computed from these collections are stable across machines.
+///
+pub fn path_order(a: &Path, b: &Path) -> Ordering {
+  // On unix, Path's Ord compares Components, and Components compare their bytes.
+  a.cmp(b)
+}
+
+// Directories whose size is at least this many bytes are streamed rather than listed in full (see
+// `PosixFS::scandir_unless_large`). This corresponds to tens of thousands of entries on most
+// filesystems.
+const LARGE_DIRECTORY_BYTES: u64 = 1 << 20;
+
+// The number of entries in each batch of a streamed directory listing.
+const SCANDIR_BATCH_SIZE: usize = 4096;
+
+// Prefixes the name of a file whose name is not valid UTF8 when it is stored in a Directory proto.
+// NUL may not occur in a path on any platform, so no UTF8 name can be mistaken for an encoded one.
+const ENCODED_NAME_PREFIX: char = '\u{0}';
+
+///
+/// Encodes a path component (i.e. a file name) as a UTF8 string for use as the `name` of a
+/// `FileNode` or `DirectoryNode` in a `Directory` proto, which cannot hold arbitrary bytes.
+///
+/// UTF8 names (which is to say: nearly all of them) are used unmodified. Other names are prefixed
+/// with NUL, and then have any bytes which are not printable ASCII (as well as `%`) escaped as
+/// `%XX`. `decode_name` reverses the encoding.
+///
+pub fn encode_name(name: &OsStr) -> String {
+  if let Some(utf8) = name.to_str() {
+    return utf8.to_owned();
+  }
+  let mut encoded = String::new();
+  encoded.push(ENCODED_NAME_PREFIX);
+  for &byte in os::os_str_bytes(name).iter() {
+    if (byte.is_ascii_graphic() || byte == b' ') && byte != b'%' {
+      encoded.push(byte as char);
+    } else {
+      encoded.push_str(&format!("%{:02X}", byte));
+    }
+  }
+  encoded
+}
+
+///
+/// Decodes a name which was encoded by `encode_name`.
+///
+pub fn decode_name(name: &str) -> OsString {
+  if !name.starts_with(ENCODED_NAME_PREFIX) {
+    return OsString::from(name);
+  }
+  let encoded = name[ENCODED_NAME_PREFIX.len_utf8()..].as_bytes();
+  let mut decoded = Vec::with_capacity(encoded.len());
+  let mut i = 0;
+  while i < encoded.len() {
+    let escaped = if encoded[i] == b'%' {
+      encoded
+        .get(i + 1..i + 3)
+        .and_then(|hex| std::str::from_utf8(hex).ok())
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+    } else {
+      None
+    };
+    match escaped {
+      Some(byte) => {
+        decoded.push(byte);
+        i += 3;
+      }
+      None => {
+        // Not produced by `encode_name`: preserve it verbatim.
+        decoded.push(encoded[i]);
+        i += 1;
+      }
+    }
+  }
+  os::os_string_from_bytes(decoded)
+}
+
+///
+/// The Stats of the entries of a directory, sorted by `path_order`.
+///
 #[derive(Debug, Eq, PartialEq)]
 pub struct DirectoryListing(pub Vec<Stat>);
 
@@ -130,6 +265,75 @@ pub struct DirectoryListing(pub Vec<Stat>);
 pub struct GitignoreStyleExcludes {
   patterns: Vec<String>,
   gitignore: Gitignore,
+  whitelists: Vec<WhitelistPrefix>,
+}
+
+///
+/// The leading components of a whitelist (`!`) pattern, which are used to decide whether the
+/// pattern might re-include anything beneath an ignored directory.
+///
+#[derive(Debug)]
+enum WhitelistPrefix {
+  // The pattern contains no slashes, and so might match at any depth.
+  Unanchored,
+  // The pattern matches paths which begin with components matching these patterns. If the
+  // remainder of the pattern began with a `**`, then it might match at any depth below them.
+  Anchored {
+    components: Vec<Pattern>,
+    double_star: bool,
+  },
+}
+
+impl WhitelistPrefix {
+  fn create(pattern: &str) -> Result<WhitelistPrefix, String> {
+    let pattern = pattern.trim_left_matches('/').trim_right_matches('/');
+    if !pattern.contains('/') {
+      return Ok(WhitelistPrefix::Unanchored);
+    }
+    let mut components = Vec::new();
+    for component in pattern.split('/') {
+      if component.contains(*DOUBLE_STAR) {
+        return Ok(WhitelistPrefix::Anchored {
+          components,
+          double_star: true,
+        });
+      }
+      components.push(Pattern::new(component).map_err(|e| {
+        format!("Could not parse whitelist pattern {:?}: {:?}", pattern, e)
+      })?);
+    }
+    Ok(WhitelistPrefix::Anchored {
+      components,
+      double_star: false,
+    })
+  }
+
+  ///
+  /// True if the pattern might match a path beneath (but not at) the given directory.
+  ///
+  fn may_match_beneath(&self, dir: &Path) -> bool {
+    let (components, double_star) = match self {
+      &WhitelistPrefix::Unanchored => return true,
+      &WhitelistPrefix::Anchored {
+        ref components,
+        double_star,
+      } => (components, double_star),
+    };
+    let mut depth = 0;
+    for dir_component in dir.components() {
+      let dir_component = match dir_component.as_os_str().to_str() {
+        Some(dir_component) => dir_component,
+        // Patterns are UTF8, but be conservative.
+        None => return true,
+      };
+      match components.get(depth) {
+        Some(component) if component.matches(dir_component) => depth += 1,
+        Some(_) => return false,
+        None => return double_star,
+      }
+    }
+    double_star || components.len() > depth
+  }
 }
 
 impl GitignoreStyleExcludes {
@@ -140,10 +344,16 @@ impl GitignoreStyleExcludes {
 
     let gitignore = Self::create_gitignore(patterns)
       .map_err(|e| format!("Could not parse glob excludes {:?}: {:?}", patterns, e))?;
+    let whitelists = patterns
+      .iter()
+      .filter(|pattern| pattern.starts_with('!'))
+      .map(|pattern| WhitelistPrefix::create(&pattern[1..]))
+      .collect::<Result<Vec<_>, _>>()?;
 
     Ok(Arc::new(Self {
       patterns: patterns.to_vec(),
       gitignore,
+      whitelists,
     }))
   }
 
@@ -159,16 +369,56 @@ impl GitignoreStyleExcludes {
     self.patterns.as_slice()
   }
 
+  ///
+  /// True if the given Stat is ignored, either because a pattern matches it, or because a pattern
+  /// matches one of its parent directories (see `matched_path_or_parents`).
+  ///
+  /// Unlike git, which never looks inside of an ignored directory, a directory which is ignored
+  /// but which might contain paths that are re-included by a whitelist (`!`) pattern is not itself
+  /// ignored, so that it is traversed. This allows for ignoring a directory but whitelisting
+  /// specific files within it.
+  ///
   fn is_ignored(&self, stat: &Stat) -> bool {
     let is_dir = match stat {
       &Stat::Dir(_) => true,
       _ => false,
     };
-    match self.gitignore.matched(stat.path(), is_dir) {
+    match self.matched_path_or_parents(stat.path(), is_dir) {
       ignore::Match::None | ignore::Match::Whitelist(_) => false,
-      ignore::Match::Ignore(_) => true,
+      ignore::Match::Ignore(_) => !(is_dir && self.may_whitelist_beneath(stat.path())),
     }
   }
+
+  ///
+  /// Matches the given path against the patterns, and if none of them match it, matches its
+  /// parent directories from the nearest to the furthest. As in git, the last pattern which matches
+  /// a path takes precedence, and a match for a path takes precedence over a match for any of its
+  /// parents.
+  ///
+  fn matched_path_or_parents(&self, path: &Path, is_dir: bool) -> ignore::Match<()> {
+    let mut current = Some(path);
+    let mut current_is_dir = is_dir;
+    while let Some(path) = current {
+      if path.as_os_str().is_empty() {
+        break;
+      }
+      match self.gitignore.matched(path, current_is_dir) {
+        ignore::Match::None => {}
+        ignore::Match::Ignore(_) => return ignore::Match::Ignore(()),
+        ignore::Match::Whitelist(_) => return ignore::Match::Whitelist(()),
+      }
+      current = path.parent();
+      current_is_dir = true;
+    }
+    ignore::Match::None
+  }
+
+  fn may_whitelist_beneath(&self, dir: &Path) -> bool {
+    self
+      .whitelists
+      .iter()
+      .any(|whitelist| whitelist.may_match_beneath(dir))
+  }
 }
 
 lazy_static! {
@@ -179,6 +429,7 @@ lazy_static! {
   static ref EMPTY_IGNORE: Arc<GitignoreStyleExcludes> = Arc::new(GitignoreStyleExcludes {
     patterns: vec![],
     gitignore: Gitignore::empty(),
+    whitelists: vec![],
   });
   static ref MISSING_GLOB_SOURCE: GlobParsedSource = GlobParsedSource(String::from(""));
 }
@@ -259,7 +510,7 @@ impl PathGlob {
   fn spread_filespecs(filespecs: &[String]) -> Result<Vec<PathGlobIncludeEntry>, String> {
     let mut spec_globs_map = Vec::new();
     for filespec in filespecs {
-      let canonical_dir = Dir(PathBuf::new());
+      let canonical_dir = Dir(PathBuf::new().into());
       let symbolic_path = PathBuf::new();
       spec_globs_map.push(PathGlobIncludeEntry {
         input: GlobParsedSource(filespec.clone()),
@@ -357,14 +608,16 @@ impl PathGlob {
       // A request for the parent of `canonical_dir`: since we've already expanded the directory
       // to make it canonical, we can safely drop it directly and recurse without this component.
       // The resulting symbolic path will continue to contain a literal `..`.
-      let mut canonical_dir_parent = canonical_dir;
+      let canonical_dir_parent = match canonical_dir.0.parent() {
+        Some(parent) => Dir(parent.into()),
+        None => {
+          return Err(format!(
+            "Globs may not traverse outside the root: {:?}",
+            parts
+          ))
+        }
+      };
       let mut symbolic_path_parent = symbolic_path;
-      if !canonical_dir_parent.0.pop() {
-        return Err(format!(
-          "Globs may not traverse outside the root: {:?}",
-          parts
-        ));
-      }
       symbolic_path_parent.push(Path::new(*PARENT_DIR));
       PathGlob::parse_globs(canonical_dir_parent, symbolic_path_parent, &parts[1..])
     } else if parts.len() == 1 {
@@ -423,6 +676,72 @@ impl StrictGlobMatching {
   }
 }
 
+///
+/// What to do when a directory listing encounters a special file (a FIFO, socket or device node),
+/// which can neither be read like a file nor captured in a Snapshot.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecialFileBehavior {
+  // Omit special files from directory listings.
+  Ignore,
+  // Omit special files from directory listings, logging a warning naming each one.
+  Warn,
+  // Fail the directory listing, naming the special file.
+  Error,
+}
+
+impl SpecialFileBehavior {
+  pub fn create(behavior: &str) -> Result<Self, String> {
+    match behavior {
+      "ignore" => Ok(SpecialFileBehavior::Ignore),
+      "warn" => Ok(SpecialFileBehavior::Warn),
+      "error" => Ok(SpecialFileBehavior::Error),
+      _ => Err(format!(
+        "Unrecognized special file behavior: {}.",
+        behavior,
+      )),
+    }
+  }
+}
+
+///
+/// What to do when a directory listing encounters a symlink whose destination is outside of the
+/// root, which would otherwise allow arbitrary files on the host into supposedly hermetic inputs.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkEscapeBehavior {
+  // Follow the symlink, so that its destination is captured as though it were inside the root.
+  // Changes to the destination are not watched.
+  Follow,
+  // Omit the symlink from directory listings, logging a warning naming it (once per Session: see
+  // `PosixFS::clear_link_escape_warnings`).
+  Warn,
+  // Fail any glob which matches the symlink, naming it. Globs which would only traverse it (such as
+  // the `**` of `**/BUILD`) skip it.
+  Error,
+}
+
+///
+/// Applies a LinkEscapeBehavior to the symlinks encountered while listing directories.
+///
+#[derive(Clone)]
+struct EscapingLinks {
+  behavior: LinkEscapeBehavior,
+  // The escaping symlinks which have been warned about, so that each is only warned about once.
+  warned: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl LinkEscapeBehavior {
+  pub fn create(behavior: &str) -> Result<Self, String> {
+    match behavior {
+      "follow" => Ok(LinkEscapeBehavior::Follow),
+      "warn" => Ok(LinkEscapeBehavior::Warn),
+      "error" => Ok(LinkEscapeBehavior::Error),
+      _ => Err(format!("Unrecognized link escape behavior: {}.", behavior,)),
+    }
+  }
+}
+
 #[derive(Debug)]
 pub struct PathGlobs {
   include: Vec<PathGlobIncludeEntry>,
@@ -485,6 +804,8 @@ pub struct PosixFS {
   root: Dir,
   pool: Arc<ResettablePool>,
   ignore: Arc<GitignoreStyleExcludes>,
+  special_file_behavior: SpecialFileBehavior,
+  escaping_links: EscapingLinks,
 }
 
 impl PosixFS {
@@ -492,6 +813,7 @@ impl PosixFS {
     root: P,
     pool: Arc<ResettablePool>,
     ignore_patterns: &[String],
+    special_file_behavior: SpecialFileBehavior,
   ) -> Result<PosixFS, String> {
     let root: &Path = root.as_ref();
     let canonical_root = root
@@ -499,7 +821,7 @@ impl PosixFS {
       .and_then(|canonical| {
         canonical.metadata().and_then(|metadata| {
           if metadata.is_dir() {
-            Ok(Dir(canonical))
+            Ok(Dir(canonical.into()))
           } else {
             Err(io::Error::new(
               io::ErrorKind::InvalidInput,
@@ -520,38 +842,158 @@ impl PosixFS {
       root: canonical_root,
       pool: pool,
       ignore: ignore,
+      special_file_behavior: special_file_behavior,
+      escaping_links: EscapingLinks {
+        behavior: LinkEscapeBehavior::Warn,
+        warned: Arc::new(Mutex::new(HashSet::new())),
+      },
     })
   }
 
-  fn scandir_sync(root: PathBuf, dir_relative_to_root: &Dir) -> Result<Vec<Stat>, io::Error> {
+  ///
+  /// Sets what happens to symlinks which point outside of the root: see `LinkEscapeBehavior`.
+  ///
+  pub fn with_link_escape_behavior(mut self, link_escape_behavior: LinkEscapeBehavior) -> PosixFS {
+    self.escaping_links.behavior = link_escape_behavior;
+    self
+  }
+
+  ///
+  /// Forgets which escaping symlinks have been warned about, so that each is warned about again
+  /// (once) by later listings.
+  ///
+  pub fn clear_link_escape_warnings(&self) {
+    self.escaping_links.warned.lock().unwrap().clear();
+  }
+
+  pub fn special_file_behavior(&self) -> SpecialFileBehavior {
+    self.special_file_behavior
+  }
+
+  pub fn link_escape_behavior(&self) -> LinkEscapeBehavior {
+    self.escaping_links.behavior
+  }
+
+  fn scandir_sync(
+    root: PathBuf,
+    dir_relative_to_root: &Dir,
+    special_file_behavior: SpecialFileBehavior,
+    escaping_links: &EscapingLinks,
+  ) -> Result<Vec<Stat>, io::Error> {
     let dir_abs = root.join(&dir_relative_to_root.0);
-    let mut stats: Vec<Stat> = dir_abs
-      .read_dir()?
-      .map(|readdir| {
-        let dir_entry = readdir?;
-        let get_metadata = || std::fs::metadata(dir_abs.join(dir_entry.file_name()));
-        PosixFS::stat_internal(
-          dir_relative_to_root.0.join(dir_entry.file_name()),
-          dir_entry.file_type()?,
-          &dir_abs,
-          get_metadata,
-        )
+    let mut entries = dir_abs.read_dir()?;
+    let (mut stats, _) = PosixFS::scandir_batch_sync(
+      &root,
+      &dir_abs,
+      dir_relative_to_root,
+      &mut entries,
+      special_file_behavior,
+      escaping_links,
+      usize::max_value(),
+    )?;
+    stats.sort_by(|s1, s2| path_order(s1.path(), s2.path()));
+    Ok(stats)
+  }
+
+  ///
+  /// Stats at most `batch_size` of the remaining entries of a directory, and returns them along
+  /// with whether the entries were exhausted.
+  ///
+  fn scandir_batch_sync(
+    root: &Path,
+    dir_abs: &Path,
+    dir_relative_to_root: &Dir,
+    entries: &mut fs::ReadDir,
+    special_file_behavior: SpecialFileBehavior,
+    escaping_links: &EscapingLinks,
+    batch_size: usize,
+  ) -> Result<(Vec<Stat>, bool), io::Error> {
+    let mut read = 0;
+    let stats = entries
+      .by_ref()
+      .take(batch_size)
+      .inspect(|_| read += 1)
+      .filter_map(|readdir| {
+        let stat = readdir.and_then(|dir_entry| {
+          let path = dir_relative_to_root.0.join(dir_entry.file_name());
+          let file_type = dir_entry.file_type()?;
+          if let Some(kind) = os::special_file_kind(&file_type) {
+            return match special_file_behavior {
+              SpecialFileBehavior::Ignore => Ok(None),
+              SpecialFileBehavior::Warn => {
+                warn!("Skipping {:?}, which is a {}.", path, kind);
+                Ok(None)
+              }
+              SpecialFileBehavior::Error => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                  "{:?} is a {}, which cannot be captured. Either ignore it, or configure the \
+                   special file behavior to skip it.",
+                  path, kind
+                ),
+              )),
+            };
+          }
+          // Escaping symlinks are otherwise listed, and followed or failed when they are matched
+          // (see `GlobMatching::canonicalize`).
+          if file_type.is_symlink() && escaping_links.behavior == LinkEscapeBehavior::Warn {
+            let dest = std::fs::read_link(dir_abs.join(dir_entry.file_name()))?;
+            if let Err(escaped) = resolve_link_destination(root, &path, &dest) {
+              if escaping_links.warned.lock().unwrap().insert(path.clone()) {
+                warn!(
+                  "Skipping {:?}, which is a symlink to {:?}, outside of the root.",
+                  path, escaped
+                );
+              }
+              return Ok(None);
+            }
+          }
+          let get_metadata = || std::fs::metadata(dir_abs.join(dir_entry.file_name()));
+          PosixFS::stat_internal(path, file_type, dir_abs, get_metadata).map(Some)
+        });
+        // Transpose the Result<Option<_>> so that skipped entries are filtered.
+        match stat {
+          Ok(Some(stat)) => Some(Ok(stat)),
+          Ok(None) => None,
+          Err(e) => Some(Err(e)),
+        }
       })
       .collect::<Result<Vec<_>, io::Error>>()?;
-    stats.sort_by(|s1, s2| s1.path().cmp(s2.path()));
-    Ok(stats)
+    Ok((stats, read < batch_size))
   }
 
   pub fn is_ignored(&self, stat: &Stat) -> bool {
     self.ignore.is_ignored(stat)
   }
 
-  pub fn read_file(&self, file: &File) -> BoxFuture<FileContent, io::Error> {
-    let path = file.path.clone();
+  ///
+  /// Runs the given function, which opens file descriptors, on the pool under the global
+  /// `FdBudget`.
+  ///
+  fn spawn_with_fds<F, T>(&self, f: F) -> BoxFuture<T, io::Error>
+  where
+    F: FnOnce() -> Result<T, io::Error> + Send + 'static,
+    T: Send + 'static,
+  {
+    let pool = self.pool.clone();
+    FdBudget::global().with_fds(move || pool.spawn_fn(f))
+  }
+
+  ///
+  /// Returns the Inode of the given file if it has more than one link (see `Inode`).
+  ///
+  pub fn inode(&self, file: &File) -> BoxFuture<Option<Inode>, io::Error> {
     let path_abs = self.root.0.join(&file.path);
     self
       .pool
-      .spawn_fn(move || {
+      .spawn_fn(move || std::fs::metadata(&path_abs).map(|metadata| Inode::for_metadata(&metadata)))
+  }
+
+  pub fn read_file(&self, file: &File) -> BoxFuture<FileContent, io::Error> {
+    let path = file.path.to_path_buf();
+    let path_abs = self.root.0.join(&file.path);
+    self
+      .spawn_with_fds(move || {
         std::fs::File::open(&path_abs).and_then(|mut f| {
           let mut content = Vec::new();
           f.read_to_end(&mut content)?;
@@ -564,35 +1006,58 @@ impl PosixFS {
       .to_boxed()
   }
 
+  ///
+  /// Reads at most `length` bytes of the given file, starting at `offset`. Fewer bytes (possibly
+  /// none) are returned if the file ends before `offset + length`.
+  ///
+  /// Unlike `read_file`, this never loads more of the file than was requested, which makes it
+  /// suitable for inspecting headers (magic numbers, shebang lines, etc) of large files.
+  ///
+  pub fn read_file_range(
+    &self,
+    file: &File,
+    offset: u64,
+    length: u64,
+  ) -> BoxFuture<FileContent, io::Error> {
+    let path = file.path.to_path_buf();
+    let path_abs = self.root.0.join(&file.path);
+    self
+      .spawn_with_fds(move || {
+        std::fs::File::open(&path_abs).and_then(|mut f| {
+          f.seek(SeekFrom::Start(offset))?;
+          let mut content = Vec::new();
+          f.take(length).read_to_end(&mut content)?;
+          Ok(FileContent {
+            path: path,
+            content: Bytes::from(content),
+          })
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Reads the destination of the given Link, relative to the root. An absolute destination inside
+  /// the root (as is common in toolchains which are captured from absolute roots) is made relative
+  /// to the root. A destination outside of the root is absolute: whether it is followed is up to
+  /// the caller (see `LinkEscapeBehavior`).
+  ///
   pub fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, io::Error> {
-    let link_parent = link.0.parent().map(|p| p.to_owned());
-    let link_abs = self.root.0.join(link.0.as_path()).to_owned();
+    let link = link.0.to_path_buf();
+    let root = self.root.0.clone();
     self
       .pool
       .spawn_fn(move || {
-        link_abs.read_link().and_then(|path_buf| {
-          if path_buf.is_absolute() {
-            Err(io::Error::new(
-              io::ErrorKind::InvalidData,
-              format!("Absolute symlink: {:?}", link_abs),
-            ))
-          } else {
-            link_parent
-              .map(|parent| parent.join(path_buf))
-              .ok_or_else(|| {
-                io::Error::new(
-                  io::ErrorKind::InvalidData,
-                  format!("Symlink without a parent?: {:?}", link_abs),
-                )
-              })
-          }
-        })
+        let dest = root.join(&link).read_link()?;
+        Ok(resolve_link_destination(&root, &link, &dest).unwrap_or_else(|escaped| escaped))
       })
       .to_boxed()
   }
 
   ///
-  /// Makes a Stat for path_for_stat relative to absolute_path_to_root.
+  /// Makes a Stat for path_for_stat relative to absolute_path_to_root. The path is only absolute if
+  /// it is outside of the root, beneath a symlink which was followed out of it (see
+  /// `LinkEscapeBehavior::Follow`).
   ///
   fn stat_internal<F>(
     path_for_stat: PathBuf,
@@ -603,15 +1068,6 @@ impl PosixFS {
   where
     F: FnOnce() -> Result<fs::Metadata, io::Error>,
   {
-    if !path_for_stat.is_relative() {
-      return Err(io::Error::new(
-        io::ErrorKind::InvalidInput,
-        format!(
-          "Argument path_for_stat to PosixFS::stat must be relative path, got {:?}",
-          path_for_stat
-        ),
-      ));
-    }
     // TODO: Make this an instance method, and stop having to check this every call.
     if !absolute_path_to_root.is_absolute() {
       return Err(io::Error::new(
@@ -623,15 +1079,16 @@ impl PosixFS {
       ));
     }
     if file_type.is_dir() {
-      Ok(Stat::Dir(Dir(path_for_stat)))
+      Ok(Stat::Dir(Dir(path_for_stat.into())))
     } else if file_type.is_file() {
-      let is_executable = get_metadata()?.permissions().mode() & 0o100 == 0o100;
+      let metadata = get_metadata()?;
       Ok(Stat::File(File {
-        path: path_for_stat,
-        is_executable: is_executable,
+        path: path_for_stat.into(),
+        is_executable: os::is_executable(&metadata),
+        size: metadata.len(),
       }))
     } else if file_type.is_symlink() {
-      Ok(Stat::Link(Link(path_for_stat)))
+      Ok(Stat::Link(Link(path_for_stat.into())))
     } else {
       Err(io::Error::new(
         io::ErrorKind::InvalidData,
@@ -654,13 +1111,117 @@ impl PosixFS {
 
   pub fn scandir(&self, dir: &Dir) -> BoxFuture<DirectoryListing, io::Error> {
     let dir = dir.to_owned();
-    let root = self.root.0.clone();
+    let root = self.root.0.to_path_buf();
+    let special_file_behavior = self.special_file_behavior;
+    let escaping_links = self.escaping_links.clone();
     self
-      .pool
-      .spawn_fn(move || PosixFS::scandir_sync(root, &dir))
+      .spawn_with_fds(move || {
+        PosixFS::scandir_sync(root, &dir, special_file_behavior, &escaping_links)
+      })
       .map(DirectoryListing)
       .to_boxed()
   }
+
+  ///
+  /// Lists the given directory in full (see `scandir`), unless it is so large that it should be
+  /// streamed instead (see `scandir_matching`), in which case returns None. A directory is large if
+  /// its size (which on most filesystems grows with its number of entries) is at least
+  /// `LARGE_DIRECTORY_BYTES`.
+  ///
+  pub fn scandir_unless_large(&self, dir: &Dir) -> BoxFuture<Option<DirectoryListing>, io::Error> {
+    let dir = dir.to_owned();
+    let root = self.root.0.to_path_buf();
+    let special_file_behavior = self.special_file_behavior;
+    let escaping_links = self.escaping_links.clone();
+    self.spawn_with_fds(move || {
+      if fs::metadata(root.join(&dir.0))?.len() >= LARGE_DIRECTORY_BYTES {
+        return Ok(None);
+      }
+      PosixFS::scandir_sync(root, &dir, special_file_behavior, &escaping_links)
+        .map(|stats| Some(DirectoryListing(stats)))
+    })
+  }
+
+  ///
+  /// Lists the given directory in batches of at most `batch_size` Stats, which are read as the
+  /// stream is polled, so that a consumer which retains only some of the entries never holds the
+  /// whole listing in memory. Unlike `scandir`, the entries are not sorted.
+  ///
+  /// The directory is held open (under the `FdBudget`) until the stream completes or is dropped.
+  ///
+  pub fn scandir_stream(
+    &self,
+    dir: &Dir,
+    batch_size: usize,
+  ) -> Box<Stream<Item = Vec<Stat>, Error = io::Error> + Send> {
+    let dir = dir.to_owned();
+    let root = self.root.0.to_path_buf();
+    let dir_abs = root.join(&dir.0);
+    let special_file_behavior = self.special_file_behavior;
+    let escaping_links = self.escaping_links.clone();
+    let pool = self.pool.clone();
+    let open_pool = self.pool.clone();
+    let batches = FdBudget::global()
+      .acquire()
+      .map_err(|()| panic!("Acquisition is infalliable."))
+      .and_then(move |permit| {
+        open_pool.spawn_fn(move || -> Result<_, io::Error> {
+          let entries = dir_abs.read_dir()?;
+          Ok(Some((permit, dir_abs, entries)))
+        })
+      })
+      .map(move |state| {
+        stream::unfold(state, move |state| {
+          let root = root.clone();
+          let dir = dir.clone();
+          let escaping_links = escaping_links.clone();
+          state.map(|(permit, dir_abs, mut entries)| {
+            pool.spawn_fn(move || -> Result<_, io::Error> {
+              let (stats, exhausted) = PosixFS::scandir_batch_sync(
+                &root,
+                &dir_abs,
+                &dir,
+                &mut entries,
+                special_file_behavior,
+                &escaping_links,
+                batch_size,
+              )?;
+              let next_state = if exhausted {
+                None
+              } else {
+                Some((permit, dir_abs, entries))
+              };
+              Ok((stats, next_state))
+            })
+          })
+        })
+      })
+      .flatten_stream();
+    Box::new(batches)
+  }
+
+  ///
+  /// Lists the entries of the given directory which match the given filter. The directory is
+  /// streamed (see `scandir_stream`), so only the matching entries are held in memory.
+  ///
+  pub fn scandir_matching(
+    &self,
+    dir: &Dir,
+    filter: &ListingFilter,
+  ) -> BoxFuture<DirectoryListing, io::Error> {
+    let filter = filter.clone();
+    self
+      .scandir_stream(dir, SCANDIR_BATCH_SIZE)
+      .fold(Vec::new(), move |mut matches, batch| {
+        matches.extend(batch.into_iter().filter(|stat| filter.matches(stat)));
+        Ok::<_, io::Error>(matches)
+      })
+      .map(|mut stats| {
+        stats.sort_by(|s1, s2| path_order(s1.path(), s2.path()));
+        DirectoryListing(stats)
+      })
+      .to_boxed()
+  }
 }
 
 impl VFS<io::Error> for Arc<PosixFS> {
@@ -668,8 +1229,20 @@ impl VFS<io::Error> for Arc<PosixFS> {
     PosixFS::read_link(self, link)
   }
 
-  fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, io::Error> {
-    PosixFS::scandir(self, &dir).map(Arc::new).to_boxed()
+  fn scandir_matching(
+    &self,
+    dir: Dir,
+    filter: &ListingFilter,
+  ) -> BoxFuture<Arc<DirectoryListing>, io::Error> {
+    let posix_fs = self.clone();
+    let filter = filter.clone();
+    PosixFS::scandir_unless_large(self, &dir)
+      .and_then(move |listing_opt| match listing_opt {
+        Some(listing) => future::ok(listing).to_boxed(),
+        None => PosixFS::scandir_matching(&posix_fs, &dir, &filter),
+      })
+      .map(Arc::new)
+      .to_boxed()
   }
 
   fn is_ignored(&self, stat: &Stat) -> bool {
@@ -679,6 +1252,10 @@ impl VFS<io::Error> for Arc<PosixFS> {
   fn mk_error(msg: &str) -> io::Error {
     io::Error::new(io::ErrorKind::Other, msg)
   }
+
+  fn link_escape_behavior(&self) -> LinkEscapeBehavior {
+    PosixFS::link_escape_behavior(self)
+  }
 }
 
 pub trait PathStatGetter<E> {
@@ -706,7 +1283,7 @@ impl PathStatGetter<io::Error> for Arc<PosixFS> {
             .and_then(move |maybe_stat| {
               match maybe_stat {
                 // Note: This will drop PathStats for symlinks which don't point anywhere.
-                Some(Stat::Link(link)) => fs.canonicalize(link.0.clone(), &link),
+                Some(Stat::Link(link)) => fs.canonicalize(link.0.to_path_buf(), &link),
                 Some(Stat::Dir(dir)) => {
                   future::ok(Some(PathStat::dir(dir.0.clone(), dir))).to_boxed()
                 }
@@ -727,9 +1304,52 @@ impl PathStatGetter<io::Error> for Arc<PosixFS> {
 ///
 pub trait VFS<E: Send + Sync + 'static>: Clone + Send + Sync + 'static {
   fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, E>;
-  fn scandir(&self, dir: Dir) -> BoxFuture<Arc<DirectoryListing>, E>;
+
+  ///
+  /// Lists the entries of the given directory which might match the given filter. Implementations
+  /// may list the whole directory (glob matching filters the listing again), but may instead
+  /// stream large directories (see `PosixFS::scandir_matching`), so that their whole listing is
+  /// never held in memory.
+  ///
+  fn scandir_matching(
+    &self,
+    dir: Dir,
+    filter: &ListingFilter,
+  ) -> BoxFuture<Arc<DirectoryListing>, E>;
+
   fn is_ignored(&self, stat: &Stat) -> bool;
   fn mk_error(msg: &str) -> E;
+
+  ///
+  /// What expansion does with Links whose destinations (as read by `read_link`) are absolute,
+  /// because they are outside of the root. The default follows them.
+  ///
+  fn link_escape_behavior(&self) -> LinkEscapeBehavior {
+    LinkEscapeBehavior::Follow
+  }
+
+  ///
+  /// A memo to share the matching of PathGlobs between expansions, if any: see
+  /// `GlobExpansionMemo`.
+  ///
+  fn glob_expansion_memo(&self) -> Option<&GlobExpansionMemo> {
+    None
+  }
+
+  ///
+  /// A record of the directories which are known not to contain matches for recursive suffix
+  /// globs, if any: see `NegativeMatchFilter`.
+  ///
+  fn negative_match_filter(&self) -> Option<&NegativeMatchFilter> {
+    None
+  }
+
+  ///
+  /// The maximum number of PathGlobs to expand concurrently at each level of a glob expansion.
+  ///
+  fn glob_expansion_parallelism(&self) -> usize {
+    DEFAULT_GLOB_EXPANSION_PARALLELISM
+  }
 }
 
 pub struct FileContent {
@@ -776,21 +1396,194 @@ pub fn safe_create_dir_all_ioerror(path: &Path) -> Result<(), io::Error> {
   }
 }
 
+///
+/// Resolves the destination of the symlink at `link` (which is relative to the root, unless it was
+/// reached by following another symlink out of the root) lexically. Returns the destination
+/// relative to the root if it is inside the root, or else the absolute destination as an error.
+///
+/// A relative destination inside the root is returned without being normalized, so that any `..`
+/// in it is later resolved against canonical directories.
+///
+fn resolve_link_destination(root: &Path, link: &Path, dest: &Path) -> Result<PathBuf, PathBuf> {
+  let parent = link.parent().unwrap_or_else(|| Path::new(""));
+  let mut dest_abs = PathBuf::new();
+  for component in root.join(parent).join(dest).components() {
+    match component {
+      Component::ParentDir => {
+        dest_abs.pop();
+      }
+      Component::CurDir => {}
+      component => dest_abs.push(component.as_os_str()),
+    }
+  }
+  let relative_dest = dest_abs
+    .strip_prefix(root)
+    .map(|relative_dest| relative_dest.to_owned());
+  match relative_dest {
+    Ok(_) if link.is_relative() && dest.is_relative() => Ok(parent.join(dest)),
+    Ok(relative_dest) => Ok(relative_dest),
+    Err(_) => Err(dest_abs),
+  }
+}
+
+fn link_escape_message(link: &Path, dest: &Path) -> String {
+  format!(
+    "{:?} is a symlink to {:?}, which is outside of the root. Either remove or ignore it, or \
+     configure the link escape behavior to skip or follow it.",
+    link, dest
+  )
+}
+
+///
+/// The entries of a directory listing which a PathGlob needs: those whose names match its
+/// wildcard. A glob which only traverses into the matching directories (a DirWildcard, including
+/// the `*` that each level of a `**` expands to) does not need files, which need not be retained
+/// even if they match.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ListingFilter {
+  pub wildcard: Pattern,
+  pub traverses: bool,
+}
+
+impl ListingFilter {
+  pub fn matches(&self, stat: &Stat) -> bool {
+    let may_traverse = match stat {
+      &Stat::File(_) => false,
+      // A Link may point to a directory.
+      &Stat::Dir(_) | &Stat::Link(_) => true,
+    };
+    (may_traverse || !self.traverses) && matches_wildcard(&self.wildcard, stat)
+  }
+}
+
+///
+/// True if the name of the given Stat matches the given wildcard. Globs are UTF8, so names which
+/// are not are matched in their lossy form (which wildcards will match, but literals cannot).
+///
+fn matches_wildcard(wildcard: &Pattern, stat: &Stat) -> bool {
+  stat
+    .path()
+    .file_name()
+    .map(|file_name| wildcard.matches(&file_name.to_string_lossy()))
+    .unwrap_or(false)
+}
+
 fn safe_create_dir_all(path: &Path) -> Result<(), String> {
   safe_create_dir_all_ioerror(path)
     .map_err(|e| format!("Failed to create dir {:?} due to {:?}", path, e))
 }
 
+#[cfg(test)]
+mod gitignore_style_excludes_test {
+  use super::{Dir, File, GitignoreStyleExcludes, Stat};
+  use std::path::PathBuf;
+
+  fn ignored(patterns: &[&str], stats: &[Stat]) -> Vec<bool> {
+    let patterns = patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+    let excludes = GitignoreStyleExcludes::create(&patterns).unwrap();
+    stats.iter().map(|stat| excludes.is_ignored(stat)).collect()
+  }
+
+  fn dir(path: &str) -> Stat {
+    Stat::Dir(Dir(PathBuf::from(path).into()))
+  }
+
+  fn file(path: &str) -> Stat {
+    Stat::File(File {
+      path: PathBuf::from(path).into(),
+      is_executable: false,
+      size: 0,
+    })
+  }
+
+  #[test]
+  fn parents_are_matched() {
+    assert_eq!(
+      ignored(
+        &["/dist/"],
+        &[dir("dist"), file("dist/a.txt"), dir("dist/sub"), file("src/dist")],
+      ),
+      vec![true, true, true, false]
+    );
+  }
+
+  #[test]
+  fn whitelist_inside_ignored_directory() {
+    assert_eq!(
+      ignored(
+        &["/3rdparty/", "!3rdparty/BUILD", "!3rdparty/python/*.txt"],
+        &[
+          // Traversed, because it might contain whitelisted files.
+          dir("3rdparty"),
+          file("3rdparty/BUILD"),
+          file("3rdparty/README"),
+          dir("3rdparty/jvm"),
+          dir("3rdparty/python"),
+          file("3rdparty/python/requirements.txt"),
+          file("3rdparty/python/BUILD"),
+        ],
+      ),
+      vec![false, false, true, true, false, false, true]
+    );
+  }
+
+  #[test]
+  fn unanchored_whitelist_inside_ignored_directory() {
+    assert_eq!(
+      ignored(
+        &["/vendor/", "!BUILD"],
+        &[dir("vendor/a/b"), file("vendor/a/b/BUILD"), file("vendor/a/b/c.py")],
+      ),
+      vec![false, false, true]
+    );
+  }
+
+  #[test]
+  fn nearest_match_takes_precedence() {
+    assert_eq!(
+      ignored(
+        &["/a/", "!a/b/", "a/b/*.log"],
+        &[
+          file("a/x.txt"),
+          dir("a/b"),
+          file("a/b/x.txt"),
+          file("a/b/x.log"),
+          file("a/b/c/x.txt"),
+        ],
+      ),
+      vec![true, false, false, true, false]
+    );
+  }
+
+  #[test]
+  fn last_pattern_takes_precedence() {
+    assert_eq!(
+      ignored(&["*.txt", "!keep.txt"], &[file("a/keep.txt"), file("a/drop.txt")]),
+      vec![false, true]
+    );
+    assert_eq!(
+      ignored(&["!keep.txt", "*.txt"], &[file("a/keep.txt"), file("a/drop.txt")]),
+      vec![true, true]
+    );
+  }
+}
+
 #[cfg(test)]
 mod posixfs_test {
   extern crate tempfile;
   extern crate testutil;
 
   use super::{
-    Dir, DirectoryListing, File, Link, PathStat, PathStatGetter, PosixFS, ResettablePool, Stat,
+    decode_name, encode_name, path_order, Dir, DirectoryListing, File, Link, ListingFilter,
+    PathStat, PathStatGetter, PosixFS, ResettablePool, SpecialFileBehavior, Stat,
   };
-  use futures::Future;
+  use futures::{Future, Stream};
+  use glob::Pattern;
   use std;
+  use std::collections::HashSet;
+  use std::ffi::{OsStr, OsString};
+  use std::os::unix::ffi::OsStrExt;
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
   use testutil::make_file;
@@ -821,21 +1614,50 @@ mod posixfs_test {
     );
     let fs = new_posixfs(&dir.path());
     let file_content = fs.read_file(&File {
-      path: path.clone(),
+      path: path.clone().into(),
       is_executable: false,
+      size: 0,
     }).wait()
       .unwrap();
     assert_eq!(file_content.path, path);
     assert_eq!(file_content.content, content);
   }
 
+  #[test]
+  fn read_file_range() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = PathBuf::from("marmosets");
+    make_file(
+      &std::fs::canonicalize(dir.path()).unwrap().join(&path),
+      "very cute".as_bytes(),
+      0o600,
+    );
+    let fs = new_posixfs(&dir.path());
+    let file = File {
+      path: path.clone().into(),
+      is_executable: false,
+      size: 0,
+    };
+    let read_range = |offset, length| {
+      fs.read_file_range(&file, offset, length)
+        .wait()
+        .unwrap()
+        .content
+    };
+    assert_eq!(read_range(0, 4), "very".as_bytes().to_vec());
+    assert_eq!(read_range(5, 4), "cute".as_bytes().to_vec());
+    assert_eq!(read_range(5, 100), "cute".as_bytes().to_vec());
+    assert_eq!(read_range(100, 4), Vec::<u8>::new());
+  }
+
   #[test]
   fn read_file_missing() {
     let dir = tempfile::TempDir::new().unwrap();
     new_posixfs(&dir.path())
       .read_file(&File {
-        path: PathBuf::from("marmosets"),
+        path: PathBuf::from("marmosets").into(),
         is_executable: false,
+        size: 0,
       })
       .wait()
       .expect_err("Expected error");
@@ -850,8 +1672,9 @@ mod posixfs_test {
     assert_eq!(
       posix_fs.stat(path.clone()).unwrap(),
       super::Stat::File(File {
-        path: path,
+        path: path.into(),
         is_executable: true,
+        size: 0,
       })
     )
   }
@@ -865,12 +1688,37 @@ mod posixfs_test {
     assert_eq!(
       posix_fs.stat(path.clone()).unwrap(),
       super::Stat::File(File {
-        path: path,
+        path: path.into(),
         is_executable: false,
+        size: 0,
       })
     )
   }
 
+  #[test]
+  fn stat_file_size() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let path = PathBuf::from("marmosets");
+    make_file(&dir.path().join(&path), "cute".as_bytes(), 0o600);
+    match posix_fs.stat(path.clone()).unwrap() {
+      super::Stat::File(file) => assert_eq!(file.size, 4),
+      stat => panic!("Expected a File, got: {:?}", stat),
+    }
+  }
+
+  #[test]
+  fn file_size_is_not_identity() {
+    let file = |size| File {
+      path: PathBuf::from("marmosets").into(),
+      is_executable: false,
+      size,
+    };
+    let mut files = HashSet::new();
+    files.insert(file(4));
+    assert!(files.contains(&file(5)));
+  }
+
   #[test]
   fn stat_dir() {
     let dir = tempfile::TempDir::new().unwrap();
@@ -879,7 +1727,7 @@ mod posixfs_test {
     std::fs::create_dir(dir.path().join(&path)).unwrap();
     assert_eq!(
       posix_fs.stat(path.clone()).unwrap(),
-      super::Stat::Dir(Dir(path))
+      super::Stat::Dir(Dir(path.into()))
     )
   }
 
@@ -894,7 +1742,7 @@ mod posixfs_test {
     std::os::unix::fs::symlink(&dir.path().join(path), dir.path().join(&link_path)).unwrap();
     assert_eq!(
       posix_fs.stat(link_path.clone()).unwrap(),
-      super::Stat::Link(Link(link_path))
+      super::Stat::Link(Link(link_path.into()))
     )
   }
 
@@ -914,6 +1762,53 @@ mod posixfs_test {
       .expect_err("Want error");
   }
 
+  #[test]
+  fn read_link_absolute_inside_root() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    let posix_fs = new_posixfs(&root);
+    let path = PathBuf::from("enclosure").join("marmosets");
+    std::fs::create_dir(root.join("enclosure")).unwrap();
+    make_file(&root.join(&path), &[], 0o600);
+
+    let link_path = PathBuf::from("remarkably_similar_marmoset");
+    std::os::unix::fs::symlink(&root.join(&path), root.join(&link_path)).unwrap();
+    assert_eq!(posix_fs.read_link(&Link(link_path.into())).wait().unwrap(), path);
+  }
+
+  #[test]
+  fn read_link_absolute_outside_root() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside_dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let outside_path = outside_dir.path().join("marmosets");
+    make_file(&outside_path, &[], 0o600);
+
+    let link_path = PathBuf::from("remarkably_similar_marmoset");
+    std::os::unix::fs::symlink(&outside_path, dir.path().join(&link_path)).unwrap();
+    assert_eq!(
+      posix_fs.read_link(&Link(link_path.into())).wait().unwrap(),
+      outside_path
+    );
+  }
+
+  #[test]
+  fn scandir_warns_about_escaping_links_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let outside_dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    std::os::unix::fs::symlink(outside_dir.path(), dir.path().join("venv")).unwrap();
+    let warned = || posix_fs.escaping_links.warned.lock().unwrap().len();
+
+    for _ in 0..2 {
+      let listing = posix_fs.scandir(&Dir(PathBuf::new().into())).wait().unwrap();
+      assert_eq!(listing.0, Vec::<Stat>::new());
+      assert_eq!(warned(), 1);
+    }
+    posix_fs.clear_link_escape_warnings();
+    assert_eq!(warned(), 0);
+  }
+
   #[test]
   fn scandir_empty() {
     let dir = tempfile::TempDir::new().unwrap();
@@ -921,7 +1816,7 @@ mod posixfs_test {
     let path = PathBuf::from("empty_enclosure");
     std::fs::create_dir(dir.path().join(&path)).unwrap();
     assert_eq!(
-      posix_fs.scandir(&Dir(path)).wait().unwrap(),
+      posix_fs.scandir(&Dir(path.into())).wait().unwrap(),
       DirectoryListing(vec![])
     );
   }
@@ -956,36 +1851,204 @@ mod posixfs_test {
     );
 
     assert_eq!(
-      posix_fs.scandir(&Dir(path)).wait().unwrap(),
+      posix_fs.scandir(&Dir(path.into())).wait().unwrap(),
       DirectoryListing(vec![
         Stat::File(File {
-          path: a_marmoset,
+          path: a_marmoset.into(),
           is_executable: false,
+          size: 0,
         }),
         Stat::File(File {
-          path: feed,
+          path: feed.into(),
           is_executable: true,
+          size: 0,
         }),
-        Stat::Dir(Dir(hammock)),
-        Stat::Link(Link(remarkably_similar_marmoset)),
+        Stat::Dir(Dir(hammock.into())),
+        Stat::Link(Link(remarkably_similar_marmoset.into())),
         Stat::File(File {
-          path: sneaky_marmoset,
+          path: sneaky_marmoset.into(),
           is_executable: false,
+          size: 0,
         }),
       ])
     );
   }
 
+  #[test]
+  fn scandir_sorted_bytewise() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    for name in &["b", "é", "a.b", "_", "B", "a"] {
+      make_file(&dir.path().join(name), &[], 0o600);
+    }
+
+    let listing = posix_fs.scandir(&Dir(PathBuf::new().into())).wait().unwrap();
+    assert_eq!(
+      listing.0.iter().map(|stat| stat.path()).collect::<Vec<_>>(),
+      vec!["B", "_", "a", "a.b", "b", "é"]
+        .into_iter()
+        .map(Path::new)
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn encode_name_utf8_unmodified() {
+    assert_eq!(encode_name(OsStr::new("café 100%")), "café 100%");
+    assert_eq!(decode_name("café 100%"), OsString::from("café 100%"));
+  }
+
+  #[test]
+  fn encode_name_non_utf8_roundtrip() {
+    let name = OsStr::from_bytes(b"caf\xe9 100%\x00\xff");
+    let encoded = encode_name(name);
+    assert_eq!(encoded, "\u{0}caf%E9 100%25%00%FF");
+    assert_eq!(decode_name(&encoded), name.to_owned());
+  }
+
+  #[test]
+  fn scandir_non_utf8() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    let name = Path::new(OsStr::from_bytes(b"caf\xe9"));
+    make_file(&dir.path().join(name), &[], 0o600);
+
+    assert_eq!(
+      posix_fs.scandir(&Dir(PathBuf::new().into())).wait().unwrap(),
+      DirectoryListing(vec![Stat::File(File {
+        path: name.to_owned().into(),
+        is_executable: false,
+        size: 0,
+      })])
+    );
+  }
+
+  #[test]
+  fn path_order_is_by_component() {
+    let mut paths = vec!["a.b", "a/b", "a", "a-b/c", "B/z"]
+      .into_iter()
+      .map(Path::new)
+      .collect::<Vec<_>>();
+    paths.sort_by(|a, b| path_order(a, b));
+    assert_eq!(
+      paths,
+      vec!["B/z", "a", "a/b", "a-b/c", "a.b"]
+        .into_iter()
+        .map(Path::new)
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn scandir_special_files() {
+    let dir = tempfile::TempDir::new().unwrap();
+    make_file(&dir.path().join("roland"), &[], 0o600);
+    let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("socket")).unwrap();
+    let listing_with = |special_file_behavior| {
+      new_posixfs_with_special_file_behavior(&dir.path(), special_file_behavior)
+        .scandir(&Dir(PathBuf::new().into()))
+        .wait()
+    };
+
+    let roland_only = DirectoryListing(vec![Stat::File(File {
+      path: PathBuf::from("roland").into(),
+      is_executable: false,
+      size: 0,
+    })]);
+    assert_eq!(listing_with(SpecialFileBehavior::Ignore).unwrap(), roland_only);
+    assert_eq!(listing_with(SpecialFileBehavior::Warn).unwrap(), roland_only);
+    let err = listing_with(SpecialFileBehavior::Error).expect_err("Want error");
+    let message = format!("{}", err);
+    assert!(
+      message.contains("\"socket\" is a socket"),
+      "Unexpected error: {}",
+      message
+    );
+  }
+
   #[test]
   fn scandir_missing() {
     let dir = tempfile::TempDir::new().unwrap();
     let posix_fs = new_posixfs(&dir.path());
     posix_fs
-      .scandir(&Dir(PathBuf::from("no_marmosets_here")))
+      .scandir(&Dir(PathBuf::from("no_marmosets_here").into()))
       .wait()
       .expect_err("Want error");
   }
 
+  #[test]
+  fn scandir_stream_batches() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for i in 0..10 {
+      make_file(&dir.path().join(format!("marmoset{}", i)), &[], 0o600);
+    }
+    let posix_fs = new_posixfs(&dir.path());
+    let root = Dir(PathBuf::from("").into());
+
+    let batches = posix_fs.scandir_stream(&root, 3).collect().wait().unwrap();
+    assert!(batches.iter().all(|batch| batch.len() <= 3), "{:?}", batches);
+    let mut streamed = batches.into_iter().flat_map(|batch| batch).collect::<Vec<_>>();
+    streamed.sort_by(|s1, s2| path_order(s1.path(), s2.path()));
+
+    assert_eq!(streamed, posix_fs.scandir(&root).wait().unwrap().0);
+  }
+
+  #[test]
+  fn scandir_stream_missing() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let posix_fs = new_posixfs(&dir.path());
+    posix_fs
+      .scandir_stream(&Dir(PathBuf::from("no_marmosets_here").into()), 3)
+      .collect()
+      .wait()
+      .expect_err("Want error");
+  }
+
+  #[test]
+  fn scandir_matching() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for name in &["c.txt", "a.txt", "b.rs"] {
+      make_file(&dir.path().join(name), &[], 0o600);
+    }
+    let posix_fs = new_posixfs(&dir.path());
+
+    let listed = |wildcard: &str, traverses: bool| {
+      let filter = ListingFilter {
+        wildcard: Pattern::new(wildcard).unwrap(),
+        traverses,
+      };
+      posix_fs
+        .scandir_matching(&Dir(PathBuf::from("").into()), &filter)
+        .wait()
+        .unwrap()
+        .0
+        .iter()
+        .map(|stat| stat.path().to_owned())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      listed("*.txt", false),
+      vec![PathBuf::from("a.txt"), PathBuf::from("c.txt")]
+    );
+
+    // A glob which traverses the matches only needs those which might be directories.
+    std::fs::create_dir(dir.path().join("d.txt")).unwrap();
+    assert_eq!(listed("*", true), vec![PathBuf::from("d.txt")]);
+  }
+
+  #[test]
+  fn scandir_unless_large_small() {
+    let dir = tempfile::TempDir::new().unwrap();
+    make_file(&dir.path().join("marmoset"), &[], 0o600);
+    let posix_fs = new_posixfs(&dir.path());
+    let root = Dir(PathBuf::from("").into());
+
+    assert_eq!(
+      posix_fs.scandir_unless_large(&root).wait().unwrap(),
+      Some(posix_fs.scandir(&root).wait().unwrap())
+    );
+  }
+
   #[test]
   fn path_stats_for_paths() {
     let dir = tempfile::TempDir::new().unwrap();
@@ -1029,38 +2092,42 @@ mod posixfs_test {
       Some(PathStat::file(
         PathBuf::from("executable_file"),
         File {
-          path: PathBuf::from("executable_file"),
+          path: PathBuf::from("executable_file").into(),
           is_executable: true,
+          size: 0,
         },
       )),
       Some(PathStat::file(
         PathBuf::from("regular_file"),
         File {
-          path: PathBuf::from("regular_file"),
+          path: PathBuf::from("regular_file").into(),
           is_executable: false,
+          size: 0,
         },
       )),
       Some(PathStat::dir(
         PathBuf::from("dir"),
-        Dir(PathBuf::from("dir")),
+        Dir(PathBuf::from("dir").into()),
       )),
       Some(PathStat::file(
         PathBuf::from("symlink"),
         File {
-          path: PathBuf::from("executable_file"),
+          path: PathBuf::from("executable_file").into(),
           is_executable: true,
+          size: 0,
         },
       )),
       Some(PathStat::file(
         PathBuf::from("dir").join("recursive_symlink"),
         File {
-          path: PathBuf::from("executable_file"),
+          path: PathBuf::from("executable_file").into(),
           is_executable: true,
+          size: 0,
         },
       )),
       Some(PathStat::dir(
         PathBuf::from("dir_symlink"),
-        Dir(PathBuf::from("dir")),
+        Dir(PathBuf::from("dir").into()),
       )),
       None,
       None,
@@ -1070,7 +2137,7 @@ mod posixfs_test {
 
   fn assert_only_file_is_executable(path: &Path, want_is_executable: bool) {
     let fs = new_posixfs(path);
-    let stats = fs.scandir(&Dir(PathBuf::from("."))).wait().unwrap();
+    let stats = fs.scandir(&Dir(PathBuf::from(".").into())).wait().unwrap();
     assert_eq!(stats.0.len(), 1);
     match stats.0.get(0).unwrap() {
       &super::Stat::File(File {
@@ -1081,10 +2148,18 @@ mod posixfs_test {
   }
 
   fn new_posixfs<P: AsRef<Path>>(dir: P) -> PosixFS {
+    new_posixfs_with_special_file_behavior(dir, SpecialFileBehavior::Error)
+  }
+
+  fn new_posixfs_with_special_file_behavior<P: AsRef<Path>>(
+    dir: P,
+    special_file_behavior: SpecialFileBehavior,
+  ) -> PosixFS {
     PosixFS::new(
       dir.as_ref(),
       Arc::new(ResettablePool::new("test-pool-".to_string())),
       &[],
+      special_file_behavior,
     ).unwrap()
   }
 }
@@ -0,0 +1,322 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+// The operations on files, paths and permissions whose implementations differ between unix and
+// Windows, so that the rest of the engine can be written once for both.
+//
+// Windows has no executable bit (whether a file is executable depends on its extension), so files
+// are never stat'd as executable there, and requests to make a file executable are ignored. Paths
+// on Windows are (potentially ill-formed) UTF16, so they are converted to and from bytes as WTF-8:
+// UTF8 which additionally encodes unpaired surrogates, so that no two names share an encoding.
+
+#[cfg(unix)]
+use libc;
+use std::borrow::Cow;
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+#[cfg(windows)]
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+///
+/// Returns the bytes of the given OsStr.
+///
+#[cfg(unix)]
+pub fn os_str_bytes(s: &OsStr) -> Cow<[u8]> {
+  Cow::Borrowed(s.as_bytes())
+}
+
+#[cfg(windows)]
+pub fn os_str_bytes(s: &OsStr) -> Cow<[u8]> {
+  if let Some(utf8) = s.to_str() {
+    return Cow::Borrowed(utf8.as_bytes());
+  }
+  let mut bytes = Vec::new();
+  for decoded in ::std::char::decode_utf16(s.encode_wide()) {
+    match decoded {
+      Ok(c) => {
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+      }
+      Err(unpaired) => {
+        // Encoded as though the surrogate were a scalar value, which UTF8 never contains.
+        let u = unpaired.unpaired_surrogate();
+        bytes.push(0xE0 | (u >> 12) as u8);
+        bytes.push(0x80 | ((u >> 6) & 0x3F) as u8);
+        bytes.push(0x80 | (u & 0x3F) as u8);
+      }
+    }
+  }
+  Cow::Owned(bytes)
+}
+
+///
+/// Creates an OsString from bytes which were returned by `os_str_bytes`.
+///
+#[cfg(unix)]
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+  OsString::from_vec(bytes)
+}
+
+#[cfg(windows)]
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+  let bytes = match String::from_utf8(bytes) {
+    Ok(utf8) => return OsString::from(utf8),
+    Err(e) => e.into_bytes(),
+  };
+  let mut wide: Vec<u16> = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let lead = bytes[i];
+    let (width, initial) = if lead < 0x80 {
+      (1, u32::from(lead))
+    } else if lead & 0xE0 == 0xC0 {
+      (2, u32::from(lead & 0x1F))
+    } else if lead & 0xF0 == 0xE0 {
+      (3, u32::from(lead & 0x0F))
+    } else if lead & 0xF8 == 0xF0 {
+      (4, u32::from(lead & 0x07))
+    } else {
+      // Not produced by `os_str_bytes`: substitute the replacement character.
+      wide.push(0xFFFD);
+      i += 1;
+      continue;
+    };
+    if i + width > bytes.len() || bytes[i + 1..i + width].iter().any(|b| b & 0xC0 != 0x80) {
+      wide.push(0xFFFD);
+      i += 1;
+      continue;
+    }
+    let code_point = bytes[i + 1..i + width]
+      .iter()
+      .fold(initial, |acc, b| (acc << 6) | u32::from(b & 0x3F));
+    if code_point >= 0x1_0000 {
+      let offset = code_point - 0x1_0000;
+      wide.push(0xD800 | (offset >> 10) as u16);
+      wide.push(0xDC00 | (offset & 0x3FF) as u16);
+    } else {
+      wide.push(code_point as u16);
+    }
+    i += width;
+  }
+  OsString::from_wide(&wide)
+}
+
+///
+/// True if the file with the given metadata is executable by its owner.
+///
+#[cfg(unix)]
+pub fn is_executable(metadata: &fs::Metadata) -> bool {
+  metadata.permissions().mode() & 0o100 == 0o100
+}
+
+#[cfg(windows)]
+pub fn is_executable(_metadata: &fs::Metadata) -> bool {
+  false
+}
+
+///
+/// Sets the permissions that the given OpenOptions will create a file with.
+///
+#[cfg(unix)]
+pub fn create_mode(options: &mut fs::OpenOptions, is_executable: bool) -> &mut fs::OpenOptions {
+  options.mode(if is_executable { 0o755 } else { 0o644 })
+}
+
+#[cfg(windows)]
+pub fn create_mode(options: &mut fs::OpenOptions, _is_executable: bool) -> &mut fs::OpenOptions {
+  options
+}
+
+///
+/// Sets the permissions of an existing file to those that `create_mode` would have created it
+/// with.
+///
+#[cfg(unix)]
+pub fn set_mode(path: &Path, is_executable: bool) -> io::Result<()> {
+  let mode = if is_executable { 0o755 } else { 0o644 };
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+pub fn set_mode(path: &Path, _is_executable: bool) -> io::Result<()> {
+  let mut permissions = fs::metadata(path)?.permissions();
+  permissions.set_readonly(false);
+  fs::set_permissions(path, permissions)
+}
+
+///
+/// Adds or removes write permission for the file with the given metadata. On unix this affects
+/// only the owner when adding permission, but all users when removing it.
+///
+#[cfg(unix)]
+pub fn set_writable(path: &Path, metadata: &fs::Metadata, writable: bool) -> io::Result<()> {
+  let mode = metadata.permissions().mode();
+  let mode = if writable { mode | 0o200 } else { mode & !0o222 };
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+pub fn set_writable(path: &Path, metadata: &fs::Metadata, writable: bool) -> io::Result<()> {
+  let mut permissions = metadata.permissions();
+  permissions.set_readonly(!writable);
+  fs::set_permissions(path, permissions)
+}
+
+///
+/// Returns a description of the given file type if it is neither a file, a directory nor a
+/// symlink.
+///
+#[cfg(unix)]
+pub fn special_file_kind(file_type: &fs::FileType) -> Option<&'static str> {
+  if file_type.is_fifo() {
+    Some("FIFO")
+  } else if file_type.is_socket() {
+    Some("socket")
+  } else if file_type.is_block_device() {
+    Some("block device")
+  } else if file_type.is_char_device() {
+    Some("character device")
+  } else {
+    None
+  }
+}
+
+#[cfg(windows)]
+pub fn special_file_kind(_file_type: &fs::FileType) -> Option<&'static str> {
+  None
+}
+
+///
+/// Returns the (device, inode) pair which identifies the file with the given metadata, if the file
+/// has more than one hardlink. Files with only one link cannot share their content with any other
+/// path, so they are not identified.
+///
+#[cfg(unix)]
+pub fn linked_inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  if metadata.nlink() > 1 {
+    Some((metadata.dev(), metadata.ino()))
+  } else {
+    None
+  }
+}
+
+#[cfg(windows)]
+pub fn linked_inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  // The equivalent (the volume serial number and file index) is not available from std.
+  None
+}
+
+///
+/// Creates a symlink at `link` which points to the directory `target`.
+///
+/// On Windows, creating symlinks requires either elevated privileges or developer mode.
+///
+#[cfg(unix)]
+pub fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+  ::std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+pub fn symlink_dir(target: &Path, link: &Path) -> io::Result<()> {
+  ::std::os::windows::fs::symlink_dir(target, link)
+}
+
+///
+/// Atomically exchanges the files or directories at the given paths, both of which must exist.
+/// Returns false without changing either path if the platform (or filesystem) does not support
+/// exchanging them.
+///
+#[cfg(target_os = "linux")]
+pub fn exchange(first: &Path, second: &Path) -> io::Result<bool> {
+  // From linux/fs.h: not exposed by the libc crate.
+  const RENAME_EXCHANGE: libc::c_uint = 1 << 1;
+  let first = CString::new(first.as_os_str().as_bytes())?;
+  let second = CString::new(second.as_os_str().as_bytes())?;
+  let result = unsafe {
+    libc::syscall(
+      libc::SYS_renameat2,
+      libc::AT_FDCWD,
+      first.as_ptr(),
+      libc::AT_FDCWD,
+      second.as_ptr(),
+      RENAME_EXCHANGE,
+    )
+  };
+  if result == 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  match err.raw_os_error() {
+    // Kernels before 3.15 lack the syscall, and some filesystems lack support for the flag.
+    Some(libc::ENOSYS) | Some(libc::EINVAL) => Ok(false),
+    _ => Err(err),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_first: &Path, _second: &Path) -> io::Result<bool> {
+  Ok(false)
+}
+
+///
+/// True unless the process with the given id is known to have exited (although its id may since
+/// have been reused).
+///
+#[cfg(unix)]
+pub fn process_may_be_running(pid: u32) -> bool {
+  let pid = pid as libc::pid_t;
+  if pid <= 0 {
+    // Not a single process: kill would address a group of processes.
+    return true;
+  }
+  // Signal 0 checks for the existence of the process without signalling it.
+  unsafe { libc::kill(pid, 0) == 0 }
+    || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(windows)]
+pub fn process_may_be_running(_pid: u32) -> bool {
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{os_str_bytes, os_string_from_bytes};
+  use std::ffi::OsStr;
+
+  #[test]
+  fn bytes_roundtrip() {
+    let name = OsStr::new("roland.txt");
+    assert_eq!(&*os_str_bytes(name), b"roland.txt");
+    assert_eq!(os_string_from_bytes(b"roland.txt".to_vec()), name.to_os_string());
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn non_utf8_bytes_roundtrip() {
+    let bytes = b"caf\xe9".to_vec();
+    assert_eq!(&*os_str_bytes(&os_string_from_bytes(bytes.clone())), &bytes[..]);
+  }
+
+  #[test]
+  #[cfg(windows)]
+  fn unpaired_surrogates_are_distinct() {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+
+    let first = OsString::from_wide(&[0x61, 0xD800]);
+    let second = OsString::from_wide(&[0x61, 0xDBFF]);
+    assert_ne!(os_str_bytes(&first), os_str_bytes(&second));
+    assert_eq!(os_string_from_bytes(os_str_bytes(&first).into_owned()), first);
+    assert_eq!(os_string_from_bytes(os_str_bytes(&second).into_owned()), second);
+  }
+}
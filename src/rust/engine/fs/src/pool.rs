@@ -1,10 +1,16 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::sync::RwLock;
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, TryLockError};
+use std::time::{Duration, Instant};
 
+use async_semaphore::AsyncSemaphore;
+use boxfuture::BoxFuture;
 use futures::future::IntoFuture;
+use futures::{Async, Future, Poll};
 use futures_cpupool::{self, CpuFuture, CpuPool};
+use num_cpus;
 
 ///
 /// A wrapper around a CpuPool, to add the ability to drop the pool before forking,
@@ -14,17 +20,149 @@ use futures_cpupool::{self, CpuFuture, CpuPool};
 /// threads are effectively destroyed. If a CpuPool has live threads during a fork, it
 /// will not be able to perform any work or be dropped cleanly (it will hang instead).
 /// It's thus necessary to drop the pool before forking, and to re-create it after forking.
+/// Because another thread might re-create the pool between `reset` and the fork, the child should
+/// additionally call `reset_in_child` immediately after the fork.
+///
+/// The number of functions that may run on the pool at once can additionally be limited (see
+/// `set_limit`), and the time that functions spend waiting for and running on the pool is counted
+/// (see `counters`).
 ///
 pub struct ResettablePool {
+  inner: Arc<Inner>,
+  // Unlimited unless `set_limit` is called.
+  limiter: AsyncSemaphore,
+}
+
+struct Inner {
   name_prefix: String,
+  threads: usize,
   pool: RwLock<Option<CpuPool>>,
+  instrumentation: PoolInstrumentation,
+}
+
+///
+/// Counts of the work that has run on a pool of threads, of the total time that it spent running
+/// (which for a ResettablePool, which wraps blocking APIs, is time which would otherwise have been
+/// spent blocking the threads that drive futures), and of the total time that it spent queued
+/// waiting for a thread.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PoolCounters {
+  pub calls: u64,
+  pub busy: Duration,
+  pub queued: Duration,
+}
+
+impl PoolCounters {
+  ///
+  /// Returns the counts that have accumulated since the given (earlier) snapshot of these counters.
+  ///
+  pub fn since(&self, earlier: &PoolCounters) -> PoolCounters {
+    PoolCounters {
+      calls: self.calls - earlier.calls,
+      busy: self.busy - earlier.busy,
+      queued: self.queued - earlier.queued,
+    }
+  }
+
+  ///
+  /// Given the counts for a window of time of the given length, returns the fraction of the
+  /// capacity of a pool with the given number of threads that was in use during the window.
+  ///
+  pub fn utilization(&self, threads: usize, window: Duration) -> f64 {
+    let capacity = secs(window) * threads as f64;
+    if capacity > 0.0 {
+      secs(self.busy) / capacity
+    } else {
+      0.0
+    }
+  }
+}
+
+fn secs(duration: Duration) -> f64 {
+  duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+///
+/// Records the PoolCounters of a pool of threads, for pools (such as a tokio Runtime) which are not
+/// ResettablePools.
+///
+#[derive(Clone, Default)]
+pub struct PoolInstrumentation(Arc<Mutex<PoolCounters>>);
+
+impl PoolInstrumentation {
+  ///
+  /// Returns a snapshot of the counters of the work that has run on the pool.
+  ///
+  pub fn counters(&self) -> PoolCounters {
+    *self.0.lock().unwrap()
+  }
+
+  pub fn record(&self, queued: Duration, busy: Duration) {
+    let mut counters = self.0.lock().unwrap();
+    counters.calls += 1;
+    counters.busy += busy;
+    counters.queued += queued;
+  }
+
+  ///
+  /// Wraps a Future which is about to be spawned on the pool, to record the time that it spends
+  /// being polled, and the time between now and when it is first polled. The time that it spends
+  /// queued after later wakeups is not recorded.
+  ///
+  pub fn instrument<F: Future>(&self, future: F) -> Instrumented<F> {
+    Instrumented {
+      inner: future,
+      instrumentation: self.clone(),
+      spawned: Instant::now(),
+      queued: None,
+      busy: Duration::from_secs(0),
+    }
+  }
+}
+
+///
+/// A Future which records its PoolCounters when it completes: see `PoolInstrumentation::instrument`.
+///
+pub struct Instrumented<F> {
+  inner: F,
+  instrumentation: PoolInstrumentation,
+  spawned: Instant,
+  queued: Option<Duration>,
+  busy: Duration,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+  type Item = F::Item;
+  type Error = F::Error;
+
+  fn poll(&mut self) -> Poll<F::Item, F::Error> {
+    let start = Instant::now();
+    if self.queued.is_none() {
+      self.queued = Some(start.duration_since(self.spawned));
+    }
+    let result = self.inner.poll();
+    self.busy += start.elapsed();
+    match result {
+      Ok(Async::NotReady) => {}
+      _ => self
+        .instrumentation
+        .record(self.queued.unwrap_or_default(), self.busy),
+    }
+    result
+  }
 }
 
 impl ResettablePool {
   pub fn new(name_prefix: String) -> ResettablePool {
     ResettablePool {
-      name_prefix: name_prefix,
-      pool: RwLock::new(None),
+      inner: Arc::new(Inner {
+        name_prefix: name_prefix,
+        threads: num_cpus::get(),
+        pool: RwLock::new(None),
+        instrumentation: PoolInstrumentation::default(),
+      }),
+      limiter: AsyncSemaphore::new(usize::max_value()),
     }
   }
 
@@ -32,7 +170,90 @@ impl ResettablePool {
   /// Delegates to `CpuPool::spawn_fn`, and shares its signature.
   /// http://alexcrichton.com/futures-rs/futures_cpupool/struct.CpuPool.html#method.spawn_fn
   ///
-  pub fn spawn_fn<F, R>(&self, f: F) -> CpuFuture<R::Item, R::Error>
+  pub fn spawn_fn<F, R>(&self, f: F) -> BoxFuture<R::Item, R::Error>
+  where
+    F: FnOnce() -> R + Send + 'static,
+    R: IntoFuture + 'static,
+    R::Future: Send + 'static,
+    R::Item: Send + 'static,
+    R::Error: Send + 'static,
+  {
+    let inner = self.inner.clone();
+    // Time spent waiting for the limiter counts as time spent queued.
+    let submitted = Instant::now();
+    self.limiter.with_acquired(move || {
+      let instrumentation = inner.instrumentation.clone();
+      inner.spawn_fn(move || {
+        let start = Instant::now();
+        let result = f();
+        instrumentation.record(start.duration_since(submitted), start.elapsed());
+        result
+      })
+    })
+  }
+
+  ///
+  /// Drives the given Future on the pool in the background: unlike the Future returned by
+  /// `spawn_fn`, it runs to completion without being polled by the caller. It is not subject to the
+  /// limit on the number of functions running at once (see `set_limit`), so it should delegate any
+  /// blocking work to `spawn_fn`.
+  ///
+  pub fn spawn_detached<F>(&self, future: F)
+  where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+  {
+    self.inner.spawn_fn(move || future).forget();
+  }
+
+  ///
+  /// Returns a snapshot of the counters of functions that have run on this pool.
+  ///
+  pub fn counters(&self) -> PoolCounters {
+    self.inner.instrumentation.counters()
+  }
+
+  ///
+  /// The number of threads in the pool.
+  ///
+  pub fn threads(&self) -> usize {
+    self.inner.threads
+  }
+
+  ///
+  /// Limits the number of functions that may run on the pool at once (or given None, removes the
+  /// limit). Functions that are already running are not affected.
+  ///
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self.limiter.set_limit(limit);
+  }
+
+  pub fn reset(&self) {
+    let mut pool = self.inner.pool.write().unwrap_or_else(PoisonError::into_inner);
+    *pool = None;
+  }
+
+  ///
+  /// Resets the pool in a child process, immediately after a fork: see
+  /// `Resettable::reset_in_child`.
+  ///
+  pub fn reset_in_child(&self) -> Result<(), String> {
+    let mut pool = match self.inner.pool.try_write() {
+      Ok(pool) => pool,
+      Err(TryLockError::Poisoned(e)) => e.into_inner(),
+      Err(TryLockError::WouldBlock) => {
+        return Err(format!(
+          "The {} pool was locked by another thread at the time of a fork.",
+          self.inner.name_prefix
+        ))
+      }
+    };
+    mem::forget(pool.take());
+    Ok(())
+  }
+}
+
+impl Inner {
+  fn spawn_fn<F, R>(&self, f: F) -> CpuFuture<R::Item, R::Error>
   where
     F: FnOnce() -> R + Send + 'static,
     R: IntoFuture + 'static,
@@ -57,14 +278,80 @@ impl ResettablePool {
     self.spawn_fn(f)
   }
 
-  pub fn reset(&self) {
-    let mut pool = self.pool.write().unwrap();
-    *pool = None;
-  }
-
   fn new_pool(&self) -> CpuPool {
     futures_cpupool::Builder::new()
+      .pool_size(self.threads)
       .name_prefix(self.name_prefix.clone())
       .create()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{PoolInstrumentation, ResettablePool};
+  use futures::future::{self, Future};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn counts_blocked_time() {
+    let pool = ResettablePool::new("test-pool-".to_string());
+    let before = pool.counters();
+    pool
+      .spawn_fn(|| {
+        thread::sleep(Duration::from_millis(10));
+        Ok::<_, ()>(())
+      })
+      .wait()
+      .unwrap();
+    let counted = pool.counters().since(&before);
+    assert_eq!(counted.calls, 1);
+    assert!(counted.busy >= Duration::from_millis(10));
+  }
+
+  #[test]
+  fn counts_queued_time() {
+    let pool = ResettablePool::new("test-pool-".to_string());
+    pool.set_limit(Some(1));
+    let before = pool.counters();
+    let sleep = || {
+      thread::sleep(Duration::from_millis(10));
+      Ok::<_, ()>(())
+    };
+    // The second function waits for the first to complete.
+    pool
+      .spawn_fn(sleep)
+      .join(pool.spawn_fn(sleep))
+      .wait()
+      .unwrap();
+    let counted = pool.counters().since(&before);
+    assert_eq!(counted.calls, 2);
+    assert!(counted.queued >= Duration::from_millis(10));
+    assert!(counted.utilization(1, Duration::from_millis(10)) >= 2.0);
+  }
+
+  #[test]
+  fn instruments_futures() {
+    let instrumentation = PoolInstrumentation::default();
+    assert_eq!(
+      instrumentation.instrument(future::ok::<_, ()>(1)).wait(),
+      Ok(1)
+    );
+    assert_eq!(instrumentation.counters().calls, 1);
+  }
+
+  #[test]
+  fn works_after_reset_in_child() {
+    let pool = ResettablePool::new("test-pool-".to_string());
+    assert_eq!(pool.spawn_fn(|| Ok::<_, ()>(1)).wait(), Ok(1));
+    pool.reset_in_child().unwrap();
+    assert_eq!(pool.spawn_fn(|| Ok::<_, ()>(2)).wait(), Ok(2));
+  }
+
+  #[test]
+  fn reset_in_child_fails_if_locked() {
+    let pool = ResettablePool::new("test-pool-".to_string());
+    let _locked = pool.inner.pool.read().unwrap();
+    assert!(pool.reset_in_child().is_err());
+  }
+}
@@ -0,0 +1,101 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use PathStat;
+
+///
+/// The number and total size of the files beneath a directory, at any depth.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectorySize {
+  pub path: PathBuf,
+  pub file_count: u64,
+  pub total_bytes: u64,
+}
+
+///
+/// Summarizes the sizes of the files in the given PathStats (which are usually the expansion of
+/// some PathGlobs) by directory, using the sizes recorded when the files were stat'd rather than
+/// reading them.
+///
+/// Every directory which contains a file at any depth is included (down to the empty path, for the
+/// root), and the summaries are sorted by path. Directories in the PathStats which contain no files
+/// are not included.
+///
+pub fn summarize_sizes(path_stats: &[PathStat]) -> Vec<DirectorySize> {
+  let mut sizes: BTreeMap<&Path, (u64, u64)> = BTreeMap::new();
+  for path_stat in path_stats {
+    let (path, size) = match path_stat {
+      &PathStat::File { ref path, ref stat } => (path.as_path(), stat.size),
+      &PathStat::Dir { .. } => continue,
+    };
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+      let entry = sizes.entry(parent).or_insert((0, 0));
+      entry.0 += 1;
+      entry.1 += size;
+      dir = parent.parent();
+    }
+  }
+  sizes
+    .into_iter()
+    .map(|(path, (file_count, total_bytes))| DirectorySize {
+      path: path.to_owned(),
+      file_count,
+      total_bytes,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{summarize_sizes, DirectorySize};
+  use std::path::PathBuf;
+  use {Dir, File, PathStat};
+
+  fn file(path: &str, size: u64) -> PathStat {
+    PathStat::file(
+      PathBuf::from(path),
+      File {
+        path: PathBuf::from(path).into(),
+        is_executable: false,
+        size,
+      },
+    )
+  }
+
+  fn size(path: &str, file_count: u64, total_bytes: u64) -> DirectorySize {
+    DirectorySize {
+      path: PathBuf::from(path),
+      file_count,
+      total_bytes,
+    }
+  }
+
+  #[test]
+  fn summarizes_recursively() {
+    let path_stats = vec![
+      file("README", 10),
+      file("src/a.rs", 100),
+      file("src/b/c.rs", 1000),
+      file("src/b/d.rs", 1),
+      PathStat::dir(PathBuf::from("empty"), Dir(PathBuf::from("empty").into())),
+    ];
+    assert_eq!(
+      summarize_sizes(&path_stats),
+      vec![
+        size("", 4, 1111),
+        size("src", 3, 1101),
+        size("src/b", 2, 1001),
+      ]
+    );
+  }
+
+  #[test]
+  fn summarizes_nothing() {
+    assert_eq!(summarize_sizes(&[]), vec![]);
+  }
+}
@@ -3,17 +3,21 @@
 
 use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
-use futures::future::{self, join_all};
+use bytes::Bytes;
+use digest::{Digest as DigestTrait, FixedOutput};
+use futures::future::{self, join_all, Shared};
 use futures::Future;
 use hashing::{Digest, Fingerprint};
 use indexmap::{self, IndexMap};
 use itertools::Itertools;
+use os::os_str_bytes;
 use protobuf;
-use std::ffi::OsString;
+use sha2::Sha256;
+use std::collections::HashMap;
 use std::fmt;
 use std::path::PathBuf;
-use std::sync::Arc;
-use {File, PathStat, PosixFS, Store};
+use std::sync::{Arc, Mutex};
+use {encode_name, path_order, File, InodeDigests, InternedPath, PathStat, PosixFS, Store};
 
 pub const EMPTY_FINGERPRINT: Fingerprint = Fingerprint([
   0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
@@ -21,9 +25,12 @@ pub const EMPTY_FINGERPRINT: Fingerprint = Fingerprint([
 ]);
 pub const EMPTY_DIGEST: Digest = Digest(EMPTY_FINGERPRINT, 0);
 
+const SHA256SUMS: &str = "SHA256SUMS";
+
 #[derive(Eq, Hash, PartialEq)]
 pub struct Snapshot {
   pub digest: Digest,
+  // Sorted by `path_order`, regardless of the order in which the PathStats were provided.
   pub path_stats: Vec<PathStat>,
 }
 
@@ -43,10 +50,13 @@ impl Snapshot {
     file_digester: S,
     path_stats: Vec<PathStat>,
   ) -> BoxFuture<Snapshot, String> {
-    let mut sorted_path_stats = path_stats.clone();
-    sorted_path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    let mut sorted_path_stats = path_stats;
+    sorted_path_stats.sort_by(|a, b| path_order(a.path(), b.path()));
     Snapshot::ingest_directory_from_sorted_path_stats(store, &file_digester, &sorted_path_stats)
-      .map(|digest| Snapshot { digest, path_stats })
+      .map(|digest| Snapshot {
+        digest,
+        path_stats: sorted_path_stats,
+      })
       .to_boxed()
   }
 
@@ -59,7 +69,7 @@ impl Snapshot {
     path_stats: &[PathStat],
   ) -> BoxFuture<Digest, String> {
     let mut sorted_path_stats = path_stats.to_owned();
-    sorted_path_stats.sort_by(|a, b| a.path().cmp(b.path()));
+    sorted_path_stats.sort_by(|a, b| path_order(a.path(), b.path()));
     Snapshot::ingest_directory_from_sorted_path_stats(store, &file_digester, &sorted_path_stats)
   }
 
@@ -97,12 +107,12 @@ impl Snapshot {
                 .clone()
                 .store_by_digest(stat.clone())
                 .map_err(|e| format!("{:?}", e))
-                .and_then(move |digest| {
+                .map(move |digest| {
                   let mut file_node = bazel_protos::remote_execution::FileNode::new();
-                  file_node.set_name(osstring_as_utf8(first_component)?);
+                  file_node.set_name(encode_name(&first_component));
                   file_node.set_digest((&digest).into());
                   file_node.set_is_executable(is_executable);
-                  Ok(file_node)
+                  file_node
                 })
                 .to_boxed(),
             );
@@ -114,7 +124,7 @@ impl Snapshot {
                 .record_directory(&bazel_protos::remote_execution::Directory::new(), true)
                 .map(move |digest| {
                   let mut directory_node = bazel_protos::remote_execution::DirectoryNode::new();
-                  directory_node.set_name(osstring_as_utf8(first_component).unwrap());
+                  directory_node.set_name(encode_name(&first_component));
                   directory_node.set_digest((&digest).into());
                   directory_node
                 })
@@ -129,11 +139,11 @@ impl Snapshot {
             store.clone(),
             file_digester,
             &paths_of_child_dir(path_group),
-          ).and_then(move |digest| {
+          ).map(move |digest| {
             let mut dir_node = bazel_protos::remote_execution::DirectoryNode::new();
-            dir_node.set_name(osstring_as_utf8(first_component)?);
+            dir_node.set_name(encode_name(&first_component));
             dir_node.set_digest((&digest).into());
-            Ok(dir_node)
+            dir_node
           })
             .to_boxed(),
         );
@@ -141,7 +151,11 @@ impl Snapshot {
     }
     join_all(dir_futures)
       .join(join_all(file_futures))
-      .and_then(move |(dirs, files)| {
+      .and_then(move |(mut dirs, mut files)| {
+        // The PathStats were sorted by `path_order`, but Directory protos are sorted by their
+        // (possibly encoded: see `encode_name`) names, which differs for names that are not UTF8.
+        dirs.sort_by(|a, b| a.name.cmp(&b.name));
+        files.sort_by(|a, b| a.name.cmp(&b.name));
         let mut directory = bazel_protos::remote_execution::Directory::new();
         directory.set_directories(protobuf::RepeatedField::from_vec(dirs));
         directory.set_files(protobuf::RepeatedField::from_vec(files));
@@ -179,7 +193,9 @@ impl Snapshot {
           }
         }
       }
-      uniq_paths.into_iter().map(|(_, v)| v).collect()
+      let mut path_stats = uniq_paths.into_iter().map(|(_, v)| v).collect::<Vec<_>>();
+      path_stats.sort_by(|a, b| path_order(a.path(), b.path()));
+      path_stats
     };
     // Recursively merge the Digests in the Snapshots.
     Self::merge_directories(store, snapshots.iter().map(|s| s.digest).collect())
@@ -206,7 +222,7 @@ impl Snapshot {
       .into_iter()
       .map(|digest| {
         store
-          .load_directory(digest)
+          .load_directory_shared(digest)
           .and_then(move |maybe_directory| {
             maybe_directory
               .ok_or_else(|| format!("Digest {:?} did not exist in the Store.", digest))
@@ -214,15 +230,15 @@ impl Snapshot {
       })
       .collect::<Vec<_>>();
     join_all(directories)
-      .and_then(move |mut directories| {
+      .and_then(move |directories| {
         let mut out_dir = bazel_protos::remote_execution::Directory::new();
 
         // Merge FileNodes.
         out_dir.set_files(protobuf::RepeatedField::from_vec(
           Itertools::flatten(
             directories
-              .iter_mut()
-              .map(|directory| directory.take_files().into_iter()),
+              .iter()
+              .map(|directory| directory.get_files().iter().cloned()),
           ).collect(),
         ));
         out_dir.mut_files().sort_by(|a, b| a.name.cmp(&b.name));
@@ -251,12 +267,26 @@ impl Snapshot {
         let sorted_child_directories = {
           let mut merged_directories = Itertools::flatten(
             directories
-              .iter_mut()
-              .map(|directory| directory.take_directories().into_iter()),
+              .iter()
+              .map(|directory| directory.get_directories().iter().cloned()),
           ).collect::<Vec<_>>();
           merged_directories.sort_by(|a, b| a.name.cmp(&b.name));
           merged_directories
         };
+        // A file and a directory (which may be empty) with the same name cannot both be kept.
+        for child_dir in &sorted_child_directories {
+          if out_dir
+            .get_files()
+            .binary_search_by(|f| f.get_name().cmp(child_dir.get_name()))
+            .is_ok()
+          {
+            return future::err(format!(
+              "Can only merge Directories with no duplicates, but found a file and a directory \
+               both named: {}",
+              child_dir.get_name()
+            )).to_boxed();
+          }
+        }
         let store2 = store.clone();
         join_all(
           sorted_child_directories
@@ -286,6 +316,72 @@ impl Snapshot {
       })
       .to_boxed()
   }
+
+  ///
+  /// Returns a Snapshot containing a single file named `SHA256SUMS`, which lists the fingerprint of
+  /// each file in the given Directory in the format of (and so can be verified by)
+  /// `sha256sum --check`.
+  ///
+  /// Files are listed in `path_order`, so the manifests of two identical Directories are identical,
+  /// and the manifests of two Directories which were expected to be identical can be diffed to find
+  /// the files which differ.
+  ///
+  pub fn sha256sums(store: Store, dir_digest: Digest) -> BoxFuture<Snapshot, String> {
+    store
+      .file_digests_for_directory(dir_digest)
+      .and_then(move |file_digests| {
+        let manifest = sha256sums_manifest(&file_digests);
+        let size = manifest.len() as u64;
+        let store2 = store.clone();
+        store
+          .store_file_bytes(manifest, true)
+          .and_then(move |manifest_digest| {
+            let mut directory = bazel_protos::remote_execution::Directory::new();
+            directory.mut_files().push({
+              let mut file_node = bazel_protos::remote_execution::FileNode::new();
+              file_node.set_name(SHA256SUMS.to_owned());
+              file_node.set_digest((&manifest_digest).into());
+              file_node
+            });
+            store2.record_directory(&directory, true)
+          })
+          .map(move |digest| Snapshot {
+            digest,
+            path_stats: vec![PathStat::file(
+              PathBuf::from(SHA256SUMS),
+              File {
+                path: PathBuf::from(SHA256SUMS).into(),
+                is_executable: false,
+                size,
+              },
+            )],
+          })
+      })
+      .to_boxed()
+  }
+}
+
+fn sha256sums_manifest(file_digests: &[(PathBuf, Digest)]) -> Bytes {
+  let mut manifest = Vec::new();
+  for &(ref path, digest) in file_digests {
+    let path = os_str_bytes(path.as_os_str());
+    // Like `sha256sum`, escape backslashes and newlines in paths, and mark the lines which contain
+    // escapes with a leading backslash.
+    if path.iter().any(|&byte| byte == b'\\' || byte == b'\n') {
+      manifest.push(b'\\');
+    }
+    manifest.extend_from_slice(digest.0.to_hex().as_bytes());
+    manifest.extend_from_slice(b"  ");
+    for &byte in path.iter() {
+      match byte {
+        b'\\' => manifest.extend_from_slice(b"\\\\"),
+        b'\n' => manifest.extend_from_slice(b"\\n"),
+        _ => manifest.push(byte),
+      }
+    }
+    manifest.push(b'\n');
+  }
+  Bytes::from(manifest)
 }
 
 impl fmt::Debug for Snapshot {
@@ -308,11 +404,11 @@ fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
       }
       Some(match s {
         PathStat::File { path, stat } => PathStat::File {
-          path: path.iter().skip(1).collect(),
+          path: path.iter().skip(1).collect::<PathBuf>().into(),
           stat: stat,
         },
         PathStat::Dir { path, stat } => PathStat::Dir {
-          path: path.iter().skip(1).collect(),
+          path: path.iter().skip(1).collect::<PathBuf>().into(),
           stat: stat,
         },
       })
@@ -320,43 +416,215 @@ fn paths_of_child_dir(paths: Vec<PathStat>) -> Vec<PathStat> {
     .collect()
 }
 
-fn osstring_as_utf8(path: OsString) -> Result<String, String> {
-  path
-    .into_string()
-    .map_err(|p| format!("{:?}'s file_name is not representable in UTF8", p))
-}
-
 // StoreFileByDigest allows a File to be saved to an underlying Store, in such a way that it can be
 // looked up by the Digest produced by the store_by_digest method.
 // It is a separate trait so that caching implementations can be written which wrap the Store (used
 // to store the bytes) and VFS (used to read the files off disk if needed).
+///
+/// Memoizes Snapshots by the (sorted) PathStats that they were created from, so that distinct
+/// PathGlobs which expand to identical PathStats create and store their Snapshot only once.
+///
+/// Like GlobExpansionMemo, entries are only valid while the files that they describe are unchanged,
+/// so the memo should be cleared whenever they might have changed (ie, between Sessions). Because
+/// PathStats do not identify the content of files, every file is also digested again on each use,
+/// and an entry is only used if the Digests of its files are unchanged.
+///
+#[derive(Default)]
+pub struct SnapshotMemo {
+  entries: Arc<Mutex<HashMap<Fingerprint, SnapshotMemoEntry>>>,
+}
+
+struct SnapshotMemoEntry {
+  // The Digests of the files of the Snapshot, in the order of its (sorted) PathStats.
+  file_digests: Vec<Digest>,
+  snapshot: Shared<BoxFuture<Snapshot, String>>,
+}
+
+impl SnapshotMemo {
+  pub fn new() -> SnapshotMemo {
+    SnapshotMemo::default()
+  }
+
+  ///
+  /// Forgets all memoized Snapshots.
+  ///
+  pub fn clear(&self) {
+    self.entries.lock().unwrap().clear();
+  }
+
+  ///
+  /// As `Snapshot::from_path_stats`, but shares the Snapshot (and the work of creating it) with
+  /// any previous call for the same PathStats whose files had the same Digests. Every file is still
+  /// digested with the given digester, so that a digester which records dependencies on files
+  /// observes all of them.
+  ///
+  pub fn from_path_stats<
+    S: StoreFileByDigest<Error> + Sized + Clone,
+    Error: fmt::Debug + 'static + Send,
+  >(
+    &self,
+    store: Store,
+    file_digester: S,
+    path_stats: Vec<PathStat>,
+  ) -> BoxFuture<Snapshot, String> {
+    let mut sorted_path_stats = path_stats;
+    sorted_path_stats.sort_by(|a, b| path_order(a.path(), b.path()));
+    let fingerprint = SnapshotMemo::fingerprint(&sorted_path_stats);
+
+    let digests = sorted_path_stats
+      .iter()
+      .filter_map(|path_stat| match *path_stat {
+        PathStat::File { ref stat, .. } => Some(
+          file_digester
+            .store_by_digest(stat.clone())
+            .map_err(|e| format!("{:?}", e)),
+        ),
+        PathStat::Dir { .. } => None,
+      })
+      .collect::<Vec<_>>();
+    let entries = self.entries.clone();
+    join_all(digests)
+      .and_then(move |file_digests| {
+        let memoized = {
+          let entries = entries.lock().unwrap();
+          entries.get(&fingerprint).and_then(|entry| {
+            if entry.file_digests == file_digests {
+              Some(entry.snapshot.clone())
+            } else {
+              None
+            }
+          })
+        };
+        let shared = match memoized {
+          Some(shared) => shared,
+          None => {
+            // Either there is no entry, or the content of its files has changed since it was
+            // created: (re-)create it. If another caller raced to create the same Snapshot, both
+            // are created.
+            let digester = PrecomputedDigests::new(&sorted_path_stats, &file_digests);
+            let shared = Snapshot::from_path_stats(store, digester, sorted_path_stats).shared();
+            entries.lock().unwrap().insert(
+              fingerprint,
+              SnapshotMemoEntry {
+                file_digests: file_digests,
+                snapshot: shared.clone(),
+              },
+            );
+            shared
+          }
+        };
+        SnapshotMemo::unshare(shared)
+      })
+      .to_boxed()
+  }
+
+  fn unshare(shared: Shared<BoxFuture<Snapshot, String>>) -> BoxFuture<Snapshot, String> {
+    shared
+      .map(|snapshot| Snapshot {
+        digest: snapshot.digest,
+        path_stats: snapshot.path_stats.clone(),
+      })
+      .map_err(|e| (*e).clone())
+      .to_boxed()
+  }
+
+  ///
+  /// A Fingerprint of the given PathStats, which must be sorted by `path_order`.
+  ///
+  fn fingerprint(sorted_path_stats: &[PathStat]) -> Fingerprint {
+    let mut hasher = Sha256::default();
+    for path_stat in sorted_path_stats {
+      let (kind, is_executable, size) = match *path_stat {
+        PathStat::Dir { .. } => (b'd', false, 0),
+        PathStat::File { ref stat, .. } => (b'f', stat.is_executable, stat.size),
+      };
+      hasher.input(&[kind, is_executable as u8]);
+      hasher.input(format!("{}:", size).as_bytes());
+      hasher.input(&os_str_bytes(path_stat.path().as_os_str()));
+      hasher.input(&[0]);
+    }
+    Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
+  }
+}
+
 pub trait StoreFileByDigest<Error> {
   fn store_by_digest(&self, file: File) -> BoxFuture<Digest, Error>;
 }
 
 ///
-/// A StoreFileByDigest which reads with a PosixFS and writes to a Store, with no caching.
+/// A StoreFileByDigest for files which have already been stored, and whose Digests are known.
+///
+#[derive(Clone)]
+struct PrecomputedDigests(Arc<HashMap<InternedPath, Digest>>);
+
+impl PrecomputedDigests {
+  ///
+  /// Pairs the files of the given PathStats with the given Digests, which are in the same order.
+  ///
+  fn new(path_stats: &[PathStat], file_digests: &[Digest]) -> PrecomputedDigests {
+    let files = path_stats.iter().filter_map(|path_stat| match *path_stat {
+      PathStat::File { ref stat, .. } => Some(stat.path.clone()),
+      PathStat::Dir { .. } => None,
+    });
+    PrecomputedDigests(Arc::new(files.zip(file_digests.iter().cloned()).collect()))
+  }
+}
+
+impl StoreFileByDigest<String> for PrecomputedDigests {
+  fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+    future::result(
+      self
+        .0
+        .get(&file.path)
+        .cloned()
+        .ok_or_else(|| format!("No Digest was computed for {:?}", file.path)),
+    ).to_boxed()
+  }
+}
+
+///
+/// A StoreFileByDigest which reads with a PosixFS and writes to a Store, with no caching (other
+/// than that files which are hardlinked to from multiple paths are only read once: see
+/// `InodeDigests`).
 ///
 #[derive(Clone)]
 pub struct OneOffStoreFileByDigest {
   store: Store,
   posix_fs: Arc<PosixFS>,
+  inode_digests: InodeDigests,
 }
 
 impl OneOffStoreFileByDigest {
   pub fn new(store: Store, posix_fs: Arc<PosixFS>) -> OneOffStoreFileByDigest {
-    OneOffStoreFileByDigest { store, posix_fs }
+    OneOffStoreFileByDigest {
+      store,
+      posix_fs,
+      inode_digests: InodeDigests::new(),
+    }
   }
 }
 
 impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
   fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
     let store = self.store.clone();
+    let posix_fs = self.posix_fs.clone();
+    let inode_digests = self.inode_digests.clone();
     self
       .posix_fs
-      .read_file(&file)
-      .map_err(move |err| format!("Error reading file {:?}: {:?}", file, err))
-      .and_then(move |content| store.store_file_bytes(content.content, true))
+      .inode(&file)
+      .map_err({
+        let file = file.clone();
+        move |err| format!("Error reading file {:?}: {:?}", file, err)
+      })
+      .and_then(move |inode| {
+        inode_digests.digest(inode, move || {
+          posix_fs
+            .read_file(&file)
+            .map_err(move |err| format!("Error reading file {:?}: {:?}", file, err))
+            .and_then(move |content| store.store_file_bytes(content.content, true))
+            .to_boxed()
+        })
+      })
       .to_boxed()
   }
 }
@@ -364,19 +632,24 @@ impl StoreFileByDigest<String> for OneOffStoreFileByDigest {
 #[cfg(test)]
 mod tests {
   extern crate tempfile;
+  use bazel_protos;
+  use boxfuture::BoxFuture;
   use futures::future::Future;
   use hashing::{Digest, Fingerprint};
-  use testutil::data::TestDirectory;
+  use testutil::data::{TestData, TestDirectory};
   use testutil::make_file;
 
   use super::super::{
-    Dir, File, GlobMatching, Path, PathGlobs, PathStat, PosixFS, ResettablePool, Snapshot, Store,
-    StrictGlobMatching,
+    path_order, Dir, File, GlobMatching, Path, PathGlobs, PathStat, PosixFS, ResettablePool,
+    Snapshot, SpecialFileBehavior, Store, StrictGlobMatching,
   };
-  use super::OneOffStoreFileByDigest;
+  use super::{OneOffStoreFileByDigest, SnapshotMemo, StoreFileByDigest};
 
   use std;
+  use std::ffi::OsStr;
+  use std::os::unix::ffi::OsStrExt;
   use std::path::PathBuf;
+  use std::sync::atomic::{AtomicUsize, Ordering};
   use std::sync::Arc;
 
   const STR: &str = "European Burmese";
@@ -397,7 +670,8 @@ mod tests {
       pool.clone(),
     ).unwrap();
     let dir = tempfile::Builder::new().prefix("root").tempdir().unwrap();
-    let posix_fs = Arc::new(PosixFS::new(dir.path(), pool, &[]).unwrap());
+    let posix_fs =
+      Arc::new(PosixFS::new(dir.path(), pool, &[], SpecialFileBehavior::Error).unwrap());
     let file_saver = OneOffStoreFileByDigest::new(store.clone(), posix_fs.clone());
     (store, dir, posix_fs, file_saver)
   }
@@ -452,6 +726,101 @@ mod tests {
     );
   }
 
+  #[test]
+  fn snapshot_hardlinked_files() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    std::fs::hard_link(dir.path().join("roland"), dir.path().join("susannah")).unwrap();
+
+    let path_stats = expand_all_sorted(posix_fs);
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, path_stats)
+      .wait()
+      .unwrap();
+    let file_digests = store
+      .file_digests_for_directory(snapshot.digest)
+      .wait()
+      .unwrap();
+    let paths = file_digests
+      .iter()
+      .map(|&(ref path, _)| path.clone())
+      .collect::<Vec<_>>();
+    assert_eq!(vec![PathBuf::from("roland"), PathBuf::from("susannah")], paths);
+    assert_eq!(file_digests[0].1, file_digests[1].1);
+  }
+
+  #[test]
+  fn snapshot_path_stats_sorted() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    for name in &["a.b", "a/b", "a-b/c", "B"] {
+      let path = dir.path().join(name);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      make_file(&path, STR.as_bytes(), 0o600);
+    }
+
+    let mut path_stats = expand_all_sorted(posix_fs);
+    path_stats.reverse();
+    let snapshot = Snapshot::from_path_stats(store, digester, path_stats)
+      .wait()
+      .unwrap();
+    assert_eq!(
+      snapshot
+        .path_stats
+        .iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>(),
+      vec!["B", "a", "a/b", "a-b", "a-b/c", "a.b"]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>()
+    );
+  }
+
+  #[test]
+  fn snapshot_and_materialize_non_utf8_names() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let latin1 = PathBuf::from(OsStr::from_bytes(b"caf\xe9"));
+    let nested = latin1.join(OsStr::from_bytes(b"100%\xff"));
+    std::fs::create_dir(dir.path().join(&latin1)).unwrap();
+    make_file(&dir.path().join(&nested), STR.as_bytes(), 0o600);
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, expand_all_sorted(posix_fs))
+      .wait()
+      .unwrap();
+    assert_eq!(
+      snapshot
+        .path_stats
+        .iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>(),
+      vec![latin1.clone(), nested.clone(), PathBuf::from("roland")]
+    );
+
+    let contents = store
+      .load_directory(snapshot.digest)
+      .wait()
+      .unwrap()
+      .map(|directory| store.contents_for_directory(&directory).wait().unwrap())
+      .unwrap();
+    assert_eq!(
+      contents.iter().map(|fc| fc.path.clone()).collect::<Vec<_>>(),
+      vec![nested.clone(), PathBuf::from("roland")]
+    );
+
+    let materialize_dir = tempfile::TempDir::new().unwrap();
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), snapshot.digest)
+      .wait()
+      .unwrap();
+    assert_eq!(
+      std::fs::read(materialize_dir.path().join(&nested)).unwrap(),
+      STR.as_bytes().to_vec()
+    );
+  }
+
   #[test]
   fn snapshot_recursive_directories_including_empty() {
     let (store, dir, posix_fs, digester) = setup();
@@ -479,7 +848,7 @@ mod tests {
           ).unwrap(),
           232,
         ),
-        path_stats: unsorted_path_stats,
+        path_stats: sorted_path_stats,
       }
     );
   }
@@ -540,6 +909,99 @@ mod tests {
     );
   }
 
+  #[test]
+  fn merge_directories_clashing_file_and_directory() {
+    let (store, _, _, _) = setup();
+
+    let containing_roland = TestDirectory::containing_roland();
+    let containing_roland_dir = {
+      let mut directory = bazel_protos::remote_execution::Directory::new();
+      directory.mut_directories().push({
+        let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+        subdir.set_name("roland".to_string());
+        subdir.set_digest((&TestDirectory::empty().digest()).into());
+        subdir
+      });
+      directory
+    };
+
+    store
+      .record_directory(&containing_roland.directory(), false)
+      .wait()
+      .expect("Storing roland directory");
+    let containing_roland_dir_digest = store
+      .record_directory(&containing_roland_dir, false)
+      .wait()
+      .expect("Storing roland dir directory");
+
+    let err = Snapshot::merge_directories(
+      store,
+      vec![containing_roland.digest(), containing_roland_dir_digest],
+    ).wait()
+      .expect_err("Want error merging");
+
+    assert!(
+      err.contains("roland"),
+      "Want error message to contain roland but was: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn snapshot_merge_and_materialize_empty_directories() {
+    let (store, tempdir, _, digester) = setup();
+
+    let cats = make_dir_stat(tempdir.path(), &PathBuf::from("cats"));
+    let dogs = make_dir_stat(tempdir.path(), &PathBuf::from("dogs"));
+    let roland = make_file_stat(
+      tempdir.path(),
+      &PathBuf::from("dogs/roland"),
+      STR.as_bytes(),
+      false,
+    );
+
+    let merged = {
+      let snapshot1 = Snapshot::from_path_stats(store.clone(), digester.clone(), vec![cats.clone()])
+        .wait()
+        .unwrap();
+      let snapshot2 =
+        Snapshot::from_path_stats(store.clone(), digester, vec![dogs.clone(), roland.clone()])
+          .wait()
+          .unwrap();
+      Snapshot::merge(store.clone(), &[snapshot1, snapshot2])
+        .wait()
+        .unwrap()
+    };
+    assert_eq!(merged.path_stats, vec![cats, dogs, roland]);
+
+    let merged_root_directory = store.load_directory(merged.digest).wait().unwrap().unwrap();
+    assert_eq!(
+      merged_root_directory
+        .directories
+        .iter()
+        .map(|dirnode| dirnode.name.clone())
+        .collect::<Vec<_>>(),
+      vec!["cats".to_string(), "dogs".to_string()],
+    );
+
+    let materialize_dir = tempfile::TempDir::new().unwrap();
+    store
+      .materialize_directory(materialize_dir.path().to_owned(), merged.digest)
+      .wait()
+      .unwrap();
+    assert!(materialize_dir.path().join("cats").is_dir());
+    assert_eq!(
+      std::fs::read_dir(materialize_dir.path().join("cats"))
+        .unwrap()
+        .count(),
+      0
+    );
+    assert_eq!(
+      std::fs::read(materialize_dir.path().join("dogs/roland")).unwrap(),
+      STR.as_bytes().to_vec()
+    );
+  }
+
   #[test]
   fn snapshot_merge_two_files() {
     let (store, tempdir, _, digester) = setup();
@@ -633,9 +1095,64 @@ mod tests {
     }
   }
 
+  #[test]
+  fn sha256sums() {
+    let (store, dir, _, digester) = setup();
+
+    let path_stats = vec![
+      make_file_stat(dir.path(), Path::new("roland"), STR.as_bytes(), false),
+      make_dir_stat(dir.path(), Path::new("cats")),
+      make_file_stat(dir.path(), Path::new("cats/new\nline"), b"", false),
+      make_file_stat(dir.path(), Path::new("cats/back\\slash"), b"", false),
+    ];
+    let snapshot = Snapshot::from_path_stats(store.clone(), digester, path_stats)
+      .wait()
+      .unwrap();
+
+    let sums = Snapshot::sha256sums(store.clone(), snapshot.digest)
+      .wait()
+      .unwrap();
+    assert_eq!(
+      sums
+        .path_stats
+        .iter()
+        .map(|path_stat| path_stat.path().to_owned())
+        .collect::<Vec<_>>(),
+      vec![PathBuf::from("SHA256SUMS")]
+    );
+    let empty = TestData::empty().fingerprint().to_hex();
+    assert_eq!(
+      manifest_content(&store, sums.digest),
+      format!(
+        "\\{}  cats/back\\\\slash\n\\{}  cats/new\\nline\n{}  roland\n",
+        empty,
+        empty,
+        TestData::new(STR).fingerprint().to_hex()
+      )
+    );
+  }
+
+  #[test]
+  fn sha256sums_empty_directory() {
+    let (store, _, _, _) = setup();
+
+    let sums = Snapshot::sha256sums(store.clone(), TestDirectory::empty().digest())
+      .wait()
+      .unwrap();
+    assert_eq!(manifest_content(&store, sums.digest), "");
+  }
+
+  fn manifest_content(store: &Store, digest: Digest) -> String {
+    let directory = store.load_directory(digest).wait().unwrap().unwrap();
+    let files_content = store.contents_for_directory(&directory).wait().unwrap();
+    assert_eq!(files_content.len(), 1);
+    assert_eq!(files_content[0].path, PathBuf::from("SHA256SUMS"));
+    String::from_utf8(files_content[0].content.to_vec()).unwrap()
+  }
+
   fn make_dir_stat(root: &Path, relpath: &Path) -> PathStat {
     std::fs::create_dir(root.join(relpath)).unwrap();
-    PathStat::dir(relpath.to_owned(), Dir(relpath.to_owned()))
+    PathStat::dir(relpath.to_owned(), Dir(relpath.to_owned().into()))
   }
 
   fn make_file_stat(root: &Path, relpath: &Path, contents: &[u8], is_executable: bool) -> PathStat {
@@ -647,12 +1164,103 @@ mod tests {
     PathStat::file(
       relpath.to_owned(),
       File {
-        path: relpath.to_owned(),
+        path: relpath.to_owned().into(),
         is_executable,
+        size: contents.len() as u64,
       },
     )
   }
 
+  ///
+  /// A StoreFileByDigest which counts the files that it is asked to digest.
+  ///
+  #[derive(Clone)]
+  struct CountingStoreFileByDigest {
+    inner: OneOffStoreFileByDigest,
+    count: Arc<AtomicUsize>,
+  }
+
+  impl StoreFileByDigest<String> for CountingStoreFileByDigest {
+    fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+      self.count.fetch_add(1, Ordering::SeqCst);
+      self.inner.store_by_digest(file)
+    }
+  }
+
+  #[test]
+  fn snapshot_memo_shares_snapshots_of_identical_path_stats() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    make_file(&dir.path().join("roland"), STR.as_bytes(), 0o600);
+    make_file(&dir.path().join("susannah"), STR.as_bytes(), 0o600);
+    let path_stats = expand_all_sorted(posix_fs);
+
+    let memo = SnapshotMemo::new();
+    let count = Arc::new(AtomicUsize::new(0));
+    let digester = CountingStoreFileByDigest {
+      inner: digester,
+      count: count.clone(),
+    };
+    let first = memo
+      .from_path_stats(store.clone(), digester.clone(), path_stats.clone())
+      .wait()
+      .unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+
+    let mut reversed = path_stats.clone();
+    reversed.reverse();
+    let second = memo
+      .from_path_stats(store.clone(), digester.clone(), reversed)
+      .wait()
+      .unwrap();
+    assert_eq!(first, second);
+    // Every file is digested again, so that dependencies on them can be recorded.
+    assert_eq!(count.load(Ordering::SeqCst), 4);
+
+    memo.clear();
+    let third = memo
+      .from_path_stats(store, digester, path_stats)
+      .wait()
+      .unwrap();
+    assert_eq!(first, third);
+    assert_eq!(count.load(Ordering::SeqCst), 6);
+  }
+
+  #[test]
+  fn snapshot_memo_recreates_snapshots_of_changed_files() {
+    let (store, dir, posix_fs, digester) = setup();
+
+    let roland = dir.path().join("roland");
+    make_file(&roland, STR.as_bytes(), 0o600);
+    let path_stats = expand_all_sorted(posix_fs.clone());
+
+    let memo = SnapshotMemo::new();
+    let first = memo
+      .from_path_stats(store.clone(), digester, path_stats.clone())
+      .wait()
+      .unwrap();
+
+    // Content of the same length leaves the PathStats unchanged.
+    let changed = "Siamese Burmese!";
+    assert_eq!(changed.len(), STR.len());
+    make_file(&roland, changed.as_bytes(), 0o600);
+    assert_eq!(path_stats, expand_all_sorted(posix_fs.clone()));
+
+    // A new digester (as for a new Session) observes the change, and so the memo does too.
+    let digester = OneOffStoreFileByDigest::new(store.clone(), posix_fs);
+    let second = memo
+      .from_path_stats(store.clone(), digester.clone(), path_stats.clone())
+      .wait()
+      .unwrap();
+    assert_ne!(first.digest, second.digest);
+    assert_eq!(
+      Snapshot::from_path_stats(store, digester, path_stats)
+        .wait()
+        .unwrap(),
+      second
+    );
+  }
+
   fn expand_all_sorted(posix_fs: Arc<PosixFS>) -> Vec<PathStat> {
     let mut v = posix_fs
       .expand(
@@ -661,7 +1269,7 @@ mod tests {
       )
       .wait()
       .unwrap();
-    v.sort_by(|a, b| a.path().cmp(b.path()));
+    v.sort_by(|a, b| path_order(a.path(), b.path()));
     v
   }
 }
@@ -1,4 +1,4 @@
-use FileContent;
+use {decode_name, path_order, FileContent};
 
 use bazel_protos;
 use boxfuture::{BoxFuture, Boxable};
@@ -6,25 +6,178 @@ use bytes::Bytes;
 use futures::{future, Future};
 use hashing::Digest;
 use protobuf::core::Message;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, TryLockError};
+use std::time::{Duration, Instant};
+
+use clone::CloneSources;
+use directory_cache::DirectoryCache;
+use fd_budget::FdBudget;
+use os;
 use pool::ResettablePool;
 
-// This is the maximum size any particular local LMDB store file is allowed to grow to.
+// This is the default maximum size any particular local LMDB store file is allowed to grow to.
 // It doesn't reflect space allocated on disk, or RAM allocated (it may be reflected in VIRT but
 // not RSS). There is no practical upper bound on this number, so we set it ridiculously high.
 const MAX_LOCAL_STORE_SIZE_BYTES: usize = 1024 * 1024 * 1024 * 1024 / 10;
 
+// The size that the map of each local LMDB store file starts at: maps are grown (by doubling)
+// when they fill, up to the maximum size. On some platforms (and under some limits on virtual
+// memory) the whole map must be reservable up front, so it is not reserved until needed.
+const INITIAL_LOCAL_STORE_SIZE_BYTES: usize = 64 * 1024 * 1024;
+
+// The default number of LMDB store files that the local store is split into (see
+// `LocalStoreLimits`), which is the number that its directory layout was originally designed for.
+const DEFAULT_LOCAL_STORE_SHARD_COUNT: usize = 16;
+
 // This is the target number of bytes which should be present in all combined LMDB store files
 // after garbage collection. We almost certainly want to make this configurable.
 const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 
+// This is the maximum number of Digests which a remote store will remember having confirmed the
+// presence of, to avoid asking about them again. At ~40 bytes per Digest this bounds the memory
+// used at a few tens of megabytes.
+const REMOTE_STORE_KNOWN_DIGESTS_CAPACITY: usize = 1024 * 1024;
+
+// How long a remote store trusts a confirmation that the server has a Digest: servers may evict
+// blobs at any time, so a confirmation is only a hint that an upload can be skipped.
+const REMOTE_STORE_KNOWN_DIGESTS_TTL_SECS: u64 = 10 * 60;
+
+// Files of at most this size are fetched from a remote store in batches, rather than individually.
+const REMOTE_STORE_BATCH_READ_MAX_BLOB_BYTES: usize = 64 * 1024;
+
+// The combined size of the files fetched in one batch. The API allows up to 4MiB, but we leave
+// room for the rest of the response inside gRPC's default maximum message size.
+const REMOTE_STORE_BATCH_READ_MAX_BYTES: usize = 2 * 1024 * 1024;
+
+// The total size of the serialized Directory protos which a Store keeps parsed in memory.
+const DIRECTORY_CACHE_CAPACITY_BYTES: usize = 64 * 1024 * 1024;
+
+///
+/// Timeouts for the RPCs that a Store makes to a remote CAS.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct RemoteTimeouts {
+  // For calls with small requests and responses, such as FindMissingBlobs.
+  pub metadata: Duration,
+  // For ByteStream reads and writes, which may transfer large blobs.
+  pub transfer: Duration,
+}
+
+///
+/// Limits on the transfers (ie, ByteStream reads and writes) that a Store makes to a remote CAS.
+/// None means unlimited.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RemoteTransferLimits {
+  // The maximum number of blobs which may be uploaded or downloaded at once.
+  pub concurrency: Option<usize>,
+  // The maximum aggregate rate at which blob content may be uploaded and downloaded.
+  pub bytes_per_second: Option<usize>,
+}
+
+///
+/// When blobs which are stored locally (such as captured Snapshots and the outputs of local
+/// processes) are written through to the remote CAS, if there is one.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WriteThrough {
+  // Blobs are uploaded (in the background) as soon as they are produced.
+  Eager,
+  // Blobs are uploaded only when the remote needs them: ie, as the inputs of remote executions.
+  Lazy,
+  // Blobs are never uploaded: the remote is used as a read-only cache.
+  Never,
+}
+
+impl WriteThrough {
+  pub fn create(write_through: &str) -> Result<WriteThrough, String> {
+    match write_through {
+      "eager" => Ok(WriteThrough::Eager),
+      "lazy" => Ok(WriteThrough::Lazy),
+      "never" => Ok(WriteThrough::Never),
+      _ => Err(format!("Unrecognized write through policy: {}.", write_through)),
+    }
+  }
+}
+
+///
+/// Limits on the local store, which is split into `shard_count` LMDB store files ("shards") by the
+/// prefix of the fingerprints that they contain. Each shard can have at most one concurrent
+/// writer, so more shards allow for more parallel writes. The map of each shard grows as it fills,
+/// up to `max_shard_bytes`.
+///
+/// Shards are kept in a directory per shard count (other than for the default count, which keeps
+/// the original layout), so changing the number of shards starts an empty local store.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LocalStoreLimits {
+  pub shard_count: usize,
+  pub max_shard_bytes: usize,
+}
+
+impl LocalStoreLimits {
+  pub fn create(shard_count: usize, max_shard_bytes: usize) -> Result<LocalStoreLimits, String> {
+    if shard_count == 0 || shard_count > 256 || !shard_count.is_power_of_two() {
+      return Err(format!(
+        "The number of local store shards must be a power of two between 1 and 256, but was {}.",
+        shard_count
+      ));
+    }
+    if max_shard_bytes == 0 {
+      return Err("The maximum size of a local store shard must be positive.".to_owned());
+    }
+    Ok(LocalStoreLimits {
+      shard_count,
+      max_shard_bytes,
+    })
+  }
+}
+
+impl Default for LocalStoreLimits {
+  fn default() -> LocalStoreLimits {
+    LocalStoreLimits {
+      shard_count: DEFAULT_LOCAL_STORE_SHARD_COUNT,
+      max_shard_bytes: MAX_LOCAL_STORE_SIZE_BYTES,
+    }
+  }
+}
+
+///
+/// The number of uploads started by `Store::write_through` which have not yet completed.
+///
+#[derive(Default)]
+struct PendingUploads {
+  count: Mutex<usize>,
+  completed: Condvar,
+}
+
+///
+/// Marks an upload as pending until it is dropped: either because it completed, or because the
+/// pool that was running it was reset.
+///
+struct PendingUpload(Arc<PendingUploads>);
+
+impl PendingUpload {
+  fn new(pending_uploads: &Arc<PendingUploads>) -> PendingUpload {
+    *pending_uploads.count.lock().unwrap() += 1;
+    PendingUpload(pending_uploads.clone())
+  }
+}
+
+impl Drop for PendingUpload {
+  fn drop(&mut self) {
+    *self.0.count.lock().unwrap() -= 1;
+    self.0.completed.notify_all();
+  }
+}
+
 ///
 /// A content-addressed store of file contents, and Directories.
 ///
@@ -41,6 +194,27 @@ const LOCAL_STORE_GC_TARGET_BYTES: usize = 4 * 1024 * 1024 * 1024;
 pub struct Store {
   local: local::ByteStore,
   remote: Option<remote::ByteStore>,
+  pool: Arc<ResettablePool>,
+  // Files that were materialized, which may be cloned to materialize the same content again.
+  clone_sources: Arc<CloneSources>,
+  // Directories which were recently loaded or recorded.
+  directory_cache: Arc<DirectoryCache>,
+  write_through: WriteThrough,
+  pending_uploads: Arc<PendingUploads>,
+  external_copies: Arc<Mutex<Vec<Arc<ExternalCopies>>>>,
+}
+
+///
+/// Copies of stored content which are kept outside of the Store (such as Directories which are
+/// materialized once to be shared by many processes), and which are garbage collected along with
+/// it: see `Store::register_external_copies`.
+///
+pub trait ExternalCopies: Send + Sync {
+  ///
+  /// Removes those copies whose content is no longer stored locally, after the given Store has
+  /// been garbage collected.
+  ///
+  fn garbage_collect(&self, store: &Store) -> Result<(), String>;
 }
 
 // Note that Store doesn't implement ByteStore because it operates at a higher level of abstraction,
@@ -53,8 +227,14 @@ impl Store {
   ///
   pub fn local_only<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<Store, String> {
     Ok(Store {
-      local: local::ByteStore::new(path, pool)?,
+      local: local::ByteStore::new(path, pool.clone())?,
       remote: None,
+      pool: pool,
+      clone_sources: Arc::new(CloneSources::new()),
+      directory_cache: Arc::new(DirectoryCache::new(DIRECTORY_CACHE_CAPACITY_BYTES)),
+      write_through: WriteThrough::Lazy,
+      pending_uploads: Arc::new(PendingUploads::default()),
+      external_copies: Arc::new(Mutex::new(Vec::new())),
     })
   }
 
@@ -68,19 +248,102 @@ impl Store {
     cas_address: String,
     thread_count: usize,
     chunk_size_bytes: usize,
-    timeout: Duration,
+    timeouts: RemoteTimeouts,
   ) -> Result<Store, String> {
     Ok(Store {
-      local: local::ByteStore::new(path, pool)?,
+      local: local::ByteStore::new(path, pool.clone())?,
       remote: Some(remote::ByteStore::new(
         cas_address,
         thread_count,
         chunk_size_bytes,
-        timeout,
+        timeouts,
+        REMOTE_STORE_KNOWN_DIGESTS_CAPACITY,
       )),
+      pool: pool,
+      clone_sources: Arc::new(CloneSources::new()),
+      directory_cache: Arc::new(DirectoryCache::new(DIRECTORY_CACHE_CAPACITY_BYTES)),
+      write_through: WriteThrough::Lazy,
+      pending_uploads: Arc::new(PendingUploads::default()),
+      external_copies: Arc::new(Mutex::new(Vec::new())),
     })
   }
 
+  ///
+  /// Configures the number and size of the files that the local store is split into: see
+  /// `LocalStoreLimits`. Should be called before this Store has been used.
+  ///
+  pub fn with_local_limits(mut self, limits: LocalStoreLimits) -> Store {
+    self.local = self.local.with_limits(limits);
+    self
+  }
+
+  ///
+  /// Configures whether files are materialized as hardlinks to files that the same Digest was
+  /// previously materialized to (see `CloneSources`). Should be called before this Store has
+  /// materialized any files, because it forgets the files that were previously materialized.
+  ///
+  pub fn with_hardlinks(mut self, hardlink: bool) -> Store {
+    self.clone_sources = Arc::new(if hardlink {
+      CloneSources::new_hardlinking()
+    } else {
+      CloneSources::new()
+    });
+    self
+  }
+
+  ///
+  /// Configures when locally stored blobs are uploaded to the remote (if any): see `WriteThrough`.
+  ///
+  pub fn with_write_through(mut self, write_through: WriteThrough) -> Store {
+    self.write_through = write_through;
+    self
+  }
+
+  ///
+  /// Configures limits on the concurrency and bandwidth of transfers to and from the remote (if
+  /// any): see `RemoteTransferLimits`.
+  ///
+  pub fn with_remote_transfer_limits(mut self, limits: RemoteTransferLimits) -> Store {
+    self.remote = self.remote.map(|remote| remote.with_transfer_limits(limits));
+    self
+  }
+
+  ///
+  /// Returns a clone of this Store whose RPCs to the remote CAS (if any) will not wait beyond the
+  /// given deadline, regardless of their timeouts. Other clones of the Store are unaffected, so
+  /// each Session can have its own deadline.
+  ///
+  pub fn with_remote_deadline(&self, deadline: Option<Instant>) -> Store {
+    let mut store = self.clone();
+    store.remote = store.remote.map(|remote| remote.with_deadline(deadline));
+    store
+  }
+
+  ///
+  /// Returns estimates of the bytes of heap memory held by this Store, by purpose. The local store
+  /// is not included, because LMDB maps its databases into memory rather than allocating.
+  ///
+  pub fn heap_usage(&self) -> Vec<(&'static str, usize)> {
+    let mut usage = vec![
+      ("clone_sources", self.clone_sources.heap_size()),
+      ("directory_cache", self.directory_cache.heap_size()),
+    ];
+    if let Some(ref remote) = self.remote {
+      usage.push(("remote_buffers", remote.buffered_bytes()));
+      usage.push(("remote_known_digests", remote.known_digests_heap_size()));
+    }
+    usage
+  }
+
+  ///
+  /// Releases the in-memory caches of the Store that can be repopulated from the local store (such
+  /// as parsed Directories), for use when the process is idle. Returns an estimate of the bytes of
+  /// heap memory released.
+  ///
+  pub fn trim_memory(&self) -> usize {
+    self.directory_cache.clear()
+  }
+
   ///
   /// LMDB Environments aren't safe to be re-used after forking, so we need to drop them before
   /// forking and re-create them afterwards.
@@ -89,13 +352,54 @@ impl Store {
   /// processes run using the same daemon, one takes out some kind of lock which the other cannot
   /// ever acquire, so lmdb returns EAGAIN whenever a transaction is created in the second process.
   ///
-  pub fn reset_prefork(&self) {
-    self.local.reset_prefork();
+  pub fn pre_fork(&self) {
+    self.local.pre_fork();
     if let Some(ref remote) = self.remote {
-      remote.reset_threadpool();
+      remote.pre_fork();
     }
   }
 
+  ///
+  /// Closes the channel to the remote store (if any), before the process exits.
+  ///
+  pub fn close_remote(&self) {
+    if let Some(ref remote) = self.remote {
+      remote.pre_fork();
+    }
+  }
+
+  ///
+  /// Called in a forked child process immediately after the fork, to discard any LMDB Environments
+  /// or gRPC clients which were re-created by another thread of the parent after `pre_fork`: see
+  /// `Resettable::reset_in_child`.
+  ///
+  pub fn post_fork_in_child(&self) -> Result<(), String> {
+    self.local.post_fork_in_child()?;
+    if let Some(ref remote) = self.remote {
+      remote.post_fork_in_child()?;
+    }
+    // Uploads which were pending in the parent will not complete in the child.
+    match self.pending_uploads.count.try_lock() {
+      Ok(mut count) => *count = 0,
+      Err(TryLockError::Poisoned(e)) => *e.into_inner() = 0,
+      Err(TryLockError::WouldBlock) => {
+        return Err(
+          "The pending uploads of the Store were locked by another thread at the time of a fork."
+            .to_owned(),
+        )
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Flushes the writes to the local store to disk. Writes are not otherwise synced as they are
+  /// committed, so this should be called before the process exits to ensure their durability.
+  ///
+  pub fn sync(&self) -> Result<(), String> {
+    self.local.sync()
+  }
+
   pub fn store_file_bytes(&self, bytes: Bytes, initial_lease: bool) -> BoxFuture<Digest, String> {
     let len = bytes.len();
     self
@@ -105,6 +409,14 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Stores the content of the given file (from its start) as a File, streaming it into the local
+  /// store rather than reading it into memory.
+  ///
+  pub fn store_file_from(&self, file: fs::File, initial_lease: bool) -> BoxFuture<Digest, String> {
+    self.local.store_file_from(file, initial_lease)
+  }
+
   ///
   /// Loads the bytes of the file with the passed fingerprint, and returns the result of applying f
   /// to that value.
@@ -137,6 +449,8 @@ impl Store {
     initial_lease: bool,
   ) -> BoxFuture<Digest, String> {
     let local = self.local.clone();
+    let directory_cache = self.directory_cache.clone();
+    let cached = Arc::new(directory.clone());
     future::result(
       directory
         .write_to_bytes()
@@ -145,7 +459,11 @@ impl Store {
       let len = bytes.len();
       local
         .store_bytes(EntryType::Directory, Bytes::from(bytes), initial_lease)
-        .map(move |fingerprint| Digest(fingerprint, len))
+        .map(move |fingerprint| {
+          let digest = Digest(fingerprint, len);
+          directory_cache.insert(digest, cached);
+          digest
+        })
     })
       .to_boxed()
   }
@@ -159,35 +477,62 @@ impl Store {
     &self,
     digest: Digest,
   ) -> BoxFuture<Option<bazel_protos::remote_execution::Directory>, String> {
-    self.load_bytes_with(
-      EntryType::Directory,
-      digest,
-      // Trust that locally stored values were canonical when they were written into the CAS,
-      // don't bother to check this, as it's slightly expensive.
-      move |bytes: Bytes| {
-        let mut directory = bazel_protos::remote_execution::Directory::new();
-        directory.merge_from_bytes(&bytes).map_err(|e| {
-          format!(
-            "LMDB corruption: Directory bytes for {:?} were not valid: {:?}",
-            digest, e
-          )
-        })?;
-        Ok(directory)
-      },
-      // Eagerly verify that CAS-returned Directories are canonical, so that we don't write them
-      // into our local store.
-      move |bytes: Bytes| {
-        let mut directory = bazel_protos::remote_execution::Directory::new();
-        directory.merge_from_bytes(&bytes).map_err(|e| {
-          format!(
-            "CAS returned Directory proto for {:?} which was not valid: {:?}",
-            digest, e
-          )
-        })?;
-        bazel_protos::verify_directory_canonical(&directory)?;
-        Ok(directory)
-      },
-    )
+    self
+      .load_directory_shared(digest)
+      .map(|maybe_directory| maybe_directory.map(|directory| (*directory).clone()))
+      .to_boxed()
+  }
+
+  ///
+  /// As `load_directory`, but returns a Directory which may be shared with other callers, and
+  /// which is thus cheap to load repeatedly: see `DirectoryCache`.
+  ///
+  pub fn load_directory_shared(
+    &self,
+    digest: Digest,
+  ) -> BoxFuture<Option<Arc<bazel_protos::remote_execution::Directory>>, String> {
+    if let Some(directory) = self.directory_cache.get(&digest) {
+      return future::ok(Some(directory)).to_boxed();
+    }
+    let directory_cache = self.directory_cache.clone();
+    self
+      .load_bytes_with(
+        EntryType::Directory,
+        digest,
+        // Trust that locally stored values were canonical when they were written into the CAS,
+        // don't bother to check this, as it's slightly expensive.
+        move |bytes: Bytes| {
+          let mut directory = bazel_protos::remote_execution::Directory::new();
+          directory.merge_from_bytes(&bytes).map_err(|e| {
+            format!(
+              "LMDB corruption: Directory bytes for {:?} were not valid: {:?}",
+              digest, e
+            )
+          })?;
+          Ok(directory)
+        },
+        // Eagerly verify that CAS-returned Directories are canonical, so that we don't write them
+        // into our local store.
+        move |bytes: Bytes| {
+          let mut directory = bazel_protos::remote_execution::Directory::new();
+          directory.merge_from_bytes(&bytes).map_err(|e| {
+            format!(
+              "CAS returned Directory proto for {:?} which was not valid: {:?}",
+              digest, e
+            )
+          })?;
+          bazel_protos::verify_directory_canonical(&directory)?;
+          Ok(directory)
+        },
+      )
+      .map(move |maybe_directory| {
+        maybe_directory.map(|directory| {
+          let directory = Arc::new(directory);
+          directory_cache.insert(digest, directory.clone());
+          directory
+        })
+      })
+      .to_boxed()
   }
 
   fn load_bytes_with<
@@ -239,6 +584,70 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Uploads the given blobs (recursively) to the remote in the background if the WriteThrough
+  /// policy is Eager, and otherwise does nothing: see `WriteThrough`. Failures are logged rather
+  /// than returned, because the blobs are already stored locally, and will be uploaded again if
+  /// they are ever needed remotely. See `flush_uploads` to wait for the uploads to complete.
+  ///
+  pub fn write_through(&self, digests: Vec<Digest>) {
+    match (self.write_through, &self.remote) {
+      (WriteThrough::Eager, &Some(_)) => {}
+      _ => return,
+    }
+    let pending_upload = PendingUpload::new(&self.pending_uploads);
+    let upload = self
+      .ensure_remote_has_recursive(digests)
+      .then(move |res| {
+        if let Err(e) = res {
+          warn!("Failed to write through to the remote store: {}", e);
+        }
+        drop(pending_upload);
+        Ok(())
+      });
+    self.pool.spawn_detached(upload);
+  }
+
+  ///
+  /// Waits for the uploads started by `write_through` to complete, until the given deadline (if
+  /// any). Fails if some uploads were still pending at the deadline.
+  ///
+  pub fn flush_uploads(&self, deadline: Option<Instant>) -> Result<(), String> {
+    let mut count = self.pending_uploads.count.lock().unwrap();
+    while *count > 0 {
+      count = match deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          if now >= deadline {
+            return Err(format!(
+              "Timed out waiting for {} upload(s) to the remote store.",
+              *count
+            ));
+          }
+          self
+            .pending_uploads
+            .completed
+            .wait_timeout(count, deadline - now)
+            .unwrap()
+            .0
+        }
+        None => self.pending_uploads.completed.wait(count).unwrap(),
+      };
+    }
+    Ok(())
+  }
+
+  ///
+  /// Forgets any confirmation from the remote store that it has the given Digests, so that
+  /// `ensure_remote_has_recursive` will check for (and upload) them again. For use when the server
+  /// reports that blobs which it previously had are missing.
+  ///
+  pub fn forget_remote_digests(&self, digests: &[Digest]) {
+    if let Some(ref remote) = self.remote {
+      remote.forget_known_digests(digests);
+    }
+  }
+
   ///
   /// Ensures that the remote ByteStore has a copy of each passed Fingerprint, including any files
   /// contained in any Directories in the list.
@@ -250,38 +659,56 @@ impl Store {
         return future::err("Cannot ensure remote has blobs without a remote".to_owned()).to_boxed()
       }
     };
-
-    let mut expanding_futures = Vec::new();
-
-    let mut expanded_digests = HashMap::new();
-    for digest in digests {
-      match self.local.entry_type(&digest.0) {
-        Ok(Some(EntryType::File)) => {
-          expanded_digests.insert(digest, EntryType::File);
-        }
-        Ok(Some(EntryType::Directory)) => {
-          expanding_futures.push(self.expand_directory(digest));
-        }
-        Ok(None) => {
-          return future::err(format!("Failed to upload digest {:?}: Not found", digest)).to_boxed()
-        }
-        Err(err) => {
-          return future::err(format!("Failed to upload digest {:?}: {:?}", digest, err)).to_boxed()
-        }
-      };
+    if self.write_through == WriteThrough::Never {
+      return future::err(format!(
+        "Cannot upload {} digest(s): the remote store is configured to be read-only",
+        digests.len()
+      )).to_boxed();
     }
 
+    let entry_type_futures = digests
+      .into_iter()
+      .map(|digest| {
+        self
+          .local
+          .entry_type(&digest.0)
+          .map_err(move |err| format!("Failed to upload digest {:?}: {:?}", digest, err))
+          .map(move |entry_type| (digest, entry_type))
+      })
+      .collect::<Vec<_>>();
+
+    let store = self.clone();
     let local = self.local.clone();
     let remote = remote.clone();
     let remote2 = remote.clone();
-    future::join_all(expanding_futures)
-      .map(move |futures| {
-        for mut digests in futures {
-          for (digest, entry_type) in digests.drain() {
-            expanded_digests.insert(digest, entry_type);
-          }
+    future::join_all(entry_type_futures)
+      .and_then(move |entry_types| {
+        let mut expanding_futures = Vec::new();
+        let mut expanded_digests = HashMap::new();
+        for (digest, entry_type) in entry_types {
+          match entry_type {
+            Some(EntryType::File) => {
+              expanded_digests.insert(digest, EntryType::File);
+            }
+            Some(EntryType::Directory) => {
+              expanding_futures.push(store.expand_directory(digest));
+            }
+            None => {
+              return future::err(format!("Failed to upload digest {:?}: Not found", digest))
+                .to_boxed()
+            }
+          };
         }
-        expanded_digests
+        future::join_all(expanding_futures)
+          .map(move |futures| {
+            for mut digests in futures {
+              for (digest, entry_type) in digests.drain() {
+                expanded_digests.insert(digest, entry_type);
+              }
+            }
+            expanded_digests
+          })
+          .to_boxed()
       })
       .and_then(move |digests| {
         if Store::upload_is_faster_than_checking_whether_to_upload(&digests) {
@@ -315,24 +742,132 @@ impl Store {
       .to_boxed()
   }
 
-  pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(&self, digests: Ds) -> Result<(), String> {
-    self.local.lease_all(digests)
+  ///
+  /// Leases the given digests, and (for Directories) everything that they transitively contain,
+  /// for the default lease duration. Digests which are not (yet) stored locally are leased as
+  /// files.
+  ///
+  pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(
+    &self,
+    digests: Ds,
+  ) -> BoxFuture<(), String> {
+    let local = self.local.clone();
+    self
+      .expand_local_digests(digests.cloned().collect(), true)
+      .and_then(move |entries| local.lease_all(entries.keys()))
+      .to_boxed()
+  }
+
+  ///
+  /// Pins the given digests, and (for Directories) everything that they transitively contain,
+  /// for the given duration, during which garbage collection will not remove them.
+  ///
+  /// Unlike `lease_all`, this is intended for digests that are referenced from outside of the
+  /// engine, and which must be pinned until some known time. Pins are counted separately from
+  /// leases, so that each pin holds until it expires or is released by `release_recursive`,
+  /// regardless of any other pins or leases of the same content.
+  ///
+  pub fn lease_recursive(&self, digests: Vec<Digest>, duration: Duration) -> BoxFuture<(), String> {
+    let local = self.local.clone();
+    self
+      .expand_local_digests(digests, false)
+      .and_then(move |entries| local.pin_entries_for(entries, duration))
+      .to_boxed()
+  }
+
+  ///
+  /// Releases one pin (taken by `lease_recursive`) of each of the given digests, and (for
+  /// Directories) of everything that they transitively contain. Once all of its pins have been
+  /// released (and any lease has expired), content may be garbage collected.
+  ///
+  pub fn release_recursive(&self, digests: Vec<Digest>) -> BoxFuture<(), String> {
+    let local = self.local.clone();
+    self
+      .expand_local_digests(digests, false)
+      .and_then(move |entries| local.unpin_entries(entries))
+      .to_boxed()
+  }
+
+  ///
+  /// Expands the given digests into everything that they transitively contain. Digests which are
+  /// not stored locally are an error, unless `unknown_are_files` is set.
+  ///
+  fn expand_local_digests(
+    &self,
+    digests: Vec<Digest>,
+    unknown_are_files: bool,
+  ) -> BoxFuture<HashMap<Digest, EntryType>, String> {
+    let store = self.clone();
+    future::join_all(
+      digests
+        .into_iter()
+        .map(move |digest| {
+          let store = store.clone();
+          store
+            .local
+            .entry_type(&digest.0)
+            .and_then(move |maybe_entry_type| match maybe_entry_type {
+              Some(EntryType::Directory) => store.expand_directory(digest),
+              Some(EntryType::File) => {
+                let mut entries = HashMap::new();
+                entries.insert(digest, EntryType::File);
+                future::ok(entries).to_boxed()
+              }
+              None if unknown_are_files => {
+                let mut entries = HashMap::new();
+                entries.insert(digest, EntryType::File);
+                future::ok(entries).to_boxed()
+              }
+              None => future::err(format!("Unknown digest: {:?}", digest)).to_boxed(),
+            })
+        })
+        .collect::<Vec<_>>(),
+    ).map(|all_entries| {
+      all_entries
+        .into_iter()
+        .flat_map(|entries| entries.into_iter())
+        .collect()
+    })
+      .to_boxed()
   }
 
   pub fn garbage_collect(&self) -> Result<(), String> {
     let target = LOCAL_STORE_GC_TARGET_BYTES;
-    match self.local.shrink(target) {
-      Ok(size) => {
-        if size > target {
-          return Err(format!(
-            "Garbage collection attempted to target {} bytes but could only shrink to {} bytes",
-            target, size
-          ));
-        }
-      }
-      Err(err) => return Err(format!("Garbage collection failed: {:?}", err)),
+    let shrunk = match self.local.shrink(target) {
+      Ok(size) if size > target => Err(format!(
+        "Garbage collection attempted to target {} bytes but could only shrink to {} bytes",
+        target, size
+      )),
+      Ok(_) => Ok(()),
+      Err(err) => Err(format!("Garbage collection failed: {:?}", err)),
     };
-    Ok(())
+    // Copies are collected even if the store could not shrink to its target, because some of
+    // their content may still have been removed.
+    let external_copies = self.external_copies.lock().unwrap().clone();
+    for copies in external_copies {
+      copies
+        .garbage_collect(self)
+        .map_err(|err| format!("Garbage collection of copies of stored content failed: {}", err))?;
+    }
+    shrunk
+  }
+
+  ///
+  /// Registers copies of stored content which should be garbage collected along with this Store.
+  ///
+  pub fn register_external_copies(&self, copies: Arc<ExternalCopies>) {
+    self.external_copies.lock().unwrap().push(copies);
+  }
+
+  ///
+  /// Whether the given Digest is stored locally (ignoring any remote).
+  ///
+  pub fn contains_locally(&self, digest: Digest) -> BoxFuture<bool, String> {
+    self
+      .local
+      .entry_type(&digest.0)
+      .map(|entry_type| entry_type.is_some())
+      .to_boxed()
   }
 
   ///
@@ -355,6 +890,97 @@ impl Store {
     }
   }
 
+  ///
+  /// Fetches those of the given files which are small and missing locally from the remote (if
+  /// any) in batches, and stores them locally. Other files are left to be fetched individually
+  /// when they are loaded, as are any small files which a batch fails to return.
+  ///
+  fn backfill_small_files(&self, file_digests: Vec<Digest>) -> BoxFuture<(), String> {
+    let remote = match self.remote {
+      Some(ref remote) => remote.clone(),
+      None => return future::ok(()).to_boxed(),
+    };
+    let local = self.local.clone();
+    let entry_type_futures = file_digests
+      .into_iter()
+      .filter(|digest| digest.1 <= REMOTE_STORE_BATCH_READ_MAX_BLOB_BYTES)
+      .map(|digest| {
+        local
+          .entry_type(&digest.0)
+          .map(move |entry_type| (digest, entry_type))
+      })
+      .collect::<Vec<_>>();
+
+    future::join_all(entry_type_futures)
+      .and_then(move |entry_types| {
+        let missing = entry_types
+          .into_iter()
+          .filter(|&(_, entry_type)| entry_type.is_none())
+          .map(|(digest, _)| digest)
+          .collect::<HashSet<_>>();
+
+        let mut batches = vec![];
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+        for digest in missing {
+          if !batch.is_empty() && batch_bytes + digest.1 > REMOTE_STORE_BATCH_READ_MAX_BYTES {
+            batches.push(batch);
+            batch = vec![];
+            batch_bytes = 0;
+          }
+          batch_bytes += digest.1;
+          batch.push(digest);
+        }
+        if !batch.is_empty() {
+          batches.push(batch);
+        }
+
+        future::join_all(
+          batches
+            .into_iter()
+            .map(move |batch| {
+              let local = local.clone();
+              let batch_len = batch.len();
+              remote
+                .load_bytes_batch(batch)
+                .or_else(move |e| {
+                  // The blobs will be read individually instead.
+                  debug!(
+                    "Failed to read a batch of {} blob(s) from the remote store: {}",
+                    batch_len, e
+                  );
+                  Ok::<_, String>(vec![])
+                })
+                .and_then(move |blobs| {
+                  future::join_all(
+                    blobs
+                      .into_iter()
+                      .map(|(digest, bytes)| {
+                        local
+                          .store_bytes(EntryType::File, bytes, true)
+                          .and_then(move |stored_fingerprint| {
+                            if digest.0 == stored_fingerprint {
+                              Ok(())
+                            } else {
+                              Err(format!(
+                                "CAS gave wrong digest: expected {:?}, got {:?}",
+                                digest,
+                                Digest(stored_fingerprint, digest.1)
+                              ))
+                            }
+                          })
+                      })
+                      .collect::<Vec<_>>(),
+                  )
+                })
+            })
+            .collect::<Vec<_>>(),
+        )
+      })
+      .map(|_| ())
+      .to_boxed()
+  }
+
   pub fn expand_directory(&self, digest: Digest) -> BoxFuture<HashMap<Digest, EntryType>, String> {
     let accumulator = Arc::new(Mutex::new(HashMap::new()));
 
@@ -376,7 +1002,7 @@ impl Store {
   ) -> BoxFuture<(), String> {
     let store = self.clone();
     self
-      .load_directory(digest)
+      .load_directory_shared(digest)
       .and_then(move |maybe_directory| match maybe_directory {
         Some(directory) => {
           {
@@ -409,6 +1035,30 @@ impl Store {
     &self,
     destination: PathBuf,
     digest: Digest,
+  ) -> BoxFuture<(), String> {
+    if self.remote.is_none() {
+      return self.materialize_directory_helper(destination, digest);
+    }
+    let store = self.clone();
+    self
+      .expand_directory(digest)
+      .and_then(move |digests| {
+        let file_digests = digests
+          .into_iter()
+          .filter(|&(_, entry_type)| entry_type == EntryType::File)
+          .map(|(digest, _)| digest)
+          .collect();
+        store
+          .backfill_small_files(file_digests)
+          .and_then(move |()| store.materialize_directory_helper(destination, digest))
+      })
+      .to_boxed()
+  }
+
+  fn materialize_directory_helper(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
   ) -> BoxFuture<(), String> {
     match super::safe_create_dir_all(&destination) {
       Ok(()) => {}
@@ -416,7 +1066,7 @@ impl Store {
     };
     let store = self.clone();
     self
-      .load_directory(digest)
+      .load_directory_shared(digest)
       .and_then(move |directory_opt| {
         directory_opt.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
       })
@@ -426,7 +1076,7 @@ impl Store {
           .iter()
           .map(|file_node| {
             let store = store.clone();
-            let path = destination.join(file_node.get_name());
+            let path = destination.join(decode_name(file_node.get_name()));
             let digest = try_future!(file_node.get_digest().into());
             store.materialize_file(path, digest, file_node.is_executable)
           })
@@ -436,9 +1086,9 @@ impl Store {
           .iter()
           .map(|directory_node| {
             let store = store.clone();
-            let path = destination.join(directory_node.get_name());
+            let path = destination.join(decode_name(directory_node.get_name()));
             let digest = try_future!(directory_node.get_digest().into());
-            store.materialize_directory(path, digest)
+            store.materialize_directory_helper(path, digest)
           })
           .collect::<Vec<_>>();
         future::join_all(file_futures)
@@ -448,50 +1098,226 @@ impl Store {
       .to_boxed()
   }
 
+  ///
+  /// Replaces the destination (if it exists) with the Directory with the given Digest, such that
+  /// an interruption never leaves a partially written directory at the destination.
+  ///
+  /// The Directory is materialized into a hidden sibling of the destination, which is synced to
+  /// disk and then swapped into place, after which the previous contents are deleted. Where the
+  /// platform supports exchanging two paths atomically (Linux, on most filesystems), the
+  /// destination always holds either its previous contents or the complete new contents.
+  /// Elsewhere, the previous destination is renamed aside before the new contents are renamed into
+  /// place, so an interruption between the two renames leaves the destination missing (but never
+  /// partially written).
+  ///
+  /// An interrupted replacement may leave hidden siblings behind: those left by processes which
+  /// have since exited are deleted by the next replacement of the same destination.
+  ///
+  pub fn replace_directory(&self, destination: PathBuf, digest: Digest) -> BoxFuture<(), String> {
+    let (parent, name) = match (destination.parent(), destination.file_name()) {
+      (Some(parent), Some(name)) => (parent.to_owned(), name.to_owned()),
+      _ => {
+        return future::err(format!(
+          "Cannot replace {:?}, which does not name a directory",
+          destination
+        )).to_boxed()
+      }
+    };
+    try_future!(super::safe_create_dir_all(&parent));
+    remove_stale_replacement_siblings(&parent, &name);
+    let staging = replacement_sibling(&parent, &name, "new");
+    let retired = replacement_sibling(&parent, &name, "old");
+
+    let pool = self.pool.clone();
+    let staging2 = staging.clone();
+    let staging3 = staging.clone();
+    self
+      .materialize_directory(staging.clone(), digest)
+      .and_then(move |()| {
+        pool.spawn_fn(move || swap_into_place(&staging2, &destination, &retired, &parent))
+      })
+      .or_else(move |e| {
+        // Nothing was moved into place, so the staging directory (if any) is garbage.
+        let _ = fs::remove_dir_all(&staging3);
+        Err(e)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Materializes a file by cloning a file that the same Digest was previously materialized to
+  /// (see `CloneSources`) if possible, and otherwise by writing its bytes, under the global
+  /// `FdBudget`.
+  ///
   fn materialize_file(
     &self,
     destination: PathBuf,
     digest: Digest,
     is_executable: bool,
   ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    FdBudget::global()
+      .with_fds(move || store.materialize_file_helper(destination, digest, is_executable))
+  }
+
+  fn materialize_file_helper(
+    &self,
+    destination: PathBuf,
+    digest: Digest,
+    is_executable: bool,
+  ) -> BoxFuture<(), String> {
+    let store = self.clone();
+    let clone_sources = self.clone_sources.clone();
+    let clone_destination = destination.clone();
     self
-      .load_file_bytes_with(digest, move |bytes| {
-        OpenOptions::new()
-          .create(true)
-          .write(true)
-          .mode(if is_executable { 0o755 } else { 0o644 })
-          .open(&destination)
-          .and_then(|mut f| f.write_all(&bytes))
-          .map_err(|e| format!("Error writing file {:?}: {:?}", destination, e))
+      .pool
+      .spawn_fn(move || -> Result<_, String> {
+        Ok(clone_sources.try_clone(digest, &clone_destination, is_executable))
       })
-      .and_then(move |write_result| match write_result {
-        Some(Ok(())) => Ok(()),
-        Some(Err(e)) => Err(e),
-        None => Err(format!("File with digest {:?} not found", digest)),
+      .and_then(move |cloned| {
+        if cloned {
+          return future::ok(()).to_boxed();
+        }
+        let clone_sources = store.clone_sources.clone();
+        store
+          .load_file_bytes_with(digest, move |bytes| {
+            os::create_mode(OpenOptions::new().create(true).write(true), is_executable)
+              .open(&destination)
+              .and_then(|mut f| f.write_all(&bytes))
+              .map_err(|e| format!("Error writing file {:?}: {:?}", destination, e))
+              .map(|()| clone_sources.record(digest, destination.clone()))
+          })
+          .and_then(move |write_result| match write_result {
+            Some(Ok(())) => Ok(()),
+            Some(Err(e)) => Err(e),
+            None => Err(format!("File with digest {:?} not found", digest)),
+          })
+          .to_boxed()
       })
       .to_boxed()
   }
 
-  // Returns files sorted by their path.
+  // Returns files sorted by their path (see `path_order`).
   pub fn contents_for_directory(
     &self,
     directory: &bazel_protos::remote_execution::Directory,
   ) -> BoxFuture<Vec<FileContent>, String> {
     let accumulator = Arc::new(Mutex::new(HashMap::new()));
-    self
-      .contents_for_directory_helper(directory, PathBuf::new(), accumulator.clone())
-      .map(|()| {
+    let backfilled = if self.remote.is_none() {
+      future::ok(()).to_boxed()
+    } else {
+      let file_digests = try_future!(
+        directory
+          .get_files()
+          .iter()
+          .map(|file_node| file_node.get_digest().into())
+          .collect::<Result<Vec<Digest>, String>>()
+      );
+      let subdir_digests = try_future!(
+        directory
+          .get_directories()
+          .iter()
+          .map(|dir_node| dir_node.get_digest().into())
+          .collect::<Result<Vec<Digest>, String>>()
+      );
+      let store = self.clone();
+      future::join_all(
+        subdir_digests
+          .into_iter()
+          .map(|digest| self.expand_directory(digest))
+          .collect::<Vec<_>>(),
+      ).and_then(move |expanded| {
+        let file_digests = expanded
+          .into_iter()
+          .flat_map(|digests| digests.into_iter())
+          .filter(|&(_, entry_type)| entry_type == EntryType::File)
+          .map(|(digest, _)| digest)
+          .chain(file_digests.into_iter())
+          .collect();
+        store.backfill_small_files(file_digests)
+      })
+        .to_boxed()
+    };
+    let store = self.clone();
+    let directory = directory.clone();
+    let accumulator2 = accumulator.clone();
+    backfilled
+      .and_then(move |()| {
+        store.contents_for_directory_helper(&directory, PathBuf::new(), accumulator2)
+      })
+      .map(move |()| {
         let map = Arc::try_unwrap(accumulator).unwrap().into_inner().unwrap();
         let mut vec: Vec<FileContent> = map
           .into_iter()
           .map(|(path, content)| FileContent { path, content })
           .collect();
-        vec.sort_by(|l, r| l.path.cmp(&r.path));
+        vec.sort_by(|l, r| path_order(&l.path, &r.path));
         vec
       })
       .to_boxed()
   }
 
+  ///
+  /// Returns the path and Digest of each file in the Directory with the given Digest (recursively),
+  /// sorted by path (see `path_order`). Unlike `contents_for_directory`, does not load the contents
+  /// of the files.
+  ///
+  pub fn file_digests_for_directory(
+    &self,
+    digest: Digest,
+  ) -> BoxFuture<Vec<(PathBuf, Digest)>, String> {
+    self
+      .file_digests_for_directory_helper(digest, PathBuf::new())
+      .map(|mut file_digests| {
+        file_digests.sort_by(|&(ref l, _), &(ref r, _)| path_order(l, r));
+        file_digests
+      })
+      .to_boxed()
+  }
+
+  fn file_digests_for_directory_helper(
+    &self,
+    digest: Digest,
+    path_so_far: PathBuf,
+  ) -> BoxFuture<Vec<(PathBuf, Digest)>, String> {
+    let store = self.clone();
+    self
+      .load_directory_shared(digest)
+      .and_then(move |maybe_dir| {
+        maybe_dir.ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+      })
+      .and_then(move |directory| {
+        let file_digests = try_future!(
+          directory
+            .get_files()
+            .iter()
+            .map(|file_node| {
+              let digest: Result<Digest, String> = file_node.get_digest().into();
+              digest.map(|digest| (path_so_far.join(decode_name(file_node.get_name())), digest))
+            })
+            .collect::<Result<Vec<_>, _>>()
+        );
+        let dir_futures = directory
+          .get_directories()
+          .iter()
+          .map(|dir_node| {
+            let digest = try_future!(dir_node.get_digest().into());
+            let path = path_so_far.join(decode_name(dir_node.get_name()));
+            store.file_digests_for_directory_helper(digest, path)
+          })
+          .collect::<Vec<_>>();
+        future::join_all(dir_futures)
+          .map(move |dir_file_digests| {
+            file_digests
+              .into_iter()
+              .chain(dir_file_digests.into_iter().flat_map(|f| f))
+              .collect()
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
   // Assumes that all fingerprints it encounters are valid.
   fn contents_for_directory_helper(
     &self,
@@ -507,7 +1333,7 @@ impl Store {
         .get_files()
         .iter()
         .map(move |file_node| {
-          let path = path_so_far_copy.join(file_node.get_name());
+          let path = path_so_far_copy.join(decode_name(file_node.get_name()));
           let contents_wrapped_copy = contents_wrapped_copy.clone();
           store_copy
             .load_file_bytes_with(try_future!(file_node.get_digest().into()), |b| b)
@@ -530,11 +1356,11 @@ impl Store {
         .into_iter()
         .map(move |dir_node| {
           let digest = try_future!(dir_node.get_digest().into());
-          let path = path_so_far.join(dir_node.get_name());
+          let path = path_so_far.join(decode_name(dir_node.get_name()));
           let store = store.clone();
           let contents_wrapped = contents_wrapped.clone();
           store
-            .load_directory(digest)
+            .load_directory_shared(digest)
             .and_then(move |maybe_dir| {
               maybe_dir
                 .ok_or_else(|| format!("Could not find sub-directory with digest {:?}", digest))
@@ -548,6 +1374,132 @@ impl Store {
   }
 }
 
+///
+/// A unique hidden path beside the given name in the given parent directory, for use during
+/// `Store::replace_directory`.
+///
+fn replacement_sibling(parent: &Path, name: &OsStr, purpose: &str) -> PathBuf {
+  lazy_static! {
+    static ref REPLACEMENTS: AtomicUsize = AtomicUsize::new(0);
+  }
+  let mut sibling_name = replacement_sibling_prefix(name);
+  sibling_name.push(format!(
+    "{}-{}-{}",
+    purpose,
+    process::id(),
+    REPLACEMENTS.fetch_add(1, Ordering::SeqCst)
+  ));
+  parent.join(sibling_name)
+}
+
+fn replacement_sibling_prefix(name: &OsStr) -> OsString {
+  let mut prefix = OsString::from(".");
+  prefix.push(name);
+  prefix.push(".pants-");
+  prefix
+}
+
+///
+/// Deletes the siblings (see `replacement_sibling`) left behind by replacements of the named
+/// directory which were interrupted, in processes which have since exited. Failures are ignored:
+/// siblings which can't be deleted now will be retried by the next replacement.
+///
+fn remove_stale_replacement_siblings(parent: &Path, name: &OsStr) {
+  let prefix = replacement_sibling_prefix(name);
+  let prefix = os::os_str_bytes(&prefix);
+  let entries = match fs::read_dir(parent) {
+    Ok(entries) => entries,
+    Err(_) => return,
+  };
+  for entry in entries.filter_map(|entry| entry.ok()) {
+    let sibling_name = entry.file_name();
+    let sibling_name = os::os_str_bytes(&sibling_name);
+    if !sibling_name.starts_with(&prefix) {
+      continue;
+    }
+    // The suffix is `{purpose}-{pid}-{counter}`.
+    let pid = String::from_utf8_lossy(&sibling_name[prefix.len()..])
+      .split('-')
+      .nth(1)
+      .and_then(|pid| pid.parse::<u32>().ok());
+    match pid {
+      Some(pid) if pid != process::id() && !os::process_may_be_running(pid) => {
+        debug!("Removing {:?}, left by an interrupted replacement", entry.path());
+        let _ = fs::remove_dir_all(entry.path());
+      }
+      _ => {}
+    }
+  }
+}
+
+///
+/// Syncs the staged directory to disk, and then swaps it into place at the destination, deleting
+/// whatever was previously at the destination.
+///
+fn swap_into_place(
+  staging: &Path,
+  destination: &Path,
+  retired: &Path,
+  parent: &Path,
+) -> Result<(), String> {
+  sync_tree(staging).map_err(|e| format!("Failed to sync {:?}: {}", staging, e))?;
+  if fs::symlink_metadata(destination).is_ok() {
+    let exchanged = os::exchange(staging, destination).map_err(|e| {
+      format!(
+        "Failed to move {:?} into place at {:?}: {}",
+        staging, destination, e
+      )
+    })?;
+    if exchanged {
+      // The staging path now holds the previous contents.
+      sync_dir(parent).map_err(|e| format!("Failed to sync {:?}: {}", parent, e))?;
+      return fs::remove_dir_all(staging)
+        .map_err(|e| format!("Failed to delete the previous {:?}: {}", destination, e));
+    }
+  }
+  let replaced = match fs::rename(destination, retired) {
+    Ok(()) => true,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+    Err(e) => return Err(format!("Failed to move {:?} aside: {}", destination, e)),
+  };
+  if let Err(e) = fs::rename(staging, destination) {
+    if replaced {
+      // Restore the previous contents, so that the destination is not left missing.
+      let _ = fs::rename(retired, destination);
+    }
+    return Err(format!(
+      "Failed to move {:?} into place at {:?}: {}",
+      staging, destination, e
+    ));
+  }
+  sync_dir(parent).map_err(|e| format!("Failed to sync {:?}: {}", parent, e))?;
+  if replaced {
+    fs::remove_dir_all(retired)
+      .map_err(|e| format!("Failed to delete the previous {:?}: {}", destination, e))?;
+  }
+  Ok(())
+}
+
+///
+/// Syncs the files and directories beneath the given directory (and the directory itself) to disk.
+///
+fn sync_tree(dir: &Path) -> Result<(), io::Error> {
+  for entry in fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_type()?.is_dir() {
+      sync_tree(&entry.path())?;
+    } else {
+      fs::File::open(entry.path())?.sync_all()?;
+    }
+  }
+  sync_dir(dir)
+}
+
+fn sync_dir(dir: &Path) -> Result<(), io::Error> {
+  // Renames and new entries are only durable once their directory is synced as well.
+  fs::File::open(dir)?.sync_all()
+}
+
 // Only public for testing.
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub enum EntryType {
@@ -564,21 +1516,27 @@ mod local {
   use digest::{Digest as DigestTrait, FixedOutput};
   use futures::future;
   use hashing::{Digest, Fingerprint};
-  use lmdb::Error::{KeyExist, NotFound};
+  use lmdb::Error::{KeyExist, MapFull, MapResized, NotFound};
   use lmdb::{
-    self, Cursor, Database, DatabaseFlags, Environment, RwTransaction, Transaction, WriteFlags,
-    NO_OVERWRITE, NO_SYNC, NO_TLS,
+    self, Cursor, Database, DatabaseFlags, Environment, RoTransaction, RwTransaction, Transaction,
+    WriteFlags, NO_OVERWRITE, NO_SYNC, NO_TLS,
   };
+  use lmdb_sys;
   use resettable::Resettable;
   use sha2::Sha256;
+  use std::cmp;
   use std::collections::{BinaryHeap, HashMap};
-  use std::fmt;
-  use std::path::Path;
-  use std::sync::Arc;
+  use std::cell::RefCell;
+  use std::fs::File;
+  use std::io::{self, Read, Seek, SeekFrom};
+  use std::mem;
+  use std::os::raw::c_int;
+  use std::path::{Path, PathBuf};
+  use std::sync::{Arc, RwLock};
   use std::time;
 
   use super::super::EMPTY_FINGERPRINT;
-  use super::MAX_LOCAL_STORE_SIZE_BYTES;
+  use super::{LocalStoreLimits, DEFAULT_LOCAL_STORE_SHARD_COUNT, INITIAL_LOCAL_STORE_SIZE_BYTES};
   use pool::ResettablePool;
 
   #[derive(Clone)]
@@ -587,6 +1545,7 @@ mod local {
   }
 
   struct InnerStore {
+    root: PathBuf,
     pool: Arc<ResettablePool>,
     // Store directories separately from files because:
     //  1. They may have different lifetimes.
@@ -597,73 +1556,213 @@ mod local {
 
   impl ByteStore {
     pub fn new<P: AsRef<Path>>(path: P, pool: Arc<ResettablePool>) -> Result<ByteStore, String> {
-      let root = path.as_ref();
+      Ok(ByteStore::new_with_limits(
+        path.as_ref(),
+        pool,
+        LocalStoreLimits::default(),
+      ))
+    }
+
+    fn new_with_limits(
+      root: &Path,
+      pool: Arc<ResettablePool>,
+      limits: LocalStoreLimits,
+    ) -> ByteStore {
       let files_root = root.join("files");
       let directories_root = root.join("directories");
-      Ok(ByteStore {
+      ByteStore {
         inner: Arc::new(InnerStore {
+          root: root.to_owned(),
           pool: pool,
-          file_dbs: Resettable::new(move || ShardedLmdb::new(&files_root).map(Arc::new)),
-          directory_dbs: Resettable::new(move || ShardedLmdb::new(&directories_root).map(Arc::new)),
+          file_dbs: Resettable::new(move || {
+            ShardedLmdb::new(&files_root, INITIAL_LOCAL_STORE_SIZE_BYTES, limits).map(Arc::new)
+          }),
+          directory_dbs: Resettable::new(move || {
+            ShardedLmdb::new(&directories_root, INITIAL_LOCAL_STORE_SIZE_BYTES, limits)
+              .map(Arc::new)
+          }),
         }),
-      })
+      }
     }
 
-    pub fn reset_prefork(&self) {
+    ///
+    /// Returns a ByteStore at the same path with the given limits. The LMDB environments are opened
+    /// lazily, so this should be called before this ByteStore has been used.
+    ///
+    pub fn with_limits(self, limits: LocalStoreLimits) -> ByteStore {
+      ByteStore::new_with_limits(&self.inner.root, self.inner.pool.clone(), limits)
+    }
+
+    pub fn pre_fork(&self) {
       self.inner.file_dbs.reset();
       self.inner.directory_dbs.reset();
     }
 
-    // Note: This performs IO on the calling thread. Hopefully the IO is small enough not to matter.
-    pub fn entry_type(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
-      {
-        let (env, directory_database, _) = self.inner.directory_dbs.get()?.get(fingerprint);
-        let txn = env
-          .begin_ro_txn()
-          .map_err(|err| format!("Failed to begin read transaction: {:?}", err))?;
-        match txn.get(directory_database, &fingerprint.as_ref()) {
-          Ok(_) => return Ok(Some(EntryType::Directory)),
-          Err(NotFound) => {}
-          Err(err) => {
-            return Err(format!(
+    pub fn post_fork_in_child(&self) -> Result<(), String> {
+      self.inner.file_dbs.reset_in_child()?;
+      self.inner.directory_dbs.reset_in_child()
+    }
+
+    pub fn sync(&self) -> Result<(), String> {
+      for dbs in &[&self.inner.file_dbs, &self.inner.directory_dbs] {
+        for shard in dbs.get()?.all_shards() {
+          shard.sync()?;
+        }
+      }
+      Ok(())
+    }
+
+    pub fn entry_type(&self, fingerprint: &Fingerprint) -> BoxFuture<Option<EntryType>, String> {
+      let store = self.clone();
+      let fingerprint = *fingerprint;
+      self
+        .inner
+        .pool
+        .spawn_fn(move || store.entry_type_blocking(&fingerprint))
+    }
+
+    fn entry_type_blocking(&self, fingerprint: &Fingerprint) -> Result<Option<EntryType>, String> {
+      for &(ref dbs, entry_type) in &[
+        (&self.inner.directory_dbs, EntryType::Directory),
+        (&self.inner.file_dbs, EntryType::File),
+      ] {
+        let present = dbs.get()?.get(fingerprint).read(|txn, content_database, _| {
+          match txn.get(content_database, &fingerprint.as_ref()) {
+            Ok(_) => Ok(true),
+            Err(NotFound) => Ok(false),
+            Err(err) => Err(format!(
               "Error reading from store when determining type of fingerprint {}: {}",
               fingerprint, err
-            ))
+            )),
           }
-        };
-      }
-      let (env, file_database, _) = self.inner.file_dbs.get()?.get(fingerprint);
-      let txn = env
-        .begin_ro_txn()
-        .map_err(|err| format!("Failed to begin read transaction: {}", err))?;
-      match txn.get(file_database, &fingerprint.as_ref()) {
-        Ok(_) => return Ok(Some(EntryType::File)),
-        Err(NotFound) => {}
-        Err(err) => {
-          return Err(format!(
-            "Error reading from store when determining type of fingerprint {}: {}",
-            fingerprint, err
-          ))
+        })?;
+        if present {
+          return Ok(Some(entry_type));
         }
-      };
+      }
       Ok(None)
     }
 
     pub fn lease_all<'a, Ds: Iterator<Item = &'a Digest>>(
       &self,
       digests: Ds,
-    ) -> Result<(), String> {
+    ) -> BoxFuture<(), String> {
+      let store = self.clone();
+      let digests = digests.cloned().collect::<Vec<_>>();
+      self
+        .inner
+        .pool
+        .spawn_fn(move || store.lease_all_blocking(&digests))
+    }
+
+    fn lease_all_blocking(&self, digests: &[Digest]) -> Result<(), String> {
       let until = Self::default_lease_until_secs_since_epoch();
       for digest in digests {
-        let (env, _, lease_database) = self.inner.file_dbs.get()?.get(&digest.0);
-        env
-          .begin_rw_txn()
-          .and_then(|mut txn| self.lease(&lease_database, &digest.0, until, &mut txn))
+        // Digests which are not (yet) stored are assumed to be of files.
+        let entry_type = self
+          .entry_type_blocking(&digest.0)?
+          .unwrap_or(EntryType::File);
+        self
+          .dbs(entry_type)?
+          .get(&digest.0)
+          .write(|txn, _, lease_database| self.lease(&lease_database, &digest.0, until, txn))
           .map_err(|err| format!("Error leasing digest {:?}: {}", digest, err))?;
       }
       Ok(())
     }
 
+    pub fn pin_entries_for(
+      &self,
+      entries: HashMap<Digest, EntryType>,
+      duration: time::Duration,
+    ) -> BoxFuture<(), String> {
+      let store = self.clone();
+      self.inner.pool.spawn_fn(move || {
+        let until = (time::SystemTime::now() + duration)
+          .duration_since(time::UNIX_EPOCH)
+          .expect("Surely you're not before the unix epoch?")
+          .as_secs();
+        for (digest, entry_type) in entries {
+          let shard = store.dbs(entry_type)?.get(&digest.0);
+          let pin_database = shard.pin_database();
+          shard
+            .write(|txn, _, _| {
+              let (count, pinned_until) = Self::read_pin(txn, pin_database, &digest.0)?;
+              Self::write_pin(
+                txn,
+                pin_database,
+                &digest.0,
+                count + 1,
+                cmp::max(pinned_until, until),
+              )
+            })
+            .map_err(|err| format!("Error pinning digest {:?}: {}", digest, err))?;
+        }
+        Ok(())
+      })
+    }
+
+    pub fn unpin_entries(&self, entries: HashMap<Digest, EntryType>) -> BoxFuture<(), String> {
+      let store = self.clone();
+      self.inner.pool.spawn_fn(move || {
+        for (digest, entry_type) in entries {
+          let shard = store.dbs(entry_type)?.get(&digest.0);
+          let pin_database = shard.pin_database();
+          shard
+            .write(|txn, _, _| {
+              match Self::read_pin(txn, pin_database, &digest.0)? {
+                (0, _) => Ok(()),
+                (1, _) => txn.del(pin_database, &digest.0.as_ref(), None),
+                (count, pinned_until) => {
+                  Self::write_pin(txn, pin_database, &digest.0, count - 1, pinned_until)
+                }
+              }
+            })
+            .map_err(|err| format!("Error releasing digest {:?}: {}", digest, err))?;
+        }
+        Ok(())
+      })
+    }
+
+    ///
+    /// Pins are stored as a count of the pins held, and the time until which the latest of them
+    /// holds (in seconds since the epoch).
+    ///
+    fn read_pin<T: Transaction>(
+      txn: &T,
+      pin_database: Database,
+      fingerprint: &Fingerprint,
+    ) -> Result<(u64, u64), lmdb::Error> {
+      match txn.get(pin_database, &fingerprint.as_ref()) {
+        Ok(bytes) => Ok((
+          LittleEndian::read_u64(&bytes[0..8]),
+          LittleEndian::read_u64(&bytes[8..16]),
+        )),
+        Err(NotFound) => Ok((0, 0)),
+        Err(err) => Err(err),
+      }
+    }
+
+    fn write_pin(
+      txn: &mut RwTransaction,
+      pin_database: Database,
+      fingerprint: &Fingerprint,
+      count: u64,
+      until_secs_since_epoch: u64,
+    ) -> Result<(), lmdb::Error> {
+      let mut buf = [0; 16];
+      LittleEndian::write_u64(&mut buf[0..8], count);
+      LittleEndian::write_u64(&mut buf[8..16], until_secs_since_epoch);
+      txn.put(pin_database, &fingerprint.as_ref(), &buf, WriteFlags::empty())
+    }
+
+    fn dbs(&self, entry_type: EntryType) -> Result<Arc<ShardedLmdb>, String> {
+      match entry_type {
+        EntryType::File => self.inner.file_dbs.get(),
+        EntryType::Directory => self.inner.directory_dbs.get(),
+      }
+    }
+
     fn default_lease_until_secs_since_epoch() -> u64 {
       let now_since_epoch = time::SystemTime::now()
         .duration_since(time::UNIX_EPOCH)
@@ -671,6 +1770,10 @@ mod local {
       (now_since_epoch + time::Duration::from_secs(2 * 60 * 60)).as_secs()
     }
 
+    ///
+    /// Extends the lease of the given fingerprint to at least the given time: an existing lease
+    /// which holds for longer is kept.
+    ///
     fn lease(
       &self,
       database: &Database,
@@ -678,6 +1781,14 @@ mod local {
       until_secs_since_epoch: u64,
       txn: &mut RwTransaction,
     ) -> Result<(), lmdb::Error> {
+      let leased_until = match txn.get(*database, &fingerprint.as_ref()) {
+        Ok(bytes) => LittleEndian::read_u64(bytes),
+        Err(NotFound) => 0,
+        Err(err) => return Err(err),
+      };
+      if leased_until >= until_secs_since_epoch {
+        return Ok(());
+      }
       let mut buf = [0; 8];
       LittleEndian::write_u64(&mut buf, until_secs_since_epoch);
       txn.put(*database, &fingerprint.as_ref(), &buf, WriteFlags::empty())
@@ -719,24 +1830,24 @@ mod local {
           EntryType::File => self.inner.file_dbs.clone(),
           EntryType::Directory => self.inner.directory_dbs.clone(),
         };
-        let (env, database, lease_database) = lmdbs.get()?.get(&aged_fingerprint.fingerprint);
-        {
-          env
-            .begin_rw_txn()
-            .and_then(|mut txn| {
-              txn.del(database, &aged_fingerprint.fingerprint.as_ref(), None)?;
+        let shard = lmdbs.get()?.get(&aged_fingerprint.fingerprint);
+        let pin_database = shard.pin_database();
+        shard
+          .write(|txn, database, lease_database| {
+            txn.del(database, &aged_fingerprint.fingerprint.as_ref(), None)?;
 
+            for db in &[lease_database, pin_database] {
               txn
-                .del(lease_database, &aged_fingerprint.fingerprint.as_ref(), None)
+                .del(*db, &aged_fingerprint.fingerprint.as_ref(), None)
                 .or_else(|err| match err {
                   NotFound => Ok(()),
                   err => Err(err),
                 })?;
-              used_bytes -= aged_fingerprint.size_bytes;
-              txn.commit()
-            })
-            .map_err(|err| format!("Error garbage collecting: {}", err))?;
-        }
+            }
+            Ok(())
+          })
+          .map_err(|err| format!("Error garbage collecting: {}", err))?;
+        used_bytes -= aged_fingerprint.size_bytes;
       }
       Ok(used_bytes)
     }
@@ -752,12 +1863,35 @@ mod local {
         EntryType::Directory => self.inner.directory_dbs.clone(),
       };
 
-      for &(ref env, ref database, ref lease_database) in &database.get()?.all_lmdbs() {
-        let txn = env
-          .begin_ro_txn()
-          .map_err(|err| format!("Error beginning transaction to garbage collect: {}", err))?;
+      for shard in database.get()?.all_shards() {
+        let pin_database = shard.pin_database();
+        shard.read(|txn, database, lease_database| {
+          Self::aged_fingerprints_in(
+            txn,
+            database,
+            lease_database,
+            pin_database,
+            entry_type,
+            used_bytes,
+            fingerprints_by_expired_ago,
+          )
+        })?;
+      }
+      Ok(())
+    }
+
+    fn aged_fingerprints_in(
+      txn: &RoTransaction,
+      database: Database,
+      lease_database: Database,
+      pin_database: Database,
+      entry_type: EntryType,
+      used_bytes: &mut usize,
+      fingerprints_by_expired_ago: &mut BinaryHeap<AgedFingerprint>,
+    ) -> Result<(), String> {
+      {
         let mut cursor = txn
-          .open_ro_cursor(*database)
+          .open_ro_cursor(database)
           .map_err(|err| format!("Failed to open lmdb read cursor: {}", err))?;
         for (key, bytes) in cursor.iter() {
           *used_bytes = *used_bytes + bytes.len();
@@ -767,15 +1901,22 @@ mod local {
           // here (either to populate leases into pre-populated AgedFingerprints, or to read sizes
           // when we delete from lmdb to track how much we've freed).
           let lease_until_unix_timestamp = txn
-            .get(*lease_database, &key)
+            .get(lease_database, &key)
             .map(|b| LittleEndian::read_u64(b))
             .unwrap_or_else(|e| match e {
               NotFound => 0,
               e => panic!("Error reading lease, probable lmdb corruption: {:?}", e),
             });
+          let pin_until_unix_timestamp =
+            Self::read_pin(txn, pin_database, &Fingerprint::from_bytes_unsafe(key))
+              .map(|(_, pinned_until)| pinned_until)
+              .unwrap_or_else(|e| panic!("Error reading pin, probable lmdb corruption: {:?}", e));
 
           let leased_until =
-            time::UNIX_EPOCH + time::Duration::from_secs(lease_until_unix_timestamp);
+            time::UNIX_EPOCH + time::Duration::from_secs(cmp::max(
+              lease_until_unix_timestamp,
+              pin_until_unix_timestamp,
+            ));
 
           let expired_seconds_ago = time::SystemTime::now()
               .duration_since(leased_until)
@@ -816,19 +1957,21 @@ mod local {
             Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice())
           };
 
-          let (env, content_database, lease_database) = dbs.get()?.get(&fingerprint);
-          let put_res = env.begin_rw_txn().and_then(|mut txn| {
-            txn.put(content_database, &fingerprint, &bytes, NO_OVERWRITE)?;
-            if initial_lease {
-              bytestore.lease(
-                &lease_database,
-                &fingerprint,
-                Self::default_lease_until_secs_since_epoch(),
-                &mut txn,
-              )?;
-            }
-            txn.commit()
-          });
+          let put_res = dbs
+            .get()?
+            .get(&fingerprint)
+            .write(|txn, content_database, lease_database| {
+              txn.put(content_database, &fingerprint, &bytes, NO_OVERWRITE)?;
+              if initial_lease {
+                bytestore.lease(
+                  &lease_database,
+                  &fingerprint,
+                  Self::default_lease_until_secs_since_epoch(),
+                  txn,
+                )?;
+              }
+              Ok(())
+            });
 
           match put_res {
             Ok(()) => Ok(fingerprint),
@@ -842,6 +1985,81 @@ mod local {
         .to_boxed()
     }
 
+    ///
+    /// Stores the content of the given file (from its start) as a File. The file is read twice:
+    /// once to compute its Digest, and then directly into space reserved for it in the store.
+    ///
+    pub fn store_file_from(&self, file: File, initial_lease: bool) -> BoxFuture<Digest, String> {
+      let dbs = self.inner.file_dbs.clone();
+      let bytestore = self.clone();
+      self
+        .inner
+        .pool
+        .spawn_fn(move || {
+          let digest = Self::digest_file(&file)
+            .map_err(|e| format!("Error reading file to store: {:?}", e))?;
+          let fingerprint = digest.0;
+
+          // The write function may run more than once (if the map is grown), and may only fail with
+          // an lmdb::Error, so the details of a failure to read the file are recorded here.
+          let read_error: RefCell<Option<io::Error>> = RefCell::new(None);
+          let put_res = dbs
+            .get()?
+            .get(&fingerprint)
+            .write(|txn, content_database, lease_database| {
+              {
+                let buf = txn.reserve(content_database, &fingerprint, digest.1, NO_OVERWRITE)?;
+                (&file)
+                  .seek(SeekFrom::Start(0))
+                  .and_then(|_| (&file).read_exact(buf))
+                  .map_err(|e| {
+                    *read_error.borrow_mut() = Some(e);
+                    lmdb::Error::Other(0)
+                  })?;
+              }
+              if initial_lease {
+                bytestore.lease(
+                  &lease_database,
+                  &fingerprint,
+                  Self::default_lease_until_secs_since_epoch(),
+                  txn,
+                )?;
+              }
+              Ok(())
+            });
+
+          match (put_res, read_error.into_inner()) {
+            (_, Some(e)) => Err(format!("Error reading file to store: {:?}", e)),
+            (Ok(()), None) | (Err(KeyExist), None) => Ok(digest),
+            (Err(err), None) => Err(format!(
+              "Error storing fingerprint {}: {}",
+              fingerprint, err
+            )),
+          }
+        })
+        .to_boxed()
+    }
+
+    fn digest_file(file: &File) -> Result<Digest, io::Error> {
+      let mut reader = file;
+      reader.seek(SeekFrom::Start(0))?;
+      let mut hasher = Sha256::default();
+      let mut len = 0;
+      let mut buf = vec![0; 64 * 1024];
+      loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+          break;
+        }
+        hasher.input(&buf[..read]);
+        len += read;
+      }
+      Ok(Digest(
+        Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice()),
+        len,
+      ))
+    }
+
     pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
       &self,
       entry_type: EntryType,
@@ -864,120 +2082,287 @@ mod local {
         .inner
         .pool
         .spawn_fn(move || {
-          let (env, db, _) = dbs.get()?.get(&fingerprint);
-          let ro_txn = env
-            .begin_ro_txn()
-            .map_err(|err| format!("Failed to begin read transaction: {}", err));
-          ro_txn.and_then(|txn| match txn.get(db, &fingerprint) {
-            Ok(bytes) => Ok(Some(f(Bytes::from(bytes)))),
-            Err(NotFound) => Ok(None),
-            Err(err) => Err(format!(
-              "Error loading fingerprint {}: {}",
-              fingerprint, err,
-            )),
-          })
+          dbs
+            .get()?
+            .get(&fingerprint)
+            .read(|txn, db, _| match txn.get(db, &fingerprint) {
+              Ok(bytes) => Ok(Some(f(Bytes::from(bytes)))),
+              Err(NotFound) => Ok(None),
+              Err(err) => Err(format!(
+                "Error loading fingerprint {}: {}",
+                fingerprint, err,
+              )),
+            })
         })
         .to_boxed()
     }
   }
 
   // Each LMDB directory can have at most one concurrent writer.
-  // We use this type to shard storage into 16 LMDB directories, based on the first 4 bits of the
-  // fingerprint being stored, so that we can write to them in parallel.
+  // We use this type to shard storage into (by default 16) LMDB directories, based on the first
+  // bits of the fingerprint being stored, so that we can write to them in parallel: see
+  // `LocalStoreLimits`.
+  //
+  // Each shard's map starts out small and is grown as it fills, so that a large store is not
+  // limited by the size of a single map, and a small store does not reserve a large one.
   #[derive(Clone)]
   struct ShardedLmdb {
-    // First Database is content, second is leases.
-    lmdbs: HashMap<u8, (Arc<Environment>, Database, Database)>,
+    // Indexed by the first `prefix_bits` bits of fingerprints.
+    shards: Vec<Arc<Shard>>,
+    prefix_bits: u32,
   }
 
   impl ShardedLmdb {
-    pub fn new(root_path: &Path) -> Result<ShardedLmdb, String> {
+    pub fn new(
+      root_path: &Path,
+      initial_map_size: usize,
+      limits: LocalStoreLimits,
+    ) -> Result<ShardedLmdb, String> {
       debug!("Initializing ShardedLmdb at root {:?}", root_path);
-      let mut lmdbs = HashMap::new();
-
-      for b in 0x00..0x10 {
-        let key = b << 4;
-
-        let dirname = {
-          let mut s = String::new();
-          fmt::Write::write_fmt(&mut s, format_args!("{:x}", key)).unwrap();
-          s[0..1].to_owned()
+      let prefix_bits = limits.shard_count.trailing_zeros();
+      let initial_map_size = cmp::min(initial_map_size, limits.max_shard_bytes);
+      let mut shards = Vec::with_capacity(limits.shard_count);
+
+      for index in 0..limits.shard_count {
+        let dir = if limits.shard_count == DEFAULT_LOCAL_STORE_SHARD_COUNT {
+          // The original layout: a directory named for the first hex digit of the fingerprints.
+          root_path.join(format!("{:x}", index))
+        } else {
+          root_path
+            .join(format!("{}-shards", limits.shard_count))
+            .join(format!("{:x}", index))
         };
-        let dir = root_path.join(dirname);
         super::super::safe_create_dir_all(&dir)
           .map_err(|err| format!("Error making directory for store at {:?}: {:?}", dir, err))?;
-        debug!("Making ShardedLmdb env for {:?}", dir);
-        let env = Environment::new()
-            // NO_SYNC
-            // =======
-            //
-            // Don't force fsync on every lmdb write transaction
-            //
-            // This significantly improves performance on slow or contended disks.
-            //
-            // On filesystems which preserve order of writes, on system crash this may lead to some
-            // transactions being rolled back. This is fine because this is just a write-once
-            // content-addressed cache. There is no risk of corruption, just compromised durability.
-            //
-            // On filesystems which don't preserve the order of writes, this may lead to lmdb
-            // corruption on system crash (but in no other circumstances, such as process crash).
-            //
-            // ------------------------------------------------------------------------------------
-            //
-            // NO_TLS
-            // ======
-            //
-            // Without this flag, each time a read transaction is started, it eats into our
-            // transaction limit (default: 126) until that thread dies.
-            //
-            // This flag makes transactions be removed from that limit when they are dropped, rather
-            // than when their thread dies. This is important, because we perform reads from a
-            // thread pool, so our threads never die. Without this flag, all read requests will fail
-            // after the first 126.
-            //
-            // The only down-side is that you need to make sure that any individual OS thread must
-            // not try to perform multiple write transactions concurrently. Fortunately, this
-            // property holds for us.
-            .set_flags(NO_SYNC | NO_TLS)
-            // 2 DBs; one for file contents, one for leases.
-            .set_max_dbs(2)
-            .set_map_size(MAX_LOCAL_STORE_SIZE_BYTES)
-            .open(&dir)
-            .map_err(|e| format!("Error making env for store at {:?}: {}", dir, e))?;
-
-        debug!("Making ShardedLmdb content database for {:?}", dir);
-        let content_database = env
-          .create_db(Some("content"), DatabaseFlags::empty())
-          .map_err(|e| {
-            format!(
-              "Error creating/opening content database at {:?}: {}",
-              dir, e
-            )
-          })?;
+        shards.push(Arc::new(Shard::new(
+          dir,
+          initial_map_size,
+          limits.max_shard_bytes,
+        )?));
+      }
 
-        debug!("Making ShardedLmdb lease database for {:?}", dir);
-        let lease_database = env
-          .create_db(Some("leases"), DatabaseFlags::empty())
-          .map_err(|e| {
-            format!(
-              "Error creating/opening content database at {:?}: {}",
-              dir, e
-            )
-          })?;
+      Ok(ShardedLmdb {
+        shards,
+        prefix_bits,
+      })
+    }
+
+    pub fn get(&self, fingerprint: &Fingerprint) -> Arc<Shard> {
+      let index = if self.prefix_bits == 0 {
+        0
+      } else {
+        (fingerprint.0[0] >> (8 - self.prefix_bits)) as usize
+      };
+      self.shards[index].clone()
+    }
+
+    pub fn all_shards(&self) -> Vec<Arc<Shard>> {
+      self.shards.clone()
+    }
+  }
+
+  ///
+  /// A single LMDB environment, containing a content database, a lease database, and a database
+  /// of pins (which, unlike leases, are counted).
+  ///
+  /// LMDB requires that a map is only resized while the process has no open transactions, so every
+  /// transaction holds `resize_lock` for reading, and resizes hold it for writing.
+  ///
+  struct Shard {
+    dir: PathBuf,
+    env: Environment,
+    // The size that the map may grow to.
+    max_map_size: usize,
+    content_database: Database,
+    lease_database: Database,
+    pin_database: Database,
+    resize_lock: RwLock<()>,
+  }
+
+  impl Shard {
+    fn new(dir: PathBuf, initial_map_size: usize, max_map_size: usize) -> Result<Shard, String> {
+      debug!("Making ShardedLmdb env for {:?}", dir);
+      let env = Environment::new()
+          // NO_SYNC
+          // =======
+          //
+          // Don't force fsync on every lmdb write transaction
+          //
+          // This significantly improves performance on slow or contended disks.
+          //
+          // On filesystems which preserve order of writes, on system crash this may lead to some
+          // transactions being rolled back. This is fine because this is just a write-once
+          // content-addressed cache. There is no risk of corruption, just compromised durability.
+          //
+          // On filesystems which don't preserve the order of writes, this may lead to lmdb
+          // corruption on system crash (but in no other circumstances, such as process crash).
+          //
+          // ------------------------------------------------------------------------------------
+          //
+          // NO_TLS
+          // ======
+          //
+          // Without this flag, each time a read transaction is started, it eats into our
+          // transaction limit (default: 126) until that thread dies.
+          //
+          // This flag makes transactions be removed from that limit when they are dropped, rather
+          // than when their thread dies. This is important, because we perform reads from a
+          // thread pool, so our threads never die. Without this flag, all read requests will fail
+          // after the first 126.
+          //
+          // The only down-side is that you need to make sure that any individual OS thread must
+          // not try to perform multiple write transactions concurrently. Fortunately, this
+          // property holds for us.
+          .set_flags(NO_SYNC | NO_TLS)
+          // 3 DBs; one for file contents, one for leases, and one for pins.
+          .set_max_dbs(3)
+          .set_map_size(initial_map_size)
+          .open(&dir)
+          .map_err(|e| format!("Error making env for store at {:?}: {}", dir, e))?;
+
+      debug!("Making ShardedLmdb content database for {:?}", dir);
+      let content_database = env
+        .create_db(Some("content"), DatabaseFlags::empty())
+        .map_err(|e| {
+          format!(
+            "Error creating/opening content database at {:?}: {}",
+            dir, e
+          )
+        })?;
+
+      debug!("Making ShardedLmdb lease database for {:?}", dir);
+      let lease_database = env
+        .create_db(Some("leases"), DatabaseFlags::empty())
+        .map_err(|e| {
+          format!(
+            "Error creating/opening content database at {:?}: {}",
+            dir, e
+          )
+        })?;
+
+      debug!("Making ShardedLmdb pin database for {:?}", dir);
+      let pin_database = env
+        .create_db(Some("pins"), DatabaseFlags::empty())
+        .map_err(|e| format!("Error creating/opening pin database at {:?}: {}", dir, e))?;
+
+      Ok(Shard {
+        dir,
+        env,
+        max_map_size,
+        content_database,
+        lease_database,
+        pin_database,
+        resize_lock: RwLock::new(()),
+      })
+    }
+
+    pub fn pin_database(&self) -> Database {
+      self.pin_database
+    }
+
+    ///
+    /// Runs the given function in a read transaction. The first Database is content, the second is
+    /// leases.
+    ///
+    pub fn read<T, F>(&self, f: F) -> Result<T, String>
+    where
+      F: FnOnce(&RoTransaction, Database, Database) -> Result<T, String>,
+    {
+      loop {
+        {
+          let _resize_guard = self.resize_lock.read().unwrap();
+          match self.env.begin_ro_txn() {
+            Ok(txn) => return f(&txn, self.content_database, self.lease_database),
+            Err(MapResized) => {}
+            Err(err) => return Err(format!("Failed to begin read transaction: {}", err)),
+          }
+        }
+        self
+          .adopt_map_size()
+          .map_err(|err| format!("Failed to resize store at {:?}: {}", self.dir, err))?;
+      }
+    }
 
-        lmdbs.insert(key, (Arc::new(env), content_database, lease_database));
+    ///
+    /// Runs the given function in a write transaction, and commits it. The first Database is
+    /// content, the second is leases.
+    ///
+    /// If the map is full, it is grown and the function is run again in a new transaction.
+    ///
+    pub fn write<T, F>(&self, f: F) -> Result<T, lmdb::Error>
+    where
+      F: Fn(&mut RwTransaction, Database, Database) -> Result<T, lmdb::Error>,
+    {
+      loop {
+        let (map_size, result) = {
+          let _resize_guard = self.resize_lock.read().unwrap();
+          let map_size = self.map_size()?;
+          let result = self.env.begin_rw_txn().and_then(|mut txn| {
+            let value = f(&mut txn, self.content_database, self.lease_database)?;
+            txn.commit()?;
+            Ok(value)
+          });
+          (map_size, result)
+        };
+        match result {
+          Err(MapFull) => self.grow(map_size)?,
+          Err(MapResized) => self.adopt_map_size()?,
+          result => return result,
+        }
       }
+    }
+
+    ///
+    /// Flushes the writes to this Shard to disk, which are not synced as they are committed: see
+    /// `Shard::new`.
+    ///
+    pub fn sync(&self) -> Result<(), String> {
+      self
+        .env
+        .sync(true)
+        .map_err(|e| format!("Failed to sync store at {:?}: {}", self.dir, e))
+    }
 
-      Ok(ShardedLmdb { lmdbs })
+    fn map_size(&self) -> Result<usize, lmdb::Error> {
+      let mut info: lmdb_sys::MDB_envinfo = unsafe { mem::zeroed() };
+      lmdb_result(unsafe { lmdb_sys::mdb_env_info(self.env.env(), &mut info) })?;
+      Ok(info.me_mapsize)
     }
 
-    // First Database is content, second is leases.
-    pub fn get(&self, fingerprint: &Fingerprint) -> (Arc<Environment>, Database, Database) {
-      self.lmdbs[&(fingerprint.0[0] & 0xF0)].clone()
+    ///
+    /// Doubles the size of the map, unless another writer already grew it from the given size.
+    ///
+    fn grow(&self, full_map_size: usize) -> Result<(), lmdb::Error> {
+      let _resize_guard = self.resize_lock.write().unwrap();
+      let map_size = self.map_size()?;
+      if map_size > full_map_size {
+        return Ok(());
+      } else if map_size >= self.max_map_size {
+        return Err(MapFull);
+      }
+      let new_map_size = cmp::min(map_size * 2, self.max_map_size);
+      debug!(
+        "Growing the map of the store at {:?} from {} to {} bytes",
+        self.dir, map_size, new_map_size
+      );
+      lmdb_result(unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), new_map_size) })
+    }
+
+    ///
+    /// Adopts the size of the map after it was grown by another process.
+    ///
+    fn adopt_map_size(&self) -> Result<(), lmdb::Error> {
+      let _resize_guard = self.resize_lock.write().unwrap();
+      // A size of zero adopts the current size of the map on disk.
+      lmdb_result(unsafe { lmdb_sys::mdb_env_set_mapsize(self.env.env(), 0) })
     }
+  }
 
-    pub fn all_lmdbs(&self) -> Vec<(Arc<Environment>, Database, Database)> {
-      self.lmdbs.values().cloned().collect()
+  fn lmdb_result(code: c_int) -> Result<(), lmdb::Error> {
+    if code == lmdb_sys::MDB_SUCCESS {
+      Ok(())
+    } else {
+      Err(lmdb::Error::from_err_code(code))
     }
   }
 
@@ -993,7 +2378,8 @@ mod local {
   #[cfg(test)]
   pub mod tests {
     use super::super::super::safe_create_dir_all;
-    use super::{ByteStore, EntryType, ResettablePool};
+    use super::{ByteStore, EntryType, LocalStoreLimits, ResettablePool, ShardedLmdb};
+    use byteorder::{ByteOrder, LittleEndian};
     use bytes::Bytes;
     use futures::Future;
     use hashing::{Digest, Fingerprint};
@@ -1033,6 +2419,94 @@ mod local {
       );
     }
 
+    #[test]
+    fn shards_grow_when_full() {
+      let dir = TempDir::new().unwrap();
+      let initial_map_size = 64 * 1024;
+      let lmdbs =
+        ShardedLmdb::new(dir.path(), initial_map_size, LocalStoreLimits::default()).unwrap();
+
+      // Fingerprints whose first four bits are zero are all stored in the same shard.
+      let value = vec![7; 128 * 1024];
+      let fingerprints: Vec<_> = (0..16).map(|b| Fingerprint([b; 32])).collect();
+      for fingerprint in &fingerprints {
+        lmdbs
+          .get(fingerprint)
+          .write(|txn, content_database, _| {
+            txn.put(content_database, fingerprint, &value, WriteFlags::empty())
+          })
+          .unwrap();
+      }
+
+      let shard = lmdbs.get(&fingerprints[0]);
+      assert!(shard.map_size().unwrap() > initial_map_size);
+      for fingerprint in &fingerprints {
+        let stored = shard
+          .read(|txn, content_database, _| {
+            txn
+              .get(content_database, fingerprint)
+              .map(|bytes| bytes.to_vec())
+              .map_err(|e| format!("{}", e))
+          })
+          .unwrap();
+        assert_eq!(stored, value);
+      }
+    }
+
+    #[test]
+    fn shards_do_not_grow_beyond_the_limit() {
+      let dir = TempDir::new().unwrap();
+      let limits = LocalStoreLimits::create(16, 256 * 1024).unwrap();
+      let lmdbs = ShardedLmdb::new(dir.path(), 64 * 1024, limits).unwrap();
+
+      let value = vec![7; 128 * 1024];
+      let results: Vec<_> = (0..16)
+        .map(|b| {
+          let fingerprint = Fingerprint([b; 32]);
+          lmdbs.get(&fingerprint).write(|txn, content_database, _| {
+            txn.put(content_database, &fingerprint, &value, WriteFlags::empty())
+          })
+        })
+        .collect();
+
+      assert!(results.iter().any(|result| result.is_err()));
+      let shard = lmdbs.get(&Fingerprint([0; 32]));
+      assert_eq!(shard.map_size().unwrap(), 256 * 1024);
+    }
+
+    #[test]
+    fn shard_count_is_configurable() {
+      let dir = TempDir::new().unwrap();
+      let limits = LocalStoreLimits::create(4, 1024 * 1024).unwrap();
+      let lmdbs = ShardedLmdb::new(dir.path(), 64 * 1024, limits).unwrap();
+
+      assert_eq!(lmdbs.all_shards().len(), 4);
+      assert!(dir.path().join("4-shards").join("3").is_dir());
+      // The first two bits of the fingerprint select the shard.
+      assert!(Arc::ptr_eq(
+        &lmdbs.get(&Fingerprint([0x00; 32])),
+        &lmdbs.get(&Fingerprint([0x3F; 32]))
+      ));
+      assert!(!Arc::ptr_eq(
+        &lmdbs.get(&Fingerprint([0x3F; 32])),
+        &lmdbs.get(&Fingerprint([0x40; 32]))
+      ));
+      assert!(Arc::ptr_eq(
+        &lmdbs.get(&Fingerprint([0xC0; 32])),
+        &lmdbs.get(&Fingerprint([0xFF; 32]))
+      ));
+    }
+
+    #[test]
+    fn invalid_limits() {
+      assert!(LocalStoreLimits::create(0, 1024).is_err());
+      assert!(LocalStoreLimits::create(3, 1024).is_err());
+      assert!(LocalStoreLimits::create(512, 1024).is_err());
+      assert!(LocalStoreLimits::create(16, 0).is_err());
+      assert!(LocalStoreLimits::create(1, 1024).is_ok());
+      assert!(LocalStoreLimits::create(256, 1024).is_ok());
+    }
+
     #[test]
     fn save_file_collision_preserves_first() {
       let dir = TempDir::new().unwrap();
@@ -1172,6 +2646,40 @@ mod local {
       );
     }
 
+    #[test]
+    fn lease_keeps_longer_lease() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let fingerprint = store
+        .store_bytes(EntryType::File, Bytes::from("0123456789"), false)
+        .wait()
+        .expect("Error storing");
+
+      let shard = store.dbs(EntryType::File).unwrap().get(&fingerprint);
+      let lease = |until| {
+        shard
+          .write(|txn, _, lease_database| store.lease(&lease_database, &fingerprint, until, txn))
+          .expect("Error leasing")
+      };
+      let leased_until = || {
+        shard
+          .read(|txn, _, lease_database| {
+            txn
+              .get(lease_database, &fingerprint.as_ref())
+              .map(|bytes| LittleEndian::read_u64(bytes))
+              .map_err(|e| format!("{}", e))
+          })
+          .unwrap()
+      };
+
+      lease(20);
+      assert_eq!(leased_until(), 20);
+      lease(10);
+      assert_eq!(leased_until(), 20);
+      lease(30);
+      assert_eq!(leased_until(), 30);
+    }
+
     #[test]
     fn garbage_collect_nothing_to_do_with_lease() {
       let dir = TempDir::new().unwrap();
@@ -1187,6 +2695,7 @@ mod local {
       let file_digest = Digest(file_fingerprint, 10);
       store
         .lease_all(vec![file_digest].iter())
+        .wait()
         .expect("Error leasing");
       store.shrink(10).expect("Error shrinking");
       assert_eq!(
@@ -1264,6 +2773,26 @@ mod local {
       );
     }
 
+    #[test]
+    fn garbage_collect_nothing_to_do_with_directory_lease() {
+      let dir = TempDir::new().unwrap();
+      let store = new_store(dir.path());
+      let testdir = TestDirectory::containing_roland();
+      store
+        .store_bytes(EntryType::Directory, testdir.bytes(), false)
+        .wait()
+        .expect("Error storing");
+      store
+        .lease_all(vec![testdir.digest()].iter())
+        .wait()
+        .expect("Error leasing");
+      store.shrink(0).expect("Error shrinking");
+      assert_eq!(
+        load_bytes(&store, EntryType::Directory, testdir.fingerprint()),
+        Ok(Some(testdir.bytes()))
+      );
+    }
+
     #[test]
     fn garbage_collect_remove_one_of_two_directories_no_leases() {
       let dir = TempDir::new().unwrap();
@@ -1413,7 +2942,7 @@ mod local {
         .wait()
         .expect("Error storing");
       assert_eq!(
-        store.entry_type(&testdata.fingerprint()),
+        store.entry_type(&testdata.fingerprint()).wait(),
         Ok(Some(EntryType::File))
       )
     }
@@ -1433,7 +2962,7 @@ mod local {
         .wait()
         .expect("Error storing");
       assert_eq!(
-        store.entry_type(&testdir.fingerprint()),
+        store.entry_type(&testdir.fingerprint()).wait(),
         Ok(Some(EntryType::Directory))
       )
     }
@@ -1453,7 +2982,7 @@ mod local {
         .wait()
         .expect("Error storing");
       assert_eq!(
-        store.entry_type(&TestDirectory::recursive().fingerprint()),
+        store.entry_type(&TestDirectory::recursive().fingerprint()).wait(),
         Ok(None)
       )
     }
@@ -1495,49 +3024,212 @@ mod local {
       load_bytes(&store, EntryType::File, fingerprint)
     }
 
-    pub fn load_directory_proto_bytes(
-      store: &ByteStore,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Bytes>, String> {
-      load_bytes(&store, EntryType::Directory, fingerprint)
+    pub fn load_directory_proto_bytes(
+      store: &ByteStore,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      load_bytes(&store, EntryType::Directory, fingerprint)
+    }
+
+    fn load_bytes(
+      store: &ByteStore,
+      entry_type: EntryType,
+      fingerprint: Fingerprint,
+    ) -> Result<Option<Bytes>, String> {
+      store.load_bytes_with(entry_type, fingerprint, |b| b).wait()
+    }
+  }
+}
+
+mod remote {
+  use super::{
+    EntryType, RemoteTimeouts, RemoteTransferLimits, REMOTE_STORE_KNOWN_DIGESTS_TTL_SECS,
+  };
+
+  use async_semaphore::AsyncSemaphore;
+  use bazel_protos;
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::{Bytes, BytesMut};
+  use digest::{Digest as DigestTrait, FixedOutput};
+  use futures::{self, future, Future, Sink, Stream};
+  use futures_timer::Delay;
+  use grpcio;
+  use hashing::{Digest, Fingerprint};
+  use resettable::Resettable;
+  use sha2::Sha256;
+  use std::cmp::min;
+  use std::collections::{HashMap, HashSet, VecDeque};
+  use std::mem;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+  use std::time::{Duration, Instant};
+
+  #[derive(Clone)]
+  pub struct ByteStore {
+    byte_stream_client: Resettable<Arc<bazel_protos::bytestream_grpc::ByteStreamClient>>,
+    cas_client:
+      Resettable<Arc<bazel_protos::remote_execution_grpc::ContentAddressableStorageClient>>,
+    chunk_size_bytes: usize,
+    timeouts: RemoteTimeouts,
+    // See `Store::with_remote_deadline`.
+    deadline: Option<Instant>,
+    env: Resettable<Arc<grpcio::Environment>>,
+    channel: Resettable<grpcio::Channel>,
+    // The total size of the blobs which are currently being uploaded or downloaded.
+    buffered_bytes: Arc<AtomicUsize>,
+    // Digests which the server has confirmed that it has, and so need not be asked about again.
+    known_digests: Arc<Mutex<KnownDigests>>,
+    // See `RemoteTransferLimits`.
+    transfer_semaphore: Option<AsyncSemaphore>,
+    bandwidth: Option<Arc<Bandwidth>>,
+  }
+
+  ///
+  /// Paces the chunks of blobs being transferred so that, in aggregate, they don't exceed a rate.
+  ///
+  /// Each chunk reserves the next free slot in a schedule, and waits until that slot starts: the
+  /// schedule is then pushed back by the time it takes to transfer the chunk at the limited rate.
+  ///
+  struct Bandwidth {
+    bytes_per_second: usize,
+    next_free: Mutex<Instant>,
+  }
+
+  impl Bandwidth {
+    fn new(bytes_per_second: usize) -> Bandwidth {
+      Bandwidth {
+        bytes_per_second,
+        next_free: Mutex::new(Instant::now()),
+      }
+    }
+
+    fn reserve<E: Send + 'static>(&self, len: usize) -> BoxFuture<(), E> {
+      let now = Instant::now();
+      let start = {
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = if *next_free > now { *next_free } else { now };
+        *next_free = start + self.duration_of(len);
+        start
+      };
+      if start <= now {
+        future::ok(()).to_boxed()
+      } else {
+        // If the timer fails, the chunk is sent early rather than failing the transfer.
+        Delay::new_at(start).then(|_| Ok(())).to_boxed()
+      }
+    }
+
+    ///
+    /// How long a transfer of the given number of bytes which started now would spend waiting
+    /// for bandwidth, including behind the transfers which have already reserved it.
+    ///
+    fn delay_for(&self, len: usize) -> Duration {
+      let now = Instant::now();
+      let next_free = *self.next_free.lock().unwrap();
+      let queued = if next_free > now {
+        next_free - now
+      } else {
+        Duration::from_secs(0)
+      };
+      queued + self.duration_of(len)
+    }
+
+    fn duration_of(&self, len: usize) -> Duration {
+      let nanos = (len as u64).saturating_mul(1_000_000_000) / self.bytes_per_second as u64;
+      Duration::from_nanos(nanos)
+    }
+  }
+
+  ///
+  /// A bounded set of Digests, which forgets the Digests that it learned about first when full, and
+  /// any Digest that it learned about more than `ttl` ago.
+  ///
+  struct KnownDigests {
+    digests: HashMap<Digest, Instant>,
+    order: VecDeque<(Digest, Instant)>,
+    capacity: usize,
+    ttl: Duration,
+  }
+
+  impl KnownDigests {
+    fn new(capacity: usize, ttl: Duration) -> KnownDigests {
+      KnownDigests {
+        digests: HashMap::new(),
+        order: VecDeque::new(),
+        capacity,
+        ttl,
+      }
+    }
+
+    fn contains(&self, digest: &Digest) -> bool {
+      self
+        .digests
+        .get(digest)
+        .map(|learned| learned.elapsed() < self.ttl)
+        .unwrap_or(false)
+    }
+
+    fn insert(&mut self, digest: Digest) {
+      if self.capacity == 0 || self.contains(&digest) {
+        return;
+      }
+      let now = Instant::now();
+      self.digests.insert(digest, now);
+      self.order.push_back((digest, now));
+      // Expired entries are removed from the front, as are the oldest when over capacity.
+      while let Some(&(oldest, learned)) = self.order.front() {
+        if self.order.len() <= self.capacity && learned.elapsed() < self.ttl {
+          break;
+        }
+        self.order.pop_front();
+        self.remove_if_learned_at(oldest, learned);
+      }
+    }
+
+    ///
+    /// Forgets the given Digest, which the server has reported is missing after all.
+    ///
+    fn forget(&mut self, digest: &Digest) {
+      // The entry is removed from `order` as well, so that it does not count towards the capacity.
+      if self.digests.remove(digest).is_some() {
+        self.order.retain(|&(known, _)| known != *digest);
+      }
+    }
+
+    fn remove_if_learned_at(&mut self, digest: Digest, learned: Instant) {
+      if self.digests.get(&digest) == Some(&learned) {
+        self.digests.remove(&digest);
+      }
     }
 
-    fn load_bytes(
-      store: &ByteStore,
-      entry_type: EntryType,
-      fingerprint: Fingerprint,
-    ) -> Result<Option<Bytes>, String> {
-      store.load_bytes_with(entry_type, fingerprint, |b| b).wait()
+    fn heap_size(&self) -> usize {
+      // An estimate, which ignores the overhead of the HashMap's buckets.
+      (self.digests.capacity() + self.order.capacity()) * mem::size_of::<(Digest, Instant)>()
     }
   }
-}
 
-mod remote {
-  use super::EntryType;
+  ///
+  /// Counts the given number of bytes towards a total of buffered bytes, until dropped.
+  ///
+  struct BufferedBytes {
+    total: Arc<AtomicUsize>,
+    len: usize,
+  }
 
-  use bazel_protos;
-  use boxfuture::{BoxFuture, Boxable};
-  use bytes::{Bytes, BytesMut};
-  use digest::{Digest as DigestTrait, FixedOutput};
-  use futures::{self, future, Future, Sink, Stream};
-  use grpcio;
-  use hashing::{Digest, Fingerprint};
-  use resettable::Resettable;
-  use sha2::Sha256;
-  use std::cmp::min;
-  use std::collections::HashSet;
-  use std::sync::Arc;
-  use std::time::Duration;
+  impl BufferedBytes {
+    fn new(total: &Arc<AtomicUsize>, len: usize) -> BufferedBytes {
+      total.fetch_add(len, Ordering::SeqCst);
+      BufferedBytes {
+        total: total.clone(),
+        len,
+      }
+    }
+  }
 
-  #[derive(Clone)]
-  pub struct ByteStore {
-    byte_stream_client: Resettable<Arc<bazel_protos::bytestream_grpc::ByteStreamClient>>,
-    cas_client:
-      Resettable<Arc<bazel_protos::remote_execution_grpc::ContentAddressableStorageClient>>,
-    chunk_size_bytes: usize,
-    upload_timeout: Duration,
-    env: Resettable<Arc<grpcio::Environment>>,
-    channel: Resettable<grpcio::Channel>,
+  impl Drop for BufferedBytes {
+    fn drop(&mut self) {
+      self.total.fetch_sub(self.len, Ordering::SeqCst);
+    }
   }
 
   impl ByteStore {
@@ -1545,7 +3237,8 @@ mod remote {
       cas_address: String,
       thread_count: usize,
       chunk_size_bytes: usize,
-      upload_timeout: Duration,
+      timeouts: RemoteTimeouts,
+      known_digests_capacity: usize,
     ) -> ByteStore {
       let env = Resettable::new(move || Arc::new(grpcio::Environment::new(thread_count)));
       let env2 = env.clone();
@@ -1567,24 +3260,146 @@ mod remote {
         byte_stream_client,
         cas_client,
         chunk_size_bytes,
-        upload_timeout,
+        timeouts,
+        deadline: None,
         env,
         channel,
+        buffered_bytes: Arc::new(AtomicUsize::new(0)),
+        known_digests: Arc::new(Mutex::new(KnownDigests::new(
+          known_digests_capacity,
+          Duration::from_secs(REMOTE_STORE_KNOWN_DIGESTS_TTL_SECS),
+        ))),
+        transfer_semaphore: None,
+        bandwidth: None,
+      }
+    }
+
+    pub fn with_transfer_limits(mut self, limits: RemoteTransferLimits) -> ByteStore {
+      self.transfer_semaphore = limits.concurrency.map(AsyncSemaphore::new);
+      self.bandwidth = limits
+        .bytes_per_second
+        .map(|bytes_per_second| Arc::new(Bandwidth::new(bytes_per_second)));
+      self
+    }
+
+    ///
+    /// Runs the given transfer once it is permitted to start by the concurrency limit (if any).
+    ///
+    fn limit_concurrency<T: Send + 'static, F: FnOnce() -> BoxFuture<T, String> + Send + 'static>(
+      &self,
+      f: F,
+    ) -> BoxFuture<T, String> {
+      match self.transfer_semaphore {
+        Some(ref semaphore) => semaphore.with_acquired(f),
+        None => f(),
+      }
+    }
+
+    ///
+    /// Waits until the given number of bytes may be transferred under the bandwidth limit (if any).
+    ///
+    fn reserve_bandwidth<E: Send + 'static>(
+      bandwidth: &Option<Arc<Bandwidth>>,
+      len: usize,
+    ) -> BoxFuture<(), E> {
+      match *bandwidth {
+        Some(ref bandwidth) => bandwidth.reserve(len),
+        None => future::ok(()).to_boxed(),
+      }
+    }
+
+    pub fn with_deadline(mut self, deadline: Option<Instant>) -> ByteStore {
+      self.deadline = deadline;
+      self
+    }
+
+    ///
+    /// CallOptions for an RPC with the given timeout, which is shortened to end at the deadline
+    /// (if any).
+    ///
+    fn call_option(&self, timeout: Duration) -> grpcio::CallOption {
+      self.call_option_after(timeout, Duration::from_secs(0))
+    }
+
+    ///
+    /// CallOptions for an RPC which transfers the given number of bytes. Time spent waiting for
+    /// bandwidth does not count against the transfer timeout, but does count against the deadline.
+    ///
+    fn transfer_call_option(&self, len: usize) -> grpcio::CallOption {
+      let pacing = match self.bandwidth {
+        Some(ref bandwidth) => bandwidth.delay_for(len),
+        None => Duration::from_secs(0),
+      };
+      self.call_option_after(self.timeouts.transfer, pacing)
+    }
+
+    fn call_option_after(&self, timeout: Duration, delay: Duration) -> grpcio::CallOption {
+      let timeout = timeout + delay;
+      let timeout = match self.deadline {
+        Some(deadline) => {
+          let now = Instant::now();
+          let remaining = if deadline > now {
+            deadline - now
+          } else {
+            Duration::from_secs(0)
+          };
+          min(timeout, remaining)
+        }
+        None => timeout,
+      };
+      grpcio::CallOption::default().timeout(timeout)
+    }
+
+    ///
+    /// The total size of the blobs which are held in memory while they are uploaded or downloaded.
+    ///
+    pub fn buffered_bytes(&self) -> usize {
+      self.buffered_bytes.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Forgets that the server had the given Digests, so that they are asked about (and uploaded if
+    /// need be) again: for use when the server reports that they are missing after all.
+    ///
+    pub fn forget_known_digests(&self, digests: &[Digest]) {
+      let mut known_digests = self.known_digests.lock().unwrap();
+      for digest in digests {
+        known_digests.forget(digest);
       }
     }
 
-    pub fn reset_threadpool(&self) {
+    ///
+    /// An estimate of the bytes of heap memory used to remember Digests which the server has.
+    ///
+    pub fn known_digests_heap_size(&self) -> usize {
+      self.known_digests.lock().unwrap().heap_size()
+    }
+
+    pub fn pre_fork(&self) {
       self.channel.reset();
       self.env.reset();
       self.cas_client.reset();
       self.byte_stream_client.reset();
     }
 
+    pub fn post_fork_in_child(&self) -> Result<(), String> {
+      self.channel.reset_in_child()?;
+      self.env.reset_in_child()?;
+      self.cas_client.reset_in_child()?;
+      self.byte_stream_client.reset_in_child()
+    }
+
     pub fn store_bytes(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
+      let store = self.clone();
+      self.limit_concurrency(move || store.store_bytes_unlimited(bytes))
+    }
+
+    fn store_bytes_unlimited(&self, bytes: Bytes) -> BoxFuture<Digest, String> {
       let mut hasher = Sha256::default();
       hasher.input(&bytes);
       let fingerprint = Fingerprint::from_bytes_unsafe(hasher.fixed_result().as_slice());
       let len = bytes.len();
+      let known_digests = self.known_digests.clone();
       let resource_name = format!(
         "{}/uploads/{}/blobs/{}/{}",
         "",
@@ -1595,34 +3410,36 @@ mod remote {
       match self
         .byte_stream_client
         .get()
-        .write_opt(grpcio::CallOption::default().timeout(self.upload_timeout))
+        .write_opt(self.transfer_call_option(len))
       {
         Err(err) => future::err(format!(
           "Error attempting to connect to upload fingerprint {}: {:?}",
           fingerprint, err
         )).to_boxed(),
         Ok((sender, receiver)) => {
+          let buffered = BufferedBytes::new(&self.buffered_bytes, len);
           let chunk_size_bytes = self.chunk_size_bytes;
-          let stream =
-            futures::stream::unfold::<_, _, futures::future::FutureResult<_, grpcio::Error>, _>(
-              (0, false),
-              move |(offset, has_sent_any)| {
-                if offset >= bytes.len() && has_sent_any {
-                  None
-                } else {
-                  let mut req = bazel_protos::bytestream::WriteRequest::new();
-                  req.set_resource_name(resource_name.clone());
-                  req.set_write_offset(offset as i64);
-                  let next_offset = min(offset + chunk_size_bytes, bytes.len());
-                  req.set_finish_write(next_offset == bytes.len());
-                  req.set_data(bytes.slice(offset, next_offset));
-                  Some(future::ok((
-                    (req, grpcio::WriteFlags::default()),
-                    (next_offset, true),
-                  )))
-                }
-              },
-            );
+          let bandwidth = self.bandwidth.clone();
+          let stream = futures::stream::unfold::<_, _, BoxFuture<_, grpcio::Error>, _>(
+            (0, false),
+            move |(offset, has_sent_any)| {
+              if offset >= bytes.len() && has_sent_any {
+                None
+              } else {
+                let mut req = bazel_protos::bytestream::WriteRequest::new();
+                req.set_resource_name(resource_name.clone());
+                req.set_write_offset(offset as i64);
+                let next_offset = min(offset + chunk_size_bytes, bytes.len());
+                req.set_finish_write(next_offset == bytes.len());
+                req.set_data(bytes.slice(offset, next_offset));
+                Some(
+                  ByteStore::reserve_bandwidth(&bandwidth, next_offset - offset)
+                    .map(move |()| ((req, grpcio::WriteFlags::default()), (next_offset, true)))
+                    .to_boxed(),
+                )
+              }
+            },
+          );
 
           future::ok(self.byte_stream_client.get())
             .join(sender.send_all(stream).map_err(move |e| {
@@ -1640,6 +3457,7 @@ mod remote {
               })
             })
             .and_then(move |received| {
+              drop(buffered);
               if received.get_committed_size() != len as i64 {
                 Err(format!(
                   "Uploading file with fingerprint {}: want commited size {} but got {}",
@@ -1648,7 +3466,9 @@ mod remote {
                   received.get_committed_size()
                 ))
               } else {
-                Ok(Digest(fingerprint, len))
+                let digest = Digest(fingerprint, len);
+                known_digests.lock().unwrap().insert(digest);
+                Ok(digest)
               }
             })
             .to_boxed()
@@ -1657,30 +3477,51 @@ mod remote {
     }
 
     pub fn load_bytes_with<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
+      &self,
+      entry_type: EntryType,
+      digest: Digest,
+      f: F,
+    ) -> BoxFuture<Option<T>, String> {
+      let store = self.clone();
+      self.limit_concurrency(move || store.load_bytes_with_unlimited(entry_type, digest, f))
+    }
+
+    fn load_bytes_with_unlimited<T: Send + 'static, F: Fn(Bytes) -> T + Send + Sync + 'static>(
       &self,
       _entry_type: EntryType,
       digest: Digest,
       f: F,
     ) -> BoxFuture<Option<T>, String> {
-      match self.byte_stream_client.get().read(&{
+      let req = {
         let mut req = bazel_protos::bytestream::ReadRequest::new();
         req.set_resource_name(format!("/blobs/{}/{}", digest.0, digest.1));
         req.set_read_offset(0);
         // 0 means no limit.
         req.set_read_limit(0);
         req
-      }) {
+      };
+      match self
+        .byte_stream_client
+        .get()
+        .read_opt(&req, self.transfer_call_option(digest.1))
+      {
         Ok(stream) => {
+          let buffered = BufferedBytes::new(&self.buffered_bytes, digest.1);
+          let bandwidth = self.bandwidth.clone();
           // We shouldn't have to pass around the client here, it's a workaround for
           // https://github.com/pingcap/grpc-rs/issues/123
           future::ok(self.byte_stream_client.get())
             .join(
               stream.fold(BytesMut::with_capacity(digest.1), move |mut bytes, r| {
                 bytes.extend_from_slice(&r.data);
-                future::ok::<_, grpcio::Error>(bytes)
+                ByteStore::reserve_bandwidth::<grpcio::Error>(&bandwidth, r.data.len())
+                  .map(move |()| bytes)
               }),
             )
-            .map(|(_client, bytes)| Some(bytes.freeze()))
+            .map(move |(_client, bytes)| {
+              drop(buffered);
+              Some(bytes.freeze())
+            })
             .or_else(|e| match e {
               grpcio::Error::RpcFailure(grpcio::RpcStatus {
                 status: grpcio::RpcStatusCode::NotFound,
@@ -1701,18 +3542,122 @@ mod remote {
       }
     }
 
+    ///
+    /// Loads the given (small) blobs in a single request, returning those which the server
+    /// returned successfully. Blobs which failed (or which the server didn't have) are omitted, and
+    /// a server which doesn't support batched reads is treated as having none of the blobs, so that
+    /// they are loaded individually instead.
+    ///
+    pub fn load_bytes_batch(
+      &self,
+      digests: Vec<Digest>,
+    ) -> BoxFuture<Vec<(Digest, Bytes)>, String> {
+      let store = self.clone();
+      self.limit_concurrency(move || store.load_bytes_batch_unlimited(digests))
+    }
+
+    fn load_bytes_batch_unlimited(
+      &self,
+      digests: Vec<Digest>,
+    ) -> BoxFuture<Vec<(Digest, Bytes)>, String> {
+      let mut request = bazel_protos::remote_execution::BatchReadBlobsRequest::new();
+      for digest in &digests {
+        request.mut_digests().push(digest.into());
+      }
+      let len: usize = digests.iter().map(|digest| digest.1).sum();
+      let client = self.cas_client.get();
+      let receiver = match client
+        .batch_read_blobs_async_opt(&request, self.transfer_call_option(len))
+      {
+        Ok(receiver) => receiver,
+        Err(err) => {
+          return future::err(format!(
+            "Error making CAS batch read request for {} digest(s): {:?}",
+            digests.len(),
+            err
+          )).to_boxed()
+        }
+      };
+      let buffered = BufferedBytes::new(&self.buffered_bytes, len);
+      let bandwidth = self.bandwidth.clone();
+      let known_digests = self.known_digests.clone();
+      // We shouldn't have to pass around the client here, it's a workaround for
+      // https://github.com/pingcap/grpc-rs/issues/123
+      future::ok(client)
+        .join(receiver)
+        .and_then(move |(_client, response)| {
+          ByteStore::reserve_bandwidth(&bandwidth, len).map(move |()| response)
+        })
+        .then(move |result| {
+          drop(buffered);
+          match result {
+            Ok(mut response) => {
+              let mut blobs = Vec::new();
+              for mut blob_response in response.take_responses().into_iter() {
+                let code = grpcio::RpcStatusCode::from(blob_response.get_status().get_code());
+                let digest_result: Result<Digest, String> = blob_response.get_digest().into();
+                let digest = match digest_result {
+                  Ok(digest) => digest,
+                  Err(err) => {
+                    debug!("Ignoring a batch read response with a bad digest: {}", err);
+                    continue;
+                  }
+                };
+                if code != grpcio::RpcStatusCode::Ok {
+                  if code != grpcio::RpcStatusCode::NotFound {
+                    debug!(
+                      "Failed to read {:?} in a batch ({:?}): it will be read individually",
+                      digest, code
+                    );
+                  }
+                  continue;
+                }
+                known_digests.lock().unwrap().insert(digest);
+                blobs.push((digest, blob_response.take_data()));
+              }
+              Ok(blobs)
+            }
+            Err(grpcio::Error::RpcFailure(grpcio::RpcStatus {
+              status: grpcio::RpcStatusCode::Unimplemented,
+              ..
+            })) => Ok(vec![]),
+            Err(err) => Err(format!(
+              "Error from server in response to CAS batch read request: {:?}",
+              err
+            )),
+          }
+        })
+        .to_boxed()
+    }
+
+    ///
+    /// Returns the subset of the given Digests which the server does not have. Digests which the
+    /// server has previously confirmed that it has (either in response to this call, or because
+    /// they were uploaded by this store) are not asked about again.
+    ///
     pub fn list_missing_digests<'a, Digests: Iterator<Item = &'a Digest>>(
       &self,
       digests: Digests,
     ) -> Result<HashSet<Digest>, String> {
+      let unknown_digests: Vec<Digest> = {
+        let known_digests = self.known_digests.lock().unwrap();
+        digests
+          .filter(|digest| !known_digests.contains(digest))
+          .cloned()
+          .collect()
+      };
+      if unknown_digests.is_empty() {
+        return Ok(HashSet::new());
+      }
+
       let mut request = bazel_protos::remote_execution::FindMissingBlobsRequest::new();
-      for digest in digests {
+      for digest in &unknown_digests {
         request.mut_blob_digests().push(digest.into());
       }
-      self
+      let missing_digests = self
         .cas_client
         .get()
-        .find_missing_blobs(&request)
+        .find_missing_blobs_opt(&request, self.call_option(self.timeouts.metadata))
         .map_err(|err| {
           format!(
             "Error from server in response to find_missing_blobs_request: {:?}",
@@ -1724,24 +3669,74 @@ mod remote {
             .get_missing_blob_digests()
             .iter()
             .map(|digest| digest.into())
-            .collect()
-        })
+            .collect::<Result<HashSet<Digest>, String>>()
+        })?;
+
+      let mut known_digests = self.known_digests.lock().unwrap();
+      for digest in unknown_digests {
+        if !missing_digests.contains(&digest) {
+          known_digests.insert(digest);
+        }
+      }
+      Ok(missing_digests)
     }
   }
 
   #[cfg(test)]
   mod tests {
-    use super::super::EntryType;
-    use super::ByteStore;
+    use super::super::{EntryType, RemoteTimeouts, RemoteTransferLimits};
+    use super::{ByteStore, KnownDigests};
     use bytes::Bytes;
-    use futures::Future;
+    use futures::{future, Future};
     use hashing::Digest;
     use mock::StubCAS;
     use std::collections::HashSet;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
     use testutil::data::{TestData, TestDirectory};
 
-    use super::super::tests::{big_file_bytes, big_file_digest, big_file_fingerprint, new_cas};
+    use super::super::tests::{
+      big_file_bytes, big_file_digest, big_file_fingerprint, new_cas, timeouts,
+    };
+
+    #[test]
+    fn known_digests_expire_and_can_be_forgotten() {
+      let roland = TestData::roland().digest();
+      let catnip = TestData::catnip().digest();
+
+      let mut known = KnownDigests::new(10, Duration::from_secs(60));
+      known.insert(roland);
+      known.insert(catnip);
+      known.forget(&roland);
+      assert!(!known.contains(&roland));
+      assert!(known.contains(&catnip));
+      // A forgotten Digest can be learned again.
+      known.insert(roland);
+      assert!(known.contains(&roland));
+
+      let mut known = KnownDigests::new(10, Duration::from_millis(10));
+      known.insert(roland);
+      ::std::thread::sleep(Duration::from_millis(20));
+      assert!(!known.contains(&roland));
+      known.insert(catnip);
+      assert_eq!(known.digests.len(), 1);
+    }
+
+    #[test]
+    fn forgotten_known_digests_do_not_count_towards_capacity() {
+      let roland = TestData::roland().digest();
+      let catnip = TestData::catnip().digest();
+      let fourty_chars = TestData::fourty_chars().digest();
+
+      let mut known = KnownDigests::new(2, Duration::from_secs(60));
+      known.insert(roland);
+      known.insert(catnip);
+      known.forget(&roland);
+      // Filling the capacity again does not push out the live entry.
+      known.insert(fourty_chars);
+      assert!(known.contains(&catnip));
+      assert!(known.contains(&fourty_chars));
+      assert_eq!(known.order.len(), 2);
+    }
 
     #[test]
     fn loads_file() {
@@ -1754,6 +3749,18 @@ mod remote {
       );
     }
 
+    #[test]
+    fn loads_file_releases_buffer() {
+      let testdata = TestData::roland();
+      let cas = new_cas(10);
+      let store = new_byte_store(&cas);
+
+      let load = store.load_bytes_with(EntryType::File, testdata.digest(), |b| b);
+      assert_eq!(store.buffered_bytes(), testdata.len());
+      assert_eq!(load.wait(), Ok(Some(testdata.bytes())));
+      assert_eq!(store.buffered_bytes(), 0);
+    }
+
     #[test]
     fn missing_file() {
       let cas = StubCAS::empty();
@@ -1868,6 +3875,7 @@ mod remote {
         store.store_bytes(testdata.bytes()).wait(),
         Ok(testdata.digest())
       );
+      assert_eq!(store.buffered_bytes(), 0);
 
       let blobs = cas.blobs.lock().unwrap();
       assert_eq!(blobs.get(&testdata.fingerprint()), Some(&testdata.bytes()));
@@ -1877,7 +3885,13 @@ mod remote {
     fn write_file_multiple_chunks() {
       let cas = StubCAS::empty();
 
-      let store = ByteStore::new(cas.address(), 1, 10 * 1024, Duration::from_secs(5));
+      let store = ByteStore::new(
+        cas.address(),
+        1,
+        10 * 1024,
+        timeouts(Duration::from_secs(5)),
+        1024,
+      );
 
       let all_the_henries = big_file_bytes();
 
@@ -1912,99 +3926,297 @@ mod remote {
 
       let store = new_byte_store(&cas);
       assert_eq!(
-        store.store_bytes(empty_file.bytes()).wait(),
-        Ok(empty_file.digest())
+        store.store_bytes(empty_file.bytes()).wait(),
+        Ok(empty_file.digest())
+      );
+
+      let blobs = cas.blobs.lock().unwrap();
+      assert_eq!(
+        blobs.get(&empty_file.fingerprint()),
+        Some(&empty_file.bytes())
+      );
+    }
+
+    #[test]
+    fn write_file_errors() {
+      let cas = StubCAS::always_errors();
+
+      let store = new_byte_store(&cas);
+      let error = store
+        .store_bytes(TestData::roland().bytes())
+        .wait()
+        .expect_err("Want error");
+      assert!(
+        error.contains("Error from server"),
+        format!("Bad error message, got: {}", error)
+      );
+      assert!(
+        error.contains("StubCAS is configured to always fail"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn write_connection_error() {
+      let store = ByteStore::new(
+        "doesnotexist.example".to_owned(),
+        1,
+        10 * 1024 * 1024,
+        timeouts(Duration::from_secs(1)),
+        1024,
+      );
+      let error = store
+        .store_bytes(TestData::roland().bytes())
+        .wait()
+        .expect_err("Want error");
+      assert!(
+        error.contains("Error attempting to upload fingerprint"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn list_missing_digests_none_missing() {
+      let cas = new_cas(1024);
+
+      let store = new_byte_store(&cas);
+      assert_eq!(
+        store.list_missing_digests(vec![TestData::roland().digest()].iter()),
+        Ok(HashSet::new())
+      );
+    }
+
+    #[test]
+    fn list_missing_digests_some_missing() {
+      let cas = StubCAS::empty();
+
+      let store = new_byte_store(&cas);
+
+      let digest = TestData::roland().digest();
+
+      let mut digest_set = HashSet::new();
+      digest_set.insert(digest);
+
+      assert_eq!(
+        store.list_missing_digests(vec![digest].iter()),
+        Ok(digest_set)
+      );
+    }
+
+    #[test]
+    fn list_missing_digests_error() {
+      let cas = StubCAS::always_errors();
+
+      let store = new_byte_store(&cas);
+
+      let error = store
+        .list_missing_digests(vec![TestData::roland().digest()].iter())
+        .expect_err("Want error");
+      assert!(
+        error.contains("StubCAS is configured to always fail"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
+
+    #[test]
+    fn list_missing_digests_remembers_present_digests() {
+      let cas = StubCAS::with_roland_and_directory(1024);
+      let store = new_byte_store(&cas);
+
+      let present = TestData::roland().digest();
+      let missing = TestData::catnip().digest();
+      let mut missing_set = HashSet::new();
+      missing_set.insert(missing);
+
+      assert_eq!(
+        store.list_missing_digests(vec![present, missing].iter()),
+        Ok(missing_set.clone())
       );
+      assert_eq!(cas.find_missing_blobs_request_count(), 1);
 
-      let blobs = cas.blobs.lock().unwrap();
+      // The missing digest is asked about again, but the present one is not.
       assert_eq!(
-        blobs.get(&empty_file.fingerprint()),
-        Some(&empty_file.bytes())
+        store.list_missing_digests(vec![present, missing].iter()),
+        Ok(missing_set)
       );
+      assert_eq!(cas.find_missing_blobs_request_count(), 2);
+
+      // Once only known digests are requested, no request is made at all.
+      assert_eq!(store.list_missing_digests(vec![present].iter()), Ok(HashSet::new()));
+      assert_eq!(cas.find_missing_blobs_request_count(), 2);
     }
 
     #[test]
-    fn write_file_errors() {
-      let cas = StubCAS::always_errors();
-
+    fn list_missing_digests_remembers_uploaded_digests() {
+      let cas = StubCAS::empty();
       let store = new_byte_store(&cas);
-      let error = store
+
+      let digest = store
         .store_bytes(TestData::roland().bytes())
         .wait()
-        .expect_err("Want error");
-      assert!(
-        error.contains("Error from server"),
-        format!("Bad error message, got: {}", error)
-      );
-      assert!(
-        error.contains("StubCAS is configured to always fail"),
-        format!("Bad error message, got: {}", error)
-      );
+        .expect("Error storing bytes");
+      assert_eq!(store.list_missing_digests(vec![digest].iter()), Ok(HashSet::new()));
+      assert_eq!(cas.find_missing_blobs_request_count(), 0);
     }
 
     #[test]
-    fn write_connection_error() {
+    fn list_missing_digests_forgets_oldest_digests_beyond_capacity() {
+      let cas = StubCAS::with_content(1024, vec![TestData::roland(), TestData::catnip()], vec![]);
       let store = ByteStore::new(
-        "doesnotexist.example".to_owned(),
+        cas.address(),
         1,
         10 * 1024 * 1024,
-        Duration::from_secs(1),
-      );
-      let error = store
-        .store_bytes(TestData::roland().bytes())
-        .wait()
-        .expect_err("Want error");
-      assert!(
-        error.contains("Error attempting to upload fingerprint"),
-        format!("Bad error message, got: {}", error)
+        timeouts(Duration::from_secs(1)),
+        1,
       );
+
+      let roland = TestData::roland().digest();
+      let catnip = TestData::catnip().digest();
+      store.list_missing_digests(vec![roland].iter()).unwrap();
+      store.list_missing_digests(vec![catnip].iter()).unwrap();
+      assert_eq!(cas.find_missing_blobs_request_count(), 2);
+
+      store.list_missing_digests(vec![catnip].iter()).unwrap();
+      assert_eq!(cas.find_missing_blobs_request_count(), 2);
+      store.list_missing_digests(vec![roland].iter()).unwrap();
+      assert_eq!(cas.find_missing_blobs_request_count(), 3);
     }
 
     #[test]
-    fn list_missing_digests_none_missing() {
-      let cas = new_cas(1024);
+    fn concurrency_limited_transfers_all_complete() {
+      let cas = new_cas(10);
+      let store = new_byte_store(&cas).with_transfer_limits(RemoteTransferLimits {
+        concurrency: Some(1),
+        bytes_per_second: None,
+      });
 
-      let store = new_byte_store(&cas);
+      let loads = (0..4)
+        .map(|_| store.load_bytes_with(EntryType::File, TestData::roland().digest(), |b| b))
+        .collect::<Vec<_>>();
       assert_eq!(
-        store.list_missing_digests(vec![TestData::roland().digest()].iter()),
-        Ok(HashSet::new())
+        future::join_all(loads).wait(),
+        Ok(vec![Some(TestData::roland().bytes()); 4])
       );
+      assert_eq!(cas.read_request_count(), 4);
     }
 
     #[test]
-    fn list_missing_digests_some_missing() {
+    fn bandwidth_limited_write_is_paced() {
+      let testdata = TestData::roland();
       let cas = StubCAS::empty();
+      // Two chunks, the second of which may only be sent once the first has "taken" 10/16 seconds.
+      let store = ByteStore::new(
+        cas.address(),
+        1,
+        10,
+        timeouts(Duration::from_secs(5)),
+        1024,
+      ).with_transfer_limits(RemoteTransferLimits {
+        concurrency: None,
+        bytes_per_second: Some(testdata.len()),
+      });
 
-      let store = new_byte_store(&cas);
-
-      let digest = TestData::roland().digest();
+      let start = Instant::now();
+      assert_eq!(
+        store.store_bytes(testdata.bytes()).wait(),
+        Ok(testdata.digest())
+      );
+      assert!(start.elapsed() >= Duration::from_millis(500));
+    }
 
-      let mut digest_set = HashSet::new();
-      digest_set.insert(digest);
+    #[test]
+    fn bandwidth_pacing_does_not_count_against_timeout() {
+      let testdata = TestData::roland();
+      let cas = StubCAS::empty();
+      // Pacing the two chunks takes longer than the transfer timeout.
+      let store = ByteStore::new(
+        cas.address(),
+        1,
+        10,
+        timeouts(Duration::from_millis(200)),
+        1024,
+      ).with_transfer_limits(RemoteTransferLimits {
+        concurrency: None,
+        bytes_per_second: Some(testdata.len() / 2),
+      });
 
+      let start = Instant::now();
       assert_eq!(
-        store.list_missing_digests(vec![digest].iter()),
-        Ok(digest_set)
+        store.store_bytes(testdata.bytes()).wait(),
+        Ok(testdata.digest())
       );
+      assert!(start.elapsed() >= Duration::from_secs(1));
     }
 
     #[test]
-    fn list_missing_digests_error() {
-      let cas = StubCAS::always_errors();
+    fn read_times_out() {
+      let cas = StubCAS::hanging();
+      let store = ByteStore::new(
+        cas.address(),
+        1,
+        10 * 1024 * 1024,
+        RemoteTimeouts {
+          metadata: Duration::from_secs(60),
+          transfer: Duration::from_millis(100),
+        },
+        1024,
+      );
+
+      let error = load_file_bytes(&store, TestData::roland().digest()).expect_err("Want error");
+      assert!(
+        error.contains("DeadlineExceeded"),
+        format!("Bad error message, got: {}", error)
+      );
+    }
 
+    #[test]
+    fn deadline_shortens_timeouts() {
+      let cas = StubCAS::hanging();
+      let store = ByteStore::new(
+        cas.address(),
+        1,
+        10 * 1024 * 1024,
+        timeouts(Duration::from_secs(60)),
+        1024,
+      ).with_deadline(Some(Instant::now() + Duration::from_millis(100)));
+
+      let start = Instant::now();
+      let error = load_file_bytes(&store, TestData::roland().digest()).expect_err("Want error");
+      assert!(
+        error.contains("DeadlineExceeded"),
+        format!("Bad error message, got: {}", error)
+      );
+      assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn passed_deadline_fails_metadata_calls() {
+      let cas = new_cas(1024);
       let store = new_byte_store(&cas);
+      let digests = vec![TestData::roland().digest()];
 
       let error = store
-        .list_missing_digests(vec![TestData::roland().digest()].iter())
+        .clone()
+        .with_deadline(Some(Instant::now()))
+        .list_missing_digests(digests.iter())
         .expect_err("Want error");
       assert!(
-        error.contains("StubCAS is configured to always fail"),
+        error.contains("DeadlineExceeded"),
         format!("Bad error message, got: {}", error)
       );
+
+      // Other clones of the store are unaffected.
+      assert_eq!(store.list_missing_digests(digests.iter()), Ok(HashSet::new()));
     }
 
     fn new_byte_store(cas: &StubCAS) -> ByteStore {
-      ByteStore::new(cas.address(), 1, 10 * 1024 * 1024, Duration::from_secs(1))
+      ByteStore::new(
+        cas.address(),
+        1,
+        10 * 1024 * 1024,
+        timeouts(Duration::from_secs(1)),
+        1024,
+      )
     }
 
     pub fn load_file_bytes(store: &ByteStore, digest: Digest) -> Result<Option<Bytes>, String> {
@@ -2030,7 +4242,7 @@ mod remote {
 
 #[cfg(test)]
 mod tests {
-  use super::{local, EntryType, FileContent, Store};
+  use super::{local, EntryType, FileContent, RemoteTimeouts, Store, WriteThrough};
 
   use bazel_protos;
   use bytes::Bytes;
@@ -2045,13 +4257,20 @@ mod tests {
   use std::collections::HashMap;
   use std::fs::File;
   use std::io::Read;
-  use std::os::unix::fs::PermissionsExt;
+  use std::os::unix::fs::{MetadataExt, PermissionsExt};
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
-  use std::time::Duration;
+  use std::time::{Duration, Instant};
   use tempfile::TempDir;
   use testutil::data::{TestData, TestDirectory};
 
+  pub fn timeouts(timeout: Duration) -> RemoteTimeouts {
+    RemoteTimeouts {
+      metadata: timeout,
+      transfer: timeout,
+    }
+  }
+
   pub fn big_file_fingerprint() -> Fingerprint {
     Fingerprint::from_hex_string("8dfba0adc29389c63062a68d76b2309b9a2486f1ab610c4720beabbdc273301f")
       .unwrap()
@@ -2112,7 +4331,7 @@ mod tests {
       cas_address,
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(1),
+      timeouts(Duration::from_secs(1)),
     ).unwrap()
   }
 
@@ -2156,6 +4375,29 @@ mod tests {
     assert_eq!(0, cas.read_request_count());
   }
 
+  #[test]
+  fn load_directory_shared_is_cached() {
+    let dir = TempDir::new().unwrap();
+
+    let testdir = TestDirectory::containing_roland();
+
+    let cas = new_cas(1024);
+    let store = new_store(dir.path(), cas.address());
+    let first = store
+      .load_directory_shared(testdir.digest())
+      .wait()
+      .unwrap()
+      .unwrap();
+    let second = store
+      .load_directory_shared(testdir.digest())
+      .wait()
+      .unwrap()
+      .unwrap();
+    assert_eq!(*first, testdir.directory());
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(1, cas.read_request_count());
+  }
+
   #[test]
   fn load_file_falls_back_and_backfills() {
     let dir = TempDir::new().unwrap();
@@ -2488,6 +4730,129 @@ mod tests {
     );
   }
 
+  #[test]
+  fn lease_and_release_recursive() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory locally");
+
+    store
+      .lease_recursive(vec![testdir.digest()], Duration::from_secs(60 * 60))
+      .wait()
+      .expect("Error leasing");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes())),
+      "File was missing despite lease of its directory"
+    );
+
+    store
+      .release_recursive(vec![testdir.digest()])
+      .wait()
+      .expect("Error releasing");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(load_file_bytes(&store, roland.digest()), Ok(None));
+  }
+
+  #[test]
+  fn release_recursive_releases_one_pin() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory locally");
+
+    // The file is pinned both directly, and as the content of its directory.
+    store
+      .lease_recursive(vec![testdir.digest()], Duration::from_secs(60 * 60))
+      .wait()
+      .expect("Error leasing");
+    store
+      .lease_recursive(vec![roland.digest()], Duration::from_secs(60))
+      .wait()
+      .expect("Error leasing");
+
+    store
+      .release_recursive(vec![roland.digest()])
+      .wait()
+      .expect("Error releasing");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes())),
+      "File was missing despite a remaining pin of its directory"
+    );
+
+    store
+      .release_recursive(vec![testdir.digest()])
+      .wait()
+      .expect("Error releasing");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(load_file_bytes(&store, roland.digest()), Ok(None));
+  }
+
+  #[test]
+  fn garbage_collect_keeps_contents_of_leased_directory() {
+    let dir = TempDir::new().unwrap();
+    let store = new_local_store(dir.path());
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error storing directory locally");
+
+    store
+      .lease_all(vec![testdir.digest()].iter())
+      .wait()
+      .expect("Error leasing");
+    store.local.shrink(0).expect("Error shrinking");
+    assert_eq!(
+      load_file_bytes(&store, roland.digest()),
+      Ok(Some(roland.bytes())),
+      "File was missing despite lease of its directory"
+    );
+  }
+
+  #[test]
+  fn lease_unknown_digest() {
+    let dir = TempDir::new().unwrap();
+    let digest = TestData::roland().digest();
+    let error = new_local_store(dir.path())
+      .lease_recursive(vec![digest], Duration::from_secs(60))
+      .wait()
+      .expect_err("Want error");
+    assert!(
+      error.contains(&format!("{:?}", digest)),
+      "Bad error message: {}",
+      error
+    );
+  }
+
   #[test]
   fn uploads_files() {
     let dir = TempDir::new().unwrap();
@@ -2641,36 +5006,105 @@ mod tests {
   }
 
   #[test]
-  fn does_not_reupload_big_file_already_in_cas() {
+  fn does_not_reupload_big_file_already_in_cas() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::empty();
+
+    new_local_store(dir.path())
+      .store_file_bytes(extra_big_file_bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+
+    new_store(dir.path(), cas.address())
+      .ensure_remote_has_recursive(vec![extra_big_file_digest()])
+      .wait()
+      .expect("Error uploading directory");
+
+    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 1);
+    assert_eq!(
+      cas.blobs.lock().unwrap().get(&extra_big_file_fingerprint()),
+      Some(&extra_big_file_bytes())
+    );
+
+    new_store(dir.path(), cas.address())
+      .ensure_remote_has_recursive(vec![extra_big_file_digest()])
+      .wait()
+      .expect("Error uploading directory");
+
+    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 1);
+    assert_eq!(
+      cas.blobs.lock().unwrap().get(&extra_big_file_fingerprint()),
+      Some(&extra_big_file_bytes())
+    );
+  }
+
+  #[test]
+  fn write_through_policies() {
+    let testdata = TestData::roland();
+    for &(write_through, want_uploaded) in &[
+      (WriteThrough::Eager, true),
+      (WriteThrough::Lazy, false),
+      (WriteThrough::Never, false),
+    ] {
+      let dir = TempDir::new().unwrap();
+      let cas = StubCAS::empty();
+      let store = new_store(dir.path(), cas.address()).with_write_through(write_through);
+      store
+        .store_file_bytes(testdata.bytes(), false)
+        .wait()
+        .expect("Error storing file locally");
+
+      store.write_through(vec![testdata.digest()]);
+      store.flush_uploads(None).expect("Error flushing uploads");
+
+      assert_eq!(
+        cas.blobs.lock().unwrap().contains_key(&testdata.fingerprint()),
+        want_uploaded,
+        "{:?}",
+        write_through
+      );
+    }
+  }
+
+  #[test]
+  fn write_through_does_not_wait_for_uploads() {
+    let dir = TempDir::new().unwrap();
+    let cas = StubCAS::hanging();
+    let testdata = TestData::roland();
+    let store = new_store(dir.path(), cas.address()).with_write_through(WriteThrough::Eager);
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error storing file locally");
+
+    let start = Instant::now();
+    store.write_through(vec![testdata.digest()]);
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    // Only the flush waits for the upload (which never completes).
+    let error = store
+      .flush_uploads(Some(Instant::now() + Duration::from_millis(100)))
+      .expect_err("Want error");
+    assert!(error.contains("1 upload(s)"), "Bad error message: {}", error);
+  }
+
+  #[test]
+  fn read_only_remote_refuses_uploads() {
     let dir = TempDir::new().unwrap();
     let cas = StubCAS::empty();
-
-    new_local_store(dir.path())
-      .store_file_bytes(extra_big_file_bytes(), false)
+    let testdata = TestData::roland();
+    let store = new_store(dir.path(), cas.address()).with_write_through(WriteThrough::Never);
+    store
+      .store_file_bytes(testdata.bytes(), false)
       .wait()
       .expect("Error storing file locally");
 
-    new_store(dir.path(), cas.address())
-      .ensure_remote_has_recursive(vec![extra_big_file_digest()])
-      .wait()
-      .expect("Error uploading directory");
-
-    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 1);
-    assert_eq!(
-      cas.blobs.lock().unwrap().get(&extra_big_file_fingerprint()),
-      Some(&extra_big_file_bytes())
-    );
-
-    new_store(dir.path(), cas.address())
-      .ensure_remote_has_recursive(vec![extra_big_file_digest()])
+    let error = store
+      .ensure_remote_has_recursive(vec![testdata.digest()])
       .wait()
-      .expect("Error uploading directory");
-
-    assert_eq!(cas.write_message_sizes.lock().unwrap().len(), 1);
-    assert_eq!(
-      cas.blobs.lock().unwrap().get(&extra_big_file_fingerprint()),
-      Some(&extra_big_file_bytes())
-    );
+      .expect_err("Want error");
+    assert!(error.contains("read-only"), "Bad error message: {}", error);
+    assert_eq!(cas.blobs.lock().unwrap().get(&testdata.fingerprint()), None);
   }
 
   #[test]
@@ -2755,6 +5189,62 @@ mod tests {
     assert!(!is_executable(&file));
   }
 
+  #[test]
+  fn materialize_file_twice() {
+    let materialize_dir = TempDir::new().unwrap();
+    let first = materialize_dir.path().join("first");
+    let second = materialize_dir.path().join("second");
+
+    let testdata = TestData::roland();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error saving bytes");
+    store
+      .materialize_file(first.clone(), testdata.digest(), false)
+      .wait()
+      .expect("Error materializing file");
+    // The second materialization may be a clone of the first.
+    store
+      .materialize_file(second.clone(), testdata.digest(), true)
+      .wait()
+      .expect("Error materializing file");
+    assert_eq!(file_contents(&first), testdata.bytes());
+    assert_eq!(file_contents(&second), testdata.bytes());
+    assert!(!is_executable(&first));
+    assert!(is_executable(&second));
+  }
+
+  #[test]
+  fn materialize_file_twice_hardlinked() {
+    let materialize_dir = TempDir::new().unwrap();
+    let first = materialize_dir.path().join("first");
+    let second = materialize_dir.path().join("second");
+
+    let testdata = TestData::roland();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path()).with_hardlinks(true);
+    store
+      .store_file_bytes(testdata.bytes(), false)
+      .wait()
+      .expect("Error saving bytes");
+    for path in vec![first.clone(), second.clone()] {
+      store
+        .materialize_file(path, testdata.digest(), false)
+        .wait()
+        .expect("Error materializing file");
+    }
+    assert_eq!(file_contents(&second), testdata.bytes());
+    assert_eq!(
+      std::fs::metadata(&first).unwrap().ino(),
+      std::fs::metadata(&second).unwrap().ino()
+    );
+  }
+
   #[test]
   fn materialize_file_executable() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2842,6 +5332,95 @@ mod tests {
     );
   }
 
+  #[test]
+  fn replace_directory() {
+    let root = TempDir::new().unwrap();
+    let destination = root.path().join("exported");
+    std::fs::create_dir(&destination).unwrap();
+    std::fs::write(destination.join("stale"), "stale").unwrap();
+
+    let roland = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+    store
+      .store_file_bytes(roland.bytes(), false)
+      .wait()
+      .expect("Error saving file bytes");
+
+    store
+      .replace_directory(destination.clone(), testdir.digest())
+      .wait()
+      .expect("Error replacing");
+
+    assert_eq!(list_dir(&destination), vec!["roland"]);
+    assert_eq!(file_contents(&destination.join("roland")), roland.bytes());
+    assert_eq!(list_dir(root.path()), vec!["exported"]);
+  }
+
+  #[test]
+  fn replace_directory_removes_stale_siblings() {
+    let root = TempDir::new().unwrap();
+    let destination = root.path().join("exported");
+    // Left by interrupted replacements in a process which has exited (pids are never this large)
+    // and in this process, and by an unrelated directory.
+    let exited = root.path().join(".exported.pants-old-2147483646-0");
+    let current = root.path().join(format!(".exported.pants-new-{}-0", std::process::id()));
+    let unrelated = root.path().join(".other.pants-old-2147483646-0");
+    for dir in &[&exited, &current, &unrelated] {
+      std::fs::create_dir(dir).unwrap();
+    }
+
+    let testdir = TestDirectory::empty();
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    store
+      .replace_directory(destination.clone(), testdir.digest())
+      .wait()
+      .expect("Error replacing");
+
+    assert!(!exited.exists());
+    assert!(current.exists());
+    assert!(unrelated.exists());
+    assert!(destination.is_dir());
+  }
+
+  #[test]
+  fn replace_directory_failure_preserves_destination() {
+    let root = TempDir::new().unwrap();
+    let destination = root.path().join("exported");
+    std::fs::create_dir(&destination).unwrap();
+    std::fs::write(destination.join("previous"), "previous").unwrap();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    // The Directory's file is not stored, so materializing it fails.
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    store
+      .replace_directory(
+        destination.clone(),
+        TestDirectory::containing_roland().digest(),
+      )
+      .wait()
+      .expect_err("Want error");
+
+    assert_eq!(list_dir(&destination), vec!["previous"]);
+    assert_eq!(list_dir(root.path()), vec!["exported"]);
+  }
+
   #[test]
   fn materialize_directory_executable() {
     let materialize_dir = TempDir::new().unwrap();
@@ -2879,7 +5458,38 @@ mod tests {
   }
 
   #[test]
-  fn works_after_reset_prefork() {
+  fn works_after_pre_fork() {
+    let dir = TempDir::new().unwrap();
+    let cas = new_cas(1024);
+
+    let testdata = TestData::roland();
+    let testdir = TestDirectory::containing_roland();
+
+    let store = new_store(dir.path(), cas.address());
+
+    // Fetches from remote, so initialises both the local and remote ByteStores:
+    assert_eq!(
+      store.load_file_bytes_with(testdata.digest(), |b| b).wait(),
+      Ok(Some(testdata.bytes()))
+    );
+
+    store.pre_fork();
+
+    // Already exists in local store:
+    assert_eq!(
+      store.load_file_bytes_with(testdata.digest(), |b| b).wait(),
+      Ok(Some(testdata.bytes()))
+    );
+
+    // Requires an RPC:
+    assert_eq!(
+      store.load_directory(testdir.digest()).wait(),
+      Ok(Some(testdir.directory()))
+    );
+  }
+
+  #[test]
+  fn works_after_post_fork_in_child() {
     let dir = TempDir::new().unwrap();
     let cas = new_cas(1024);
 
@@ -2894,7 +5504,7 @@ mod tests {
       Ok(Some(testdata.bytes()))
     );
 
-    store.reset_prefork();
+    store.post_fork_in_child().unwrap();
 
     // Already exists in local store:
     assert_eq!(
@@ -2968,6 +5578,152 @@ mod tests {
     );
   }
 
+  #[test]
+  fn materialize_remote_directory_batches_small_files() {
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let cas = StubCAS::with_content(
+      1024,
+      vec![TestData::roland(), TestData::catnip()],
+      vec![TestDirectory::containing_roland(), TestDirectory::recursive()],
+    );
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_store(store_dir.path(), cas.address());
+    store
+      .materialize_directory(
+        materialize_dir.path().to_owned(),
+        TestDirectory::recursive().digest(),
+      )
+      .wait()
+      .expect("Error materializing");
+
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("treats")),
+      catnip.bytes()
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("cats").join("roland")),
+      roland.bytes()
+    );
+    // Both Directories are read individually, but both files are fetched in a single batch.
+    assert_eq!(cas.read_request_count(), 2);
+    assert_eq!(cas.batch_read_blobs_request_count(), 1);
+  }
+
+  #[test]
+  fn materialize_remote_directory_reads_failed_batch_entries_individually() {
+    let materialize_dir = TempDir::new().unwrap();
+
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let cas = StubCAS::with_failing_batch_reads(
+      vec![TestData::roland(), TestData::catnip()],
+      vec![TestDirectory::containing_roland(), TestDirectory::recursive()],
+    );
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_store(store_dir.path(), cas.address());
+    store
+      .materialize_directory(
+        materialize_dir.path().to_owned(),
+        TestDirectory::recursive().digest(),
+      )
+      .wait()
+      .expect("Error materializing");
+
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("treats")),
+      catnip.bytes()
+    );
+    assert_eq!(
+      file_contents(&materialize_dir.path().join("cats").join("roland")),
+      roland.bytes()
+    );
+    // The batch fails, so both files are read individually, as well as both Directories.
+    assert_eq!(cas.batch_read_blobs_request_count(), 1);
+    assert_eq!(cas.read_request_count(), 4);
+  }
+
+  #[test]
+  fn contents_for_remote_directory_batches_small_files() {
+    let roland = TestData::roland();
+    let catnip = TestData::catnip();
+    let cas = StubCAS::with_content(
+      1024,
+      vec![TestData::roland(), TestData::catnip()],
+      vec![TestDirectory::containing_roland()],
+    );
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_store(store_dir.path(), cas.address());
+    let file_contents = store
+      .contents_for_directory(&TestDirectory::recursive().directory())
+      .wait()
+      .expect("Getting FileContents");
+
+    assert_same_filecontents(
+      file_contents,
+      vec![
+        FileContent {
+          path: PathBuf::from("cats").join("roland"),
+          content: roland.bytes(),
+        },
+        FileContent {
+          path: PathBuf::from("treats"),
+          content: catnip.bytes(),
+        },
+      ],
+    );
+    assert_eq!(cas.read_request_count(), 1);
+    assert_eq!(cas.batch_read_blobs_request_count(), 1);
+  }
+
+  #[test]
+  fn file_digests_for_directory() {
+    let testdir = TestDirectory::containing_roland();
+    let recursive_testdir = TestDirectory::recursive();
+
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+    store
+      .record_directory(&recursive_testdir.directory(), false)
+      .wait()
+      .expect("Error saving recursive Directory");
+    store
+      .record_directory(&testdir.directory(), false)
+      .wait()
+      .expect("Error saving Directory");
+
+    // The contents of the files are not required.
+    assert_eq!(
+      store
+        .file_digests_for_directory(recursive_testdir.digest())
+        .wait(),
+      Ok(vec![
+        (
+          PathBuf::from("cats").join("roland"),
+          TestData::roland().digest(),
+        ),
+        (PathBuf::from("treats"), TestData::catnip().digest()),
+      ])
+    );
+  }
+
+  #[test]
+  fn file_digests_for_missing_directory() {
+    let store_dir = TempDir::new().unwrap();
+    let store = new_local_store(store_dir.path());
+
+    let error = store
+      .file_digests_for_directory(TestDirectory::recursive().digest())
+      .wait()
+      .expect_err("Want error");
+    assert!(error.contains("Could not find directory"), error);
+  }
+
   fn assert_same_filecontents(left: Vec<FileContent>, right: Vec<FileContent>) {
     assert_eq!(
       left.len(),
@@ -0,0 +1,104 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::RwLock;
+use std::thread;
+
+use libc;
+
+// Linux retains only the first 15 bytes of the name of a thread.
+const MAX_NAME_BYTES: usize = 15;
+
+lazy_static! {
+  static ref NAME_PREFIX: RwLock<String> = RwLock::new(String::new());
+}
+
+///
+/// Sets a prefix for the names of threads which are subsequently named by `thread_name`, so that
+/// the threads of several processes can be told apart. Because OS tools may show only the first 15
+/// bytes of the name of a thread, the prefix should be short.
+///
+pub fn set_name_prefix(prefix: &str) {
+  *NAME_PREFIX.write().unwrap() = prefix.to_owned();
+}
+
+///
+/// Returns the name of a thread with the given role, such as `store-gc`, or the prefix of the names
+/// of the threads of a pool, such as `io-pool-`.
+///
+/// Naming threads by role allows for telling which subsystem of the engine is busy from OS tools
+/// (such as `top -H`) and debuggers.
+///
+pub fn thread_name(role: &str) -> String {
+  format!("{}{}", *NAME_PREFIX.read().unwrap(), role)
+}
+
+///
+/// Spawns a thread which is named for the given role: see `thread_name`.
+///
+pub fn spawn<F, T>(role: &str, f: F) -> thread::JoinHandle<T>
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  thread::Builder::new()
+    .name(thread_name(role))
+    .spawn(f)
+    .unwrap_or_else(|e| panic!("Failed to spawn a {} thread: {}", role, e))
+}
+
+///
+/// Names the calling thread for the given role (see `thread_name`). Threads spawned by Rust are
+/// named when they are spawned: this is for other threads, such as python threads.
+///
+pub fn set_current_thread_name(role: &str) -> Result<(), String> {
+  let mut name = thread_name(role).into_bytes();
+  name.truncate(MAX_NAME_BYTES);
+  let name = CString::new(name).map_err(|e| format!("Invalid thread name for {}: {}", role, e))?;
+  set_os_thread_name(&name)
+    .map_err(|e| format!("Failed to name the thread for {}: {}", role, e))
+}
+
+#[cfg(target_os = "linux")]
+fn set_os_thread_name(name: &CStr) -> io::Result<()> {
+  let res = unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr() as libc::c_ulong, 0, 0, 0) };
+  if res == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::last_os_error())
+  }
+}
+
+#[cfg(target_os = "macos")]
+fn set_os_thread_name(name: &CStr) -> io::Result<()> {
+  extern "C" {
+    fn pthread_setname_np(name: *const libc::c_char) -> libc::c_int;
+  }
+  let res = unsafe { pthread_setname_np(name.as_ptr()) };
+  if res == 0 {
+    Ok(())
+  } else {
+    Err(io::Error::from_raw_os_error(res))
+  }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn set_os_thread_name(_name: &CStr) -> io::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{spawn, thread_name};
+  use std::thread;
+
+  #[test]
+  fn spawns_named_threads() {
+    let name = spawn("test-role", || thread::current().name().map(str::to_owned))
+      .join()
+      .unwrap();
+    assert_eq!(name, Some(thread_name("test-role")));
+  }
+}
@@ -5,10 +5,13 @@ extern crate boxfuture;
 extern crate fnv;
 extern crate futures;
 extern crate hashing;
+#[macro_use]
+extern crate log;
 extern crate petgraph;
 
 mod node;
 
+use std::cmp;
 use std::collections::binary_heap::BinaryHeap;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{File, OpenOptions};
@@ -16,7 +19,7 @@ use std::hash::BuildHasherDefault;
 use std::io::{self, BufWriter, Write};
 use std::mem;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Mutex, TryLockError};
 use std::time::{Duration, Instant};
 
 use fnv::FnvHasher;
@@ -92,6 +95,8 @@ enum EntryState<N: Node> {
   // completing.
   //
   // The `previous_result` value for a Running node is not a valid value. See NotStarted.
+  //
+  // Sending to `abort` stops the spawned work of the node by dropping it: see `Entry::cancel`.
   Running {
     run_token: RunToken,
     generation: Generation,
@@ -99,6 +104,7 @@ enum EntryState<N: Node> {
     waiters: Vec<oneshot::Sender<Result<(N::Item, Generation), N::Error>>>,
     previous_result: Option<Result<N::Item, N::Error>>,
     dirty: bool,
+    abort: oneshot::Sender<()>,
   },
   // A node that has completed, and then possibly been marked dirty. Because marking a node
   // dirty does not eagerly re-execute any logic, it will stay this way until a caller moves it
@@ -108,6 +114,7 @@ enum EntryState<N: Node> {
     generation: Generation,
     result: Result<N::Item, N::Error>,
     dep_generations: Vec<Generation>,
+    elapsed: Duration,
     dirty: bool,
   },
 }
@@ -120,6 +127,45 @@ impl<N: Node> EntryState<N> {
       previous_result: None,
     }
   }
+
+  ///
+  /// The successful result held by this state, whether or not it is currently valid.
+  ///
+  fn held_item(&self) -> Option<&N::Item> {
+    match self {
+      &EntryState::NotStarted {
+        previous_result: Some(Ok(ref item)),
+        ..
+      }
+      | &EntryState::Running {
+        previous_result: Some(Ok(ref item)),
+        ..
+      }
+      | &EntryState::Completed {
+        result: Ok(ref item),
+        ..
+      } => Some(item),
+      _ => None,
+    }
+  }
+
+  ///
+  /// The result (successful or not) held by this state, whether or not it is currently valid.
+  ///
+  fn held_result(&self) -> Option<Result<&N::Item, &N::Error>> {
+    match self {
+      &EntryState::NotStarted {
+        previous_result: Some(ref result),
+        ..
+      }
+      | &EntryState::Running {
+        previous_result: Some(ref result),
+        ..
+      }
+      | &EntryState::Completed { ref result, .. } => Some(result.as_ref()),
+      _ => None,
+    }
+  }
 }
 
 ///
@@ -151,6 +197,9 @@ pub struct Entry<N: Node> {
   // maps is painful.
   node: EntryKey<N>,
   state: EntryState<N>,
+  // The length of the longest chain of dependencies from a root to this Entry that has been
+  // declared so far.
+  depth: usize,
 }
 
 impl<N: Node> Entry<N> {
@@ -163,6 +212,7 @@ impl<N: Node> Entry<N> {
     Entry {
       node: node,
       state: EntryState::initial(),
+      depth: 0,
     }
   }
 
@@ -188,8 +238,12 @@ impl<N: Node> Entry<N> {
       &EntryKey::Valid(ref n) => {
         let context = context_factory.clone_for(entry_id);
         let node = n.clone();
+        // The work is only aborted by an explicit send: dropping the Sender (as happens whenever
+        // the state changes) lets it run to completion.
+        let (abort, aborted) = oneshot::channel();
+        let aborted = aborted.or_else(|_| future::empty::<(), ()>());
 
-        context_factory.spawn(future::lazy(move || {
+        let work: BoxFuture<(), ()> = future::lazy(move || {
           // If we have previous result generations, compare them to all current dependency
           // generations (which, if they are dirty, will cause recursive cleaning). If they
           // match, we can consider the previous result value to be clean for reuse.
@@ -238,7 +292,8 @@ impl<N: Node> Entry<N> {
                 .to_boxed()
             }
           })
-        }));
+        }).to_boxed();
+        context_factory.spawn(aborted.select2(work).then(|_| Ok(())));
 
         EntryState::Running {
           waiters: Vec::new(),
@@ -247,11 +302,13 @@ impl<N: Node> Entry<N> {
           generation,
           previous_result,
           dirty: false,
+          abort,
         }
       }
       &EntryKey::Cyclic(_) => EntryState::Completed {
         result: Err(N::Error::cyclic()),
         dep_generations: Vec::new(),
+        elapsed: Duration::default(),
         run_token,
         generation,
         dirty: false,
@@ -321,6 +378,7 @@ impl<N: Node> Entry<N> {
         result,
         dep_generations,
         dirty,
+        ..
       } => {
         assert!(
           dirty,
@@ -372,6 +430,9 @@ impl<N: Node> Entry<N> {
   /// result should be used. This special case exists to avoid 1) cloning the result to call this
   /// method, and 2) comparing the current/previous results unnecessarily.
   ///
+  /// If the Node was completing after having been invalidated (ie, it had a previous result),
+  /// returns whether its result changed.
+  ///
   fn complete<C>(
     &mut self,
     context: &C,
@@ -379,7 +440,8 @@ impl<N: Node> Entry<N> {
     result_run_token: RunToken,
     dep_generations: Vec<Generation>,
     result: Option<Result<N::Item, N::Error>>,
-  ) where
+  ) -> Option<bool>
+  where
     C: NodeContext<Node = N>,
   {
     // We care about exactly one case: a Running state with the same run_token. All other states
@@ -389,15 +451,17 @@ impl<N: Node> Entry<N> {
       _ => {
         // We care about exactly one case: a Running state with the same run_token. All other states
         // represent various (legal) race conditions.
-        return;
+        return None;
       }
     }
 
+    let mut changed = None;
     self.state = match mem::replace(&mut self.state, EntryState::initial()) {
       EntryState::Running {
         waiters,
         run_token,
         generation,
+        start_time,
         previous_result,
         dirty,
         ..
@@ -419,13 +483,18 @@ impl<N: Node> Entry<N> {
           let (generation, next_result) = if let Some(result) = result {
             if Some(&result) == previous_result.as_ref() {
               // Node was re-executed, but had the same result value.
+              changed = Some(false);
               (generation, result)
             } else {
+              if previous_result.is_some() {
+                changed = Some(true);
+              }
               (generation.next(), result)
             }
           } else {
             // Node was marked clean.
             // NB: The `expect` here avoids a clone and a comparison: see the method docs.
+            changed = Some(false);
             (
               generation,
               previous_result.expect("A Node cannot be marked clean without a previous result."),
@@ -441,6 +510,7 @@ impl<N: Node> Entry<N> {
           EntryState::Completed {
             result: next_result,
             dep_generations,
+            elapsed: start_time.elapsed(),
             run_token,
             generation,
             dirty: false,
@@ -449,6 +519,7 @@ impl<N: Node> Entry<N> {
       }
       s => s,
     };
+    changed
   }
 
   ///
@@ -489,6 +560,16 @@ impl<N: Node> Entry<N> {
     }
   }
 
+  ///
+  /// If the Node has completed (and has not been dirtied), returns how long it ran for.
+  ///
+  fn elapsed(&self) -> Option<Duration> {
+    match &self.state {
+      &EntryState::Completed { elapsed, dirty, .. } if !dirty => Some(elapsed),
+      _ => None,
+    }
+  }
+
   ///
   /// Clears the state of this Node, forcing it to be recomputed.
   ///
@@ -522,6 +603,71 @@ impl<N: Node> Entry<N> {
     };
   }
 
+  ///
+  /// If this Node is running, stops its work (which drops any Futures that it is waiting on) and
+  /// clears it as `clear` would. Its waiters fail as if it had been invalidated.
+  ///
+  /// Returns whether the Node was running.
+  ///
+  fn cancel(&mut self) -> bool {
+    match mem::replace(&mut self.state, EntryState::initial()) {
+      EntryState::Running {
+        run_token,
+        generation,
+        previous_result,
+        abort,
+        ..
+      } => {
+        // The work may already have completed, in which case there is nothing to stop.
+        let _ = abort.send(());
+        self.state = EntryState::NotStarted {
+          run_token: run_token.next(),
+          generation,
+          previous_result,
+        };
+        true
+      }
+      state => {
+        self.state = state;
+        false
+      }
+    }
+  }
+
+  ///
+  /// If this Node has completed, drops its result (including the previous result that `clear`
+  /// would preserve), forcing it to be recomputed the next time it is requested. Because there is
+  /// then nothing to compare the recomputed result to, it will be considered to have changed.
+  ///
+  /// Returns the estimated heap size of the dropped result, if there was one.
+  ///
+  fn evict(&mut self) -> Option<usize> {
+    let (run_token, generation) = match &self.state {
+      &EntryState::Completed {
+        run_token,
+        generation,
+        ..
+      }
+      | &EntryState::NotStarted {
+        run_token,
+        generation,
+        previous_result: Some(_),
+      } => (run_token, generation),
+      _ => return None,
+    };
+    let bytes = self
+      .state
+      .held_item()
+      .map(|item| N::heap_size(item).1)
+      .unwrap_or(0);
+    self.state = EntryState::NotStarted {
+      run_token: run_token.next(),
+      generation,
+      previous_result: None,
+    };
+    Some(bytes)
+  }
+
   ///
   /// Dirties this Node, which will cause it to examine its dependencies the next time it is
   /// requested, and re-run if any of them have changed generations.
@@ -553,11 +699,128 @@ pub struct InvalidationResult {
   pub dirtied: usize,
 }
 
+///
+/// The results that were dropped from a Graph by `Graph::evict`.
+///
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct EvictionResult {
+  /// The number of Nodes whose results were dropped.
+  pub evicted: usize,
+  /// The estimated bytes of heap memory held by the dropped results: see `Node::heap_size`.
+  pub bytes: usize,
+}
+
+///
+/// Cumulative counts of the effects of invalidation on the Graph, keyed by `Node::type_name`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct InvalidationCounters {
+  /// Nodes that were cleared or dirtied by invalidation.
+  pub invalidated: HashMap<&'static str, usize>,
+  /// Invalidated Nodes that were subsequently re-run or cleaned, and had the same result.
+  pub unchanged: HashMap<&'static str, usize>,
+  /// Invalidated Nodes that were subsequently re-run, and had a different result.
+  pub changed: HashMap<&'static str, usize>,
+}
+
+impl InvalidationCounters {
+  ///
+  /// Returns the counts that have accumulated since the given (earlier) snapshot of these counters.
+  ///
+  pub fn since(&self, earlier: &InvalidationCounters) -> InvalidationCounters {
+    let delta = |current: &HashMap<&'static str, usize>, earlier: &HashMap<&'static str, usize>| {
+      current
+        .iter()
+        .filter_map(|(&type_name, &count)| {
+          let count = count - earlier.get(type_name).cloned().unwrap_or(0);
+          if count > 0 {
+            Some((type_name, count))
+          } else {
+            None
+          }
+        })
+        .collect()
+    };
+    InvalidationCounters {
+      invalidated: delta(&self.invalidated, &earlier.invalidated),
+      unchanged: delta(&self.unchanged, &earlier.unchanged),
+      changed: delta(&self.changed, &earlier.changed),
+    }
+  }
+
+  fn increment(counts: &mut HashMap<&'static str, usize>, type_name: &'static str) {
+    *counts.entry(type_name).or_insert(0) += 1;
+  }
+}
+
+///
+/// The approximate heap memory held by a Graph. See `Graph::heap_usage`.
+///
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeapUsage {
+  /// The bytes held by the Entries of the Graph, their edges, and the index of their Nodes.
+  pub entries: usize,
+  /// The bytes held by the results of Nodes, keyed by the name from `Node::heap_size`.
+  pub results: HashMap<&'static str, usize>,
+}
+
+///
+/// A threshold at which to warn, and a limit past which to fail, for some measure of the size of a
+/// Graph. Either may be None to disable it.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limit {
+  pub warn: Option<usize>,
+  pub fail: Option<usize>,
+}
+
+///
+/// Guards against pathological (usually unboundedly recursive) rules, which would otherwise grow
+/// the Graph until the process runs out of memory.
+///
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GraphLimits {
+  /// The total number of Nodes in the Graph.
+  pub node_count: Limit,
+  /// The length of the longest chain of dependencies from a root.
+  pub depth: Limit,
+}
+
+///
+/// Limits how much of the Graph a trace renders: the complete trace of a large Graph may run to
+/// thousands of lines, which bury the Nodes that actually failed.
+///
+#[derive(Clone, Debug, Default)]
+pub struct TraceOptions {
+  /// The maximum number of Nodes to render on the path to each failure: the Nodes furthest from
+  /// the failure are omitted first.
+  pub max_depth: Option<usize>,
+  /// If non-empty, the `Node::type_name`s of the Nodes to render (besides the failures themselves).
+  pub node_types: Vec<String>,
+  /// Whether to render only the path to the first failure found, rather than to each failure.
+  pub first_failure_only: bool,
+}
+
 type Nodes<N> = HashMap<EntryKey<N>, EntryId>;
 
+// The number of recently completed entries to retain for `Graph::dump`.
+const RECENTLY_COMPLETED_LIMIT: usize = 100;
+
 struct InnerGraph<N: Node> {
   nodes: Nodes<N>,
   pg: PGraph<N>,
+  invalidation_counters: InvalidationCounters,
+  // The most recently completed entries, most recent last.
+  recently_completed: VecDeque<EntryId>,
+  // When an entry last completed.
+  last_completion: Option<Instant>,
+  limits: GraphLimits,
+  // Whether the warning threshold of `limits.depth` has been crossed (and reported).
+  depth_warned: bool,
+  // The estimated bytes of heap memory held by the results of entries, keyed by the name from
+  // `Node::heap_size`. Maintained by `update_entry` as results are stored and dropped, so that
+  // `heap_usage` does not need to walk the Graph.
+  result_bytes: HashMap<&'static str, usize>,
 }
 
 impl<N: Node> InnerGraph<N> {
@@ -574,6 +837,28 @@ impl<N: Node> InnerGraph<N> {
     self.pg.node_weight_mut(id)
   }
 
+  ///
+  /// Applies the given function to the Entry with the given id, and updates the accounting of the
+  /// heap memory held by results for any change to the result that the Entry holds.
+  ///
+  /// Only completing and evicting an Entry change the result that it holds (clearing, cancelling,
+  /// dirtying and re-running it preserve its previous result), so only those need to use this.
+  ///
+  fn update_entry<T, F: FnOnce(&mut Entry<N>) -> T>(&mut self, id: EntryId, f: F) -> Option<T> {
+    let result_bytes = &mut self.result_bytes;
+    self.pg.node_weight_mut(id).map(|entry| {
+      let before = entry.state.held_item().map(N::heap_size);
+      let output = f(entry);
+      if let Some((name, size)) = before {
+        *result_bytes.entry(name).or_insert(0) -= size;
+      }
+      if let Some((name, size)) = entry.state.held_item().map(N::heap_size) {
+        *result_bytes.entry(name).or_insert(0) += size;
+      }
+      output
+    })
+  }
+
   fn unsafe_entry_for_id(&self, id: EntryId) -> &Entry<N> {
     self
       .pg
@@ -596,6 +881,97 @@ impl<N: Node> InnerGraph<N> {
     id
   }
 
+  ///
+  /// Checks that requesting the given Node (as a dependency of `src_id`, if any) would not exceed
+  /// the GraphLimits, and warns the first time that it crosses one of their warning thresholds.
+  ///
+  /// Returns the depth that the Node would have.
+  ///
+  fn check_limits(&mut self, src_id: Option<EntryId>, node: &N) -> Result<usize, String> {
+    let depth = src_id
+      .and_then(|src_id| self.entry_for_id(src_id))
+      .map(|src| src.depth + 1)
+      .unwrap_or(0);
+
+    if !self.nodes.contains_key(&EntryKey::Valid(node.clone())) {
+      let count = self.nodes.len() + 1;
+      if let Some(fail) = self.limits.node_count.fail.filter(|&fail| count > fail) {
+        return Err(format!(
+          "The graph exceeded its limit of {} nodes while requesting {} for {}.",
+          fail,
+          node.format(),
+          self.roots_str(src_id)
+        ));
+      }
+      if self.limits.node_count.warn == Some(count) {
+        warn!(
+          "The graph has grown to {} nodes while requesting {} for {}.",
+          count,
+          node.format(),
+          self.roots_str(src_id)
+        );
+      }
+    }
+
+    if let Some(fail) = self.limits.depth.fail.filter(|&fail| depth > fail) {
+      return Err(format!(
+        "The graph exceeded its limit of {} on the depth of dependencies while requesting {} for \
+         {}. This usually indicates that a rule (indirectly) requests itself without terminating.",
+        fail,
+        node.format(),
+        self.roots_str(src_id)
+      ));
+    }
+    if let Some(warn) = self.limits.depth.warn.filter(|&warn| depth > warn) {
+      if !self.depth_warned {
+        self.depth_warned = true;
+        warn!(
+          "Dependencies in the graph are more than {} deep while requesting {} for {}.",
+          warn,
+          node.format(),
+          self.roots_str(src_id)
+        );
+      }
+    }
+
+    Ok(depth)
+  }
+
+  ///
+  /// Renders the roots which (transitively) depend on the given Entry, or the Entry itself if it
+  /// has no dependents.
+  ///
+  fn roots_str(&self, id: Option<EntryId>) -> String {
+    // The number of roots to render before eliding the rest.
+    const LIMIT: usize = 3;
+
+    let id = match id {
+      Some(id) => id,
+      None => return "a new root".to_string(),
+    };
+    let mut roots = VecDeque::new();
+    roots.push_back(id);
+    let roots = self
+      .walk(roots, Direction::Incoming)
+      .filter(|&eid| {
+        self
+          .pg
+          .neighbors_directed(eid, Direction::Incoming)
+          .next()
+          .is_none()
+      })
+      .map(|eid| self.unsafe_entry_for_id(eid).node.content().format())
+      .collect::<Vec<_>>();
+    let rendered = roots.iter().take(LIMIT).cloned().collect::<Vec<_>>().join(", ");
+    if roots.len() > LIMIT {
+      format!("roots {}, and {} more", rendered, roots.len() - LIMIT)
+    } else if roots.len() == 1 {
+      format!("root {}", rendered)
+    } else {
+      format!("roots {}", rendered)
+    }
+  }
+
   ///
   /// Detect whether adding an edge from src to dst would create a cycle.
   ///
@@ -642,19 +1018,64 @@ impl<N: Node> InnerGraph<N> {
     }
   }
 
+  fn cancel(&mut self, roots: &[N]) -> usize {
+    let root_ids = roots
+      .iter()
+      .cloned()
+      .filter_map(|node| self.entry_id(&EntryKey::Valid(node)))
+      .cloned()
+      .collect();
+    let reachable = self
+      .walk(root_ids, Direction::Outgoing)
+      .collect::<Vec<_>>();
+    reachable
+      .into_iter()
+      .filter(|&eid| {
+        self
+          .entry_for_id_mut(eid)
+          .map(|entry| entry.cancel())
+          .unwrap_or(false)
+      })
+      .count()
+  }
+
+  fn evict<P: Fn(&N) -> bool>(&mut self, predicate: P) -> EvictionResult {
+    let mut result = EvictionResult::default();
+    let ids = self
+      .nodes
+      .iter()
+      .filter(|&(key, _)| predicate(key.content()))
+      .map(|(_, &eid)| eid)
+      .collect::<Vec<_>>();
+    for eid in ids {
+      if let Some(bytes) = self.update_entry(eid, |entry| entry.evict()).and_then(|b| b) {
+        result.evicted += 1;
+        result.bytes += bytes;
+      }
+    }
+    result
+  }
+
   ///
   /// Clears the values of all "invalidation root" Nodes and dirties their transitive dependents.
   ///
   /// An "invalidation root" is a Node in the graph which can be invalidated for a reason other
   /// than having had its dependencies changed.
   ///
-  fn invalidate_from_roots<P: Fn(&N) -> bool>(&mut self, predicate: P) -> InvalidationResult {
+  fn invalidate_from_roots<P: Fn(&N, Option<Result<&N::Item, &N::Error>>) -> bool>(
+    &mut self,
+    predicate: P,
+  ) -> InvalidationResult {
     // Collect all entries that will be cleared.
     let root_ids: HashSet<_, FNV> = self
       .nodes
       .iter()
       .filter_map(|(entry, &entry_id)| {
-        if predicate(entry.content()) {
+        let result = self
+          .pg
+          .node_weight(entry_id)
+          .and_then(|stored| stored.state.held_result());
+        if predicate(entry.content(), result) {
           Some(entry_id)
         } else {
           None
@@ -671,6 +1092,14 @@ impl<N: Node> InnerGraph<N> {
       cleared: root_ids.len(),
       dirtied: transitive_ids.len(),
     };
+    for id in root_ids.iter().chain(transitive_ids.iter()) {
+      if let Some(entry) = self.pg.node_weight(*id) {
+        InvalidationCounters::increment(
+          &mut self.invalidation_counters.invalidated,
+          entry.node.content().type_name(),
+        );
+      }
+    }
 
     // Clear roots and remove their outbound edges.
     for id in &root_ids {
@@ -698,6 +1127,7 @@ impl<N: Node> InnerGraph<N> {
     &self,
     mut visualizer: V,
     roots: &[N],
+    min_duration: Duration,
     path: &Path,
   ) -> io::Result<()> {
     let file = try!(File::create(path));
@@ -711,27 +1141,72 @@ impl<N: Node> InnerGraph<N> {
     try!(f.write_all(b"  concentrate=true;\n"));
     try!(f.write_all(b"  rankdir=TB;\n"));
 
-    let mut format_color = |entry: &Entry<N>| visualizer.color(entry.node.content(), entry.peek());
-
     let root_entries = roots
       .iter()
       .filter_map(|n| self.entry_id(&EntryKey::Valid(n.clone())))
       .cloned()
       .collect();
 
-    for eid in self.walk(root_entries, Direction::Outgoing) {
+    // Collect the entries to render, and the sorted durations of the completed entries in order to
+    // compute the percentile of each.
+    let entry_ids = self
+      .walk(root_entries, Direction::Outgoing)
+      .collect::<Vec<_>>();
+    let mut durations = entry_ids
+      .iter()
+      .filter_map(|eid| self.unsafe_entry_for_id(*eid).elapsed())
+      .collect::<Vec<_>>();
+    durations.sort();
+    let percentile = |elapsed: Duration| {
+      let below = durations.iter().take_while(|d| **d < elapsed).count();
+      below as f64 / cmp::max(durations.len(), 1) as f64
+    };
+    // Entries that have not completed are only rendered if no threshold was set.
+    let is_visible = |entry: &Entry<N>| match entry.elapsed() {
+      Some(elapsed) => elapsed >= min_duration,
+      None => min_duration == Duration::default(),
+    };
+
+    for eid in entry_ids {
       let entry = self.unsafe_entry_for_id(eid);
+      if !is_visible(entry) {
+        continue;
+      }
       let node_str = entry.format();
+      let result = entry.peek();
+
+      // Label the node with its duration and (if it referenced stored content) output size.
+      let mut label = node_str.clone();
+      if let Some(elapsed) = entry.elapsed() {
+        label.push_str(&format!("\\n{} ms", Self::duration_millis(elapsed)));
+      }
+      let digests = result
+        .clone()
+        .and_then(|r| r.ok())
+        .map(N::digests)
+        .unwrap_or_else(Vec::new);
+      if !digests.is_empty() {
+        let size: usize = digests.iter().map(|d| d.1).sum();
+        label.push_str(&format!(", {} bytes", size));
+      }
 
       // Write the node header.
       try!(f.write_fmt(format_args!(
-        "  \"{}\" [style=filled, fillcolor={}];\n",
+        "  \"{}\" [style=filled, fillcolor={}, label=\"{}\"];\n",
         node_str,
-        format_color(entry)
+        visualizer.color(
+          entry.node.content(),
+          result,
+          entry.elapsed().map(&percentile)
+        ),
+        label
       )));
 
       for dep_id in self.pg.neighbors(eid) {
         let dep_entry = self.unsafe_entry_for_id(dep_id);
+        if !is_visible(dep_entry) {
+          continue;
+        }
 
         // Write an entry per edge.
         let dep_str = dep_entry.format();
@@ -743,8 +1218,87 @@ impl<N: Node> InnerGraph<N> {
     Ok(())
   }
 
-  fn trace<T: NodeTracer<N>>(&self, roots: &[N], file_path: &Path) -> Result<(), String> {
-    let root_ids: HashSet<EntryId, FNV> = roots
+  ///
+  /// Returns the running entries, and how long they have been running for, longest first.
+  ///
+  fn running(&self, now: &Instant) -> Vec<(EntryId, Duration)> {
+    let mut running = self
+      .pg
+      .node_indices()
+      .filter_map(|eid| {
+        self
+          .unsafe_entry_for_id(eid)
+          .current_running_duration(now)
+          .map(|duration| (eid, duration))
+      })
+      .collect::<Vec<_>>();
+    running.sort_by(|a, b| b.1.cmp(&a.1));
+    running
+  }
+
+  ///
+  /// Writes a plain text description of the in-flight state of the graph: each running entry and
+  /// the incomplete dependencies that it is waiting on, followed by the most recently completed
+  /// entries.
+  ///
+  fn dump(&self, path: &Path) -> io::Result<()> {
+    let file = try!(File::create(path));
+    let mut f = BufWriter::new(file);
+
+    let running = self.running(&Instant::now());
+    try!(writeln!(f, "Running nodes ({}):", running.len()));
+    for (eid, duration) in running {
+      try!(writeln!(
+        f,
+        "  {} (running for {} ms)",
+        self.unsafe_entry_for_id(eid).node.content().format(),
+        Self::duration_millis(duration)
+      ));
+      for dep_id in self.pg.neighbors(eid) {
+        let dep_entry = self.unsafe_entry_for_id(dep_id);
+        let dep_state = match dep_entry.state {
+          EntryState::NotStarted { .. } => "not started",
+          EntryState::Running { .. } => "running",
+          EntryState::Completed { dirty: true, .. } => "dirty",
+          EntryState::Completed { .. } => continue,
+        };
+        try!(writeln!(
+          f,
+          "    waiting on: {} ({})",
+          dep_entry.node.content().format(),
+          dep_state
+        ));
+      }
+    }
+
+    try!(writeln!(
+      f,
+      "\nRecently completed nodes ({}, most recent first):",
+      self.recently_completed.len()
+    ));
+    for eid in self.recently_completed.iter().rev() {
+      let entry = self.unsafe_entry_for_id(*eid);
+      let elapsed = match entry.elapsed() {
+        Some(elapsed) => format!("{} ms", Self::duration_millis(elapsed)),
+        None => "since re-run or dirtied".to_string(),
+      };
+      try!(writeln!(f, "  {} ({})", entry.node.content().format(), elapsed));
+    }
+    Ok(())
+  }
+
+  fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos()) / 1_000_000
+  }
+
+  fn trace<T: NodeTracer<N>>(
+    &self,
+    roots: &[N],
+    file_path: &Path,
+    options: &TraceOptions,
+  ) -> Result<(), String> {
+    // NB: The roots are kept in order, so that the "first" failure is deterministic.
+    let root_ids: Vec<EntryId> = roots
       .into_iter()
       .filter_map(|nk| self.entry_id(&EntryKey::Valid(nk.clone())))
       .cloned()
@@ -823,25 +1377,63 @@ impl<N: Node> InnerGraph<N> {
       };
 
       // Render the path.
+      let (path, omitted) = self.trace_filter_path(path, options);
       self
-        .trace_render_path_to_file::<T>(&path, file_path)
+        .trace_render_path_to_file::<T>(&path, omitted, file_path)
         .map_err(|e| format!("Failed to render trace to {:?}: {}", file_path, e))?;
+
+      if options.first_failure_only {
+        break;
+      }
     }
 
     Ok(())
   }
 
   ///
-  /// Renders a Graph path to the given file path.
+  /// Applies the given TraceOptions to a Graph path that ends in a failure, returning the path to
+  /// render, and the number of Nodes that were omitted from it. The failure is always rendered.
+  ///
+  fn trace_filter_path(&self, path: Vec<EntryId>, options: &TraceOptions) -> (Vec<EntryId>, usize) {
+    let len = path.len();
+    let mut filtered: Vec<EntryId> = path
+      .into_iter()
+      .enumerate()
+      .filter(|&(index, id)| {
+        index == len - 1
+          || options.node_types.is_empty()
+          || {
+            let type_name = self.unsafe_entry_for_id(id).node.content().type_name();
+            options.node_types.iter().any(|t| t == type_name)
+          }
+      })
+      .map(|(_, id)| id)
+      .collect();
+    if let Some(max_depth) = options.max_depth {
+      let excess = filtered.len().saturating_sub(cmp::max(max_depth, 1));
+      filtered.drain(..excess);
+    }
+    let omitted = len - filtered.len();
+    (filtered, omitted)
+  }
+
+  ///
+  /// Renders a Graph path to the given file path, noting the number of Nodes that were omitted
+  /// from it.
   ///
   fn trace_render_path_to_file<T: NodeTracer<N>>(
     &self,
     path: &[EntryId],
+    omitted: usize,
     file_path: &Path,
   ) -> io::Result<()> {
     let file = try!(OpenOptions::new().append(true).open(file_path));
     let mut f = BufWriter::new(file);
 
+    if omitted > 0 {
+      try!(writeln!(&mut f, "({} Nodes omitted)", omitted));
+    }
+
     let _format = |eid: EntryId, depth: usize, is_last: bool| -> String {
       let entry = self.unsafe_entry_for_id(eid);
       let indent = "  ".repeat(depth);
@@ -909,10 +1501,7 @@ impl<N: Node> InnerGraph<N> {
 
       if deps.peek().is_none() {
         // If the entry has no running deps, it is a leaf. Emit it.
-        res.push((
-          self.unsafe_entry_for_id(id).node.content().format(),
-          duration,
-        ));
+        res.push((progress_str(self.unsafe_entry_for_id(id).node.content()), duration));
         if res.len() >= k {
           break;
         }
@@ -943,6 +1532,19 @@ impl<N: Node> InnerGraph<N> {
       .collect()
   }
 
+  fn heap_usage(&self) -> HeapUsage {
+    // Each node is stored in the petgraph (along with the heads of its adjacency lists) and in
+    // the index of Nodes, and each edge is stored in the petgraph along with its adjacency links.
+    let node_size = mem::size_of::<Entry<N>>()
+      + mem::size_of::<EntryKey<N>>()
+      + 3 * mem::size_of::<EntryId>();
+    let edge_size = 4 * mem::size_of::<EntryId>();
+    HeapUsage {
+      entries: self.pg.node_count() * node_size + self.pg.edge_count() * edge_size,
+      results: self.result_bytes.clone(),
+    }
+  }
+
   fn digests_internal<'g>(
     &'g self,
     entryids: Vec<EntryId>,
@@ -950,9 +1552,9 @@ impl<N: Node> InnerGraph<N> {
     entryids
       .into_iter()
       .filter_map(move |eid| self.entry_for_id(eid))
-      .filter_map(|entry| match entry.peek() {
-        Some(Ok(item)) => N::digest(item),
-        _ => None,
+      .flat_map(|entry| match entry.peek() {
+        Some(Ok(item)) => N::digests(item),
+        _ => vec![],
       })
   }
 }
@@ -966,9 +1568,19 @@ pub struct Graph<N: Node> {
 
 impl<N: Node> Graph<N> {
   pub fn new() -> Graph<N> {
+    Graph::new_with_limits(GraphLimits::default())
+  }
+
+  pub fn new_with_limits(limits: GraphLimits) -> Graph<N> {
     let inner = InnerGraph {
       nodes: HashMap::default(),
       pg: DiGraph::new(),
+      invalidation_counters: InvalidationCounters::default(),
+      recently_completed: VecDeque::with_capacity(RECENTLY_COMPLETED_LIMIT),
+      last_completion: None,
+      limits: limits,
+      depth_warned: false,
+      result_bytes: HashMap::new(),
     };
     Graph {
       inner: Mutex::new(inner),
@@ -984,12 +1596,18 @@ impl<N: Node> Graph<N> {
   /// In the context of the given src Node, declare a dependency on the given dst Node and
   /// begin its execution if it has not already started.
   ///
+  /// Fails without declaring the dependency if it would exceed the GraphLimits.
+  ///
   pub fn get<C>(&self, src_id: EntryId, context: &C, dst_node: N) -> BoxFuture<N::Item, N::Error>
   where
     C: NodeContext<Node = N>,
   {
     // Get or create the destination, and then insert the dep and return its state.
     let mut inner = self.inner.lock().unwrap();
+    let depth = match inner.check_limits(Some(src_id), &dst_node) {
+      Ok(depth) => depth,
+      Err(msg) => return future::err(N::Error::limit_exceeded(msg)).to_boxed(),
+    };
     let dst_id = {
       // TODO: doing cycle detection under the lock... unfortunate, but probably unavoidable
       // without a much more complicated algorithm.
@@ -1006,6 +1624,7 @@ impl<N: Node> Graph<N> {
     // Declare the dep, and return the state of the destination.
     inner.pg.add_edge(src_id, dst_id, ());
     if let Some(entry) = inner.entry_for_id_mut(dst_id) {
+      entry.depth = cmp::max(entry.depth, depth);
       entry.get(context, dst_id).map(|(res, _)| res).to_boxed()
     } else {
       future::err(N::Error::invalidated()).to_boxed()
@@ -1020,6 +1639,9 @@ impl<N: Node> Graph<N> {
     C: NodeContext<Node = N>,
   {
     let mut inner = self.inner.lock().unwrap();
+    if let Err(msg) = inner.check_limits(None, &node) {
+      return future::err(N::Error::limit_exceeded(msg)).to_boxed();
+    }
     let id = inner.ensure_entry(EntryKey::Valid(node));
     if let Some(entry) = inner.entry_for_id_mut(id) {
       entry.get(context, id).map(|(res, _)| res).to_boxed()
@@ -1115,8 +1737,33 @@ impl<N: Node> Graph<N> {
       .filter_map(|dep_id| inner.entry_for_id(dep_id))
       .map(|entry| entry.generation())
       .collect();
-    if let Some(entry) = inner.entry_for_id_mut(entry_id) {
-      entry.complete(context, entry_id, run_token, dep_generations, result);
+    let outcome = inner
+      .update_entry(entry_id, |entry| {
+        entry
+          .complete(context, entry_id, run_token, dep_generations, result)
+          .map(|changed| (entry.node.content().type_name(), changed))
+      })
+      .and_then(|outcome| outcome);
+    if let Some((type_name, changed)) = outcome {
+      let counters = &mut inner.invalidation_counters;
+      if changed {
+        InvalidationCounters::increment(&mut counters.changed, type_name);
+      } else {
+        InvalidationCounters::increment(&mut counters.unchanged, type_name);
+      }
+    }
+    // If this run completed the entry (rather than being discarded, or re-running it because it
+    // was dirtied), record it.
+    let completed = inner
+      .entry_for_id(entry_id)
+      .map(|entry| entry.run_token() == run_token && entry.elapsed().is_some())
+      .unwrap_or(false);
+    if completed {
+      if inner.recently_completed.len() >= RECENTLY_COMPLETED_LIMIT {
+        inner.recently_completed.pop_front();
+      }
+      inner.recently_completed.push_back(entry_id);
+      inner.last_completion = Some(Instant::now());
     }
   }
 
@@ -1128,24 +1775,131 @@ impl<N: Node> Graph<N> {
     inner.clear()
   }
 
-  pub fn invalidate_from_roots<P: Fn(&N) -> bool>(&self, predicate: P) -> InvalidationResult {
+  ///
+  /// Stops the work of the running Nodes that are reachable from the given roots, so that an
+  /// execution which is being abandoned does not continue to consume resources. The Nodes are
+  /// cleared, and will re-run if they are requested again.
+  ///
+  /// Other requests that were waiting on the cancelled Nodes fail as if they had been invalidated.
+  ///
+  /// Returns the number of Nodes that were cancelled.
+  ///
+  pub fn cancel(&self, roots: &[N]) -> usize {
     let mut inner = self.inner.lock().unwrap();
-    inner.invalidate_from_roots(predicate)
+    inner.cancel(roots)
   }
 
-  pub fn trace<T: NodeTracer<N>>(&self, roots: &[N], path: &Path) -> Result<(), String> {
-    let inner = self.inner.lock().unwrap();
-    inner.trace::<T>(roots, path)
+  ///
+  /// Drops the results of the completed Nodes that match the predicate, in order to release the
+  /// memory that they hold: they are recomputed if they are requested again. Unlike invalidation,
+  /// this does not affect the Nodes that depend on them, which keep their own results.
+  ///
+  /// Running Nodes are not affected.
+  ///
+  pub fn evict<P: Fn(&N) -> bool>(&self, predicate: P) -> EvictionResult {
+    let mut inner = self.inner.lock().unwrap();
+    inner.evict(predicate)
   }
 
-  pub fn visualize<V: NodeVisualizer<N>>(
+  pub fn invalidate_from_roots<P: Fn(&N) -> bool>(&self, predicate: P) -> InvalidationResult {
+    let mut inner = self.inner.lock().unwrap();
+    inner.invalidate_from_roots(|node, _| predicate(node))
+  }
+
+  ///
+  /// Like `invalidate_from_roots`, but the predicate is additionally given the result (if any, and
+  /// whether successful or not) that is currently memoized for each Node.
+  ///
+  pub fn invalidate_from_roots_with_results<
+    P: Fn(&N, Option<Result<&N::Item, &N::Error>>) -> bool,
+  >(
+    &self,
+    predicate: P,
+  ) -> InvalidationResult {
+    let mut inner = self.inner.lock().unwrap();
+    inner.invalidate_from_roots(predicate)
+  }
+
+  ///
+  /// Returns a snapshot of the cumulative invalidation counters for this Graph. Use
+  /// `InvalidationCounters::since` to compute the counts for a particular window of time.
+  ///
+  pub fn invalidation_counters(&self) -> InvalidationCounters {
+    let inner = self.inner.lock().unwrap();
+    inner.invalidation_counters.clone()
+  }
+
+  pub fn trace<T: NodeTracer<N>>(
+    &self,
+    roots: &[N],
+    path: &Path,
+    options: &TraceOptions,
+  ) -> Result<(), String> {
+    let inner = self.inner.lock().unwrap();
+    inner.trace::<T>(roots, path, options)
+  }
+
+  ///
+  /// Renders the sub-graph reachable from the given roots to a GraphViz file. Nodes that ran for
+  /// less than `min_duration` (as well as Nodes that have not completed, if a non-zero
+  /// `min_duration` is set) are omitted.
+  ///
+  pub fn visualize<V: NodeVisualizer<N>>(
     &self,
     visualizer: V,
     roots: &[N],
+    min_duration: Duration,
     path: &Path,
   ) -> io::Result<()> {
     let inner = self.inner.lock().unwrap();
-    inner.visualize(visualizer, roots, path)
+    inner.visualize(visualizer, roots, min_duration, path)
+  }
+
+  ///
+  /// Writes the running Nodes (and the incomplete dependencies that they are waiting on) and the
+  /// most recently completed Nodes to a file, for post-mortem analysis of crashes and hangs.
+  ///
+  /// Because this is intended to be used while the process is in a bad state, it does not block
+  /// waiting for the Graph lock (returning an error if it is held), and it ignores poisoning.
+  ///
+  pub fn dump(&self, path: &Path) -> io::Result<()> {
+    let inner = match self.inner.try_lock() {
+      Ok(inner) => inner,
+      Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+      Err(TryLockError::WouldBlock) => {
+        return Err(io::Error::new(
+          io::ErrorKind::WouldBlock,
+          "The Graph lock is currently held.",
+        ))
+      }
+    };
+    inner.dump(path)
+  }
+
+  ///
+  /// Returns the running Nodes (described for users where possible), and how long they have been
+  /// running for, longest first.
+  ///
+  pub fn running_nodes(&self) -> Vec<(String, Duration)> {
+    let inner = self.inner.lock().unwrap();
+    inner
+      .running(&Instant::now())
+      .into_iter()
+      .map(|(eid, duration)| {
+        (
+          progress_str(inner.unsafe_entry_for_id(eid).node.content()),
+          duration,
+        )
+      })
+      .collect()
+  }
+
+  ///
+  /// Returns when a Node last completed, if ever.
+  ///
+  pub fn last_completion(&self) -> Option<Instant> {
+    let inner = self.inner.lock().unwrap();
+    inner.last_completion
   }
 
   pub fn heavy_hitters(&self, roots: &[N], k: usize) -> Vec<(String, Duration)> {
@@ -1162,6 +1916,23 @@ impl<N: Node> Graph<N> {
     let inner = self.inner.lock().unwrap();
     inner.all_digests()
   }
+
+  ///
+  /// Returns an estimate of the heap memory held by the Graph, with the memory held by the
+  /// results of its Nodes broken down by the kind of result.
+  ///
+  pub fn heap_usage(&self) -> HeapUsage {
+    let inner = self.inner.lock().unwrap();
+    inner.heap_usage()
+  }
+}
+
+///
+/// Renders a Node for users watching a run: by its description if it has one, and otherwise by its
+/// key.
+///
+fn progress_str<N: Node>(node: &N) -> String {
+  node.user_facing_name().unwrap_or_else(|| node.format())
 }
 
 ///
@@ -1200,10 +1971,15 @@ mod tests {
   extern crate rand;
 
   use std::cmp;
-  use std::collections::HashSet;
+  use std::collections::{HashMap, HashSet};
+  use std::env;
+  use std::fs;
+  use std::mem;
+  use std::process;
+  use std::sync::atomic::{AtomicBool, Ordering};
   use std::sync::{mpsc, Arc, Mutex};
   use std::thread;
-  use std::time::Duration;
+  use std::time::{Duration, Instant};
 
   use boxfuture::{BoxFuture, Boxable};
   use futures::future::{self, Future};
@@ -1211,7 +1987,10 @@ mod tests {
 
   use self::rand::Rng;
 
-  use super::{EntryId, Graph, InvalidationResult, Node, NodeContext, NodeError};
+  use super::{
+    EntryId, EvictionResult, Graph, GraphLimits, HeapUsage, InvalidationCounters,
+    InvalidationResult, Limit, Node, NodeContext, NodeError, NodeTracer, TraceOptions,
+  };
 
   #[test]
   fn create() {
@@ -1223,6 +2002,195 @@ mod tests {
     );
   }
 
+  #[test]
+  fn running_nodes_and_last_completion() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(None, graph.last_completion());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert!(graph.last_completion().is_some());
+    assert!(graph.running_nodes().is_empty());
+  }
+
+  #[test]
+  fn cancel_stops_running_nodes() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new_with_block_at(0, TNode(0), graph.clone());
+    let root = graph.create(TNode(2), &context);
+    wait_until(|| graph.running_nodes().len() == 3);
+
+    // Cancelling the root stops the blocked work, and fails the waiters of the cancelled Nodes.
+    assert_eq!(graph.cancel(&[TNode(2)]), 3);
+    assert_eq!(root.wait(), Err(TError::Invalidated));
+    wait_until(|| context.blocked_work_dropped.load(Ordering::SeqCst));
+    assert!(graph.running_nodes().is_empty());
+    assert_eq!(graph.cancel(&[TNode(2)]), 0);
+
+    // The cancelled Nodes re-run when they are next requested.
+    let context = TContext::new(1, graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 1), T(1, 1), T(2, 1)])
+    );
+  }
+
+  #[test]
+  fn depth_limit() {
+    let limits = GraphLimits {
+      depth: Limit {
+        warn: None,
+        fail: Some(2),
+      },
+      ..GraphLimits::default()
+    };
+
+    let graph = Arc::new(Graph::new_with_limits(limits));
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+
+    let graph = Arc::new(Graph::new_with_limits(limits));
+    let context = TContext::new(0, graph.clone());
+    match graph.create(TNode(3), &context).wait() {
+      Err(TError::LimitExceeded(msg)) => assert!(msg.contains("for root TNode(3)")),
+      res => panic!("Expected the depth limit to be exceeded, got: {:?}", res),
+    }
+  }
+
+  #[test]
+  fn node_count_limit() {
+    let graph = Arc::new(Graph::new_with_limits(GraphLimits {
+      node_count: Limit {
+        warn: None,
+        fail: Some(3),
+      },
+      ..GraphLimits::default()
+    }));
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    match graph.create(TNode(3), &context).wait() {
+      Err(TError::LimitExceeded(msg)) => assert!(msg.contains("limit of 3 nodes")),
+      res => panic!("Expected the node count limit to be exceeded, got: {:?}", res),
+    }
+  }
+
+  #[test]
+  fn dump() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+
+    let path = env::temp_dir().join(format!("graph-dump-test-{}.txt", process::id()));
+    graph.dump(&path).unwrap();
+    let dump = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(dump.contains("Running nodes (0):"));
+    // The most recently completed Node is the root, which completes after its dependencies.
+    let completed = dump
+      .lines()
+      .skip_while(|line| !line.starts_with("Recently"))
+      .skip(1)
+      .collect::<Vec<_>>();
+    assert_eq!(3, completed.len());
+    assert!(completed[0].starts_with("  TNode(2)"));
+  }
+
+  #[test]
+  fn trace_options() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(
+      graph.create(TNode(3), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0)])
+    );
+
+    let trace = |options: TraceOptions| {
+      let path = env::temp_dir().join(format!("graph-trace-test-{}.txt", process::id()));
+      fs::write(&path, "").unwrap();
+      graph.trace::<TTracer>(&[TNode(3)], &path, &options).unwrap();
+      let trace = fs::read_to_string(&path).unwrap();
+      fs::remove_file(&path).unwrap();
+      trace
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().to_owned())
+        .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+      trace(TraceOptions::default()),
+      vec![
+        "Computing TNode(3)",
+        "Computing TNode(2)",
+        "Computing TNode(1)",
+        "Computing TNode(0)",
+        "[T(0, 0)]",
+      ]
+    );
+    assert_eq!(
+      trace(TraceOptions {
+        max_depth: Some(2),
+        ..TraceOptions::default()
+      }),
+      vec![
+        "(2 Nodes omitted)",
+        "Computing TNode(1)",
+        "Computing TNode(0)",
+        "[T(0, 0)]",
+      ]
+    );
+    // The failure itself is rendered regardless of its type.
+    assert_eq!(
+      trace(TraceOptions {
+        node_types: vec!["Other".to_owned()],
+        ..TraceOptions::default()
+      }),
+      vec!["(3 Nodes omitted)", "Computing TNode(0)", "[T(0, 0)]"]
+    );
+  }
+
+  #[test]
+  fn heap_usage() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+    assert_eq!(graph.heap_usage(), HeapUsage::default());
+
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    let usage = graph.heap_usage();
+    assert!(usage.entries > 0);
+    // The three Nodes hold one, two and three tokens respectively.
+    let results = usage.results["Vec<T>"];
+    assert!(results >= 6 * mem::size_of::<T>());
+
+    // Cleared Nodes continue to hold their previous results.
+    graph.invalidate_from_roots(|&TNode(n)| n == 0);
+    assert_eq!(graph.heap_usage(), usage);
+
+    // Evicted results are no longer counted, and are counted again once they are recomputed.
+    graph.evict(|_| true);
+    assert_eq!(graph.heap_usage().results["Vec<T>"], 0);
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(graph.heap_usage(), usage);
+  }
+
   #[test]
   fn invalidate_and_clean() {
     let graph = Arc::new(Graph::new());
@@ -1252,6 +2220,105 @@ mod tests {
     assert_eq!(context.runs(), vec![TNode(2), TNode(1), TNode(0), TNode(1)]);
   }
 
+  #[test]
+  fn evict() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    let usage = graph.heap_usage();
+
+    // Evict the lower two Nodes, which releases their results without affecting the upper Node.
+    let result = graph.evict(|&TNode(n)| n < 2);
+    assert_eq!(result.evicted, 2);
+    assert_eq!(
+      result.bytes,
+      usage.results["Vec<T>"] - graph.heap_usage().results["Vec<T>"]
+    );
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(context.runs(), vec![TNode(2), TNode(1), TNode(0)]);
+
+    // Evicted Nodes are recomputed when they are next requested, and can't be evicted again
+    // until then.
+    assert_eq!(graph.evict(|&TNode(n)| n < 2), EvictionResult::default());
+    assert_eq!(
+      graph.create(TNode(1), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0)])
+    );
+    assert_eq!(
+      context.runs(),
+      vec![TNode(2), TNode(1), TNode(0), TNode(1), TNode(0)]
+    );
+  }
+
+  #[test]
+  fn invalidate_by_result() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+
+    // Only the Node whose result contains exactly two tokens is cleared.
+    assert_eq!(
+      graph.invalidate_from_roots_with_results(|_, result| {
+        match result {
+          Some(Ok(tokens)) => tokens.len() == 2,
+          _ => false,
+        }
+      }),
+      InvalidationResult {
+        cleared: 1,
+        dirtied: 1
+      }
+    );
+    assert_eq!(
+      graph.create(TNode(2), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(context.runs(), vec![TNode(2), TNode(1), TNode(0), TNode(1)]);
+  }
+
+  #[test]
+  fn invalidate_and_cutoff() {
+    let graph = Arc::new(Graph::new());
+    let context = TContext::new(0, graph.clone());
+
+    // Create a chain of five nodes.
+    assert_eq!(
+      graph.create(TNode(4), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0), T(4, 0)])
+    );
+
+    // Clear the bottom Node, which dirties all of the nodes above it.
+    assert_eq!(
+      graph.invalidate_from_roots(|&TNode(n)| n == 0),
+      InvalidationResult {
+        cleared: 1,
+        dirtied: 4
+      }
+    );
+
+    // The bottom Node re-runs and produces an equal result, so its dependents are cleaned
+    // without re-running.
+    assert_eq!(
+      graph.create(TNode(4), &context).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0), T(3, 0), T(4, 0)])
+    );
+    assert_eq!(
+      context.runs(),
+      vec![TNode(4), TNode(3), TNode(2), TNode(1), TNode(0), TNode(0)]
+    );
+  }
+
   #[test]
   fn invalidate_and_rerun() {
     let graph = Arc::new(Graph::new());
@@ -1283,6 +2350,61 @@ mod tests {
     assert_eq!(context1.runs(), vec![TNode(1), TNode(2)]);
   }
 
+  #[test]
+  fn invalidation_counters() {
+    let graph = Arc::new(Graph::new());
+    let context0 = TContext::new(0, graph.clone());
+
+    // Create three nodes, then clear the bottom Node, which dirties the upper nodes.
+    assert_eq!(
+      graph.create(TNode(2), &context0).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    assert_eq!(
+      graph.invalidation_counters(),
+      InvalidationCounters::default()
+    );
+    graph.invalidate_from_roots(|&TNode(n)| n == 0);
+    let invalidated = graph.invalidation_counters();
+    assert_eq!(
+      invalidated.invalidated,
+      vec![("TNode", 3)].into_iter().collect()
+    );
+
+    // Re-running with the same context produces identical results for all nodes.
+    assert_eq!(
+      graph.create(TNode(2), &context0).wait(),
+      Ok(vec![T(0, 0), T(1, 0), T(2, 0)])
+    );
+    let unchanged = graph.invalidation_counters().since(&invalidated);
+    assert_eq!(
+      unchanged,
+      InvalidationCounters {
+        invalidated: HashMap::new(),
+        unchanged: vec![("TNode", 3)].into_iter().collect(),
+        changed: HashMap::new(),
+      }
+    );
+
+    // Clear the middle Node, and re-run with a new context, which changes the middle and upper
+    // Nodes.
+    let before = graph.invalidation_counters();
+    graph.invalidate_from_roots(|&TNode(n)| n == 1);
+    let context1 = TContext::new(1, graph.clone());
+    assert_eq!(
+      graph.create(TNode(2), &context1).wait(),
+      Ok(vec![T(0, 0), T(1, 1), T(2, 1)])
+    );
+    assert_eq!(
+      graph.invalidation_counters().since(&before),
+      InvalidationCounters {
+        invalidated: vec![("TNode", 2)].into_iter().collect(),
+        unchanged: HashMap::new(),
+        changed: vec![("TNode", 2)].into_iter().collect(),
+      }
+    );
+  }
+
   #[test]
   fn invalidate_with_changed_dependencies() {
     let graph = Arc::new(Graph::new());
@@ -1407,6 +2529,15 @@ mod tests {
 
     fn run(self, context: TContext) -> BoxFuture<Vec<T>, TError> {
       context.ran(self.clone());
+      if context.block_at(&self) {
+        let guard = SetOnDrop(context.blocked_work_dropped.clone());
+        return future::empty()
+          .map(move |v| {
+            let _guard = &guard;
+            v
+          })
+          .to_boxed();
+      }
       let depth = self.0;
       let token = T(depth, context.id());
       if depth > 0 && !context.stop_at(&self) {
@@ -1426,8 +2557,16 @@ mod tests {
       format!("{:?}", self)
     }
 
-    fn digest(_result: Self::Item) -> Option<Digest> {
-      None
+    fn type_name(&self) -> &'static str {
+      "TNode"
+    }
+
+    fn digests(_result: Self::Item) -> Vec<Digest> {
+      vec![]
+    }
+
+    fn heap_size(result: &Vec<T>) -> (&'static str, usize) {
+      ("Vec<T>", result.capacity() * mem::size_of::<T>())
     }
   }
 
@@ -1485,6 +2624,8 @@ mod tests {
   struct TContext {
     id: usize,
     stop_at: Option<TNode>,
+    block_at: Option<TNode>,
+    blocked_work_dropped: Arc<AtomicBool>,
     graph: Arc<Graph<TNode>>,
     runs: Arc<Mutex<Vec<TNode>>>,
     entry_id: Option<EntryId>,
@@ -1495,6 +2636,8 @@ mod tests {
       TContext {
         id: self.id,
         stop_at: self.stop_at.clone(),
+        block_at: self.block_at.clone(),
+        blocked_work_dropped: self.blocked_work_dropped.clone(),
         graph: self.graph.clone(),
         runs: self.runs.clone(),
         entry_id: Some(entry_id),
@@ -1521,6 +2664,8 @@ mod tests {
       TContext {
         id,
         stop_at: None,
+        block_at: None,
+        blocked_work_dropped: Arc::new(AtomicBool::new(false)),
         graph,
         runs: Arc::new(Mutex::new(Vec::new())),
         entry_id: None,
@@ -1531,12 +2676,24 @@ mod tests {
       TContext {
         id,
         stop_at: Some(stop_at),
+        block_at: None,
+        blocked_work_dropped: Arc::new(AtomicBool::new(false)),
         graph,
         runs: Arc::new(Mutex::new(Vec::new())),
         entry_id: None,
       }
     }
 
+    ///
+    /// A context in which the given Node never completes.
+    ///
+    fn new_with_block_at(id: usize, block_at: TNode, graph: Arc<Graph<TNode>>) -> TContext {
+      TContext {
+        block_at: Some(block_at),
+        ..TContext::new(id, graph)
+      }
+    }
+
     fn id(&self) -> usize {
       self.id
     }
@@ -1554,15 +2711,55 @@ mod tests {
       Some(node) == self.stop_at.as_ref()
     }
 
+    fn block_at(&self, node: &TNode) -> bool {
+      Some(node) == self.block_at.as_ref()
+    }
+
     fn runs(&self) -> Vec<TNode> {
       self.runs.lock().unwrap().clone()
     }
   }
 
+  ///
+  /// Sets a flag when dropped, to detect that a Future which held it was dropped.
+  ///
+  struct SetOnDrop(Arc<AtomicBool>);
+  impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+      self.0.store(true, Ordering::SeqCst);
+    }
+  }
+
+  fn wait_until<F: Fn() -> bool>(condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+      assert!(Instant::now() < deadline, "Timed out waiting for condition.");
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  ///
+  /// A tracer which treats the Nodes without dependencies as failures.
+  ///
+  struct TTracer;
+  impl NodeTracer<TNode> for TTracer {
+    fn is_bottom(result: Option<Result<Vec<T>, TError>>) -> bool {
+      result.is_none()
+    }
+
+    fn state_str(_indent: &str, result: Option<Result<Vec<T>, TError>>) -> String {
+      match result {
+        Some(Ok(tokens)) => format!("{:?}", tokens),
+        result => format!("{:?}", result),
+      }
+    }
+  }
+
   #[derive(Clone, Debug, Eq, PartialEq)]
   enum TError {
     Cyclic,
     Invalidated,
+    LimitExceeded(String),
   }
   impl NodeError for TError {
     fn invalidated() -> Self {
@@ -1572,5 +2769,9 @@ mod tests {
     fn cyclic() -> Self {
       TError::Cyclic
     }
+
+    fn limit_exceeded(message: String) -> Self {
+      TError::LimitExceeded(message)
+    }
   }
 }
@@ -30,9 +30,30 @@ pub trait Node: Clone + Debug + Eq + Hash + Send + 'static {
   fn format(&self) -> String;
 
   ///
-  /// If the given Node output represents an FS operation, returns its Digest.
+  /// A description of the work that this Node does, for display to users while it is running, if
+  /// it has one which is more meaningful than its `format`ted key.
   ///
-  fn digest(result: Self::Item) -> Option<Digest>;
+  fn user_facing_name(&self) -> Option<String> {
+    None
+  }
+
+  ///
+  /// A short, static name for the kind of this Node, used to aggregate metrics by Node type.
+  ///
+  fn type_name(&self) -> &'static str;
+
+  ///
+  /// Returns the Digests of any content in the Store that the given Node output references (such
+  /// as the Digest of a file, or of a Directory that holds the output of a process), so that the
+  /// content can be leased for as long as the output is held.
+  ///
+  fn digests(result: Self::Item) -> Vec<Digest>;
+
+  ///
+  /// A short, static name for the kind of the given Node output, and an estimate of the bytes of
+  /// heap memory that it holds, used to attribute the memory held by the Graph by kind of output.
+  ///
+  fn heap_size(result: &Self::Item) -> (&'static str, usize);
 }
 
 pub trait NodeError: Clone + Debug + Eq + Send {
@@ -46,6 +67,12 @@ pub trait NodeError: Clone + Debug + Eq + Send {
   /// Creates an instance that represents that a Node dependency was cyclic.
   ///
   fn cyclic() -> Self;
+
+  ///
+  /// Creates an instance that represents that a Node could not be requested because the Graph
+  /// would have exceeded its GraphLimits.
+  ///
+  fn limit_exceeded(message: String) -> Self;
 }
 
 ///
@@ -60,7 +87,15 @@ pub trait NodeVisualizer<N: Node> {
   ///
   /// Returns a GraphViz color name/id within Self::color_scheme for the given Node/result.
   ///
-  fn color(&mut self, node: &N, result: Option<Result<N::Item, N::Error>>) -> String;
+  /// If the Node has completed, `duration_percentile` is the fraction (between 0.0 and 1.0) of
+  /// the visualized Nodes that completed more quickly than it did.
+  ///
+  fn color(
+    &mut self,
+    node: &N,
+    result: Option<Result<N::Item, N::Error>>,
+    duration_percentile: Option<f64>,
+  ) -> String;
 }
 
 ///
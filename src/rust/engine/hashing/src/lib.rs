@@ -11,7 +11,7 @@ use sha2::Sha256;
 use std::fmt;
 use std::io::{self, Write};
 
-const FINGERPRINT_SIZE: usize = 32;
+pub const FINGERPRINT_SIZE: usize = 32;
 
 #[derive(Clone, Copy, Eq, Hash, PartialEq, Ord, PartialOrd)]
 pub struct Fingerprint(pub [u8; FINGERPRINT_SIZE]);
@@ -0,0 +1,170 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+extern crate build_utils;
+extern crate env_logger;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate nailgun;
+
+use build_utils::BuildRoot;
+use std::env;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::{self, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The exit code used when pantsd cannot be reached, which allows wrapper scripts to fall back to
+// running pants without the daemon (EX_TEMPFAIL).
+const PANTSD_UNAVAILABLE_EXIT_CODE: i32 = 75;
+
+// The command that pantsd expects: see `pants.bin.remote_pants_runner`.
+const PANTS_COMMAND: &str = "pants";
+
+// The process group id of the pantsd-runner handling our request, negated, as sent by pantsd.
+static REMOTE_PID: AtomicIsize = AtomicIsize::new(0);
+
+///
+/// A thin pantsd client, which forwards its arguments and environment to a running pantsd, streams
+/// the output of the run, forwards SIGINT and SIGQUIT (as SIGINT, like
+/// `pants.bin.remote_pants_runner`), and exits with the exit code of the run.
+///
+/// Unlike the python client, it does not launch pantsd if it is not running: instead, it exits
+/// with code 75, so that a wrapper can fall back to running pants without the daemon.
+///
+fn main() {
+  env_logger::init();
+
+  let start_time = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9)
+    .unwrap_or(0.0);
+
+  let stream = match connect() {
+    Ok(stream) => stream,
+    Err(e) => {
+      eprintln!("Failed to connect to pantsd: {}", e);
+      exit(PANTSD_UNAVAILABLE_EXIT_CODE);
+    }
+  };
+
+  match execute(stream, start_time) {
+    Ok(exit_code) => exit(exit_code),
+    Err(e) => {
+      eprintln!("Lost connection to pantsd: {}", e);
+      exit(1);
+    }
+  }
+}
+
+fn connect() -> Result<TcpStream, String> {
+  let port = pailgun_port()?;
+  debug!("Connecting to pantsd on port {}", port);
+  TcpStream::connect(("127.0.0.1", port)).map_err(|e| format!("port {}: {}", port, e))
+}
+
+///
+/// Determines the port of the pailgun server of pantsd, either from the `PANTSD_PAILGUN_PORT`
+/// environment variable, or from the socket metadata that pantsd writes to the subprocess dir.
+///
+fn pailgun_port() -> Result<u16, String> {
+  let port = match env::var("PANTSD_PAILGUN_PORT") {
+    Ok(port) => port,
+    Err(_) => {
+      let subprocess_dir = match env::var_os("PANTS_SUBPROCESSDIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => BuildRoot::find()
+          .map_err(|e| format!("{}", e))?
+          .join(".pids"),
+      };
+      let socket_path = subprocess_dir.join("pantsd").join("socket_pailgun");
+      let mut port = String::new();
+      File::open(&socket_path)
+        .and_then(|mut f| f.read_to_string(&mut port))
+        .map_err(|e| format!("Failed to read {:?}: {}", socket_path, e))?;
+      port
+    }
+  };
+  port
+    .trim()
+    .parse()
+    .map_err(|e| format!("Invalid pantsd port {:?}: {}", port, e))
+}
+
+fn execute(stream: TcpStream, start_time: f64) -> Result<i32, String> {
+  let working_dir = env::current_dir()
+    .map_err(|e| format!("Failed to determine the working directory: {}", e))?
+    .to_string_lossy()
+    .into_owned();
+
+  let mut environment = env::vars().collect::<Vec<_>>();
+  for fd in 0..3 {
+    let is_atty = unsafe { libc::isatty(fd) } == 1;
+    environment.push((format!("NAILGUN_TTY_{}", fd), (is_atty as i32).to_string()));
+    if is_atty {
+      environment.push((format!("NAILGUN_TTY_PATH_{}", fd), ttyname(fd)));
+    }
+  }
+  environment.push((
+    "PANTSD_RUNTRACKER_CLIENT_START_TIME".to_string(),
+    start_time.to_string(),
+  ));
+  environment.push((
+    "NAILGUN_FILESEPARATOR".to_string(),
+    path::MAIN_SEPARATOR.to_string(),
+  ));
+  environment.push(("NAILGUN_PATHSEPARATOR".to_string(), ":".to_string()));
+
+  let request = nailgun::Request {
+    working_dir: working_dir,
+    command: PANTS_COMMAND.to_string(),
+    arguments: env::args().skip(1).collect(),
+    environment: environment,
+  };
+
+  trap_signals();
+  nailgun::execute(
+    stream,
+    &request,
+    io::stdin(),
+    &mut io::stdout(),
+    &mut io::stderr(),
+    |pid| REMOTE_PID.store(pid as isize, Ordering::SeqCst),
+  )
+}
+
+fn ttyname(fd: libc::c_int) -> String {
+  let name = unsafe { libc::ttyname(fd) };
+  if name.is_null() {
+    String::new()
+  } else {
+    unsafe { CStr::from_ptr(name) }
+      .to_string_lossy()
+      .into_owned()
+  }
+}
+
+///
+/// Forwards SIGINT and SIGQUIT to the remote process as SIGINT: SIGQUIT would abruptly kill the
+/// pantsd-runner, rather than allowing it to shut down the run gracefully.
+///
+fn trap_signals() {
+  extern "C" fn forward_signal(_: libc::c_int) {
+    let pid = REMOTE_PID.load(Ordering::SeqCst);
+    if pid != 0 {
+      unsafe {
+        libc::kill(pid as libc::pid_t, libc::SIGINT);
+      }
+    }
+  }
+
+  unsafe {
+    libc::signal(libc::SIGINT, forward_signal as libc::sighandler_t);
+    libc::signal(libc::SIGQUIT, forward_signal as libc::sighandler_t);
+  }
+}
@@ -0,0 +1,197 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use {read_chunk, write_chunk, Chunk, ChunkType, Request};
+
+// The size of the stdin chunks sent to the server.
+const STDIN_CHUNK_BYTES: usize = 8192;
+
+///
+/// Sends the given request to the Nailgun server connected to by `stream`, and returns the exit
+/// code of its execution.
+///
+/// Output is written to `stdout` and `stderr` as it is received. If the server asks for input,
+/// `stdin` is forwarded to it on a background thread until it is exhausted. `on_pid` is called with
+/// the remote process id, if the server sends one (as pantsd does), which allows the caller to
+/// forward signals to the remote process.
+///
+pub fn execute<I, O, E, P>(
+  stream: TcpStream,
+  request: &Request,
+  stdin: I,
+  stdout: &mut O,
+  stderr: &mut E,
+  on_pid: P,
+) -> Result<i32, String>
+where
+  I: Read + Send + 'static,
+  O: Write,
+  E: Write,
+  P: Fn(i32),
+{
+  let mut reader = stream
+    .try_clone()
+    .map_err(|e| format!("Failed to clone connection: {}", e))?;
+  let mut writer = stream;
+  request.send(&mut writer)?;
+
+  let mut input = Some((stdin, writer));
+  loop {
+    let chunk = read_chunk(&mut reader)?;
+    match chunk.chunk_type {
+      ChunkType::Stdout => write_flush(stdout, &chunk.payload)?,
+      ChunkType::Stderr => write_flush(stderr, &chunk.payload)?,
+      ChunkType::Pid => on_pid(parse_int(&chunk)?),
+      ChunkType::StartReadingInput => {
+        if let Some((stdin, writer)) = input.take() {
+          thread::spawn(move || forward_stdin(stdin, writer));
+        }
+      }
+      ChunkType::Exit => {
+        write_flush(stdout, &[])?;
+        write_flush(stderr, &[])?;
+        return parse_int(&chunk);
+      }
+      chunk_type => return Err(format!("Received an unexpected {:?} chunk.", chunk_type)),
+    }
+  }
+}
+
+fn write_flush<W: Write>(writer: &mut W, payload: &[u8]) -> Result<(), String> {
+  writer
+    .write_all(payload)
+    .and_then(|()| writer.flush())
+    .map_err(|e| format!("Failed to write output: {}", e))
+}
+
+fn parse_int(chunk: &Chunk) -> Result<i32, String> {
+  let payload = String::from_utf8_lossy(&chunk.payload);
+  payload.trim().parse().map_err(|e| {
+    format!(
+      "Expected an integer in a {:?} chunk, but got {:?}: {}",
+      chunk.chunk_type, payload, e
+    )
+  })
+}
+
+///
+/// Sends the content of `stdin` to the server as Stdin chunks, followed by a Stdin-EOF chunk.
+///
+fn forward_stdin<I: Read, W: Write>(mut stdin: I, mut writer: W) {
+  let mut buf = [0; STDIN_CHUNK_BYTES];
+  loop {
+    match stdin.read(&mut buf) {
+      Ok(0) => break,
+      Ok(len) => {
+        if let Err(e) = write_chunk(&mut writer, ChunkType::Stdin, &buf[..len]) {
+          debug!("Stopped forwarding stdin: {}", e);
+          return;
+        }
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => {
+        debug!("Failed to read stdin: {}", e);
+        break;
+      }
+    }
+  }
+  if let Err(e) = write_chunk(&mut writer, ChunkType::StdinEof, &[]) {
+    debug!("Failed to send stdin EOF: {}", e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::execute;
+  use std::cell::Cell;
+  use std::io::Cursor;
+  use std::net::{TcpListener, TcpStream};
+  use std::thread;
+  use {read_chunk, write_chunk, ChunkType, Request};
+
+  fn request() -> Request {
+    Request {
+      working_dir: "/build/root".to_string(),
+      command: "pants".to_string(),
+      arguments: vec!["cat".to_string()],
+      environment: vec![],
+    }
+  }
+
+  #[test]
+  fn execute_with_input() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let request = Request::parse(&mut stream).unwrap();
+      write_chunk(&mut stream, ChunkType::Pid, b"-42").unwrap();
+      write_chunk(&mut stream, ChunkType::Stderr, b"reading\n").unwrap();
+      write_chunk(&mut stream, ChunkType::StartReadingInput, b"").unwrap();
+      // Echo the input until it is exhausted.
+      loop {
+        let chunk = read_chunk(&mut stream).unwrap();
+        match chunk.chunk_type {
+          ChunkType::Stdin => write_chunk(&mut stream, ChunkType::Stdout, &chunk.payload).unwrap(),
+          ChunkType::StdinEof => break,
+          other => panic!("Unexpected chunk: {:?}", other),
+        }
+      }
+      write_chunk(&mut stream, ChunkType::Exit, b"3").unwrap();
+      request
+    });
+
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let pid = Cell::new(None);
+    let exit_code = execute(
+      TcpStream::connect(address).unwrap(),
+      &request(),
+      Cursor::new(b"some input".to_vec()),
+      &mut stdout,
+      &mut stderr,
+      |remote_pid| pid.set(Some(remote_pid)),
+    );
+
+    assert_eq!(exit_code, Ok(3));
+    assert_eq!(pid.get(), Some(-42));
+    assert_eq!(stdout, b"some input".to_vec());
+    assert_eq!(stderr, b"reading\n".to_vec());
+    assert_eq!(server.join().unwrap(), request());
+  }
+
+  #[test]
+  fn execute_connection_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      Request::parse(&mut stream).unwrap();
+      write_chunk(&mut stream, ChunkType::Stdout, b"partial").unwrap();
+    });
+
+    let mut stdout = Vec::new();
+    let exit_code = execute(
+      TcpStream::connect(address).unwrap(),
+      &request(),
+      Cursor::new(vec![]),
+      &mut stdout,
+      &mut Vec::new(),
+      |_| {},
+    );
+
+    assert_eq!(
+      exit_code,
+      Err(
+        "Failed to read chunk header: Expected 5 bytes before the connection was closed."
+          .to_string()
+      )
+    );
+    assert_eq!(stdout, b"partial".to_vec());
+    server.join().unwrap();
+  }
+}
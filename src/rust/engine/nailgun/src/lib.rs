@@ -0,0 +1,309 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#[macro_use]
+extern crate log;
+
+mod client;
+pub use client::execute;
+
+use std::io::{self, Read, Write};
+
+///
+/// The types of chunk in the Nailgun protocol, as described on
+/// http://martiansoftware.com/nailgun/protocol.html, and as spoken by pantsd (see
+/// `pants.java.nailgun_protocol`).
+///
+/// Communications proceed as follows:
+///
+///   1) Client connects to server
+///   2) Client transmits zero or more "Argument" chunks
+///   3) Client transmits zero or more "Environment" chunks
+///   4) Client transmits exactly one "Working Directory" chunk
+///   5) Client transmits exactly one "Command" chunk
+///   6) If server requires stdin input, server transmits exactly one "Start-reading-input" chunk
+///
+/// After step 5 (and/or 6) the following may happen, interleaved and in any order:
+///
+///   7) Client transmits zero or more "stdin" chunks (Only if the client has received a
+///      "Start-reading-input" chunk, and only until the client transmits a "stdin-eof" chunk).
+///   8) Server transmits zero or more "stdout" chunks.
+///   9) Server transmits zero or more "stderr" chunks.
+///
+/// Steps 7-9 repeat indefinitely until the server transmits an "exit" chunk.
+///
+/// pantsd additionally transmits a "PID" chunk containing the (negated) process group id of the
+/// process handling the request, so that clients can forward signals to it.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkType {
+  Argument,
+  Environment,
+  WorkingDir,
+  Command,
+  Pid,
+  Stdin,
+  Stdout,
+  Stderr,
+  StartReadingInput,
+  StdinEof,
+  Exit,
+}
+
+impl ChunkType {
+  fn to_byte(self) -> u8 {
+    match self {
+      ChunkType::Argument => b'A',
+      ChunkType::Environment => b'E',
+      ChunkType::WorkingDir => b'D',
+      ChunkType::Command => b'C',
+      ChunkType::Pid => b'P',
+      ChunkType::Stdin => b'0',
+      ChunkType::Stdout => b'1',
+      ChunkType::Stderr => b'2',
+      ChunkType::StartReadingInput => b'S',
+      ChunkType::StdinEof => b'.',
+      ChunkType::Exit => b'X',
+    }
+  }
+
+  fn from_byte(byte: u8) -> Option<ChunkType> {
+    match byte {
+      b'A' => Some(ChunkType::Argument),
+      b'E' => Some(ChunkType::Environment),
+      b'D' => Some(ChunkType::WorkingDir),
+      b'C' => Some(ChunkType::Command),
+      b'P' => Some(ChunkType::Pid),
+      b'0' => Some(ChunkType::Stdin),
+      b'1' => Some(ChunkType::Stdout),
+      b'2' => Some(ChunkType::Stderr),
+      b'S' => Some(ChunkType::StartReadingInput),
+      b'.' => Some(ChunkType::StdinEof),
+      b'X' => Some(ChunkType::Exit),
+      _ => None,
+    }
+  }
+}
+
+// The length of the payload as a four byte big-endian unsigned int, followed by the chunk type.
+const HEADER_BYTES: usize = 5;
+
+const ENVIRON_SEP: char = '=';
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chunk {
+  pub chunk_type: ChunkType,
+  pub payload: Vec<u8>,
+}
+
+pub fn write_chunk<W: Write>(
+  writer: &mut W,
+  chunk_type: ChunkType,
+  payload: &[u8],
+) -> Result<(), String> {
+  if payload.len() > u32::max_value() as usize {
+    return Err(format!(
+      "Chunk payload of {} bytes is too large to send.",
+      payload.len()
+    ));
+  }
+  let len = payload.len() as u32;
+  let mut chunk = Vec::with_capacity(HEADER_BYTES + payload.len());
+  chunk.extend_from_slice(&[
+    (len >> 24) as u8,
+    (len >> 16) as u8,
+    (len >> 8) as u8,
+    len as u8,
+    chunk_type.to_byte(),
+  ]);
+  chunk.extend_from_slice(payload);
+  writer
+    .write_all(&chunk)
+    .and_then(|()| writer.flush())
+    .map_err(|e| format!("Failed to write {:?} chunk: {}", chunk_type, e))
+}
+
+///
+/// Reads a single chunk. The payload of a chunk is always drained before its type is validated, so
+/// that an unrecognized chunk does not leave a partial payload to be read as the next chunk.
+///
+pub fn read_chunk<R: Read>(reader: &mut R) -> Result<Chunk, String> {
+  let mut header = [0; HEADER_BYTES];
+  read_exact(reader, &mut header).map_err(|e| format!("Failed to read chunk header: {}", e))?;
+  let len = header[..4]
+    .iter()
+    .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+  let mut payload = vec![0; len];
+  read_exact(reader, &mut payload).map_err(|e| format!("Failed to read chunk payload: {}", e))?;
+  let chunk_type = ChunkType::from_byte(header[4])
+    .ok_or_else(|| format!("Invalid chunk type: {:?}", header[4] as char))?;
+  Ok(Chunk {
+    chunk_type: chunk_type,
+    payload: payload,
+  })
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), String> {
+  reader.read_exact(buf).map_err(|e| match e.kind() {
+    io::ErrorKind::UnexpectedEof => format!(
+      "Expected {} bytes before the connection was closed.",
+      buf.len()
+    ),
+    _ => e.to_string(),
+  })
+}
+
+///
+/// The request (or "pre-execution") section of a Nailgun exchange.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+  pub working_dir: String,
+  pub command: String,
+  pub arguments: Vec<String>,
+  pub environment: Vec<(String, String)>,
+}
+
+impl Request {
+  pub fn send<W: Write>(&self, writer: &mut W) -> Result<(), String> {
+    for argument in &self.arguments {
+      write_chunk(writer, ChunkType::Argument, argument.as_bytes())?;
+    }
+    for &(ref key, ref value) in &self.environment {
+      let item = format!("{}{}{}", key, ENVIRON_SEP, value);
+      write_chunk(writer, ChunkType::Environment, item.as_bytes())?;
+    }
+    write_chunk(writer, ChunkType::WorkingDir, self.working_dir.as_bytes())?;
+    write_chunk(writer, ChunkType::Command, self.command.as_bytes())
+  }
+
+  ///
+  /// Parses a request. Argument and Environment chunks are optional, and may be sent more than
+  /// once, while the Working Directory and Command chunks are required, and must be sent as the
+  /// last two chunks of the request.
+  ///
+  pub fn parse<R: Read>(reader: &mut R) -> Result<Request, String> {
+    let mut working_dir = None;
+    let mut command = None;
+    let mut arguments = Vec::new();
+    let mut environment = Vec::new();
+    while working_dir.is_none() || command.is_none() {
+      let Chunk {
+        chunk_type,
+        payload,
+      } = read_chunk(reader)?;
+      let payload = String::from_utf8(payload)
+        .map_err(|e| format!("{:?} chunk was not valid UTF-8: {}", chunk_type, e))?;
+      match chunk_type {
+        ChunkType::Argument => arguments.push(payload),
+        ChunkType::Environment => {
+          let mut parts = payload.splitn(2, ENVIRON_SEP);
+          let key = parts.next().unwrap_or("").to_owned();
+          let value = parts
+            .next()
+            .ok_or_else(|| format!("Malformed environment chunk: {:?}", payload))?
+            .to_owned();
+          environment.push((key, value));
+        }
+        ChunkType::WorkingDir => working_dir = Some(payload),
+        ChunkType::Command => command = Some(payload),
+        chunk_type => {
+          return Err(format!(
+            "Received a {:?} chunk before the request was fully received.",
+            chunk_type
+          ))
+        }
+      }
+    }
+    Ok(Request {
+      working_dir: working_dir.unwrap(),
+      command: command.unwrap(),
+      arguments: arguments,
+      environment: environment,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{read_chunk, write_chunk, Chunk, ChunkType, Request};
+  use std::io::Cursor;
+
+  #[test]
+  fn chunk_roundtrip() {
+    let mut buf = Vec::new();
+    write_chunk(&mut buf, ChunkType::Stdout, b"hello").unwrap();
+    assert_eq!(buf, b"\x00\x00\x00\x051hello".to_vec());
+    assert_eq!(
+      read_chunk(&mut Cursor::new(buf)),
+      Ok(Chunk {
+        chunk_type: ChunkType::Stdout,
+        payload: b"hello".to_vec(),
+      })
+    );
+  }
+
+  #[test]
+  fn empty_chunk() {
+    let mut buf = Vec::new();
+    write_chunk(&mut buf, ChunkType::StartReadingInput, b"").unwrap();
+    assert_eq!(buf, b"\x00\x00\x00\x00S".to_vec());
+    assert_eq!(
+      read_chunk(&mut Cursor::new(buf)).map(|c| c.chunk_type),
+      Ok(ChunkType::StartReadingInput)
+    );
+  }
+
+  #[test]
+  fn invalid_chunk_type() {
+    let mut reader = Cursor::new(b"\x00\x00\x00\x01Zz\x00\x00\x00\x00X".to_vec());
+    assert_eq!(
+      read_chunk(&mut reader),
+      Err("Invalid chunk type: 'Z'".to_string())
+    );
+    // The payload of the invalid chunk was drained.
+    assert_eq!(
+      read_chunk(&mut reader).map(|c| c.chunk_type),
+      Ok(ChunkType::Exit)
+    );
+  }
+
+  #[test]
+  fn truncated_payload() {
+    let mut reader = Cursor::new(b"\x00\x00\x00\x051hel".to_vec());
+    assert_eq!(
+      read_chunk(&mut reader),
+      Err(
+        "Failed to read chunk payload: Expected 5 bytes before the connection was closed."
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn request_roundtrip() {
+    let request = Request {
+      working_dir: "/build/root".to_string(),
+      command: "pants".to_string(),
+      arguments: vec!["list".to_string(), "src::".to_string()],
+      environment: vec![
+        ("HOME".to_string(), "/home/user".to_string()),
+        ("EQUATION".to_string(), "a=b".to_string()),
+      ],
+    };
+    let mut buf = Vec::new();
+    request.send(&mut buf).unwrap();
+    assert_eq!(Request::parse(&mut Cursor::new(buf)), Ok(request));
+  }
+
+  #[test]
+  fn request_with_execution_chunk() {
+    let mut buf = Vec::new();
+    write_chunk(&mut buf, ChunkType::Argument, b"list").unwrap();
+    write_chunk(&mut buf, ChunkType::Stdin, b"input").unwrap();
+    assert_eq!(
+      Request::parse(&mut Cursor::new(buf)),
+      Err("Received a Stdin chunk before the request was fully received.".to_string())
+    );
+  }
+}
@@ -0,0 +1,339 @@
+use std::mem;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+use std::str;
+
+///
+/// The version of this interface. The engine refuses to load a plugin which was built against a
+/// different version.
+///
+/// Only `#[repr(C)]` types and `extern "C"` functions cross the interface (see `RawIntrinsic`), so
+/// a plugin need not be built with the same compiler as the engine.
+///
+pub const ABI_VERSION: u32 = 2;
+
+///
+/// The symbols which `export_plugin!` defines, and which the engine looks up in a plugin.
+///
+pub const ABI_VERSION_SYMBOL: &str = "PANTS_PLUGIN_ABI_VERSION";
+pub const REGISTER_SYMBOL: &str = "pants_plugin_register";
+
+///
+/// A file that is input to an Intrinsic.
+///
+pub struct InputFile<'a> {
+  pub path: &'a Path,
+  pub content: &'a [u8],
+}
+
+///
+/// An intrinsic which is implemented outside of the engine.
+///
+/// Rules bind an intrinsic to an output type by name (see `IntrinsicPluginRule` in
+/// `pants.engine.rules`), and the engine then computes that type for the rule's input type by
+/// running the intrinsic on the files in its Directory, and constructing the output type from the
+/// bytes that it returns.
+///
+/// Intrinsics are run on a thread pool for blocking work, and must be deterministic: their results
+/// are memoized like those of any other rule.
+///
+pub trait Intrinsic: Send + Sync {
+  ///
+  /// The name which rules use to refer to this intrinsic. Names must be unique across all plugins.
+  ///
+  fn name(&self) -> &str;
+
+  ///
+  /// Computes the output for the given files, which are sorted by path.
+  ///
+  fn run(&self, files: &[InputFile]) -> Result<Vec<u8>, String>;
+}
+
+///
+/// Collects the Intrinsics which a plugin provides.
+///
+#[derive(Default)]
+pub struct Registry {
+  intrinsics: Vec<Box<Intrinsic>>,
+}
+
+impl Registry {
+  pub fn new() -> Registry {
+    Registry::default()
+  }
+
+  pub fn register(&mut self, intrinsic: Box<Intrinsic>) {
+    self.intrinsics.push(intrinsic);
+  }
+
+  pub fn into_intrinsics(self) -> Vec<Box<Intrinsic>> {
+    self.intrinsics
+  }
+}
+
+///
+/// A byte string which is borrowed across the plugin interface.
+///
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RawSlice {
+  pub ptr: *const u8,
+  pub len: usize,
+}
+
+impl RawSlice {
+  pub fn new(bytes: &[u8]) -> RawSlice {
+    RawSlice {
+      ptr: bytes.as_ptr(),
+      len: bytes.len(),
+    }
+  }
+
+  ///
+  /// The caller must ensure that the bytes that this slice was created from outlive 'a.
+  ///
+  pub unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+    slice::from_raw_parts(self.ptr, self.len)
+  }
+}
+
+///
+/// A file that is input to an Intrinsic, as passed across the plugin interface. The path is UTF8.
+///
+#[repr(C)]
+pub struct RawInputFile {
+  pub path: RawSlice,
+  pub content: RawSlice,
+}
+
+///
+/// The output of an Intrinsic (or if `is_error` is set, the UTF8 of an error message). It was
+/// allocated by the plugin, and so must be freed by the plugin: see `RawIntrinsic::free_output`.
+///
+#[repr(C)]
+pub struct RawOutput {
+  pub ptr: *mut u8,
+  pub len: usize,
+  pub capacity: usize,
+  pub is_error: bool,
+}
+
+///
+/// An Intrinsic, as passed across the plugin interface: an opaque instance, and the functions which
+/// operate on it. Instances are never freed, because plugins are never unloaded.
+///
+#[repr(C)]
+pub struct RawIntrinsic {
+  pub name: RawSlice,
+  pub instance: *mut c_void,
+  pub run: unsafe extern "C" fn(*mut c_void, *const RawInputFile, usize) -> RawOutput,
+  pub free_output: unsafe extern "C" fn(RawOutput),
+}
+
+///
+/// The function which the engine passes to a plugin's `RegisterFn`, which the plugin calls (with
+/// the `host` that it was given) once per Intrinsic that it provides.
+///
+pub type AddIntrinsicFn = unsafe extern "C" fn(host: *mut c_void, intrinsic: RawIntrinsic);
+
+///
+/// The type of the registration function which `export_plugin!` defines. Returns false if the
+/// plugin failed to register its Intrinsics.
+///
+pub type RegisterFn = unsafe extern "C" fn(host: *mut c_void, add: AddIntrinsicFn) -> bool;
+
+///
+/// Registers the Intrinsics that the given function registers with the given host. Called by the
+/// `RegisterFn` that `export_plugin!` defines, and so compiled into (and run by) the plugin.
+///
+#[doc(hidden)]
+pub unsafe fn register_with(
+  register: fn(&mut Registry),
+  host: *mut c_void,
+  add: AddIntrinsicFn,
+) -> bool {
+  // Panics must not unwind across the interface.
+  let intrinsics = match panic::catch_unwind(|| {
+    let mut registry = Registry::new();
+    register(&mut registry);
+    registry.into_intrinsics()
+  }) {
+    Ok(intrinsics) => intrinsics,
+    Err(_) => return false,
+  };
+  for intrinsic in intrinsics {
+    let instance: &'static Box<Intrinsic> = Box::leak(Box::new(intrinsic));
+    add(
+      host,
+      RawIntrinsic {
+        name: RawSlice::new(instance.name().as_bytes()),
+        instance: instance as *const Box<Intrinsic> as *mut c_void,
+        run: run_intrinsic,
+        free_output: free_output,
+      },
+    );
+  }
+  true
+}
+
+unsafe extern "C" fn run_intrinsic(
+  instance: *mut c_void,
+  files: *const RawInputFile,
+  files_len: usize,
+) -> RawOutput {
+  let intrinsic = &*(instance as *const Box<Intrinsic>);
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    let files = slice::from_raw_parts(files, files_len)
+      .iter()
+      .map(|file| {
+        let path = str::from_utf8(file.path.as_slice())
+          .map_err(|e| format!("Input path was not valid UTF8: {}", e))?;
+        Ok(InputFile {
+          path: Path::new(path),
+          content: file.content.as_slice(),
+        })
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    intrinsic.run(&files)
+  })).unwrap_or_else(|_| Err(format!("Intrinsic {} panicked.", intrinsic.name())));
+  let (bytes, is_error) = match result {
+    Ok(bytes) => (bytes, false),
+    Err(error) => (error.into_bytes(), true),
+  };
+  let mut bytes = mem::ManuallyDrop::new(bytes);
+  RawOutput {
+    ptr: bytes.as_mut_ptr(),
+    len: bytes.len(),
+    capacity: bytes.capacity(),
+    is_error: is_error,
+  }
+}
+
+unsafe extern "C" fn free_output(output: RawOutput) {
+  mem::drop(Vec::from_raw_parts(output.ptr, output.len, output.capacity));
+}
+
+///
+/// Defines the symbols which the engine uses to load a plugin, given a function which registers
+/// the plugin's Intrinsics. A plugin is a crate with `crate-type = ["cdylib"]` which invokes this
+/// macro once:
+///
+///   export_plugin!(register);
+///
+///   fn register(registry: &mut Registry) {
+///     registry.register(Box::new(MyIntrinsic));
+///   }
+///
+#[macro_export]
+macro_rules! export_plugin {
+  ($register:path) => {
+    #[no_mangle]
+    pub static PANTS_PLUGIN_ABI_VERSION: u32 = $crate::ABI_VERSION;
+
+    #[no_mangle]
+    pub unsafe extern "C" fn pants_plugin_register(
+      host: *mut ::std::os::raw::c_void,
+      add: $crate::AddIntrinsicFn,
+    ) -> bool {
+      $crate::register_with($register, host, add)
+    }
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{
+    register_with, InputFile, Intrinsic, RawInputFile, RawIntrinsic, RawSlice, Registry,
+  };
+  use std::os::raw::c_void;
+  use std::path::Path;
+  use std::slice;
+
+  struct TotalSize;
+
+  impl Intrinsic for TotalSize {
+    fn name(&self) -> &str {
+      "total_size"
+    }
+
+    fn run(&self, files: &[InputFile]) -> Result<Vec<u8>, String> {
+      let total: usize = files.iter().map(|f| f.content.len()).sum();
+      Ok(total.to_string().into_bytes())
+    }
+  }
+
+  fn register(registry: &mut Registry) {
+    registry.register(Box::new(TotalSize));
+  }
+
+  unsafe extern "C" fn add(host: *mut c_void, intrinsic: RawIntrinsic) {
+    (*(host as *mut Vec<RawIntrinsic>)).push(intrinsic);
+  }
+
+  #[test]
+  fn register_and_run() {
+    let mut registry = Registry::new();
+    register(&mut registry);
+    let intrinsics = registry.into_intrinsics();
+    assert_eq!(1, intrinsics.len());
+    assert_eq!("total_size", intrinsics[0].name());
+
+    let files = vec![
+      InputFile {
+        path: Path::new("a"),
+        content: b"abc",
+      },
+      InputFile {
+        path: Path::new("b"),
+        content: b"de",
+      },
+    ];
+    assert_eq!(Ok(b"5".to_vec()), intrinsics[0].run(&files));
+  }
+
+  #[test]
+  fn register_and_run_raw() {
+    let mut intrinsics: Vec<RawIntrinsic> = vec![];
+    assert!(unsafe {
+      register_with(register, &mut intrinsics as *mut _ as *mut c_void, add)
+    });
+    assert_eq!(1, intrinsics.len());
+    let intrinsic = &intrinsics[0];
+    assert_eq!(b"total_size", unsafe { intrinsic.name.as_slice() });
+
+    let files = vec![
+      RawInputFile {
+        path: RawSlice::new(b"a"),
+        content: RawSlice::new(b"abc"),
+      },
+      RawInputFile {
+        path: RawSlice::new(b"b"),
+        content: RawSlice::new(b"de"),
+      },
+    ];
+    unsafe {
+      let output = (intrinsic.run)(intrinsic.instance, files.as_ptr(), files.len());
+      assert!(!output.is_error);
+      assert_eq!(b"5", slice::from_raw_parts(output.ptr, output.len));
+      (intrinsic.free_output)(output);
+    }
+  }
+
+  #[test]
+  fn register_panic() {
+    fn panicking_register(_: &mut Registry) {
+      panic!("Could not register.");
+    }
+    let mut intrinsics: Vec<RawIntrinsic> = vec![];
+    assert!(!unsafe {
+      register_with(
+        panicking_register,
+        &mut intrinsics as *mut _ as *mut c_void,
+        add,
+      )
+    });
+    assert!(intrinsics.is_empty());
+  }
+}
@@ -0,0 +1,66 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+//!
+//! A plugin which is loaded by the engine's tests.
+//!
+
+#[macro_use]
+extern crate plugin_api;
+
+use plugin_api::{InputFile, Intrinsic, Registry};
+
+export_plugin!(register);
+
+fn register(registry: &mut Registry) {
+  registry.register(Box::new(TotalSize));
+  registry.register(Box::new(Fail));
+  registry.register(Box::new(Panic));
+}
+
+///
+/// Returns the total size of its input files, as a decimal string.
+///
+struct TotalSize;
+
+impl Intrinsic for TotalSize {
+  fn name(&self) -> &str {
+    "test_plugin_total_size"
+  }
+
+  fn run(&self, files: &[InputFile]) -> Result<Vec<u8>, String> {
+    let total: usize = files.iter().map(|f| f.content.len()).sum();
+    Ok(total.to_string().into_bytes())
+  }
+}
+
+///
+/// Fails, naming its input files.
+///
+struct Fail;
+
+impl Intrinsic for Fail {
+  fn name(&self) -> &str {
+    "test_plugin_fail"
+  }
+
+  fn run(&self, files: &[InputFile]) -> Result<Vec<u8>, String> {
+    let paths: Vec<_> = files.iter().map(|f| f.path.display().to_string()).collect();
+    Err(format!("Failed for {}", paths.join(", ")))
+  }
+}
+
+///
+/// Panics.
+///
+struct Panic;
+
+impl Intrinsic for Panic {
+  fn name(&self) -> &str {
+    "test_plugin_panic"
+  }
+
+  fn run(&self, _files: &[InputFile]) -> Result<Vec<u8>, String> {
+    panic!("Intrinsic panicked.")
+  }
+}
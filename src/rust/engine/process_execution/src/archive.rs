@@ -0,0 +1,1009 @@
+extern crate tempfile;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use bytes::Bytes;
+use fs;
+use futures::{future, Future};
+use hashing::{Digest, Fingerprint};
+use protobuf::Message;
+
+use super::{
+  CommandRunner, ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult,
+  ResultSource,
+};
+use remote::{request_digests, request_protos, CONTAINER_IMAGE_PROPERTY};
+use workunits::WorkUnit;
+
+///
+/// A directory holding the results of process executions, along with the contents of their output
+/// directories, which is written by a RecordingCommandRunner and read by a ReplayingCommandRunner.
+///
+/// The layout of an archive is:
+///   requests/<action fingerprint>: A description of the request, for human consumption.
+///   actions/<action fingerprint>: The Action proto for the request.
+///   results/<action fingerprint>: An ActionResult proto for the request.
+///   blobs/<fingerprint>: The bytes of the Command proto for each request, of the Directory protos
+///     of its input files (but not of the files themselves), and of each file and Directory proto
+///     below its output directory.
+///
+/// Entries are keyed by the action digest of their request (see `remote::request_digests`), and
+/// the output directory of a result is stored as the `tree_digest` of an OutputDirectory with an
+/// empty path.
+///
+#[derive(Clone)]
+pub struct Archive {
+  path: Arc<PathBuf>,
+}
+
+impl Archive {
+  pub fn new(path: PathBuf) -> Archive {
+    Archive {
+      path: Arc::new(path),
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  fn entry_path(&self, kind: &str, fingerprint: Fingerprint) -> PathBuf {
+    self.path.join(kind).join(fingerprint.to_hex())
+  }
+
+  fn read(&self, kind: &str, fingerprint: Fingerprint) -> Result<Option<Vec<u8>>, String> {
+    let path = self.entry_path(kind, fingerprint);
+    match ::std::fs::read(&path) {
+      Ok(bytes) => Ok(Some(bytes)),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+  }
+
+  ///
+  /// Writes an entry atomically, so that concurrent writers of an entry (and readers of a
+  /// partially written archive) never observe a partial entry.
+  ///
+  fn write(&self, kind: &str, digest: Digest, bytes: &[u8]) -> Result<(), String> {
+    let path = self.entry_path(kind, digest.0);
+    if path.exists() {
+      return Ok(());
+    }
+    let dir = self.path.join(kind);
+    ::std::fs::create_dir_all(&dir)
+      .and_then(|()| tempfile::NamedTempFile::new_in(&dir))
+      .and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.persist(&path).map_err(|e| e.error)
+      })
+      .map(|_| ())
+      .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+  }
+
+  fn read_blob(&self, digest: Digest) -> Result<Bytes, String> {
+    self
+      .read("blobs", digest.0)?
+      .map(Bytes::from)
+      .ok_or_else(|| format!("Archive {} does not contain {:?}", self.path.display(), digest))
+  }
+
+  ///
+  /// Records the given request and its result, along with the contents of its output directory,
+  /// which are loaded from the given Store.
+  ///
+  pub fn record(
+    &self,
+    store: fs::Store,
+    req: &ExecuteProcessRequest,
+    result: &FallibleExecuteProcessResult,
+  ) -> BoxFuture<(), String> {
+    let (action_digest, command_digest) = try_future!(request_digests(req));
+    let (action, command) = try_future!(request_protos(req));
+    let action_bytes = try_future!(
+      action
+        .write_to_bytes()
+        .map_err(|e| format!("Error serializing Action proto: {:?}", e))
+    );
+    let command_bytes = try_future!(
+      command
+        .write_to_bytes()
+        .map_err(|e| format!("Error serializing Command proto: {:?}", e))
+    );
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    action_result.set_stdout_raw(result.stdout.clone());
+    action_result.set_stderr_raw(result.stderr.clone());
+    action_result.set_exit_code(result.exit_code);
+    action_result.mut_output_directories().push({
+      let mut output_directory = bazel_protos::remote_execution::OutputDirectory::new();
+      output_directory.set_tree_digest((&result.output_directory).into());
+      output_directory
+    });
+    let request_description = format!("{:#?}\n", req);
+
+    let archive = self.clone();
+    self
+      .export_directory(store.clone(), result.output_directory, true)
+      .join(self.export_directory(store, req.input_files, false))
+      .and_then(move |_| {
+        // The result is written last, so that it is only visible once its outputs are.
+        archive.write("blobs", command_digest, &command_bytes)?;
+        archive.write("actions", action_digest, &action_bytes)?;
+        archive.write("requests", action_digest, request_description.as_bytes())?;
+        let bytes = action_result
+          .write_to_bytes()
+          .map_err(|e| format!("Error serializing ActionResult proto: {:?}", e))?;
+        archive.write("results", action_digest, &bytes)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Returns the recorded result of the given request (if any), after storing the contents of its
+  /// output directory in the given Store.
+  ///
+  pub fn replay(
+    &self,
+    store: fs::Store,
+    req: &ExecuteProcessRequest,
+  ) -> BoxFuture<Option<FallibleExecuteProcessResult>, String> {
+    let (action_digest, _) = try_future!(request_digests(req));
+    let bytes = match try_future!(self.read("results", action_digest.0)) {
+      Some(bytes) => bytes,
+      None => return future::ok(None).to_boxed(),
+    };
+    let mut action_result = bazel_protos::remote_execution::ActionResult::new();
+    try_future!(action_result.merge_from_bytes(&bytes).map_err(|e| format!(
+      "Archive {} contains an invalid result for {:?}: {:?}",
+      self.path.display(),
+      action_digest,
+      e
+    )));
+    let output_directory: Digest = try_future!(
+      action_result
+        .get_output_directories()
+        .first()
+        .ok_or_else(|| format!(
+          "Archive {} contains a result without an output directory for {:?}",
+          self.path.display(),
+          action_digest
+        ))
+        .and_then(|output_directory| output_directory.get_tree_digest().into())
+    );
+
+    self
+      .import_directory(store, output_directory)
+      .map(move |()| {
+        Some(FallibleExecuteProcessResult {
+          stdout: Bytes::from(action_result.get_stdout_raw()),
+          stderr: Bytes::from(action_result.get_stderr_raw()),
+          exit_code: action_result.get_exit_code(),
+          output_directory: output_directory,
+          stdout_overflow: None,
+          stderr_overflow: None,
+          source: ResultSource::LocalCache,
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Compares two recorded requests, identified by their action fingerprints (see
+  /// `remote::request_digests`): the first from this Archive, and the second from the given Archive,
+  /// which might have been recorded on another machine. This helps to explain why two requests
+  /// which were expected to share a cache entry did not.
+  ///
+  pub fn diff(
+    &self,
+    left: Fingerprint,
+    other: &Archive,
+    right: Fingerprint,
+  ) -> Result<RequestDiff, String> {
+    let left = self.load_request(left)?;
+    let right = other.load_request(right)?;
+    Ok(RequestDiff::between(&left, &right))
+  }
+
+  fn load_request(&self, action_fingerprint: Fingerprint) -> Result<RecordedRequest, String> {
+    let action_bytes = self.read("actions", action_fingerprint)?.ok_or_else(|| {
+      format!(
+        "Archive {} does not contain a request with action fingerprint {}",
+        self.path.display(),
+        action_fingerprint.to_hex()
+      )
+    })?;
+    let mut action = bazel_protos::remote_execution::Action::new();
+    action.merge_from_bytes(&action_bytes).map_err(|e| {
+      format!(
+        "Archive {} contains an invalid action {}: {:?}",
+        self.path.display(),
+        action_fingerprint.to_hex(),
+        e
+      )
+    })?;
+
+    let command_digest: Result<Digest, String> = action.get_command_digest().into();
+    let command_digest = command_digest?;
+    let mut command = bazel_protos::remote_execution::Command::new();
+    command
+      .merge_from_bytes(&self.read_blob(command_digest)?)
+      .map_err(|e| {
+        format!(
+          "Archive {} contains an invalid command {:?}: {:?}",
+          self.path.display(),
+          command_digest,
+          e
+        )
+      })?;
+
+    let input_root: Result<Digest, String> = action.get_input_root_digest().into();
+    let mut input_files = BTreeMap::new();
+    self.load_input_files(input_root?, PathBuf::new(), &mut input_files)?;
+
+    Ok(RecordedRequest {
+      argv: command.get_arguments().to_vec(),
+      env: command
+        .get_environment_variables()
+        .iter()
+        .map(|env| (env.get_name().to_owned(), env.get_value().to_owned()))
+        .collect(),
+      output_files: command.get_output_files().iter().cloned().collect(),
+      container_image: command
+        .get_platform()
+        .get_properties()
+        .iter()
+        .find(|property| property.get_name() == CONTAINER_IMAGE_PROPERTY)
+        .map(|property| property.get_value().to_owned()),
+      input_files: input_files,
+    })
+  }
+
+  fn load_input_files(
+    &self,
+    digest: Digest,
+    prefix: PathBuf,
+    input_files: &mut BTreeMap<PathBuf, Digest>,
+  ) -> Result<(), String> {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory
+      .merge_from_bytes(&self.read_blob(digest)?)
+      .map_err(|e| {
+        format!(
+          "Archive {} contains an invalid directory {:?}: {:?}",
+          self.path.display(),
+          digest,
+          e
+        )
+      })?;
+    for file in directory.get_files() {
+      let file_digest: Result<Digest, String> = file.get_digest().into();
+      input_files.insert(prefix.join(file.get_name()), file_digest?);
+    }
+    for subdir in directory.get_directories() {
+      let subdir_digest: Result<Digest, String> = subdir.get_digest().into();
+      self.load_input_files(subdir_digest?, prefix.join(subdir.get_name()), input_files)?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Writes the Directory protos below the given directory to the archive, along with the contents
+  /// of their files if `with_files` is set.
+  ///
+  fn export_directory(
+    &self,
+    store: fs::Store,
+    digest: Digest,
+    with_files: bool,
+  ) -> BoxFuture<(), String> {
+    let archive = self.clone();
+    store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| {
+        let directory = try_future!(
+          maybe_directory.ok_or_else(|| format!("Could not record unknown directory: {:?}", digest))
+        );
+        try_future!(
+          directory
+            .write_to_bytes()
+            .map_err(|e| format!("Error serializing directory proto {:?}: {:?}", directory, e))
+            .and_then(|bytes| archive.write("blobs", digest, &bytes))
+        );
+
+        let files = directory
+          .get_files()
+          .iter()
+          .filter(|_| with_files)
+          .map(|file| {
+            let digest: Digest = try_future!(file.get_digest().into());
+            let archive = archive.clone();
+            store
+              .load_file_bytes_with(digest, |bytes| bytes)
+              .and_then(move |maybe_bytes| {
+                maybe_bytes
+                  .ok_or_else(|| format!("Could not record unknown file: {:?}", digest))
+                  .and_then(|bytes| archive.write("blobs", digest, &bytes))
+              })
+              .to_boxed()
+          })
+          .collect::<Vec<_>>();
+        let subdirs = directory
+          .get_directories()
+          .iter()
+          .map(|subdir| {
+            let digest: Digest = try_future!(subdir.get_digest().into());
+            archive.export_directory(store.clone(), digest, with_files)
+          })
+          .collect::<Vec<_>>();
+        future::join_all(files)
+          .join(future::join_all(subdirs))
+          .map(|_| ())
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn import_directory(&self, store: fs::Store, digest: Digest) -> BoxFuture<(), String> {
+    let bytes = try_future!(self.read_blob(digest));
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    try_future!(directory.merge_from_bytes(&bytes).map_err(|e| format!(
+      "Archive {} contains an invalid directory {:?}: {:?}",
+      self.path.display(),
+      digest,
+      e
+    )));
+
+    let files = directory
+      .get_files()
+      .iter()
+      .map(|file| {
+        let digest: Digest = try_future!(file.get_digest().into());
+        let bytes = try_future!(self.read_blob(digest));
+        store
+          .store_file_bytes(bytes, true)
+          .and_then(move |stored| verify_digest(digest, stored))
+          .to_boxed()
+      })
+      .collect::<Vec<_>>();
+    let subdirs = directory
+      .get_directories()
+      .iter()
+      .map(|subdir| {
+        let digest: Digest = try_future!(subdir.get_digest().into());
+        self.import_directory(store.clone(), digest)
+      })
+      .collect::<Vec<_>>();
+    future::join_all(files)
+      .join(future::join_all(subdirs))
+      .and_then(move |_| store.record_directory(&directory, true))
+      .and_then(move |stored| verify_digest(digest, stored))
+      .to_boxed()
+  }
+}
+
+///
+/// The parts of a recorded request which contribute to its action digest.
+///
+struct RecordedRequest {
+  argv: Vec<String>,
+  env: BTreeMap<String, String>,
+  output_files: BTreeSet<String>,
+  container_image: Option<String>,
+  input_files: BTreeMap<PathBuf, Digest>,
+}
+
+///
+/// The differences between two recorded requests: see `Archive::diff`. Differences are represented
+/// as (left, right) pairs, in which `None` indicates that a request lacks an entry.
+///
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct RequestDiff {
+  pub argv: Option<(Vec<String>, Vec<String>)>,
+  pub env: BTreeMap<String, (Option<String>, Option<String>)>,
+  pub output_files: Option<(BTreeSet<String>, BTreeSet<String>)>,
+  pub container_image: Option<(Option<String>, Option<String>)>,
+  pub input_files: BTreeMap<PathBuf, (Option<Digest>, Option<Digest>)>,
+}
+
+impl RequestDiff {
+  fn between(left: &RecordedRequest, right: &RecordedRequest) -> RequestDiff {
+    RequestDiff {
+      argv: diff_values(&left.argv, &right.argv),
+      env: diff_maps(&left.env, &right.env),
+      output_files: diff_values(&left.output_files, &right.output_files),
+      container_image: diff_values(&left.container_image, &right.container_image),
+      input_files: diff_maps(&left.input_files, &right.input_files),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    *self == RequestDiff::default()
+  }
+}
+
+impl fmt::Display for RequestDiff {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.is_empty() {
+      return writeln!(f, "The requests do not differ.");
+    }
+    if let Some((ref left, ref right)) = self.argv {
+      writeln!(f, "argv:\n  - {:?}\n  + {:?}", left, right)?;
+    }
+    if !self.env.is_empty() {
+      writeln!(f, "env:")?;
+      for (name, &(ref left, ref right)) in &self.env {
+        let describe = |value: &Option<String>| match *value {
+          Some(ref value) => format!("{:?}", value),
+          None => "(unset)".to_owned(),
+        };
+        writeln!(f, "  {}: {} -> {}", name, describe(left), describe(right))?;
+      }
+    }
+    if let Some((ref left, ref right)) = self.output_files {
+      writeln!(f, "output files:\n  - {:?}\n  + {:?}", left, right)?;
+    }
+    if let Some((ref left, ref right)) = self.container_image {
+      writeln!(f, "container image:\n  - {:?}\n  + {:?}", left, right)?;
+    }
+    if !self.input_files.is_empty() {
+      writeln!(f, "input files:")?;
+      for (path, &(ref left, ref right)) in &self.input_files {
+        let describe = |digest: &Option<Digest>| match *digest {
+          Some(digest) => format!("{}:{}", digest.0.to_hex(), digest.1),
+          None => "(absent)".to_owned(),
+        };
+        writeln!(
+          f,
+          "  {}: {} -> {}",
+          path.display(),
+          describe(left),
+          describe(right)
+        )?;
+      }
+    }
+    Ok(())
+  }
+}
+
+fn diff_values<T: Clone + Eq>(left: &T, right: &T) -> Option<(T, T)> {
+  if left == right {
+    None
+  } else {
+    Some((left.clone(), right.clone()))
+  }
+}
+
+fn diff_maps<K: Clone + Ord, V: Clone + Eq>(
+  left: &BTreeMap<K, V>,
+  right: &BTreeMap<K, V>,
+) -> BTreeMap<K, (Option<V>, Option<V>)> {
+  left
+    .keys()
+    .chain(right.keys())
+    .filter(|key| left.get(*key) != right.get(*key))
+    .map(|key| (key.clone(), (left.get(key).cloned(), right.get(key).cloned())))
+    .collect()
+}
+
+fn verify_digest(expected: Digest, actual: Digest) -> Result<(), String> {
+  if expected == actual {
+    Ok(())
+  } else {
+    Err(format!(
+      "Archive contains corrupt content: expected {:?}, got {:?}",
+      expected, actual
+    ))
+  }
+}
+
+///
+/// A CommandRunner which records the results of the requests that it runs (via an underlying
+/// CommandRunner) to an Archive, so that they can later be replayed by a ReplayingCommandRunner.
+///
+/// Failures to run a request are not recorded, but results with non-zero exit codes are.
+///
+pub struct RecordingCommandRunner {
+  inner: Box<CommandRunner>,
+  store: fs::Store,
+  archive: Archive,
+}
+
+impl RecordingCommandRunner {
+  pub fn new(
+    inner: Box<CommandRunner>,
+    store: fs::Store,
+    archive: Archive,
+  ) -> RecordingCommandRunner {
+    RecordingCommandRunner {
+      inner,
+      store,
+      archive,
+    }
+  }
+}
+
+impl CommandRunner for RecordingCommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let store = self.store.clone();
+    let archive = self.archive.clone();
+    self
+      .inner
+      .run_in_workunit(req.clone(), workunit)
+      .and_then(move |result| {
+        archive
+          .record(store, &req, &result)
+          .map_err(move |e| {
+            ExecutionError::Fatal(format!(
+              "Failed to record the result of {:?} to {}: {}",
+              req.description,
+              archive.path().display(),
+              e
+            ))
+          })
+          .map(|()| result)
+      })
+      .to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.inner.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.inner.post_fork_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    self.inner.shutdown()
+  }
+
+  fn in_flight_operations(&self) -> Vec<String> {
+    self.inner.in_flight_operations()
+  }
+}
+
+///
+/// A CommandRunner which responds to requests with the results recorded in an Archive when it
+/// contains them, and otherwise runs them (via an underlying CommandRunner) and records their
+/// results, so that the Archive acts as a cache.
+///
+/// Results with non-zero exit codes are only recorded for requests which cache failures.
+///
+pub struct CachingCommandRunner {
+  inner: Arc<Box<CommandRunner>>,
+  store: fs::Store,
+  archive: Archive,
+}
+
+impl CachingCommandRunner {
+  pub fn new(
+    inner: Box<CommandRunner>,
+    store: fs::Store,
+    archive: Archive,
+  ) -> CachingCommandRunner {
+    CachingCommandRunner {
+      inner: Arc::new(inner),
+      store,
+      archive,
+    }
+  }
+}
+
+impl CommandRunner for CachingCommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let inner = self.inner.clone();
+    let store = self.store.clone();
+    let archive = self.archive.clone();
+    self
+      .archive
+      .replay(self.store.clone(), &req)
+      .map_err(ExecutionError::Fatal)
+      .and_then(move |maybe_result| match maybe_result {
+        Some(result) => future::ok(result).to_boxed(),
+        None => inner
+          .run_in_workunit(req.clone(), workunit)
+          .and_then(move |result| {
+            if result.exit_code != 0 && !req.cache_failures {
+              return future::ok(result).to_boxed();
+            }
+            archive
+              .record(store, &req, &result)
+              .map_err(move |e| {
+                ExecutionError::Fatal(format!(
+                  "Failed to cache the result of {:?} in {}: {}",
+                  req.description,
+                  archive.path().display(),
+                  e
+                ))
+              })
+              .map(|()| result)
+              .to_boxed()
+          })
+          .to_boxed(),
+      })
+      .to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.inner.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.inner.post_fork_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    self.inner.shutdown()
+  }
+
+  fn in_flight_operations(&self) -> Vec<String> {
+    self.inner.in_flight_operations()
+  }
+}
+
+///
+/// A CommandRunner which never executes anything: instead, it responds to each request with a
+/// result from an Archive recorded by a RecordingCommandRunner. A request without a recorded
+/// result fails.
+///
+/// This allows a build to be reproduced on a machine that lacks the tools (or the inputs) that the
+/// recorded processes used.
+///
+pub struct ReplayingCommandRunner {
+  store: fs::Store,
+  archive: Archive,
+}
+
+impl ReplayingCommandRunner {
+  pub fn new(store: fs::Store, archive: Archive) -> ReplayingCommandRunner {
+    ReplayingCommandRunner { store, archive }
+  }
+}
+
+impl CommandRunner for ReplayingCommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let archive_path = self.archive.path().to_owned();
+    self
+      .archive
+      .replay(self.store.clone(), &req)
+      .map_err(ExecutionError::Fatal)
+      .and_then(move |maybe_result| {
+        maybe_result.ok_or_else(|| {
+          ExecutionError::Fatal(format!(
+            "Archive {} does not contain a result for {:?}: {:?}",
+            archive_path.display(),
+            req.description,
+            req.argv
+          ))
+        })
+      })
+      .to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.store.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.store.post_fork_in_child()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::fake;
+  use super::super::CommandRunner as CommandRunnerTrait;
+  use super::super::{ExecuteProcessRequest, FallibleExecuteProcessResult, ResultSource};
+  use super::{
+    Archive, CachingCommandRunner, RecordingCommandRunner, ReplayingCommandRunner, RequestDiff,
+  };
+  use bytes::Bytes;
+  use fs;
+  use futures::Future;
+  use remote::request_digests;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use tempfile;
+  use testing::request;
+  use testutil::data::{TestData, TestDirectory};
+
+  fn new_store(dir: &tempfile::TempDir) -> fs::Store {
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    fs::Store::local_only(dir.path(), pool).unwrap()
+  }
+
+  #[test]
+  fn record_and_replay() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let archive = Archive::new(archive_dir.path().to_owned());
+
+    let result = FallibleExecuteProcessResult {
+      stdout: Bytes::from("stdout"),
+      stderr: Bytes::from("stderr"),
+      exit_code: 1,
+      output_directory: TestDirectory::nested().digest(),
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::Fake,
+    };
+
+    // Record the result of a request, with outputs that only exist in the recording Store.
+    let recording_store_dir = tempfile::TempDir::new().unwrap();
+    let recording_store = new_store(&recording_store_dir);
+    recording_store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .unwrap();
+    recording_store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .unwrap();
+    recording_store
+      .record_directory(&TestDirectory::nested().directory(), false)
+      .wait()
+      .unwrap();
+    let fake = fake::CommandRunner::new();
+    fake
+      .respond(&request(&["/bin/cat", "roland"]), result.clone())
+      .unwrap();
+    let recorder =
+      RecordingCommandRunner::new(Box::new(fake), recording_store, archive.clone());
+    assert_eq!(
+      recorder.run(request(&["/bin/cat", "roland"])).wait(),
+      Ok(result.clone())
+    );
+
+    // And replay it into an empty Store.
+    let replaying_store_dir = tempfile::TempDir::new().unwrap();
+    let replaying_store = new_store(&replaying_store_dir);
+    let replayer = ReplayingCommandRunner::new(replaying_store.clone(), archive);
+    assert_eq!(
+      replayer.run(request(&["/bin/cat", "roland"])).wait(),
+      Ok(FallibleExecuteProcessResult {
+        source: ResultSource::LocalCache,
+        ..result
+      })
+    );
+    assert_eq!(
+      replaying_store
+        .load_file_bytes_with(TestData::roland().digest(), |bytes| bytes)
+        .wait(),
+      Ok(Some(TestData::roland().bytes()))
+    );
+    assert_eq!(
+      replaying_store
+        .load_directory(TestDirectory::nested().digest())
+        .wait(),
+      Ok(Some(TestDirectory::nested().directory()))
+    );
+  }
+
+  #[test]
+  fn replay_unrecorded() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let replayer = ReplayingCommandRunner::new(
+      new_store(&store_dir),
+      Archive::new(archive_dir.path().to_owned()),
+    );
+
+    let error = replayer
+      .run(request(&["/bin/cat", "roland"]))
+      .wait()
+      .expect_err("Want error");
+    assert!(
+      error
+        .message()
+        .contains("does not contain a result for \"archived\""),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  fn failures_are_not_recorded() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let recorder = RecordingCommandRunner::new(
+      Box::new(fake::CommandRunner::new()),
+      new_store(&store_dir),
+      Archive::new(archive_dir.path().to_owned()),
+    );
+
+    recorder
+      .run(request(&["/bin/cat", "roland"]))
+      .wait()
+      .expect_err("Want error");
+    assert!(!archive_dir.path().join("results").exists());
+  }
+
+  #[test]
+  fn caching_runs_only_uncached_requests() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let result = FallibleExecuteProcessResult {
+      stdout: Bytes::from("stdout"),
+      stderr: Bytes::new(),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::Fake,
+    };
+    let fake = fake::CommandRunner::new();
+    fake
+      .respond(&request(&["/bin/echo", "stdout"]), result.clone())
+      .unwrap();
+    let cacher = CachingCommandRunner::new(
+      Box::new(fake.clone()),
+      new_store(&store_dir),
+      Archive::new(archive_dir.path().to_owned()),
+    );
+
+    assert_eq!(
+      cacher.run(request(&["/bin/echo", "stdout"])).wait(),
+      Ok(result.clone())
+    );
+    assert_eq!(
+      cacher.run(request(&["/bin/echo", "stdout"])).wait(),
+      Ok(FallibleExecuteProcessResult {
+        source: ResultSource::LocalCache,
+        ..result
+      })
+    );
+    assert_eq!(fake.invocations().len(), 1);
+  }
+
+  #[test]
+  fn caching_respects_cache_failures() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let req = ExecuteProcessRequest {
+      cache_failures: false,
+      ..request(&["/bin/false"])
+    };
+    let fake = fake::CommandRunner::new();
+    fake
+      .respond(
+        &req,
+        FallibleExecuteProcessResult {
+          stdout: Bytes::new(),
+          stderr: Bytes::new(),
+          exit_code: 1,
+          output_directory: fs::EMPTY_DIGEST,
+          stdout_overflow: None,
+          stderr_overflow: None,
+          source: ResultSource::Fake,
+        },
+      )
+      .unwrap();
+    let cacher = CachingCommandRunner::new(
+      Box::new(fake.clone()),
+      new_store(&store_dir),
+      Archive::new(archive_dir.path().to_owned()),
+    );
+
+    for _ in 0..2 {
+      assert_eq!(cacher.run(req.clone()).wait().map(|r| r.exit_code), Ok(1));
+    }
+    assert_eq!(fake.invocations().len(), 2);
+  }
+
+  #[test]
+  fn diff_recorded_requests() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let archive = Archive::new(archive_dir.path().to_owned());
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let store = new_store(&store_dir);
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .unwrap();
+    store
+      .record_directory(
+        &TestDirectory::containing_roland_and_treats().directory(),
+        false,
+      )
+      .wait()
+      .unwrap();
+
+    let left = ExecuteProcessRequest {
+      env: vec![("FOO".to_owned(), "local".to_owned())]
+        .into_iter()
+        .collect(),
+      input_files: TestDirectory::containing_roland().digest(),
+      ..request(&["/bin/cat", "roland"])
+    };
+    let right = ExecuteProcessRequest {
+      env: vec![
+        ("FOO".to_owned(), "ci".to_owned()),
+        ("CI".to_owned(), "true".to_owned()),
+      ].into_iter()
+        .collect(),
+      input_files: TestDirectory::containing_roland_and_treats().digest(),
+      container_image: Some("debian:stretch".to_owned()),
+      ..request(&["/bin/cat", "roland"])
+    };
+
+    let fake = fake::CommandRunner::new();
+    let result = FallibleExecuteProcessResult {
+      stdout: Bytes::from("roland"),
+      stderr: Bytes::new(),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::Fake,
+    };
+    fake.respond(&left, result.clone()).unwrap();
+    fake.respond(&right, result).unwrap();
+    let recorder = RecordingCommandRunner::new(Box::new(fake), store, archive.clone());
+    recorder.run(left.clone()).wait().unwrap();
+    recorder.run(right.clone()).wait().unwrap();
+
+    let (left_action_digest, _) = request_digests(&left).unwrap();
+    let (right_action_digest, _) = request_digests(&right).unwrap();
+    let left_fingerprint = left_action_digest.0;
+    let right_fingerprint = right_action_digest.0;
+    let diff = archive
+      .diff(left_fingerprint, &archive, right_fingerprint)
+      .unwrap();
+    assert_eq!(
+      diff,
+      RequestDiff {
+        argv: None,
+        env: vec![
+          ("CI".to_owned(), (None, Some("true".to_owned()))),
+          (
+            "FOO".to_owned(),
+            (Some("local".to_owned()), Some("ci".to_owned())),
+          ),
+        ].into_iter()
+          .collect(),
+        output_files: None,
+        container_image: Some((None, Some("debian:stretch".to_owned()))),
+        input_files: vec![(
+          PathBuf::from("treats"),
+          (None, Some(TestData::catnip().digest())),
+        )].into_iter()
+          .collect(),
+      }
+    );
+    assert!(
+      archive
+        .diff(left_fingerprint, &archive, left_fingerprint)
+        .unwrap()
+        .is_empty()
+    );
+  }
+}
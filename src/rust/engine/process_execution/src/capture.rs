@@ -0,0 +1,380 @@
+extern crate tempfile;
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use boxfuture::{BoxFuture, Boxable};
+use bytes::{Bytes, BytesMut};
+use futures::{future, Future};
+use hashing::Digest;
+
+use FallibleExecuteProcessResult;
+
+///
+/// What happens to the output of a process beyond the limit on how much of it is captured.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputOverflow {
+  // The overflow is dropped, and the captured output ends with a marker saying how much was.
+  Truncate,
+  // As for Truncate, but the complete output is also spilled to disk while the process runs, and
+  // then stored, so that the result can reference it by Digest.
+  Store,
+}
+
+impl OutputOverflow {
+  pub fn create(overflow: &str) -> Result<OutputOverflow, String> {
+    match overflow {
+      "truncate" => Ok(OutputOverflow::Truncate),
+      "store" => Ok(OutputOverflow::Store),
+      _ => Err(format!("Unrecognized output overflow: {}.", overflow)),
+    }
+  }
+}
+
+///
+/// A limit on the number of bytes of each of the stdout and stderr of a process which are captured
+/// in memory.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutputLimit {
+  pub max_bytes: Option<usize>,
+  pub overflow: OutputOverflow,
+}
+
+impl OutputLimit {
+  pub fn unlimited() -> OutputLimit {
+    OutputLimit {
+      max_bytes: None,
+      overflow: OutputOverflow::Truncate,
+    }
+  }
+
+  ///
+  /// Returns the limit for a request which sets its own maximum (if any), which may only lower
+  /// this limit.
+  ///
+  pub fn for_request(&self, request_max_bytes: Option<usize>) -> OutputLimit {
+    let max_bytes = match (self.max_bytes, request_max_bytes) {
+      (Some(global), Some(request)) => Some(min(global, request)),
+      (global, request) => global.or(request),
+    };
+    OutputLimit {
+      max_bytes,
+      overflow: self.overflow,
+    }
+  }
+}
+
+///
+/// One output stream of a process, captured up to an OutputLimit.
+///
+pub struct CapturedOutput {
+  limit: OutputLimit,
+  spill_dir: PathBuf,
+  captured: BytesMut,
+  total_bytes: usize,
+  // The complete output, once it has overflowed, if the overflow is to be stored.
+  spill: Option<File>,
+}
+
+impl CapturedOutput {
+  ///
+  /// If the overflow is to be stored, it is spilled into an anonymous file in `spill_dir`.
+  ///
+  pub fn new(limit: OutputLimit, spill_dir: &Path) -> CapturedOutput {
+    CapturedOutput {
+      limit,
+      spill_dir: spill_dir.to_owned(),
+      captured: BytesMut::with_capacity(min(8192, limit.max_bytes.unwrap_or(8192))),
+      total_bytes: 0,
+      spill: None,
+    }
+  }
+
+  pub fn extend(&mut self, bytes: &[u8]) -> Result<(), String> {
+    self.total_bytes += bytes.len();
+    let max_bytes = match self.limit.max_bytes {
+      Some(max_bytes) => max_bytes,
+      None => {
+        self.captured.extend_from_slice(bytes);
+        return Ok(());
+      }
+    };
+
+    let room = min(max_bytes - self.captured.len(), bytes.len());
+    self.captured.extend_from_slice(&bytes[..room]);
+    if self.total_bytes <= max_bytes || self.limit.overflow != OutputOverflow::Store {
+      return Ok(());
+    }
+
+    if self.spill.is_none() {
+      // This is the first overflow, so everything before it needs to be spilled as well.
+      let mut spill = tempfile::tempfile_in(&self.spill_dir)
+        .map_err(|e| format!("Error creating file to spill process output to: {:?}", e))?;
+      spill
+        .write_all(&self.captured)
+        .map_err(|e| format!("Error spilling process output: {:?}", e))?;
+      self.spill = Some(spill);
+    }
+    self
+      .spill
+      .as_mut()
+      .unwrap()
+      .write_all(&bytes[room..])
+      .map_err(|e| format!("Error spilling process output: {:?}", e))
+  }
+
+  ///
+  /// Returns the captured output, and whether (and how) it overflowed. A spilled overflow is
+  /// streamed into the Store, rather than read back into memory.
+  ///
+  pub fn finish(self, store: &::fs::Store) -> BoxFuture<(Bytes, Option<Overflow>), String> {
+    let dropped_bytes = match self.limit.max_bytes {
+      Some(max_bytes) if self.total_bytes > max_bytes => self.total_bytes - max_bytes,
+      _ => return future::ok((self.captured.freeze(), None)).to_boxed(),
+    };
+
+    let captured = self.captured;
+    match self.spill {
+      None => future::ok(with_trailer(captured, dropped_bytes, None)).to_boxed(),
+      Some(spill) => store
+        .store_file_from(spill, true)
+        .map_err(|e| format!("Error storing spilled process output: {}", e))
+        .map(move |digest| with_trailer(captured, dropped_bytes, Some(digest)))
+        .to_boxed(),
+    }
+  }
+}
+
+///
+/// How the output of a process overflowed its OutputLimit.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Overflow {
+  pub dropped_bytes: usize,
+  // The Digest of the complete output, if the overflow was stored.
+  pub digest: Option<Digest>,
+}
+
+impl OutputLimit {
+  ///
+  /// Applies this limit to a result whose output was captured in full (for example, by a remote
+  /// execution server), as if it had been captured with a CapturedOutput.
+  ///
+  pub fn apply(
+    &self,
+    result: FallibleExecuteProcessResult,
+    store: &::fs::Store,
+  ) -> BoxFuture<FallibleExecuteProcessResult, String> {
+    let FallibleExecuteProcessResult {
+      stdout,
+      stderr,
+      exit_code,
+      output_directory,
+      stdout_overflow,
+      stderr_overflow,
+      source,
+    } = result;
+    self
+      .apply_to(stdout, stdout_overflow, store)
+      .join(self.apply_to(stderr, stderr_overflow, store))
+      .map(
+        move |((stdout, stdout_overflow), (stderr, stderr_overflow))| {
+          FallibleExecuteProcessResult {
+            stdout,
+            stderr,
+            exit_code,
+            output_directory,
+            stdout_overflow,
+            stderr_overflow,
+            source,
+          }
+        },
+      )
+      .to_boxed()
+  }
+
+  fn apply_to(
+    &self,
+    output: Bytes,
+    overflow: Option<Overflow>,
+    store: &::fs::Store,
+  ) -> BoxFuture<(Bytes, Option<Overflow>), String> {
+    let max_bytes = match self.max_bytes {
+      Some(max_bytes) if overflow.is_none() && output.len() > max_bytes => max_bytes,
+      _ => return future::ok((output, overflow)).to_boxed(),
+    };
+    let dropped_bytes = output.len() - max_bytes;
+    let captured = BytesMut::from(&output[..max_bytes]);
+    match self.overflow {
+      OutputOverflow::Truncate => {
+        future::ok(with_trailer(captured, dropped_bytes, None)).to_boxed()
+      }
+      OutputOverflow::Store => store
+        .store_file_bytes(output, true)
+        .map(move |digest| with_trailer(captured, dropped_bytes, Some(digest)))
+        .to_boxed(),
+    }
+  }
+}
+
+fn with_trailer(
+  mut captured: BytesMut,
+  dropped_bytes: usize,
+  digest: Option<Digest>,
+) -> (Bytes, Option<Overflow>) {
+  let trailer = match digest {
+    Some(digest) => format!(
+      "\n[{} more bytes of output were truncated: complete output stored as {}]\n",
+      dropped_bytes, digest.0
+    ),
+    None => format!("\n[{} more bytes of output were truncated]\n", dropped_bytes),
+  };
+  captured.extend_from_slice(trailer.as_bytes());
+  (
+    captured.freeze(),
+    Some(Overflow {
+      dropped_bytes,
+      digest,
+    }),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{CapturedOutput, OutputLimit, OutputOverflow, Overflow};
+  use bytes::Bytes;
+  use fs;
+  use futures::Future;
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use {FallibleExecuteProcessResult, ResultSource};
+
+  #[test]
+  fn unlimited() {
+    let (store, dirs) = new_store();
+    let mut output = CapturedOutput::new(OutputLimit::unlimited(), dirs.1.path());
+    output.extend(b"European ").unwrap();
+    output.extend(b"Burmese").unwrap();
+    assert_eq!(
+      output.finish(&store).wait(),
+      Ok((Bytes::from("European Burmese"), None))
+    );
+  }
+
+  #[test]
+  fn within_limit() {
+    let (store, dirs) = new_store();
+    let mut output = CapturedOutput::new(limit(16, OutputOverflow::Store), dirs.1.path());
+    output.extend(b"European Burmese").unwrap();
+    assert_eq!(
+      output.finish(&store).wait(),
+      Ok((Bytes::from("European Burmese"), None))
+    );
+  }
+
+  #[test]
+  fn truncates_overflow() {
+    let (store, dirs) = new_store();
+    let mut output = CapturedOutput::new(limit(10, OutputOverflow::Truncate), dirs.1.path());
+    output.extend(b"European ").unwrap();
+    output.extend(b"Burmese").unwrap();
+    assert_eq!(
+      output.finish(&store).wait(),
+      Ok((
+        Bytes::from("European B\n[6 more bytes of output were truncated]\n"),
+        Some(Overflow {
+          dropped_bytes: 6,
+          digest: None,
+        })
+      ))
+    );
+  }
+
+  #[test]
+  fn stores_overflow() {
+    let (store, dirs) = new_store();
+    let mut output = CapturedOutput::new(limit(10, OutputOverflow::Store), dirs.1.path());
+    output.extend(b"European ").unwrap();
+    output.extend(b"Bur").unwrap();
+    output.extend(b"mese").unwrap();
+    let (captured, overflow) = output.finish(&store).wait().unwrap();
+
+    let overflow = overflow.expect("Want the output to have overflowed");
+    assert_eq!(overflow.dropped_bytes, 6);
+    let digest = overflow.digest.expect("Want a Digest for the overflow");
+    assert!(captured.starts_with(b"European B\n[6 more bytes of output were truncated"));
+    assert_eq!(
+      store.load_file_bytes_with(digest, |bytes| bytes).wait(),
+      Ok(Some(Bytes::from("European Burmese")))
+    );
+  }
+
+  #[test]
+  fn applies_to_complete_result() {
+    let (store, _dirs) = new_store();
+    let result = FallibleExecuteProcessResult {
+      stdout: Bytes::from("European Burmese"),
+      stderr: Bytes::from("meow"),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::ExecutedRemotely,
+    };
+
+    let limited = limit(10, OutputOverflow::Store)
+      .apply(result.clone(), &store)
+      .wait()
+      .unwrap();
+    assert!(limited.stdout.starts_with(b"European B\n[6 more bytes of output were truncated"));
+    let digest = limited
+      .stdout_overflow
+      .and_then(|overflow| overflow.digest)
+      .expect("Want a Digest for the overflow");
+    assert_eq!(
+      store.load_file_bytes_with(digest, |bytes| bytes).wait(),
+      Ok(Some(Bytes::from("European Burmese")))
+    );
+    assert_eq!(limited.stderr, Bytes::from("meow"));
+    assert_eq!(limited.stderr_overflow, None);
+
+    assert_eq!(
+      OutputLimit::unlimited().apply(result.clone(), &store).wait(),
+      Ok(result)
+    );
+  }
+
+  #[test]
+  fn request_limit_lowers_global_limit() {
+    let global = limit(10, OutputOverflow::Store);
+    assert_eq!(global.for_request(None), global);
+    assert_eq!(global.for_request(Some(20)), global);
+    assert_eq!(
+      global.for_request(Some(5)),
+      limit(5, OutputOverflow::Store)
+    );
+    assert_eq!(
+      OutputLimit::unlimited().for_request(Some(5)),
+      limit(5, OutputOverflow::Truncate)
+    );
+  }
+
+  fn limit(max_bytes: usize, overflow: OutputOverflow) -> OutputLimit {
+    OutputLimit {
+      max_bytes: Some(max_bytes),
+      overflow,
+    }
+  }
+
+  fn new_store() -> (fs::Store, (TempDir, TempDir)) {
+    let store_dir = TempDir::new().unwrap();
+    let spill_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool).unwrap();
+    (store, (store_dir, spill_dir))
+  }
+}
@@ -0,0 +1,191 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+///
+/// The minimum interval between measurements of the disk usage of a running process with a quota.
+/// A process may exceed its quota by however much it can write in this time before it is
+/// terminated.
+///
+pub const CHECK_INTERVAL_MILLIS: u64 = 250;
+
+///
+/// The interval between measurements is stretched to this multiple of the time that the last
+/// measurement took, so that walking a large sandbox cannot monopolize the pool that it runs on.
+///
+pub const CHECK_INTERVAL_PER_MEASUREMENT_TIME: u32 = 4;
+
+///
+/// Measures the bytes that a process writes into its sandbox, and optionally enforces a quota on
+/// them. Also measures the growth of the writable cache directories that the process shares with
+/// other processes, which is reported (see `DiskUsage`) but never counts towards the quota: other
+/// processes which are running at the same time may write to the caches too.
+///
+/// Bytes written are measured as the growth in the total size of the files beneath those
+/// directories since the process was spawned: files which the process deletes before it is
+/// measured do not count. The caches, which may be large, are only measured when the process
+/// starts and when it finishes.
+///
+pub struct DiskQuota {
+  sandbox: PathBuf,
+  sandbox_baseline: u64,
+  caches: Vec<PathBuf>,
+  cache_baseline: u64,
+  max_bytes: Option<u64>,
+}
+
+///
+/// The bytes that a process wrote: see `DiskQuota`.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiskUsage {
+  pub sandbox_bytes: u64,
+  // The growth of the shared caches while the process ran, which other processes may have
+  // contributed to.
+  pub cache_bytes: u64,
+}
+
+impl DiskQuota {
+  ///
+  /// Measures the current size of the given sandbox and cache directories, against which later
+  /// measurements are compared.
+  ///
+  pub fn start(
+    sandbox: PathBuf,
+    caches: Vec<PathBuf>,
+    max_bytes: Option<u64>,
+  ) -> Result<DiskQuota, String> {
+    let sandbox_baseline = usage(&sandbox)?;
+    let cache_baseline = total_usage(&caches)?;
+    Ok(DiskQuota {
+      sandbox,
+      sandbox_baseline,
+      caches,
+      cache_baseline,
+      max_bytes,
+    })
+  }
+
+  pub fn max_bytes(&self) -> Option<u64> {
+    self.max_bytes
+  }
+
+  ///
+  /// Returns the number of bytes written into the sandbox since the quota was started, or an error
+  /// if they exceed the quota.
+  ///
+  pub fn check(&self) -> Result<u64, String> {
+    let written = usage(&self.sandbox)?.saturating_sub(self.sandbox_baseline);
+    match self.max_bytes {
+      Some(max_bytes) if written > max_bytes => Err(format!(
+        "Process exceeded its disk quota of {} bytes: it wrote at least {} bytes into its \
+         sandbox.",
+        max_bytes, written
+      )),
+      _ => Ok(written),
+    }
+  }
+
+  ///
+  /// Checks the quota one last time (to catch a process which exceeded it between checks), and
+  /// measures the growth of the caches.
+  ///
+  pub fn finish(&self) -> Result<DiskUsage, String> {
+    let sandbox_bytes = self.check()?;
+    let cache_bytes = total_usage(&self.caches)?.saturating_sub(self.cache_baseline);
+    Ok(DiskUsage {
+      sandbox_bytes,
+      cache_bytes,
+    })
+  }
+}
+
+fn total_usage(dirs: &[PathBuf]) -> Result<u64, String> {
+  dirs.iter().map(|dir| usage(dir)).sum()
+}
+
+fn usage(dir: &Path) -> Result<u64, String> {
+  disk_usage(dir)
+    .map_err(|e| format!("Error measuring the disk usage of {}: {}", dir.display(), e))
+}
+
+///
+/// The total size of the files beneath the given path, at any depth. Symlinks are not followed (so
+/// immutable inputs which are symlinked into a sandbox do not count), and files which disappear
+/// while they are being measured are skipped.
+///
+pub fn disk_usage(path: &Path) -> io::Result<u64> {
+  let metadata = match fs::symlink_metadata(path) {
+    Ok(metadata) => metadata,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(e),
+  };
+  if !metadata.is_dir() {
+    return Ok(metadata.len());
+  }
+  let entries = match fs::read_dir(path) {
+    Ok(entries) => entries,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(e),
+  };
+  let mut total = 0;
+  for entry in entries {
+    total += disk_usage(&entry?.path())?;
+  }
+  Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+
+  use super::{disk_usage, DiskQuota, DiskUsage};
+  use std::fs;
+
+  #[test]
+  fn measures_recursively() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::write(dir.path().join("a"), b"12345").unwrap();
+    fs::create_dir_all(dir.path().join("b/c")).unwrap();
+    fs::write(dir.path().join("b/c/d"), b"123").unwrap();
+    assert_eq!(disk_usage(dir.path()).unwrap(), 8);
+    assert_eq!(disk_usage(&dir.path().join("missing")).unwrap(), 0);
+  }
+
+  #[test]
+  fn measures_growth_against_quota() {
+    let sandbox = tempfile::TempDir::new().unwrap();
+    let cache = tempfile::TempDir::new().unwrap();
+    fs::write(sandbox.path().join("input"), b"pre-existing").unwrap();
+    fs::write(cache.path().join("existing"), b"pre-existing").unwrap();
+    let quota = DiskQuota::start(
+      sandbox.path().to_owned(),
+      vec![cache.path().to_owned()],
+      Some(10),
+    ).unwrap();
+    assert_eq!(quota.check(), Ok(0));
+
+    fs::write(sandbox.path().join("output"), b"12345").unwrap();
+    // Growth of the caches is reported, but does not count towards the quota.
+    fs::write(cache.path().join("entry"), b"123456789012345").unwrap();
+    assert_eq!(quota.check(), Ok(5));
+    assert_eq!(
+      quota.finish(),
+      Ok(DiskUsage {
+        sandbox_bytes: 5,
+        cache_bytes: 15,
+      })
+    );
+
+    fs::write(sandbox.path().join("another"), b"123456").unwrap();
+    assert_eq!(
+      quota.check(),
+      Err(
+        "Process exceeded its disk quota of 10 bytes: it wrote at least 11 bytes into its \
+         sandbox."
+          .to_owned()
+      )
+    );
+    assert!(quota.finish().is_err());
+  }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::future;
+use hashing::Digest;
+
+use super::{ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult};
+use remote::request_digests;
+
+///
+/// A deterministic CommandRunner for tests, which never executes anything: instead, it responds to
+/// each request with a canned result (keyed by the action digest of the request: see
+/// `remote::request_digests`), and records the requests that it was asked to run.
+///
+/// A request without a canned result fails deterministically, so it is not retried (see
+/// `layers::RetryingCommandRunner`), and is recorded exactly once.
+///
+#[derive(Clone, Default)]
+pub struct CommandRunner {
+  inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+  responses: HashMap<Digest, FallibleExecuteProcessResult>,
+  invocations: Vec<ExecuteProcessRequest>,
+}
+
+impl CommandRunner {
+  pub fn new() -> CommandRunner {
+    CommandRunner::default()
+  }
+
+  ///
+  /// Responds to all future runs of the given request with the given result.
+  ///
+  pub fn respond(
+    &self,
+    req: &ExecuteProcessRequest,
+    result: FallibleExecuteProcessResult,
+  ) -> Result<(), String> {
+    let (action_digest, _) = request_digests(req)?;
+    let mut inner = self.inner.lock().unwrap();
+    inner.responses.insert(action_digest, result);
+    Ok(())
+  }
+
+  ///
+  /// Returns the requests that have been run so far, in the order that they were run.
+  ///
+  pub fn invocations(&self) -> Vec<ExecuteProcessRequest> {
+    let inner = self.inner.lock().unwrap();
+    inner.invocations.clone()
+  }
+}
+
+impl super::CommandRunner for CommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let mut inner = self.inner.lock().unwrap();
+    let result = request_digests(&req).and_then(|(action_digest, _)| {
+      inner
+        .responses
+        .get(&action_digest)
+        .cloned()
+        .ok_or_else(|| {
+          format!(
+            "No canned result for {:?} (with action digest {:?}): {:?}",
+            req.description, action_digest, req.argv
+          )
+        })
+    });
+    inner.invocations.push(req);
+    future::result(result.map_err(ExecutionError::Fatal)).to_boxed()
+  }
+
+  fn pre_fork(&self) {}
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::CommandRunner as CommandRunnerTrait;
+  use super::super::{FallibleExecuteProcessResult, ResultSource};
+  use super::CommandRunner;
+  use bytes::Bytes;
+  use fs;
+  use futures::Future;
+  use testing::request;
+
+  fn result(stdout: &str) -> FallibleExecuteProcessResult {
+    FallibleExecuteProcessResult {
+      stdout: Bytes::from(stdout),
+      stderr: Bytes::new(),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::Fake,
+    }
+  }
+
+  #[test]
+  fn canned_result() {
+    let runner = CommandRunner::new();
+    runner.respond(&request(&["/bin/echo", "foo"]), result("foo\n")).unwrap();
+    runner.respond(&request(&["/bin/echo", "bar"]), result("bar\n")).unwrap();
+
+    assert_eq!(
+      runner.run(request(&["/bin/echo", "bar"])).wait(),
+      Ok(result("bar\n"))
+    );
+    assert_eq!(
+      runner.run(request(&["/bin/echo", "foo"])).wait(),
+      Ok(result("foo\n"))
+    );
+    assert_eq!(
+      runner.invocations(),
+      vec![request(&["/bin/echo", "bar"]), request(&["/bin/echo", "foo"])]
+    );
+  }
+
+  #[test]
+  fn no_canned_result() {
+    let runner = CommandRunner::new();
+    runner.respond(&request(&["/bin/echo", "foo"]), result("foo\n")).unwrap();
+
+    let error = runner
+      .run(request(&["/bin/echo", "bar"]))
+      .wait()
+      .expect_err("Want error");
+    assert!(
+      error
+        .message()
+        .starts_with("No canned result for \"/bin/echo bar\""),
+      "{}",
+      error
+    );
+    // Retrying the request would fail again, so the failure is not transient.
+    assert!(!error.is_transient());
+    // Failed requests are recorded too.
+    assert_eq!(runner.invocations(), vec![request(&["/bin/echo", "bar"])]);
+  }
+}
@@ -0,0 +1,502 @@
+extern crate tempfile;
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use boxfuture::{BoxFuture, Boxable};
+use futures::sync::oneshot;
+use futures::{future, Future};
+use hashing::{Digest, Fingerprint};
+
+///
+/// Shared, read-only copies of the Directories which processes declare that they will not modify
+/// (such as interpreters and toolchains), which are symlinked into sandboxes rather than being
+/// materialized into each of them.
+///
+/// Each Directory is materialized at most once per `work_dir` (including across runs, because the
+/// copies are named by Digest), and is made read-only before it is used, so that a process which
+/// modifies its "immutable" inputs fails, rather than affecting other processes.
+///
+/// The copies are garbage collected along with the Store: a copy is removed (after its write
+/// permissions are restored) once its Directory is no longer stored locally, unless it has been
+/// used by this process.
+///
+#[derive(Clone)]
+pub struct ImmutableRoots {
+  store: ::fs::Store,
+  fs_pool: Arc<::fs::ResettablePool>,
+  dir: PathBuf,
+  inner: Arc<Mutex<HashMap<Digest, RootState>>>,
+}
+
+enum RootState {
+  Materialized,
+  // The root is being materialized, and these callers are waiting for it.
+  Materializing(Vec<oneshot::Sender<Result<(), String>>>),
+}
+
+impl ImmutableRoots {
+  pub fn new(
+    store: ::fs::Store,
+    fs_pool: Arc<::fs::ResettablePool>,
+    work_dir: &Path,
+  ) -> ImmutableRoots {
+    let dir = work_dir.join("immutable_roots");
+    let inner = Arc::new(Mutex::new(HashMap::new()));
+    store.register_external_copies(Arc::new(RootsCollector {
+      dir: dir.clone(),
+      inner: inner.clone(),
+    }));
+    ImmutableRoots {
+      store,
+      fs_pool,
+      dir,
+      inner,
+    }
+  }
+
+  ///
+  /// The directory which holds the read-only copies, and which the symlinks in sandboxes point
+  /// into.
+  ///
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  ///
+  /// Symlinks a read-only copy of each of the given Directories into the sandbox, at the given
+  /// paths relative to it. Any parent directories of the paths must already exist.
+  ///
+  pub fn link_into(
+    &self,
+    sandbox: PathBuf,
+    roots: Vec<(PathBuf, Digest)>,
+  ) -> BoxFuture<(), String> {
+    let fs_pool = self.fs_pool.clone();
+    let links = roots
+      .into_iter()
+      .map(|(relative_path, digest)| {
+        let destination = sandbox.join(relative_path);
+        let fs_pool = fs_pool.clone();
+        self.path_for(digest).and_then(move |root| {
+          fs_pool.spawn_fn(move || {
+            ::fs::os::symlink_dir(&root, &destination).map_err(|e| {
+              format!(
+                "Error linking immutable input directory {:?} to {:?}: {:?}",
+                destination, root, e
+              )
+            })
+          })
+        })
+      })
+      .collect::<Vec<_>>();
+    future::join_all(links).map(|_| ()).to_boxed()
+  }
+
+  ///
+  /// Returns the path of the read-only copy of the given Directory, materializing it if necessary.
+  ///
+  pub fn path_for(&self, digest: Digest) -> BoxFuture<PathBuf, String> {
+    let path = self.dir.join(root_name(digest));
+
+    let waiting = {
+      let mut inner = self.inner.lock().unwrap();
+      match inner.entry(digest) {
+        Entry::Occupied(mut entry) => match entry.get_mut() {
+          &mut RootState::Materialized => return future::ok(path).to_boxed(),
+          &mut RootState::Materializing(ref mut waiters) => {
+            let (sender, receiver) = oneshot::channel();
+            waiters.push(sender);
+            Some(receiver)
+          }
+        },
+        Entry::Vacant(entry) => {
+          entry.insert(RootState::Materializing(Vec::new()));
+          None
+        }
+      }
+    };
+    if let Some(receiver) = waiting {
+      return receiver
+        .map_err(|_| "Materialization of an immutable root was cancelled.".to_string())
+        .and_then(move |res| res.map(|()| path))
+        .to_boxed();
+    }
+
+    let inner = self.inner.clone();
+    self
+      .materialize(digest, path.clone())
+      .then(move |res| {
+        let waiters = {
+          let mut inner = inner.lock().unwrap();
+          let previous = if res.is_ok() {
+            inner.insert(digest, RootState::Materialized)
+          } else {
+            // Later callers will try again.
+            inner.remove(&digest)
+          };
+          match previous {
+            Some(RootState::Materializing(waiters)) => waiters,
+            _ => Vec::new(),
+          }
+        };
+        for waiter in waiters {
+          let _ = waiter.send(res.clone());
+        }
+        res.map(|()| path)
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Materializes the given Directory into a temporary directory, makes it read-only, and then
+  /// moves it into place (unless it is already in place, perhaps from a previous run).
+  ///
+  fn materialize(&self, digest: Digest, path: PathBuf) -> BoxFuture<(), String> {
+    if path.is_dir() {
+      return future::ok(()).to_boxed();
+    }
+    let tmp = try_future!(
+      fs::create_dir_all(&self.dir)
+        .and_then(|()| {
+          tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempdir_in(&self.dir)
+        })
+        .map_err(|e| format!("Error making tempdir for an immutable root: {:?}", e))
+    );
+    let fs_pool = self.fs_pool.clone();
+    self
+      .store
+      .materialize_directory(tmp.path().to_owned(), digest)
+      .and_then(move |()| {
+        fs_pool.spawn_fn(move || {
+          let tmp = tmp.into_path();
+          let result = set_read_only(&tmp, true).and_then(|()| fs::rename(&tmp, &path));
+          result.or_else(|e| {
+            // Clean up the temporary copy, which might be partially read-only.
+            let _ = remove_root(&tmp);
+            // If another process moved its own copy into place first, that copy is used.
+            if path.is_dir() {
+              Ok(())
+            } else {
+              Err(format!(
+                "Error moving immutable root into place at {:?}: {:?}",
+                path, e
+              ))
+            }
+          })
+        })
+      })
+      .to_boxed()
+  }
+}
+
+///
+/// Garbage collects the copies in an ImmutableRoots `dir` whose Directories are no longer stored.
+///
+struct RootsCollector {
+  dir: PathBuf,
+  inner: Arc<Mutex<HashMap<Digest, RootState>>>,
+}
+
+impl ::fs::ExternalCopies for RootsCollector {
+  fn garbage_collect(&self, store: &::fs::Store) -> Result<(), String> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(format!("Error listing immutable roots in {:?}: {:?}", self.dir, e)),
+    };
+    for entry in entries {
+      let path = entry
+        .map(|entry| entry.path())
+        .map_err(|e| format!("Error listing immutable roots in {:?}: {:?}", self.dir, e))?;
+      // Temporary copies (which may be in use by other processes) are ignored.
+      let digest = match path.file_name().and_then(|name| name.to_str()).and_then(parse_root_name) {
+        Some(digest) => digest,
+        None => continue,
+      };
+      // The lock is held while the copy is removed, so that it is not concurrently reused.
+      let inner = self.inner.lock().unwrap();
+      if inner.contains_key(&digest) || store.contains_locally(digest).wait()? {
+        continue;
+      }
+      remove_root(&path)
+        .map_err(|e| format!("Error removing immutable root {:?}: {:?}", path, e))?;
+    }
+    Ok(())
+  }
+}
+
+fn root_name(digest: Digest) -> String {
+  format!("{}-{}", digest.0.to_hex(), digest.1)
+}
+
+fn parse_root_name(name: &str) -> Option<Digest> {
+  let mut parts = name.splitn(2, '-');
+  match (parts.next(), parts.next()) {
+    (Some(fingerprint), Some(size_bytes)) => {
+      match (Fingerprint::from_hex_string(fingerprint), size_bytes.parse()) {
+        (Ok(fingerprint), Ok(size_bytes)) => Some(Digest(fingerprint, size_bytes)),
+        _ => None,
+      }
+    }
+    _ => None,
+  }
+}
+
+///
+/// Removes a (possibly read-only) copy, after restoring its write permissions.
+///
+fn remove_root(path: &Path) -> io::Result<()> {
+  set_read_only(path, false).and_then(|()| fs::remove_dir_all(path))
+}
+
+///
+/// Removes the given directories from the given Directory, and returns the Digest of the remainder
+/// along with the Digest of each removed directory. The parents of the removed directories remain,
+/// so that the removed directories can be linked into them.
+///
+pub fn split(
+  store: ::fs::Store,
+  digest: Digest,
+  directories: BTreeSet<PathBuf>,
+) -> BoxFuture<(Digest, Vec<(PathBuf, Digest)>), String> {
+  directories
+    .into_iter()
+    .fold(
+      future::ok((digest, Vec::new())).to_boxed(),
+      |acc: BoxFuture<(Digest, Vec<(PathBuf, Digest)>), String>, path| {
+        let store = store.clone();
+        acc
+          .and_then(move |(remainder, mut removed)| {
+            remove_subtree(store, remainder, path.clone()).map(move |(remainder, subtree)| {
+              removed.push((path, subtree));
+              (remainder, removed)
+            })
+          })
+          .to_boxed()
+      },
+    )
+}
+
+///
+/// Removes the directory at the given relative path from the given Directory, and returns the
+/// Digest of the remainder along with the Digest of the removed directory.
+///
+fn remove_subtree(
+  store: ::fs::Store,
+  digest: Digest,
+  path: PathBuf,
+) -> BoxFuture<(Digest, Digest), String> {
+  let (name, rest) = {
+    let mut components = path.components();
+    match components.next() {
+      Some(Component::Normal(name)) => (name.to_owned(), components.as_path().to_owned()),
+      _ => {
+        return future::err(format!(
+          "Immutable input directories must be relative paths within the input files, but got \
+           {:?}",
+          path
+        )).to_boxed()
+      }
+    }
+  };
+  store
+    .load_directory(digest)
+    .and_then(move |directory_opt| {
+      directory_opt.ok_or_else(|| format!("Directory with digest {:?} not found", digest))
+    })
+    .and_then(move |mut directory| {
+      let index = try_future!(
+        directory
+          .get_directories()
+          .iter()
+          .position(|node| ::fs::decode_name(node.get_name()) == name)
+          .ok_or_else(|| format!(
+            "Immutable input directory {:?} is not a directory in the input files.",
+            path
+          ))
+      );
+      let child: Result<Digest, String> = directory.get_directories()[index].get_digest().into();
+      let child = try_future!(child);
+      let child_remainder = if rest.as_os_str().is_empty() {
+        future::ok((None, child)).to_boxed()
+      } else {
+        remove_subtree(store.clone(), child, rest)
+          .map(|(remainder, subtree)| (Some(remainder), subtree))
+          .to_boxed()
+      };
+      child_remainder
+        .and_then(move |(child_remainder, subtree)| {
+          match child_remainder {
+            Some(remainder) => directory.mut_directories()[index].set_digest((&remainder).into()),
+            None => {
+              directory.mut_directories().remove(index);
+            }
+          }
+          store
+            .record_directory(&directory, true)
+            .map(move |remainder| (remainder, subtree))
+        })
+        .to_boxed()
+    })
+    .to_boxed()
+}
+
+///
+/// Recursively removes (or restores) the write permissions of the given directory and its
+/// contents. Symlinks are not followed.
+///
+fn set_read_only(path: &Path, read_only: bool) -> io::Result<()> {
+  let metadata = fs::symlink_metadata(path)?;
+  if metadata.file_type().is_symlink() {
+    return Ok(());
+  }
+  if metadata.is_dir() {
+    // A directory must be writable to modify its contents, so it is made writable first.
+    if !read_only {
+      set_writable(path, &metadata, true)?;
+    }
+    for entry in fs::read_dir(path)? {
+      set_read_only(&entry?.path(), read_only)?;
+    }
+    if read_only {
+      set_writable(path, &metadata, false)?;
+    }
+    Ok(())
+  } else {
+    set_writable(path, &metadata, !read_only)
+  }
+}
+
+fn set_writable(path: &Path, metadata: &fs::Metadata, writable: bool) -> io::Result<()> {
+  ::fs::os::set_writable(path, metadata, writable)
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+  extern crate testutil;
+
+  use super::{set_read_only, split, ImmutableRoots};
+  use fs;
+  use futures::Future;
+  use std::collections::BTreeSet;
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use testutil::data::{TestData, TestDirectory};
+
+  #[test]
+  fn split_top_level_directory() {
+    let (store, _store_dir) = new_store();
+    let directories = vec![PathBuf::from("cats")].into_iter().collect::<BTreeSet<_>>();
+    let (remainder, removed) = split(store, TestDirectory::nested().digest(), directories)
+      .wait()
+      .unwrap();
+    assert_eq!(TestDirectory::empty().digest(), remainder);
+    assert_eq!(
+      vec![(
+        PathBuf::from("cats"),
+        TestDirectory::containing_roland().digest()
+      )],
+      removed
+    );
+  }
+
+  #[test]
+  fn split_missing_directory() {
+    let (store, _store_dir) = new_store();
+    let directories = vec![PathBuf::from("dogs")].into_iter().collect::<BTreeSet<_>>();
+    assert!(
+      split(store, TestDirectory::nested().digest(), directories)
+        .wait()
+        .is_err()
+    );
+  }
+
+  #[test]
+  fn roots_are_materialized_once_and_read_only() {
+    let (store, _store_dir) = new_store();
+    let work_dir = TempDir::new().unwrap();
+    let fs_pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let roots = ImmutableRoots::new(store, fs_pool, work_dir.path());
+    let digest = TestDirectory::containing_roland().digest();
+
+    let first = roots.path_for(digest).wait().unwrap();
+    let second = roots.path_for(digest).wait().unwrap();
+    assert_eq!(first, second);
+    assert_eq!(
+      ::std::fs::read_to_string(first.join("roland")).unwrap(),
+      TestData::roland().string()
+    );
+    for path in &[first.clone(), first.join("roland")] {
+      assert!(::std::fs::metadata(path).unwrap().permissions().readonly());
+    }
+
+    let sandbox = TempDir::new().unwrap();
+    roots
+      .link_into(
+        sandbox.path().to_owned(),
+        vec![(PathBuf::from("cats"), digest)],
+      )
+      .wait()
+      .unwrap();
+    assert_eq!(
+      ::std::fs::read_to_string(sandbox.path().join("cats").join("roland")).unwrap(),
+      TestData::roland().string()
+    );
+
+    // Allow the work_dir to be cleaned up.
+    set_read_only(&first, false).unwrap();
+  }
+
+  #[test]
+  fn roots_are_garbage_collected_with_the_store() {
+    let (store, _store_dir) = new_store();
+    let work_dir = TempDir::new().unwrap();
+    let fs_pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let roots = ImmutableRoots::new(store.clone(), fs_pool.clone(), work_dir.path());
+    let path = roots
+      .path_for(TestDirectory::containing_roland().digest())
+      .wait()
+      .unwrap();
+
+    // The Directory is still stored (and the copy was used by this process), so it is kept.
+    store.garbage_collect().unwrap();
+    assert!(path.is_dir());
+
+    // Another process whose store no longer contains the Directory removes the read-only copy.
+    let other_store_dir = TempDir::new().unwrap();
+    let other_store = fs::Store::local_only(other_store_dir.path(), fs_pool.clone()).unwrap();
+    let _other_roots = ImmutableRoots::new(other_store.clone(), fs_pool, work_dir.path());
+    other_store.garbage_collect().unwrap();
+    assert!(!path.exists());
+  }
+
+  fn new_store() -> (fs::Store, TempDir) {
+    let store_dir = TempDir::new().unwrap();
+    let fs_pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), fs_pool).unwrap();
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .unwrap();
+    for directory in &[
+      TestDirectory::empty(),
+      TestDirectory::containing_roland(),
+      TestDirectory::nested(),
+    ] {
+      store
+        .record_directory(&directory.directory(), false)
+        .wait()
+        .unwrap();
+    }
+    (store, store_dir)
+  }
+}
@@ -0,0 +1,529 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use boxfuture::{BoxFuture, Boxable};
+use fs;
+use futures::future::{self, Either, Future, Loop};
+use futures_timer::Delay;
+
+use super::{
+  Bound, BoundedCommandRunner, CommandRunner, ExecuteProcessRequest, ExecutionError,
+  FallibleExecuteProcessResult,
+};
+use archive::{Archive, CachingCommandRunner, RecordingCommandRunner};
+use workunits::WorkUnit;
+
+///
+/// A layer of a chain of CommandRunners, which wraps the layers beneath it: see `build`.
+///
+pub enum Layer {
+  /// Bounds the number of concurrent requests: see `BoundedCommandRunner`.
+  Bound(Bound),
+  /// Serves requests from an Archive when possible, and records results to it otherwise: see
+  /// `archive::CachingCommandRunner`.
+  Cache(Archive),
+  /// Records results to an Archive: see `archive::RecordingCommandRunner`.
+  Record(Archive),
+  /// Retries requests which fail transiently: see `RetryingCommandRunner`.
+  Retry { attempts: usize, backoff: Duration },
+  /// Runs requests with an alternate CommandRunner as well, if they have not completed within a
+  /// delay: see `SpeculatingCommandRunner`.
+  Speculate {
+    delay: Duration,
+    alternate: Box<CommandRunner>,
+  },
+}
+
+///
+/// Builds a chain of CommandRunners from the given layers (outermost first) above the given leaf
+/// CommandRunner, which actually runs (or otherwise produces the results of) requests.
+///
+/// For example, the layers `[Bound(..), Cache(..)]` above a local leaf bound the number of
+/// concurrent requests, including those which are served from the cache, while the layers
+/// `[Cache(..), Bound(..)]` only bound the requests which miss the cache.
+///
+pub fn build(
+  leaf: Box<CommandRunner>,
+  layers: Vec<Layer>,
+  store: &fs::Store,
+) -> Box<CommandRunner> {
+  layers
+    .into_iter()
+    .rev()
+    .fold(leaf, |inner, layer| -> Box<CommandRunner> {
+      match layer {
+        Layer::Bound(bound) => Box::new(BoundedCommandRunner::with_bound(inner, bound)),
+        Layer::Cache(archive) => {
+          Box::new(CachingCommandRunner::new(inner, store.clone(), archive))
+        }
+        Layer::Record(archive) => {
+          Box::new(RecordingCommandRunner::new(inner, store.clone(), archive))
+        }
+        Layer::Retry { attempts, backoff } => {
+          Box::new(RetryingCommandRunner::new(inner, attempts, backoff))
+        }
+        Layer::Speculate { delay, alternate } => {
+          Box::new(SpeculatingCommandRunner::new(inner, alternate, delay))
+        }
+      }
+    })
+}
+
+///
+/// A CommandRunner which retries requests that fail transiently (see `super::ExecutionError`).
+/// Other failures (such as missing inputs, or a process which exceeded its disk quota) would only
+/// recur, so they are returned immediately.
+///
+pub struct RetryingCommandRunner {
+  inner: Arc<Box<CommandRunner>>,
+  // The maximum number of retries following the initial attempt.
+  attempts: usize,
+  // The delay before the first retry, which doubles for each subsequent retry.
+  backoff: Duration,
+}
+
+impl RetryingCommandRunner {
+  pub fn new(
+    inner: Box<CommandRunner>,
+    attempts: usize,
+    backoff: Duration,
+  ) -> RetryingCommandRunner {
+    RetryingCommandRunner {
+      inner: Arc::new(inner),
+      attempts,
+      backoff,
+    }
+  }
+}
+
+impl CommandRunner for RetryingCommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let inner = self.inner.clone();
+    let attempts = self.attempts;
+    let backoff = self.backoff;
+    future::loop_fn(0, move |attempt| {
+      inner
+        .run_in_workunit(req.clone(), workunit.clone())
+        .then(move |res| match res {
+          Err(ref e) if attempt < attempts && e.is_transient() => {
+            // The exponent is capped to avoid overflow.
+            let delay = backoff * 2u32.pow(cmp::min(attempt, 16) as u32);
+            debug!(
+              "Retrying process execution (retry {} of {}) in {:?} after failure: {}",
+              attempt + 1,
+              attempts,
+              delay,
+              e
+            );
+            Delay::new(delay)
+              .then(move |_| Ok(Loop::Continue(attempt + 1)))
+              .to_boxed()
+          }
+          res => future::result(res.map(Loop::Break)).to_boxed(),
+        })
+    }).to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.inner.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.inner.post_fork_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    self.inner.shutdown()
+  }
+
+  fn in_flight_operations(&self) -> Vec<String> {
+    self.inner.in_flight_operations()
+  }
+}
+
+///
+/// A CommandRunner which runs each request with a primary CommandRunner, and if it has not
+/// completed within a delay, with an alternate CommandRunner as well (for example, locally while
+/// waiting on a slow remote execution). The first successful result is used, and the other run is
+/// cancelled.
+///
+/// If either run fails, the result of the other is used.
+///
+/// Each run reports its progress to its own child of the WorkUnit of the request: see
+/// `WorkUnit::child`. The run which is cancelled reports that it failed.
+///
+pub struct SpeculatingCommandRunner {
+  primary: Arc<Box<CommandRunner>>,
+  alternate: Arc<Box<CommandRunner>>,
+  delay: Duration,
+}
+
+impl SpeculatingCommandRunner {
+  pub fn new(
+    primary: Box<CommandRunner>,
+    alternate: Box<CommandRunner>,
+    delay: Duration,
+  ) -> SpeculatingCommandRunner {
+    SpeculatingCommandRunner {
+      primary: Arc::new(primary),
+      alternate: Arc::new(alternate),
+      delay,
+    }
+  }
+}
+
+impl CommandRunner for SpeculatingCommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let description = req.description.clone();
+    let primary_workunit = workunit.child(format!("{} (primary)", description));
+    let primary = self
+      .primary
+      .run_in_workunit(req.clone(), primary_workunit.clone())
+      .then(report_to(primary_workunit.clone()));
+    // The alternate run only has a WorkUnit once it has started.
+    let alternate_workunit = Arc::new(Mutex::new(None));
+    let alternate_workunit2 = alternate_workunit.clone();
+    let alternate_runner = self.alternate.clone();
+    let alternate = Delay::new(self.delay)
+      .map_err(|e| ExecutionError::Fatal(format!("Speculation delay failed: {}", e)))
+      .and_then(move |()| {
+        debug!("Speculatively running {:?} with an alternate runner", description);
+        let workunit = workunit.child(format!("{} (alternate)", description));
+        *alternate_workunit2.lock().unwrap() = Some(workunit.clone());
+        alternate_runner
+          .run_in_workunit(req, workunit.clone())
+          .then(report_to(workunit))
+      });
+    primary
+      .select2(alternate)
+      .then(move |res| match res {
+        Ok(Either::A((result, _alternate))) => {
+          if let Some(ref workunit) = *alternate_workunit.lock().unwrap() {
+            workunit.failed("Cancelled, because the primary run completed first.".to_owned());
+          }
+          future::ok(result).to_boxed()
+        }
+        Ok(Either::B((result, _primary))) => {
+          primary_workunit
+            .failed("Cancelled, because the alternate run completed first.".to_owned());
+          future::ok(result).to_boxed()
+        }
+        Err(Either::A((e, alternate))) => {
+          debug!("Primary run failed, so using the alternate run: {}", e);
+          alternate.to_boxed()
+        }
+        Err(Either::B((e, primary))) => {
+          debug!("Alternate run failed, so using the primary run: {}", e);
+          primary.to_boxed()
+        }
+      })
+      .to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.primary.pre_fork();
+    self.alternate.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.primary.post_fork_in_child()?;
+    self.alternate.post_fork_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    let mut killed = self.primary.shutdown();
+    killed.extend(self.alternate.shutdown());
+    killed
+  }
+
+  fn in_flight_operations(&self) -> Vec<String> {
+    let mut operations = self.primary.in_flight_operations();
+    operations.extend(self.alternate.in_flight_operations());
+    operations
+  }
+}
+
+///
+/// Reports the outcome of a run to the given WorkUnit, and passes it through.
+///
+fn report_to(
+  workunit: WorkUnit,
+) -> impl FnOnce(Result<FallibleExecuteProcessResult, ExecutionError>)
+  -> Result<FallibleExecuteProcessResult, ExecutionError> {
+  move |res| {
+    match res {
+      Ok(ref result) => workunit.completed(result.exit_code, result.source),
+      Err(ref e) => workunit.failed(e.to_string()),
+    }
+    res
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::fake;
+  use super::super::CommandRunner as CommandRunnerTrait;
+  use super::super::{
+    ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult, ResultSource,
+  };
+  use super::{build, Layer, RetryingCommandRunner, SpeculatingCommandRunner};
+  use archive::Archive;
+  use boxfuture::{BoxFuture, Boxable};
+  use bytes::Bytes;
+  use fs;
+  use futures::future;
+  use futures::Future;
+  use futures_timer::Delay;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::Arc;
+  use std::time::Duration;
+  use tempfile;
+  use testing;
+  use workunits::{WorkUnitEvent, WorkUnitStore};
+
+  fn request() -> ExecuteProcessRequest {
+    testing::request(&["/bin/echo", "layered"])
+  }
+
+  fn result(stdout: &str) -> FallibleExecuteProcessResult {
+    FallibleExecuteProcessResult {
+      stdout: Bytes::from(stdout),
+      stderr: Bytes::new(),
+      exit_code: 0,
+      output_directory: fs::EMPTY_DIGEST,
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::Fake,
+    }
+  }
+
+  ///
+  /// Fails the given number of runs (transiently, unless `failing_deterministically`), and then
+  /// succeeds after the given delay.
+  ///
+  #[derive(Clone)]
+  struct TestRunner {
+    runs: Arc<AtomicUsize>,
+    failures: usize,
+    failure: ExecutionError,
+    delay: Duration,
+    stdout: &'static str,
+  }
+
+  impl TestRunner {
+    fn new(failures: usize, delay: Duration, stdout: &'static str) -> TestRunner {
+      TestRunner {
+        runs: Arc::new(AtomicUsize::new(0)),
+        failures,
+        failure: ExecutionError::Transient("Flaked".to_string()),
+        delay,
+        stdout,
+      }
+    }
+
+    fn failing_deterministically(self) -> TestRunner {
+      TestRunner {
+        failure: ExecutionError::Fatal("Broken".to_string()),
+        ..self
+      }
+    }
+
+    fn runs(&self) -> usize {
+      self.runs.load(Ordering::SeqCst)
+    }
+  }
+
+  impl CommandRunnerTrait for TestRunner {
+    fn run(
+      &self,
+      _req: ExecuteProcessRequest,
+    ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+      if self.runs.fetch_add(1, Ordering::SeqCst) < self.failures {
+        return future::err(self.failure.clone()).to_boxed();
+      }
+      let stdout = self.stdout;
+      Delay::new(self.delay)
+        .map_err(|e| ExecutionError::Fatal(format!("{}", e)))
+        .map(move |()| result(stdout))
+        .to_boxed()
+    }
+
+    fn pre_fork(&self) {}
+
+    fn post_fork_in_child(&self) -> Result<(), String> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn retries_failures() {
+    let flaky = TestRunner::new(2, Duration::from_millis(0), "eventually");
+    let runner =
+      RetryingCommandRunner::new(Box::new(flaky.clone()), 2, Duration::from_millis(1));
+    assert_eq!(runner.run(request()).wait(), Ok(result("eventually")));
+    assert_eq!(flaky.runs(), 3);
+  }
+
+  #[test]
+  fn retries_are_bounded() {
+    let flaky = TestRunner::new(2, Duration::from_millis(0), "eventually");
+    let runner =
+      RetryingCommandRunner::new(Box::new(flaky.clone()), 1, Duration::from_millis(1));
+    assert_eq!(
+      runner.run(request()).wait(),
+      Err(ExecutionError::Transient("Flaked".to_string()))
+    );
+    assert_eq!(flaky.runs(), 2);
+  }
+
+  #[test]
+  fn does_not_retry_deterministic_failures() {
+    let broken = TestRunner::new(1, Duration::from_millis(0), "eventually")
+      .failing_deterministically();
+    let runner =
+      RetryingCommandRunner::new(Box::new(broken.clone()), 2, Duration::from_millis(1));
+    assert_eq!(
+      runner.run(request()).wait(),
+      Err(ExecutionError::Fatal("Broken".to_string()))
+    );
+    assert_eq!(broken.runs(), 1);
+  }
+
+  #[test]
+  fn speculates_slow_requests() {
+    let slow = TestRunner::new(0, Duration::from_secs(10), "slow");
+    let fast = TestRunner::new(0, Duration::from_millis(0), "fast");
+    let runner = SpeculatingCommandRunner::new(
+      Box::new(slow),
+      Box::new(fast.clone()),
+      Duration::from_millis(10),
+    );
+    assert_eq!(runner.run(request()).wait(), Ok(result("fast")));
+    assert_eq!(fast.runs(), 1);
+  }
+
+  #[test]
+  fn does_not_speculate_fast_requests() {
+    let fast = TestRunner::new(0, Duration::from_millis(0), "fast");
+    let alternate = TestRunner::new(0, Duration::from_millis(0), "alternate");
+    let runner = SpeculatingCommandRunner::new(
+      Box::new(fast),
+      Box::new(alternate.clone()),
+      Duration::from_secs(10),
+    );
+    assert_eq!(runner.run(request()).wait(), Ok(result("fast")));
+    assert_eq!(alternate.runs(), 0);
+  }
+
+  #[test]
+  fn speculation_survives_failure_of_primary() {
+    let runner = SpeculatingCommandRunner::new(
+      Box::new(fake::CommandRunner::new()),
+      Box::new(TestRunner::new(0, Duration::from_millis(0), "alternate")),
+      Duration::from_millis(10),
+    );
+    assert_eq!(runner.run(request()).wait(), Ok(result("alternate")));
+  }
+
+  #[test]
+  fn speculative_runs_report_their_own_workunits() {
+    let runner = SpeculatingCommandRunner::new(
+      Box::new(TestRunner::new(0, Duration::from_secs(10), "slow")),
+      Box::new(TestRunner::new(0, Duration::from_millis(0), "fast")),
+      Duration::from_millis(10),
+    );
+    let workunits = WorkUnitStore::new();
+    let workunit = workunits.start("echo".to_owned(), fs::EMPTY_DIGEST);
+    assert_eq!(
+      runner.run_in_workunit(request(), workunit.clone()).wait(),
+      Ok(result("fast"))
+    );
+
+    let (events, _) = workunits.take_events();
+    let described = events
+      .into_iter()
+      .map(|event| match event {
+        WorkUnitEvent::Started {
+          parent_id,
+          description,
+          ..
+        } => {
+          let expected_parent_id = if description == "echo" {
+            None
+          } else {
+            Some(workunit.id())
+          };
+          assert_eq!(parent_id, expected_parent_id);
+          format!("started {}", description)
+        }
+        WorkUnitEvent::Completed { .. } => "completed".to_owned(),
+        WorkUnitEvent::Failed { error, .. } => format!("failed: {}", error),
+        WorkUnitEvent::FirstOutput { .. } => "first output".to_owned(),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      described,
+      vec![
+        "started echo",
+        "started echo (primary)",
+        "started echo (alternate)",
+        "completed",
+        "failed: Cancelled, because the alternate run completed first.",
+      ]
+    );
+  }
+
+  #[test]
+  fn builds_layers_outermost_first() {
+    let archive_dir = tempfile::TempDir::new().unwrap();
+    let store_dir = tempfile::TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool).unwrap();
+
+    // Retries happen beneath the cache, so only the successful result is cached.
+    let flaky = TestRunner::new(1, Duration::from_millis(0), "cached");
+    let runner = build(
+      Box::new(flaky.clone()),
+      vec![
+        Layer::Cache(Archive::new(archive_dir.path().to_owned())),
+        Layer::Retry {
+          attempts: 1,
+          backoff: Duration::from_millis(1),
+        },
+      ],
+      &store,
+    );
+    assert_eq!(runner.run(request()).wait(), Ok(result("cached")));
+    assert_eq!(
+      runner.run(request()).wait(),
+      Ok(FallibleExecuteProcessResult {
+        source: ResultSource::LocalCache,
+        ..result("cached")
+      })
+    );
+    assert_eq!(flaky.runs(), 2);
+  }
+}
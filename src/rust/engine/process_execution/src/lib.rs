@@ -9,6 +9,8 @@ extern crate futures;
 extern crate futures_timer;
 extern crate grpcio;
 extern crate hashing;
+#[cfg(unix)]
+extern crate libc;
 #[macro_use]
 extern crate log;
 #[cfg(test)]
@@ -22,17 +24,36 @@ extern crate tempfile;
 extern crate testutil;
 extern crate tokio_codec;
 extern crate tokio_process;
+#[cfg(windows)]
+extern crate winapi;
 
 use boxfuture::BoxFuture;
 use bytes::Bytes;
 use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 use async_semaphore::AsyncSemaphore;
 
+pub mod archive;
+pub mod capture;
+pub mod disk_quota;
+pub mod fake;
+pub mod immutable_roots;
+pub mod layers;
 pub mod local;
+pub mod platform;
 pub mod remote;
+pub mod sandbox;
+pub mod seatbelt;
+pub mod temp_root;
+#[cfg(test)]
+mod testing;
+pub mod workunits;
+
+use workunits::WorkUnit;
 
 ///
 /// A process to be executed.
@@ -62,6 +83,40 @@ pub struct ExecuteProcessRequest {
 
   pub output_directories: BTreeSet<PathBuf>,
 
+  ///
+  /// Directories within the input_files which the process will not modify. The local runner
+  /// materializes each of them once (read-only) and symlinks them into sandboxes, rather than
+  /// materializing them into each sandbox: see `immutable_roots::ImmutableRoots`.
+  ///
+  pub immutable_input_directories: BTreeSet<PathBuf>,
+
+  ///
+  /// The maximum number of bytes of each of stdout and stderr to capture, which may only lower
+  /// the limit that the CommandRunner was configured with: see `capture::OutputLimit`.
+  ///
+  pub max_output_bytes: Option<usize>,
+
+  ///
+  /// The maximum number of bytes that the process may write into its sandbox, beyond which it is
+  /// terminated and its execution fails: see `disk_quota::DiskQuota`. Ignored by the remote runner.
+  ///
+  pub max_disk_bytes: Option<u64>,
+
+  ///
+  /// Whether a result in which the process exited unsuccessfully may be memoized or served from a
+  /// cache. If false, only successful results are reused, so that a failing process (such as a
+  /// failing test) runs again each time it is requested. This does not affect the cache key of the
+  /// request.
+  ///
+  pub cache_failures: bool,
+
+  ///
+  /// The container image that the process must be executed in remotely, which is requested via the
+  /// `container-image` platform property, and is part of the cache key of the request. The local
+  /// runner refuses to run requests which set it.
+  ///
+  pub container_image: Option<String>,
+
   pub timeout: std::time::Duration,
 
   pub description: String,
@@ -79,12 +134,174 @@ pub struct FallibleExecuteProcessResult {
   // It's unclear whether this should be a Snapshot or a digest of a Directory. A Directory digest
   // is handy, so let's try that out for now.
   pub output_directory: hashing::Digest,
+
+  ///
+  /// Whether the stdout or stderr of the process overflowed the limit on how much of it is
+  /// captured, and if the overflow was stored (see `capture::OutputOverflow`), the Digest of the
+  /// complete output.
+  ///
+  pub stdout_overflow: Option<capture::Overflow>,
+  pub stderr_overflow: Option<capture::Overflow>,
+
+  pub source: ResultSource,
+}
+
+///
+/// Where the result of a process execution came from.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ResultSource {
+  // The process was run by a local::CommandRunner.
+  ExecutedLocally,
+  // The process was run by a remote execution service.
+  ExecutedRemotely,
+  // A remote execution service answered from its action cache, without running the process.
+  RemoteCache,
+  // The result was replayed from an archive of previous results (see `archive::Archive`).
+  LocalCache,
+  // The result was canned by a fake::CommandRunner.
+  Fake,
+}
+
+impl ResultSource {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      ResultSource::ExecutedLocally => "executed_locally",
+      ResultSource::ExecutedRemotely => "executed_remotely",
+      ResultSource::RemoteCache => "remote_cache",
+      ResultSource::LocalCache => "local_cache",
+      ResultSource::Fake => "fake",
+    }
+  }
+}
+
+///
+/// An error from a CommandRunner. A transient error is one which might not recur if the request
+/// were run again (such as an unavailable server, or an interrupted system call). A fatal error is
+/// deterministic (such as a missing input, or a process which exceeded its disk quota), and is
+/// never retried: see `layers::RetryingCommandRunner`.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecutionError {
+  Transient(String),
+  Fatal(String),
+}
+
+impl ExecutionError {
+  ///
+  /// Converts an I/O error from running a process, which is transient if it indicates that the
+  /// operation was interrupted, rather than that it failed.
+  ///
+  pub fn from_io_error(context: &str, error: &io::Error) -> ExecutionError {
+    let message = format!("{}: {:?}", context, error);
+    match error.kind() {
+      io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+        ExecutionError::Transient(message)
+      }
+      _ => ExecutionError::Fatal(message),
+    }
+  }
+
+  pub fn is_transient(&self) -> bool {
+    match self {
+      &ExecutionError::Transient(_) => true,
+      &ExecutionError::Fatal(_) => false,
+    }
+  }
+
+  pub fn message(&self) -> &str {
+    match self {
+      &ExecutionError::Transient(ref message) | &ExecutionError::Fatal(ref message) => message,
+    }
+  }
+}
+
+impl fmt::Display for ExecutionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.message())
+  }
+}
+
+// Errors which are not known to be transient are fatal.
+impl From<String> for ExecutionError {
+  fn from(message: String) -> ExecutionError {
+    ExecutionError::Fatal(message)
+  }
 }
 
 pub trait CommandRunner: Send + Sync {
-  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String>;
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError>;
+
+  ///
+  /// Like `run`, but reports the progress of the process to the given WorkUnit while it runs. The
+  /// caller reports the start and completion of the WorkUnit: runners report only what they alone
+  /// can observe (such as the first output of a local process).
+  ///
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    _workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run(req)
+  }
+
+  ///
+  /// Drops any resources (such as thread pools, gRPC channels and LMDB environments) which are not
+  /// safe to use in a process that is forked from this one. They are re-created lazily when they
+  /// are next needed.
+  ///
+  fn pre_fork(&self);
 
-  fn reset_prefork(&self);
+  ///
+  /// Called in a forked child process, immediately after the fork, to discard any resources which
+  /// another thread of the parent re-created between `pre_fork` and the fork. Unlike `pre_fork`,
+  /// resources are leaked rather than dropped, because their threads do not exist in the child.
+  ///
+  /// Fails if a resource was locked by another thread of the parent at the time of the fork, in
+  /// which case the child cannot safely run processes, and should exit.
+  ///
+  fn post_fork_in_child(&self) -> Result<(), String>;
+
+  ///
+  /// Terminates any processes that this CommandRunner is running, giving them a chance to exit
+  /// gracefully: see `local::CommandRunner::new`, and releases its connections to any remote
+  /// services. Returns the ids of the processes that did not exit gracefully, and so were killed.
+  ///
+  fn shutdown(&self) -> Vec<u32> {
+    vec![]
+  }
+
+  ///
+  /// Describes the operations that this CommandRunner is waiting on in other processes (such as
+  /// remote executions), for use in diagnosing stalls.
+  ///
+  fn in_flight_operations(&self) -> Vec<String> {
+    Vec::new()
+  }
+}
+
+///
+/// The bound on the number of concurrent requests of a BoundedCommandRunner, which may be adjusted
+/// while the runner is in use (including after it has been wrapped by other CommandRunners).
+///
+#[derive(Clone)]
+pub struct Bound(AsyncSemaphore);
+
+impl Bound {
+  pub fn new(bound: usize) -> Bound {
+    Bound(AsyncSemaphore::new(bound))
+  }
+
+  ///
+  /// Temporarily lowers the bound on the number of concurrent requests (or given None, restores
+  /// the bound that it was created with). Requests that are already running are not affected.
+  ///
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self.0.set_limit(limit);
+  }
 }
 
 ///
@@ -97,20 +314,56 @@ pub struct BoundedCommandRunner {
 
 impl BoundedCommandRunner {
   pub fn new(inner: Box<CommandRunner>, bound: usize) -> BoundedCommandRunner {
+    BoundedCommandRunner::with_bound(inner, Bound::new(bound))
+  }
+
+  pub fn with_bound(inner: Box<CommandRunner>, bound: Bound) -> BoundedCommandRunner {
     BoundedCommandRunner {
       inner: Arc::new(inner),
-      sema: AsyncSemaphore::new(bound),
+      sema: bound.0,
     }
   }
+
+  ///
+  /// See `Bound::set_limit`.
+  ///
+  pub fn set_limit(&self, limit: Option<usize>) {
+    self.sema.set_limit(limit);
+  }
 }
 
 impl CommandRunner for BoundedCommandRunner {
-  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
     let inner = self.inner.clone();
-    self.sema.with_acquired(move || inner.run(req))
+    self
+      .sema
+      .with_acquired(move || inner.run_in_workunit(req, workunit))
+  }
+
+  fn pre_fork(&self) {
+    self.inner.pre_fork();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.inner.post_fork_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    self.inner.shutdown()
   }
 
-  fn reset_prefork(&self) {
-    self.inner.reset_prefork();
+  fn in_flight_operations(&self) -> Vec<String> {
+    self.inner.in_flight_operations()
   }
 }
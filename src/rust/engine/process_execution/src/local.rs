@@ -1,63 +1,180 @@
 extern crate log;
-extern crate tempfile;
 
 use boxfuture::{BoxFuture, Boxable};
 use fs::{self, GlobMatching, PathGlobs, PathStatGetter, Snapshot, StrictGlobMatching};
-use futures::{future, Future, Stream};
-use std::collections::BTreeSet;
-use std::os::unix::process::ExitStatusExt;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::Arc;
+use futures::future::Either;
+use futures::{future, Async, Future, Poll, Stream};
+use futures_timer::Delay;
+#[cfg(unix)]
+use libc;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use tokio_codec::{Decoder, FramedRead};
 use tokio_process::{Child, CommandExt};
 
-use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+use super::CommandRunner as CommandRunnerTrait;
+use super::{ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult, ResultSource};
+use capture::{CapturedOutput, OutputLimit};
+use disk_quota::{DiskQuota, CHECK_INTERVAL_MILLIS, CHECK_INTERVAL_PER_MEASUREMENT_TIME};
+use sandbox::SandboxPool;
+use seatbelt::SeatbeltProfile;
+use temp_root::TempRoot;
+use workunits::WorkUnit;
 
 use bytes::{Bytes, BytesMut};
 
+// Variables which name a directory for temporary files. A relative path in one of these is resolved
+// against the sandbox of the process, so that a request which declares one is identical across
+// machines (and can be cached remotely), while its processes do not share temporary files.
+const SANDBOX_RELATIVE_ENV_VARS: &[&str] = &["TMPDIR", "TMP", "TEMP"];
+
 pub struct CommandRunner {
   store: fs::Store,
   fs_pool: Arc<fs::ResettablePool>,
-  work_dir: PathBuf,
+  sandboxes: SandboxPool,
   cleanup_local_dirs: bool,
+  running: RunningProcesses,
+  output_limit: OutputLimit,
+  inherited_env: BTreeMap<String, String>,
+  seatbelt_profile: Option<SeatbeltProfile>,
+  cache_dirs: Vec<PathBuf>,
 }
 
 impl CommandRunner {
+  ///
+  /// Processes which are still running when their execution is cancelled (or when the runner is
+  /// shut down) are sent SIGTERM, and then SIGKILL if they have not exited within
+  /// `termination_grace_period`.
+  ///
+  /// Sandboxes are created in `temp_root`, and claimed from a pool of size `sandbox_pool_size`:
+  /// see `SandboxPool`.
+  ///
   pub fn new(
     store: fs::Store,
     fs_pool: Arc<fs::ResettablePool>,
     work_dir: PathBuf,
+    temp_root: TempRoot,
     cleanup_local_dirs: bool,
+    termination_grace_period: Duration,
+    sandbox_pool_size: usize,
   ) -> CommandRunner {
     CommandRunner {
+      sandboxes: SandboxPool::new(
+        store.clone(),
+        fs_pool.clone(),
+        work_dir,
+        temp_root,
+        sandbox_pool_size,
+      ),
       store,
       fs_pool,
-      work_dir,
       cleanup_local_dirs,
+      running: RunningProcesses::new(termination_grace_period),
+      output_limit: OutputLimit::unlimited(),
+      inherited_env: BTreeMap::new(),
+      seatbelt_profile: None,
+      cache_dirs: vec![],
     }
   }
 
+  ///
+  /// Limits how much of the stdout and stderr of each process is captured: see `OutputLimit`.
+  ///
+  pub fn with_output_limit(mut self, output_limit: OutputLimit) -> CommandRunner {
+    self.output_limit = output_limit;
+    self
+  }
+
+  ///
+  /// Allows processes to inherit the named variables from the environment of this process, unless
+  /// they declare values for them. Inherited variables do not contribute to the cache key of a
+  /// request, so this is an escape hatch for tools which cannot run without (say) `HOME` or
+  /// `LANG`: by default, a process sees only the variables that it declared.
+  ///
+  pub fn with_inherited_env(mut self, names: BTreeSet<String>) -> CommandRunner {
+    self.inherited_env = names
+      .into_iter()
+      .filter_map(|name| env::var(&name).ok().map(|value| (name, value)))
+      .collect();
+    self
+  }
+
+  ///
+  /// On macOS, runs each process under a `sandbox-exec` profile which denies it access to the
+  /// filesystem outside of its sandbox, the shared copies of its immutable inputs, the system, and
+  /// the given writable cache directories: see `SeatbeltProfile`. Growth of the cache directories
+  /// while a process runs is reported, but does not count towards its disk quota.
+  ///
+  pub fn with_macos_sandbox(mut self, cache_dirs: Vec<PathBuf>) -> CommandRunner {
+    self.seatbelt_profile = Some(SeatbeltProfile::new(
+      vec![self.sandboxes.immutable_roots_dir().to_owned()],
+      cache_dirs.clone(),
+    ));
+    self.cache_dirs = cache_dirs;
+    self
+  }
+
   fn outputs_stream_for_child(
-    mut child: Child,
-  ) -> impl Stream<Item = ChildOutput, Error = String> + Send {
+    mut child: GracefulChild,
+  ) -> impl Stream<Item = ChildOutput, Error = ExecutionError> + Send {
     // TODO: This assumes that the Child was launched with stdout/stderr `Stdio::piped`.
-    let stdout_stream = FramedRead::new(child.stdout().take().unwrap(), IdentityDecoder)
-      .map(|bytes| ChildOutput::Stdout(bytes.into()));
-    let stderr_stream = FramedRead::new(child.stderr().take().unwrap(), IdentityDecoder)
-      .map(|bytes| ChildOutput::Stderr(bytes.into()));
-    let exit_stream = child.into_stream().map(|exit_status| {
-      ChildOutput::Exit(
-        exit_status
-          .code()
-          .or_else(|| exit_status.signal().map(|signal| -signal)),
-      )
-    });
+    let (stdout, stderr) = {
+      let child = child.child.as_mut().unwrap();
+      (child.stdout().take().unwrap(), child.stderr().take().unwrap())
+    };
+    let stdout_stream =
+      FramedRead::new(stdout, IdentityDecoder).map(|bytes| ChildOutput::Stdout(bytes.into()));
+    let stderr_stream =
+      FramedRead::new(stderr, IdentityDecoder).map(|bytes| ChildOutput::Stderr(bytes.into()));
+    let exit_stream = child
+      .into_stream()
+      .map(|exit_status| {
+        ChildOutput::Exit(exit_code(&exit_status))
+      });
     stdout_stream
       .select(stderr_stream)
       .chain(exit_stream)
-      .map_err(|e| format!("Failed to consume process outputs: {:?}", e))
+      .map_err(|e| ExecutionError::from_io_error("Failed to consume process outputs", &e))
+  }
+
+  ///
+  /// Measures the disk usage of a running process periodically, failing if it exceeds its quota.
+  /// Never completes successfully: it is raced against the process.
+  ///
+  /// The interval between measurements grows with the time that they take: see
+  /// `CHECK_INTERVAL_PER_MEASUREMENT_TIME`.
+  ///
+  fn enforce_disk_quota(
+    quota: Arc<DiskQuota>,
+    fs_pool: Arc<fs::ResettablePool>,
+  ) -> BoxFuture<(), String> {
+    let min_interval = Duration::from_millis(CHECK_INTERVAL_MILLIS);
+    future::loop_fn(min_interval, move |interval| {
+      let quota = quota.clone();
+      let fs_pool = fs_pool.clone();
+      Delay::new(interval)
+        .map_err(|e| format!("Timer failed while enforcing disk quota: {}", e))
+        .and_then(move |()| {
+          fs_pool.spawn_fn(move || {
+            let start = Instant::now();
+            quota.check().map(|_| start.elapsed())
+          })
+        })
+        .map(move |took| -> future::Loop<(), Duration> {
+          future::Loop::Continue(::std::cmp::max(
+            min_interval,
+            took * CHECK_INTERVAL_PER_MEASUREMENT_TIME,
+          ))
+        })
+    }).to_boxed()
   }
 
   fn construct_output_snapshot(
@@ -66,15 +183,18 @@ impl CommandRunner {
     output_file_paths: BTreeSet<PathBuf>,
     output_dir_paths: BTreeSet<PathBuf>,
   ) -> BoxFuture<Snapshot, String> {
+    // Each output directory is matched both by its own path (so that it is captured even if it is
+    // empty), and by a recursive glob for its contents.
     let output_dirs_glob_strings: Result<Vec<String>, String> = output_dir_paths
       .into_iter()
       .map(|p| {
         p.into_os_string()
           .into_string()
           .map_err(|e| format!("Error stringifying output_directories: {:?}", e))
-          .map(|s| format!("{}/**", s))
+          .map(|s| vec![format!("{}/**", s), s])
       })
-      .collect();
+      .collect::<Result<Vec<_>, _>>()
+      .map(|globs| globs.into_iter().flat_map(|g| g).collect());
 
     let output_dirs_future = posix_fs
       .expand(try_future!(PathGlobs::create(
@@ -106,70 +226,163 @@ impl CommandRunner {
   }
 }
 
-impl super::CommandRunner for CommandRunner {
+///
+/// Computes the environment of a process, which contains only the variables that its request
+/// declared, plus any inherited variables (see `CommandRunner::with_inherited_env`) that it did not
+/// declare. Nothing else leaks in from the environment of this process.
+///
+fn sandbox_env(
+  declared: BTreeMap<String, String>,
+  inherited: &BTreeMap<String, String>,
+  sandbox: &Path,
+) -> Result<BTreeMap<String, String>, String> {
+  let mut env = inherited.clone();
+  for (name, value) in declared {
+    let value = if SANDBOX_RELATIVE_ENV_VARS.contains(&name.as_str())
+      && Path::new(&value).is_relative()
+    {
+      let path = sandbox.join(&value);
+      ::std::fs::create_dir_all(&path)
+        .map_err(|e| format!("Error creating {} for {}: {}", path.display(), name, e))?;
+      path
+        .into_os_string()
+        .into_string()
+        .map_err(|path| format!("Non-UTF8 sandbox path for {}: {:?}", name, path))?
+    } else {
+      value
+    };
+    env.insert(name, value);
+  }
+  Ok(env)
+}
+
+impl CommandRunnerTrait for CommandRunner {
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    self.run_in_workunit(req, WorkUnit::ignored())
+  }
+
   ///
-  /// Runs a command on this machine in the passed working directory.
+  /// Runs a command on this machine in the passed working directory, reporting its first output to
+  /// the WorkUnit.
   ///
-  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let workdir = try_future!(
-      tempfile::Builder::new()
-        .prefix("process-execution")
-        .tempdir_in(&self.work_dir)
-        .map_err(|err| {
-          format!(
-            "Error making tempdir for local process execution: {:?}",
-            err
-          )
-        })
-    );
-
+  fn run_in_workunit(
+    &self,
+    req: ExecuteProcessRequest,
+    workunit: WorkUnit,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    // Processes are not run in containers locally, so running one that requires an image would
+    // silently produce a result from the wrong environment.
+    if let Some(ref container_image) = req.container_image {
+      return future::err(ExecutionError::Fatal(format!(
+        "Cannot run {:?} locally, because it requires the container image {:?}: configure \
+         remote execution to run it.",
+        req.description, container_image
+      ))).to_boxed();
+    }
     let store = self.store.clone();
+    let store2 = self.store.clone();
     let fs_pool = self.fs_pool.clone();
+    let fs_pool2 = self.fs_pool.clone();
+    let fs_pool3 = self.fs_pool.clone();
+    let output_limit = self.output_limit.for_request(req.max_output_bytes);
+    let max_disk_bytes = req.max_disk_bytes;
+    let cache_dirs = self.cache_dirs.clone();
     let env = req.env;
+    let inherited_env = self.inherited_env.clone();
     let output_file_paths = req.output_files;
     let output_dir_paths = req.output_directories;
     let cleanup_local_dirs = self.cleanup_local_dirs;
+    let running = self.running.clone();
     let argv = req.argv;
+    let seatbelt_profile = self.seatbelt_profile.clone();
     let req_description = req.description;
+    let temp_root = self.sandboxes.temp_root().clone();
     self
-      .store
-      .materialize_directory(workdir.path().to_owned(), req.input_files)
-      .and_then(move |()| {
-        Command::new(&argv[0])
-                  .args(&argv[1..])
-                  .current_dir(workdir.path())
-                  .env_clear()
-                  // It would be really nice not to have to manually set PATH but this is sadly the only way
-                  // to stop automatic PATH searching.
-                  .env("PATH", "")
-                  .envs(env)
-                  .stdin(Stdio::null())
-                  .stdout(Stdio::piped())
-                  .stderr(Stdio::piped())
-                  .spawn_async()
-                  .map_err(|e| format!("Error launching process: {:?}", e))
-                  .map(|child| (child, workdir))
+      .sandboxes
+      .claim_with_immutable_inputs(req.input_files, req.immutable_input_directories)
+      .and_then(move |workdir| {
+        // The sandbox is measured before the process is spawned, so that its inputs do not count
+        // towards the bytes that it writes.
+        let sandbox = workdir.path().to_owned();
+        fs_pool2
+          .spawn_fn(move || DiskQuota::start(sandbox, cache_dirs, max_disk_bytes))
+          .map(|quota| (Arc::new(quota), workdir))
+      })
+      .map_err(ExecutionError::Fatal)
+      .and_then(move |(quota, workdir)| {
+        let env = sandbox_env(env, &inherited_env, workdir.path())?;
+        let argv = match seatbelt_profile {
+          Some(ref profile) => profile.wrap(workdir.path(), &argv)?,
+          None => argv,
+        };
+        running
+          .spawn(
+            in_own_process_group(&mut Command::new(&argv[0]))
+              .args(&argv[1..])
+              .current_dir(workdir.path())
+              .env_clear()
+              // It would be really nice not to have to manually set PATH but this is sadly the only
+              // way to stop automatic PATH searching.
+              .env("PATH", "")
+              .envs(env)
+              .stdin(Stdio::null())
+              .stdout(Stdio::piped())
+              .stderr(Stdio::piped()),
+          )
+          .map(|child| (child, quota, workdir))
       })
-      .and_then(|(child, workdir)| {
+      .and_then(move |(child, quota, workdir)| {
         // Consume the stream of ChildOutputs incrementally.
         let init = (
-          BytesMut::with_capacity(8192),
-          BytesMut::with_capacity(8192),
+          CapturedOutput::new(output_limit, workdir.path()),
+          CapturedOutput::new(output_limit, workdir.path()),
           None,
         );
+        let workunit2 = workunit.clone();
+        let watchdog = match quota.max_bytes() {
+          Some(_) => Self::enforce_disk_quota(quota.clone(), fs_pool3.clone()),
+          None => future::empty().to_boxed(),
+        };
         Self::outputs_stream_for_child(child)
           .fold(
             init,
-            |(mut stdout, mut stderr, mut exit_code), child_output| {
+            move |(mut stdout, mut stderr, mut exit_code), child_output| {
               match child_output {
-                ChildOutput::Stdout(bytes) => stdout.extend_from_slice(&bytes),
-                ChildOutput::Stderr(bytes) => stderr.extend_from_slice(&bytes),
+                ChildOutput::Stdout(bytes) => {
+                  workunit.output_received();
+                  stdout.extend(&bytes)?
+                }
+                ChildOutput::Stderr(bytes) => {
+                  workunit.output_received();
+                  stderr.extend(&bytes)?
+                }
                 ChildOutput::Exit(code) => exit_code = code,
               };
-              Ok((stdout, stderr, exit_code)) as Result<_, String>
+              Ok((stdout, stderr, exit_code)) as Result<_, ExecutionError>
             },
           )
-          .map(|output| (output, workdir))
+          // If the process exceeds its quota, the watchdog fails, and dropping the process
+          // terminates it.
+          .select2(watchdog.map_err(ExecutionError::Fatal))
+          .then(|res| match res {
+            Ok(Either::A((output, _))) => Ok(output),
+            Ok(Either::B(((), _))) => unreachable!("The disk quota watchdog never completes."),
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => Err(e),
+          })
+          .and_then(move |output| {
+            // Measure the process one last time, to report the bytes that it wrote, and to catch a
+            // process which exceeded its quota between checks.
+            fs_pool3
+              .spawn_fn(move || quota.finish())
+              .map_err(ExecutionError::Fatal)
+              .map(move |usage| {
+                workunit2.disk_usage_measured(usage);
+                (output, workdir)
+              })
+          })
       })
       .and_then(move |((stdout, stderr, exit_code), workdir)| {
         let output_snapshot = if output_file_paths.is_empty() && output_dir_paths.is_empty() {
@@ -181,6 +394,7 @@ impl super::CommandRunner for CommandRunner {
               workdir.path(),
               fs_pool,
               &[],
+              fs::SpecialFileBehavior::Warn,
             )
           )
           .map_err(|err| {
@@ -202,14 +416,20 @@ impl super::CommandRunner for CommandRunner {
           .map(move |result| (result, workdir) )
           .map(move |(result, workdir)| {
             if !cleanup_local_dirs {
-              // This consumes the `TempDir` without deleting directory on the filesystem, meaning
-              // that the temporary directory will no longer be automatically deleted when dropped.
-              let preserved_path = workdir.into_path();
-              info!(
-                "preserved local process execution dir `{:?}` for {:?}",
-                preserved_path,
-                req_description
-              );
+              // Moves the `TempDir` out of the root of this run, so that it is neither deleted when
+              // dropped, nor when the run exits.
+              match temp_root.preserve(workdir) {
+                Ok(preserved_path) => info!(
+                  "preserved local process execution dir `{:?}` for {:?}",
+                  preserved_path,
+                  req_description
+                ),
+                Err(err) => warn!(
+                  "Failed to preserve local process execution dir for {:?}: {}",
+                  req_description,
+                  err
+                ),
+              }
             }
             result
           })
@@ -217,21 +437,334 @@ impl super::CommandRunner for CommandRunner {
         };
 
         output_snapshot
-          .map(move |snapshot| FallibleExecuteProcessResult {
-            stdout: stdout.freeze(),
-            stderr: stderr.freeze(),
-            exit_code: exit_code.unwrap_or(-1),
-            output_directory: snapshot.digest,
+          .join3(stdout.finish(&store2), stderr.finish(&store2))
+          .map(move |(snapshot, (stdout, stdout_overflow), (stderr, stderr_overflow))| {
+            FallibleExecuteProcessResult {
+              stdout,
+              stderr,
+              exit_code: exit_code.unwrap_or(-1),
+              output_directory: snapshot.digest,
+              stdout_overflow,
+              stderr_overflow,
+              source: ResultSource::ExecutedLocally,
+            }
           })
+          .map_err(ExecutionError::Fatal)
           .to_boxed()
       })
       .to_boxed()
   }
 
-  fn reset_prefork(&self) {
-    self.store.reset_prefork();
+  fn pre_fork(&self) {
+    self.store.pre_fork();
     self.fs_pool.reset();
   }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.store.post_fork_in_child()?;
+    self.fs_pool.reset_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    self.running.terminate_all()
+  }
+}
+
+///
+/// The ids of the child processes that have been spawned but not yet reaped, which are therefore
+/// safe to signal: once a process has been reaped, its id may be reused by an unrelated process.
+///
+/// Each child is the leader of its own process group (see `in_own_process_group`), which is
+/// signalled as a whole so that its descendants are stopped too. Signals are sent, and children are
+/// reaped (see `poll_reaping`), while holding the lock, so that a child which is signalled cannot
+/// have been reaped.
+///
+/// Once the runner has been shut down (see `terminate_all`), no further processes are spawned.
+///
+#[derive(Clone)]
+struct RunningProcesses {
+  pids: Arc<Mutex<HashSet<u32>>>,
+  shut_down: Arc<AtomicBool>,
+  grace_period: Duration,
+}
+
+impl RunningProcesses {
+  fn new(grace_period: Duration) -> RunningProcesses {
+    RunningProcesses {
+      pids: Arc::new(Mutex::new(HashSet::new())),
+      shut_down: Arc::new(AtomicBool::new(false)),
+      grace_period: grace_period,
+    }
+  }
+
+  ///
+  /// Spawns the given Command (which should have been configured by `in_own_process_group`), unless
+  /// the runner has been shut down.
+  ///
+  fn spawn(&self, command: &mut Command) -> Result<GracefulChild, ExecutionError> {
+    // Holding the lock while spawning ensures that `terminate_all` either sees the new process, or
+    // has already prevented it from being spawned.
+    let mut pids = self.pids.lock().unwrap();
+    if self.shut_down.load(Ordering::SeqCst) {
+      return Err(ExecutionError::Fatal(
+        "Cannot launch process: process execution has been shut down.".to_string(),
+      ));
+    }
+    let child = command
+      .spawn_async()
+      .map_err(|e| ExecutionError::from_io_error("Error launching process", &e))?;
+    ensure_own_process_group(child.id());
+    pids.insert(child.id());
+    Ok(GracefulChild {
+      child: Some(child),
+      running: self.clone(),
+    })
+  }
+
+  ///
+  /// Polls the given child for its exit, and if it has exited (and so been reaped), forgets it.
+  ///
+  fn poll_reaping(&self, child: &mut Child) -> Poll<ExitStatus, io::Error> {
+    let mut pids = self.pids.lock().unwrap();
+    let res = child.poll();
+    match res {
+      Ok(Async::NotReady) => (),
+      // A child which could not be polled is forgotten too, because it can no longer be known
+      // whether it has been reaped.
+      Ok(Async::Ready(_)) | Err(_) => {
+        pids.remove(&child.id());
+      }
+    }
+    res
+  }
+
+  ///
+  /// Sends SIGTERM to the given process (and its process group) if it has not been reaped.
+  ///
+  fn terminate(&self, pid: u32) {
+    if self.pids.lock().unwrap().contains(&pid) {
+      terminate(pid);
+    }
+  }
+
+  ///
+  /// Sends SIGKILL to the given process (and its process group) if it has not been reaped.
+  ///
+  fn kill(&self, pid: u32) {
+    if self.pids.lock().unwrap().contains(&pid) {
+      kill(pid);
+    }
+  }
+
+  ///
+  /// Prevents any further processes from being spawned, sends SIGTERM to all running processes,
+  /// waits up to the grace period for them to exit, and then sends SIGKILL to any that have not.
+  /// Returns the ids of the processes that were killed.
+  ///
+  fn terminate_all(&self) -> Vec<u32> {
+    {
+      let pids = self.pids.lock().unwrap();
+      self.shut_down.store(true, Ordering::SeqCst);
+      if pids.is_empty() {
+        return vec![];
+      }
+      info!("Terminating {} running processes.", pids.len());
+      for &pid in pids.iter() {
+        terminate(pid);
+      }
+    }
+    let deadline = Instant::now() + self.grace_period;
+    while Instant::now() < deadline && !self.pids.lock().unwrap().is_empty() {
+      thread::sleep(Duration::from_millis(10));
+    }
+    let pids = self.pids.lock().unwrap();
+    let mut killed = pids.iter().cloned().collect::<Vec<_>>();
+    killed.sort();
+    for &pid in &killed {
+      warn!(
+        "Process {} did not exit within {:?} of SIGTERM: killing it.",
+        pid, self.grace_period
+      );
+      kill(pid);
+    }
+    killed
+  }
+}
+
+///
+/// The exit code of a process, or the negated number of the signal that killed it.
+///
+#[cfg(unix)]
+fn exit_code(exit_status: &ExitStatus) -> Option<i32> {
+  use std::os::unix::process::ExitStatusExt;
+  exit_status
+    .code()
+    .or_else(|| exit_status.signal().map(|signal| -signal))
+}
+
+#[cfg(windows)]
+fn exit_code(exit_status: &ExitStatus) -> Option<i32> {
+  exit_status.code()
+}
+
+///
+/// Makes a Command spawn its process as the leader of a new process group, so that the process and
+/// any descendants which it does not move to other groups can be signalled together.
+///
+#[cfg(unix)]
+fn in_own_process_group(command: &mut Command) -> &mut Command {
+  use std::os::unix::process::CommandExt;
+  command.before_exec(|| {
+    if unsafe { libc::setpgid(0, 0) } == 0 {
+      Ok(())
+    } else {
+      Err(io::Error::last_os_error())
+    }
+  })
+}
+
+#[cfg(windows)]
+fn in_own_process_group(command: &mut Command) -> &mut Command {
+  command
+}
+
+///
+/// Called in the parent after spawning a process with `in_own_process_group`, so that its group
+/// exists even if it is signalled before the child has run far enough to create the group itself.
+///
+#[cfg(unix)]
+fn ensure_own_process_group(pid: u32) {
+  // Fails harmlessly if the child already exec'd, which it only does after creating the group.
+  unsafe {
+    libc::setpgid(pid as libc::pid_t, pid as libc::pid_t);
+  }
+}
+
+#[cfg(windows)]
+fn ensure_own_process_group(_pid: u32) {}
+
+///
+/// Asks the process group led by the given process to exit. Must only be called for a process
+/// which has not been reaped: see `RunningProcesses`.
+///
+#[cfg(unix)]
+fn terminate(pid: u32) {
+  unsafe {
+    libc::kill(-(pid as libc::pid_t), libc::SIGTERM);
+  }
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) {
+  // Windows has no equivalent of SIGTERM for processes without a console or a window, so rather
+  // than letting the process run until the grace period has elapsed, it is killed immediately.
+  kill(pid);
+}
+
+///
+/// Forces the process group led by the given process to exit. Must only be called for a process
+/// which has not been reaped: see `RunningProcesses`.
+///
+#[cfg(unix)]
+fn kill(pid: u32) {
+  unsafe {
+    libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+  }
+}
+
+#[cfg(windows)]
+fn kill(pid: u32) {
+  use winapi::um::handleapi::CloseHandle;
+  use winapi::um::processthreadsapi::{OpenProcess, TerminateProcess};
+  use winapi::um::winnt::PROCESS_TERMINATE;
+  unsafe {
+    let handle = OpenProcess(PROCESS_TERMINATE, 0, pid);
+    if !handle.is_null() {
+      TerminateProcess(handle, 1);
+      CloseHandle(handle);
+    }
+  }
+}
+
+///
+/// A running child process which, if it is dropped before it exits (because its execution was
+/// cancelled), is sent SIGTERM, and then SIGKILL if it has not exited within the grace period.
+///
+/// Dropping a Child would otherwise SIGKILL it immediately, which gives tools no opportunity to
+/// flush their outputs (such as coverage data or test reports), and can leave their caches corrupt.
+///
+struct GracefulChild {
+  child: Option<Child>,
+  running: RunningProcesses,
+}
+
+impl Future for GracefulChild {
+  type Item = ExitStatus;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<ExitStatus, io::Error> {
+    let exit_status = match self.running.poll_reaping(
+      self
+        .child
+        .as_mut()
+        .expect("GracefulChild polled after completion"),
+    )? {
+      Async::Ready(exit_status) => exit_status,
+      Async::NotReady => return Ok(Async::NotReady),
+    };
+    self.child.take();
+    Ok(Async::Ready(exit_status))
+  }
+}
+
+///
+/// Reaps a child which has been abandoned by its GracefulChild: see `RunningProcesses`.
+///
+struct Reaping {
+  child: Child,
+  running: RunningProcesses,
+}
+
+impl Future for Reaping {
+  type Item = ExitStatus;
+  type Error = io::Error;
+
+  fn poll(&mut self) -> Poll<ExitStatus, io::Error> {
+    self.running.poll_reaping(&mut self.child)
+  }
+}
+
+impl Drop for GracefulChild {
+  fn drop(&mut self) {
+    let child = match self.child.take() {
+      Some(child) => child,
+      None => return,
+    };
+    let pid = child.id();
+    let running = self.running.clone();
+    debug!("Terminating cancelled process {}.", pid);
+    running.terminate(pid);
+    // Wait for the process on another thread, so that the cancellation does not block.
+    fs::threads::spawn("process-reaper", move || {
+      let grace_period = running.grace_period;
+      let reaping = Reaping {
+        child,
+        running: running.clone(),
+      };
+      let still_running = match reaping.select2(Delay::new(grace_period)).wait() {
+        Ok(Either::A(_)) | Err(Either::A(_)) => None,
+        Ok(Either::B((_, reaping))) | Err(Either::B((_, reaping))) => Some(reaping),
+      };
+      if let Some(reaping) = still_running {
+        warn!(
+          "Process {} did not exit within {:?} of SIGTERM: killing it.",
+          pid, grace_period
+        );
+        running.kill(pid);
+        let _ = reaping.wait();
+      }
+    });
+  }
 }
 
 ///
@@ -268,31 +801,35 @@ mod tests {
   extern crate testutil;
 
   use super::super::CommandRunner as CommandRunnerTrait;
-  use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+  use super::{ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult, ResultSource};
+  use capture::{OutputLimit, OutputOverflow};
   use fs;
-  use futures::Future;
+  use futures::future::{self, Either};
+  use futures::{task, Async, Future};
+  #[cfg(unix)]
+  use libc;
   use std;
   use std::collections::{BTreeMap, BTreeSet};
   use std::env;
+  #[cfg(unix)]
   use std::os::unix::fs::PermissionsExt;
   use std::path::{Path, PathBuf};
   use std::sync::Arc;
+  use std::thread;
   use std::time::Duration;
+  use temp_root::TempRoot;
   use tempfile::TempDir;
+  use testing::{self, wait_until};
+  use testutil::as_bytes;
   use testutil::data::{TestData, TestDirectory};
-  use testutil::{as_bytes, owned_string_vec};
+  use workunits::{WorkUnitEvent, WorkUnitStore};
 
   #[test]
   #[cfg(unix)]
   fn stdout() {
     let result = run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&["/bin/echo", "-n", "foo"]),
-      env: BTreeMap::new(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
+      ..testing::request(&["/bin/echo", "-n", "foo"])
     });
 
     assert_eq!(
@@ -302,21 +839,55 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
 
+  #[test]
+  #[cfg(unix)]
+  fn reports_first_output_to_workunit() {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let runner = super::CommandRunner::new(
+      store,
+      pool,
+      work_dir.path().to_owned(),
+      TempRoot::create(work_dir.path()).unwrap(),
+      true,
+      Duration::from_secs(3),
+      0,
+    );
+    let workunits = WorkUnitStore::new();
+    let workunit = workunits.start("echo foo".to_string(), fs::EMPTY_DIGEST);
+    runner
+      .run_in_workunit(
+        ExecuteProcessRequest {
+          description: "echo foo".to_string(),
+          ..testing::request(&["/bin/echo", "foo"])
+        },
+        workunit.clone(),
+      )
+      .wait()
+      .unwrap();
+
+    let (events, _) = workunits.take_events();
+    assert_eq!(
+      events.last(),
+      Some(&WorkUnitEvent::FirstOutput { id: workunit.id() })
+    );
+  }
+
   #[test]
   #[cfg(unix)]
   fn stdout_and_stderr_and_exit_code() {
     let result = run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&["/bin/bash", "-c", "echo -n foo ; echo >&2 -n bar ; exit 1"]),
-      env: BTreeMap::new(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
       description: "echo foo and fail".to_string(),
+      ..testing::request(&["/bin/bash", "-c", "echo -n foo ; echo >&2 -n bar ; exit 1"])
     });
 
     assert_eq!(
@@ -326,6 +897,9 @@ mod tests {
         stderr: as_bytes("bar"),
         exit_code: 1,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -335,13 +909,8 @@ mod tests {
   fn capture_exit_code_signal() {
     // Launch a process that kills itself with a signal.
     let result = run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&["/bin/bash", "-c", "kill $$"]),
-      env: BTreeMap::new(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
       description: "kill self".to_string(),
+      ..testing::request(&["/bin/bash", "-c", "kill $$"])
     });
 
     assert_eq!(
@@ -351,6 +920,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: -15,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -363,13 +935,9 @@ mod tests {
     env.insert("BAR".to_string(), "not foo".to_string());
 
     let result = run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&["/usr/bin/env"]),
       env: env.clone(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
       description: "run env".to_string(),
+      ..testing::request(&["/usr/bin/env"])
     });
 
     let stdout = String::from_utf8(result.unwrap().stdout.to_vec()).unwrap();
@@ -398,13 +966,8 @@ mod tests {
       env.insert("BAR".to_string(), "not foo".to_string());
 
       ExecuteProcessRequest {
-        argv: owned_string_vec(&["/usr/bin/env"]),
         env: env,
-        input_files: fs::EMPTY_DIGEST,
-        output_files: BTreeSet::new(),
-        output_directories: BTreeSet::new(),
-        timeout: Duration::from_millis(1000),
-        description: "run env".to_string(),
+        ..testing::request(&["/usr/bin/env"])
       }
     }
 
@@ -414,33 +977,77 @@ mod tests {
     assert_eq!(result1.unwrap(), result2.unwrap());
   }
 
+  #[test]
+  #[cfg(unix)]
+  fn sandbox_relative_tmpdir() {
+    let work_dir = TempDir::new().unwrap();
+    let mut env = BTreeMap::new();
+    env.insert("TMPDIR".to_string(), "tmp".to_string());
+
+    let result = run_command_locally_in_dir_with_cleanup(
+      ExecuteProcessRequest {
+        argv: vec![
+          find_bash(),
+          "-c".to_owned(),
+          "test -d \"$TMPDIR\" && echo -n \"$TMPDIR\"".to_owned(),
+        ],
+        env: env,
+        input_files: fs::EMPTY_DIGEST,
+        output_files: BTreeSet::new(),
+        output_directories: BTreeSet::new(),
+        immutable_input_directories: BTreeSet::new(),
+        max_output_bytes: None,
+        max_disk_bytes: None,
+        cache_failures: true,
+        container_image: None,
+        timeout: Duration::from_millis(1000),
+        description: "echo tmpdir".to_string(),
+      },
+      work_dir.path().to_owned(),
+    ).unwrap();
+
+    assert_eq!(result.exit_code, 0);
+    let tmpdir = PathBuf::from(String::from_utf8(result.stdout.to_vec()).unwrap());
+    assert!(tmpdir.starts_with(work_dir.path()), "{:?}", tmpdir);
+    assert!(tmpdir.ends_with("tmp"), "{:?}", tmpdir);
+  }
+
+  #[test]
+  fn declared_env_overrides_inherited_env() {
+    let mut inherited = BTreeMap::new();
+    inherited.insert("HOME".to_string(), "/home/roland".to_string());
+    inherited.insert("LANG".to_string(), "C".to_string());
+    let mut declared = BTreeMap::new();
+    declared.insert("LANG".to_string(), "en_US.UTF-8".to_string());
+    declared.insert("TMPDIR".to_string(), "/tmp".to_string());
+
+    let mut want = BTreeMap::new();
+    want.insert("HOME".to_string(), "/home/roland".to_string());
+    want.insert("LANG".to_string(), "en_US.UTF-8".to_string());
+    want.insert("TMPDIR".to_string(), "/tmp".to_string());
+    assert_eq!(
+      super::sandbox_env(declared, &inherited, Path::new("/sandbox")),
+      Ok(want)
+    );
+  }
+
   #[test]
   fn binary_not_found() {
     run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&["echo", "-n", "foo"]),
-      env: BTreeMap::new(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
+      ..testing::request(&["echo", "-n", "foo"])
     }).expect_err("Want Err");
   }
 
   #[test]
   fn output_files_none() {
     let result = run_command_locally(ExecuteProcessRequest {
-      argv: owned_string_vec(&[
+      description: "bash".to_string(),
+      ..testing::request(&[
         which("bash").expect("No bash on PATH").to_str().unwrap(),
         "-c",
         "exit 0",
-      ]),
-      env: BTreeMap::new(),
-      input_files: fs::EMPTY_DIGEST,
-      output_files: BTreeSet::new(),
-      output_directories: BTreeSet::new(),
-      timeout: Duration::from_millis(1000),
-      description: "bash".to_string(),
+      ])
     });
     assert_eq!(
       result.unwrap(),
@@ -449,6 +1056,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -465,6 +1075,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: vec![PathBuf::from("roland")].into_iter().collect(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
     });
@@ -476,6 +1091,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -497,6 +1115,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: vec![PathBuf::from("treats")].into_iter().collect(),
       output_directories: vec![PathBuf::from("cats")].into_iter().collect(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "bash".to_string(),
     });
@@ -508,6 +1131,40 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
+      }
+    )
+  }
+
+  #[test]
+  fn output_dirs_empty() {
+    let result = run_command_locally(ExecuteProcessRequest {
+      argv: vec![find_bash(), "-c".to_owned(), "/bin/mkdir cats".to_string()],
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: vec![PathBuf::from("cats")].into_iter().collect(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
+      timeout: Duration::from_millis(1000),
+      description: "bash".to_string(),
+    });
+
+    assert_eq!(
+      result.unwrap(),
+      FallibleExecuteProcessResult {
+        stdout: as_bytes(""),
+        stderr: as_bytes(""),
+        exit_code: 0,
+        output_directory: TestDirectory::containing_empty_dir().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -530,6 +1187,11 @@ mod tests {
         .into_iter()
         .collect(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "treats-roland".to_string(),
     });
@@ -541,6 +1203,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::recursive().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -561,6 +1226,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: vec![PathBuf::from("roland")].into_iter().collect(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "echo foo".to_string(),
     });
@@ -572,6 +1242,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 1,
         output_directory: TestDirectory::containing_roland().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -590,6 +1263,11 @@ mod tests {
         .into_iter()
         .collect(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "echo-roland".to_string(),
     });
@@ -601,6 +1279,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: TestDirectory::containing_roland().digest(),
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedLocally,
       }
     )
   }
@@ -621,6 +1302,11 @@ mod tests {
         input_files: fs::EMPTY_DIGEST,
         output_files: vec![PathBuf::from("roland")].into_iter().collect(),
         output_directories: BTreeSet::new(),
+        immutable_input_directories: BTreeSet::new(),
+        max_output_bytes: None,
+        max_disk_bytes: None,
+        cache_failures: true,
+        container_image: None,
         timeout: Duration::from_millis(1000),
         description: "bash".to_string(),
       },
@@ -640,9 +1326,320 @@ mod tests {
     assert_eq!(rolands_path.exists(), true);
   }
 
+  #[test]
+  fn cancelled_process_is_terminated() {
+    let marker_dir = TempDir::new().unwrap();
+    let started = marker_dir.path().join("started");
+    let terminated = marker_dir.path().join("terminated");
+    let (runner, _dirs) = new_runner(Duration::from_secs(5));
+
+    let script = format!(
+      "trap 'echo > {}; exit 0' TERM; echo > {}; while true; do sleep 0.1; done",
+      terminated.display(),
+      started.display()
+    );
+    let cancelled = runner
+      .run(bash_request(&script))
+      .select2(file_exists(started))
+      .wait();
+    match cancelled {
+      // Drop the execution once it has started.
+      Ok(Either::B((_, execution))) => drop(execution),
+      _ => panic!("The process exited before it was cancelled."),
+    }
+
+    // The process should be given a chance to handle SIGTERM.
+    wait_until(|| terminated.exists());
+  }
+
+  #[test]
+  fn cancelled_process_is_killed_after_grace_period() {
+    let marker_dir = TempDir::new().unwrap();
+    let started = marker_dir.path().join("started");
+    let (runner, _dirs) = new_runner(Duration::from_millis(100));
+
+    let script = format!(
+      "trap '' TERM; echo > {}; while true; do sleep 0.1; done",
+      started.display()
+    );
+    match runner
+      .run(bash_request(&script))
+      .select2(file_exists(started))
+      .wait()
+    {
+      Ok(Either::B((_, execution))) => drop(execution),
+      _ => panic!("The process exited before it was cancelled."),
+    }
+
+    // The process ignores SIGTERM, so it must be killed (and reaped).
+    wait_until(|| runner.running.pids.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn cancelled_process_is_terminated_with_its_descendants() {
+    let marker_dir = TempDir::new().unwrap();
+    let started = marker_dir.path().join("started");
+    let grandchild_pid = marker_dir.path().join("grandchild_pid");
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+
+    let script = format!(
+      "sleep 1000 & echo -n $! > {}; echo > {}; wait",
+      grandchild_pid.display(),
+      started.display()
+    );
+    match runner
+      .run(bash_request(&script))
+      .select2(file_exists(started))
+      .wait()
+    {
+      Ok(Either::B((_, execution))) => drop(execution),
+      _ => panic!("The process exited before it was cancelled."),
+    }
+
+    // The whole process group is signalled, so the grandchild exits too.
+    let pid: libc::pid_t = std::fs::read_to_string(&grandchild_pid)
+      .unwrap()
+      .parse()
+      .unwrap();
+    wait_until(|| unsafe { libc::kill(pid, 0) } != 0);
+    wait_until(|| runner.running.pids.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn shutdown_terminates_processes() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(5));
+    let runner = Arc::new(runner);
+
+    let execution = {
+      let runner = runner.clone();
+      thread::spawn(move || {
+        runner
+          .run(bash_request("while true; do sleep 0.1; done"))
+          .wait()
+      })
+    };
+    wait_until(|| !runner.running.pids.lock().unwrap().is_empty());
+
+    // The process exits on SIGTERM, so it is not killed.
+    assert_eq!(runner.shutdown(), Vec::<u32>::new());
+    assert_eq!(execution.join().unwrap().unwrap().exit_code, -15);
+  }
+
+  #[test]
+  fn container_image_is_refused() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(5));
+    let error = runner
+      .run(ExecuteProcessRequest {
+        container_image: Some("debian:stretch".to_owned()),
+        ..bash_request("true")
+      })
+      .wait()
+      .unwrap_err();
+    assert!(
+      error
+        .message()
+        .contains("requires the container image \"debian:stretch\""),
+      "{}",
+      error
+    );
+  }
+
+  #[test]
+  fn shutdown_prevents_new_processes() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(5));
+    assert_eq!(runner.shutdown(), Vec::<u32>::new());
+
+    let error = runner.run(bash_request("true")).wait().unwrap_err();
+    assert_eq!(
+      error,
+      ExecutionError::Fatal(
+        "Cannot launch process: process execution has been shut down.".to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn shutdown_reports_killed_processes() {
+    let marker_dir = TempDir::new().unwrap();
+    let started = marker_dir.path().join("started");
+    let (runner, _dirs) = new_runner(Duration::from_millis(100));
+    let runner = Arc::new(runner);
+
+    let script = format!(
+      "trap '' TERM; echo > {}; while true; do sleep 0.1; done",
+      started.display()
+    );
+    let execution = {
+      let runner = runner.clone();
+      thread::spawn(move || runner.run(bash_request(&script)).wait())
+    };
+    wait_until(|| started.exists());
+
+    let pids = runner
+      .running
+      .pids
+      .lock()
+      .unwrap()
+      .iter()
+      .cloned()
+      .collect::<Vec<_>>();
+    assert_eq!(runner.shutdown(), pids);
+    assert_eq!(execution.join().unwrap().unwrap().exit_code, -9);
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn reports_disk_bytes_written_to_workunit() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+    let workunits = WorkUnitStore::new();
+    let workunit = workunits.start("write".to_string(), fs::EMPTY_DIGEST);
+    let result = runner
+      .run_in_workunit(bash_request("echo -n 12345 > out"), workunit.clone())
+      .wait()
+      .unwrap();
+    workunit.completed(result.exit_code, result.source);
+
+    let (events, _) = workunits.take_events();
+    assert_eq!(
+      events.last(),
+      Some(&WorkUnitEvent::Completed {
+        id: workunit.id(),
+        exit_code: 0,
+        source: ResultSource::ExecutedLocally,
+        disk_bytes_written: Some(5),
+        cache_bytes_written: Some(0),
+      })
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn fails_process_which_exceeds_disk_quota() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+    let mut req = bash_request("head -c 2048 /dev/zero > out");
+    req.max_disk_bytes = Some(1024);
+    assert_eq!(
+      runner.run(req).wait(),
+      Err(ExecutionError::Fatal(
+        "Process exceeded its disk quota of 1024 bytes: it wrote at least 2048 bytes into its \
+         sandbox."
+          .to_owned()
+      ))
+    );
+  }
+
+  #[test]
+  #[cfg(unix)]
+  fn terminates_runaway_process_which_exceeds_disk_quota() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+    let mut req = bash_request("while true; do head -c 1024 /dev/zero >> out; sleep 0.01; done");
+    req.max_disk_bytes = Some(64 * 1024);
+    let err = runner.run(req).wait().expect_err("A runaway process should fail.");
+    assert!(
+      err.message().starts_with("Process exceeded its disk quota"),
+      "{}",
+      err
+    );
+    // Running the process again would only exceed the quota again.
+    assert!(!err.is_transient());
+
+    // The process is terminated (and reaped).
+    wait_until(|| runner.running.pids.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn output_limit() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+    let runner = runner.with_output_limit(OutputLimit {
+      max_bytes: Some(8),
+      overflow: OutputOverflow::Store,
+    });
+
+    let result = runner
+      .run(bash_request("echo -n European Burmese; echo -n meow >&2"))
+      .wait()
+      .unwrap();
+    assert!(result.stdout.starts_with(b"European\n[8 more bytes"));
+    assert!(result.stdout_overflow.is_some());
+    assert_eq!(result.stderr, as_bytes("meow"));
+    assert_eq!(result.stderr_overflow, None);
+
+    // A request may lower the limit further.
+    let result = runner
+      .run(ExecuteProcessRequest {
+        max_output_bytes: Some(2),
+        max_disk_bytes: None,
+        cache_failures: true,
+        container_image: None,
+        ..bash_request("echo -n meow >&2")
+      })
+      .wait()
+      .unwrap();
+    assert!(result.stderr.starts_with(b"me\n[2 more bytes"));
+  }
+
+  #[test]
+  #[cfg(target_os = "macos")]
+  fn macos_sandbox_denies_writes_outside_sandbox() {
+    let (runner, _dirs) = new_runner(Duration::from_secs(3));
+    let cache_dir = TempDir::new().unwrap();
+    let elsewhere = TempDir::new().unwrap();
+    let runner = runner.with_macos_sandbox(vec![cache_dir.path().to_owned()]);
+
+    let script = format!(
+      "echo -n sandbox > file && echo -n cache > {}/file",
+      cache_dir.path().display()
+    );
+    let result = runner.run(bash_request(&script)).wait().unwrap();
+    assert_eq!(result.exit_code, 0, "{:?}", result.stderr);
+    assert!(cache_dir.path().join("file").exists());
+
+    let script = format!("echo -n elsewhere > {}/file", elsewhere.path().display());
+    let result = runner.run(bash_request(&script)).wait().unwrap();
+    assert_ne!(result.exit_code, 0);
+    assert!(!elsewhere.path().join("file").exists());
+  }
+
+  fn bash_request(script: &str) -> ExecuteProcessRequest {
+    testing::request(&["/bin/bash", "-c", script])
+  }
+
+  ///
+  /// A future which completes once the given path exists.
+  ///
+  fn file_exists(path: PathBuf) -> impl Future<Item = (), Error = String> {
+    future::poll_fn(move || {
+      if path.exists() {
+        Ok(Async::Ready(()))
+      } else {
+        thread::sleep(Duration::from_millis(10));
+        task::current().notify();
+        Ok(Async::NotReady)
+      }
+    })
+  }
+
+  fn new_runner(termination_grace_period: Duration) -> (super::CommandRunner, (TempDir, TempDir)) {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
+    let runner = super::CommandRunner::new(
+      store,
+      pool,
+      work_dir.path().to_owned(),
+      TempRoot::create(work_dir.path()).unwrap(),
+      true,
+      termination_grace_period,
+      0,
+    );
+    (runner, (store_dir, work_dir))
+  }
+
   fn run_command_locally(
     req: ExecuteProcessRequest,
-  ) -> Result<FallibleExecuteProcessResult, String> {
+  ) -> Result<FallibleExecuteProcessResult, ExecutionError> {
     let work_dir = TempDir::new().unwrap();
     run_command_locally_in_dir_with_cleanup(req, work_dir.path().to_owned())
   }
@@ -650,7 +1647,7 @@ mod tests {
   fn run_command_locally_in_dir_with_cleanup(
     req: ExecuteProcessRequest,
     dir: PathBuf,
-  ) -> Result<FallibleExecuteProcessResult, String> {
+  ) -> Result<FallibleExecuteProcessResult, ExecutionError> {
     run_command_locally_in_dir(req, dir, true)
   }
 
@@ -658,16 +1655,20 @@ mod tests {
     req: ExecuteProcessRequest,
     dir: PathBuf,
     cleanup: bool,
-  ) -> Result<FallibleExecuteProcessResult, String> {
+  ) -> Result<FallibleExecuteProcessResult, ExecutionError> {
     let store_dir = TempDir::new().unwrap();
     let pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
     let store = fs::Store::local_only(store_dir.path(), pool.clone()).unwrap();
-    let runner = super::CommandRunner {
-      store: store,
-      fs_pool: pool,
-      work_dir: dir,
-      cleanup_local_dirs: cleanup,
-    };
+    let temp_root = TempRoot::create(&dir).unwrap();
+    let runner = super::CommandRunner::new(
+      store,
+      pool,
+      dir,
+      temp_root,
+      cleanup,
+      Duration::from_secs(3),
+      0,
+    );
     runner.run(req).wait()
   }
 
@@ -0,0 +1,57 @@
+use std::env::consts;
+
+///
+/// The platform that processes are executed on locally, with each component normalized to the
+/// names that pants uses elsewhere (see `pants.util.osutil`), so that it can be used to key caches
+/// of per-platform artifacts.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Platform {
+  // The operating system: for example "linux" or "darwin".
+  pub os: &'static str,
+  // The instruction set architecture: for example "x86_64".
+  pub arch: &'static str,
+  // The family of C library that binaries must link against: "glibc" or "musl" on Linux, and
+  // "system" elsewhere.
+  pub libc: &'static str,
+}
+
+impl Platform {
+  pub fn current() -> Platform {
+    Platform {
+      os: match consts::OS {
+        "macos" => "darwin",
+        os => os,
+      },
+      arch: consts::ARCH,
+      libc: if cfg!(all(target_os = "linux", target_env = "musl")) {
+        "musl"
+      } else if cfg!(target_os = "linux") {
+        "glibc"
+      } else {
+        "system"
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Platform;
+
+  #[test]
+  #[cfg(all(target_os = "linux", target_env = "gnu"))]
+  fn current_linux() {
+    let platform = Platform::current();
+    assert_eq!(platform.os, "linux");
+    assert_eq!(platform.libc, "glibc");
+  }
+
+  #[test]
+  #[cfg(target_os = "macos")]
+  fn current_darwin() {
+    let platform = Platform::current();
+    assert_eq!(platform.os, "darwin");
+    assert_eq!(platform.libc, "system");
+  }
+}
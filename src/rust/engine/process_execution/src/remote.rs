@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use bazel_protos;
@@ -16,7 +17,8 @@ use protobuf::{self, Message, ProtobufEnum};
 use resettable::Resettable;
 use sha2::Sha256;
 
-use super::{ExecuteProcessRequest, FallibleExecuteProcessResult};
+use super::capture::OutputLimit;
+use super::{ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult, ResultSource};
 use std::cmp::min;
 
 #[derive(Clone)]
@@ -26,51 +28,126 @@ pub struct CommandRunner {
   execution_client: Resettable<Arc<bazel_protos::remote_execution_grpc::ExecutionClient>>,
   operations_client: Resettable<Arc<bazel_protos::operations_grpc::OperationsClient>>,
   store: Store,
+  // The name of the current Operation (and the description of its process) for each in-flight
+  // execution, keyed by an id that is unique to each call to `run`.
+  in_flight: Arc<Mutex<HashMap<usize, String>>>,
+  next_run_id: Arc<AtomicUsize>,
+  output_limit: OutputLimit,
 }
 
 #[derive(Debug, PartialEq)]
-enum ExecutionError {
-  // String is the error message.
-  Fatal(String),
+enum OperationError {
+  // The execution failed, and will not be continued.
+  Failed(ExecutionError),
   // Digests are Files and Directories which have been reported to be missing. May be incomplete.
   MissingDigests(Vec<Digest>),
-  // String is the operation name which can be used to poll the GetOperation gRPC API.
+  // String is the operation name which can be used to reconnect to the WaitExecution gRPC API.
   NotFinished(String),
 }
 
+impl OperationError {
+  fn fatal(message: String) -> OperationError {
+    OperationError::Failed(ExecutionError::Fatal(message))
+  }
+}
+
 impl CommandRunner {
-  // The Execute API used to be unary, and became streaming. The contract of the streaming API is
-  // that if the client closes the stream after one request, it should continue to function exactly
-  // like the unary API.
-  // For maximal compatibility with servers, we fall back to this unary-like behavior, and control
-  // our own polling rates.
-  // In the future, we may want to remove this behavior if servers reliably support the full stream
-  // behavior.
-  fn oneshot_execute(
+  ///
+  /// Sends an ExecuteRequest, and follows the stream of Operations that the server responds with
+  /// until one of them is done.
+  ///
+  /// Long-running executions may outlive the stream (which might be closed by the server, or
+  /// dropped by a load balancer between us), in which case the latest Operation is returned
+  /// without being done, and the caller should reconnect to it using `wait_execution`.
+  ///
+  fn execute(
     &self,
     execute_request: Arc<bazel_protos::remote_execution::ExecuteRequest>,
-  ) -> BoxFuture<bazel_protos::operations::Operation, String> {
+  ) -> BoxFuture<bazel_protos::operations::Operation, ExecutionError> {
     let stream = try_future!(
       self
         .execution_client
         .get()
         .execute(&execute_request)
-        .map_err(rpcerror_to_string)
+        .map_err(rpcerror_to_execution_error)
     );
-    stream
-        .take(1)
-        .into_future()
-        // If there was an error, drop the _stream to disconnect so that the server doesn't keep the
-        // connection alive and continue sending on it.
-        .map_err(|(error, _stream)| rpcerror_to_string(error))
-        .and_then(|(maybe_operation, _stream)| {
-          // If there was a response, drop the _stream to disconnect so that the server doesn't keep
-          // the connection alive and continue sending on it.
-          maybe_operation.ok_or_else(|| {
-            "Didn't get proper stream response from server during remote execution".to_owned()
-          })
+    follow_operations(stream, None)
+      .map_err(rpcerror_to_execution_error)
+      .and_then(|maybe_operation| {
+        maybe_operation.ok_or_else(|| {
+          ExecutionError::Fatal(
+            "Didn't get proper stream response from server during remote execution".to_owned(),
+          )
         })
-        .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Reconnects to the stream of Operations for an execution which is not done, and follows it
+  /// like `execute` does.
+  ///
+  /// Falls back to polling the Operation if the server does not implement WaitExecution, and
+  /// re-executes the request if the server no longer knows about the Operation (for example,
+  /// because we have reconnected to a different server).
+  ///
+  fn wait_execution(
+    &self,
+    operation_name: String,
+    execute_request: Arc<bazel_protos::remote_execution::ExecuteRequest>,
+  ) -> BoxFuture<bazel_protos::operations::Operation, ExecutionError> {
+    let mut wait_request = bazel_protos::remote_execution::WaitExecutionRequest::new();
+    wait_request.set_name(operation_name.clone());
+    let stream = try_future!(
+      self
+        .execution_client
+        .get()
+        .wait_execution(&wait_request)
+        .map_err(rpcerror_to_execution_error)
+    );
+
+    let mut latest = bazel_protos::operations::Operation::new();
+    latest.set_name(operation_name.clone());
+    let command_runner = self.clone();
+    follow_operations(stream, Some(latest))
+      .then(move |res| match res {
+        Ok(maybe_operation) => future::done(maybe_operation.ok_or_else(|| {
+          ExecutionError::Fatal(format!(
+            "Lost track of operation {} during remote execution",
+            operation_name
+          ))
+        })).to_boxed(),
+        Err(grpcio::Error::RpcFailure(status)) => match status.status {
+          grpcio::RpcStatusCode::Unimplemented => command_runner.get_operation(operation_name),
+          grpcio::RpcStatusCode::NotFound => {
+            debug!(
+              "Server does not know about operation {}; re-executing",
+              operation_name
+            );
+            command_runner.execute(execute_request)
+          }
+          _ => {
+            future::err(rpcerror_to_execution_error(grpcio::Error::RpcFailure(status))).to_boxed()
+          }
+        },
+        Err(err) => future::err(rpcerror_to_execution_error(err)).to_boxed(),
+      })
+      .to_boxed()
+  }
+
+  fn get_operation(
+    &self,
+    operation_name: String,
+  ) -> BoxFuture<bazel_protos::operations::Operation, ExecutionError> {
+    let mut operation_request = bazel_protos::operations::GetOperationRequest::new();
+    operation_request.set_name(operation_name);
+    future::done(
+      self
+        .operations_client
+        .get()
+        .get_operation(&operation_request)
+        .map_err(rpcerror_to_execution_error),
+    ).to_boxed()
   }
 }
 
@@ -89,14 +166,90 @@ impl super::CommandRunner for CommandRunner {
   /// user has changed, or files which aren't known to the local git repository, but these are
   /// optimizations to shave off a round-trip in the future.
   ///
-  /// Loops until the server gives a response, either successful or error. Does not have any
-  /// timeout: polls in a tight loop.
+  /// Follows the execution until the server gives a response, either successful or error,
+  /// reconnecting (with a backoff) whenever the stream of updates for it ends early. Fails if the
+  /// request's timeout elapses first.
   ///
-  fn run(&self, req: ExecuteProcessRequest) -> BoxFuture<FallibleExecuteProcessResult, String> {
-    let operations_client = self.operations_client.clone();
+  fn run(
+    &self,
+    req: ExecuteProcessRequest,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+    let command_runner = self.clone();
+    self
+      .run_once(req.clone(), false)
+      .and_then(move |result| {
+        // A failure which was served from the action cache may be stale: if the request does not
+        // cache failures, it is executed again.
+        if !req.cache_failures
+          && result.source == ResultSource::RemoteCache
+          && result.exit_code != 0
+        {
+          debug!(
+            "Re-executing {:?}, which failed with exit code {} in the action cache",
+            req.description, result.exit_code
+          );
+          command_runner.run_once(req, true)
+        } else {
+          future::ok(result).to_boxed()
+        }
+      })
+      .to_boxed()
+  }
+
+  fn pre_fork(&self) {
+    self.channel.reset();
+    self.env.reset();
+    self.execution_client.reset();
+    self.operations_client.reset();
+  }
+
+  fn post_fork_in_child(&self) -> Result<(), String> {
+    self.channel.reset_in_child()?;
+    self.env.reset_in_child()?;
+    self.execution_client.reset_in_child()?;
+    self.operations_client.reset_in_child()
+  }
+
+  fn shutdown(&self) -> Vec<u32> {
+    // Closes the channel. Operations that are still in flight on the server are not cancelled.
+    super::CommandRunner::pre_fork(self);
+    vec![]
+  }
 
+  fn in_flight_operations(&self) -> Vec<String> {
+    let mut operations = self
+      .in_flight
+      .lock()
+      .unwrap()
+      .values()
+      .cloned()
+      .collect::<Vec<_>>();
+    operations.sort();
+    operations
+  }
+}
+
+impl CommandRunner {
+  const BACKOFF_INCR_WAIT_MILLIS: u64 = 500;
+  const BACKOFF_MAX_WAIT_MILLIS: u64 = 5000;
+
+  ///
+  /// Executes the request once, optionally skipping the lookup of its result in the action cache.
+  ///
+  fn run_once(
+    &self,
+    req: ExecuteProcessRequest,
+    skip_cache_lookup: bool,
+  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
     let store = self.store.clone();
-    let execute_request_result = make_execute_request(&req);
+    let store2 = self.store.clone();
+    // The server captures the complete output, so it is limited once the result has been fetched.
+    let output_limit = self.output_limit.for_request(req.max_output_bytes);
+    let execute_request_result =
+      make_execute_request(&req).map(|(action, command, mut execute_request)| {
+        execute_request.set_skip_cache_lookup(skip_cache_lookup);
+        (action, command, execute_request)
+      });
 
     let ExecuteProcessRequest {
       description,
@@ -110,51 +263,57 @@ impl super::CommandRunner for CommandRunner {
         let command_runner2 = self.clone();
         let execute_request = Arc::new(execute_request);
         let execute_request2 = execute_request.clone();
+        let run_id = self.next_run_id.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.in_flight.clone();
+        let in_flight2 = self.in_flight.clone();
         self
           .upload_proto(&command)
           .join(self.upload_proto(&action))
+          .map_err(ExecutionError::Fatal)
           .and_then(move |_| {
             debug!(
               "Executing remotely request: {:?} (command: {:?})",
               execute_request, command
             );
-            command_runner.oneshot_execute(execute_request)
+            command_runner.execute(execute_request)
           })
           .and_then(move |operation| {
             let start_time = Instant::now();
 
             future::loop_fn((operation, 0), move |(operation, iter_num)| {
               let description = description.clone();
+              in_flight.lock().unwrap().insert(
+                run_id,
+                format!("{} ({})", operation.get_name(), description),
+              );
 
               let execute_request2 = execute_request2.clone();
               let store = store.clone();
-              let operations_client = operations_client.clone();
               let command_runner2 = command_runner2.clone();
               command_runner2
                 .extract_execute_response(operation)
                 .map(future::Loop::Break)
                 .or_else(move |value| {
                   match value {
-                    ExecutionError::Fatal(err) => future::err(err).to_boxed(),
-                    ExecutionError::MissingDigests(missing_digests) => {
+                    OperationError::Failed(err) => future::err(err).to_boxed(),
+                    OperationError::MissingDigests(missing_digests) => {
                       debug!(
                         "Server reported missing digests; trying to upload: {:?}",
                         missing_digests
                       );
                       let execute_request = execute_request2.clone();
+                      // The server may have evicted blobs that it previously confirmed it had.
+                      store.forget_remote_digests(&missing_digests);
                       store.ensure_remote_has_recursive(missing_digests)
+                              .map_err(ExecutionError::Fatal)
                               .and_then(move |()| {
-                                command_runner2.oneshot_execute(execute_request)
+                                command_runner2.execute(execute_request)
                               })
                               // Reset `iter_num` on `MissingDigests`
                               .map(|operation| future::Loop::Continue((operation, 0)))
                               .to_boxed()
                     }
-                    ExecutionError::NotFinished(operation_name) => {
-                      let mut operation_request =
-                        bazel_protos::operations::GetOperationRequest::new();
-                      operation_request.set_name(operation_name.clone());
-
+                    OperationError::NotFinished(operation_name) => {
                       let backoff_period = min(
                         CommandRunner::BACKOFF_MAX_WAIT_MILLIS,
                         (1 + iter_num) * CommandRunner::BACKOFF_INCR_WAIT_MILLIS,
@@ -164,29 +323,26 @@ impl super::CommandRunner for CommandRunner {
                       let elapsed = start_time.elapsed();
 
                       if elapsed > timeout {
-                        future::err(format!(
+                        future::err(ExecutionError::Fatal(format!(
                           "Exceeded time out of {:?} with {:?} for operation {}, {}",
                           timeout, elapsed, operation_name, description
-                        )).to_boxed()
+                        ))).to_boxed()
                       } else {
+                        let operation_name2 = operation_name.clone();
                         // maybe the delay here should be the min of remaining time and the backoff period
                         Delay::new(Duration::from_millis(backoff_period))
                           .map_err(move |e| {
-                            format!(
+                            ExecutionError::Fatal(format!(
                               "Future-Delay errored at operation result polling for {}, {}: {}",
-                              operation_name, description, e
-                            )
+                              operation_name2, description, e
+                            ))
                           })
                           .and_then(move |_| {
-                            future::done(
-                              operations_client
-                                .get()
-                                .get_operation(&operation_request)
-                                .map_err(rpcerror_to_string),
-                            ).map(move |operation| {
-                              future::Loop::Continue((operation, iter_num + 1))
-                            })
-                              .to_boxed()
+                            command_runner2
+                              .wait_execution(operation_name, execute_request2)
+                              .map(move |operation| {
+                                future::Loop::Continue((operation, iter_num + 1))
+                              })
                           })
                           .to_boxed()
                       }
@@ -195,24 +351,21 @@ impl super::CommandRunner for CommandRunner {
                 })
             })
           })
+          .then(move |res| {
+            in_flight2.lock().unwrap().remove(&run_id);
+            res
+          })
+          .and_then(move |result| {
+            output_limit
+              .apply(result, &store2)
+              .map_err(ExecutionError::Fatal)
+          })
           .to_boxed()
       }
-      Err(err) => future::err(err).to_boxed(),
+      Err(err) => future::err(ExecutionError::Fatal(err)).to_boxed(),
     }
   }
 
-  fn reset_prefork(&self) {
-    self.channel.reset();
-    self.env.reset();
-    self.execution_client.reset();
-    self.operations_client.reset();
-  }
-}
-
-impl CommandRunner {
-  const BACKOFF_INCR_WAIT_MILLIS: u64 = 500;
-  const BACKOFF_MAX_WAIT_MILLIS: u64 = 5000;
-
   pub fn new(address: String, thread_count: usize, store: Store) -> CommandRunner {
     let env = Resettable::new(move || Arc::new(grpcio::Environment::new(thread_count)));
     let env2 = env.clone();
@@ -237,9 +390,20 @@ impl CommandRunner {
       execution_client,
       operations_client,
       store,
+      in_flight: Arc::new(Mutex::new(HashMap::new())),
+      next_run_id: Arc::new(AtomicUsize::new(0)),
+      output_limit: OutputLimit::unlimited(),
     }
   }
 
+  ///
+  /// Limits how much of the stdout and stderr of each process is kept: see `OutputLimit`.
+  ///
+  pub fn with_output_limit(mut self, output_limit: OutputLimit) -> CommandRunner {
+    self.output_limit = output_limit;
+    self
+  }
+
   fn upload_proto<P: protobuf::Message>(&self, proto: &P) -> BoxFuture<(), String> {
     let store = self.store.clone();
     let store2 = store.clone();
@@ -262,17 +426,21 @@ impl CommandRunner {
   fn extract_execute_response(
     &self,
     mut operation: bazel_protos::operations::Operation,
-  ) -> BoxFuture<FallibleExecuteProcessResult, ExecutionError> {
+  ) -> BoxFuture<FallibleExecuteProcessResult, OperationError> {
     // TODO: Log less verbosely
     debug!("Got operation response: {:?}", operation);
     if !operation.get_done() {
-      return future::err(ExecutionError::NotFinished(operation.take_name())).to_boxed();
+      return future::err(OperationError::NotFinished(operation.take_name())).to_boxed();
     }
     if operation.has_error() {
-      return future::err(ExecutionError::Fatal(format_error(&operation.get_error()))).to_boxed();
+      let error = operation.get_error();
+      return future::err(OperationError::Failed(status_to_execution_error(
+        grpcio::RpcStatusCode::from(error.get_code()),
+        format_error(error),
+      ))).to_boxed();
     }
     if !operation.has_response() {
-      return future::err(ExecutionError::Fatal(
+      return future::err(OperationError::fatal(
         "Operation finished but no response supplied".to_string(),
       )).to_boxed();
     }
@@ -280,7 +448,7 @@ impl CommandRunner {
     try_future!(
       execute_response
         .merge_from_bytes(operation.get_response().get_value())
-        .map_err(|e| ExecutionError::Fatal(format!("Invalid ExecuteResponse: {:?}", e)))
+        .map_err(|e| OperationError::fatal(format!("Invalid ExecuteResponse: {:?}", e)))
     );
     // TODO: Log less verbosely
     debug!("Got (nested) execute response: {:?}", execute_response);
@@ -289,6 +457,7 @@ impl CommandRunner {
       .extract_stdout(&execute_response)
       .join(self.extract_stderr(&execute_response))
       .join(self.extract_output_files(&execute_response))
+      .map_err(OperationError::Failed)
       .and_then(move |((stdout, stderr), output_directory)| {
         match grpcio::RpcStatusCode::from(execute_response.get_status().get_code()) {
           grpcio::RpcStatusCode::Ok => future::ok(FallibleExecuteProcessResult {
@@ -296,10 +465,17 @@ impl CommandRunner {
             stderr: stderr,
             exit_code: execute_response.get_result().get_exit_code(),
             output_directory: output_directory,
+            stdout_overflow: None,
+            stderr_overflow: None,
+            source: if execute_response.get_cached_result() {
+              ResultSource::RemoteCache
+            } else {
+              ResultSource::ExecutedRemotely
+            },
           }).to_boxed(),
           grpcio::RpcStatusCode::FailedPrecondition => {
             if execute_response.get_status().get_details().len() != 1 {
-              return future::err(ExecutionError::Fatal(format!(
+              return future::err(OperationError::fatal(format!(
               "Received multiple details in FailedPrecondition ExecuteResponse's status field: {:?}",
               execute_response.get_status().get_details()
             ))).to_boxed();
@@ -311,7 +487,7 @@ impl CommandRunner {
                 "type.googleapis.com/{}",
                 precondition_failure.descriptor().full_name()
               ) {
-              return future::err(ExecutionError::Fatal(format!(
+              return future::err(OperationError::fatal(format!(
                 "Received FailedPrecondition, but didn't know how to resolve it: {},\
                  protobuf type {}",
                 execute_response.get_status().get_message(),
@@ -322,7 +498,7 @@ impl CommandRunner {
               precondition_failure
                 .merge_from_bytes(details.get_value())
                 .map_err(|e| {
-                  ExecutionError::Fatal(format!(
+                  OperationError::fatal(format!(
                     "Error deserializing FailedPrecondition proto: {:?}",
                     e
                   ))
@@ -334,42 +510,45 @@ impl CommandRunner {
 
             for violation in precondition_failure.get_violations() {
               if violation.get_field_type() != "MISSING" {
-                return future::err(ExecutionError::Fatal(format!(
+                return future::err(OperationError::fatal(format!(
                   "Didn't know how to process PreconditionFailure violation: {:?}",
                   violation
                 ))).to_boxed();
               }
               let parts: Vec<_> = violation.get_subject().split('/').collect();
               if parts.len() != 3 || parts[0] != "blobs" {
-                return future::err(ExecutionError::Fatal(format!(
+                return future::err(OperationError::fatal(format!(
                   "Received FailedPrecondition MISSING but didn't recognize subject {}",
                   violation.get_subject()
                 ))).to_boxed();
               }
               let digest = Digest(
                 try_future!(Fingerprint::from_hex_string(parts[1]).map_err(|e| {
-                  ExecutionError::Fatal(format!("Bad digest in missing blob: {}: {}", parts[1], e))
+                  OperationError::fatal(format!("Bad digest in missing blob: {}: {}", parts[1], e))
                 })),
                 try_future!(
                   parts[2].parse::<usize>().map_err(|e| {
-                    ExecutionError::Fatal(format!("Missing blob had bad size: {}: {}", parts[2], e))
+                    OperationError::fatal(format!("Missing blob had bad size: {}: {}", parts[2], e))
                   })
                 ),
               );
               missing_digests.push(digest);
             }
             if missing_digests.is_empty() {
-              return future::err(ExecutionError::Fatal(
+              return future::err(OperationError::fatal(
                 "Error from remote execution: FailedPrecondition, but no details".to_owned(),
               )).to_boxed();
             }
-            future::err(ExecutionError::MissingDigests(missing_digests)).to_boxed()
+            future::err(OperationError::MissingDigests(missing_digests)).to_boxed()
+          }
+          code => {
+            let message = format!(
+              "Error from remote execution: {:?}: {:?}",
+              code,
+              execute_response.get_status().get_message()
+            );
+            future::err(OperationError::Failed(status_to_execution_error(code, message))).to_boxed()
           }
-          code => future::err(ExecutionError::Fatal(format!(
-            "Error from remote execution: {:?}: {:?}",
-            code,
-            execute_response.get_status().get_message()
-          ))).to_boxed(),
         }
       })
       .to_boxed()
@@ -473,12 +652,14 @@ impl CommandRunner {
       .map(|output_file| {
         let output_file_path_buf = PathBuf::from(output_file.get_path());
         let digest: Result<Digest, String> = output_file.get_digest().into();
-        path_map.insert(output_file_path_buf.clone(), digest?);
+        let digest = digest?;
+        path_map.insert(output_file_path_buf.clone(), digest);
         Ok(PathStat::file(
           output_file_path_buf.clone(),
           File {
-            path: output_file_path_buf,
+            path: output_file_path_buf.into(),
             is_executable: output_file.get_is_executable(),
+            size: digest.1 as u64,
           },
         ))
       })
@@ -501,7 +682,7 @@ impl CommandRunner {
 
     impl fs::StoreFileByDigest<String> for StoreOneOffRemoteDigest {
       fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
-        match self.map_of_paths_to_digests.get(&file.path) {
+        match self.map_of_paths_to_digests.get(file.path.as_path()) {
           Some(digest) => future::ok(digest.clone()),
           None => future::err(format!(
             "Didn't know digest for path in remote execution response: {:?}",
@@ -525,6 +706,11 @@ impl CommandRunner {
   }
 }
 
+///
+/// The name of the Platform property which requests that an action run in a container image.
+///
+pub const CONTAINER_IMAGE_PROPERTY: &str = "container-image";
+
 fn make_execute_request(
   req: &ExecuteProcessRequest,
 ) -> Result<
@@ -555,6 +741,13 @@ fn make_execute_request(
   output_files.sort();
   command.set_output_files(protobuf::repeated::RepeatedField::from_vec(output_files));
 
+  if let Some(ref container_image) = req.container_image {
+    let mut property = bazel_protos::remote_execution::Platform_Property::new();
+    property.set_name(CONTAINER_IMAGE_PROPERTY.to_owned());
+    property.set_value(container_image.clone());
+    command.mut_platform().mut_properties().push(property);
+  }
+
   let mut action = bazel_protos::remote_execution::Action::new();
   action.set_command_digest(digest(&command)?);
   action.set_input_root_digest((&req.input_files).into());
@@ -565,6 +758,117 @@ fn make_execute_request(
   Ok((action, command, execute_request))
 }
 
+///
+/// Computes the digests of the Action and of the Command that the given request is executed as
+/// remotely. The action digest is the key under which a remote execution service caches the result
+/// of the request, so this is useful when diagnosing unexpected cache misses.
+///
+/// Returns a tuple of (action digest, command digest).
+///
+pub fn request_digests(req: &ExecuteProcessRequest) -> Result<(Digest, Digest), String> {
+  let (action, _, execute_request) = make_execute_request(req)?;
+  let action_digest: Result<Digest, String> = execute_request.get_action_digest().into();
+  let command_digest: Result<Digest, String> = action.get_command_digest().into();
+  Ok((action_digest?, command_digest?))
+}
+
+///
+/// Returns the Action and the Command that the given request is executed as remotely: see
+/// `request_digests`.
+///
+pub fn request_protos(
+  req: &ExecuteProcessRequest,
+) -> Result<
+  (
+    bazel_protos::remote_execution::Action,
+    bazel_protos::remote_execution::Command,
+  ),
+  String,
+> {
+  let (action, command, _) = make_execute_request(req)?;
+  Ok((action, command))
+}
+
+///
+/// Sets the given environment variable of the request to its execution seed, which tools that need
+/// a random seed may use in order to behave deterministically.
+///
+/// The seed is derived from the action digest of the rest of the request (see `request_digests`),
+/// so it is stable for as long as the cache key of the request is, and changes when its inputs do.
+/// It is a non-negative 32 bit integer, which most seeding APIs accept.
+///
+pub fn with_execution_seed(
+  mut req: ExecuteProcessRequest,
+  env_var: String,
+) -> Result<ExecuteProcessRequest, String> {
+  req.env.remove(&env_var);
+  let (action_digest, _) = request_digests(&req)?;
+  let seed = (action_digest.0)
+    .0
+    .iter()
+    .take(4)
+    .fold(0u32, |seed, byte| (seed << 8) | u32::from(*byte));
+  req.env.insert(env_var, seed.to_string());
+  Ok(req)
+}
+
+///
+/// Follows a stream of Operations until one of them is done, and returns it.
+///
+/// If the stream ends first, or is dropped with an error which reconnecting might recover from,
+/// the latest Operation (either from the stream, or the one passed in) is returned instead.
+///
+fn follow_operations<S>(
+  stream: S,
+  latest: Option<bazel_protos::operations::Operation>,
+) -> BoxFuture<Option<bazel_protos::operations::Operation>, grpcio::Error>
+where
+  S: Stream<Item = bazel_protos::operations::Operation, Error = grpcio::Error> + Send + 'static,
+{
+  future::loop_fn((stream, latest), |(stream, latest)| {
+    // Once the loop breaks, the stream is dropped, which disconnects it so that the server doesn't
+    // keep the connection alive.
+    stream.into_future().then(move |res| match res {
+      Ok((Some(operation), stream)) => {
+        if operation.get_done() {
+          Ok(future::Loop::Break(Some(operation)))
+        } else {
+          Ok(future::Loop::Continue((stream, Some(operation))))
+        }
+      }
+      Ok((None, _stream)) => Ok(future::Loop::Break(latest)),
+      Err((error, _stream)) => {
+        if latest.is_some() && is_reconnectable(&error) {
+          debug!(
+            "Operation stream dropped with {}; will reconnect",
+            rpcerror_to_execution_error(error)
+          );
+          Ok(future::Loop::Break(latest))
+        } else {
+          Err(error)
+        }
+      }
+    })
+  }).to_boxed()
+}
+
+///
+/// Whether an error which ended a stream of Operations was (probably) caused by the connection
+/// rather than by the execution, such that reconnecting to the Operation might succeed.
+///
+fn is_reconnectable(error: &grpcio::Error) -> bool {
+  match error {
+    &grpcio::Error::RpcFailure(ref status) => match status.status {
+      grpcio::RpcStatusCode::Aborted
+      | grpcio::RpcStatusCode::Cancelled
+      | grpcio::RpcStatusCode::DeadlineExceeded
+      | grpcio::RpcStatusCode::Unavailable => true,
+      _ => false,
+    },
+    _ => false,
+  }
+}
+
 fn format_error(error: &bazel_protos::status::Status) -> String {
   let error_code_enum = bazel_protos::code::Code::from_i32(error.get_code());
   let error_code = match error_code_enum {
@@ -574,14 +878,40 @@ fn format_error(error: &bazel_protos::status::Status) -> String {
   format!("{}: {}", error_code, error.get_message())
 }
 
-fn rpcerror_to_string(error: grpcio::Error) -> String {
+///
+/// Converts an error from an RPC, which is transient if retrying the RPC might succeed.
+///
+fn rpcerror_to_execution_error(error: grpcio::Error) -> ExecutionError {
   match error {
-    grpcio::Error::RpcFailure(status) => format!(
-      "{:?}: {:?}",
-      status.status,
-      status.details.unwrap_or_else(|| "[no message]".to_string())
-    ),
-    err => format!("{:?}", err),
+    grpcio::Error::RpcFailure(status) => {
+      let message = format!(
+        "{:?}: {:?}",
+        status.status,
+        status.details.unwrap_or_else(|| "[no message]".to_string())
+      );
+      status_to_execution_error(status.status, message)
+    }
+    err => ExecutionError::Fatal(format!("{:?}", err)),
+  }
+}
+
+///
+/// Converts a failure with the given status, which is transient if retrying might succeed.
+///
+fn status_to_execution_error(status: grpcio::RpcStatusCode, message: String) -> ExecutionError {
+  if is_transient_status(status) {
+    ExecutionError::Transient(message)
+  } else {
+    ExecutionError::Fatal(message)
+  }
+}
+
+fn is_transient_status(status: grpcio::RpcStatusCode) -> bool {
+  match status {
+    grpcio::RpcStatusCode::Unavailable
+    | grpcio::RpcStatusCode::DeadlineExceeded
+    | grpcio::RpcStatusCode::ResourceExhausted => true,
+    _ => false,
   }
 }
 
@@ -603,7 +933,7 @@ mod tests {
   use bazel_protos;
   use bytes::Bytes;
   use fs;
-  use futures::Future;
+  use futures::{stream, Future};
   use grpcio;
   use hashing::{Digest, Fingerprint};
   use mock;
@@ -613,7 +943,10 @@ mod tests {
   use testutil::{as_bytes, owned_string_vec};
 
   use super::super::CommandRunner as CommandRunnerTrait;
-  use super::{CommandRunner, ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult};
+  use super::{
+    CommandRunner, ExecuteProcessRequest, ExecutionError, FallibleExecuteProcessResult,
+    OperationError, ResultSource,
+  };
   use std::collections::{BTreeMap, BTreeSet};
   use std::iter::{self, FromIterator};
   use std::ops::Sub;
@@ -648,6 +981,11 @@ mod tests {
         .map(|p| PathBuf::from(p))
         .collect(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "some description".to_owned(),
     };
@@ -696,6 +1034,108 @@ mod tests {
     );
   }
 
+  #[test]
+  fn make_execute_request_with_container_image() {
+    let req = |container_image: Option<&str>| ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "yo"]),
+      env: BTreeMap::new(),
+      input_files: fs::EMPTY_DIGEST,
+      output_files: BTreeSet::new(),
+      output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: container_image.map(|image| image.to_owned()),
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+    };
+
+    let (_, command, execute_request) =
+      super::make_execute_request(&req(Some("docker://image@sha256:abc"))).unwrap();
+    let properties = command.get_platform().get_properties();
+    assert_eq!(properties.len(), 1);
+    assert_eq!(properties[0].get_name(), "container-image");
+    assert_eq!(properties[0].get_value(), "docker://image@sha256:abc");
+
+    // The image is part of the cache key.
+    let (_, command_without_image, execute_request_without_image) =
+      super::make_execute_request(&req(None)).unwrap();
+    assert!(!command_without_image.has_platform());
+    assert_ne!(
+      execute_request.get_action_digest(),
+      execute_request_without_image.get_action_digest()
+    );
+  }
+
+  #[test]
+  fn request_digests() {
+    let req = ExecuteProcessRequest {
+      argv: owned_string_vec(&["/bin/echo", "yo"]),
+      env: vec![("SOME".to_owned(), "value".to_owned())]
+        .into_iter()
+        .collect(),
+      input_files: TestDirectory::containing_roland().digest(),
+      output_files: vec!["path/to/file", "other/file"]
+        .into_iter()
+        .map(|p| PathBuf::from(p))
+        .collect(),
+      output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
+      timeout: Duration::from_millis(1000),
+      description: "some description".to_owned(),
+    };
+
+    // The same digests as are sent by make_execute_request.
+    assert_eq!(
+      super::request_digests(&req),
+      Ok((
+        Digest(
+          Fingerprint::from_hex_string(
+            "736c7aca28d63a5b09c4549e0e70bf8cb1645070a038a51abd7283f611cbd6c6",
+          ).unwrap(),
+          140,
+        ),
+        Digest(
+          Fingerprint::from_hex_string(
+            "fb09aa134e9d84aed9c35a9dadb128ab6924285fbcb7e4bec0f65b5c08988a5f",
+          ).unwrap(),
+          56,
+        ),
+      ))
+    );
+  }
+
+  #[test]
+  fn execution_seed() {
+    let seeded = |req: ExecuteProcessRequest| {
+      super::with_execution_seed(req, "SEED".to_owned())
+        .unwrap()
+        .env
+        .get("SEED")
+        .unwrap()
+        .clone()
+    };
+    let seed = seeded(echo_foo_request());
+    assert!(seed.parse::<u32>().is_ok());
+
+    // The seed is stable, including when the request already has one.
+    assert_eq!(seeded(echo_foo_request()), seed);
+    assert_eq!(
+      seeded(super::with_execution_seed(echo_foo_request(), "SEED".to_owned()).unwrap()),
+      seed
+    );
+
+    // But it changes with the cache key of the request.
+    let mut req = echo_foo_request();
+    req.argv.push("bar".to_owned());
+    assert_ne!(seeded(req), seed);
+  }
+
   #[test]
   fn server_rejecting_execute_request_gives_error() {
     let execute_request = echo_foo_request();
@@ -709,6 +1149,11 @@ mod tests {
           input_files: fs::EMPTY_DIGEST,
           output_files: BTreeSet::new(),
           output_directories: BTreeSet::new(),
+          immutable_input_directories: BTreeSet::new(),
+          max_output_bytes: None,
+          max_disk_bytes: None,
+          cache_failures: true,
+          container_image: None,
           timeout: Duration::from_millis(1000),
           description: "wrong command".to_string(),
         }).unwrap()
@@ -720,7 +1165,7 @@ mod tests {
     let error = run_command_remote(mock_server.address(), execute_request).expect_err("Want Err");
     assert_eq!(
       error,
-      "InvalidArgument: \"Did not expect this request\"".to_string()
+      ExecutionError::Fatal("InvalidArgument: \"Did not expect this request\"".to_string())
     );
   }
 
@@ -755,6 +1200,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       }
     );
   }
@@ -778,6 +1226,9 @@ mod tests {
         stderr: testdata_empty.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       })
     );
   }
@@ -801,6 +1252,9 @@ mod tests {
         stderr: testdata.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       })
     );
   }
@@ -837,7 +1291,7 @@ mod tests {
       cas.address(),
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(1),
+      store_timeouts(),
     ).expect("Failed to make store");
 
     let cmd_runner = CommandRunner::new(mock_server.address(), 1, store);
@@ -849,6 +1303,9 @@ mod tests {
         stderr: test_stderr.bytes(),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       })
     );
 
@@ -906,6 +1363,9 @@ mod tests {
         stderr: as_bytes(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       }
     );
   }
@@ -921,6 +1381,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: BTreeSet::new(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: request_timeout,
       description: "echo-a-foo".to_string(),
     };
@@ -940,8 +1405,8 @@ mod tests {
 
     let error_msg = run_command_remote(mock_server.address(), execute_request)
       .expect_err("Timeout did not cause failure.");
-    assert_contains(&error_msg, "Exceeded time out");
-    assert_contains(&error_msg, "echo-a-foo");
+    assert_contains(error_msg.message(), "Exceeded time out");
+    assert_contains(error_msg.message(), "echo-a-foo");
   }
 
   #[test]
@@ -1004,7 +1469,10 @@ mod tests {
 
     let result = run_command_remote(mock_server.address(), execute_request).expect_err("Want Err");
 
-    assert_eq!(result, "INTERNAL: Something went wrong");
+    assert_eq!(
+      result,
+      ExecutionError::Fatal("INTERNAL: Something went wrong".to_owned())
+    );
   }
 
   #[test]
@@ -1034,7 +1502,10 @@ mod tests {
 
     let result = run_command_remote(mock_server.address(), execute_request).expect_err("Want Err");
 
-    assert_eq!(result, "INTERNAL: Something went wrong");
+    assert_eq!(
+      result,
+      ExecutionError::Fatal("INTERNAL: Something went wrong".to_owned())
+    );
   }
 
   #[test]
@@ -1058,7 +1529,10 @@ mod tests {
 
     let result = run_command_remote(mock_server.address(), execute_request).expect_err("Want Err");
 
-    assert_eq!(result, "Operation finished but no response supplied");
+    assert_eq!(
+      result,
+      ExecutionError::Fatal("Operation finished but no response supplied".to_owned())
+    );
   }
 
   #[test]
@@ -1082,7 +1556,10 @@ mod tests {
 
     let result = run_command_remote(mock_server.address(), execute_request).expect_err("Want Err");
 
-    assert_eq!(result, "Operation finished but no response supplied");
+    assert_eq!(
+      result,
+      ExecutionError::Fatal("Operation finished but no response supplied".to_owned())
+    );
   }
 
   #[test]
@@ -1120,7 +1597,7 @@ mod tests {
       cas.address(),
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(1),
+      store_timeouts(),
     ).expect("Failed to make store");
     store
       .store_file_bytes(roland.bytes(), false)
@@ -1137,6 +1614,9 @@ mod tests {
         stderr: Bytes::from(""),
         exit_code: 0,
         output_directory: fs::EMPTY_DIGEST,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: ResultSource::ExecutedRemotely,
       })
     );
     {
@@ -1174,14 +1654,14 @@ mod tests {
       cas.address(),
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(1),
+      store_timeouts(),
     ).expect("Failed to make store");
 
     let error = CommandRunner::new(mock_server.address(), 1, store)
       .run(cat_roland_request())
       .wait()
       .expect_err("Want error");
-    assert_contains(&error, &format!("{}", missing_digest.0));
+    assert_contains(error.message(), &format!("{}", missing_digest.0));
   }
 
   #[test]
@@ -1213,6 +1693,9 @@ mod tests {
       stderr: Bytes::from("simba"),
       exit_code: 17,
       output_directory: TestDirectory::nested().digest(),
+      stdout_overflow: None,
+      stderr_overflow: None,
+      source: ResultSource::ExecutedRemotely,
     };
 
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -1250,7 +1733,7 @@ mod tests {
 
     assert_eq!(
       extract_execute_response(operation),
-      Err(ExecutionError::NotFinished(operation_name))
+      Err(OperationError::NotFinished(operation_name))
     );
   }
 
@@ -1270,7 +1753,7 @@ mod tests {
 
     assert_eq!(
       extract_execute_response(operation),
-      Err(ExecutionError::MissingDigests(missing_files))
+      Err(OperationError::MissingDigests(missing_files))
     );
   }
 
@@ -1289,7 +1772,9 @@ mod tests {
     let (operation, _duration) = make_precondition_failure_operation(missing);
 
     match extract_execute_response(operation) {
-      Err(ExecutionError::Fatal(err)) => assert_contains(&err, "monkeys"),
+      Err(OperationError::Failed(ExecutionError::Fatal(err))) => {
+        assert_contains(&err, "monkeys")
+      }
       other => assert!(false, "Want fatal error, got {:?}", other),
     };
   }
@@ -1305,7 +1790,9 @@ mod tests {
     let (operation, _duration) = make_precondition_failure_operation(missing);
 
     match extract_execute_response(operation) {
-      Err(ExecutionError::Fatal(err)) => assert_contains(&err, "OUT_OF_CAPACITY"),
+      Err(OperationError::Failed(ExecutionError::Fatal(err))) => {
+        assert_contains(&err, "OUT_OF_CAPACITY")
+      }
       other => assert!(false, "Want fatal error, got {:?}", other),
     };
   }
@@ -1317,7 +1804,9 @@ mod tests {
     let (operation, _duration) = make_precondition_failure_operation(missing);
 
     match extract_execute_response(operation) {
-      Err(ExecutionError::Fatal(err)) => assert_contains(&err.to_lowercase(), "precondition"),
+      Err(OperationError::Failed(ExecutionError::Fatal(err))) => {
+        assert_contains(&err.to_lowercase(), "precondition")
+      }
       other => assert!(false, "Want fatal error, got {:?}", other),
     };
   }
@@ -1338,11 +1827,36 @@ mod tests {
     }));
 
     match extract_execute_response(operation) {
-      Err(ExecutionError::Fatal(err)) => assert_contains(&err, "PermissionDenied"),
+      Err(OperationError::Failed(ExecutionError::Fatal(err))) => {
+        assert_contains(&err, "PermissionDenied")
+      }
       other => assert!(false, "Want fatal error, got {:?}", other),
     };
   }
 
+  #[test]
+  fn extract_execute_response_unavailable_is_transient() {
+    let mut operation = bazel_protos::operations::Operation::new();
+    operation.set_name("cat".to_owned());
+    operation.set_done(true);
+    operation.set_response(make_any_proto(&{
+      let mut response = bazel_protos::remote_execution::ExecuteResponse::new();
+      response.set_status({
+        let mut status = bazel_protos::status::Status::new();
+        status.set_code(grpcio::RpcStatusCode::Unavailable as i32);
+        status
+      });
+      response
+    }));
+
+    match extract_execute_response(operation) {
+      Err(OperationError::Failed(ExecutionError::Transient(err))) => {
+        assert_contains(&err, "Unavailable")
+      }
+      other => assert!(false, "Want transient error, got {:?}", other),
+    };
+  }
+
   #[test]
   fn digest_command() {
     let mut command = bazel_protos::remote_execution::Command::new();
@@ -1438,6 +1952,119 @@ mod tests {
     }
   }
 
+  #[test]
+  fn reconnects_with_wait_execution() {
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![
+          make_incomplete_operation(&op_name),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    run_command_remote(mock_server.address(), execute_request).unwrap();
+
+    assert_eq!(
+      received_message_names(&mock_server),
+      vec!["ExecuteRequest", "WaitExecutionRequest"]
+    );
+  }
+
+  #[test]
+  fn reexecutes_if_operation_is_unknown() {
+    let execute_request = echo_foo_request();
+    let mock_server = {
+      let op_name = "gimme-foo".to_string();
+      mock::execution_server::TestServer::new(mock::execution_server::MockExecution::new(
+        op_name.clone(),
+        super::make_execute_request(&execute_request).unwrap().2,
+        vec![
+          make_incomplete_operation("forgotten-foo"),
+          make_successful_operation(
+            &op_name,
+            StdoutType::Raw("foo".to_owned()),
+            StderrType::Raw("".to_owned()),
+            0,
+          ),
+        ],
+      ))
+    };
+    let result = run_command_remote(mock_server.address(), execute_request).unwrap();
+
+    assert_eq!(result.stdout, as_bytes("foo"));
+    assert_eq!(
+      received_message_names(&mock_server),
+      vec!["ExecuteRequest", "WaitExecutionRequest", "ExecuteRequest"]
+    );
+  }
+
+  #[test]
+  fn follow_operations_until_done() {
+    let (incomplete, _) = make_incomplete_operation("gimme-foo");
+    let (done, _) = make_successful_operation(
+      "gimme-foo",
+      StdoutType::Raw("foo".to_owned()),
+      StderrType::Raw("".to_owned()),
+      0,
+    );
+    // Operations after the done one are not consumed.
+    let stream = stream::iter_ok(vec![incomplete.clone(), done.clone(), incomplete]);
+    assert_eq!(
+      super::follow_operations(stream, None).wait().unwrap(),
+      Some(done)
+    );
+  }
+
+  #[test]
+  fn follow_operations_returns_latest_when_dropped() {
+    let (incomplete, _) = make_incomplete_operation("gimme-foo");
+    let stream = stream::iter_result(vec![
+      Ok(incomplete.clone()),
+      Err(rpc_error(grpcio::RpcStatusCode::Unavailable)),
+    ]);
+    assert_eq!(
+      super::follow_operations(stream, None).wait().unwrap(),
+      Some(incomplete.clone())
+    );
+
+    // Reconnecting to an Operation may fail before the stream sends anything.
+    let stream = stream::iter_result(vec![Err(rpc_error(grpcio::RpcStatusCode::Unavailable))]);
+    assert_eq!(
+      super::follow_operations(stream, Some(incomplete.clone()))
+        .wait()
+        .unwrap(),
+      Some(incomplete)
+    );
+  }
+
+  #[test]
+  fn follow_operations_fails_if_not_reconnectable() {
+    // No Operation to reconnect to.
+    let stream = stream::iter_result(vec![Err(rpc_error(grpcio::RpcStatusCode::Unavailable))]);
+    super::follow_operations(stream, None)
+      .wait()
+      .expect_err("Want Err");
+
+    // An error which reconnecting would not fix.
+    let (incomplete, _) = make_incomplete_operation("gimme-foo");
+    let stream = stream::iter_result(vec![
+      Ok(incomplete),
+      Err(rpc_error(grpcio::RpcStatusCode::PermissionDenied)),
+    ]);
+    super::follow_operations(stream, None)
+      .wait()
+      .expect_err("Want Err");
+  }
+
   #[test]
   fn extract_output_files_from_response_one_file() {
     let mut output_file = bazel_protos::remote_execution::OutputFile::new();
@@ -1526,6 +2153,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: BTreeSet::new(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(5000),
       description: "echo a foo".to_string(),
     }
@@ -1625,15 +2257,37 @@ mod tests {
     (operation, None)
   }
 
+  fn rpc_error(code: grpcio::RpcStatusCode) -> grpcio::Error {
+    grpcio::Error::RpcFailure(grpcio::RpcStatus::new(code, None))
+  }
+
+  fn received_message_names(mock_server: &mock::execution_server::TestServer) -> Vec<String> {
+    mock_server
+      .mock_responder
+      .received_messages
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|&(ref name, _, _)| name.clone())
+      .collect()
+  }
+
   fn run_command_remote(
     address: String,
     request: ExecuteProcessRequest,
-  ) -> Result<FallibleExecuteProcessResult, String> {
+  ) -> Result<FallibleExecuteProcessResult, ExecutionError> {
     let cas = mock::StubCAS::with_roland_and_directory(1024);
     let command_runner = create_command_runner(address, &cas);
     command_runner.run(request).wait()
   }
 
+  fn store_timeouts() -> fs::RemoteTimeouts {
+    fs::RemoteTimeouts {
+      metadata: Duration::from_secs(1),
+      transfer: Duration::from_secs(1),
+    }
+  }
+
   fn create_command_runner(address: String, cas: &mock::StubCAS) -> CommandRunner {
     let store_dir = TempDir::new().unwrap();
     let store = fs::Store::with_remote(
@@ -1642,7 +2296,7 @@ mod tests {
       cas.address(),
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(1),
+      store_timeouts(),
     ).expect("Failed to make store");
 
     CommandRunner::new(address, 1, store)
@@ -1650,7 +2304,7 @@ mod tests {
 
   fn extract_execute_response(
     operation: bazel_protos::operations::Operation,
-  ) -> Result<FallibleExecuteProcessResult, ExecutionError> {
+  ) -> Result<FallibleExecuteProcessResult, OperationError> {
     let cas = mock::StubCAS::with_roland_and_directory(1024);
     let command_runner = create_command_runner("".to_owned(), &cas);
     command_runner.extract_execute_response(operation).wait()
@@ -1703,6 +2357,11 @@ mod tests {
       input_files: TestDirectory::containing_roland().digest(),
       output_files: BTreeSet::new(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "cat a roland".to_string(),
     }
@@ -1715,6 +2374,11 @@ mod tests {
       input_files: fs::EMPTY_DIGEST,
       output_files: BTreeSet::new(),
       output_directories: BTreeSet::new(),
+      immutable_input_directories: BTreeSet::new(),
+      max_output_bytes: None,
+      max_disk_bytes: None,
+      cache_failures: true,
+      container_image: None,
       timeout: Duration::from_millis(1000),
       description: "unleash a roaring meow".to_string(),
     }
@@ -0,0 +1,640 @@
+extern crate tempfile;
+
+use std::cmp;
+use std::collections::{BTreeSet, HashMap};
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bazel_protos;
+use boxfuture::{BoxFuture, Boxable};
+use fs;
+use futures::{future, Future};
+use hashing::Digest;
+use protobuf::RepeatedField;
+
+use self::tempfile::TempDir;
+use immutable_roots::{self, ImmutableRoots};
+use temp_root::TempRoot;
+
+// The number of processes which must have used a subtree before copies of it are pooled.
+const HOT_SUBTREE_USES: usize = 2;
+
+// The maximum number of distinct subtrees which are pooled at once: when another subtree becomes
+// hot, the pooled copies of the least recently used subtree are deleted.
+const MAX_POOLED_SUBTREES: usize = 16;
+
+// The maximum number of distinct subtrees whose uses are counted before the counts are reset.
+const MAX_COUNTED_SUBTREES: usize = 4096;
+
+// The default maximum total size of the files in the pooled copies of subtrees (including copies
+// which are being created).
+const MAX_POOLED_BYTES: u64 = 1024 * 1024 * 1024;
+
+///
+/// A pool of sandboxes for local process execution, which amortizes the cost of setting up a
+/// sandbox across many processes.
+///
+/// The pool holds pre-created empty directories, and pre-materialized copies of "hot" subtrees:
+/// directories at the root of the inputs of a process (such as toolchains) which were also used by
+/// earlier processes. A claimed sandbox has pooled copies of its hot subtrees moved into place, so
+/// that only the remainder of its inputs needs to be materialized, and the pool is topped up in the
+/// background after each claim. The pooled copies are limited both in number (per subtree) and in
+/// their total size.
+///
+/// Processes may modify their sandboxes, so pooled directories are never shared: each is moved
+/// into exactly one sandbox, and deleted along with it. The exception is the inputs which a process
+/// declares to be immutable, which are shared via `ImmutableRoots`.
+///
+#[derive(Clone)]
+pub struct SandboxPool {
+  store: fs::Store,
+  fs_pool: Arc<fs::ResettablePool>,
+  temp_root: TempRoot,
+  size: usize,
+  max_bytes: u64,
+  inner: Arc<Mutex<Inner>>,
+  immutable_roots: ImmutableRoots,
+}
+
+impl SandboxPool {
+  ///
+  /// Creates a pool in `temp_root` which holds up to `size` empty directories, and up to `size`
+  /// copies of each hot subtree. A pool of size zero creates and materializes each sandbox from
+  /// scratch.
+  ///
+  /// Shared copies of immutable inputs are kept in `work_dir`, because they are reused across runs.
+  ///
+  pub fn new(
+    store: fs::Store,
+    fs_pool: Arc<fs::ResettablePool>,
+    work_dir: PathBuf,
+    temp_root: TempRoot,
+    size: usize,
+  ) -> SandboxPool {
+    SandboxPool {
+      immutable_roots: ImmutableRoots::new(store.clone(), fs_pool.clone(), &work_dir),
+      store,
+      fs_pool,
+      temp_root,
+      size,
+      max_bytes: MAX_POOLED_BYTES,
+      inner: Arc::new(Mutex::new(Inner::default())),
+    }
+  }
+
+  ///
+  /// Limits the total size of the files in the pooled copies of subtrees. A subtree which is
+  /// larger than the limit is never pooled.
+  ///
+  pub fn with_max_bytes(mut self, max_bytes: u64) -> SandboxPool {
+    self.max_bytes = max_bytes;
+    self
+  }
+
+  ///
+  /// The root in which sandboxes are created.
+  ///
+  pub fn temp_root(&self) -> &TempRoot {
+    &self.temp_root
+  }
+
+  ///
+  /// The directory which holds the shared copies of immutable inputs (see `ImmutableRoots`).
+  ///
+  pub fn immutable_roots_dir(&self) -> &Path {
+    self.immutable_roots.dir()
+  }
+
+  ///
+  /// Claims a sandbox containing the given input files, in which the given immutable directories
+  /// within the input files are symlinks to shared read-only copies (see `ImmutableRoots`).
+  ///
+  pub fn claim_with_immutable_inputs(
+    &self,
+    input_files: Digest,
+    immutable_directories: BTreeSet<PathBuf>,
+  ) -> BoxFuture<TempDir, String> {
+    if immutable_directories.is_empty() {
+      return self.claim(input_files);
+    }
+    let pool = self.clone();
+    immutable_roots::split(self.store.clone(), input_files, immutable_directories)
+      .and_then(move |(remainder, immutable)| {
+        pool.claim(remainder).and_then(move |sandbox| {
+          pool
+            .immutable_roots
+            .link_into(sandbox.path().to_owned(), immutable)
+            .map(move |()| sandbox)
+        })
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Claims a sandbox containing the given input files.
+  ///
+  pub fn claim(&self, input_files: Digest) -> BoxFuture<TempDir, String> {
+    let sandbox = try_future!(self.take_empty());
+    let sandbox_path = sandbox.path().to_owned();
+    if self.size == 0 {
+      return self
+        .store
+        .materialize_directory(sandbox_path, input_files)
+        .map(move |()| sandbox)
+        .to_boxed();
+    }
+
+    let pool = self.clone();
+    self
+      .store
+      .load_directory(input_files)
+      .and_then(move |directory_opt| {
+        directory_opt.ok_or_else(|| format!("Directory with digest {:?} not found", input_files))
+      })
+      .and_then(move |directory| {
+        let (remainder, hot_subtrees) =
+          try_future!(pool.place_subtrees(directory, &sandbox_path));
+        pool.fs_pool.spawn_detached(pool.top_up(hot_subtrees));
+        let store = pool.store.clone();
+        pool
+          .store
+          .record_directory(&remainder, false)
+          .and_then(move |remainder_digest| {
+            store
+              .materialize_directory(sandbox_path, remainder_digest)
+              .map(move |()| sandbox)
+          })
+          .to_boxed()
+      })
+      .to_boxed()
+  }
+
+  fn take_empty(&self) -> Result<TempDir, String> {
+    let pooled = self.inner.lock().unwrap().empty.dirs.pop();
+    match pooled {
+      Some(dir) => Ok(dir),
+      None => create_dir(&self.temp_root),
+    }
+  }
+
+  ///
+  /// Moves pooled copies of the hot subtrees of the given Directory into the sandbox. Returns the
+  /// remainder of the Directory, which must still be materialized, and the hot subtrees of the
+  /// Directory, which should be topped up.
+  ///
+  fn place_subtrees(
+    &self,
+    mut directory: bazel_protos::remote_execution::Directory,
+    sandbox: &Path,
+  ) -> Result<(bazel_protos::remote_execution::Directory, Vec<Digest>), String> {
+    let mut inner = self.inner.lock().unwrap();
+    // Evicted copies are deleted after the lock is released.
+    let mut evicted = Vec::new();
+    let mut remainder = Vec::new();
+    let mut hot_subtrees = Vec::new();
+    for node in directory.take_directories().into_iter() {
+      let digest: Result<Digest, String> = node.get_digest().into();
+      let digest = digest?;
+      if !inner.use_subtree(digest, &mut evicted) {
+        remainder.push(node);
+        continue;
+      }
+      hot_subtrees.push(digest);
+      match inner.pooled(Some(digest)).and_then(|pooled| pooled.dirs.pop()) {
+        Some(copy) => place(copy, &sandbox.join(fs::decode_name(node.get_name())))?,
+        None => remainder.push(node),
+      }
+    }
+    drop(inner);
+    drop(evicted);
+    directory.set_directories(RepeatedField::from_vec(remainder));
+    Ok((directory, hot_subtrees))
+  }
+
+  ///
+  /// Tops up the pooled empty directories, and the pooled copies of the given subtrees (as far as
+  /// `max_bytes` allows). Failing to top up the pool is logged, rather than failing the claim that
+  /// triggered it.
+  ///
+  fn top_up(&self, hot_subtrees: Vec<Digest>) -> BoxFuture<(), ()> {
+    let pool = self.clone();
+    self
+      .measure(hot_subtrees.clone())
+      .then(move |measured| {
+        if let Err(e) = measured {
+          warn!("Failed to top up the local sandbox pool: {}", e);
+        }
+        let reservations = pool.reserve(hot_subtrees);
+        let creations = reservations
+          .into_iter()
+          .map(|reservation| pool.create_pooled(reservation))
+          .collect::<Vec<_>>();
+        future::join_all(creations).map(|_| ())
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Records the size of a copy of each of the given subtrees which has not been measured yet.
+  ///
+  fn measure(&self, subtrees: Vec<Digest>) -> BoxFuture<(), String> {
+    let unmeasured = {
+      let mut inner = self.inner.lock().unwrap();
+      subtrees
+        .into_iter()
+        .filter(|subtree| {
+          inner
+            .pooled(Some(*subtree))
+            .map(|pooled| pooled.bytes_each.is_none())
+            .unwrap_or(false)
+        })
+        .collect::<Vec<_>>()
+    };
+    let measurements = unmeasured
+      .into_iter()
+      .map(|subtree| {
+        let inner = self.inner.clone();
+        self
+          .store
+          .file_digests_for_directory(subtree)
+          .map(move |file_digests| {
+            let bytes: u64 = file_digests
+              .iter()
+              .map(|&(_, digest)| digest.1 as u64)
+              .sum();
+            if let Some(pooled) = inner.lock().unwrap().pooled(Some(subtree)) {
+              pooled.bytes_each = Some(bytes);
+            }
+          })
+      })
+      .collect::<Vec<_>>();
+    future::join_all(measurements).map(|_| ()).to_boxed()
+  }
+
+  ///
+  /// Reserves the directories which are needed to top up the pooled empty directories, and the
+  /// pooled copies of the given (measured) subtrees.
+  ///
+  fn reserve(&self, hot_subtrees: Vec<Digest>) -> Vec<Reservation> {
+    let mut inner = self.inner.lock().unwrap();
+    let mut pooled_bytes = inner.pooled_bytes();
+    let mut reservations = Vec::new();
+    for subtree in iter::once(None).chain(hot_subtrees.into_iter().map(Some)) {
+      // A subtree might have been evicted since it was used.
+      if let Some(pooled) = inner.pooled(subtree) {
+        let bytes_each = match (subtree, pooled.bytes_each) {
+          (None, _) => 0,
+          (Some(_), Some(bytes_each)) => bytes_each,
+          // The subtree could not be measured.
+          (Some(_), None) => continue,
+        };
+        let mut needed = self
+          .size
+          .saturating_sub(pooled.dirs.len() + pooled.pending);
+        if bytes_each > 0 {
+          let affordable = self.max_bytes.saturating_sub(pooled_bytes) / bytes_each;
+          needed = cmp::min(needed as u64, affordable) as usize;
+        }
+        pooled.pending += needed;
+        pooled_bytes += needed as u64 * bytes_each;
+        for _ in 0..needed {
+          reservations.push(Reservation {
+            inner: self.inner.clone(),
+            subtree,
+            generation: pooled.generation,
+          });
+        }
+      }
+    }
+    reservations
+  }
+
+  fn create_pooled(&self, reservation: Reservation) -> BoxFuture<(), ()> {
+    let temp_root = self.temp_root.clone();
+    let store = self.store.clone();
+    let subtree = reservation.subtree;
+    self
+      .fs_pool
+      .spawn_fn(move || create_dir(&temp_root))
+      .and_then(move |dir| match subtree {
+        Some(digest) => store
+          .materialize_directory(dir.path().to_owned(), digest)
+          .map(move |()| dir)
+          .to_boxed(),
+        None => future::ok(dir).to_boxed(),
+      })
+      .then(move |result| {
+        match result {
+          Ok(dir) => reservation.fulfil(dir),
+          Err(e) => warn!("Failed to top up the local sandbox pool: {}", e),
+        }
+        Ok(())
+      })
+      .to_boxed()
+  }
+}
+
+#[derive(Default)]
+struct Inner {
+  empty: Pooled,
+  subtrees: HashMap<Digest, Pooled>,
+  uses: HashMap<Digest, usize>,
+  clock: usize,
+}
+
+impl Inner {
+  fn pooled(&mut self, subtree: Option<Digest>) -> Option<&mut Pooled> {
+    match subtree {
+      Some(digest) => self.subtrees.get_mut(&digest),
+      None => Some(&mut self.empty),
+    }
+  }
+
+  ///
+  /// The total size of the pooled copies of subtrees, including those which are being created.
+  ///
+  fn pooled_bytes(&self) -> u64 {
+    self
+      .subtrees
+      .values()
+      .map(|pooled| (pooled.dirs.len() + pooled.pending) as u64 * pooled.bytes_each.unwrap_or(0))
+      .sum()
+  }
+
+  ///
+  /// Records a use of the given subtree, and returns true if it is hot.
+  ///
+  fn use_subtree(&mut self, digest: Digest, evicted: &mut Vec<Pooled>) -> bool {
+    self.clock += 1;
+    if let Some(pooled) = self.subtrees.get_mut(&digest) {
+      pooled.last_used = self.clock;
+      return true;
+    }
+
+    if self.uses.len() >= MAX_COUNTED_SUBTREES {
+      self.uses.clear();
+    }
+    let uses = {
+      let uses = self.uses.entry(digest).or_insert(0);
+      *uses += 1;
+      *uses
+    };
+    if uses < HOT_SUBTREE_USES {
+      return false;
+    }
+
+    self.uses.remove(&digest);
+    if self.subtrees.len() >= MAX_POOLED_SUBTREES {
+      let least_recently_used = self
+        .subtrees
+        .iter()
+        .min_by_key(|&(_, pooled)| pooled.last_used)
+        .map(|(digest, _)| *digest);
+      if let Some(pooled) = least_recently_used.and_then(|d| self.subtrees.remove(&d)) {
+        evicted.push(pooled);
+      }
+    }
+    self.subtrees.insert(
+      digest,
+      Pooled {
+        dirs: Vec::new(),
+        pending: 0,
+        bytes_each: None,
+        last_used: self.clock,
+        generation: self.clock,
+      },
+    );
+    true
+  }
+}
+
+#[derive(Default)]
+struct Pooled {
+  dirs: Vec<TempDir>,
+  // The number of directories which are being created to top up `dirs`.
+  pending: usize,
+  // The total size of the files in each directory, once it has been measured.
+  bytes_each: Option<u64>,
+  last_used: usize,
+  // Distinguishes this Pooled from any earlier one for the same subtree, which was evicted.
+  generation: usize,
+}
+
+///
+/// A directory which is being created to top up a Pooled. It is counted as pending until it is
+/// either added to the pool, or abandoned (because creating it failed, or because the claim which
+/// was topping up the pool was cancelled).
+///
+struct Reservation {
+  inner: Arc<Mutex<Inner>>,
+  subtree: Option<Digest>,
+  generation: usize,
+}
+
+impl Reservation {
+  ///
+  /// The Pooled which this Reservation was counted by, unless its subtree has since been evicted.
+  ///
+  fn pooled<'a>(&self, inner: &'a mut Inner) -> Option<&'a mut Pooled> {
+    let generation = self.generation;
+    inner
+      .pooled(self.subtree)
+      .filter(|pooled| pooled.generation == generation)
+  }
+
+  fn fulfil(self, dir: TempDir) {
+    let mut inner = self.inner.lock().unwrap();
+    // If the subtree was evicted while its copy was being created, the copy is deleted.
+    if let Some(pooled) = self.pooled(&mut inner) {
+      pooled.dirs.push(dir);
+    }
+  }
+}
+
+impl Drop for Reservation {
+  fn drop(&mut self) {
+    let mut inner = self.inner.lock().unwrap();
+    if let Some(pooled) = self.pooled(&mut inner) {
+      pooled.pending -= 1;
+    }
+  }
+}
+
+fn create_dir(temp_root: &TempRoot) -> Result<TempDir, String> {
+  temp_root
+    .tempdir("process-execution")
+    .map_err(|err| format!("Error making tempdir for local process execution: {}", err))
+}
+
+///
+/// Moves a pooled copy of a subtree to its destination in a sandbox.
+///
+fn place(copy: TempDir, destination: &Path) -> Result<(), String> {
+  let source = copy.into_path();
+  ::std::fs::rename(&source, destination).map_err(|e| {
+    let _ = ::std::fs::remove_dir_all(&source);
+    format!(
+      "Error moving pooled directory {:?} to {:?}: {:?}",
+      source, destination, e
+    )
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate tempfile;
+  extern crate testutil;
+
+  use super::SandboxPool;
+  use temp_root::TempRoot;
+  use fs;
+  use futures::Future;
+  use hashing::Digest;
+  use std::collections::BTreeSet;
+  use std::os::unix::fs::PermissionsExt;
+  use std::path::{Path, PathBuf};
+  use std::sync::Arc;
+  use tempfile::TempDir;
+  use testing::wait_until;
+  use testutil::data::{TestData, TestDirectory};
+
+  #[test]
+  fn claims_inputs() {
+    let (pool, _dirs) = new_pool(0);
+    let sandbox = pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    assert_roland(&sandbox.path().join("cats").join("roland"));
+  }
+
+  #[test]
+  fn pools_empty_directories() {
+    let (pool, _dirs) = new_pool(2);
+    let first = pool.claim(TestDirectory::empty().digest()).wait().unwrap();
+    wait_until(|| pool.inner.lock().unwrap().empty.dirs.len() == 2);
+    let second = pool.claim(TestDirectory::empty().digest()).wait().unwrap();
+    assert_ne!(first.path(), second.path());
+    // The claimed directory is replaced in the background.
+    wait_until(|| pool.inner.lock().unwrap().empty.dirs.len() == 2);
+  }
+
+  #[test]
+  fn pools_hot_subtrees() {
+    let (pool, _dirs) = new_pool(2);
+    let subtree = TestDirectory::containing_roland().digest();
+
+    // A subtree becomes hot once it has been used twice.
+    pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    assert!(!pool.inner.lock().unwrap().subtrees.contains_key(&subtree));
+    pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    wait_until(|| pooled_copies(&pool, subtree) == 2);
+
+    // A pooled copy is moved into the next sandbox which uses the subtree, and then replaced.
+    let pooled_copy = pool.inner.lock().unwrap().subtrees[&subtree].dirs[1]
+      .path()
+      .to_owned();
+    let sandbox = pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    assert!(!pooled_copy.exists());
+    assert_roland(&sandbox.path().join("cats").join("roland"));
+    wait_until(|| pooled_copies(&pool, subtree) == 2);
+  }
+
+  #[test]
+  fn pooled_copies_are_limited_by_size() {
+    let (pool, _dirs) = new_pool(4);
+    let roland_bytes = TestData::roland().bytes().len() as u64;
+    let pool = pool.with_max_bytes(roland_bytes * 3 / 2);
+    let subtree = TestDirectory::containing_roland().digest();
+
+    // Only one copy of the subtree fits.
+    for _ in 0..2 {
+      pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    }
+    wait_until(|| {
+      let inner = pool.inner.lock().unwrap();
+      inner.subtrees[&subtree].pending == 0 && inner.empty.dirs.len() == 4
+    });
+    assert_eq!(pooled_copies(&pool, subtree), 1);
+    assert_eq!(pool.inner.lock().unwrap().pooled_bytes(), roland_bytes);
+
+    // And a subtree which does not fit at all is not pooled.
+    let pool = pool.with_max_bytes(roland_bytes - 1);
+    pool.claim(TestDirectory::nested().digest()).wait().unwrap();
+    wait_until(|| pool.inner.lock().unwrap().empty.dirs.len() == 4);
+    assert_eq!(pooled_copies(&pool, subtree), 0);
+  }
+
+  #[test]
+  fn claimed_sandboxes_are_independent() {
+    let (pool, _dirs) = new_pool(2);
+    let sandboxes = (0..4)
+      .map(|_| pool.claim(TestDirectory::nested().digest()).wait().unwrap())
+      .collect::<Vec<_>>();
+    ::std::fs::remove_file(sandboxes[2].path().join("cats").join("roland")).unwrap();
+    for sandbox in &[&sandboxes[0], &sandboxes[1], &sandboxes[3]] {
+      assert_roland(&sandbox.path().join("cats").join("roland"));
+    }
+  }
+
+  #[test]
+  fn links_immutable_inputs() {
+    let (pool, _dirs) = new_pool(0);
+    let immutable: BTreeSet<PathBuf> = vec![PathBuf::from("cats")].into_iter().collect();
+    let sandboxes = (0..2)
+      .map(|_| {
+        pool
+          .claim_with_immutable_inputs(TestDirectory::nested().digest(), immutable.clone())
+          .wait()
+          .unwrap()
+      })
+      .collect::<Vec<_>>();
+    let roots = sandboxes
+      .iter()
+      .map(|sandbox| ::std::fs::read_link(sandbox.path().join("cats")).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(roots[0], roots[1]);
+    assert_roland(&sandboxes[0].path().join("cats").join("roland"));
+
+    // Allow the shared root to be deleted along with the work dir.
+    ::std::fs::set_permissions(&roots[0], ::std::fs::Permissions::from_mode(0o755)).unwrap();
+  }
+
+  fn pooled_copies(pool: &SandboxPool, subtree: Digest) -> usize {
+    pool
+      .inner
+      .lock()
+      .unwrap()
+      .subtrees
+      .get(&subtree)
+      .map(|pooled| pooled.dirs.len())
+      .unwrap_or(0)
+  }
+
+  fn assert_roland(path: &Path) {
+    assert_eq!(
+      ::std::fs::read_to_string(path).unwrap(),
+      TestData::roland().string()
+    );
+  }
+
+  fn new_pool(size: usize) -> (SandboxPool, (TempDir, TempDir)) {
+    let store_dir = TempDir::new().unwrap();
+    let work_dir = TempDir::new().unwrap();
+    let fs_pool = Arc::new(fs::ResettablePool::new("test-pool-".to_owned()));
+    let store = fs::Store::local_only(store_dir.path(), fs_pool.clone()).unwrap();
+    store
+      .store_file_bytes(TestData::roland().bytes(), false)
+      .wait()
+      .unwrap();
+    store
+      .record_directory(&TestDirectory::containing_roland().directory(), false)
+      .wait()
+      .unwrap();
+    store
+      .record_directory(&TestDirectory::nested().directory(), false)
+      .wait()
+      .unwrap();
+    let temp_root = TempRoot::create(work_dir.path()).unwrap();
+    let pool = SandboxPool::new(store, fs_pool, work_dir.path().to_owned(), temp_root, size);
+    (pool, (store_dir, work_dir))
+  }
+}
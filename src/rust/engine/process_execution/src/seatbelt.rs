@@ -0,0 +1,166 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// The location of the macOS tool which runs a process under a sandbox profile.
+const SANDBOX_EXEC: &str = "/usr/bin/sandbox-exec";
+
+// Directories which contain the system's tools and libraries, and which processes may read (but not
+// write) under the profile.
+const SYSTEM_READABLE_DIRS: &[&str] = &[
+  "/Applications",
+  "/Library",
+  "/System",
+  "/bin",
+  "/dev",
+  "/opt",
+  "/private/etc",
+  "/private/var/db",
+  "/sbin",
+  "/usr",
+];
+
+// Devices which processes may write to under the profile.
+const WRITABLE_DEVICES: &[&str] = &["/dev/null", "/dev/tty", "/dev/zero"];
+
+///
+/// A macOS sandbox ("seatbelt") profile for local process execution, which denies processes access
+/// to the filesystem outside of their sandbox, the read-only directories they are given, the system,
+/// and a declared set of writable caches.
+///
+/// This gives processes on macOS hermeticity guarantees similar to those of running them in a
+/// mount namespace on Linux: a process which reads an undeclared input (or writes outside of its
+/// sandbox) fails, rather than producing a result which depends on the state of the machine.
+///
+#[derive(Clone, Debug)]
+pub struct SeatbeltProfile {
+  // Directories which processes may read, such as the shared copies of immutable inputs.
+  readable_dirs: Vec<PathBuf>,
+  // Directories which processes may read and write, such as caches shared between processes.
+  writable_dirs: Vec<PathBuf>,
+}
+
+impl SeatbeltProfile {
+  pub fn new(readable_dirs: Vec<PathBuf>, writable_dirs: Vec<PathBuf>) -> SeatbeltProfile {
+    SeatbeltProfile {
+      readable_dirs,
+      writable_dirs,
+    }
+  }
+
+  ///
+  /// Wraps the given argv so that it runs under this profile, with access to the given sandbox.
+  ///
+  pub fn wrap(&self, sandbox: &Path, argv: &[String]) -> Result<Vec<String>, String> {
+    let mut wrapped = vec![
+      SANDBOX_EXEC.to_owned(),
+      "-p".to_owned(),
+      self.render(sandbox)?,
+    ];
+    wrapped.extend(argv.iter().cloned());
+    Ok(wrapped)
+  }
+
+  ///
+  /// Renders the profile for a process in the given sandbox.
+  ///
+  /// The kernel checks accesses against the real paths of files, so all paths are canonicalized
+  /// (on macOS, the default temporary directory is beneath a symlink).
+  ///
+  fn render(&self, sandbox: &Path) -> Result<String, String> {
+    let mut profile = String::from(
+      "(version 1)\n\
+       (deny default)\n\
+       (allow process-exec process-fork signal)\n\
+       (allow sysctl-read mach-lookup ipc-posix-shm pseudo-tty)\n\
+       (allow network*)\n\
+       (allow file-read-metadata)\n",
+    );
+
+    profile.push_str("(allow file-read* (literal \"/\")");
+    for dir in SYSTEM_READABLE_DIRS {
+      push_subpath(&mut profile, Path::new(dir))?;
+    }
+    for dir in &self.readable_dirs {
+      push_subpath(&mut profile, dir)?;
+    }
+    profile.push_str(")\n");
+
+    profile.push_str("(allow file-read* file-write*");
+    push_subpath(&mut profile, sandbox)?;
+    for dir in &self.writable_dirs {
+      push_subpath(&mut profile, dir)?;
+    }
+    for device in WRITABLE_DEVICES {
+      profile.push_str(&format!(" (literal {})", quote(Path::new(device))?));
+    }
+    profile.push_str(")\n");
+    Ok(profile)
+  }
+}
+
+fn push_subpath(profile: &mut String, dir: &Path) -> Result<(), String> {
+  // A directory which does not exist (yet) cannot be canonicalized, but neither can it be accessed
+  // via a symlink, so it is used as is.
+  let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+  profile.push_str(&format!(" (subpath {})", quote(&dir)?));
+  Ok(())
+}
+
+///
+/// Quotes a path as a string literal in the profile language.
+///
+fn quote(path: &Path) -> Result<String, String> {
+  let path = path
+    .to_str()
+    .ok_or_else(|| format!("Non-UTF8 path in sandbox profile: {:?}", path))?;
+  Ok(format!(
+    "\"{}\"",
+    path.replace('\\', "\\\\").replace('"', "\\\"")
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{quote, SeatbeltProfile};
+  use std::path::{Path, PathBuf};
+
+  #[test]
+  fn quote_escapes() {
+    assert_eq!(
+      quote(Path::new("/tmp/a \"b\"\\c")).unwrap(),
+      "\"/tmp/a \\\"b\\\"\\\\c\""
+    );
+  }
+
+  #[test]
+  fn render_grants_access_to_sandbox_and_caches() {
+    let profile = SeatbeltProfile::new(
+      vec![PathBuf::from("/nonexistent/immutable_roots")],
+      vec![PathBuf::from("/nonexistent/cache")],
+    );
+    let argv = profile
+      .wrap(
+        Path::new("/nonexistent/sandbox"),
+        &["/bin/echo".to_owned(), "hi".to_owned()],
+      )
+      .unwrap();
+    assert_eq!(argv[0], "/usr/bin/sandbox-exec");
+    assert_eq!(&argv[3..], &["/bin/echo".to_owned(), "hi".to_owned()]);
+
+    let rendered = &argv[2];
+    assert!(rendered.starts_with("(version 1)\n(deny default)\n"));
+    let read_only = rendered
+      .lines()
+      .find(|line| line.starts_with("(allow file-read* (literal"))
+      .unwrap();
+    assert!(read_only.contains("(subpath \"/nonexistent/immutable_roots\")"));
+    assert!(!read_only.contains("/nonexistent/sandbox"));
+    let read_write = rendered
+      .lines()
+      .find(|line| line.starts_with("(allow file-read* file-write*"))
+      .unwrap();
+    assert!(read_write.contains("(subpath \"/nonexistent/sandbox\")"));
+    assert!(read_write.contains("(subpath \"/nonexistent/cache\")"));
+    assert!(!read_write.contains("immutable_roots"));
+  }
+}
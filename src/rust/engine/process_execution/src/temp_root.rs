@@ -0,0 +1,251 @@
+extern crate tempfile;
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use self::tempfile::TempDir;
+
+// The prefix of the name of the root directory of each run.
+const RUN_PREFIX: &str = "run-";
+
+// A file in the root directory of each run, which the run holds an exclusive lock on for as long as
+// it is alive.
+const LOCK_FILE: &str = ".lock";
+
+///
+/// The root of the temporary directories (such as sandboxes) which the engine creates during a run.
+///
+/// Each run creates its own root beneath a shared base directory, and deletes it when it exits.
+/// A run which crashes (or is killed) cannot delete its root, so creating a TempRoot also deletes
+/// the roots of earlier runs which are no longer alive: a run holds a lock on a file in its root
+/// for as long as it is alive, and a root whose lock can be taken has been abandoned.
+///
+/// Directories which are created in a TempRoot are moved within it (for example, when pooled
+/// sandboxes are claimed), so the base directory should be on a local disk, but it need not be on
+/// the same disk as the workdir.
+///
+#[derive(Clone)]
+pub struct TempRoot {
+  inner: Arc<Inner>,
+}
+
+struct Inner {
+  base: PathBuf,
+  dir: PathBuf,
+  // Held (and locked) until the root is deleted.
+  _lock: File,
+}
+
+impl TempRoot {
+  ///
+  /// Creates the root for this run beneath `base`, after deleting any abandoned roots there.
+  ///
+  pub fn create(base: &Path) -> Result<TempRoot, String> {
+    fs::create_dir_all(base)
+      .map_err(|e| format!("Error making temporary directory root {:?}: {:?}", base, e))?;
+    remove_abandoned(base);
+
+    let dir = tempfile::Builder::new()
+      .prefix(RUN_PREFIX)
+      .tempdir_in(base)
+      .map_err(|e| format!("Error making temporary directory root in {:?}: {:?}", base, e))?
+      .into_path();
+    let lock = OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(dir.join(LOCK_FILE))
+      .and_then(|lock| lock_exclusive(&lock, true).map(|_| lock))
+      .map_err(|e| format!("Error locking temporary directory root {:?}: {:?}", dir, e))?;
+    // Another run may have deleted the root between its creation and the lock being taken.
+    if !dir.is_dir() {
+      return Err(format!(
+        "Temporary directory root {:?} was deleted while it was being created.",
+        dir
+      ));
+    }
+
+    Ok(TempRoot {
+      inner: Arc::new(Inner {
+        base: base.to_owned(),
+        dir,
+        _lock: lock,
+      }),
+    })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.inner.dir
+  }
+
+  ///
+  /// Creates a temporary directory in this root, which is deleted when it is dropped.
+  ///
+  pub fn tempdir(&self, prefix: &str) -> Result<TempDir, String> {
+    tempfile::Builder::new()
+      .prefix(prefix)
+      .tempdir_in(&self.inner.dir)
+      .map_err(|e| format!("Error making temporary directory in {:?}: {:?}", self.inner.dir, e))
+  }
+
+  ///
+  /// Moves the given temporary directory out of this root (into its base directory), so that it is
+  /// not deleted when the run exits. Returns its new path.
+  ///
+  pub fn preserve(&self, dir: TempDir) -> Result<PathBuf, String> {
+    let source = dir.into_path();
+    let name = source
+      .file_name()
+      .ok_or_else(|| format!("Temporary directory {:?} has no name.", source))?;
+    let destination = self.inner.base.join(name);
+    fs::rename(&source, &destination)
+      .map_err(|e| format!("Error preserving {:?} as {:?}: {:?}", source, destination, e))?;
+    Ok(destination)
+  }
+}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    if let Err(e) = fs::remove_dir_all(&self.dir) {
+      warn!(
+        "Failed to delete temporary directory root {:?}: {:?}",
+        self.dir, e
+      );
+    }
+  }
+}
+
+///
+/// Deletes the roots in `base` which belong to runs which are no longer alive. Failures are logged
+/// rather than returned, because they only leak disk space.
+///
+fn remove_abandoned(base: &Path) {
+  let entries = match fs::read_dir(base) {
+    Ok(entries) => entries,
+    Err(e) => {
+      warn!("Failed to list temporary directory roots in {:?}: {:?}", base, e);
+      return;
+    }
+  };
+  for entry in entries.filter_map(|entry| entry.ok()) {
+    let is_root = entry
+      .file_name()
+      .to_str()
+      .map(|name| name.starts_with(RUN_PREFIX))
+      .unwrap_or(false);
+    if !is_root {
+      continue;
+    }
+    let dir = entry.path();
+    // A root without a lock file may still be being created, so it is left alone.
+    let abandoned = File::open(dir.join(LOCK_FILE))
+      .and_then(|lock| lock_exclusive(&lock, false))
+      .unwrap_or(false);
+    if abandoned {
+      debug!("Deleting abandoned temporary directory root {:?}", dir);
+      if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!(
+          "Failed to delete abandoned temporary directory root {:?}: {:?}",
+          dir, e
+        );
+      }
+    }
+  }
+}
+
+///
+/// Takes an exclusive lock on the given file, which is released when the file is closed. Returns
+/// false if the lock is held elsewhere and `block` is false.
+///
+#[cfg(unix)]
+fn lock_exclusive(file: &File, block: bool) -> ::std::io::Result<bool> {
+  use libc;
+  use std::io;
+  use std::os::unix::io::AsRawFd;
+
+  let operation = if block {
+    libc::LOCK_EX
+  } else {
+    libc::LOCK_EX | libc::LOCK_NB
+  };
+  if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  if err.kind() == io::ErrorKind::WouldBlock {
+    Ok(false)
+  } else {
+    Err(err)
+  }
+}
+
+///
+/// Without file locks, other runs cannot be known to have exited, so their roots are never deleted.
+///
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File, block: bool) -> ::std::io::Result<bool> {
+  Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{TempRoot, RUN_PREFIX};
+  use std::fs;
+  use tempfile::TempDir;
+
+  fn roots(base: &TempDir) -> Vec<String> {
+    let mut roots = fs::read_dir(base.path())
+      .unwrap()
+      .map(|entry| entry.unwrap().file_name().to_str().unwrap().to_owned())
+      .filter(|name| name.starts_with(RUN_PREFIX))
+      .collect::<Vec<_>>();
+    roots.sort();
+    roots
+  }
+
+  #[test]
+  fn deletes_root_when_dropped() {
+    let base = TempDir::new().unwrap();
+    let root = TempRoot::create(base.path()).unwrap();
+    let sandbox = root.tempdir("sandbox").unwrap();
+    assert!(sandbox.path().starts_with(root.path()));
+    assert_eq!(roots(&base).len(), 1);
+
+    drop(sandbox);
+    drop(root);
+    assert_eq!(roots(&base), Vec::<String>::new());
+  }
+
+  #[test]
+  fn deletes_abandoned_roots_but_not_live_ones() {
+    let base = TempDir::new().unwrap();
+    let live = TempRoot::create(base.path()).unwrap();
+
+    // A root whose run crashed: its directory remains, but its lock is not held.
+    let abandoned = base.path().join(format!("{}abandoned", RUN_PREFIX));
+    fs::create_dir_all(abandoned.join("sandbox")).unwrap();
+    fs::write(abandoned.join(super::LOCK_FILE), b"").unwrap();
+
+    let second = TempRoot::create(base.path()).unwrap();
+    assert!(!abandoned.exists());
+    assert!(live.path().is_dir());
+    assert!(second.path().is_dir());
+  }
+
+  #[test]
+  fn preserved_dirs_outlive_the_root() {
+    let base = TempDir::new().unwrap();
+    let root = TempRoot::create(base.path()).unwrap();
+    let sandbox = root.tempdir("sandbox").unwrap();
+    fs::write(sandbox.path().join("out"), b"debug me").unwrap();
+
+    let preserved = root.preserve(sandbox).unwrap();
+    drop(root);
+    assert!(preserved.starts_with(base.path()));
+    assert_eq!(fs::read(preserved.join("out")).unwrap(), b"debug me".to_vec());
+
+    // Preserved directories are not mistaken for abandoned roots.
+    TempRoot::create(base.path()).unwrap();
+    assert!(preserved.is_dir());
+  }
+}
@@ -0,0 +1,41 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::Duration;
+
+use fs;
+
+use super::ExecuteProcessRequest;
+
+///
+/// A request to run the given argv with no inputs, outputs or environment, which the tests of
+/// CommandRunners customize with struct update syntax where they need to.
+///
+pub fn request(argv: &[&str]) -> ExecuteProcessRequest {
+  ExecuteProcessRequest {
+    argv: argv.iter().map(|s| s.to_string()).collect(),
+    env: BTreeMap::new(),
+    input_files: fs::EMPTY_DIGEST,
+    output_files: BTreeSet::new(),
+    output_directories: BTreeSet::new(),
+    immutable_input_directories: BTreeSet::new(),
+    max_output_bytes: None,
+    max_disk_bytes: None,
+    cache_failures: true,
+    container_image: None,
+    timeout: Duration::from_millis(1000),
+    description: argv.join(" "),
+  }
+}
+
+///
+/// Waits (for up to ten seconds) until the given condition holds.
+///
+pub fn wait_until<F: Fn() -> bool>(condition: F) {
+  for _ in 0..1000 {
+    if condition() {
+      return;
+    }
+    thread::sleep(Duration::from_millis(10));
+  }
+  panic!("Timed out waiting for condition.");
+}
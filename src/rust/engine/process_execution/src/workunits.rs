@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use fs;
+use hashing::Digest;
+
+use super::ResultSource;
+use disk_quota::DiskUsage;
+
+// The number of events that are buffered for a consumer which has not yet taken them: beyond this,
+// the oldest events are dropped (and counted) so that an absent consumer cannot exhaust memory.
+const MAX_BUFFERED_EVENTS: usize = 10000;
+
+///
+/// An event in the lifetime of a process execution, which UIs can consume (see
+/// `WorkUnitStore::take_events`) to show the status of each process as it runs.
+///
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum WorkUnitEvent {
+  // The process was requested: it may still wait for a slot to run in, or be served from a cache.
+  // A WorkUnit which is one of several attempts to run a process on behalf of another WorkUnit
+  // (such as a speculative run) has the id of that WorkUnit as its parent.
+  Started {
+    id: u64,
+    parent_id: Option<u64>,
+    description: String,
+    input_files: Digest,
+  },
+  // The process wrote its first byte of stdout or stderr. Only reported by CommandRunners which
+  // observe output as it is produced.
+  FirstOutput { id: u64 },
+  // The process completed (successfully or not). The bytes that it wrote into its sandbox, and
+  // the growth of the caches that it shared with other processes while it ran, are only known for
+  // processes which were executed locally: see `disk_quota::DiskUsage`.
+  Completed {
+    id: u64,
+    exit_code: i32,
+    source: ResultSource,
+    disk_bytes_written: Option<u64>,
+    cache_bytes_written: Option<u64>,
+  },
+  // The process could not be run.
+  Failed { id: u64, error: String },
+}
+
+///
+/// Buffers WorkUnitEvents until they are taken by a consumer.
+///
+#[derive(Clone, Default)]
+pub struct WorkUnitStore {
+  inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+  next_id: u64,
+  events: VecDeque<WorkUnitEvent>,
+  dropped: usize,
+}
+
+impl WorkUnitStore {
+  pub fn new() -> WorkUnitStore {
+    WorkUnitStore::default()
+  }
+
+  ///
+  /// Starts a WorkUnit for the process with the given description and input files.
+  ///
+  pub fn start(&self, description: String, input_files: Digest) -> WorkUnit {
+    self.start_with_parent(None, description, input_files)
+  }
+
+  fn start_with_parent(
+    &self,
+    parent_id: Option<u64>,
+    description: String,
+    input_files: Digest,
+  ) -> WorkUnit {
+    let id = {
+      let mut inner = self.inner.lock().unwrap();
+      inner.next_id += 1;
+      inner.next_id
+    };
+    self.push(WorkUnitEvent::Started {
+      id,
+      parent_id,
+      description,
+      input_files,
+    });
+    WorkUnit {
+      id,
+      input_files,
+      store: Some(self.clone()),
+      output_reported: Arc::new(AtomicBool::new(false)),
+      disk_usage: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  ///
+  /// Takes the events which have been buffered since the last call, along with the number of
+  /// events which were dropped because the buffer was full.
+  ///
+  pub fn take_events(&self) -> (Vec<WorkUnitEvent>, usize) {
+    let mut inner = self.inner.lock().unwrap();
+    let dropped = inner.dropped;
+    inner.dropped = 0;
+    (inner.events.drain(..).collect(), dropped)
+  }
+
+  ///
+  /// Locks the store until the returned guard is dropped, so that a process which is forked while
+  /// the guard is held does not inherit the lock held by a thread which does not exist in it.
+  ///
+  pub fn lock_for_fork(&self) -> WorkUnitStoreGuard {
+    WorkUnitStoreGuard(self.inner.lock().unwrap_or_else(PoisonError::into_inner))
+  }
+
+  fn push(&self, event: WorkUnitEvent) {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.events.len() >= MAX_BUFFERED_EVENTS {
+      inner.events.pop_front();
+      inner.dropped += 1;
+    }
+    inner.events.push_back(event);
+  }
+}
+
+///
+/// See `WorkUnitStore::lock_for_fork`.
+///
+pub struct WorkUnitStoreGuard<'a>(MutexGuard<'a, Inner>);
+
+///
+/// The handle for the execution of a single process, via which its progress is reported.
+///
+#[derive(Clone)]
+pub struct WorkUnit {
+  id: u64,
+  input_files: Digest,
+  // None for a WorkUnit whose events are discarded.
+  store: Option<WorkUnitStore>,
+  output_reported: Arc<AtomicBool>,
+  disk_usage: Arc<Mutex<Option<DiskUsage>>>,
+}
+
+impl WorkUnit {
+  ///
+  /// A WorkUnit which reports nothing, for executions which nothing is observing.
+  ///
+  pub fn ignored() -> WorkUnit {
+    WorkUnit {
+      id: 0,
+      input_files: fs::EMPTY_DIGEST,
+      store: None,
+      output_reported: Arc::new(AtomicBool::new(false)),
+      disk_usage: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  ///
+  /// Starts a WorkUnit for one of several attempts to run the process of this WorkUnit, which
+  /// reports its own progress: this WorkUnit still reports the overall outcome.
+  ///
+  pub fn child(&self, description: String) -> WorkUnit {
+    match self.store {
+      Some(ref store) => store.start_with_parent(Some(self.id), description, self.input_files),
+      None => WorkUnit::ignored(),
+    }
+  }
+
+  ///
+  /// Reports that the process produced output: only the first call for a WorkUnit has an effect.
+  ///
+  pub fn output_received(&self) {
+    if !self.output_reported.swap(true, Ordering::SeqCst) {
+      self.push(WorkUnitEvent::FirstOutput { id: self.id });
+    }
+  }
+
+  ///
+  /// Records the bytes that the process wrote to disk, which are reported when it completes.
+  ///
+  pub fn disk_usage_measured(&self, usage: DiskUsage) {
+    *self.disk_usage.lock().unwrap() = Some(usage);
+  }
+
+  pub fn completed(&self, exit_code: i32, source: ResultSource) {
+    let disk_usage = *self.disk_usage.lock().unwrap();
+    self.push(WorkUnitEvent::Completed {
+      id: self.id,
+      exit_code,
+      source,
+      disk_bytes_written: disk_usage.map(|usage| usage.sandbox_bytes),
+      cache_bytes_written: disk_usage.map(|usage| usage.cache_bytes),
+    });
+  }
+
+  pub fn failed(&self, error: String) {
+    self.push(WorkUnitEvent::Failed { id: self.id, error });
+  }
+
+  fn push(&self, event: WorkUnitEvent) {
+    if let Some(ref store) = self.store {
+      store.push(event);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::ResultSource;
+  use super::{WorkUnit, WorkUnitEvent, WorkUnitStore, MAX_BUFFERED_EVENTS};
+  use disk_quota::DiskUsage;
+  use hashing::{Digest, Fingerprint};
+
+  #[test]
+  fn reports_lifecycle_once() {
+    let store = WorkUnitStore::new();
+    let input_files = Digest(Fingerprint([1; 32]), 10);
+    let workunit = store.start("Run a thing".to_owned(), input_files);
+    workunit.output_received();
+    workunit.clone().output_received();
+    workunit.disk_usage_measured(DiskUsage {
+      sandbox_bytes: 7,
+      cache_bytes: 3,
+    });
+    workunit.completed(0, ResultSource::ExecutedLocally);
+
+    let id = workunit.id();
+    assert_eq!(
+      store.take_events(),
+      (
+        vec![
+          WorkUnitEvent::Started {
+            id,
+            parent_id: None,
+            description: "Run a thing".to_owned(),
+            input_files,
+          },
+          WorkUnitEvent::FirstOutput { id },
+          WorkUnitEvent::Completed {
+            id,
+            exit_code: 0,
+            source: ResultSource::ExecutedLocally,
+            disk_bytes_written: Some(7),
+            cache_bytes_written: Some(3),
+          },
+        ],
+        0
+      )
+    );
+    assert_eq!(store.take_events(), (vec![], 0));
+  }
+
+  #[test]
+  fn drops_oldest_events_when_full() {
+    let store = WorkUnitStore::new();
+    let input_files = Digest(Fingerprint([1; 32]), 10);
+    for _ in 0..(MAX_BUFFERED_EVENTS + 2) {
+      store.start("Run a thing".to_owned(), input_files);
+    }
+    let (events, dropped) = store.take_events();
+    assert_eq!(events.len(), MAX_BUFFERED_EVENTS);
+    assert_eq!(dropped, 2);
+    assert_eq!(
+      events[0],
+      WorkUnitEvent::Started {
+        id: 3,
+        parent_id: None,
+        description: "Run a thing".to_owned(),
+        input_files,
+      }
+    );
+  }
+
+  #[test]
+  fn children_report_their_parent() {
+    let store = WorkUnitStore::new();
+    let input_files = Digest(Fingerprint([1; 32]), 10);
+    let parent = store.start("Run a thing".to_owned(), input_files);
+    let child = parent.child("Run a thing (again)".to_owned());
+    child.failed("Cancelled".to_owned());
+
+    let (events, _) = store.take_events();
+    assert_eq!(
+      events[1..].to_vec(),
+      vec![
+        WorkUnitEvent::Started {
+          id: child.id(),
+          parent_id: Some(parent.id()),
+          description: "Run a thing (again)".to_owned(),
+          input_files,
+        },
+        WorkUnitEvent::Failed {
+          id: child.id(),
+          error: "Cancelled".to_owned(),
+        },
+      ]
+    );
+
+    // The children of an ignored WorkUnit are ignored too.
+    assert_eq!(WorkUnit::ignored().child("Run".to_owned()).id(), 0);
+  }
+}
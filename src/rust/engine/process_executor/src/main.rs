@@ -75,6 +75,12 @@ fn main() {
         .multiple(true)
         .help("Environment variables with which the process should be run."),
     )
+    .arg(
+      Arg::with_name("container-image")
+        .long("container-image")
+        .takes_value(true)
+        .help("The container image to execute the process in remotely."),
+    )
     .setting(AppSettings::TrailingVarArg)
     .arg(
       Arg::with_name("argv")
@@ -115,7 +121,10 @@ fn main() {
       cas_server.to_owned(),
       1,
       10 * 1024 * 1024,
-      Duration::from_secs(30),
+      fs::RemoteTimeouts {
+        metadata: Duration::from_secs(30),
+        transfer: Duration::from_secs(30),
+      },
     ),
     (None, None) => fs::Store::local_only(local_store_path, pool.clone()),
     _ => panic!("Must specify either both --server and --cas-server or neither."),
@@ -138,6 +147,11 @@ fn main() {
     input_files,
     output_files: BTreeSet::new(),
     output_directories: BTreeSet::new(),
+    immutable_input_directories: BTreeSet::new(),
+    max_output_bytes: None,
+    max_disk_bytes: None,
+    cache_failures: true,
+    container_image: args.value_of("container-image").map(|image| image.to_owned()),
     timeout: Duration::new(15 * 60, 0),
     description: "process_executor".to_string(),
   };
@@ -149,11 +163,19 @@ fn main() {
       store,
     )),
     None => Box::new(process_execution::local::CommandRunner::new(
-      store, pool, work_dir, true,
+      store,
+      pool,
+      work_dir.clone(),
+      process_execution::temp_root::TempRoot::create(&work_dir).expect("Error making temp root"),
+      true,
+      Duration::from_secs(3),
+      0,
     )),
   };
 
   let result = runner.run(request).wait().expect("Error executing");
+  // Exiting skips destructors, so the runner (and its temporary directories) is dropped first.
+  drop(runner);
 
   print!("{}", String::from_utf8(result.stdout.to_vec()).unwrap());
   eprint!("{}", String::from_utf8(result.stderr.to_vec()).unwrap());
@@ -0,0 +1,6 @@
+extern crate futures;
+extern crate grpcio;
+extern crate protobuf;
+
+mod gen;
+pub use gen::*;
@@ -1,4 +1,5 @@
-use std::sync::{Arc, RwLock};
+use std::mem;
+use std::sync::{Arc, PoisonError, RwLock, TryLockError};
 
 ///
 /// Resettable is a lazily computed value which can be reset, so that it can be lazily computed
@@ -8,6 +9,11 @@ use std::sync::{Arc, RwLock};
 /// to reset any references which hide background threads, so that forked processes don't inherit
 /// pointers to threads from the parent process which will not exist in the forked process.
 ///
+/// Because another thread of the parent might compute the value again between `reset` and the
+/// fork, the child should additionally call `reset_in_child` immediately after the fork.
+///
+/// A panic while computing the value does not prevent it from being computed (or reset) later.
+///
 #[derive(Clone)]
 pub struct Resettable<T> {
   val: Arc<RwLock<Option<T>>>,
@@ -30,12 +36,12 @@ where
 
   pub fn get(&self) -> T {
     {
-      if let Some(ref val) = *self.val.read().unwrap() {
+      if let Some(ref val) = *self.val.read().unwrap_or_else(PoisonError::into_inner) {
         return val.clone();
       }
     }
     {
-      let mut maybe_val = self.val.write().unwrap();
+      let mut maybe_val = self.val.write().unwrap_or_else(PoisonError::into_inner);
       {
         if let Some(ref val) = *maybe_val {
           return val.clone();
@@ -48,6 +54,26 @@ where
   }
 
   pub fn reset(&self) {
-    *self.val.write().unwrap() = None
+    *self.val.write().unwrap_or_else(PoisonError::into_inner) = None
+  }
+
+  ///
+  /// Resets the value in a child process, immediately after a fork. Unlike `reset`, the value is
+  /// leaked rather than dropped, because it may refer to threads of the parent process which do
+  /// not exist in the child, and dropping it might block waiting for them.
+  ///
+  /// Fails (rather than deadlocking) if the value was locked by another thread of the parent at
+  /// the time of the fork, in which case the child cannot safely use the value, and should exit.
+  ///
+  pub fn reset_in_child(&self) -> Result<(), String> {
+    let mut maybe_val = match self.val.try_write() {
+      Ok(maybe_val) => maybe_val,
+      Err(TryLockError::Poisoned(e)) => e.into_inner(),
+      Err(TryLockError::WouldBlock) => {
+        return Err("A Resettable value was locked by another thread at the time of a fork.".into())
+      }
+    };
+    mem::forget(maybe_val.take());
+    Ok(())
   }
 }
@@ -0,0 +1,275 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::{BTreeMap, HashSet};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+
+use fs::FileContent;
+
+///
+/// The names of the targets defined in each BUILD file of a directory, ordered by path.
+///
+pub struct AddressNames {
+  pub namespace: PathBuf,
+  pub names_by_path: Vec<(PathBuf, Vec<String>)>,
+}
+
+///
+/// Parses the names of the targets defined by the given BUILD files, which must all be located in
+/// the same directory.
+///
+/// Rather than evaluating BUILD files, this finds top-level calls to any of the given symbols, and
+/// extracts their `name` arguments, which must be string literals. Evaluation is required for
+/// BUILD files which compute target names, or which use macros (or any other callable which is not
+/// one of the symbols) at the top level, so those are reported as errors.
+///
+pub fn parse_address_names(
+  files: &[FileContent],
+  symbols: &HashSet<String>,
+) -> Result<AddressNames, String> {
+  let mut namespace = None;
+  let mut names_by_path = BTreeMap::new();
+  let mut all_names: BTreeMap<String, PathBuf> = BTreeMap::new();
+  for file in files {
+    let parent = file
+      .path
+      .parent()
+      .map(Path::to_path_buf)
+      .unwrap_or_default();
+    match namespace {
+      Some(ref namespace) if *namespace != parent => {
+        return Err(format!(
+          "Expected BUILD files to share the same parent directory {:?}, but received: {:?}",
+          namespace, file.path
+        ))
+      }
+      _ => {}
+    }
+    namespace = Some(parent);
+
+    let source = String::from_utf8_lossy(&file.content);
+    let names = parse_names(&source, symbols).map_err(|e| format!("{:?}: {}", file.path, e))?;
+    for name in &names {
+      if let Some(previous_path) = all_names.insert(name.clone(), file.path.clone()) {
+        return Err(format!(
+          "A target with name {:?} is already defined in {:?}, will not overwrite with the \
+           definition from {:?}.",
+          name, previous_path, file.path
+        ));
+      }
+    }
+    names_by_path.insert(file.path.clone(), names);
+  }
+  Ok(AddressNames {
+    namespace: namespace.unwrap_or_default(),
+    names_by_path: names_by_path.into_iter().collect(),
+  })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+  Ident(String),
+  Str(String),
+  Open,
+  Close,
+  Comma,
+  Dot,
+  Assign,
+  Other,
+}
+
+// Python keywords which may be followed by an open bracket, and which are not calls.
+const KEYWORDS: &[&str] = &[
+  "and", "assert", "elif", "for", "if", "in", "is", "lambda", "not", "or", "return", "while",
+  "yield",
+];
+
+///
+/// Returns the names of the top-level calls to the given symbols in the given source, which may
+/// not make top-level calls to anything else.
+///
+fn parse_names(source: &str, symbols: &HashSet<String>) -> Result<Vec<String>, String> {
+  let tokens = tokenize(source)?;
+  let mut names = Vec::new();
+  let mut depth = 0;
+  let mut i = 0;
+  while i < tokens.len() {
+    match tokens[i] {
+      Token::Ident(ref symbol)
+        if depth == 0
+          && tokens.get(i + 1) == Some(&Token::Open)
+          && (i == 0 || tokens[i - 1] != Token::Dot) =>
+      {
+        if symbols.contains(symbol) {
+          let (name, end) = parse_call(symbol, &tokens, i + 2)?;
+          names.push(name);
+          i = end;
+          continue;
+        } else if !KEYWORDS.contains(&symbol.as_str()) {
+          return Err(format!(
+            "Calls {}, which is not a known target type, so the names of the targets that it \
+             defines could not be determined without evaluating the BUILD file.",
+            symbol
+          ));
+        }
+      }
+      Token::Open => depth += 1,
+      Token::Close => depth -= 1,
+      _ => {}
+    }
+    i += 1;
+  }
+  Ok(names)
+}
+
+///
+/// Parses the arguments of a call to `symbol` which start at `start`, and returns the value of its
+/// `name` argument, and the index of the token following the call.
+///
+fn parse_call(symbol: &str, tokens: &[Token], start: usize) -> Result<(String, usize), String> {
+  let mut name = None;
+  let mut depth = 1;
+  let mut i = start;
+  while depth > 0 {
+    match tokens.get(i) {
+      None => return Err(format!("Unterminated call to {}.", symbol)),
+      Some(&Token::Open) => depth += 1,
+      Some(&Token::Close) => depth -= 1,
+      Some(&Token::Ident(ref ident))
+        if depth == 1 && ident == "name" && tokens.get(i + 1) == Some(&Token::Assign) =>
+      {
+        // Adjacent string literals are concatenated.
+        let mut value = String::new();
+        let mut j = i + 2;
+        while let Some(&Token::Str(ref s)) = tokens.get(j) {
+          value.push_str(s);
+          j += 1;
+        }
+        match tokens.get(j) {
+          Some(&Token::Comma) | Some(&Token::Close) if j > i + 2 => name = Some(value),
+          _ => {
+            return Err(format!(
+              "The name of a {} target could not be determined without evaluating the BUILD \
+               file: names must be string literals.",
+              symbol
+            ))
+          }
+        }
+        i = j;
+        continue;
+      }
+      Some(_) => {}
+    }
+    i += 1;
+  }
+  name
+    .map(|name| (name, i))
+    .ok_or_else(|| format!("A {} target was defined without a name.", symbol))
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+  let mut tokens = Vec::new();
+  let mut chars = source.chars().peekable();
+  while let Some(c) = chars.next() {
+    let token = match c {
+      '#' => {
+        while chars.peek().map_or(false, |&c| c != '\n') {
+          chars.next();
+        }
+        continue;
+      }
+      '\'' | '"' => Token::Str(read_string(&mut chars, c, false)?),
+      c if c == '_' || c.is_alphabetic() => {
+        let mut ident = c.to_string();
+        while let Some(&c) = chars.peek() {
+          if c == '_' || c.is_alphanumeric() {
+            ident.push(c);
+            chars.next();
+          } else {
+            break;
+          }
+        }
+        // A string prefix (r'', b'', u'', rb'', etc) is not an identifier.
+        let is_prefix = ident.len() <= 2 && ident.chars().all(|c| "rRbBuU".contains(c));
+        match chars.peek().cloned() {
+          Some(quote) if is_prefix && (quote == '\'' || quote == '"') => {
+            chars.next();
+            let is_raw = ident.contains('r') || ident.contains('R');
+            Token::Str(read_string(&mut chars, quote, is_raw)?)
+          }
+          _ => Token::Ident(ident),
+        }
+      }
+      '(' | '[' | '{' => Token::Open,
+      ')' | ']' | '}' => Token::Close,
+      ',' => Token::Comma,
+      '.' => Token::Dot,
+      '=' | '!' | '<' | '>' if chars.peek() == Some(&'=') => {
+        chars.next();
+        Token::Other
+      }
+      '=' => Token::Assign,
+      c if c.is_whitespace() || c == '\\' => continue,
+      _ => Token::Other,
+    };
+    tokens.push(token);
+  }
+  Ok(tokens)
+}
+
+///
+/// Reads the remainder of a string literal which was opened by the given quote character.
+///
+fn read_string(chars: &mut Peekable<Chars>, quote: char, is_raw: bool) -> Result<String, String> {
+  // An empty string, or the start of a triple quoted string.
+  let is_triple = if chars.peek() == Some(&quote) {
+    chars.next();
+    if chars.peek() == Some(&quote) {
+      chars.next();
+      true
+    } else {
+      return Ok(String::new());
+    }
+  } else {
+    false
+  };
+
+  let mut value = String::new();
+  let mut trailing_quotes = 0;
+  while let Some(c) = chars.next() {
+    if c == quote {
+      trailing_quotes += 1;
+      if !is_triple || trailing_quotes == 3 {
+        let len = value.len() - (trailing_quotes - 1);
+        value.truncate(len);
+        return Ok(value);
+      }
+      value.push(c);
+      continue;
+    }
+    trailing_quotes = 0;
+    match c {
+      '\n' if !is_triple => break,
+      '\\' => {
+        let escaped = chars
+          .next()
+          .ok_or_else(|| "Unterminated string literal.".to_string())?;
+        if is_raw {
+          value.push(c);
+          value.push(escaped);
+        } else {
+          match escaped {
+            'n' => value.push('\n'),
+            't' => value.push('\t'),
+            '\n' => {}
+            other => value.push(other),
+          }
+        }
+      }
+      _ => value.push(c),
+    }
+  }
+  Err("Unterminated string literal.".to_string())
+}
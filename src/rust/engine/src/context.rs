@@ -2,23 +2,39 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 use std;
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 
-use tokio::runtime::Runtime;
+use num_cpus;
+use tokio::runtime::{self, Runtime};
+use tokio_threadpool;
 
 use futures::Future;
 
 use boxfuture::{BoxFuture, Boxable};
-use core::{Failure, TypeId};
-use fs::{safe_create_dir_all_ioerror, PosixFS, ResettablePool, Store};
-use graph::{EntryId, Graph, NodeContext};
+use core::{Failure, Key, TypeId, ANY_TYPE};
+use externs;
+use fs::{
+  self, safe_create_dir_all_ioerror, GlobExpansionMemo, InodeDigests, LinkEscapeBehavior,
+  LocalStoreLimits, NegativeMatchFilter, PoolInstrumentation, PosixFS, RemoteTimeouts,
+  RemoteTransferLimits, ResettablePool, SnapshotMemo, SpecialFileBehavior, Store, WriteThrough,
+};
+use graph::{EntryId, Graph, GraphLimits, NodeContext};
 use handles::maybe_drop_handles;
 use nodes::{NodeKey, TryInto, WrappedNode};
-use process_execution::{self, BoundedCommandRunner, CommandRunner};
+use process_execution::capture::OutputLimit;
+use process_execution::temp_root::TempRoot;
+use process_execution::workunits::{WorkUnitStore, WorkUnitStoreGuard};
+use process_execution::layers::{self, Layer};
+use process_execution::{self, Bound, CommandRunner, ResultSource};
 use resettable::Resettable;
 use rule_graph::RuleGraph;
+use snapshot_cache::SnapshotCache;
 use tasks::Tasks;
 use types::Types;
 
@@ -32,14 +48,42 @@ use types::Types;
 ///
 pub struct Core {
   pub graph: Graph<NodeKey>,
+  pub build_root: PathBuf,
   pub tasks: Tasks,
   pub rule_graph: RuleGraph,
   pub types: Types,
+  // The subject of the Nodes which compute products independent of any subject: see
+  // `Tasks::gen_provider`.
+  pub provider_subject: Key,
   pub fs_pool: Arc<ResettablePool>,
   pub runtime: Resettable<Arc<Runtime>>,
+  // The number of threads of the runtime, and the counters of the Nodes that have run on it.
+  pub runtime_threads: usize,
+  pub runtime_instrumentation: PoolInstrumentation,
   pub store: Store,
   pub vfs: PosixFS,
-  pub command_runner: BoundedCommandRunner,
+  // Shared between the glob expansions of a Session, and cleared when a new Session begins.
+  pub glob_expansion_memo: GlobExpansionMemo,
+  pub negative_match_filter: NegativeMatchFilter,
+  // Shared between the Snapshot captures of a Session, and cleared when a new Session begins.
+  pub snapshot_memo: SnapshotMemo,
+  // Shared between the DigestFile Nodes of the graph, and cleared whenever files are invalidated.
+  pub inode_digests: InodeDigests,
+  pub process_result_counters: Mutex<ProcessResultCounters>,
+  // Events for the processes that are run, buffered until a UI takes them.
+  pub workunits: WorkUnitStore,
+  pub snapshot_cache: Option<Arc<SnapshotCache>>,
+  // The chain of CommandRunners that processes are run with: see `Core::new`.
+  pub command_runner: Box<CommandRunner>,
+  // Bounds the concurrency of the command_runner, and is throttled by the background_policy.
+  pub process_execution_bound: Bound,
+  // If set, the command_runner delegates to this fake, rather than executing processes.
+  pub fake_command_runner: Option<process_execution::fake::CommandRunner>,
+  pub transient_retry_policy: RetryPolicy,
+  pub determinism_sampler: DeterminismSampler,
+  pub stall_policy: StallPolicy,
+  pub background_policy: BackgroundPolicy,
+  executions: Mutex<RunningExecutions>,
 }
 
 impl Core {
@@ -49,18 +93,60 @@ impl Core {
     types: Types,
     build_root: &Path,
     ignore_patterns: Vec<String>,
+    special_file_behavior: SpecialFileBehavior,
+    link_escape_behavior: LinkEscapeBehavior,
+    snapshot_cache_dir: Option<PathBuf>,
+    engine_temp_dir: Option<PathBuf>,
+    materialize_hardlinks: bool,
+    local_store_limits: LocalStoreLimits,
+    thread_name_prefix: String,
     work_dir: PathBuf,
     remote_store_server: Option<String>,
     remote_execution_server: Option<String>,
     remote_store_thread_count: usize,
     remote_store_chunk_bytes: usize,
-    remote_store_chunk_upload_timeout: Duration,
+    remote_store_timeouts: RemoteTimeouts,
+    remote_store_write_through: WriteThrough,
+    remote_store_transfer_limits: RemoteTransferLimits,
     process_execution_parallelism: usize,
     process_execution_cleanup_local_dirs: bool,
+    process_termination_grace_period: Duration,
+    process_execution_sandbox_pool_size: usize,
+    process_execution_output_limit: OutputLimit,
+    process_execution_inherited_env: BTreeSet<String>,
+    process_execution_macos_sandbox: Option<Vec<PathBuf>>,
+    process_execution_record_dir: Option<PathBuf>,
+    process_execution_replay_dir: Option<PathBuf>,
+    process_execution_cache_dir: Option<PathBuf>,
+    remote_execution_speculation_delay: Option<Duration>,
+    transient_retry_policy: RetryPolicy,
+    determinism_sampler: DeterminismSampler,
+    stall_policy: StallPolicy,
+    background_policy: BackgroundPolicy,
+    graph_limits: GraphLimits,
+    use_fake_command_runner: bool,
   ) -> Core {
-    let fs_pool = Arc::new(ResettablePool::new("io-".to_string()));
-    let runtime = Resettable::new(|| {
-      Arc::new(Runtime::new().unwrap_or_else(|e| panic!("Could not initialize Runtime: {:?}", e)))
+    // Check the limit on open files before anything opens them, and size the budget for
+    // filesystem operations to fit within it.
+    if let Err(e) = fs::fd_budget::initialize() {
+      warn!("{}", e);
+    }
+
+    // Threads are named by role: see `fs::threads::thread_name`.
+    fs::threads::set_name_prefix(&thread_name_prefix);
+    let fs_pool = Arc::new(ResettablePool::new(fs::threads::thread_name("io-pool-")));
+    let runtime_threads = num_cpus::get();
+    let runtime = Resettable::new(move || {
+      let mut threadpool_builder = tokio_threadpool::Builder::new();
+      threadpool_builder
+        .pool_size(runtime_threads)
+        .name_prefix(fs::threads::thread_name("cpu-pool-"));
+      Arc::new(
+        runtime::Builder::new()
+          .threadpool_builder(threadpool_builder)
+          .build()
+          .unwrap_or_else(|e| panic!("Could not initialize Runtime: {:?}", e)),
+      )
     });
 
     let store_path = match std::env::home_dir() {
@@ -77,54 +163,415 @@ impl Core {
           address,
           remote_store_thread_count,
           remote_store_chunk_bytes,
-          remote_store_chunk_upload_timeout,
+          remote_store_timeouts,
         ),
         None => Store::local_only(store_path, fs_pool.clone()),
       })
+      .map(|store| {
+        store
+          .with_local_limits(local_store_limits)
+          .with_hardlinks(materialize_hardlinks)
+          .with_write_through(remote_store_write_through)
+          .with_remote_transfer_limits(remote_store_transfer_limits)
+      })
       .unwrap_or_else(|e| panic!("Could not initialize Store: {:?}", e));
 
-    let underlying_command_runner: Box<CommandRunner> = match remote_execution_server {
-      Some(address) => Box::new(process_execution::remote::CommandRunner::new(
-        address,
-        // Allow for some overhead for bookkeeping threads (if any).
-        process_execution_parallelism + 2,
-        store.clone(),
-      )),
-      None => Box::new(process_execution::local::CommandRunner::new(
-        store.clone(),
-        fs_pool.clone(),
-        work_dir,
-        process_execution_cleanup_local_dirs,
-      )),
+    let fake_command_runner = if use_fake_command_runner {
+      Some(process_execution::fake::CommandRunner::new())
+    } else {
+      None
+    };
+
+    // If remote execution is speculated against, a local runner is the alternate of the innermost
+    // layer.
+    let (underlying_command_runner, speculation_layer): (Box<CommandRunner>, Option<Layer>) = {
+      let local_command_runner = || -> Box<CommandRunner> {
+        let temp_root = TempRoot::create(&engine_temp_dir.unwrap_or_else(|| work_dir.join("tmp")))
+          .unwrap_or_else(|e| panic!("Could not initialize temporary directory root: {}", e));
+        let runner = process_execution::local::CommandRunner::new(
+          store.clone(),
+          fs_pool.clone(),
+          work_dir,
+          temp_root,
+          process_execution_cleanup_local_dirs,
+          process_termination_grace_period,
+          process_execution_sandbox_pool_size,
+        ).with_output_limit(process_execution_output_limit)
+          .with_inherited_env(process_execution_inherited_env);
+        match process_execution_macos_sandbox {
+          Some(cache_dirs) if cfg!(target_os = "macos") => {
+            Box::new(runner.with_macos_sandbox(cache_dirs))
+          }
+          Some(_) => {
+            warn!("The macOS sandbox for local process execution is only available on macOS.");
+            Box::new(runner)
+          }
+          None => Box::new(runner),
+        }
+      };
+      match (
+        &fake_command_runner,
+        process_execution_replay_dir,
+        remote_execution_server,
+      ) {
+        (&Some(ref fake), _, _) => (Box::new(fake.clone()) as Box<CommandRunner>, None),
+        (&None, Some(replay_dir), _) => (
+          Box::new(process_execution::archive::ReplayingCommandRunner::new(
+            store.clone(),
+            process_execution::archive::Archive::new(replay_dir),
+          )) as Box<CommandRunner>,
+          None,
+        ),
+        (&None, None, Some(address)) => (
+          Box::new(process_execution::remote::CommandRunner::new(
+            address,
+            // Allow for some overhead for bookkeeping threads (if any).
+            process_execution_parallelism + 2,
+            store.clone(),
+          ).with_output_limit(process_execution_output_limit)) as Box<CommandRunner>,
+          remote_execution_speculation_delay.map(|delay| Layer::Speculate {
+            delay,
+            alternate: local_command_runner(),
+          }),
+        ),
+        (&None, None, None) => (local_command_runner(), None),
+      }
     };
 
-    let command_runner =
-      BoundedCommandRunner::new(underlying_command_runner, process_execution_parallelism);
+    // The layers above the underlying CommandRunner, outermost first. Failures which the runner
+    // marked as transient are retried, and each retry waits for the bound again. Requests which are
+    // served from the cache do not wait for the bound.
+    let process_execution_bound = Bound::new(process_execution_parallelism);
+    let mut command_runner_layers = vec![Layer::Retry {
+      attempts: transient_retry_policy.attempts,
+      backoff: transient_retry_policy.backoff,
+    }];
+    if let Some(cache_dir) = process_execution_cache_dir {
+      command_runner_layers.push(Layer::Cache(process_execution::archive::Archive::new(
+        cache_dir,
+      )));
+    }
+    command_runner_layers.push(Layer::Bound(process_execution_bound.clone()));
+    if let Some(record_dir) = process_execution_record_dir {
+      command_runner_layers.push(Layer::Record(process_execution::archive::Archive::new(
+        record_dir,
+      )));
+    }
+    command_runner_layers.extend(speculation_layer);
+    let command_runner = layers::build(underlying_command_runner, command_runner_layers, &store);
 
     let rule_graph = RuleGraph::new(&tasks, root_subject_types);
 
+    let snapshot_cache = snapshot_cache_dir
+      .map(|dir| Arc::new(SnapshotCache::new(dir, build_root, &ignore_patterns)));
+
     Core {
-      graph: Graph::new(),
+      graph: Graph::new_with_limits(graph_limits),
+      build_root: build_root.to_owned(),
       tasks: tasks,
       rule_graph: rule_graph,
       types: types,
+      provider_subject: Key::new(
+        externs::key_for(externs::store_tuple(&[])).id(),
+        ANY_TYPE,
+      ),
       fs_pool: fs_pool.clone(),
       runtime: runtime,
+      runtime_threads: runtime_threads,
+      runtime_instrumentation: PoolInstrumentation::default(),
       store: store,
       // FIXME: Errors in initialization should definitely be exposed as python
       // exceptions, rather than as panics.
-      vfs: PosixFS::new(build_root, fs_pool, &ignore_patterns).unwrap_or_else(|e| {
-        panic!("Could not initialize VFS: {:?}", e);
-      }),
+      vfs: PosixFS::new(
+        build_root,
+        fs_pool,
+        &ignore_patterns,
+        special_file_behavior,
+      ).map(|vfs| vfs.with_link_escape_behavior(link_escape_behavior))
+        .unwrap_or_else(|e| {
+          panic!("Could not initialize VFS: {:?}", e);
+        }),
+      glob_expansion_memo: GlobExpansionMemo::new(),
+      negative_match_filter: NegativeMatchFilter::new(),
+      snapshot_memo: SnapshotMemo::new(),
+      inode_digests: InodeDigests::new(),
+      process_result_counters: Mutex::new(ProcessResultCounters::default()),
+      workunits: WorkUnitStore::new(),
+      snapshot_cache: snapshot_cache,
       command_runner: command_runner,
+      process_execution_bound: process_execution_bound,
+      fake_command_runner: fake_command_runner,
+      transient_retry_policy: transient_retry_policy,
+      determinism_sampler: determinism_sampler,
+      stall_policy: stall_policy,
+      background_policy: background_policy,
+      executions: Mutex::new(RunningExecutions::default()),
     }
   }
 
-  pub fn pre_fork(&self) {
+  ///
+  /// Marks an execution on behalf of a (background or foreground) Session as running until the
+  /// returned guard is dropped, and applies or lifts the BackgroundPolicy accordingly.
+  ///
+  pub fn running_execution(&self, background: bool) -> RunningExecution {
+    let mut executions = self.executions.lock().unwrap();
+    if background {
+      executions.background += 1;
+    } else {
+      executions.foreground += 1;
+    }
+    self.apply_background_policy(&mut executions);
+    RunningExecution {
+      core: self,
+      background: background,
+    }
+  }
+
+  fn apply_background_policy(&self, executions: &mut RunningExecutions) {
+    let throttled = executions.background > 0 && executions.foreground == 0;
+    if throttled == executions.throttled {
+      return;
+    }
+    executions.throttled = throttled;
+    if throttled {
+      debug!("Only background executions are running: throttling process execution and IO.");
+      self
+        .process_execution_bound
+        .set_limit(self.background_policy.process_execution_parallelism);
+      self.fs_pool.set_limit(self.background_policy.io_parallelism);
+    } else {
+      debug!("No longer throttling process execution and IO.");
+      self.process_execution_bound.set_limit(None);
+      self.fs_pool.set_limit(None);
+    }
+  }
+
+  ///
+  /// Begins shutting down: see `Scheduler::shutdown`.
+  ///
+  pub fn begin_shutdown(&self, deadline: Instant) {
+    self.executions.lock().unwrap().shutdown_deadline = Some(deadline);
+  }
+
+  ///
+  /// If the Core is shutting down, the deadline after which running executions are cancelled.
+  /// Executions which begin after shutdown has begun are cancelled immediately.
+  ///
+  pub fn shutdown_deadline(&self) -> Option<Instant> {
+    self.executions.lock().unwrap().shutdown_deadline
+  }
+
+  ///
+  /// The number of (background or foreground) executions that are running.
+  ///
+  pub fn running_execution_count(&self) -> usize {
+    let executions = self.executions.lock().unwrap();
+    executions.foreground + executions.background
+  }
+
+  ///
+  /// Drops the thread pools, LMDB Environments and gRPC channels of the Core before a fork, so that
+  /// the forked process re-creates them rather than inheriting references to threads that do not
+  /// exist in it.
+  ///
+  /// Also locks the Core's other shared state (and the interner), and returns the guards, which
+  /// the caller must hold across the fork and drop after it (in both the parent and the child).
+  /// Otherwise, the child might inherit a lock which was held by a thread which does not exist in
+  /// it. Other threads which use the locked state block until the guards are dropped.
+  ///
+  pub fn pre_fork(&self) -> ForkGuards {
     self.fs_pool.reset();
-    self.store.reset_prefork();
+    self.store.pre_fork();
     self.runtime.reset();
-    self.command_runner.reset_prefork();
+    self.command_runner.pre_fork();
+    ForkGuards {
+      _executions: self
+        .executions
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner),
+      _workunits: self.workunits.lock_for_fork(),
+      _interns: externs::lock_interns_for_fork(),
+    }
+  }
+
+  ///
+  /// Called in the forked child process immediately after a fork (and after dropping the guards
+  /// returned by `pre_fork`), to discard (without dropping) anything that another thread of the
+  /// parent re-created between `pre_fork` and the fork.
+  ///
+  /// Fails if any of those were locked by another thread at the time of the fork, in which case
+  /// the child cannot safely use the Core, and should exit.
+  ///
+  pub fn post_fork_in_child(&self) -> Result<(), String> {
+    self.fs_pool.reset_in_child()?;
+    self.store.post_fork_in_child()?;
+    self.runtime.reset_in_child()?;
+    self.command_runner.post_fork_in_child()
+  }
+}
+
+///
+/// The locks which are held across a fork: see `Core::pre_fork`.
+///
+pub struct ForkGuards<'a> {
+  _executions: MutexGuard<'a, RunningExecutions>,
+  _workunits: WorkUnitStoreGuard<'a>,
+  _interns: externs::InternsGuard,
+}
+
+///
+/// Controls how Nodes retry failures that are classified as transient (ie, caused by flaky
+/// infrastructure rather than by a deterministic failure of a rule).
+///
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+  /// The maximum number of retries following the initial attempt.
+  pub attempts: usize,
+  /// The delay before the first retry, which doubles for each subsequent retry.
+  pub backoff: Duration,
+}
+
+impl RetryPolicy {
+  ///
+  /// The delay before the given (zero-indexed) retry. The exponent is capped to avoid overflow.
+  ///
+  pub fn backoff_for(&self, attempt: usize) -> Duration {
+    self.backoff * 2u32.pow(cmp::min(attempt, 16) as u32)
+  }
+}
+
+///
+/// Controls how the Scheduler reacts when an execution stalls: ie, when Nodes are running, but none
+/// of them have completed for a while.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct StallPolicy {
+  /// How long to wait for any Node to complete before reporting a stall, or None to never report.
+  pub timeout: Option<Duration>,
+  /// Whether to fail the remaining roots of a stalled execution (and cancel their running work),
+  /// rather than continuing to wait.
+  pub cancel: bool,
+}
+
+///
+/// Limits the work of background Sessions (such as those of a process which speculatively rebuilds
+/// when files change), so that they don't starve interactive work on the same machine.
+///
+/// The limits apply while background Sessions are executing but no foreground Session is: as soon
+/// as a foreground Session begins executing, they are lifted until it completes.
+///
+#[derive(Clone, Copy, Debug)]
+pub struct BackgroundPolicy {
+  /// The maximum number of concurrent processes while throttled, or None for no limit.
+  pub process_execution_parallelism: Option<usize>,
+  /// The maximum number of concurrent operations on the IO pool while throttled, or None for no
+  /// limit.
+  pub io_parallelism: Option<usize>,
+}
+
+///
+/// Cumulative counts of where the ProcessResults requested by rules came from (see
+/// `process_execution::ResultSource`).
+///
+#[derive(Clone, Debug, Default)]
+pub struct ProcessResultCounters {
+  // ProcessResults requested by rules, whether or not they were already memoized in the Graph.
+  requested: usize,
+  // ProcessResults which were computed (rather than memoized), by their source.
+  computed: HashMap<&'static str, usize>,
+}
+
+impl ProcessResultCounters {
+  pub fn record_requested(&mut self) {
+    self.requested += 1;
+  }
+
+  pub fn record_computed(&mut self, source: ResultSource) {
+    *self.computed.entry(source.name()).or_insert(0) += 1;
+  }
+
+  ///
+  /// Returns the counts by source that have accumulated since the given (earlier) snapshot of these
+  /// counters. Requests which did not cause a ProcessResult to be computed were served from the
+  /// Graph, and are counted as `memoized`.
+  ///
+  pub fn since(&self, earlier: &ProcessResultCounters) -> HashMap<&'static str, usize> {
+    let mut counts: HashMap<&'static str, usize> = self
+      .computed
+      .iter()
+      .map(|(&source, &count)| {
+        let earlier_count = earlier.computed.get(source).cloned().unwrap_or(0);
+        (source, count - earlier_count)
+      })
+      .filter(|&(_, count)| count > 0)
+      .collect();
+    let computed: usize = counts.values().sum();
+    let requested = self.requested - earlier.requested;
+    counts.insert("memoized", requested.saturating_sub(computed));
+    counts
+  }
+}
+
+#[derive(Default)]
+struct RunningExecutions {
+  foreground: usize,
+  background: usize,
+  throttled: bool,
+  // If the Core is shutting down, the deadline after which running executions are cancelled.
+  shutdown_deadline: Option<Instant>,
+}
+
+///
+/// A guard for an execution that is running: see `Core::running_execution`.
+///
+pub struct RunningExecution<'a> {
+  core: &'a Core,
+  background: bool,
+}
+
+impl<'a> Drop for RunningExecution<'a> {
+  fn drop(&mut self) {
+    let mut executions = self.core.executions.lock().unwrap();
+    if self.background {
+      executions.background -= 1;
+    } else {
+      executions.foreground -= 1;
+    }
+    self.core.apply_background_policy(&mut executions);
+  }
+}
+
+///
+/// Selects the sample of Tasks that are re-run to detect nondeterministic rules.
+///
+/// Sampling hashes the Task with keys that are chosen randomly for each process, so a particular
+/// Task is consistently sampled (or not) for the lifetime of the process, but different Tasks are
+/// sampled in different processes.
+///
+pub struct DeterminismSampler {
+  rate: f64,
+  hasher: RandomState,
+}
+
+impl DeterminismSampler {
+  ///
+  /// Creates a sampler that selects approximately the given fraction (between 0.0 and 1.0) of
+  /// Tasks.
+  ///
+  pub fn new(rate: f64) -> DeterminismSampler {
+    DeterminismSampler {
+      rate: rate,
+      hasher: RandomState::new(),
+    }
+  }
+
+  pub fn should_check<T: Hash>(&self, t: &T) -> bool {
+    if self.rate <= 0.0 {
+      return false;
+    }
+    let mut hasher = self.hasher.build_hasher();
+    t.hash(&mut hasher);
+    (hasher.finish() as f64) < self.rate * (u64::max_value() as f64)
   }
 }
 
@@ -132,16 +579,29 @@ impl Core {
 pub struct Context {
   pub entry_id: EntryId,
   pub core: Arc<Core>,
+  // The deadline of the Session whose execution started this Node (if any): see `Context::store`.
+  pub deadline: Option<Instant>,
 }
 
 impl Context {
-  pub fn new(entry_id: EntryId, core: Arc<Core>) -> Context {
+  pub fn new(entry_id: EntryId, core: Arc<Core>, deadline: Option<Instant>) -> Context {
     Context {
       entry_id: entry_id,
       core: core,
+      deadline: deadline,
     }
   }
 
+  ///
+  /// The Store, with RPCs to the remote store bounded by the deadline of the Session whose
+  /// execution started this Node. Nodes are shared between Sessions, so a Node which is awaited by
+  /// another Session keeps the deadline that it started with. The failures that a deadline causes
+  /// are not memoized beyond the run: see `NodeKey::is_session_scoped`.
+  ///
+  pub fn store(&self) -> Store {
+    self.core.store.with_remote_deadline(self.deadline)
+  }
+
   ///
   /// Get the future value for the given Node implementation.
   ///
@@ -172,6 +632,7 @@ impl NodeContext for Context {
     Context {
       entry_id: entry_id,
       core: self.core.clone(),
+      deadline: self.deadline,
     }
   }
 
@@ -183,6 +644,7 @@ impl NodeContext for Context {
   where
     F: Future<Item = (), Error = ()> + Send + 'static,
   {
+    let future = self.core.runtime_instrumentation.instrument(future);
     self.core.runtime.get().executor().spawn(future);
   }
 }
@@ -109,7 +109,7 @@ impl Key {
 ///
 /// A wrapper around a handle: soon to contain an Arc.
 ///
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone)]
 pub struct Value(Arc<Handle>);
 
 impl Value {
@@ -118,6 +118,20 @@ impl Value {
   }
 }
 
+///
+/// Values are compared by the Graph to decide whether a re-executed Node has changed (and thus
+/// whether its dependents need to re-run), so they are compared with python's `__eq__` (see
+/// `externs::equals`) rather than by identity: a rule which re-runs produces a new object, which
+/// is often equal to its previous result.
+///
+impl PartialEq for Value {
+  fn eq(&self, other: &Value) -> bool {
+    Arc::ptr_eq(&self.0, &other.0) || externs::equals(&self.0, &other.0)
+  }
+}
+
+impl Eq for Value {}
+
 impl Deref for Value {
   type Target = Handle;
 
@@ -151,15 +165,63 @@ impl From<Handle> for Value {
   }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Failure {
   /// A Node failed because a filesystem change invalidated it or its inputs.
   /// A root requestor should usually immediately retry their request.
   Invalidated,
+  /// A request was cancelled before it completed (for example, because it stalled). Holds a message
+  /// explaining why. Unlike an invalidated request, a cancelled request should not be retried.
+  ///
+  /// Only the roots of an abandoned execution fail this way: the Nodes that they were waiting on
+  /// are cancelled, and fail as though they had been invalidated (see `Scheduler::abandon`).
+  Cancelled(String),
   /// There was no valid combination of rules to satisfy a request.
   Noop(Noop),
-  /// A rule raised an exception.
-  Throw(Value, String),
+  /// A rule raised an exception. Holds the exception, its Python traceback, the "engine
+  /// traceback" (the formatted Nodes that the failure propagated through, innermost first), and
+  /// the category of the failure.
+  Throw(Value, String, Vec<String>, ErrorCategory),
+}
+
+impl Failure {
+  pub fn category(&self) -> ErrorCategory {
+    match self {
+      &Failure::Invalidated => ErrorCategory::Invalidation,
+      &Failure::Cancelled(_) => ErrorCategory::Cancellation,
+      &Failure::Noop(_) => ErrorCategory::UserRule,
+      &Failure::Throw(_, _, _, category) => category,
+    }
+  }
+}
+
+impl Eq for Failure {}
+
+///
+/// Failures are compared by the Graph to decide whether a re-executed Node has changed (and thus
+/// whether its dependents need to re-run). Python exceptions generally only implement identity
+/// equality, so two Throws are considered equal if they have the same traceback (which always ends
+/// with a rendering of the exception): otherwise a rule that deterministically fails would
+/// invalidate all of its dependents every time it re-ran.
+///
+/// Unlike Values (see `Value`'s `PartialEq`), the exceptions themselves are therefore not
+/// compared.
+///
+/// The engine traceback is ignored, since it describes where a failure was observed rather than
+/// the failure itself.
+///
+impl PartialEq for Failure {
+  fn eq(&self, other: &Failure) -> bool {
+    match (self, other) {
+      (&Failure::Invalidated, &Failure::Invalidated) => true,
+      (&Failure::Cancelled(ref m1), &Failure::Cancelled(ref m2)) => m1 == m2,
+      (&Failure::Noop(ref n1), &Failure::Noop(ref n2)) => n1 == n2,
+      (&Failure::Throw(_, ref tb1, _, c1), &Failure::Throw(_, ref tb2, _, c2)) => {
+        c1 == c2 && tb1 == tb2
+      }
+      _ => false,
+    }
+  }
 }
 
 // NB: enum members are listed in ascending priority order based on how likely they are
@@ -181,12 +243,51 @@ impl fmt::Debug for Noop {
   }
 }
 
+///
+/// A coarse classification of a Failure, which allows callers to decide how to react to it (for
+/// example: which exit code to use, or whether to retry) without inspecting its message.
+///
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+  /// A rule raised an exception, or the installed rules could not satisfy a request.
+  UserRule,
+  /// The engine failed to interact with the filesystem, the Store, or a process runner.
+  Infrastructure,
+  /// A file or directory that was expected to exist did not.
+  MissingFile,
+  /// The inputs of a request changed while it was running.
+  Invalidation,
+  /// A request was cancelled before it completed.
+  Cancellation,
+}
+
+impl ErrorCategory {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      &ErrorCategory::UserRule => "user_rule",
+      &ErrorCategory::Infrastructure => "infrastructure",
+      &ErrorCategory::MissingFile => "missing_file",
+      &ErrorCategory::Invalidation => "invalidation",
+      &ErrorCategory::Cancellation => "cancellation",
+    }
+  }
+}
+
+///
+/// Creates a Failure for an error that occurred in the engine's own operations.
+///
 pub fn throw(msg: &str) -> Failure {
+  throw_category(ErrorCategory::Infrastructure, msg)
+}
+
+pub fn throw_category(category: ErrorCategory, msg: &str) -> Failure {
   Failure::Throw(
     externs::create_exception(msg),
     format!(
       "Traceback (no traceback):\n  <pants native internals>\nException: {}",
       msg
     ).to_string(),
+    Vec::new(),
+    category,
   )
 }
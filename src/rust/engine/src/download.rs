@@ -0,0 +1,379 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::fs::File as StdFile;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use futures::future::{self, join_all, Future};
+use reqwest;
+use tar;
+use tempfile;
+
+use boxfuture::{BoxFuture, Boxable};
+use fs::{self, File, PathStat, ResettablePool, Store, StoreFileByDigest};
+use hashing::{Digest, WriterHasher};
+
+// The maximum number of symlinks that will be followed to resolve a symlink in an archive.
+const MAX_LINK_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ArchiveType {
+  None,
+  Tar,
+  TarGz,
+}
+
+impl ArchiveType {
+  pub fn create(archive_type: &str) -> Result<ArchiveType, String> {
+    match archive_type {
+      "" => Ok(ArchiveType::None),
+      "tar" => Ok(ArchiveType::Tar),
+      "tgz" => Ok(ArchiveType::TarGz),
+      _ => Err(format!(
+        "Unrecognized archive type: {}. Supported types are tar and tgz.",
+        archive_type
+      )),
+    }
+  }
+}
+
+///
+/// A binary tool (or an archive containing one) to be fetched from any of a list of mirror URLs,
+/// which must have the given Digest.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct BinaryToolRequest {
+  pub name: String,
+  pub version: String,
+  pub platform: String,
+  pub urls: Vec<String>,
+  pub digest: Digest,
+  pub archive_type: ArchiveType,
+  pub timeout: Duration,
+}
+
+impl BinaryToolRequest {
+  fn description(&self) -> String {
+    if self.platform.is_empty() {
+      format!("{} {}", self.name, self.version)
+    } else {
+      format!("{} {} for {}", self.name, self.version, self.platform)
+    }
+  }
+}
+
+///
+/// Fetches the given tool, and returns a Snapshot containing either the tool itself (as an
+/// executable file named after the tool), or the contents of its archive.
+///
+/// Tools are stored in the Store under their Digest, so a tool is only downloaded if it has not
+/// been fetched before. Downloads are streamed to a temporary file (rather than into memory) and
+/// verified against the Digest before anything is stored.
+///
+pub fn fetch_binary_tool(
+  store: Store,
+  pool: Arc<ResettablePool>,
+  request: BinaryToolRequest,
+) -> BoxFuture<fs::Snapshot, String> {
+  let digest = request.digest;
+  let store2 = store.clone();
+  let pool2 = pool.clone();
+  let request2 = request.clone();
+  store
+    .load_file_bytes_with(digest, |bytes| bytes)
+    .and_then(move |maybe_bytes| match maybe_bytes {
+      Some(bytes) => future::ok(bytes).to_boxed(),
+      None => {
+        let store3 = store2.clone();
+        pool2
+          .spawn_fn(move || download_verified(&request2))
+          .and_then(move |file| store2.store_file_from(file, true))
+          .and_then(move |digest| store3.load_file_bytes_with(digest, |bytes| bytes))
+          .and_then(move |maybe_bytes| {
+            maybe_bytes.ok_or_else(|| format!("Fetched {:?}, but it was not stored.", digest))
+          })
+          .to_boxed()
+      }
+    })
+    .and_then(move |bytes| match request.archive_type {
+      ArchiveType::None => snapshot_of_tool(store, request.name, bytes),
+      archive_type => {
+        let description = request.description();
+        pool
+          .spawn_fn(move || {
+            extract(archive_type, &bytes)
+              .map_err(|e| format!("Failed to extract {}: {}", description, e))
+          })
+          .and_then(move |entries| snapshot_of_entries(store, entries))
+          .to_boxed()
+      }
+    })
+    .to_boxed()
+}
+
+///
+/// Tries each URL of the request in order, returning a temporary file containing the content of
+/// the first which downloads successfully and matches the expected Digest.
+///
+fn download_verified(request: &BinaryToolRequest) -> Result<StdFile, String> {
+  let mut errors = Vec::new();
+  for url in &request.urls {
+    info!("Fetching {} from {}", request.description(), url);
+    match download(url, request.digest, request.timeout) {
+      Ok(file) => return Ok(file),
+      Err(e) => errors.push(format!("{}: {}", url, e)),
+    }
+  }
+  Err(format!(
+    "Failed to fetch {} from any source:\n  {}",
+    request.description(),
+    errors.join("\n  ")
+  ))
+}
+
+///
+/// Streams the content of the given URL into a temporary file, hashing it as it is written.
+///
+/// The timeout applies to connecting, and to each read from the connection, so a slow download
+/// which is making progress is not interrupted. No more than one byte beyond the expected size is
+/// read, so a server which sends too much content cannot fill the disk.
+///
+fn download(url: &str, expected_digest: Digest, timeout: Duration) -> Result<StdFile, String> {
+  let mut file =
+    tempfile::tempfile().map_err(|e| format!("Error creating a temporary file: {}", e))?;
+  let limit = expected_digest.1 as u64 + 1;
+  let actual_digest = {
+    let mut hasher = WriterHasher::new(&mut file);
+    let size = if url.starts_with("file://") {
+      StdFile::open(&url["file://".len()..])
+        .and_then(|f| io::copy(&mut f.take(limit), &mut hasher))
+        .map_err(|e| format!("Error reading file: {}", e))?
+    } else {
+      let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| format!("Error creating an HTTP client: {}", e))?;
+      let response = client
+        .get(url)
+        .send()
+        .map_err(|e| format!("Error downloading: {}", e))?;
+      if !response.status().is_success() {
+        return Err(format!("Unexpected status: {}", response.status()));
+      }
+      io::copy(&mut response.take(limit), &mut hasher)
+        .map_err(|e| format!("Error downloading: {}", e))?
+    };
+    Digest(hasher.finish(), size as usize)
+  };
+  if actual_digest == expected_digest {
+    Ok(file)
+  } else {
+    Err(format!(
+      "Downloaded content had digest {:?}, but {:?} was expected.",
+      actual_digest, expected_digest
+    ))
+  }
+}
+
+///
+/// An entry of an extracted archive.
+///
+enum Entry {
+  Dir,
+  File { content: Bytes, is_executable: bool },
+  Link(PathBuf),
+}
+
+fn extract(archive_type: ArchiveType, bytes: &[u8]) -> Result<Vec<(PathBuf, Entry)>, String> {
+  match archive_type {
+    ArchiveType::None => Err("Not an archive.".to_string()),
+    ArchiveType::Tar => extract_tar(bytes),
+    ArchiveType::TarGz => extract_tar(GzDecoder::new(bytes)),
+  }
+}
+
+fn extract_tar<R: Read>(reader: R) -> Result<Vec<(PathBuf, Entry)>, String> {
+  let mut archive = tar::Archive::new(reader);
+  let mut entries = Vec::new();
+  for entry in archive.entries().map_err(|e| e.to_string())? {
+    let mut entry = entry.map_err(|e| e.to_string())?;
+    let path = normalize(&entry.path().map_err(|e| e.to_string())?)?;
+    if path.as_os_str().is_empty() {
+      // The root of the archive.
+      continue;
+    }
+    let entry_type = entry.header().entry_type();
+    let extracted = if entry_type.is_dir() {
+      Entry::Dir
+    } else if entry_type.is_file() {
+      let mode = entry.header().mode().map_err(|e| e.to_string())?;
+      let is_executable = (mode & 0o100) != 0;
+      let mut content = Vec::new();
+      entry
+        .read_to_end(&mut content)
+        .map_err(|e| e.to_string())?;
+      Entry::File {
+        content: Bytes::from(content),
+        is_executable: is_executable,
+      }
+    } else if entry_type.is_symlink() {
+      let dest = entry
+        .link_name()
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Symlink {:?} had no destination.", path))?
+        .into_owned();
+      Entry::Link(dest)
+    } else {
+      // Other entries (hard links, devices, PAX headers, etc) are not tool content.
+      continue;
+    };
+    entries.push((path, extracted));
+  }
+  Ok(entries)
+}
+
+///
+/// Normalizes a relative path in an archive, which may not escape the archive.
+///
+fn normalize(path: &Path) -> Result<PathBuf, String> {
+  let mut normalized = PathBuf::new();
+  for component in path.components() {
+    match component {
+      Component::CurDir => {}
+      Component::Normal(c) => normalized.push(c),
+      Component::ParentDir => {
+        if !normalized.pop() {
+          return Err(format!("Path {:?} escapes the archive.", path));
+        }
+      }
+      Component::RootDir | Component::Prefix(_) => {
+        return Err(format!("Path {:?} is absolute.", path))
+      }
+    }
+  }
+  Ok(normalized)
+}
+
+///
+/// Resolves a symlink in an archive to the file that it (possibly transitively) points to.
+///
+fn resolve_link<'a>(
+  files: &'a HashMap<PathBuf, (Bytes, bool)>,
+  links: &HashMap<PathBuf, PathBuf>,
+  path: &Path,
+) -> Result<&'a (Bytes, bool), String> {
+  let mut current = path.to_owned();
+  for _ in 0..MAX_LINK_DEPTH {
+    let dest = match links.get(&current) {
+      Some(dest) => dest,
+      None => {
+        return files
+          .get(&current)
+          .ok_or_else(|| format!("Symlink {:?} does not point to a file in the archive.", path))
+      }
+    };
+    let parent = current.parent().map(|p| p.to_owned()).unwrap_or_default();
+    current = normalize(&parent.join(dest))?;
+  }
+  Err(format!("Too many levels of symlinks for {:?}", path))
+}
+
+fn snapshot_of_tool(store: Store, name: String, bytes: Bytes) -> BoxFuture<fs::Snapshot, String> {
+  let path = PathBuf::from(name);
+  snapshot_of_entries(
+    store,
+    vec![(
+      path,
+      Entry::File {
+        content: bytes,
+        is_executable: true,
+      },
+    )],
+  )
+}
+
+///
+/// Stores the given entries, and returns a Snapshot of them. Symlinks are replaced by copies of
+/// the files that they point to, because Snapshots cannot contain symlinks.
+///
+fn snapshot_of_entries(
+  store: Store,
+  entries: Vec<(PathBuf, Entry)>,
+) -> BoxFuture<fs::Snapshot, String> {
+  let mut dirs = Vec::new();
+  let mut files = HashMap::new();
+  let mut links = HashMap::new();
+  for (path, entry) in entries {
+    match entry {
+      Entry::Dir => dirs.push(path),
+      Entry::File {
+        content,
+        is_executable,
+      } => {
+        files.insert(path, (content, is_executable));
+      }
+      Entry::Link(dest) => {
+        links.insert(path, dest);
+      }
+    }
+  }
+  let mut file_entries = Vec::new();
+  for path in links.keys() {
+    let resolved = try_future!(resolve_link(&files, &links, path)).clone();
+    file_entries.push((path.clone(), resolved));
+  }
+  file_entries.extend(files.into_iter());
+
+  let stored_files = file_entries
+    .into_iter()
+    .map(|(path, (content, is_executable))| {
+      let size = content.len() as u64;
+      store.store_file_bytes(content, true).map(move |digest| {
+        let file = File {
+          path: path.clone().into(),
+          is_executable: is_executable,
+          size: size,
+        };
+        (PathStat::file(path, file), digest)
+      })
+    })
+    .collect::<Vec<_>>();
+  join_all(stored_files)
+    .and_then(move |stored_files| {
+      let mut path_stats = dirs
+        .into_iter()
+        .map(|path| PathStat::dir(path.clone(), fs::Dir(path.into())))
+        .collect::<Vec<_>>();
+      let mut digests = HashMap::new();
+      for (path_stat, digest) in stored_files {
+        digests.insert(path_stat.path().to_owned(), digest);
+        path_stats.push(path_stat);
+      }
+      fs::Snapshot::from_path_stats(store, StoredFileDigests(Arc::new(digests)), path_stats)
+    })
+    .to_boxed()
+}
+
+///
+/// A StoreFileByDigest for files whose content has already been stored.
+///
+#[derive(Clone)]
+struct StoredFileDigests(Arc<HashMap<PathBuf, Digest>>);
+
+impl StoreFileByDigest<String> for StoredFileDigests {
+  fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+    future::result(
+      self
+        .0
+        .get(file.path.as_path())
+        .cloned()
+        .ok_or_else(|| format!("No stored content for {:?}", file.path)),
+    ).to_boxed()
+  }
+}
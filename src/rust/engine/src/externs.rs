@@ -1,18 +1,21 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::cmp;
 use std::ffi::OsString;
 use std::mem;
 use std::os::raw;
-use std::os::unix::ffi::OsStringExt;
 use std::string::FromUtf8Error;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Once, PoisonError, RwLock, RwLockWriteGuard, ONCE_INIT};
 
-use core::{Failure, Function, Key, TypeConstraint, TypeId, Value};
+use core::{ErrorCategory, Failure, Function, Key, TypeConstraint, TypeId, Value};
 use enum_primitive::FromPrimitive;
+use fs;
 use handles::{DroppingHandle, Handle};
 use interning::Interns;
 use log;
+use log_file;
 
 pub fn eval(python: &str) -> Result<Value, Failure> {
   with_externs(|e| (e.eval)(e.context, python.as_ptr(), python.len() as u64)).into()
@@ -26,6 +29,13 @@ pub fn equals(h1: &Handle, h2: &Handle) -> bool {
   with_externs(|e| (e.equals)(e.context, h1, h2))
 }
 
+///
+/// Returns the truthiness of the given Value, as computed by python's `bool`.
+///
+pub fn truthy(val: &Value) -> bool {
+  with_externs(|e| (e.truthy)(e.context, val as &Handle))
+}
+
 pub fn key_for(val: Value) -> Key {
   let mut interns = INTERNS.write().unwrap();
   interns.insert(val)
@@ -36,6 +46,11 @@ pub fn val_for(key: &Key) -> Value {
   interns.get(key).clone()
 }
 
+pub fn interns_heap_size() -> usize {
+  let interns = INTERNS.read().unwrap();
+  interns.heap_size()
+}
+
 pub fn clone_val(handle: &Handle) -> Handle {
   with_externs(|e| (e.clone_val)(e.context, handle))
 }
@@ -86,6 +101,16 @@ pub fn store_i64(val: i64) -> Value {
   with_externs(|e| (e.store_i64)(e.context, val).into())
 }
 
+pub fn store_bool(val: bool) -> Value {
+  with_externs(|e| {
+    if val {
+      e.py_true.clone()
+    } else {
+      e.py_false.clone()
+    }
+  })
+}
+
 ///
 /// Pulls out the value specified by the field name from a given Value
 ///
@@ -118,6 +143,10 @@ pub fn project_multi_strs(item: &Value, field: &str) -> Vec<String> {
     .collect()
 }
 
+pub fn project_bool(value: &Value, field: &str) -> bool {
+  truthy(&project_ignoring_type(value, field))
+}
+
 pub fn project_str(value: &Value, field: &str) -> String {
   let name_val = with_externs(|e| {
     (e.project_ignoring_type)(
@@ -210,6 +239,19 @@ pub fn unsafe_call(func: &Function, args: &[Value]) -> Value {
   })
 }
 
+///
+/// Locks the interner until the returned guard is dropped, so that a process which is forked while
+/// the guard is held does not inherit the lock held by a thread which does not exist in it.
+///
+pub fn lock_interns_for_fork() -> InternsGuard {
+  InternsGuard(INTERNS.write().unwrap_or_else(PoisonError::into_inner))
+}
+
+///
+/// See `lock_interns_for_fork`.
+///
+pub struct InternsGuard(RwLockWriteGuard<'static, Interns>);
+
 /////////////////////////////////////////////////////////////////////////////////////////
 /// The remainder of this file deals with the static initialization of the Externs.
 /////////////////////////////////////////////////////////////////////////////////////////
@@ -217,18 +259,23 @@ pub fn unsafe_call(func: &Function, args: &[Value]) -> Value {
 lazy_static! {
   static ref EXTERNS: RwLock<Option<Externs>> = RwLock::new(None);
   static ref INTERNS: RwLock<Interns> = RwLock::new(Interns::new());
+  // Per-module overrides of the log level, as pairs of module path and level filter. See
+  // `set_module_log_level`.
+  static ref MODULE_LOG_LEVELS: RwLock<Vec<(String, log::LevelFilter)>> = RwLock::new(Vec::new());
 }
 
-// This is mut so that the max level can be set via set_externs.
-// It should only be set exactly once, and nothing should ever read it (it is only defined to
-// prevent the FfiLogger from being dropped).
-// In order to avoid a performance hit, there is no lock guarding it (because if it had a lock, it
-// would need to be acquired for every single logging statement).
-// Please don't mutate it.
-// Please.
-static mut LOGGER: FfiLogger = FfiLogger {
-  level_filter: log::LevelFilter::Off,
-};
+// True if there are any MODULE_LOG_LEVELS, which allows the logger to avoid acquiring their lock
+// for every logging statement in the common case.
+static HAS_MODULE_LOG_LEVELS: AtomicBool = AtomicBool::new(false);
+
+// The LevelFilters (stored via `as usize`) for records logged to Python and to the engine's log
+// file. Atomics are used (rather than a lock) in order to avoid a performance hit on every single
+// logging statement.
+static PYTHON_LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+static FILE_LOG_LEVEL: AtomicUsize = AtomicUsize::new(0);
+
+static LOGGER: FfiLogger = FfiLogger;
+static INSTALL_LOGGER: Once = ONCE_INIT;
 
 ///
 /// Set the static Externs for this process. All other methods of this module will fail
@@ -238,9 +285,103 @@ pub fn set_externs(externs: Externs) {
   let log_level = externs.log_level;
   let mut externs_ref = EXTERNS.write().unwrap();
   *externs_ref = Some(externs);
-  unsafe {
-    LOGGER.init(log_level);
+  let python_level = PythonLogLevel::from_u8(log_level)
+    .unwrap_or_else(|| panic!("Unrecognised log level from python: {}", log_level));
+  let level_filter: log::LevelFilter = python_level.into();
+  PYTHON_LOG_LEVEL.store(level_filter as usize, Ordering::SeqCst);
+  install_logger();
+}
+
+///
+/// Sets the level of the records that are written to the engine's log file (see `log_file`).
+///
+/// This may be called before `set_externs`, in order to capture records that are logged before
+/// Python logging is available.
+///
+pub fn set_log_file_level(level: u8) -> Result<(), String> {
+  let python_level = PythonLogLevel::from_u8(level)
+    .ok_or_else(|| format!("Unrecognised log level from python: {}", level))?;
+  let level_filter: log::LevelFilter = python_level.into();
+  FILE_LOG_LEVEL.store(level_filter as usize, Ordering::SeqCst);
+  install_logger();
+  Ok(())
+}
+
+///
+/// Installs the FfiLogger (exactly once), and updates the global max level to admit the most
+/// verbose of the configured levels.
+///
+fn install_logger() {
+  INSTALL_LOGGER.call_once(|| {
+    log::set_logger(&LOGGER).expect("Failed to set logger: another logger was already installed.");
+  });
+  update_max_level();
+}
+
+///
+/// The `log` macros discard records that are more verbose than the global max level before
+/// consulting the logger, so the max level must admit the most verbose configured level.
+///
+fn update_max_level() {
+  let module_levels = MODULE_LOG_LEVELS.read().unwrap();
+  log::set_max_level(
+    module_levels
+      .iter()
+      .map(|&(_, level)| level)
+      .fold(
+        cmp::max(
+          level_filter_from_usize(PYTHON_LOG_LEVEL.load(Ordering::SeqCst)),
+          level_filter_from_usize(FILE_LOG_LEVEL.load(Ordering::SeqCst)),
+        ),
+        cmp::max,
+      ),
+  );
+}
+
+fn level_filter_from_usize(level: usize) -> log::LevelFilter {
+  match level {
+    0 => log::LevelFilter::Off,
+    1 => log::LevelFilter::Error,
+    2 => log::LevelFilter::Warn,
+    3 => log::LevelFilter::Info,
+    4 => log::LevelFilter::Debug,
+    _ => log::LevelFilter::Trace,
+  }
+}
+
+///
+/// Overrides the log level for the given module path (for example, `process_execution::remote`)
+/// and all of its submodules, until it is overridden again. A level of NotSet removes the
+/// override.
+///
+pub fn set_module_log_level(module: String, level: u8) -> Result<(), String> {
+  let python_level = PythonLogLevel::from_u8(level)
+    .ok_or_else(|| format!("Unrecognised log level from python: {}", level))?;
+  {
+    let mut module_levels = MODULE_LOG_LEVELS.write().unwrap();
+    module_levels.retain(|&(ref existing, _)| *existing != module);
+    if python_level != PythonLogLevel::NotSet {
+      module_levels.push((module, python_level.into()));
+    }
+    HAS_MODULE_LOG_LEVELS.store(!module_levels.is_empty(), Ordering::SeqCst);
   }
+  update_max_level();
+  Ok(())
+}
+
+///
+/// Returns the level of the most specific module override that applies to the given log target.
+///
+fn module_log_level(target: &str) -> Option<log::LevelFilter> {
+  let module_levels = MODULE_LOG_LEVELS.read().unwrap();
+  module_levels
+    .iter()
+    .filter(|&&(ref module, _)| {
+      target.starts_with(module.as_str())
+        && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+    })
+    .max_by_key(|&&(ref module, _)| module.len())
+    .map(|&(_, level)| level)
 }
 
 fn with_externs<F, T>(f: F) -> T
@@ -266,6 +407,7 @@ pub struct Externs {
   pub eval: EvalExtern,
   pub identify: IdentifyExtern,
   pub equals: EqualsExtern,
+  pub truthy: TruthyExtern,
   pub clone_val: CloneValExtern,
   pub drop_handles: DropHandlesExtern,
   pub satisfied_by: SatisfiedByExtern,
@@ -281,6 +423,9 @@ pub struct Externs {
   pub create_exception: CreateExceptionExtern,
   // TODO: This type is also declared on `types::Types`.
   pub py_str_type: TypeId,
+  // The python `True` and `False` singletons, which `store_bool` shares rather than creating.
+  pub py_true: Value,
+  pub py_false: Value,
 }
 
 // The pointer to the context is safe for sharing between threads.
@@ -299,6 +444,8 @@ pub type IdentifyExtern = extern "C" fn(*const ExternContext, *const Handle) ->
 
 pub type EqualsExtern = extern "C" fn(*const ExternContext, *const Handle, *const Handle) -> bool;
 
+pub type TruthyExtern = extern "C" fn(*const ExternContext, *const Handle) -> bool;
+
 pub type CloneValExtern = extern "C" fn(*const ExternContext, *const Handle) -> Handle;
 
 pub type DropHandlesExtern = extern "C" fn(*const ExternContext, *const DroppingHandle, u64);
@@ -325,7 +472,7 @@ pub struct PyResult {
 impl PyResult {
   fn failure_from(v: Value) -> Failure {
     let traceback = project_str(&v, "_formatted_exc");
-    Failure::Throw(v, traceback)
+    Failure::Throw(v, traceback, Vec::new(), ErrorCategory::UserRule)
   }
 }
 
@@ -449,6 +596,21 @@ impl TypeIdBuffer {
   }
 }
 
+// Points to an array of TypeConstraints.
+#[repr(C)]
+pub struct TypeConstraintBuffer {
+  constraints_ptr: *mut TypeConstraint,
+  constraints_len: u64,
+  // A Handle to hold the underlying array alive.
+  handle_: Handle,
+}
+
+impl TypeConstraintBuffer {
+  pub fn to_vec(&self) -> Vec<TypeConstraint> {
+    with_vec(self.constraints_ptr, self.constraints_len as usize, |vec| vec.clone())
+  }
+}
+
 pub type ProjectIgnoringTypeExtern = extern "C" fn(
   *const ExternContext,
   *const Handle,
@@ -477,7 +639,7 @@ impl Buffer {
   }
 
   pub fn to_os_string(&self) -> OsString {
-    OsString::from_vec(self.to_bytes())
+    fs::os::os_string_from_bytes(self.to_bytes())
   }
 
   pub fn to_string(&self) -> Result<String, FromUtf8Error> {
@@ -510,7 +672,7 @@ impl BufferBuffer {
     self
       .to_bytes_vecs()
       .into_iter()
-      .map(OsString::from_vec)
+      .map(fs::os::os_string_from_bytes)
       .collect()
   }
 
@@ -593,52 +755,51 @@ impl From<PythonLogLevel> for log::LevelFilter {
 }
 
 ///
-/// FfiLogger is an implementation of log::Log which asks the Python logging system to log via cffi.
+/// FfiLogger is an implementation of log::Log which asks the Python logging system to log via cffi,
+/// and which also writes to the engine's own log file (see `log_file`).
 ///
-struct FfiLogger {
-  level_filter: log::LevelFilter,
-}
+struct FfiLogger;
 
 impl FfiLogger {
-  // init must only be called once in the lifetime of the program. No other loggers may be init'd.
-  // If either of the above are violated, expect a panic.
-  pub fn init(&'static mut self, max_level: u8) {
-    let max_python_level = PythonLogLevel::from_u8(max_level);
-    self.level_filter = {
-      match max_python_level {
-        Some(python_level) => {
-          let level: log::LevelFilter = python_level.into();
-          level
-        }
-        None => panic!("Unrecognised log level from python: {}", max_level),
-      }
+  fn python_enabled(&self, metadata: &log::Metadata) -> bool {
+    let base_level = level_filter_from_usize(PYTHON_LOG_LEVEL.load(Ordering::Relaxed));
+    let level_filter = if HAS_MODULE_LOG_LEVELS.load(Ordering::Relaxed) {
+      module_log_level(metadata.target()).unwrap_or(base_level)
+    } else {
+      base_level
     };
+    metadata.level() <= level_filter
+  }
 
-    log::set_max_level(self.level_filter);
-    log::set_logger(self)
-      .expect("Failed to set logger (maybe you tried to call init multiple times?)");
+  fn file_enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= level_filter_from_usize(FILE_LOG_LEVEL.load(Ordering::Relaxed))
   }
 }
 
 impl log::Log for FfiLogger {
   fn enabled(&self, metadata: &log::Metadata) -> bool {
-    metadata.level() <= self.level_filter
+    self.python_enabled(metadata) || self.file_enabled(metadata)
   }
 
   fn log(&self, record: &log::Record) {
-    if !self.enabled(record.metadata()) {
+    if self.file_enabled(record.metadata()) {
+      log_file::write(record);
+    }
+    if !self.python_enabled(record.metadata()) {
       return;
     }
     let level: PythonLogLevel = record.level().into();
     let message = format!("{}", record.args());
-    with_externs(|e| {
+    // Records may be logged to the log file before the Externs have been set.
+    let externs_opt = EXTERNS.read().unwrap();
+    if let Some(ref e) = *externs_opt {
       (e.log)(
         e.context,
         level as u8,
         message.as_ptr(),
         message.len() as u64,
       )
-    })
+    }
   }
 
   fn flush(&self) {}
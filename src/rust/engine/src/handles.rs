@@ -91,3 +91,14 @@ pub fn maybe_drop_handles() {
     externs::drop_handles(&handles);
   }
 }
+
+///
+/// Drops all queued Handles, however few there are. Returns the number of Handles dropped.
+///
+pub fn drop_all_handles() -> usize {
+  let handles = DROPPING_HANDLES.lock().unwrap().drain(..).collect::<Vec<_>>();
+  if !handles.is_empty() {
+    externs::drop_handles(&handles);
+  }
+  handles.len()
+}
@@ -0,0 +1,110 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::future::{self, Future};
+use regex::Regex;
+
+use boxfuture::{BoxFuture, Boxable};
+use fs::{FileContent, ResettablePool};
+
+lazy_static! {
+  // `import a.b, c as d` (the names are split apart below).
+  static ref PYTHON_IMPORT: Regex = Regex::new(concat!(
+    r"(?m)^[ \t]*import[ \t]+",
+    r"([\w.]+(?:[ \t]+as[ \t]+\w+)?(?:[ \t]*,[ \t]*[\w.]+(?:[ \t]+as[ \t]+\w+)?)*)"
+  )).unwrap();
+  // `from a.b import c`, including relative imports (`from ..a import b`).
+  static ref PYTHON_FROM_IMPORT: Regex =
+    Regex::new(r"(?m)^[ \t]*from[ \t]+(\.*[\w.]*)[ \t]+import\b").unwrap();
+  // `import a.b.C;`, `import static a.b.C.d;`, `import a.b.*;` and `import a.b._` (Scala).
+  static ref JVM_IMPORT: Regex =
+    Regex::new(r"(?m)^[ \t]*import[ \t]+(?:static[ \t]+)?([\w.]*\w(?:\.\*|\._)?)").unwrap();
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Language {
+  Python,
+  Jvm,
+}
+
+impl Language {
+  pub fn create(language: &str) -> Result<Language, String> {
+    match language {
+      "python" => Ok(Language::Python),
+      "jvm" => Ok(Language::Jvm),
+      _ => Err(format!(
+        "Unrecognized language: {}. Supported languages are python and jvm.",
+        language
+      )),
+    }
+  }
+}
+
+///
+/// Scans each of the given files for import statements in parallel, and returns the imported
+/// names of each file, in the order in which they appear.
+///
+/// Scanning matches import statements using regular expressions rather than parsing the files, so
+/// it is approximate: in particular, import statements inside of multi-line strings are
+/// (incorrectly) reported, and imports which are computed at runtime are not.
+///
+pub fn scan_imports(
+  pool: Arc<ResettablePool>,
+  files: Vec<FileContent>,
+  language: Language,
+) -> BoxFuture<Vec<(PathBuf, Vec<String>)>, String> {
+  future::join_all(
+    files
+      .into_iter()
+      .map(|file| {
+        pool.spawn_fn(move || {
+          let source = String::from_utf8_lossy(&file.content).into_owned();
+          let imports = match language {
+            Language::Python => python_imports(&source),
+            Language::Jvm => jvm_imports(&source),
+          };
+          Ok::<_, String>((file.path, imports))
+        })
+      })
+      .collect::<Vec<_>>(),
+  ).to_boxed()
+}
+
+fn python_imports(source: &str) -> Vec<String> {
+  let mut imports = Vec::new();
+  // Collect the matches of both patterns in the order that they occur in the file.
+  let mut matches = PYTHON_IMPORT
+    .captures_iter(source)
+    .map(|c| (c.get(1).unwrap(), false))
+    .chain(
+      PYTHON_FROM_IMPORT
+        .captures_iter(source)
+        .map(|c| (c.get(1).unwrap(), true)),
+    )
+    .collect::<Vec<_>>();
+  matches.sort_by_key(|&(m, _)| m.start());
+  for (m, is_from) in matches {
+    if is_from {
+      imports.push(m.as_str().to_owned());
+    } else {
+      // Drop the aliases of `import a as b, c as d`.
+      imports.extend(
+        m.as_str()
+          .split(',')
+          .filter_map(|name| name.split_whitespace().next())
+          .map(|name| name.to_owned()),
+      );
+    }
+  }
+  imports
+}
+
+fn jvm_imports(source: &str) -> Vec<String> {
+  JVM_IMPORT
+    .captures_iter(source)
+    .map(|c| c[1].to_owned())
+    .collect()
+}
@@ -3,6 +3,7 @@
 
 use std::collections::HashMap;
 use std::hash;
+use std::mem;
 
 use core::{Key, Value, FNV};
 use externs;
@@ -69,6 +70,15 @@ impl Interns {
       .get(&k)
       .unwrap_or_else(|| panic!("Previously memoized object disappeared for {:?}", k))
   }
+
+  ///
+  /// An estimate of the bytes of heap memory held by the Interns, excluding the python objects
+  /// that its Values refer to.
+  ///
+  pub fn heap_size(&self) -> usize {
+    self.forward.capacity() * mem::size_of::<(InternKey, Key)>()
+      + self.reverse.capacity() * mem::size_of::<(Key, Value)>()
+  }
 }
 
 struct InternKey(i64, Value);
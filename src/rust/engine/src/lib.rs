@@ -2,60 +2,94 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 pub mod cffi_externs;
+mod build_file_parser;
 mod context;
 mod core;
+mod download;
 mod externs;
 mod handles;
+mod imports;
 mod interning;
+mod log_file;
+mod memory;
 mod nodes;
+mod panics;
+mod plugins;
+#[cfg(unix)]
+mod profiler;
+#[cfg(not(unix))]
+#[path = "profiler_unsupported.rs"]
+mod profiler;
+mod query_server;
 mod rule_graph;
 mod scheduler;
+mod scm;
 mod selectors;
+mod snapshot_cache;
 mod tasks;
 mod types;
 
+extern crate backtrace;
 #[macro_use]
 extern crate boxfuture;
+extern crate bytes;
 #[macro_use]
 extern crate enum_primitive;
+extern crate flate2;
 extern crate fnv;
 extern crate fs;
 extern crate futures;
+extern crate git2;
 extern crate graph;
+extern crate grpcio;
 extern crate hashing;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate num_cpus;
+extern crate plugin_api;
 extern crate process_execution;
+extern crate query_protos;
+extern crate regex;
+extern crate reqwest;
 extern crate resettable;
+extern crate tar;
+extern crate tempfile;
 extern crate tokio;
+extern crate tokio_threadpool;
 
+use std::cell::RefCell;
 use std::ffi::CStr;
 use std::fs::File;
 use std::io;
 use std::mem;
 use std::os::raw;
-use std::panic;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
-use context::Core;
+use context::{BackgroundPolicy, Core, DeterminismSampler, ForkGuards, RetryPolicy, StallPolicy};
 use core::{Failure, Function, Key, TypeConstraint, TypeId, Value};
 use externs::{
   Buffer, BufferBuffer, CallExtern, CloneValExtern, CreateExceptionExtern, DropHandlesExtern,
   EqualsExtern, EvalExtern, ExternContext, Externs, GeneratorSendExtern, IdentifyExtern, LogExtern,
   ProjectIgnoringTypeExtern, ProjectMultiExtern, PyResult, SatisfiedByExtern,
   SatisfiedByTypeExtern, StoreBytesExtern, StoreI64Extern, StoreTupleExtern, StoreUtf8Extern,
-  TypeIdBuffer, TypeToStrExtern, ValToStrExtern,
+  TruthyExtern, TypeConstraintBuffer, TypeIdBuffer, TypeToStrExtern, ValToStrExtern,
 };
 use futures::Future;
+use graph::{GraphLimits, Limit, TraceOptions};
 use handles::Handle;
-use hashing::Digest;
+use hashing::{Digest, Fingerprint};
+use process_execution::capture::{OutputLimit, OutputOverflow};
+use process_execution::workunits::WorkUnitEvent;
+use query_server::QueryServer;
 use rule_graph::{GraphMaker, RuleGraph};
-use scheduler::{ExecutionRequest, RootResult, Scheduler, Session};
+use scheduler::{ExecutionRequest, RootCallback, RootResult, Scheduler, Session};
 use tasks::Tasks;
-use types::Types;
+use types::{FieldKind, Types, CONSTRUCTOR_SCHEMAS};
 
 #[repr(C)]
 enum RawStateTag {
@@ -63,39 +97,71 @@ enum RawStateTag {
   Throw = 2,
   Noop = 3,
   Invalidated = 4,
+  Cancelled = 5,
 }
 
 #[repr(C)]
 pub struct RawNode {
-  subject: Key,
-  product: TypeConstraint,
-  // The Handle represents a union tagged with RawStateTag.
+  // The Handle represents a union tagged with RawStateTag. For failures, it is a tuple of the
+  // exception, its engine traceback, and its ErrorCategory.
   state_tag: u8,
   state_handle: Handle,
 }
 
 impl RawNode {
-  fn create(subject: &Key, product: &TypeConstraint, state: RootResult) -> RawNode {
-    let (state_tag, state_value) = match state {
-      Ok(v) => (RawStateTag::Return as u8, v),
-      Err(Failure::Throw(exc, _)) => (RawStateTag::Throw as u8, exc),
-      Err(Failure::Noop(noop)) => (
-        RawStateTag::Noop as u8,
-        externs::create_exception(&format!("{:?}", noop)),
-      ),
-      Err(Failure::Invalidated) => (
-        RawStateTag::Invalidated as u8,
-        externs::create_exception("Exhausted retries due to changed files."),
-      ),
-    };
+  fn create(state: RootResult) -> RawNode {
+    let (state_tag, state_value) = RawNode::state(&state);
 
     RawNode {
-      subject: subject.clone(),
-      product: product.clone(),
       state_tag: state_tag,
       state_handle: state_value.into(),
     }
   }
+
+  ///
+  /// Converts a RootResult into a RawStateTag and a Value representing the state.
+  ///
+  fn state(state: &RootResult) -> (u8, Value) {
+    let failure = match state {
+      &Ok(ref v) => return (RawStateTag::Return as u8, v.clone()),
+      &Err(ref failure) => failure,
+    };
+    let (state_tag, exc, engine_traceback) = match failure {
+      &Failure::Throw(ref exc, _, ref engine_traceback, _) => (
+        RawStateTag::Throw,
+        exc.clone(),
+        // Outermost Node first, to match the order of a Python traceback.
+        engine_traceback
+          .iter()
+          .rev()
+          .map(|node| externs::store_utf8(node))
+          .collect::<Vec<_>>(),
+      ),
+      &Failure::Noop(noop) => (
+        RawStateTag::Noop,
+        externs::create_exception(&format!("{:?}", noop)),
+        vec![],
+      ),
+      &Failure::Invalidated => (
+        RawStateTag::Invalidated,
+        externs::create_exception("Exhausted retries due to changed files."),
+        vec![],
+      ),
+      &Failure::Cancelled(ref message) => (
+        RawStateTag::Cancelled,
+        externs::create_exception(message),
+        vec![],
+      ),
+    };
+    (
+      state_tag as u8,
+      externs::store_tuple(&[
+        exc,
+        externs::store_tuple(&engine_traceback),
+        externs::store_utf8(failure.category().as_str()),
+      ]),
+    )
+  }
 }
 
 #[repr(C)]
@@ -106,11 +172,8 @@ pub struct RawNodes {
 }
 
 impl RawNodes {
-  fn create(node_states: Vec<(&Key, &TypeConstraint, RootResult)>) -> Box<RawNodes> {
-    let nodes = node_states
-      .into_iter()
-      .map(|(subject, product, state)| RawNode::create(subject, product, state))
-      .collect();
+  fn create(node_states: Vec<RootResult>) -> Box<RawNodes> {
+    let nodes = node_states.into_iter().map(RawNode::create).collect();
     let mut raw_nodes = Box::new(RawNodes {
       nodes_ptr: Vec::new().as_ptr(),
       nodes_len: 0,
@@ -133,6 +196,7 @@ pub extern "C" fn externs_set(
   eval: EvalExtern,
   identify: IdentifyExtern,
   equals: EqualsExtern,
+  truthy: TruthyExtern,
   clone_val: CloneValExtern,
   drop_handles: DropHandlesExtern,
   type_to_str: TypeToStrExtern,
@@ -147,6 +211,8 @@ pub extern "C" fn externs_set(
   project_multi: ProjectMultiExtern,
   create_exception: CreateExceptionExtern,
   py_str_type: TypeId,
+  py_true: Handle,
+  py_false: Handle,
 ) {
   externs::set_externs(Externs {
     context,
@@ -157,6 +223,7 @@ pub extern "C" fn externs_set(
     eval,
     identify,
     equals,
+    truthy,
     clone_val,
     drop_handles,
     type_to_str,
@@ -171,6 +238,8 @@ pub extern "C" fn externs_set(
     project_multi,
     create_exception,
     py_str_type,
+    py_true: py_true.into(),
+    py_false: py_false.into(),
   });
 }
 
@@ -202,13 +271,38 @@ pub extern "C" fn scheduler_create(
   construct_file: Function,
   construct_link: Function,
   construct_process_result: Function,
+  construct_lazy_bytes: Function,
+  construct_changed_files: Function,
+  construct_address_names: Function,
+  construct_source_imports: Function,
+  construct_file_size_summary: Function,
+  construct_environment: Function,
+  construct_digest_equality: Function,
+  construct_platform: Function,
   type_address: TypeConstraint,
   type_has_products: TypeConstraint,
   type_has_variants: TypeConstraint,
   type_path_globs: TypeConstraint,
   type_directory_digest: TypeConstraint,
   type_snapshot: TypeConstraint,
+  type_file_content: TypeConstraint,
   type_files_content: TypeConstraint,
+  type_file_range: TypeConstraint,
+  type_binary_tool_to_fetch: TypeConstraint,
+  type_changed_files_request: TypeConstraint,
+  type_changed_files: TypeConstraint,
+  type_path_globs_at_revision: TypeConstraint,
+  type_address_names_request: TypeConstraint,
+  type_address_names: TypeConstraint,
+  type_source_imports_request: TypeConstraint,
+  type_source_imports: TypeConstraint,
+  type_file_size_summary: TypeConstraint,
+  type_environment_request: TypeConstraint,
+  type_environment: TypeConstraint,
+  type_checksums_request: TypeConstraint,
+  type_digest_equality_request: TypeConstraint,
+  type_digest_equality: TypeConstraint,
+  type_platform: TypeConstraint,
   type_dir: TypeConstraint,
   type_file: TypeConstraint,
   type_link: TypeConstraint,
@@ -226,8 +320,43 @@ pub extern "C" fn scheduler_create(
   remote_store_thread_count: u64,
   remote_store_chunk_bytes: u64,
   remote_store_chunk_upload_timeout_seconds: u64,
+  remote_store_metadata_timeout_seconds: u64,
+  remote_store_write_through_buf: Buffer,
+  remote_store_max_concurrent_transfers: u64,
+  remote_store_max_bytes_per_second: u64,
   process_execution_parallelism: u64,
   process_execution_cleanup_local_dirs: bool,
+  process_termination_grace_period_seconds: u64,
+  process_execution_sandbox_pool_size: u64,
+  process_execution_max_output_bytes: u64,
+  process_execution_output_overflow_buf: Buffer,
+  process_execution_inherited_env_buf: BufferBuffer,
+  process_execution_macos_sandbox: bool,
+  process_execution_macos_sandbox_cache_dirs_buf: BufferBuffer,
+  process_execution_record_dir_buf: Buffer,
+  process_execution_replay_dir_buf: Buffer,
+  process_execution_cache_dir_buf: Buffer,
+  remote_execution_speculation_delay_ms: u64,
+  transient_failure_retry_attempts: u64,
+  transient_failure_retry_backoff_ms: u64,
+  nondeterministic_rule_check_rate: f64,
+  stall_timeout_seconds: u64,
+  cancel_stalled_executions: bool,
+  background_process_execution_parallelism: u64,
+  background_io_parallelism: u64,
+  graph_node_count_warning: u64,
+  graph_node_count_limit: u64,
+  graph_depth_warning: u64,
+  graph_depth_limit: u64,
+  special_file_behavior_buf: Buffer,
+  link_escape_behavior_buf: Buffer,
+  snapshot_cache_dir_buf: Buffer,
+  engine_temp_dir_buf: Buffer,
+  materialize_hardlinks: bool,
+  local_store_shard_count: u64,
+  local_store_max_shard_bytes: u64,
+  thread_name_prefix_buf: Buffer,
+  use_fake_command_runner: bool,
 ) -> *const Scheduler {
   let root_type_ids = root_type_ids.to_vec();
   let ignore_patterns = ignore_patterns_buf
@@ -243,13 +372,38 @@ pub extern "C" fn scheduler_create(
     construct_file: construct_file,
     construct_link: construct_link,
     construct_process_result: construct_process_result,
+    construct_lazy_bytes: construct_lazy_bytes,
+    construct_changed_files: construct_changed_files,
+    construct_address_names: construct_address_names,
+    construct_source_imports: construct_source_imports,
+    construct_file_size_summary: construct_file_size_summary,
+    construct_environment: construct_environment,
+    construct_digest_equality: construct_digest_equality,
+    construct_platform: construct_platform,
     address: type_address,
     has_products: type_has_products,
     has_variants: type_has_variants,
     path_globs: type_path_globs,
     directory_digest: type_directory_digest,
     snapshot: type_snapshot,
+    file_content: type_file_content,
     files_content: type_files_content,
+    file_range: type_file_range,
+    binary_tool_to_fetch: type_binary_tool_to_fetch,
+    changed_files_request: type_changed_files_request,
+    changed_files: type_changed_files,
+    path_globs_at_revision: type_path_globs_at_revision,
+    address_names_request: type_address_names_request,
+    address_names: type_address_names,
+    source_imports_request: type_source_imports_request,
+    source_imports: type_source_imports,
+    file_size_summary: type_file_size_summary,
+    environment_request: type_environment_request,
+    environment: type_environment,
+    checksums_request: type_checksums_request,
+    digest_equality_request: type_digest_equality_request,
+    digest_equality: type_digest_equality,
+    platform: type_platform,
     dir: type_dir,
     file: type_file,
     link: type_link,
@@ -261,6 +415,20 @@ pub extern "C" fn scheduler_create(
   };
   let mut tasks = with_tasks(tasks_ptr, |tasks| tasks.clone());
   tasks.intrinsics_set(&types);
+  // The Platform is computed natively rather than by rules, and cannot change during the lifetime of
+  // the Scheduler, so it is installed as a singleton.
+  let platform = process_execution::platform::Platform::current();
+  tasks.singleton_add(
+    externs::unsafe_call(
+      &types.construct_platform,
+      &[
+        externs::store_utf8(platform.os),
+        externs::store_utf8(platform.arch),
+        externs::store_utf8(platform.libc),
+      ],
+    ),
+    types.platform,
+  );
   // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
   let remote_store_server_string = remote_store_server
     .to_string()
@@ -268,12 +436,69 @@ pub extern "C" fn scheduler_create(
   let remote_execution_server_string = remote_execution_server
     .to_string()
     .expect("remote_execution_server was not valid UTF8");
-  Box::into_raw(Box::new(Scheduler::new(Core::new(
+  let special_file_behavior = special_file_behavior_buf
+    .to_string()
+    .map_err(|e| format!("{:?}", e))
+    .and_then(|behavior| fs::SpecialFileBehavior::create(&behavior))
+    .unwrap_or_else(|e| panic!("Invalid special_file_behavior: {}", e));
+  let link_escape_behavior = link_escape_behavior_buf
+    .to_string()
+    .map_err(|e| format!("{:?}", e))
+    .and_then(|behavior| fs::LinkEscapeBehavior::create(&behavior))
+    .unwrap_or_else(|e| panic!("Invalid link_escape_behavior: {}", e));
+  let remote_store_write_through = remote_store_write_through_buf
+    .to_string()
+    .map_err(|e| format!("{:?}", e))
+    .and_then(|write_through| fs::WriteThrough::create(&write_through))
+    .unwrap_or_else(|e| panic!("Invalid remote_store_write_through: {}", e));
+  let local_store_limits = fs::LocalStoreLimits::create(
+    local_store_shard_count as usize,
+    local_store_max_shard_bytes as usize,
+  ).unwrap_or_else(|e| panic!("Invalid local store limits: {}", e));
+  let process_execution_output_limit = process_execution_output_overflow_buf
+    .to_string()
+    .map_err(|e| format!("{:?}", e))
+    .and_then(|overflow| OutputOverflow::create(&overflow))
+    .map(|overflow| OutputLimit {
+      max_bytes: if process_execution_max_output_bytes == 0 {
+        None
+      } else {
+        Some(process_execution_max_output_bytes as usize)
+      },
+      overflow,
+    })
+    .unwrap_or_else(|e| panic!("Invalid process_execution_output_overflow: {}", e));
+  let process_execution_inherited_env = process_execution_inherited_env_buf
+    .to_strings()
+    .unwrap_or_else(|e| panic!("Failed to decode inherited env var names as UTF8: {:?}", e))
+    .into_iter()
+    .collect();
+  let process_execution_macos_sandbox = if process_execution_macos_sandbox {
+    Some(
+      process_execution_macos_sandbox_cache_dirs_buf
+        .to_os_strings()
+        .into_iter()
+        .map(PathBuf::from)
+        .collect(),
+    )
+  } else {
+    None
+  };
+  Arc::into_raw(Arc::new(Scheduler::new(Core::new(
     root_type_ids.clone(),
     tasks,
     types,
     build_root_buf.to_os_string().as_ref(),
     ignore_patterns,
+    special_file_behavior,
+    link_escape_behavior,
+    optional_path(snapshot_cache_dir_buf),
+    optional_path(engine_temp_dir_buf),
+    materialize_hardlinks,
+    local_store_limits,
+    thread_name_prefix_buf
+      .to_string()
+      .expect("thread_name_prefix was not valid UTF8"),
     PathBuf::from(work_dir_buf.to_os_string()),
     if remote_store_server_string.is_empty() {
       None
@@ -287,12 +512,114 @@ pub extern "C" fn scheduler_create(
     },
     remote_store_thread_count as usize,
     remote_store_chunk_bytes as usize,
-    Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
+    fs::RemoteTimeouts {
+      metadata: Duration::from_secs(remote_store_metadata_timeout_seconds),
+      transfer: Duration::from_secs(remote_store_chunk_upload_timeout_seconds),
+    },
+    remote_store_write_through,
+    fs::RemoteTransferLimits {
+      concurrency: optional_limit(remote_store_max_concurrent_transfers),
+      bytes_per_second: optional_limit(remote_store_max_bytes_per_second),
+    },
     process_execution_parallelism as usize,
     process_execution_cleanup_local_dirs as bool,
+    Duration::from_secs(process_termination_grace_period_seconds),
+    process_execution_sandbox_pool_size as usize,
+    process_execution_output_limit,
+    process_execution_inherited_env,
+    process_execution_macos_sandbox,
+    optional_path(process_execution_record_dir_buf),
+    optional_path(process_execution_replay_dir_buf),
+    optional_path(process_execution_cache_dir_buf),
+    if remote_execution_speculation_delay_ms == 0 {
+      None
+    } else {
+      Some(Duration::from_millis(remote_execution_speculation_delay_ms))
+    },
+    RetryPolicy {
+      attempts: transient_failure_retry_attempts as usize,
+      backoff: Duration::from_millis(transient_failure_retry_backoff_ms),
+    },
+    DeterminismSampler::new(nondeterministic_rule_check_rate),
+    StallPolicy {
+      timeout: if stall_timeout_seconds == 0 {
+        None
+      } else {
+        Some(Duration::from_secs(stall_timeout_seconds))
+      },
+      cancel: cancel_stalled_executions,
+    },
+    BackgroundPolicy {
+      process_execution_parallelism: optional_limit(background_process_execution_parallelism),
+      io_parallelism: optional_limit(background_io_parallelism),
+    },
+    GraphLimits {
+      node_count: Limit {
+        warn: optional_limit(graph_node_count_warning),
+        fail: optional_limit(graph_node_count_limit),
+      },
+      depth: Limit {
+        warn: optional_limit(graph_depth_warning),
+        fail: optional_limit(graph_depth_limit),
+      },
+    },
+    use_fake_command_runner,
   ))))
 }
 
+// We can't currently pass Options over the FFI, so zero represents no limit.
+fn optional_limit(limit: u64) -> Option<usize> {
+  if limit == 0 {
+    None
+  } else {
+    Some(limit as usize)
+  }
+}
+
+// We can't currently pass Options over the FFI, so empty paths represent None.
+fn optional_path(path_buf: Buffer) -> Option<PathBuf> {
+  let path = path_buf.to_os_string();
+  if path.is_empty() {
+    None
+  } else {
+    Some(PathBuf::from(path))
+  }
+}
+
+///
+/// Returns a Handle representing a tuple of (constructor name, fields) pairs, where the fields are
+/// a tuple of (field name, kind, constructor names) triples. See `types::CONSTRUCTOR_SCHEMAS`.
+///
+#[no_mangle]
+pub extern "C" fn constructor_schemas() -> Handle {
+  let schemas = CONSTRUCTOR_SCHEMAS
+    .iter()
+    .map(|&(constructor, fields)| {
+      let fields = fields
+        .iter()
+        .map(|&(field, kind)| {
+          let constructors: Vec<_> = match kind {
+            FieldKind::Product(constructors) => {
+              constructors.iter().map(|c| externs::store_utf8(c)).collect()
+            }
+            _ => vec![],
+          };
+          externs::store_tuple(&[
+            externs::store_utf8(field),
+            externs::store_utf8(kind.name()),
+            externs::store_tuple(&constructors),
+          ])
+        })
+        .collect::<Vec<_>>();
+      externs::store_tuple(&[
+        externs::store_utf8(constructor),
+        externs::store_tuple(&fields),
+      ])
+    })
+    .collect::<Vec<_>>();
+  externs::store_tuple(&schemas).into()
+}
+
 ///
 /// Returns a Handle representing a tuple of tuples of metric name string and metric value int.
 ///
@@ -318,13 +645,236 @@ pub extern "C" fn scheduler_metrics(
   })
 }
 
+#[no_mangle]
+pub extern "C" fn scheduler_heap_usage(scheduler_ptr: *mut Scheduler) -> Handle {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let values = scheduler
+      .heap_usage()
+      .into_iter()
+      .map(|(name, bytes)| {
+        externs::store_tuple(&[
+          externs::store_utf8(&name),
+          externs::store_i64(bytes as i64),
+        ])
+      })
+      .collect::<Vec<_>>();
+    externs::store_tuple(&values).into()
+  })
+}
+
+///
+/// Takes the events for processes run by the Scheduler which have been buffered since the last
+/// call. Returns a Handle representing a tuple of a tuple of events, and the number of events
+/// which were dropped because they were not taken in time. Each event is a tuple whose first two
+/// elements are its kind and the id of its process, followed by:
+///   `started`: the description of the process, the fingerprint and size of its input files, and
+///     the id of the process on whose behalf it is a speculative run (or -1 if it is not one: see
+///     `WorkUnit::child`), which python reports as a `parent_id` of None.
+///   `first_output`: nothing.
+///   `completed`: the exit code of the process, the source of its result, the bytes that it
+///     wrote into its sandbox, and the growth of the shared caches while it ran (or -1 for either
+///     if they are unknown).
+///   `failed`: the error which prevented the process from running.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_workunit_events(scheduler_ptr: *mut Scheduler) -> Handle {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let (events, dropped) = scheduler.core.workunits.take_events();
+    let values = events
+      .into_iter()
+      .map(|event| match event {
+        WorkUnitEvent::Started {
+          id,
+          parent_id,
+          description,
+          input_files,
+        } => externs::store_tuple(&[
+          externs::store_utf8("started"),
+          externs::store_i64(id as i64),
+          externs::store_utf8(&description),
+          externs::store_utf8(&input_files.0.to_hex()),
+          externs::store_i64(input_files.1 as i64),
+          externs::store_i64(parent_id.map(|id| id as i64).unwrap_or(-1)),
+        ]),
+        WorkUnitEvent::FirstOutput { id } => externs::store_tuple(&[
+          externs::store_utf8("first_output"),
+          externs::store_i64(id as i64),
+        ]),
+        WorkUnitEvent::Completed {
+          id,
+          exit_code,
+          source,
+          disk_bytes_written,
+          cache_bytes_written,
+        } => externs::store_tuple(&[
+          externs::store_utf8("completed"),
+          externs::store_i64(id as i64),
+          externs::store_i64(i64::from(exit_code)),
+          externs::store_utf8(source.name()),
+          externs::store_i64(disk_bytes_written.map(|bytes| bytes as i64).unwrap_or(-1)),
+          externs::store_i64(cache_bytes_written.map(|bytes| bytes as i64).unwrap_or(-1)),
+        ]),
+        WorkUnitEvent::Failed { id, error } => externs::store_tuple(&[
+          externs::store_utf8("failed"),
+          externs::store_i64(id as i64),
+          externs::store_utf8(&error),
+        ]),
+      })
+      .collect::<Vec<_>>();
+    externs::store_tuple(&[
+      externs::store_tuple(&values),
+      externs::store_i64(dropped as i64),
+    ]).into()
+  })
+}
+
+///
+/// Terminates any processes that are being executed on behalf of the Scheduler: they are sent
+/// SIGTERM, and then SIGKILL if they have not exited within the termination grace period.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_terminate_processes(scheduler_ptr: *mut Scheduler) {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler.core.command_runner.shutdown();
+  })
+}
+
+///
+/// Shuts down the Scheduler (see `Scheduler::shutdown`), giving running executions the given
+/// number of milliseconds to complete. Returns a tuple of the number of executions which were
+/// cancelled, the ids of the processes which were killed, and any errors encountered.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_shutdown(scheduler_ptr: *mut Scheduler, timeout_ms: u64) -> Handle {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let report = scheduler.shutdown(Duration::from_millis(timeout_ms));
+    externs::store_tuple(&[
+      externs::store_i64(report.cancelled_executions as i64),
+      externs::store_tuple(
+        &report
+          .killed_processes
+          .iter()
+          .map(|&pid| externs::store_i64(i64::from(pid)))
+          .collect::<Vec<_>>(),
+      ),
+      externs::store_tuple(
+        &report
+          .errors
+          .iter()
+          .map(|error| externs::store_utf8(error))
+          .collect::<Vec<_>>(),
+      ),
+    ]).into()
+  })
+}
+
+///
+/// Returns an empty tuple if the Scheduler was not trimmed, and otherwise a tuple of the fields of
+/// the TrimReport, in which the optional `rss_bytes` is a tuple of zero or one items.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_trim_memory(scheduler_ptr: *mut Scheduler, min_idle_ms: u64) -> Handle {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    match scheduler.trim_memory(Duration::from_millis(min_idle_ms)) {
+      Some(report) => externs::store_tuple(&[
+        externs::store_i64(report.evicted_nodes as i64),
+        externs::store_i64(report.released_values as i64),
+        externs::store_i64(report.graph_bytes as i64),
+        externs::store_i64(report.store_bytes as i64),
+        externs::store_tuple(
+          &report
+            .rss_bytes
+            .iter()
+            .map(|&bytes| externs::store_i64(bytes as i64))
+            .collect::<Vec<_>>(),
+        ),
+      ]),
+      None => externs::store_tuple(&[]),
+    }.into()
+  })
+}
+
+///
+/// Returns the generation that was persisted along with the snapshot cache, or an empty string if
+/// there is none (or the snapshot cache is disabled).
+///
+#[no_mangle]
+pub extern "C" fn scheduler_snapshot_cache_generation(scheduler_ptr: *mut Scheduler) -> Handle {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let generation = scheduler
+      .core
+      .snapshot_cache
+      .as_ref()
+      .and_then(|cache| cache.persisted_generation())
+      .unwrap_or_default();
+    externs::store_utf8(&generation).into()
+  })
+}
+
+///
+/// Sets (and persists) the generation under which Snapshots are cached, if the snapshot cache is
+/// enabled.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_set_snapshot_cache_generation(
+  scheduler_ptr: *mut Scheduler,
+  generation_buf: Buffer,
+) {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    if let Some(ref cache) = scheduler.core.snapshot_cache {
+      let generation = generation_buf
+        .to_string()
+        .expect("snapshot cache generation was not valid UTF8");
+      cache.set_generation(generation);
+    }
+  })
+}
+
+thread_local! {
+  // The guards returned by `Core::pre_fork`, which are held by the thread that is about to fork
+  // until `scheduler_post_fork_in_parent` or `scheduler_post_fork_in_child`, along with a reference
+  // to the Scheduler that they borrow from. The guards are dropped before the reference.
+  static FORK_GUARDS: RefCell<Option<(ForkGuards<'static>, Arc<Scheduler>)>> = RefCell::new(None);
+}
+
+///
+/// Prepares the Scheduler for a fork, which must immediately follow on the same thread. One of
+/// `scheduler_post_fork_in_parent` or `scheduler_post_fork_in_child` must be called on that thread
+/// after the fork.
+///
 #[no_mangle]
 pub extern "C" fn scheduler_pre_fork(scheduler_ptr: *mut Scheduler) {
+  // Release any guards from an earlier pre_fork which was not followed by a post_fork, which would
+  // otherwise deadlock below.
+  release_fork_guards();
+  let scheduler = with_scheduler_arc(scheduler_ptr);
+  // The guards borrow from the Core, which the Arc that is stored alongside them keeps alive.
+  let core: &'static Core = unsafe { &*(&*scheduler.core as *const Core) };
+  let guards = core.pre_fork();
+  FORK_GUARDS.with(|fork_guards| *fork_guards.borrow_mut() = Some((guards, scheduler)));
+}
+
+#[no_mangle]
+pub extern "C" fn scheduler_post_fork_in_parent(_scheduler_ptr: *mut Scheduler) {
+  release_fork_guards();
+}
+
+///
+/// Fails if the Scheduler's resources were locked by another thread at the time of the fork, in
+/// which case the child should exit.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_post_fork_in_child(scheduler_ptr: *mut Scheduler) -> PyResult {
+  release_fork_guards();
   with_scheduler(scheduler_ptr, |scheduler| {
-    scheduler.core.pre_fork();
+    scheduler.core.post_fork_in_child().into()
   })
 }
 
+fn release_fork_guards() {
+  FORK_GUARDS.with(|fork_guards| fork_guards.borrow_mut().take());
+}
+
 #[no_mangle]
 pub extern "C" fn scheduler_execute(
   scheduler_ptr: *mut Scheduler,
@@ -342,11 +892,47 @@ pub extern "C" fn scheduler_execute(
   })
 }
 
+///
+/// Like `scheduler_execute`, but additionally calls the given python callable with
+/// `(root_index, state_tag, state_value)` as soon as each root completes.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_execute_streaming(
+  scheduler_ptr: *mut Scheduler,
+  session_ptr: *mut Session,
+  execution_request_ptr: *mut ExecutionRequest,
+  callback: Handle,
+) -> *const RawNodes {
+  let callback: Value = callback.into();
+  let on_root: RootCallback = Arc::new(move |index: usize, result: &RootResult| {
+    let (state_tag, state_value) = RawNode::state(result);
+    let args = [
+      externs::store_i64(index as i64),
+      externs::store_i64(i64::from(state_tag)),
+      state_value,
+    ];
+    if let Err(f) = externs::call(&callback, &args) {
+      warn!("Root completion callback failed: {:?}", f);
+    }
+  });
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_execution_request(execution_request_ptr, |execution_request| {
+      with_session(session_ptr, |session| {
+        Box::into_raw(RawNodes::create(scheduler.execute_streaming(
+          execution_request,
+          session,
+          on_root,
+        )))
+      })
+    })
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn scheduler_destroy(scheduler_ptr: *mut Scheduler) {
-  // convert the raw pointer back to a Box (without `forget`ing it) in order to cause it
-  // to be destroyed at the end of this function.
-  let _ = unsafe { Box::from_raw(scheduler_ptr) };
+  // convert the raw pointer back to an Arc (without `forget`ing it) in order to cause it
+  // to be destroyed at the end of this function (unless a QueryServer still refers to it).
+  let _ = unsafe { Arc::from_raw(scheduler_ptr) };
 }
 
 #[no_mangle]
@@ -365,6 +951,46 @@ pub extern "C" fn execution_add_root_select(
   })
 }
 
+///
+/// Adds a root which selects the product for each of the subjects in the given collection: see
+/// `Scheduler::add_root_selects`.
+///
+#[no_mangle]
+pub extern "C" fn execution_add_root_selects(
+  scheduler_ptr: *mut Scheduler,
+  execution_request_ptr: *mut ExecutionRequest,
+  subjects_value: Handle,
+  product: TypeConstraint,
+) -> PyResult {
+  let subjects = externs::project_multi(&subjects_value.into(), "dependencies")
+    .into_iter()
+    .map(externs::key_for)
+    .collect();
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_execution_request(execution_request_ptr, |execution_request| {
+      scheduler
+        .add_root_selects(execution_request, subjects, product)
+        .into()
+    })
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn execution_add_root_select_multiple(
+  scheduler_ptr: *mut Scheduler,
+  execution_request_ptr: *mut ExecutionRequest,
+  subject: Key,
+  products: TypeConstraintBuffer,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    with_execution_request(execution_request_ptr, |execution_request| {
+      scheduler
+        .add_root_select_multiple(execution_request, subject, products.to_vec())
+        .into()
+    })
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn tasks_create() -> *const Tasks {
   // Allocate on the heap via `Box` and return a raw pointer to the boxed value.
@@ -382,14 +1008,65 @@ pub extern "C" fn tasks_singleton_add(
   })
 }
 
+#[no_mangle]
+pub extern "C" fn tasks_plugin_intrinsic_add(
+  tasks_ptr: *mut Tasks,
+  name_buf: Buffer,
+  output_constraint: TypeConstraint,
+  input_constraint: TypeConstraint,
+  constructor: Function,
+) {
+  let name = name_buf
+    .to_string()
+    .expect("Failed to decode name for plugin intrinsic");
+  with_tasks(tasks_ptr, |tasks| {
+    tasks.plugin_intrinsic_add(name, output_constraint, input_constraint, constructor);
+  })
+}
+
+///
+/// Loads the plugins at the given paths: must be called after all tasks have been added, since it
+/// validates that the plugins provide all of the plugin intrinsics.
+///
+#[no_mangle]
+pub extern "C" fn tasks_load_plugins(tasks_ptr: *mut Tasks, paths_buf: BufferBuffer) -> PyResult {
+  let paths = paths_buf
+    .to_os_strings()
+    .into_iter()
+    .map(PathBuf::from)
+    .collect::<Vec<_>>();
+  with_tasks(tasks_ptr, |tasks| tasks.load_plugins(&paths).into())
+}
+
 #[no_mangle]
 pub extern "C" fn tasks_task_begin(
   tasks_ptr: *mut Tasks,
   func: Function,
   output_type: TypeConstraint,
+  cacheable: bool,
+  provider: bool,
+  name_buf: Buffer,
+  description_buf: Buffer,
 ) {
+  let name = name_buf
+    .to_string()
+    .expect("Failed to decode name for task");
+  let description = description_buf
+    .to_string()
+    .expect("Failed to decode description for task");
   with_tasks(tasks_ptr, |tasks| {
-    tasks.task_begin(func, output_type);
+    tasks.task_begin(
+      func,
+      output_type,
+      cacheable,
+      provider,
+      name,
+      if description.is_empty() {
+        None
+      } else {
+        Some(description)
+      },
+    );
   })
 }
 
@@ -445,6 +1122,21 @@ pub extern "C" fn graph_invalidate(scheduler_ptr: *mut Scheduler, paths_buf: Buf
   })
 }
 
+#[no_mangle]
+pub extern "C" fn graph_invalidate_prefixes(
+  scheduler_ptr: *mut Scheduler,
+  prefixes_buf: BufferBuffer,
+) -> u64 {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let prefixes = prefixes_buf
+      .to_os_strings()
+      .into_iter()
+      .map(PathBuf::from)
+      .collect::<Vec<_>>();
+    scheduler.invalidate_prefixes(&prefixes) as u64
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn graph_invalidate_all_paths(scheduler_ptr: *mut Scheduler) -> u64 {
   with_scheduler(scheduler_ptr, |scheduler| {
@@ -452,23 +1144,69 @@ pub extern "C" fn graph_invalidate_all_paths(scheduler_ptr: *mut Scheduler) -> u
   })
 }
 
+#[no_mangle]
+pub extern "C" fn graph_invalidate_all(scheduler_ptr: *mut Scheduler) -> u64 {
+  with_scheduler(scheduler_ptr, |scheduler| scheduler.invalidate_all() as u64)
+}
+
 #[no_mangle]
 pub extern "C" fn graph_len(scheduler_ptr: *mut Scheduler) -> u64 {
   with_scheduler(scheduler_ptr, |scheduler| scheduler.core.graph.len() as u64)
 }
 
+///
+/// Overrides the engine log level for a module path (and its submodules) at runtime. A level of
+/// 0 (NotSet) removes a previous override.
+///
+#[no_mangle]
+pub extern "C" fn set_module_log_level(module_buf: Buffer, level: u8) -> PyResult {
+  module_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode module path: {:?}", e))
+    .and_then(|module| externs::set_module_log_level(module, level))
+    .into()
+}
+
+#[no_mangle]
+pub extern "C" fn init_log_file(
+  path_buf: Buffer,
+  max_bytes: u64,
+  max_age_secs: u64,
+  max_rotated_files: u64,
+  level: u8,
+) -> PyResult {
+  path_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode log file path: {:?}", e))
+    .and_then(|path| {
+      log_file::init(
+        PathBuf::from(path),
+        max_bytes,
+        Duration::from_secs(max_age_secs),
+        max_rotated_files as usize,
+      ).map_err(|e| format!("Failed to open log file: {}", e))
+    })
+    .and_then(|()| externs::set_log_file_level(level))
+    .into()
+}
+
 #[no_mangle]
 pub extern "C" fn graph_visualize(
   scheduler_ptr: *mut Scheduler,
   session_ptr: *mut Session,
   path_ptr: *const raw::c_char,
+  min_duration_ms: u64,
 ) -> PyResult {
   with_scheduler(scheduler_ptr, |scheduler| {
     with_session(session_ptr, |session| {
       let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
       let path = PathBuf::from(path_str);
       scheduler
-        .visualize(session, path.as_path())
+        .visualize(
+          session,
+          Duration::from_millis(min_duration_ms),
+          path.as_path(),
+        )
         .map_err(|e| format!("Failed to visualize to {}: {:?}", path.display(), e))
         .into()
     })
@@ -480,13 +1218,28 @@ pub extern "C" fn graph_trace(
   scheduler_ptr: *mut Scheduler,
   execution_request_ptr: *mut ExecutionRequest,
   path_ptr: *const raw::c_char,
+  max_depth: u64,
+  node_types_buf: BufferBuffer,
+  first_failure_only: bool,
 ) {
   let path_str = unsafe { CStr::from_ptr(path_ptr).to_string_lossy().into_owned() };
   let path = PathBuf::from(path_str);
+  // Zero means that the depth of the trace is not limited.
+  let options = TraceOptions {
+    max_depth: if max_depth > 0 {
+      Some(max_depth as usize)
+    } else {
+      None
+    },
+    node_types: node_types_buf
+      .to_strings()
+      .expect("Failed to decode node types for trace"),
+    first_failure_only,
+  };
   with_scheduler(scheduler_ptr, |scheduler| {
     with_execution_request(execution_request_ptr, |execution_request| {
       scheduler
-        .trace(execution_request, path.as_path())
+        .trace(execution_request, path.as_path(), &options)
         .unwrap_or_else(|e| {
           println!("Failed to write trace to {}: {:?}", path.display(), e);
         });
@@ -500,9 +1253,18 @@ pub extern "C" fn nodes_destroy(raw_nodes_ptr: *mut RawNodes) {
 }
 
 #[no_mangle]
-pub extern "C" fn session_create(scheduler_ptr: *mut Scheduler) -> *const Session {
+pub extern "C" fn session_create(
+  scheduler_ptr: *mut Scheduler,
+  background: bool,
+  timeout_seconds: u64,
+) -> *const Session {
   with_scheduler(scheduler_ptr, |scheduler| {
-    Box::into_raw(Box::new(Session::new(scheduler)))
+    let timeout = if timeout_seconds == 0 {
+      None
+    } else {
+      Some(Duration::from_secs(timeout_seconds))
+    };
+    Box::into_raw(Box::new(Session::new(scheduler, background, timeout)))
   })
 }
 
@@ -511,6 +1273,65 @@ pub extern "C" fn session_destroy(ptr: *mut Session) {
   let _ = unsafe { Box::from_raw(ptr) };
 }
 
+///
+/// Creates a QueryServer for the given Scheduler, which will use the given python handler (see
+/// `pants.engine.query.QueryHandler`) to resolve and render requests. The server holds a reference
+/// to the Scheduler, so the Scheduler will not be destroyed until the server has been.
+///
+#[no_mangle]
+pub extern "C" fn query_server_create(
+  scheduler_ptr: *mut Scheduler,
+  handler: Handle,
+) -> *const QueryServer {
+  let scheduler = with_scheduler_arc(scheduler_ptr);
+  Box::into_raw(Box::new(QueryServer::new(scheduler, handler.into())))
+}
+
+///
+/// Starts the given QueryServer on a unix socket in the given directory, and returns the path of
+/// the socket.
+///
+#[no_mangle]
+pub extern "C" fn query_server_start(
+  query_server_ptr: *mut QueryServer,
+  socket_dir_buf: Buffer,
+) -> PyResult {
+  let socket_dir = PathBuf::from(socket_dir_buf.to_os_string());
+  with_query_server(query_server_ptr, |query_server| {
+    query_server
+      .start(&socket_dir)
+      .map(|path| externs::store_utf8(&path.to_string_lossy()))
+      .into()
+  })
+}
+
+#[no_mangle]
+pub extern "C" fn query_server_stop(query_server_ptr: *mut QueryServer) {
+  with_query_server(query_server_ptr, |query_server| query_server.stop())
+}
+
+#[no_mangle]
+pub extern "C" fn query_server_pre_fork(query_server_ptr: *mut QueryServer) {
+  with_query_server(query_server_ptr, |query_server| query_server.pre_fork())
+}
+
+#[no_mangle]
+pub extern "C" fn query_server_post_fork_in_parent(
+  query_server_ptr: *mut QueryServer,
+) -> PyResult {
+  with_query_server(query_server_ptr, |query_server| query_server.post_fork_in_parent().into())
+}
+
+#[no_mangle]
+pub extern "C" fn query_server_post_fork_in_child(query_server_ptr: *mut QueryServer) -> PyResult {
+  with_query_server(query_server_ptr, |query_server| query_server.post_fork_in_child().into())
+}
+
+#[no_mangle]
+pub extern "C" fn query_server_destroy(ptr: *mut QueryServer) {
+  let _ = unsafe { Box::from_raw(ptr) };
+}
+
 #[no_mangle]
 pub extern "C" fn execution_request_create() -> *const ExecutionRequest {
   Box::into_raw(Box::new(ExecutionRequest::new()))
@@ -564,23 +1385,66 @@ pub extern "C" fn rule_subgraph_visualize(
 }
 
 #[no_mangle]
-pub extern "C" fn set_panic_handler() {
-  panic::set_hook(Box::new(|panic_info| {
-    let mut panic_str = format!(
-      "panic at '{}'",
-      panic_info.payload().downcast_ref::<&str>().unwrap()
-    );
+pub extern "C" fn set_panic_handler(crash_report_dir_buf: Buffer) -> PyResult {
+  crash_report_dir_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode crash report dir: {:?}", e))
+    .map(|crash_report_dir| {
+      panics::set_hook(if crash_report_dir.is_empty() {
+        None
+      } else {
+        Some(PathBuf::from(crash_report_dir))
+      })
+    })
+    .into()
+}
 
-    if let Some(location) = panic_info.location() {
-      let panic_location_str = format!(", {}:{}", location.file(), location.line());
-      panic_str.push_str(&panic_location_str);
-    }
+///
+/// Names the calling thread for the given role: see `fs::threads::set_current_thread_name`.
+///
+#[no_mangle]
+pub extern "C" fn set_thread_name(role_buf: Buffer) -> PyResult {
+  role_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode thread role: {:?}", e))
+    .and_then(|role| fs::threads::set_current_thread_name(&role))
+    .into()
+}
+
+///
+/// Dumps the Graphs of all live Schedulers to files in the given directory. See `Graph::dump`.
+///
+#[no_mangle]
+pub extern "C" fn graph_dump_all(dir_buf: Buffer) -> PyResult {
+  dir_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode graph dump dir: {:?}", e))
+    .map(|dir| {
+      panics::dump_graphs(Path::new(&dir));
+    })
+    .into()
+}
 
-    error!("{}", panic_str);
+///
+/// Starts sampling the CPU usage of the engine: see `profiler::start`.
+///
+#[no_mangle]
+pub extern "C" fn cpu_profile_start(frequency_hz: u64) -> PyResult {
+  profiler::start(frequency_hz).into()
+}
 
-    let panic_file_bug_str = "Please file a bug at https://github.com/pantsbuild/pants/issues.";
-    error!("{}", panic_file_bug_str);
-  }));
+///
+/// Stops sampling the CPU usage of the engine, and writes a profile to the given path. Returns the
+/// number of samples in the profile.
+///
+#[no_mangle]
+pub extern "C" fn cpu_profile_stop(path_buf: Buffer) -> PyResult {
+  path_buf
+    .to_string()
+    .map_err(|e| format!("Failed to decode CPU profile path: {:?}", e))
+    .and_then(|path| profiler::stop(Path::new(&path)))
+    .map(|samples| externs::store_i64(samples as i64))
+    .into()
 }
 
 #[no_mangle]
@@ -593,17 +1457,115 @@ pub extern "C" fn garbage_collect_store(scheduler_ptr: *mut Scheduler) {
   });
 }
 
+///
+/// Waits for the outputs which are being written through to the remote store (if any) to be
+/// uploaded: see `Store::write_through`.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_flush_uploads(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler.core.store.flush_uploads(None).into()
+  })
+}
+
 #[no_mangle]
 pub extern "C" fn lease_files_in_graph(scheduler_ptr: *mut Scheduler) {
   with_scheduler(scheduler_ptr, |scheduler| {
     let digests = scheduler.core.graph.all_digests();
-    match scheduler.core.store.lease_all(digests.iter()) {
+    match scheduler.core.store.lease_all(digests.iter()).wait() {
       Ok(_) => {}
       Err(err) => error!("{}", &err),
     }
   });
 }
 
+///
+/// Leases the given digests (and, for Directories, their contents) in the local store for the
+/// given number of seconds, so that garbage collection does not remove them while they are
+/// referenced from outside of the engine.
+///
+#[no_mangle]
+pub extern "C" fn lease_digests(
+  scheduler_ptr: *mut Scheduler,
+  digests_value: Handle,
+  duration_secs: u64,
+) -> PyResult {
+  let digests = match lift_digests(digests_value) {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .store
+      .lease_recursive(digests, Duration::from_secs(duration_secs))
+      .wait()
+      .into()
+  })
+}
+
+///
+/// Releases one pin (taken by `lease_digests`) of each of the given digests (and, for Directories,
+/// of their contents) in the local store. Once all of its pins have been released, content may be
+/// garbage collected.
+///
+#[no_mangle]
+pub extern "C" fn release_digests(
+  scheduler_ptr: *mut Scheduler,
+  digests_value: Handle,
+) -> PyResult {
+  let digests = match lift_digests(digests_value) {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    scheduler
+      .core
+      .store
+      .release_recursive(digests)
+      .wait()
+      .into()
+  })
+}
+
+///
+/// Loads the content of a LazyBytes (or any other file digest) from the store.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_load_bytes(
+  scheduler_ptr: *mut Scheduler,
+  digest_value: Handle,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    nodes::lift_digest(&digest_value.into())
+      .and_then(|digest| {
+        scheduler
+          .core
+          .store
+          .load_file_bytes_with(digest, |bytes| bytes)
+          .wait()?
+          .ok_or_else(|| format!("Could not find file with digest {:?}", digest))
+      })
+      .map(|bytes| externs::store_bytes(&bytes))
+      .into()
+  })
+}
+
+fn lift_digests(digests_value: Handle) -> Result<Vec<hashing::Digest>, String> {
+  externs::project_multi(&digests_value.into(), "dependencies")
+    .iter()
+    .map(|v| nodes::lift_digest(v))
+    .collect()
+}
+
 #[no_mangle]
 pub extern "C" fn capture_snapshots(
   scheduler_ptr: *mut Scheduler,
@@ -614,6 +1576,12 @@ pub extern "C" fn capture_snapshots(
     .iter()
     .map(|value| {
       let root = PathBuf::from(externs::project_str(&value, "root"));
+      if !root.is_absolute() {
+        return Err(format!(
+          "Snapshots may only be captured from absolute roots, but got: {:?}",
+          root
+        ));
+      }
       let path_globs =
         nodes::Snapshot::lift_path_globs(&externs::project_ignoring_type(&value, "path_globs"));
       path_globs.map(|path_globs| (path_globs, root))
@@ -651,12 +1619,7 @@ pub extern "C" fn merge_directories(
   scheduler_ptr: *mut Scheduler,
   directories_value: Handle,
 ) -> PyResult {
-  let digests_result: Result<Vec<hashing::Digest>, String> =
-    externs::project_multi(&directories_value.into(), "dependencies")
-      .iter()
-      .map(|v| nodes::lift_digest(v))
-      .collect();
-  let digests = match digests_result {
+  let digests = match lift_digests(directories_value) {
     Ok(d) => d,
     Err(err) => {
       let e: Result<Value, String> = Err(err);
@@ -677,18 +1640,8 @@ pub extern "C" fn materialize_directories(
   scheduler_ptr: *mut Scheduler,
   directories_paths_and_digests_value: Handle,
 ) -> PyResult {
-  let values = externs::project_multi(&directories_paths_and_digests_value.into(), "dependencies");
-  let directories_paths_and_digests_results: Result<Vec<(PathBuf, Digest)>, String> = values
-    .iter()
-    .map(|value| {
-      let dir = PathBuf::from(externs::project_str(&value, "path"));
-      let dir_digest =
-        nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest"));
-      dir_digest.map(|dir_digest| (dir, dir_digest))
-    })
-    .collect();
-
-  let dir_and_digests = match directories_paths_and_digests_results {
+  let dir_and_digests = match lift_directories_to_materialize(directories_paths_and_digests_value)
+  {
     Ok(d) => d,
     Err(err) => {
       let e: Result<Value, String> = Err(err);
@@ -708,6 +1661,177 @@ pub extern "C" fn materialize_directories(
     .into()
 }
 
+///
+/// Like `materialize_directories`, but replaces each directory (deleting any existing contents)
+/// such that an interruption never leaves it partially written: see `Store::replace_directory`.
+///
+#[no_mangle]
+pub extern "C" fn replace_directories(
+  scheduler_ptr: *mut Scheduler,
+  directories_paths_and_digests_value: Handle,
+) -> PyResult {
+  let dir_and_digests = match lift_directories_to_materialize(directories_paths_and_digests_value)
+  {
+    Ok(d) => d,
+    Err(err) => {
+      let e: Result<Value, String> = Err(err);
+      return e.into();
+    }
+  };
+
+  with_scheduler(scheduler_ptr, |scheduler| {
+    futures::future::join_all(
+      dir_and_digests
+        .into_iter()
+        .map(|(dir, digest)| scheduler.core.store.replace_directory(dir, digest))
+        .collect::<Vec<_>>(),
+    )
+  }).map(|_| ())
+    .wait()
+    .into()
+}
+
+fn lift_directories_to_materialize(
+  directories_paths_and_digests_value: Handle,
+) -> Result<Vec<(PathBuf, Digest)>, String> {
+  externs::project_multi(&directories_paths_and_digests_value.into(), "dependencies")
+    .iter()
+    .map(|value| {
+      let dir = PathBuf::from(externs::project_str(&value, "path"));
+      let dir_digest =
+        nodes::lift_digest(&externs::project_ignoring_type(&value, "directory_digest"));
+      dir_digest.map(|dir_digest| (dir, dir_digest))
+    })
+    .collect()
+}
+
+///
+/// Computes the (action digest, command digest) that an ExecuteProcessRequest is executed as
+/// remotely: the action digest is the key that a remote cache stores the result of the process
+/// under.
+///
+#[no_mangle]
+pub extern "C" fn execute_process_request_digests(process_request_value: Handle) -> PyResult {
+  nodes::ExecuteProcess::lift(&process_request_value.into())
+    .and_then(|process| process_execution::remote::request_digests(&process.0))
+    .map(|(action_digest, command_digest)| {
+      externs::store_tuple(&[store_digest(&action_digest), store_digest(&command_digest)])
+    })
+    .into()
+}
+
+///
+/// Describes the differences between two requests which were recorded to the given archive
+/// directories (see `process_execution::archive::Archive::diff`), and which are identified by the
+/// hex fingerprints of their action digests.
+///
+#[no_mangle]
+pub extern "C" fn explain_process_request_diff(
+  left_archive_buf: Buffer,
+  left_fingerprint_buf: Buffer,
+  right_archive_buf: Buffer,
+  right_fingerprint_buf: Buffer,
+) -> PyResult {
+  let fingerprint = |buf: Buffer| {
+    buf
+      .to_string()
+      .map_err(|e| format!("Fingerprint was not valid UTF8: {}", e))
+      .and_then(|hex| Fingerprint::from_hex_string(&hex))
+  };
+  let left_archive =
+    process_execution::archive::Archive::new(PathBuf::from(left_archive_buf.to_os_string()));
+  let right_archive =
+    process_execution::archive::Archive::new(PathBuf::from(right_archive_buf.to_os_string()));
+  fingerprint(left_fingerprint_buf)
+    .and_then(|left| Ok((left, fingerprint(right_fingerprint_buf)?)))
+    .and_then(|(left, right)| left_archive.diff(left, &right_archive, right))
+    .map(|diff| externs::store_utf8(&diff.to_string()))
+    .into()
+}
+
+///
+/// Responds to executions of the given ExecuteProcessRequest with the given result, for a
+/// Scheduler that was created with a fake CommandRunner.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_fake_process_result(
+  scheduler_ptr: *mut Scheduler,
+  process_request_value: Handle,
+  stdout_buf: Buffer,
+  stderr_buf: Buffer,
+  exit_code: i32,
+  output_directory_value: Handle,
+) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    let result = nodes::lift_digest(&output_directory_value.into()).map(|output_directory| {
+      process_execution::FallibleExecuteProcessResult {
+        stdout: stdout_buf.to_bytes().into(),
+        stderr: stderr_buf.to_bytes().into(),
+        exit_code: exit_code,
+        output_directory: output_directory,
+        stdout_overflow: None,
+        stderr_overflow: None,
+        source: process_execution::ResultSource::Fake,
+      }
+    });
+    fake_command_runner(scheduler)
+      .and_then(|runner| {
+        let process = nodes::ExecuteProcess::lift(&process_request_value.into())?;
+        runner.respond(&process.0, result?)
+      })
+      .into()
+  })
+}
+
+///
+/// Returns a tuple of (argv, description, action digest) for each request that the fake
+/// CommandRunner of the given Scheduler has been asked to execute.
+///
+#[no_mangle]
+pub extern "C" fn scheduler_fake_process_invocations(scheduler_ptr: *mut Scheduler) -> PyResult {
+  with_scheduler(scheduler_ptr, |scheduler| {
+    fake_command_runner(scheduler)
+      .and_then(|runner| {
+        runner
+          .invocations()
+          .iter()
+          .map(|req| {
+            let (action_digest, _) = process_execution::remote::request_digests(req)?;
+            let argv = req
+              .argv
+              .iter()
+              .map(|arg| externs::store_utf8(arg))
+              .collect::<Vec<_>>();
+            Ok(externs::store_tuple(&[
+              externs::store_tuple(&argv),
+              externs::store_utf8(&req.description),
+              store_digest(&action_digest),
+            ]))
+          })
+          .collect::<Result<Vec<_>, String>>()
+      })
+      .map(|invocations| externs::store_tuple(&invocations))
+      .into()
+  })
+}
+
+fn fake_command_runner(
+  scheduler: &Scheduler,
+) -> Result<&process_execution::fake::CommandRunner, String> {
+  scheduler
+    .core
+    .fake_command_runner
+    .as_ref()
+    .ok_or_else(|| "The Scheduler was not created with a fake CommandRunner.".to_string())
+}
+
+fn store_digest(digest: &Digest) -> Value {
+  externs::store_tuple(&[
+    externs::store_utf8(&digest.0.to_hex()),
+    externs::store_i64(digest.1 as i64),
+  ])
+}
+
 fn graph_full(scheduler: &Scheduler, subject_types: Vec<TypeId>) -> RuleGraph {
   let graph_maker = GraphMaker::new(&scheduler.core.tasks, subject_types);
   graph_maker.full_graph()
@@ -737,12 +1861,22 @@ fn with_scheduler<F, T>(scheduler_ptr: *mut Scheduler, f: F) -> T
 where
   F: FnOnce(&Scheduler) -> T,
 {
-  let scheduler = unsafe { Box::from_raw(scheduler_ptr) };
+  let scheduler = unsafe { Arc::from_raw(scheduler_ptr) };
   let t = f(&scheduler);
   mem::forget(scheduler);
   t
 }
 
+///
+/// Returns a new reference to the Scheduler, for objects which outlive a single call.
+///
+fn with_scheduler_arc(scheduler_ptr: *mut Scheduler) -> Arc<Scheduler> {
+  let scheduler = unsafe { Arc::from_raw(scheduler_ptr) };
+  let new_ref = scheduler.clone();
+  mem::forget(scheduler);
+  new_ref
+}
+
 ///
 /// See `with_scheduler`.
 ///
@@ -769,6 +1903,19 @@ where
   t
 }
 
+///
+/// See `with_scheduler`.
+///
+fn with_query_server<F, T>(query_server_ptr: *mut QueryServer, f: F) -> T
+where
+  F: FnOnce(&mut QueryServer) -> T,
+{
+  let mut query_server = unsafe { Box::from_raw(query_server_ptr) };
+  let t = f(&mut query_server);
+  mem::forget(query_server);
+  t
+}
+
 ///
 /// See `with_scheduler`.
 ///
@@ -0,0 +1,131 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log;
+
+lazy_static! {
+  static ref LOG_FILE: Mutex<Option<RotatingLogFile>> = Mutex::new(None);
+}
+
+///
+/// Opens (or replaces) the engine's own log file. Until this has been called, `write` discards
+/// its input.
+///
+pub fn init(
+  path: PathBuf,
+  max_bytes: u64,
+  max_age: Duration,
+  max_rotated_files: usize,
+) -> io::Result<()> {
+  let log_file = RotatingLogFile::open(path, max_bytes, max_age, max_rotated_files)?;
+  *LOG_FILE.lock().unwrap() = Some(log_file);
+  Ok(())
+}
+
+///
+/// Appends a record to the engine's log file, if one has been opened.
+///
+/// Failures to write are ignored: there is nowhere else to report them.
+///
+pub fn write(record: &log::Record) {
+  let mut log_file = LOG_FILE.lock().unwrap();
+  if let Some(ref mut log_file) = *log_file {
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    let line = format!(
+      "{}.{:03} {} {}: {}\n",
+      timestamp.as_secs(),
+      timestamp.subsec_nanos() / 1_000_000,
+      record.level(),
+      record.target(),
+      record.args()
+    );
+    let _ = log_file.write(line.as_bytes());
+  }
+}
+
+///
+/// A log file which is rotated when it exceeds a maximum size or age. Rotated files are renamed
+/// with an increasing numeric suffix (`engine.log.1` is the most recently rotated), and the oldest
+/// files beyond `max_rotated_files` are deleted.
+///
+struct RotatingLogFile {
+  path: PathBuf,
+  max_bytes: u64,
+  max_age: Duration,
+  max_rotated_files: usize,
+  file: File,
+  bytes_written: u64,
+  opened_at: Instant,
+}
+
+impl RotatingLogFile {
+  fn open(
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    max_rotated_files: usize,
+  ) -> io::Result<RotatingLogFile> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let file = Self::open_append(&path)?;
+    let bytes_written = file.metadata()?.len();
+    Ok(RotatingLogFile {
+      path: path,
+      max_bytes: max_bytes,
+      max_age: max_age,
+      max_rotated_files: max_rotated_files,
+      file: file,
+      bytes_written: bytes_written,
+      opened_at: Instant::now(),
+    })
+  }
+
+  fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+  }
+
+  fn rotated_path(&self, index: usize) -> PathBuf {
+    let mut rotated = self.path.clone().into_os_string();
+    rotated.push(format!(".{}", index));
+    PathBuf::from(rotated)
+  }
+
+  fn write(&mut self, line: &[u8]) -> io::Result<()> {
+    let too_large =
+      self.bytes_written > 0 && self.bytes_written + line.len() as u64 > self.max_bytes;
+    if too_large || self.opened_at.elapsed() > self.max_age {
+      self.rotate()?;
+    }
+    self.file.write_all(line)?;
+    self.bytes_written += line.len() as u64;
+    Ok(())
+  }
+
+  fn rotate(&mut self) -> io::Result<()> {
+    if self.max_rotated_files == 0 {
+      fs::remove_file(&self.path)?;
+    } else {
+      // Shift each rotated file up by one, overwriting (and thus dropping) the oldest.
+      for index in (1..self.max_rotated_files).rev() {
+        let from = self.rotated_path(index);
+        if from.exists() {
+          fs::rename(&from, self.rotated_path(index + 1))?;
+        }
+      }
+      fs::rename(&self.path, self.rotated_path(1))?;
+    }
+    self.file = Self::open_append(&self.path)?;
+    self.bytes_written = 0;
+    self.opened_at = Instant::now();
+    Ok(())
+  }
+}
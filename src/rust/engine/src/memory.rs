@@ -0,0 +1,49 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+#[cfg(target_os = "linux")]
+use std::fs;
+
+#[cfg(target_os = "linux")]
+use libc;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+extern "C" {
+  fn malloc_trim(pad: libc::size_t) -> libc::c_int;
+}
+
+///
+/// Asks the allocator to return the free memory in its heaps to the OS. Freed memory is otherwise
+/// retained by the process for reuse, so that a process which was briefly very large remains so.
+///
+/// Only glibc supports this: elsewhere, this is a no-op.
+///
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn trim_allocator() {
+  unsafe {
+    malloc_trim(0);
+  }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn trim_allocator() {}
+
+///
+/// Returns the resident set size of this process in bytes, if it can be measured on this platform.
+///
+#[cfg(target_os = "linux")]
+pub fn resident_set_bytes() -> Option<usize> {
+  // The second field of statm is the number of resident pages.
+  let statm = fs::read_to_string("/proc/self/statm").ok()?;
+  let pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+  let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+  if page_size <= 0 {
+    return None;
+  }
+  Some(pages * page_size as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resident_set_bytes() -> Option<usize> {
+  None
+}
@@ -1,26 +1,41 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
-use std::collections::{BTreeMap, HashMap};
-use std::os::unix::ffi::OsStrExt;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fmt::Debug;
+use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::future::{self, Future};
+use bytes::Bytes;
+use futures::future::{self, Future, Loop};
+use tokio::timer::Delay;
 
 use boxfuture::{BoxFuture, Boxable};
+use build_file_parser;
 use context::{Context, Core};
-use core::{throw, Failure, Key, Noop, TypeConstraint, Value, Variants};
+use core::{
+  throw, throw_category, ErrorCategory, Failure, Key, Noop, TypeConstraint, Value, Variants,
+};
+use download::{self, ArchiveType, BinaryToolRequest};
 use externs;
 use fs::{
-  self, Dir, DirectoryListing, File, FileContent, GlobMatching, Link, PathGlobs, PathStat,
-  StoreFileByDigest, StrictGlobMatching, VFS,
+  self, Dir, DirectoryListing, File, FileContent, GlobExpansionMemo, GlobMatching, Link,
+  ListingFilter, NegativeMatchFilter, PathGlobs, PathStat, StoreFileByDigest, StrictGlobMatching,
+  VFS,
 };
 use hashing;
+use imports::{self, Language};
+use panics;
+use plugin_api;
 use process_execution::{self, CommandRunner};
 use rule_graph;
+use scm::{self, GitTreeFS};
 use selectors;
+use snapshot_cache::SnapshotCache;
 use tasks::{self, Intrinsic, IntrinsicKind};
 
 use graph::{Node, NodeError, NodeTracer, NodeVisualizer};
@@ -41,29 +56,111 @@ fn err<O: Send + 'static>(failure: Failure) -> NodeFuture<O> {
 ///
 fn was_required(failure: Failure) -> Failure {
   match failure {
-    Failure::Noop(noop) => throw(&format!("No source of required dependency: {:?}", noop)),
+    Failure::Noop(noop) => throw_category(
+      ErrorCategory::UserRule,
+      &format!("No source of required dependency: {:?}", noop),
+    ),
     f => f,
   }
 }
 
+///
+/// Runs the given operation, retrying it according to the Core's transient RetryPolicy for as
+/// long as it fails with an error that `is_transient` classifies as transient. Other errors (and
+/// the last transient error, once retries are exhausted) are returned unmodified.
+///
+fn retry_transient<T, E, F, P>(context: &Context, op: F, is_transient: P) -> BoxFuture<T, E>
+where
+  T: Send + 'static,
+  E: Debug + Send + 'static,
+  F: Fn() -> BoxFuture<T, E> + Send + Sync + 'static,
+  P: Fn(&E) -> bool + Send + Sync + 'static,
+{
+  let policy = context.core.transient_retry_policy;
+  let op = Arc::new(op);
+  let is_transient = Arc::new(is_transient);
+  future::loop_fn(0, move |attempt| {
+    let is_transient = is_transient.clone();
+    (*op)()
+      .then(move |res| match res {
+        Err(ref e) if attempt < policy.attempts && (*is_transient)(e) => {
+          let backoff = policy.backoff_for(attempt);
+          debug!(
+            "Retrying after transient failure (retry {} of {}) in {:?}: {:?}",
+            attempt + 1,
+            policy.attempts,
+            backoff,
+            e
+          );
+          Delay::new(Instant::now() + backoff)
+            .then(move |_| Ok(Loop::Continue(attempt + 1)))
+            .to_boxed()
+        }
+        res => future::result(res.map(Loop::Break)).to_boxed(),
+      })
+      .to_boxed()
+  }).to_boxed()
+}
+
+
+///
+/// Filesystem errors that indicate that an operation was interrupted, rather than that it failed.
+///
+fn is_transient_io_error(e: &io::Error) -> bool {
+  match e.kind() {
+    io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+    _ => false,
+  }
+}
+
+fn io_error_category(e: &io::Error) -> ErrorCategory {
+  match e.kind() {
+    io::ErrorKind::NotFound => ErrorCategory::MissingFile,
+    _ => ErrorCategory::Infrastructure,
+  }
+}
+
 impl VFS<Failure> for Context {
   fn read_link(&self, link: &Link) -> NodeFuture<PathBuf> {
     self.get(ReadLink(link.clone())).map(|res| res.0).to_boxed()
   }
 
-  fn scandir(&self, dir: Dir) -> NodeFuture<Arc<DirectoryListing>> {
-    self.get(Scandir(dir))
+  fn scandir_matching(
+    &self,
+    dir: Dir,
+    filter: &ListingFilter,
+  ) -> NodeFuture<Arc<DirectoryListing>> {
+    let context = self.clone();
+    let filter = filter.clone();
+    self
+      .get(Scandir(dir.clone()))
+      .and_then(move |listing_opt| match listing_opt {
+        Some(listing) => future::ok(listing).to_boxed(),
+        // The directory is too large to be listed in full.
+        None => context.get(ScandirMatching { dir, filter }),
+      })
+      .to_boxed()
   }
 
   fn is_ignored(&self, stat: &fs::Stat) -> bool {
     self.core.vfs.is_ignored(stat)
   }
 
+  fn link_escape_behavior(&self) -> fs::LinkEscapeBehavior {
+    self.core.vfs.link_escape_behavior()
+  }
+
   fn mk_error(msg: &str) -> Failure {
-    Failure::Throw(
-      externs::create_exception(msg),
-      "<pants native internals>".to_string(),
-    )
+    // Errors created while expanding globs are caused by the globs that were requested.
+    throw_category(ErrorCategory::UserRule, msg)
+  }
+
+  fn glob_expansion_memo(&self) -> Option<&GlobExpansionMemo> {
+    Some(&self.core.glob_expansion_memo)
+  }
+
+  fn negative_match_filter(&self) -> Option<&NegativeMatchFilter> {
+    Some(&self.core.negative_match_filter)
   }
 }
 
@@ -102,7 +199,7 @@ pub struct Select {
   pub subject: Key,
   pub variants: Variants,
   pub selector: selectors::Select,
-  entries: rule_graph::Entries,
+  entries: Arc<rule_graph::Entries>,
 }
 
 impl Select {
@@ -113,13 +210,7 @@ impl Select {
     edges: &rule_graph::RuleEdges,
   ) -> Select {
     let selector = selectors::Select::without_variant(product);
-    let select_key = rule_graph::SelectKey::JustSelect(selector.clone());
-    Select {
-      selector: selector,
-      subject: subject,
-      variants: variants,
-      entries: edges.entries_for(&select_key),
-    }
+    Select::new_with_selector(selector, subject, variants, edges)
   }
 
   pub fn new_with_entries(
@@ -133,7 +224,7 @@ impl Select {
       selector: selector,
       subject: subject,
       variants: variants,
-      entries: entries,
+      entries: Arc::new(entries),
     }
   }
 
@@ -143,16 +234,25 @@ impl Select {
     variants: Variants,
     edges: &rule_graph::RuleEdges,
   ) -> Select {
-    let select_key = rule_graph::SelectKey::JustSelect(selector.clone());
+    let entries = edges.entries_for_select(&selector, subject.type_id().clone());
+    Select::new_with_shared_entries(selector, subject, variants, entries)
+  }
+
+  ///
+  /// Creates a Select with entries that were already filtered for the selector and the type of
+  /// the subject, which allows many Selects (such as the roots of a large request) to share them.
+  ///
+  pub fn new_with_shared_entries(
+    selector: selectors::Select,
+    subject: Key,
+    variants: Variants,
+    entries: Arc<rule_graph::Entries>,
+  ) -> Select {
     Select {
       selector: selector,
       subject: subject,
       variants: variants,
-      entries: edges
-        .entries_for(&select_key)
-        .into_iter()
-        .filter(|e| e.matches_subject_type(subject.type_id().clone()))
-        .collect(),
+      entries: entries,
     }
   }
 
@@ -233,8 +333,7 @@ impl Select {
               }
               continue;
             }
-            i @ Failure::Invalidated => return Err(i),
-            f @ Failure::Throw(..) => return Err(f),
+            f => return Err(f),
           }
         }
       }
@@ -244,7 +343,10 @@ impl Select {
       // TODO: Multiple successful tasks are not currently supported. We could allow for this
       // by adding support for "mergeable" products. see:
       //   https://github.com/pantsbuild/pants/issues/2526
-      return Err(throw("Conflicting values produced for subject and type."));
+      return Err(throw_category(
+        ErrorCategory::UserRule,
+        "Conflicting values produced for subject and type.",
+      ));
     }
 
     match matches.pop() {
@@ -279,6 +381,247 @@ impl Select {
       .to_boxed()
   }
 
+  fn file_size_summary(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for FileSizeSummary intrinsic.");
+    // Compute PathGlobs for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.path_globs.clone(),
+      self.subject,
+      self.variants.clone(),
+      &edges,
+    ).run(context.clone())
+      .and_then(move |path_globs_val| {
+        context.get(SummarizeFileSizes(externs::key_for(path_globs_val)))
+      })
+      .to_boxed()
+  }
+
+  fn address_names(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for AddressNames intrinsic.");
+    // Compute an AddressNamesRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.address_names_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(|request_val| {
+        ParseAddressNames::lift(&request_val).map_err(|str| {
+          throw_category(
+            ErrorCategory::UserRule,
+            &format!("Error lifting AddressNamesRequest: {}", str),
+          )
+        })
+      })
+      .and_then(move |parse_address_names| context.get(parse_address_names))
+      .to_boxed()
+  }
+
+  fn source_imports(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for SourceImports intrinsic.");
+    // Compute a SourceImportsRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.source_imports_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(|request_val| {
+        ScanImports::lift(&request_val).map_err(|str| {
+          throw_category(
+            ErrorCategory::UserRule,
+            &format!("Error lifting SourceImportsRequest: {}", str),
+          )
+        })
+      })
+      .and_then(move |scan_imports| context.get(scan_imports))
+      .to_boxed()
+  }
+
+  fn checksums(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<fs::Snapshot> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for Checksums intrinsic.");
+    // Compute a ChecksumsRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.checksums_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(|request_val| {
+        lift_digest(&externs::project_ignoring_type(
+          &request_val,
+          "directory_digest",
+        )).map_err(|str| {
+          throw_category(
+            ErrorCategory::UserRule,
+            &format!("Error lifting ChecksumsRequest: {}", str),
+          )
+        })
+      })
+      .and_then(move |digest| {
+        fs::Snapshot::sha256sums(context.store(), digest).map_err(|e| throw(&e))
+      })
+      .to_boxed()
+  }
+
+  fn digest_equality(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<bool> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for DigestEquality intrinsic.");
+    // Compute a DigestEqualityRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.digest_equality_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(move |request_val| {
+        let lift_error = |str: String| {
+          throw_category(
+            ErrorCategory::UserRule,
+            &format!("Error lifting DigestEqualityRequest: {}", str),
+          )
+        };
+        let expected_val = externs::project_ignoring_type(&request_val, "expected");
+        let expected = try_future!(lift_digest(&expected_val).map_err(&lift_error));
+        // The actual digest is either given, or is that of a (memoized) capture of PathGlobs.
+        let actual_val = externs::project_ignoring_type(&request_val, "actual");
+        let actual = if externs::satisfied_by(&context.core.types.path_globs, &actual_val) {
+          context
+            .get(Snapshot(externs::key_for(actual_val)))
+            .map(|snapshot| snapshot.digest)
+            .to_boxed()
+        } else {
+          future::result(lift_digest(&actual_val).map_err(&lift_error)).to_boxed()
+        };
+        actual.map(move |actual| expected == actual).to_boxed()
+      })
+      .to_boxed()
+  }
+
+  ///
+  /// Runs the given plugin intrinsic (see `plugins::Plugins`) on the files in the Directory of its
+  /// input (which is either a DirectoryDigest, or has a `directory_digest` field), and constructs
+  /// the product from the bytes that it returns.
+  ///
+  fn plugin_intrinsic(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+    index: usize,
+  ) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for plugin intrinsic.");
+    let tasks::PluginIntrinsic {
+      name,
+      input,
+      constructor,
+      ..
+    } = context.core.tasks.plugin_intrinsic(index).clone();
+    let intrinsic = match context.core.tasks.plugins().intrinsic(&name) {
+      Some(intrinsic) => intrinsic.clone(),
+      None => {
+        return err(throw(&format!(
+          "No loaded plugin provides the intrinsic {}.",
+          name
+        )))
+      }
+    };
+    let context = context.clone();
+    let directory_digest_type = context.core.types.directory_digest;
+    Select::new(input, self.subject, self.variants.clone(), edges)
+      .run(context.clone())
+      .and_then(move |input_val| {
+        let directory_digest_val = if externs::satisfied_by(&directory_digest_type, &input_val) {
+          input_val
+        } else {
+          externs::project_ignoring_type(&input_val, "directory_digest")
+        };
+        lift_digest(&directory_digest_val).map_err(|str| throw(&str))
+      })
+      .and_then(move |digest| {
+        let store = context.store();
+        let fs_pool = context.core.fs_pool.clone();
+        context
+          .core
+          .store
+          .load_directory(digest)
+          .and_then(move |maybe_directory| {
+            maybe_directory
+              .ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+          })
+          .and_then(move |directory| store.contents_for_directory(&directory))
+          .and_then(move |files_content| {
+            fs_pool.spawn_fn(move || {
+              let files = files_content
+                .iter()
+                .map(|file_content| plugin_api::InputFile {
+                  path: &file_content.path,
+                  content: &file_content.content,
+                })
+                .collect::<Vec<_>>();
+              intrinsic.run(&files)
+            })
+          })
+          .map_err(move |e| throw(&format!("Plugin intrinsic {} failed: {}", name, e)))
+          .and_then(move |output| {
+            externs::call(
+              &externs::val_for(&constructor.0),
+              &[externs::store_bytes(&output)],
+            )
+          })
+      })
+      .to_boxed()
+  }
+
+  fn snapshot_at_revision(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+  ) -> NodeFuture<Arc<fs::Snapshot>> {
+    let edges = context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for SnapshotAtRevision intrinsic.");
+    // Compute PathGlobsAtRevision for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.path_globs_at_revision,
+      self.subject,
+      self.variants.clone(),
+      &edges,
+    ).run(context.clone())
+      .and_then(move |value| context.get(SnapshotAtRevision(externs::key_for(value))))
+      .to_boxed()
+  }
+
   fn execute_process(
     &self,
     context: &Context,
@@ -299,9 +642,115 @@ impl Select {
     ).run(context.clone())
       .and_then(|process_request_val| {
         ExecuteProcess::lift(&process_request_val)
-          .map_err(|str| throw(&format!("Error lifting ExecuteProcess: {}", str)))
+          .map_err(|str| {
+            throw_category(
+              ErrorCategory::UserRule,
+              &format!("Error lifting ExecuteProcess: {}", str),
+            )
+          })
+      })
+      .and_then(move |process_request| {
+        context
+          .core
+          .process_result_counters
+          .lock()
+          .unwrap()
+          .record_requested();
+        context.get(process_request)
+      })
+      .to_boxed()
+  }
+
+  fn read_file_range(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for ReadFileRange intrinsic.");
+    // Compute a FileRange for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.file_range,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(move |file_range_val| {
+        ReadFileRange::lift(&context, &file_range_val)
+          .and_then(move |read_file_range| context.get(read_file_range))
+      })
+      .to_boxed()
+  }
+
+  fn fetch_binary_tool(
+    &self,
+    context: &Context,
+    entry: &rule_graph::Entry,
+  ) -> NodeFuture<Arc<fs::Snapshot>> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for FetchBinaryTool intrinsic.");
+    // Compute a BinaryToolToFetch for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.binary_tool_to_fetch,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(|binary_tool_val| {
+        FetchBinaryTool::lift(&binary_tool_val).map_err(|str| {
+          throw_category(
+            ErrorCategory::UserRule,
+            &format!("Error lifting BinaryToolToFetch: {}", str),
+          )
+        })
+      })
+      .and_then(move |fetch_binary_tool| context.get(fetch_binary_tool))
+      .to_boxed()
+  }
+
+  fn changed_files(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for ChangedFiles intrinsic.");
+    // Compute a ChangedFilesRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.changed_files_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(move |request_val| {
+        let changes_since = externs::project_str(&request_val, "changes_since");
+        context.get(ChangedFiles(changes_since))
+      })
+      .to_boxed()
+  }
+
+  fn environment(&self, context: &Context, entry: &rule_graph::Entry) -> NodeFuture<Value> {
+    let edges = &context
+      .core
+      .rule_graph
+      .edges_for_inner(entry)
+      .expect("Expected edges to exist for Environment intrinsic.");
+    // Compute an EnvironmentRequest for the subject.
+    let context = context.clone();
+    Select::new(
+      context.core.types.environment_request,
+      self.subject,
+      self.variants.clone(),
+      edges,
+    ).run(context.clone())
+      .and_then(move |request_val| {
+        let names = externs::project_multi_strs(&request_val, "names");
+        context.get(ReadEnvironment(names))
       })
-      .and_then(move |process_request| context.get(process_request))
       .to_boxed()
   }
 
@@ -310,7 +759,7 @@ impl Select {
   /// given subject and variants.
   ///
   fn gen_nodes(&self, context: &Context) -> Vec<NodeFuture<Value>> {
-    if let Some(&(_, ref value)) = context.core.tasks.gen_singleton(self.product()) {
+    if let Some((_, value)) = context.core.tasks.gen_singleton(self.product()) {
       return vec![future::ok(value.clone()).to_boxed()];
     }
 
@@ -319,6 +768,16 @@ impl Select {
       .iter()
       .map(
         |entry| match context.core.rule_graph.rule_for_inner(entry) {
+          &rule_graph::Rule::Task(ref task) if entry.is_subject_independent() => {
+            // Shared by all subjects (and variants).
+            context.get(Task {
+              subject: context.core.provider_subject,
+              product: self.product().clone(),
+              variants: Variants::default(),
+              task: task.clone(),
+              entry: Arc::new(entry.clone()),
+            })
+          }
           &rule_graph::Rule::Task(ref task) => context.get(Task {
             subject: self.subject,
             product: self.product().clone(),
@@ -336,6 +795,16 @@ impl Select {
               .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
               .to_boxed()
           }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::SnapshotAtRevision,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .snapshot_at_revision(&context, &entry)
+              .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
+              .to_boxed()
+          }
           &rule_graph::Rule::Intrinsic(Intrinsic {
             kind: IntrinsicKind::FilesContent,
             ..
@@ -356,7 +825,7 @@ impl Select {
                 lift_digest(&directory_digest_val).map_err(|str| throw(&str))
               })
               .and_then(move |digest| {
-                let store = context.core.store.clone();
+                let store = context.store();
                 context
                   .core
                   .store
@@ -387,33 +856,99 @@ impl Select {
                 externs::unsafe_call(
                   &context.core.types.construct_process_result,
                   &[
-                    externs::store_bytes(&result.0.stdout),
-                    externs::store_bytes(&result.0.stderr),
-                    externs::store_i64(result.0.exit_code.into()),
-                    Snapshot::store_directory(&context.core, &result.0.output_directory),
+                    result.store_stdout(&context.core),
+                    result.store_stderr(&context.core),
+                    externs::store_i64(result.result.exit_code.into()),
+                    Snapshot::store_directory(&context.core, &result.result.output_directory),
+                    externs::store_bool(result.result.stdout_overflow.is_some()),
+                    externs::store_bool(result.result.stderr_overflow.is_some()),
+                    externs::store_utf8(result.result.source.name()),
                   ],
                 )
               })
               .to_boxed()
           }
-        },
-      )
-      .collect::<Vec<NodeFuture<Value>>>()
-  }
-}
-
-// TODO: This is a Node only because it is used as a root in the graph, but it should never be
-// requested using context.get
-impl WrappedNode for Select {
-  type Item = Value;
-
-  fn run(self, context: Context) -> NodeFuture<Value> {
-    // TODO add back support for variants https://github.com/pantsbuild/pants/issues/4020
-
-    // If there is a variant_key, see whether it has been configured; if not, no match.
-    let variant_value: Option<String> = match self.selector.variant_key {
-      Some(ref variant_key) => {
-        let variant_value = self.variants.find(variant_key);
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::ReadFileRange,
+            ..
+          }) => self.read_file_range(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::FetchBinaryTool,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .fetch_binary_tool(&context, &entry)
+              .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
+              .to_boxed()
+          }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::ChangedFiles,
+            ..
+          }) => self.changed_files(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::AddressNames,
+            ..
+          }) => self.address_names(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::SourceImports,
+            ..
+          }) => self.source_imports(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::FileSizeSummary,
+            ..
+          }) => self.file_size_summary(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::Environment,
+            ..
+          }) => self.environment(context, &entry),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::Checksums,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .checksums(&context, &entry)
+              .map(move |snapshot| Snapshot::store_snapshot(&context.core, &snapshot))
+              .to_boxed()
+          }
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::Plugin(index),
+            ..
+          }) => self.plugin_intrinsic(context, &entry, index),
+          &rule_graph::Rule::Intrinsic(Intrinsic {
+            kind: IntrinsicKind::DigestEquality,
+            ..
+          }) => {
+            let context = context.clone();
+            self
+              .digest_equality(&context, &entry)
+              .map(move |equal| {
+                externs::unsafe_call(
+                  &context.core.types.construct_digest_equality,
+                  &[externs::store_bool(equal)],
+                )
+              })
+              .to_boxed()
+          }
+        },
+      )
+      .collect::<Vec<NodeFuture<Value>>>()
+  }
+}
+
+// TODO: This is a Node only because it is used as a root in the graph, but it should never be
+// requested using context.get
+impl WrappedNode for Select {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    // TODO add back support for variants https://github.com/pantsbuild/pants/issues/4020
+
+    // If there is a variant_key, see whether it has been configured; if not, no match.
+    let variant_value: Option<String> = match self.selector.variant_key {
+      Some(ref variant_key) => {
+        let variant_value = self.variants.find(variant_key);
         if variant_value.is_none() {
           return err(Failure::Noop(Noop::NoVariant));
         }
@@ -456,29 +991,78 @@ impl From<Select> for NodeKey {
   }
 }
 
+///
+/// A Node that selects several products for the same subject, and returns them as a tuple in the
+/// order they were requested.
+///
+/// Because each product is requested via a Select for the same subject, any portion of the
+/// subject's dependency subgraph which is needed for more than one of the products is shared
+/// between them, exactly as it would be for separate roots in a single request.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SelectMultiple {
+  pub subject: Key,
+  pub selects: Vec<Select>,
+}
+
+// TODO: Like Select, this is a Node only because it is used as a root in the graph.
+impl WrappedNode for SelectMultiple {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    future::join_all(
+      self
+        .selects
+        .into_iter()
+        .map(|select| context.get(select))
+        .collect::<Vec<_>>(),
+    ).map(|values| externs::store_tuple(&values))
+      .to_boxed()
+  }
+}
+
+impl From<SelectMultiple> for NodeKey {
+  fn from(n: SelectMultiple) -> Self {
+    NodeKey::SelectMultiple(n)
+  }
+}
+
+///
+/// Lifts a python value representing a digest in the form of the REAPI Digest proto: that is, any
+/// value with a `hash` field containing a hex SHA-256 fingerprint, and a `size_bytes` field. This
+/// includes `DirectoryDigest` and `Digest`, as well as instances of the REAPI proto itself.
+///
 pub fn lift_digest(digest: &Value) -> Result<hashing::Digest, String> {
-  let fingerprint = externs::project_str(&digest, "fingerprint");
-  let digest_length = externs::project_str(&digest, "serialized_bytes_length");
-  let digest_length_as_usize = digest_length
-    .parse::<usize>()
-    .map_err(|err| format!("Length was not a usize: {:?}", err))?;
-  Ok(hashing::Digest(
-    hashing::Fingerprint::from_hex_string(&fingerprint)?,
-    digest_length_as_usize,
-  ))
+  let hash = externs::project_str(&digest, "hash");
+  let size_bytes = externs::project_str(&digest, "size_bytes");
+  let fingerprint = hashing::Fingerprint::from_hex_string(&hash).map_err(|err| {
+    format!(
+      "Invalid digest: hash {:?} was not {} hex characters: {}",
+      hash,
+      hashing::FINGERPRINT_SIZE * 2,
+      err
+    )
+  })?;
+  let size_bytes = size_bytes.parse::<usize>().map_err(|err| {
+    format!(
+      "Invalid digest: size_bytes {:?} was not a non-negative integer: {}",
+      size_bytes, err
+    )
+  })?;
+  Ok(hashing::Digest(fingerprint, size_bytes))
 }
 
 ///
 /// A Node that represents executing a process.
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct ExecuteProcess(process_execution::ExecuteProcessRequest);
+pub struct ExecuteProcess(pub process_execution::ExecuteProcessRequest);
 
 impl ExecuteProcess {
   ///
   /// Lifts a Key representing a python ExecuteProcessRequest value into a ExecuteProcess Node.
   ///
-  fn lift(value: &Value) -> Result<ExecuteProcess, String> {
+  pub fn lift(value: &Value) -> Result<ExecuteProcess, String> {
     let mut env: BTreeMap<String, String> = BTreeMap::new();
     let env_var_parts = externs::project_multi_strs(&value, "env");
     if env_var_parts.len() % 2 != 0 {
@@ -491,7 +1075,7 @@ impl ExecuteProcess {
       );
     }
     let digest = lift_digest(&externs::project_ignoring_type(&value, "input_files"))
-      .map_err(|err| format!("Error parsing digest {}", err))?;
+      .map_err(|err| format!("Error parsing input_files: {}", err))?;
 
     let output_files = externs::project_multi_strs(&value, "output_files")
       .into_iter()
@@ -503,6 +1087,12 @@ impl ExecuteProcess {
       .map(PathBuf::from)
       .collect();
 
+    let immutable_input_directories =
+      externs::project_multi_strs(&value, "immutable_input_directories")
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+
     let timeout_str = externs::project_str(&value, "timeout_seconds");
     let timeout_in_seconds = timeout_str
       .parse::<f64>()
@@ -510,33 +1100,169 @@ impl ExecuteProcess {
 
     let description = externs::project_str(&value, "description");
 
-    Ok(ExecuteProcess(process_execution::ExecuteProcessRequest {
+    // Zero means that the request does not limit its output.
+    let max_output_bytes = externs::project_str(&value, "max_output_bytes")
+      .parse::<usize>()
+      .map_err(|err| format!("Max output bytes was not an integer: {:?}", err))?;
+
+    // Zero means that the request does not limit the bytes that it writes to disk.
+    let max_disk_bytes = externs::project_str(&value, "max_disk_bytes")
+      .parse::<u64>()
+      .map_err(|err| format!("Max disk bytes was not an integer: {:?}", err))?;
+
+    // Empty means that the request may execute in any container image.
+    let container_image = externs::project_str(&value, "container_image");
+
+    // Empty means that the request does not receive an execution seed.
+    let execution_seed_env_var = externs::project_str(&value, "execution_seed_env_var");
+
+    let request = process_execution::ExecuteProcessRequest {
       argv: externs::project_multi_strs(&value, "argv"),
       env: env,
       input_files: digest,
       output_files: output_files,
       output_directories: output_directories,
+      immutable_input_directories: immutable_input_directories,
+      max_output_bytes: if max_output_bytes > 0 {
+        Some(max_output_bytes)
+      } else {
+        None
+      },
+      max_disk_bytes: if max_disk_bytes > 0 {
+        Some(max_disk_bytes)
+      } else {
+        None
+      },
+      cache_failures: externs::project_bool(&value, "cache_failures"),
+      container_image: if container_image.is_empty() {
+        None
+      } else {
+        Some(container_image)
+      },
       timeout: Duration::from_millis((timeout_in_seconds * 1000.0) as u64),
       description: description,
-    }))
+    };
+    if execution_seed_env_var.is_empty() {
+      Ok(ExecuteProcess(request))
+    } else {
+      process_execution::remote::with_execution_seed(request, execution_seed_env_var)
+        .map(ExecuteProcess)
+    }
   }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct ProcessResult(process_execution::FallibleExecuteProcessResult);
+///
+/// Outputs of processes which are smaller than this are passed to Python directly, rather than
+/// being written to the Store and passed as `LazyBytes`.
+///
+const LAZY_PROCESS_OUTPUT_MIN_BYTES: usize = 4 * 1024;
+
+///
+/// The result of executing a process, along with the Digests of those of its outputs which were
+/// large enough to be written to the Store (see `LAZY_PROCESS_OUTPUT_MIN_BYTES`), and which are
+/// thus only copied into Python if they are read: see `LazyBytes`.
+///
+/// The stored outputs are reported by `Node::digests`, so that they are leased for as long as the
+/// result is held by the Graph.
+///
+#[derive(Clone, Debug)]
+pub struct ProcessResult {
+  result: process_execution::FallibleExecuteProcessResult,
+  stdout_digest: Option<hashing::Digest>,
+  stderr_digest: Option<hashing::Digest>,
+}
+
+impl ProcessResult {
+  fn store_stdout(&self, core: &Arc<Core>) -> Value {
+    Self::store_output(core, &self.result.stdout, self.stdout_digest)
+  }
+
+  fn store_stderr(&self, core: &Arc<Core>) -> Value {
+    Self::store_output(core, &self.result.stderr, self.stderr_digest)
+  }
+
+  fn store_output(core: &Arc<Core>, bytes: &[u8], digest: Option<hashing::Digest>) -> Value {
+    match digest {
+      Some(digest) => Snapshot::store_lazy_bytes(core, &digest),
+      None => externs::store_bytes(bytes),
+    }
+  }
+
+  ///
+  /// Writes the given output to the Store if it is large enough to be passed to Python lazily.
+  ///
+  fn store_if_large(
+    context: &Context,
+    output: &Bytes,
+  ) -> BoxFuture<Option<hashing::Digest>, String> {
+    if output.len() < LAZY_PROCESS_OUTPUT_MIN_BYTES {
+      return future::ok(None).to_boxed();
+    }
+    context
+      .core
+      .store
+      .store_file_bytes(output.clone(), true)
+      .map(Some)
+      .to_boxed()
+  }
+}
+
+// The source of a result doesn't affect its dependents, so a re-run which produces the same output
+// in a different way (for example, from a cache rather than by executing) is not a change. The
+// Digests of the outputs are determined by the outputs, and so needn't be compared.
+impl PartialEq for ProcessResult {
+  fn eq(&self, other: &ProcessResult) -> bool {
+    self.result == process_execution::FallibleExecuteProcessResult {
+      source: self.result.source,
+      ..other.result.clone()
+    }
+  }
+}
+
+impl Eq for ProcessResult {}
 
 impl WrappedNode for ExecuteProcess {
   type Item = ProcessResult;
 
   fn run(self, context: Context) -> NodeFuture<ProcessResult> {
     let request = self.0;
+    let workunit = context
+      .core
+      .workunits
+      .start(request.description.clone(), request.input_files);
+    let workunit2 = workunit.clone();
 
+    // NB: The Core's command_runner retries failures itself: see `Core::new`.
     context
       .core
       .command_runner
-      .run(request)
-      .map(ProcessResult)
+      .run_in_workunit(request, workunit2)
+      .then(move |res| {
+        match res {
+          Ok(ref result) => workunit.completed(result.exit_code, result.source),
+          Err(ref e) => workunit.failed(e.to_string()),
+        };
+        res
+      })
       .map_err(|e| throw(&format!("Failed to execute process: {}", e)))
+      .and_then(move |result| {
+        context
+          .core
+          .process_result_counters
+          .lock()
+          .unwrap()
+          .record_computed(result.source);
+        let outputs = ProcessResult::store_if_large(&context, &result.stdout)
+          .join(ProcessResult::store_if_large(&context, &result.stderr))
+          .map_err(|e| throw(&format!("Failed to store process outputs: {}", e)));
+        // Written through to the remote store in the background: see `Store::write_through`.
+        context.store().write_through(vec![result.output_directory]);
+        outputs.map(move |(stdout_digest, stderr_digest)| ProcessResult {
+          result: result,
+          stdout_digest: stdout_digest,
+          stderr_digest: stderr_digest,
+        })
+      })
       .to_boxed()
   }
 }
@@ -547,96 +1273,539 @@ impl From<ExecuteProcess> for NodeKey {
   }
 }
 
-///
-/// A Node that represents reading the destination of a symlink (non-recursively).
-///
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct ReadLink(Link);
-
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct LinkDest(PathBuf);
-
-impl WrappedNode for ReadLink {
-  type Item = LinkDest;
+///
+/// A Node that represents fetching a binary tool into the Store. Because it is memoized in the
+/// Graph, concurrent requests for the same tool share a single download.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FetchBinaryTool(BinaryToolRequest);
+
+impl FetchBinaryTool {
+  ///
+  /// Lifts a python BinaryToolToFetch value into a FetchBinaryTool Node.
+  ///
+  fn lift(value: &Value) -> Result<FetchBinaryTool, String> {
+    let fingerprint =
+      hashing::Fingerprint::from_hex_string(&externs::project_str(&value, "fingerprint"))?;
+    let size_bytes = externs::project_str(&value, "size_bytes")
+      .parse::<usize>()
+      .map_err(|err| format!("size_bytes was not a usize: {:?}", err))?;
+    let archive_type = ArchiveType::create(&externs::project_str(&value, "archive_type"))?;
+    let timeout_secs = externs::project_str(&value, "timeout_secs")
+      .parse::<u64>()
+      .map_err(|err| format!("timeout_secs was not a u64: {:?}", err))?;
+    Ok(FetchBinaryTool(BinaryToolRequest {
+      name: externs::project_str(&value, "name"),
+      version: externs::project_str(&value, "version"),
+      platform: externs::project_str(&value, "platform"),
+      urls: externs::project_multi_strs(&value, "urls"),
+      digest: hashing::Digest(fingerprint, size_bytes),
+      archive_type: archive_type,
+      timeout: Duration::from_secs(timeout_secs),
+    }))
+  }
+}
+
+impl WrappedNode for FetchBinaryTool {
+  type Item = Arc<fs::Snapshot>;
+
+  fn run(self, context: Context) -> NodeFuture<Arc<fs::Snapshot>> {
+    download::fetch_binary_tool(
+      context.store(),
+      context.core.fs_pool.clone(),
+      self.0,
+    ).map(Arc::new)
+      .map_err(|e| throw(&e))
+      .to_boxed()
+  }
+}
+
+impl From<FetchBinaryTool> for NodeKey {
+  fn from(n: FetchBinaryTool) -> Self {
+    NodeKey::FetchBinaryTool(n)
+  }
+}
+
+///
+/// A Node that represents computing the files in the build root which have changed since a
+/// revision in git. Because the result depends on the state of the whole working tree, it is only
+/// memoized for the lifetime of a Session.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ChangedFiles(String);
+
+impl WrappedNode for ChangedFiles {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let build_root = context.core.build_root.clone();
+    let changes_since = self.0;
+    context
+      .core
+      .fs_pool
+      .spawn_fn(move || scm::changed_files(&build_root, &changes_since))
+      .map_err(|e| throw(&e))
+      .and_then(move |paths| -> Result<Value, Failure> {
+        let paths = paths
+          .iter()
+          .map(|path| {
+            path
+              .to_str()
+              .map(externs::store_utf8)
+              .ok_or_else(|| throw(&format!("Changed path {:?} was not valid UTF8", path)))
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        Ok(externs::unsafe_call(
+          &context.core.types.construct_changed_files,
+          &[externs::store_tuple(&paths)],
+        ))
+      })
+      .to_boxed()
+  }
+}
+
+impl From<ChangedFiles> for NodeKey {
+  fn from(n: ChangedFiles) -> Self {
+    NodeKey::ChangedFiles(n)
+  }
+}
+
+///
+/// A Node that represents reading the given variables from the environment of this process. Rules
+/// which read the environment directly are not invalidated when it changes, so instead they
+/// declare the variables that they need and request them via this Node, which is only memoized
+/// for the lifetime of a Session (and so re-reads the environment for each Session).
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ReadEnvironment(Vec<String>);
+
+impl WrappedNode for ReadEnvironment {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    // Variables which are not set are omitted.
+    let entries = self
+      .0
+      .iter()
+      .filter_map(|name| env::var_os(name).map(|value| (name, value)))
+      .map(|(name, value)| {
+        value
+          .into_string()
+          .map(|value| {
+            externs::store_tuple(&[externs::store_utf8(name), externs::store_utf8(&value)])
+          })
+          .map_err(|_| {
+            throw_category(
+              ErrorCategory::UserRule,
+              &format!("The value of the environment variable {} was not valid UTF8", name),
+            )
+          })
+      })
+      .collect::<Result<Vec<_>, _>>();
+    let entries = match entries {
+      Ok(entries) => entries,
+      Err(failure) => return err(failure),
+    };
+    future::ok(externs::unsafe_call(
+      &context.core.types.construct_environment,
+      &[externs::store_tuple(&entries)],
+    )).to_boxed()
+  }
+}
+
+impl From<ReadEnvironment> for NodeKey {
+  fn from(n: ReadEnvironment) -> Self {
+    NodeKey::ReadEnvironment(n)
+  }
+}
+
+///
+/// A Node that represents parsing the names of the targets defined by the BUILD files of a
+/// directory (without evaluating them), given the symbols which define targets.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ParseAddressNames {
+  digest: hashing::Digest,
+  symbols: Vec<String>,
+}
+
+impl ParseAddressNames {
+  ///
+  /// Lifts a python AddressNamesRequest value into a ParseAddressNames Node.
+  ///
+  fn lift(value: &Value) -> Result<ParseAddressNames, String> {
+    let digest = lift_digest(&externs::project_ignoring_type(&value, "directory_digest"))?;
+    let mut symbols = externs::project_multi_strs(&value, "symbols");
+    symbols.sort();
+    symbols.dedup();
+    Ok(ParseAddressNames {
+      digest: digest,
+      symbols: symbols,
+    })
+  }
+
+  fn store_address_names(
+    context: &Context,
+    address_names: &build_file_parser::AddressNames,
+  ) -> Value {
+    let names_by_path: Vec<_> = address_names
+      .names_by_path
+      .iter()
+      .map(|&(ref path, ref names)| {
+        let names: Vec<_> = names.iter().map(|n| externs::store_utf8(n)).collect();
+        externs::store_tuple(&[Snapshot::store_path(path), externs::store_tuple(&names)])
+      })
+      .collect();
+    externs::unsafe_call(
+      &context.core.types.construct_address_names,
+      &[
+        Snapshot::store_path(&address_names.namespace),
+        externs::store_tuple(&names_by_path),
+      ],
+    )
+  }
+}
+
+impl WrappedNode for ParseAddressNames {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let digest = self.digest;
+    let symbols = self.symbols.into_iter().collect::<HashSet<_>>();
+    let store = context.store();
+    context
+      .core
+      .store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+      })
+      .and_then(move |directory| store.contents_for_directory(&directory))
+      .and_then(move |files| build_file_parser::parse_address_names(&files, &symbols))
+      .map_err(|e| throw(&e))
+      .map(move |address_names| Self::store_address_names(&context, &address_names))
+      .to_boxed()
+  }
+}
+
+impl From<ParseAddressNames> for NodeKey {
+  fn from(n: ParseAddressNames) -> Self {
+    NodeKey::ParseAddressNames(n)
+  }
+}
+
+///
+/// A Node that represents scanning the files of a directory for the names that they import.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ScanImports {
+  digest: hashing::Digest,
+  language: Language,
+}
+
+impl ScanImports {
+  ///
+  /// Lifts a python SourceImportsRequest value into a ScanImports Node.
+  ///
+  fn lift(value: &Value) -> Result<ScanImports, String> {
+    Ok(ScanImports {
+      digest: lift_digest(&externs::project_ignoring_type(&value, "directory_digest"))?,
+      language: Language::create(&externs::project_str(&value, "language"))?,
+    })
+  }
+
+  fn store_source_imports(context: &Context, imports_by_path: &[(PathBuf, Vec<String>)]) -> Value {
+    let imports_by_path: Vec<_> = imports_by_path
+      .iter()
+      .map(|&(ref path, ref imports)| {
+        let imports: Vec<_> = imports.iter().map(|i| externs::store_utf8(i)).collect();
+        externs::store_tuple(&[Snapshot::store_path(path), externs::store_tuple(&imports)])
+      })
+      .collect();
+    externs::unsafe_call(
+      &context.core.types.construct_source_imports,
+      &[externs::store_tuple(&imports_by_path)],
+    )
+  }
+}
+
+impl WrappedNode for ScanImports {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let digest = self.digest;
+    let language = self.language;
+    let store = context.store();
+    let pool = context.core.fs_pool.clone();
+    context
+      .core
+      .store
+      .load_directory(digest)
+      .and_then(move |maybe_directory| {
+        maybe_directory.ok_or_else(|| format!("Could not find directory with digest {:?}", digest))
+      })
+      .and_then(move |directory| store.contents_for_directory(&directory))
+      .and_then(move |files| imports::scan_imports(pool, files, language))
+      .map_err(|e| throw(&e))
+      .map(move |imports_by_path| Self::store_source_imports(&context, &imports_by_path))
+      .to_boxed()
+  }
+}
+
+impl From<ScanImports> for NodeKey {
+  fn from(n: ScanImports) -> Self {
+    NodeKey::ScanImports(n)
+  }
+}
+
+///
+/// A Node that represents reading the destination of a symlink (non-recursively).
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ReadLink(Link);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LinkDest(PathBuf);
+
+impl WrappedNode for ReadLink {
+  type Item = LinkDest;
+
+  fn run(self, context: Context) -> NodeFuture<LinkDest> {
+    let link = self.0.clone();
+    let context2 = context.clone();
+    let read_link = move || context2.core.vfs.read_link(&self.0);
+    retry_transient(&context, read_link, is_transient_io_error)
+      .map(LinkDest)
+      .map_err(move |e| {
+        throw_category(
+          io_error_category(&e),
+          &format!("Failed to read_link for {:?}: {:?}", link, e),
+        )
+      })
+      .to_boxed()
+  }
+}
+
+impl From<ReadLink> for NodeKey {
+  fn from(n: ReadLink) -> Self {
+    NodeKey::ReadLink(n)
+  }
+}
+
+///
+/// A Node that represents reading a file and fingerprinting its contents.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DigestFile(pub File);
+
+impl WrappedNode for DigestFile {
+  type Item = hashing::Digest;
+
+  fn run(self, context: Context) -> NodeFuture<hashing::Digest> {
+    // A file which is hardlinked to from other paths is only read once: see `InodeDigests`. If the
+    // file can't be stat'd, reading it will fail with the reason.
+    let inode = context.core.vfs.inode(&self.0).or_else(|_| Ok::<_, Failure>(None));
+    inode
+      .and_then(move |inode| {
+        let inode_digests = context.core.inode_digests.clone();
+        inode_digests.digest(inode, move || DigestFile::read_and_store(self.0, context))
+      })
+      .to_boxed()
+  }
+}
+
+impl DigestFile {
+  fn read_and_store(file: File, context: Context) -> NodeFuture<hashing::Digest> {
+    let context2 = context.clone();
+    let file2 = file.clone();
+    let read_file = move || context2.core.vfs.read_file(&file2);
+    retry_transient(&context, read_file, is_transient_io_error)
+      .map_err(move |e| {
+        throw_category(
+          io_error_category(&e),
+          &format!("Error reading file {:?}: {:?}", file, e,),
+        )
+      })
+      .and_then(move |c| {
+        context
+          .core
+          .store
+          .store_file_bytes(c.content, true)
+          .map_err(|e| throw(&e))
+      })
+      .to_boxed()
+  }
+}
+
+impl From<DigestFile> for NodeKey {
+  fn from(n: DigestFile) -> Self {
+    NodeKey::DigestFile(n)
+  }
+}
+
+///
+/// A Node that represents reading a range of the bytes of a file, without fingerprinting them.
+///
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ReadFileRange {
+  // The path that was requested, which might traverse symlinks to reach `file`.
+  path: PathBuf,
+  file: File,
+  offset: u64,
+  length: u64,
+}
+
+impl ReadFileRange {
+  ///
+  /// Lifts a python FileRange value into a ReadFileRange Node, by expanding its path (which
+  /// follows symlinks and respects ignore patterns) to the single File that it must refer to.
+  ///
+  fn lift(context: &Context, value: &Value) -> NodeFuture<ReadFileRange> {
+    let path = externs::project_str(value, "path");
+    let lift_u64 = |field: &str| {
+      externs::project_str(value, field)
+        .parse::<u64>()
+        .map_err(|err| format!("FileRange {} was not a non-negative integer: {:?}", field, err))
+    };
+    let range = lift_u64("offset").and_then(|offset| lift_u64("length").map(|len| (offset, len)));
+    let (offset, length) = match range {
+      Ok(range) => range,
+      Err(e) => return err(throw_category(ErrorCategory::UserRule, &e)),
+    };
+    let path_globs = match PathGlobs::create(&[path.clone()], &[], StrictGlobMatching::Ignore) {
+      Ok(path_globs) => path_globs,
+      Err(e) => {
+        return err(throw_category(
+          ErrorCategory::UserRule,
+          &format!("Failed to parse FileRange path {}: {}", path, e),
+        ))
+      }
+    };
+    context
+      .expand(path_globs)
+      .and_then(move |mut path_stats| {
+        if path_stats.len() == 1 {
+          if let Some(PathStat::File { path, stat }) = path_stats.pop() {
+            return Ok(ReadFileRange {
+              path: path.to_path_buf(),
+              file: stat,
+              offset: offset,
+              length: length,
+            });
+          }
+        }
+        Err(throw_category(
+          ErrorCategory::MissingFile,
+          &format!("FileRange path {} did not match exactly one file.", path),
+        ))
+      })
+      .to_boxed()
+  }
+}
+
+impl WrappedNode for ReadFileRange {
+  type Item = Value;
 
-  fn run(self, context: Context) -> NodeFuture<LinkDest> {
-    let link = self.0.clone();
-    context
-      .core
-      .vfs
-      .read_link(&self.0)
-      .map(LinkDest)
-      .map_err(move |e| throw(&format!("Failed to read_link for {:?}: {:?}", link, e)))
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let path = self.path.clone();
+    let file = self.file.clone();
+    let context2 = context.clone();
+    let read_file_range =
+      move || context2.core.vfs.read_file_range(&self.file, self.offset, self.length);
+    retry_transient(&context, read_file_range, is_transient_io_error)
+      .map_err(move |e| {
+        throw_category(
+          io_error_category(&e),
+          &format!("Error reading file {:?}: {:?}", file, e),
+        )
+      })
+      .map(move |mut file_content| {
+        file_content.path = path;
+        Snapshot::store_file_content(&context, &file_content)
+      })
       .to_boxed()
   }
 }
 
-impl From<ReadLink> for NodeKey {
-  fn from(n: ReadLink) -> Self {
-    NodeKey::ReadLink(n)
+impl From<ReadFileRange> for NodeKey {
+  fn from(n: ReadFileRange) -> Self {
+    NodeKey::ReadFileRange(n)
   }
 }
 
 ///
-/// A Node that represents reading a file and fingerprinting its contents.
+/// A Node that represents executing a directory listing that returns a Stat per directory
+/// entry (generally in one syscall). No symlinks are expanded.
+///
+/// Large directories are not listed (see `PosixFS::scandir_unless_large`): instead, the entries of
+/// a large directory which match a particular wildcard are streamed by a ScandirMatching Node.
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct DigestFile(pub File);
+pub struct Scandir(Dir);
 
-impl WrappedNode for DigestFile {
-  type Item = hashing::Digest;
+impl WrappedNode for Scandir {
+  type Item = Option<Arc<DirectoryListing>>;
 
-  fn run(self, context: Context) -> NodeFuture<hashing::Digest> {
-    let file = self.0.clone();
-    context
-      .core
-      .vfs
-      .read_file(&self.0)
-      .map_err(move |e| throw(&format!("Error reading file {:?}: {:?}", file, e,)))
-      .and_then(move |c| {
-        context
-          .core
-          .store
-          .store_file_bytes(c.content, true)
-          .map_err(|e| throw(&e))
+  fn run(self, context: Context) -> NodeFuture<Option<Arc<DirectoryListing>>> {
+    let dir = self.0.clone();
+    let context2 = context.clone();
+    let scandir = move || context2.core.vfs.scandir_unless_large(&self.0);
+    retry_transient(&context, scandir, is_transient_io_error)
+      .then(move |listing_res| match listing_res {
+        Ok(listing_opt) => Ok(listing_opt.map(Arc::new)),
+        Err(e) => Err(throw_category(
+          io_error_category(&e),
+          &format!("Failed to scandir for {:?}: {:?}", dir, e),
+        )),
       })
       .to_boxed()
   }
 }
 
-impl From<DigestFile> for NodeKey {
-  fn from(n: DigestFile) -> Self {
-    NodeKey::DigestFile(n)
+impl From<Scandir> for NodeKey {
+  fn from(n: Scandir) -> Self {
+    NodeKey::Scandir(n)
   }
 }
 
 ///
-/// A Node that represents executing a directory listing that returns a Stat per directory
-/// entry (generally in one syscall). No symlinks are expanded.
+/// A Node that lists the entries of a large directory which match a ListingFilter, by streaming
+/// the listing of the directory so that only the matching entries are held in memory (see
+/// `PosixFS::scandir_matching`).
 ///
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct Scandir(Dir);
+pub struct ScandirMatching {
+  dir: Dir,
+  filter: ListingFilter,
+}
 
-impl WrappedNode for Scandir {
+impl WrappedNode for ScandirMatching {
   type Item = Arc<DirectoryListing>;
 
   fn run(self, context: Context) -> NodeFuture<Arc<DirectoryListing>> {
-    let dir = self.0.clone();
-    context
-      .core
-      .vfs
-      .scandir(&self.0)
+    let dir = self.dir.clone();
+    let context2 = context.clone();
+    let scandir = move || {
+      context2
+        .core
+        .vfs
+        .scandir_matching(&self.dir, &self.filter)
+    };
+    retry_transient(&context, scandir, is_transient_io_error)
       .then(move |listing_res| match listing_res {
         Ok(listing) => Ok(Arc::new(listing)),
-        Err(e) => Err(throw(&format!("Failed to scandir for {:?}: {:?}", dir, e))),
+        Err(e) => Err(throw_category(
+          io_error_category(&e),
+          &format!("Failed to scandir for {:?}: {:?}", dir, e),
+        )),
       })
       .to_boxed()
   }
 }
 
-impl From<Scandir> for NodeKey {
-  fn from(n: Scandir) -> Self {
-    NodeKey::Scandir(n)
+impl From<ScandirMatching> for NodeKey {
+  fn from(n: ScandirMatching) -> Self {
+    NodeKey::ScandirMatching(n)
   }
 }
 
@@ -650,18 +1819,86 @@ impl Snapshot {
   fn create(context: Context, path_globs: PathGlobs) -> NodeFuture<fs::Snapshot> {
     // Recursively expand PathGlobs into PathStats.
     // We rely on Context::expand tracking dependencies for scandirs,
-    // and fs::Snapshot::from_path_stats tracking dependencies for file digests.
+    // and fs::SnapshotMemo::from_path_stats tracking dependencies for file digests.
     context
       .expand(path_globs)
       .map_err(|e| format!("PathGlobs expansion failed: {:?}", e))
       .and_then(move |path_stats| {
-        fs::Snapshot::from_path_stats(context.core.store.clone(), context.clone(), path_stats)
+        context
+          .core
+          .snapshot_memo
+          .from_path_stats(context.store(), context.clone(), path_stats)
           .map_err(move |e| format!("Snapshot failed: {}", e))
       })
       .map_err(|e| throw(&e))
       .to_boxed()
   }
 
+  ///
+  /// Loads the Snapshot for the given PathGlobs from the snapshot cache, or else creates it and
+  /// stores it in the cache.
+  ///
+  fn create_cached(
+    context: Context,
+    subject: Key,
+    path_globs: PathGlobs,
+    cache: Arc<SnapshotCache>,
+    fingerprint: hashing::Fingerprint,
+  ) -> NodeFuture<fs::Snapshot> {
+    let load_cache = cache.clone();
+    let store = context.store();
+    context
+      .core
+      .fs_pool
+      .spawn_fn(move || Ok::<_, String>(load_cache.load(fingerprint)))
+      .and_then(move |cached| match cached {
+        // A cached Snapshot can only be used if the Store still holds its contents.
+        Some(snapshot) => store
+          .expand_directory(snapshot.digest)
+          .map(move |_| Some(snapshot))
+          .to_boxed(),
+        None => future::ok(None).to_boxed(),
+      })
+      .then(move |cached| match cached {
+        Ok(Some(snapshot)) => {
+          cache.restored(subject);
+          future::ok(snapshot).to_boxed()
+        }
+        cached => {
+          if let Err(e) = cached {
+            debug!("Not using cached Snapshot: {}", e);
+          }
+          let fs_pool = context.core.fs_pool.clone();
+          Self::create(context, path_globs)
+            .and_then(move |snapshot| {
+              fs_pool.spawn_fn(move || {
+                if let Err(e) = cache.store(fingerprint, &snapshot) {
+                  warn!("Failed to cache Snapshot: {}", e);
+                }
+                Ok(snapshot)
+              })
+            })
+            .to_boxed()
+        }
+      })
+      .to_boxed()
+  }
+
+  fn cache_fingerprint(
+    cache: &SnapshotCache,
+    generation: &str,
+    item: &Value,
+  ) -> hashing::Fingerprint {
+    let glob_match_error_behavior =
+      externs::project_ignoring_type(item, "glob_match_error_behavior");
+    cache.fingerprint(
+      generation,
+      &externs::project_multi_strs(item, "include"),
+      &externs::project_multi_strs(item, "exclude"),
+      &externs::project_str(&glob_match_error_behavior, "failure_behavior"),
+    )
+  }
+
   pub fn lift_path_globs(item: &Value) -> Result<PathGlobs, String> {
     let include = externs::project_multi_strs(item, "include");
     let exclude = externs::project_multi_strs(item, "exclude");
@@ -687,6 +1924,16 @@ impl Snapshot {
     )
   }
 
+  pub fn store_lazy_bytes(core: &Arc<Core>, item: &hashing::Digest) -> Value {
+    externs::unsafe_call(
+      &core.types.construct_lazy_bytes,
+      &[
+        externs::store_utf8(&item.0.to_hex()),
+        externs::store_i64(item.1 as i64),
+      ],
+    )
+  }
+
   pub fn store_snapshot(core: &Arc<Core>, item: &fs::Snapshot) -> Value {
     let path_stats: Vec<_> = item
       .path_stats
@@ -703,7 +1950,7 @@ impl Snapshot {
   }
 
   fn store_path(item: &Path) -> Value {
-    externs::store_bytes(item.as_os_str().as_bytes())
+    externs::store_bytes(&fs::os::os_str_bytes(item.as_os_str()))
   }
 
   fn store_dir(core: &Arc<Core>, item: &Dir) -> Value {
@@ -712,7 +1959,10 @@ impl Snapshot {
   }
 
   fn store_file(core: &Arc<Core>, item: &File) -> Value {
-    let args = [Self::store_path(item.path.as_path())];
+    let args = [
+      Self::store_path(item.path.as_path()),
+      externs::store_i64(item.size as i64),
+    ];
     externs::unsafe_call(&core.types.construct_file, &args)
   }
 
@@ -754,11 +2004,33 @@ impl WrappedNode for Snapshot {
   type Item = Arc<fs::Snapshot>;
 
   fn run(self, context: Context) -> NodeFuture<Arc<fs::Snapshot>> {
-    let lifted_path_globs = Self::lift_path_globs(&externs::val_for(&self.0));
-    future::result(lifted_path_globs)
-      .map_err(|e| throw(&format!("Failed to parse PathGlobs: {}", e)))
-      .and_then(move |path_globs| Self::create(context, path_globs))
-      .map(Arc::new)
+    let value = externs::val_for(&self.0);
+    let path_globs = match Self::lift_path_globs(&value) {
+      Ok(path_globs) => path_globs,
+      Err(e) => {
+        return err(throw_category(
+          ErrorCategory::UserRule,
+          &format!("Failed to parse PathGlobs: {}", e),
+        ))
+      }
+    };
+    // The generation is captured before the globs are expanded, so that a Snapshot which was
+    // captured while files were changing is never cached under a later generation.
+    let cached = context.core.snapshot_cache.clone().and_then(|cache| {
+      let generation = cache.generation()?;
+      let fingerprint = Self::cache_fingerprint(&cache, &generation, &value);
+      Some((cache, fingerprint))
+    });
+    let context2 = context.clone();
+    match cached {
+      Some((cache, fingerprint)) => {
+        Self::create_cached(context, self.0, path_globs, cache, fingerprint)
+      }
+      None => Self::create(context, path_globs),
+    }.map(move |snapshot| {
+      context2.store().write_through(vec![snapshot.digest]);
+      Arc::new(snapshot)
+    })
       .to_boxed()
   }
 }
@@ -769,6 +2041,112 @@ impl From<Snapshot> for NodeKey {
   }
 }
 
+///
+/// A Node that captures an fs::Snapshot for a PathGlobsAtRevision subject, by expanding the globs
+/// against (and storing file content from) the git object database rather than the working copy.
+///
+/// Because revisions other than commit ids may move, it is only memoized for the lifetime of a
+/// Session.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SnapshotAtRevision(Key);
+
+impl WrappedNode for SnapshotAtRevision {
+  type Item = Arc<fs::Snapshot>;
+
+  fn run(self, context: Context) -> NodeFuture<Arc<fs::Snapshot>> {
+    let value = externs::val_for(&self.0);
+    let revision = externs::project_str(&value, "revision");
+    let path_globs =
+      match Snapshot::lift_path_globs(&externs::project_ignoring_type(&value, "path_globs")) {
+        Ok(path_globs) => path_globs,
+        Err(e) => {
+          return err(throw_category(
+            ErrorCategory::UserRule,
+            &format!("Failed to parse PathGlobs: {}", e),
+          ))
+        }
+      };
+    let store = context.store();
+    future::result(GitTreeFS::new(context.core.clone(), &revision))
+      .and_then(move |git_fs| {
+        git_fs
+          .expand(path_globs)
+          .map_err(|e| format!("PathGlobs expansion failed: {}", e))
+          .and_then(move |path_stats| {
+            fs::Snapshot::from_path_stats(store, git_fs, path_stats)
+              .map_err(move |e| format!("Snapshot failed: {}", e))
+          })
+      })
+      .map(Arc::new)
+      .map_err(|e| throw(&e))
+      .to_boxed()
+  }
+}
+
+impl From<SnapshotAtRevision> for NodeKey {
+  fn from(n: SnapshotAtRevision) -> Self {
+    NodeKey::SnapshotAtRevision(n)
+  }
+}
+
+///
+/// A Node that summarizes the sizes of the files matched by a PathGlobs subject, per directory.
+///
+/// Like a Snapshot, the globs are expanded via the Scandir Nodes (so that the summary is
+/// invalidated when the files change), but the sizes are taken from the Stats of the files, so
+/// their content is neither read nor stored.
+///
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SummarizeFileSizes(Key);
+
+impl SummarizeFileSizes {
+  fn store_file_size_summary(context: &Context, sizes: &[fs::DirectorySize]) -> Value {
+    let directories: Vec<_> = sizes
+      .iter()
+      .map(|size| {
+        externs::store_tuple(&[
+          Snapshot::store_path(&size.path),
+          externs::store_i64(size.file_count as i64),
+          externs::store_i64(size.total_bytes as i64),
+        ])
+      })
+      .collect();
+    externs::unsafe_call(
+      &context.core.types.construct_file_size_summary,
+      &[externs::store_tuple(&directories)],
+    )
+  }
+}
+
+impl WrappedNode for SummarizeFileSizes {
+  type Item = Value;
+
+  fn run(self, context: Context) -> NodeFuture<Value> {
+    let path_globs = match Snapshot::lift_path_globs(&externs::val_for(&self.0)) {
+      Ok(path_globs) => path_globs,
+      Err(e) => {
+        return err(throw_category(
+          ErrorCategory::UserRule,
+          &format!("Failed to parse PathGlobs: {}", e),
+        ))
+      }
+    };
+    context
+      .expand(path_globs)
+      .map(move |path_stats| {
+        Self::store_file_size_summary(&context, &fs::summarize_sizes(&path_stats))
+      })
+      .to_boxed()
+  }
+}
+
+impl From<SummarizeFileSizes> for NodeKey {
+  fn from(n: SummarizeFileSizes) -> Self {
+    NodeKey::SummarizeFileSizes(n)
+  }
+}
+
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct Task {
   subject: Key,
@@ -788,23 +2166,45 @@ impl Task {
       .into_iter()
       .map(|get| {
         let externs::Get(product, subject) = get;
+        let get = selectors::Get {
+          product: product,
+          subject: subject.type_id().clone(),
+        };
         let entries = context
           .core
           .rule_graph
           .edges_for_inner(&entry)
-          .expect("edges for task exist.")
-          .entries_for(&rule_graph::SelectKey::JustGet(selectors::Get {
-            product: product,
-            subject: subject.type_id().clone(),
-          }));
+          .map(|edges| edges.entries_for(&rule_graph::SelectKey::JustGet(get)))
+          .unwrap_or_else(Vec::new);
+        if entries.is_empty() {
+          return err(Self::missing_get(context, &entry, &get));
+        }
         Select::new_with_entries(product, subject, Variants::default(), entries)
           .run(context.clone())
           .map_err(was_required)
+          .to_boxed()
       })
       .collect::<Vec<_>>();
     future::join_all(get_futures).to_boxed()
   }
 
+  ///
+  /// A Failure for a Get that the rule graph has no entries for: generally because the Task did
+  /// not declare it, or declared it for a different subject type.
+  ///
+  fn missing_get(context: &Context, entry: &rule_graph::Entry, get: &selectors::Get) -> Failure {
+    let message = match context.core.rule_graph.rule_for_inner(entry) {
+      &rule_graph::Rule::Task(ref task) => {
+        rule_graph::missing_get_str(&context.core.tasks, task, get)
+      }
+      &rule_graph::Rule::Intrinsic(ref intrinsic) => format!(
+        "Intrinsic {:?} requested an undeclared Get: {:?}",
+        intrinsic.kind, get
+      ),
+    };
+    throw_category(ErrorCategory::UserRule, &message)
+  }
+
   ///
   /// Given a python generator Value, loop to request the generator's dependencies until
   /// it completes with a result Value.
@@ -830,12 +2230,56 @@ impl Task {
       })
     }).to_boxed()
   }
+
+  ///
+  /// Re-runs the given task function with the same dependencies, and warns if it does not
+  /// reproduce the given result.
+  ///
+  /// A rule that produces different outputs for identical inputs silently poisons caching, so
+  /// this is used to check a sample of Tasks when `nondeterministic_rule_check_rate` is enabled.
+  ///
+  fn check_determinism(context: &Context, task: &tasks::Task, deps: &[Value], result: &Value) {
+    if externs::satisfied_by(&context.core.types.generator, result) {
+      // A generator requests further dependencies, and so can't be re-run in isolation.
+      return;
+    }
+    let name = &task.name;
+    match externs::call(&externs::val_for(&task.func.0), deps) {
+      Ok(ref rerun) if Self::same_result(result, rerun) => {}
+      Ok(rerun) => warn!(
+        "Rule {} is nondeterministic: it produced both {} and {} for the same inputs.",
+        name,
+        externs::val_to_str(result),
+        externs::val_to_str(&rerun)
+      ),
+      Err(_) => warn!(
+        "Rule {} is nondeterministic: it failed when re-run with the same inputs.",
+        name
+      ),
+    }
+  }
+
+  ///
+  /// Python values often only implement identity equality, so values that represent Digests are
+  /// also compared by Digest.
+  ///
+  fn same_result(left: &Value, right: &Value) -> bool {
+    if left == right {
+      return true;
+    }
+    match (lift_digest(left), lift_digest(right)) {
+      (Ok(left_digest), Ok(right_digest)) => left_digest == right_digest,
+      _ => false,
+    }
+  }
 }
 
 impl WrappedNode for Task {
   type Item = Value;
 
   fn run(self, context: Context) -> NodeFuture<Value> {
+    let check_determinism = context.core.determinism_sampler.should_check(&self);
+    let task = self.task.clone();
     let deps = {
       let edges = &context
         .core
@@ -856,11 +2300,19 @@ impl WrappedNode for Task {
       )
     };
 
-    let func = self.task.func;
     let entry = self.entry;
+    let context2 = context.clone();
     deps
       .then(move |deps_result| match deps_result {
-        Ok(deps) => externs::call(&externs::val_for(&func.0), &deps),
+        Ok(deps) => {
+          let result = externs::call(&externs::val_for(&task.func.0), &deps);
+          if check_determinism {
+            if let Ok(ref value) = result {
+              Self::check_determinism(&context2, &task, &deps, value);
+            }
+          }
+          result
+        }
         Err(failure) => Err(failure),
       })
       .then(move |task_result| match task_result {
@@ -883,30 +2335,31 @@ impl From<Task> for NodeKey {
   }
 }
 
+///
+/// Visualizes Nodes colored by how long they took to run, relative to the other visualized Nodes.
+///
 #[derive(Default)]
-pub struct Visualizer {
-  viz_colors: HashMap<String, String>,
-}
+pub struct Visualizer;
 
 impl NodeVisualizer<NodeKey> for Visualizer {
   fn color_scheme(&self) -> &str {
-    "set312"
-  }
-
-  fn color(&mut self, node: &NodeKey, result: Option<Result<NodeResult, Failure>>) -> String {
-    let max_colors = 12;
-    match result {
-      None | Some(Err(Failure::Noop(_))) => "white".to_string(),
-      Some(Err(Failure::Throw(..))) => "4".to_string(),
-      Some(Err(Failure::Invalidated)) => "12".to_string(),
-      Some(Ok(_)) => {
-        let viz_colors_len = self.viz_colors.len();
-        self
-          .viz_colors
-          .entry(node.product_str())
-          .or_insert_with(|| format!("{}", viz_colors_len % max_colors + 1))
-          .clone()
-      }
+    "ylorrd9"
+  }
+
+  fn color(
+    &mut self,
+    _node: &NodeKey,
+    result: Option<Result<NodeResult, Failure>>,
+    duration_percentile: Option<f64>,
+  ) -> String {
+    match (result, duration_percentile) {
+      (None, _) | (Some(Err(Failure::Noop(_))), _) => "white".to_string(),
+      (Some(Err(Failure::Throw(..))), _) => "/x11/lightblue".to_string(),
+      (Some(Err(Failure::Invalidated)), _) => "/x11/gray".to_string(),
+      (Some(Err(Failure::Cancelled(_))), _) => "/x11/lightpink".to_string(),
+      (Some(Ok(_)), None) => "white".to_string(),
+      // Scale the percentile onto the 9 colors of the scheme.
+      (Some(Ok(_)), Some(percentile)) => format!("{}", 1 + (percentile * 8.0).round() as usize),
     }
   }
 }
@@ -917,6 +2370,9 @@ impl NodeTracer<NodeKey> for Tracer {
   fn is_bottom(result: Option<Result<NodeResult, Failure>>) -> bool {
     match result {
       Some(Err(Failure::Invalidated)) => false,
+      // The dependencies of a cancelled Node were cancelled along with it, so (as for Nodes that
+      // are still running) it's not useful to render them.
+      Some(Err(Failure::Cancelled(_))) => true,
       Some(Err(Failure::Noop(..))) => true,
       Some(Err(Failure::Throw(..))) => false,
       Some(Ok(_)) => true,
@@ -933,7 +2389,7 @@ impl NodeTracer<NodeKey> for Tracer {
     match result {
       None => "<None>".to_string(),
       Some(Ok(ref x)) => format!("{:?}", x),
-      Some(Err(Failure::Throw(ref x, ref traceback))) => format!(
+      Some(Err(Failure::Throw(ref x, ref traceback, ..))) => format!(
         "Throw({})\n{}",
         externs::val_to_str(x),
         traceback
@@ -944,18 +2400,29 @@ impl NodeTracer<NodeKey> for Tracer {
       ),
       Some(Err(Failure::Noop(ref x))) => format!("Noop({:?})", x),
       Some(Err(Failure::Invalidated)) => "Invalidated".to_string(),
+      Some(Err(Failure::Cancelled(ref message))) => format!("Cancelled({})", message),
     }
   }
 }
 
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub enum NodeKey {
+  ChangedFiles(ChangedFiles),
   DigestFile(DigestFile),
   ExecuteProcess(ExecuteProcess),
+  FetchBinaryTool(FetchBinaryTool),
+  ParseAddressNames(ParseAddressNames),
+  ReadEnvironment(ReadEnvironment),
+  ReadFileRange(ReadFileRange),
   ReadLink(ReadLink),
+  ScanImports(ScanImports),
   Scandir(Scandir),
+  ScandirMatching(ScandirMatching),
   Select(Select),
+  SelectMultiple(SelectMultiple),
   Snapshot(Snapshot),
+  SnapshotAtRevision(SnapshotAtRevision),
+  SummarizeFileSizes(SummarizeFileSizes),
   Task(Task),
 }
 
@@ -965,32 +2432,123 @@ impl NodeKey {
       externs::key_to_str(&tc.0)
     }
     match self {
+      &NodeKey::ChangedFiles(..) => "ChangedFiles".to_string(),
       &NodeKey::ExecuteProcess(..) => "ProcessResult".to_string(),
       &NodeKey::Select(ref s) => typstr(&s.selector.product),
+      &NodeKey::SelectMultiple(ref s) => format!(
+        "({})",
+        s.selects
+          .iter()
+          .map(|select| typstr(&select.selector.product))
+          .collect::<Vec<_>>()
+          .join(", ")
+      ),
       &NodeKey::Task(ref s) => typstr(&s.product),
       &NodeKey::Snapshot(..) => "Snapshot".to_string(),
+      &NodeKey::SnapshotAtRevision(..) => "Snapshot".to_string(),
       &NodeKey::DigestFile(..) => "DigestFile".to_string(),
+      &NodeKey::FetchBinaryTool(..) => "Snapshot".to_string(),
+      &NodeKey::ParseAddressNames(..) => "AddressNames".to_string(),
+      &NodeKey::ReadEnvironment(..) => "Environment".to_string(),
+      &NodeKey::ReadFileRange(..) => "FileContent".to_string(),
       &NodeKey::ReadLink(..) => "LinkDest".to_string(),
+      &NodeKey::ScanImports(..) => "SourceImports".to_string(),
       &NodeKey::Scandir(..) => "DirectoryListing".to_string(),
+      &NodeKey::ScandirMatching(..) => "DirectoryListing".to_string(),
+      &NodeKey::SummarizeFileSizes(..) => "FileSizeSummary".to_string(),
+    }
+  }
+
+  ///
+  /// Whether the result of this Node may be evicted from the Graph while the Scheduler is idle (see
+  /// `Scheduler::trim_memory`). Only Nodes which compute Values from the (memoized) results of
+  /// their dependencies are evictable: Nodes that observe the filesystem or the environment, or
+  /// that execute processes, are expensive to recompute, and might not compute the same result.
+  ///
+  pub fn evictable(&self) -> bool {
+    match self {
+      &NodeKey::Select { .. } | &NodeKey::SelectMultiple { .. } | &NodeKey::Task { .. } => true,
+
+      &NodeKey::ChangedFiles { .. }
+      | &NodeKey::DigestFile { .. }
+      | &NodeKey::ExecuteProcess { .. }
+      | &NodeKey::FetchBinaryTool { .. }
+      | &NodeKey::ParseAddressNames { .. }
+      | &NodeKey::ReadEnvironment { .. }
+      | &NodeKey::ReadFileRange { .. }
+      | &NodeKey::ReadLink { .. }
+      | &NodeKey::ScanImports { .. }
+      | &NodeKey::Scandir { .. }
+      | &NodeKey::ScandirMatching { .. }
+      | &NodeKey::Snapshot { .. }
+      | &NodeKey::SnapshotAtRevision { .. }
+      | &NodeKey::SummarizeFileSizes { .. } => false,
     }
   }
 
   pub fn fs_subject(&self) -> Option<&Path> {
     match self {
       &NodeKey::DigestFile(ref s) => Some(s.0.path.as_path()),
+      &NodeKey::ReadFileRange(ref s) => Some(s.file.path.as_path()),
       &NodeKey::ReadLink(ref s) => Some((s.0).0.as_path()),
       &NodeKey::Scandir(ref s) => Some((s.0).0.as_path()),
+      &NodeKey::ScandirMatching(ref s) => Some(s.dir.0.as_path()),
 
       // Not FS operations:
       // Explicitly listed so that if people add new NodeKeys they need to consider whether their
       // NodeKey represents an FS operation, and accordingly whether they need to add it to the
       // above list or the below list.
-      &NodeKey::ExecuteProcess { .. }
+      &NodeKey::ChangedFiles { .. }
+      | &NodeKey::ExecuteProcess { .. }
+      | &NodeKey::FetchBinaryTool { .. }
+      | &NodeKey::ParseAddressNames { .. }
+      | &NodeKey::ReadEnvironment { .. }
+      | &NodeKey::ScanImports { .. }
       | &NodeKey::Select { .. }
+      | &NodeKey::SelectMultiple { .. }
       | &NodeKey::Snapshot { .. }
+      | &NodeKey::SnapshotAtRevision { .. }
+      | &NodeKey::SummarizeFileSizes { .. }
       | &NodeKey::Task { .. } => None,
     }
   }
+
+  ///
+  /// Returns the subject of this Node if it is a Snapshot, whose result might have been restored
+  /// from the snapshot cache.
+  ///
+  pub fn snapshot_subject(&self) -> Option<&Key> {
+    match self {
+      &NodeKey::Snapshot(ref s) => Some(&s.0),
+      _ => None,
+    }
+  }
+
+  ///
+  /// Returns true if this Node's (current) result should only be memoized for the lifetime of a
+  /// Session, because it was produced by a rule that was not marked cacheable or by a provider,
+  /// because it depends on state which is not tracked by invalidation, because it is the failed
+  /// result of a process which should not cache failures, or because it is an infrastructure
+  /// failure. Infrastructure failures include RPCs to the remote store which were cut short by the
+  /// deadline of the Session that ran them (see `Context::store`), and must not be returned to a
+  /// later Session which has a longer deadline or none at all.
+  ///
+  pub fn is_session_scoped(&self, result: Option<Result<&NodeResult, &Failure>>) -> bool {
+    if let Some(Err(&Failure::Throw(_, _, _, ErrorCategory::Infrastructure))) = result {
+      return true;
+    }
+    match self {
+      &NodeKey::ChangedFiles(..)
+      | &NodeKey::ReadEnvironment(..)
+      | &NodeKey::SnapshotAtRevision(..) => true,
+      &NodeKey::Task(ref s) => !s.task.cacheable || s.task.provider,
+      &NodeKey::ExecuteProcess(ref e) if !e.0.cache_failures => match result {
+        Some(Ok(&NodeResult::ProcessResult(ref p))) => p.result.exit_code != 0,
+        _ => false,
+      },
+      _ => false,
+    }
+  }
 }
 
 impl Node for NodeKey {
@@ -1000,15 +2558,37 @@ impl Node for NodeKey {
   type Error = Failure;
 
   fn run(self, context: Context) -> NodeFuture<NodeResult> {
-    match self {
+    let node = self.clone();
+    // Convert any panic while running this Node into a Failure, rather than leaving it incomplete.
+    let result = panics::catch(move || match self {
+      NodeKey::ChangedFiles(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::DigestFile(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::ExecuteProcess(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::FetchBinaryTool(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::ParseAddressNames(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::ReadEnvironment(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::ReadFileRange(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::ReadLink(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::ScanImports(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::Scandir(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::ScandirMatching(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::Select(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::SelectMultiple(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::Snapshot(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::SnapshotAtRevision(n) => n.run(context).map(|v| v.into()).to_boxed(),
+      NodeKey::SummarizeFileSizes(n) => n.run(context).map(|v| v.into()).to_boxed(),
       NodeKey::Task(n) => n.run(context).map(|v| v.into()).to_boxed(),
-    }
+    });
+    // Record this Node in the engine traceback of any exception that propagates through it.
+    result
+      .map_err(move |failure| match failure {
+        Failure::Throw(exc, traceback, mut engine_traceback, category) => {
+          engine_traceback.push(node.format());
+          Failure::Throw(exc, traceback, engine_traceback, category)
+        }
+        f => f,
+      })
+      .to_boxed()
   }
 
   fn format(&self) -> String {
@@ -1021,33 +2601,141 @@ impl Node for NodeKey {
     // FIXME(cosmicexplorer): these should all be converted to fmt::Debug implementations, and then
     // this method can go away in favor of the auto-derived Debug for this type.
     match self {
+      &NodeKey::ChangedFiles(ref s) => format!("ChangedFiles({})", s.0),
       &NodeKey::DigestFile(ref s) => format!("DigestFile({:?})", s.0),
-      &NodeKey::ExecuteProcess(ref s) => format!("ExecuteProcess({:?}", s.0),
+      &NodeKey::ExecuteProcess(ref s) => {
+        if s.0.description.is_empty() {
+          format!("ExecuteProcess({:?})", s.0.argv)
+        } else {
+          format!("ExecuteProcess({})", s.0.description)
+        }
+      }
+      &NodeKey::FetchBinaryTool(ref s) => format!("FetchBinaryTool({:?})", s.0),
+      &NodeKey::ParseAddressNames(ref s) => format!("ParseAddressNames({:?})", s.digest),
+      &NodeKey::ReadEnvironment(ref s) => format!("ReadEnvironment({})", s.0.join(", ")),
+      &NodeKey::ReadFileRange(ref s) => format!(
+        "ReadFileRange({:?}, {}, {})",
+        s.file, s.offset, s.length
+      ),
       &NodeKey::ReadLink(ref s) => format!("ReadLink({:?})", s.0),
+      &NodeKey::ScanImports(ref s) => format!("ScanImports({:?}, {:?})", s.digest, s.language),
       &NodeKey::Scandir(ref s) => format!("Scandir({:?})", s.0),
+      &NodeKey::ScandirMatching(ref s) => {
+        format!(
+          "ScandirMatching({:?}, {}, traverses: {})",
+          s.dir,
+          s.filter.wildcard.as_str(),
+          s.filter.traverses
+        )
+      }
       &NodeKey::Select(ref s) => format!(
         "Select({}, {})",
         keystr(&s.subject),
         typstr(&s.selector.product)
       ),
-      &NodeKey::Task(ref s) => format!(
-        "Task({}, {}, {})",
-        externs::project_str(&externs::val_for(&s.task.func.0), "__name__"),
+      &NodeKey::SelectMultiple(ref s) => format!(
+        "SelectMultiple({}, {})",
         keystr(&s.subject),
-        typstr(&s.product)
+        self.product_str()
       ),
+      &NodeKey::Task(ref s) => match s.task.description {
+        Some(ref description) => format!(
+          "Task({}, {}, {}): {}",
+          s.task.name,
+          keystr(&s.subject),
+          typstr(&s.product),
+          description
+        ),
+        None => format!(
+          "Task({}, {}, {})",
+          s.task.name,
+          keystr(&s.subject),
+          typstr(&s.product)
+        ),
+      },
       &NodeKey::Snapshot(ref s) => format!("Snapshot({})", keystr(&s.0)),
+      &NodeKey::SnapshotAtRevision(ref s) => format!("SnapshotAtRevision({})", keystr(&s.0)),
+      &NodeKey::SummarizeFileSizes(ref s) => format!("SummarizeFileSizes({})", keystr(&s.0)),
+    }
+  }
+
+  fn user_facing_name(&self) -> Option<String> {
+    match self {
+      &NodeKey::ExecuteProcess(ref s) if !s.0.description.is_empty() => {
+        Some(s.0.description.clone())
+      }
+      // A provider's subject is a placeholder, so it is described without one.
+      &NodeKey::Task(ref s) if s.task.provider => s.task.description.clone(),
+      &NodeKey::Task(ref s) => s
+        .task
+        .description
+        .as_ref()
+        .map(|description| format!("{} ({})", description, externs::key_to_str(&s.subject))),
+      _ => None,
+    }
+  }
+
+  fn type_name(&self) -> &'static str {
+    match self {
+      &NodeKey::ChangedFiles(..) => "ChangedFiles",
+      &NodeKey::DigestFile(..) => "DigestFile",
+      &NodeKey::ExecuteProcess(..) => "ExecuteProcess",
+      &NodeKey::FetchBinaryTool(..) => "FetchBinaryTool",
+      &NodeKey::ParseAddressNames(..) => "ParseAddressNames",
+      &NodeKey::ReadEnvironment(..) => "ReadEnvironment",
+      &NodeKey::ReadFileRange(..) => "ReadFileRange",
+      &NodeKey::ReadLink(..) => "ReadLink",
+      &NodeKey::ScanImports(..) => "ScanImports",
+      &NodeKey::Scandir(..) => "Scandir",
+      &NodeKey::ScandirMatching(..) => "ScandirMatching",
+      &NodeKey::Select(..) => "Select",
+      &NodeKey::SelectMultiple(..) => "SelectMultiple",
+      &NodeKey::Snapshot(..) => "Snapshot",
+      &NodeKey::SnapshotAtRevision(..) => "SnapshotAtRevision",
+      &NodeKey::SummarizeFileSizes(..) => "SummarizeFileSizes",
+      &NodeKey::Task(..) => "Task",
+    }
+  }
+
+  fn digests(res: NodeResult) -> Vec<hashing::Digest> {
+    match res {
+      NodeResult::Digest(d) => vec![d],
+      NodeResult::Snapshot(s) => vec![s.digest],
+      NodeResult::ProcessResult(p) => {
+        let mut digests = vec![p.result.output_directory];
+        digests.extend(p.stdout_digest);
+        digests.extend(p.stderr_digest);
+        digests
+      }
+      NodeResult::DirectoryListing(_) | NodeResult::LinkDest(_) | NodeResult::Value(_) => vec![],
     }
   }
 
-  fn digest(res: NodeResult) -> Option<hashing::Digest> {
+  fn heap_size(res: &NodeResult) -> (&'static str, usize) {
+    // NB: The paths of Stats and PathStats are interned, and accounted for by `InternedPath`, and
+    // the memory held by Values is held by python.
     match res {
-      NodeResult::Digest(d) => Some(d),
-      NodeResult::DirectoryListing(_)
-      | NodeResult::LinkDest(_)
-      | NodeResult::ProcessResult(_)
-      | NodeResult::Snapshot(_)
-      | NodeResult::Value(_) => None,
+      &NodeResult::Digest(_) => ("Digest", 0),
+      &NodeResult::DirectoryListing(ref listing_opt) => (
+        "DirectoryListing",
+        listing_opt
+          .as_ref()
+          .map(|listing| {
+            mem::size_of::<DirectoryListing>() + listing.0.capacity() * mem::size_of::<fs::Stat>()
+          })
+          .unwrap_or(0),
+      ),
+      &NodeResult::LinkDest(ref dest) => ("LinkDest", dest.0.as_os_str().len()),
+      &NodeResult::ProcessResult(ref result) => (
+        "ProcessResult",
+        result.result.stdout.len() + result.result.stderr.len(),
+      ),
+      &NodeResult::Snapshot(ref snapshot) => (
+        "Snapshot",
+        mem::size_of::<fs::Snapshot>()
+          + snapshot.path_stats.capacity() * mem::size_of::<PathStat>(),
+      ),
+      &NodeResult::Value(_) => ("Value", 0),
     }
   }
 }
@@ -1060,12 +2748,17 @@ impl NodeError for Failure {
   fn cyclic() -> Failure {
     Failure::Noop(Noop::Cycle)
   }
+
+  fn limit_exceeded(message: String) -> Failure {
+    throw_category(ErrorCategory::UserRule, &message)
+  }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum NodeResult {
   Digest(hashing::Digest),
-  DirectoryListing(Arc<DirectoryListing>),
+  // None for a directory which was too large to be listed in full (see `Scandir`).
+  DirectoryListing(Option<Arc<DirectoryListing>>),
   LinkDest(LinkDest),
   ProcessResult(ProcessResult),
   Snapshot(Arc<fs::Snapshot>),
@@ -1104,6 +2797,12 @@ impl From<LinkDest> for NodeResult {
 
 impl From<Arc<DirectoryListing>> for NodeResult {
   fn from(v: Arc<DirectoryListing>) -> Self {
+    NodeResult::DirectoryListing(Some(v))
+  }
+}
+
+impl From<Option<Arc<DirectoryListing>>> for NodeResult {
+  fn from(v: Option<Arc<DirectoryListing>>) -> Self {
     NodeResult::DirectoryListing(v)
   }
 }
@@ -1197,6 +2896,17 @@ impl TryFrom<NodeResult> for LinkDest {
 impl TryFrom<NodeResult> for Arc<DirectoryListing> {
   type Err = ();
 
+  fn try_from(nr: NodeResult) -> Result<Self, ()> {
+    match nr {
+      NodeResult::DirectoryListing(Some(v)) => Ok(v),
+      _ => Err(()),
+    }
+  }
+}
+
+impl TryFrom<NodeResult> for Option<Arc<DirectoryListing>> {
+  type Err = ();
+
   fn try_from(nr: NodeResult) -> Result<Self, ()> {
     match nr {
       NodeResult::DirectoryListing(v) => Ok(v),
@@ -1204,3 +2914,39 @@ impl TryFrom<NodeResult> for Arc<DirectoryListing> {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::{NodeKey, ReadLink, Tracer, Visualizer};
+  use core::Failure;
+  use fs::Link;
+  use graph::{NodeTracer, NodeVisualizer};
+  use std::path::PathBuf;
+
+  fn cancelled() -> Failure {
+    Failure::Cancelled("The request was cancelled before it completed, because it stalled.".into())
+  }
+
+  #[test]
+  fn tracer_renders_cancellation_reason() {
+    assert!(Tracer::is_bottom(Some(Err(cancelled()))));
+    assert_eq!(
+      Tracer::state_str("  ", Some(Err(cancelled()))),
+      "Cancelled(The request was cancelled before it completed, because it stalled.)"
+    );
+  }
+
+  #[test]
+  fn visualizer_colors_cancellations() {
+    let node = NodeKey::ReadLink(ReadLink(Link(PathBuf::from("a").into())));
+    let mut visualizer = Visualizer::default();
+    assert_eq!(
+      visualizer.color(&node, Some(Err(cancelled())), None),
+      "/x11/lightpink"
+    );
+    assert_ne!(
+      visualizer.color(&node, Some(Err(Failure::Invalidated)), None),
+      "/x11/lightpink"
+    );
+  }
+}
@@ -0,0 +1,198 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe, PanicInfo};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use backtrace::Backtrace;
+use futures::future::{self, Future};
+
+use boxfuture::{BoxFuture, Boxable};
+use context::Core;
+use core::{ErrorCategory, Failure};
+use externs;
+
+lazy_static! {
+  // The Cores of all live Schedulers, whose Graphs are dumped on panic. See `dump_graphs`.
+  static ref CORES: Mutex<Vec<Weak<Core>>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+  // The most recent panic observed by the hook on this thread. Because the hook runs on the
+  // panicking thread before unwinding begins, the `catch_unwind` that stops the unwinding can
+  // retrieve the details (most importantly the backtrace, which is unavailable once unwound).
+  static LAST_PANIC: RefCell<Option<PanicReport>> = RefCell::new(None);
+}
+
+///
+/// The details of a panic, captured at the point it occurred.
+///
+struct PanicReport {
+  message: String,
+  backtrace: String,
+}
+
+///
+/// Installs a panic hook which logs panics, records them for `catch`, and (if a directory is
+/// given) persists a crash report file for each of them.
+///
+pub fn set_hook(crash_report_dir: Option<PathBuf>) {
+  panic::set_hook(Box::new(move |panic_info| {
+    let mut message = format!("panic at '{}'", payload_str(panic_info.payload()));
+    if let Some(location) = panic_info.location() {
+      message.push_str(&format!(", {}:{}", location.file(), location.line()));
+    }
+    let report = PanicReport {
+      message: message,
+      backtrace: format!("{:?}", Backtrace::new()),
+    };
+
+    error!("{}", report.message);
+    debug!("{}", report.backtrace);
+    if let Some(ref dir) = crash_report_dir {
+      match write_crash_report(dir, panic_info, &report) {
+        Ok(path) => error!("Wrote a crash report to {}", path.display()),
+        Err(e) => error!("Failed to write a crash report to {}: {}", dir.display(), e),
+      }
+      dump_graphs(dir);
+    }
+    error!("Please file a bug at https://github.com/pantsbuild/pants/issues.");
+
+    LAST_PANIC.with(|last_panic| *last_panic.borrow_mut() = Some(report));
+  }));
+}
+
+///
+/// Runs the given function (and polls the future it returns), converting any panic into a
+/// `Failure::Throw` which carries the Rust backtrace of the panic as its traceback.
+///
+/// A panic in a spawned future would otherwise unwind through the executor, and the Node that it
+/// was computing would never complete.
+///
+pub fn catch<F, T>(f: F) -> BoxFuture<T, Failure>
+where
+  F: FnOnce() -> BoxFuture<T, Failure> + Send + 'static,
+  T: Send + 'static,
+{
+  AssertUnwindSafe(future::lazy(f))
+    .catch_unwind()
+    .then(|res| match res {
+      Ok(res) => res,
+      Err(payload) => Err(failure_from_panic(payload)),
+    })
+    .to_boxed()
+}
+
+///
+/// Registers the Core of a Scheduler, in order to dump its Graph on panic.
+///
+pub fn register_core(core: &Arc<Core>) {
+  let mut cores = CORES.lock().unwrap_or_else(|e| e.into_inner());
+  cores.retain(|core| core.upgrade().is_some());
+  cores.push(Arc::downgrade(core));
+}
+
+///
+/// Dumps the Graph of each live Scheduler to a file in the given directory (see `Graph::dump`),
+/// logging the outcome of each, and returns the paths that were written.
+///
+pub fn dump_graphs(dir: &Path) -> Vec<PathBuf> {
+  let cores = CORES
+    .lock()
+    .unwrap_or_else(|e| e.into_inner())
+    .iter()
+    .filter_map(|core| core.upgrade())
+    .collect::<Vec<_>>();
+  if let Err(e) = fs::create_dir_all(dir) {
+    error!("Failed to create {} to dump graphs to: {}", dir.display(), e);
+    return vec![];
+  }
+  let timestamp = timestamp_str();
+  cores
+    .into_iter()
+    .enumerate()
+    .filter_map(|(index, core)| {
+      let path = dir.join(format!(
+        "graph-{}-{}-{}.txt",
+        process::id(),
+        timestamp,
+        index
+      ));
+      match core.graph.dump(&path) {
+        Ok(()) => {
+          error!("Dumped the engine's graph to {}", path.display());
+          Some(path)
+        }
+        Err(e) => {
+          error!("Failed to dump the engine's graph to {}: {}", path.display(), e);
+          None
+        }
+      }
+    })
+    .collect()
+}
+
+fn timestamp_str() -> String {
+  let timestamp = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default();
+  format!(
+    "{}.{:03}",
+    timestamp.as_secs(),
+    timestamp.subsec_nanos() / 1_000_000
+  )
+}
+
+fn failure_from_panic(payload: Box<Any + Send>) -> Failure {
+  let report = LAST_PANIC
+    .with(|last_panic| last_panic.borrow_mut().take())
+    .unwrap_or_else(|| PanicReport {
+      // The hook was not installed (or was replaced): all we know is the payload.
+      message: format!("panic at '{}'", payload_str(&*payload)),
+      backtrace: "<no backtrace available: the engine's panic hook was not installed>".to_string(),
+    });
+  let message = format!("The native engine panicked: {}", report.message);
+  Failure::Throw(
+    externs::create_exception(&message),
+    format!("{}\nException: {}", report.backtrace, message),
+    Vec::new(),
+    ErrorCategory::Infrastructure,
+  )
+}
+
+fn payload_str(payload: &(Any + Send)) -> &str {
+  if let Some(s) = payload.downcast_ref::<&str>() {
+    s
+  } else if let Some(s) = payload.downcast_ref::<String>() {
+    s
+  } else {
+    "<non-string panic payload>"
+  }
+}
+
+fn write_crash_report(
+  dir: &Path,
+  panic_info: &PanicInfo,
+  report: &PanicReport,
+) -> Result<PathBuf, String> {
+  fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+  let path = dir.join(format!("crash-{}-{}.txt", process::id(), timestamp_str()));
+  let mut file = File::create(&path).map_err(|e| e.to_string())?;
+  write!(
+    file,
+    "{}\n\nthread: {}\nlocation: {:?}\n\n{}\n",
+    report.message,
+    thread::current().name().unwrap_or("<unnamed>"),
+    panic_info.location(),
+    report.backtrace
+  ).map_err(|e| e.to_string())?;
+  Ok(path)
+}
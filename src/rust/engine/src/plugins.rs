@@ -0,0 +1,289 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str;
+use std::sync::Arc;
+
+#[cfg(unix)]
+use libc;
+use plugin_api::{self, InputFile, Intrinsic, RawInputFile, RawIntrinsic, RawSlice, RegisterFn};
+
+///
+/// The Intrinsics provided by plugins, which are cdylibs that use `plugin_api::export_plugin!`.
+///
+/// Plugins are loaded once, when the Scheduler is created, and are never unloaded: their code may
+/// be referenced by Intrinsics (and by values that they return) for the lifetime of the process.
+///
+/// Plugins communicate with the engine only via the C types and functions of `plugin_api`, so they
+/// need not be built with the same compiler as the engine, and panics in plugins are caught in the
+/// plugin and reported as errors.
+///
+#[derive(Clone, Default)]
+pub struct Plugins {
+  intrinsics: HashMap<String, Arc<Intrinsic>>,
+}
+
+impl Plugins {
+  pub fn load(paths: &[PathBuf]) -> Result<Plugins, String> {
+    let mut plugins = Plugins::default();
+    for path in paths {
+      let register = load_library(path)?;
+      let mut raw_intrinsics: Vec<RawIntrinsic> = Vec::new();
+      let registered = unsafe {
+        register(
+          &mut raw_intrinsics as *mut Vec<RawIntrinsic> as *mut c_void,
+          add_intrinsic,
+        )
+      };
+      if !registered {
+        return Err(format!(
+          "Plugin {} failed to register its intrinsics.",
+          path.display()
+        ));
+      }
+      for raw in raw_intrinsics {
+        let name = str::from_utf8(unsafe { raw.name.as_slice() })
+          .map_err(|e| {
+            format!(
+              "Plugin {} provided an intrinsic with an invalid name: {}",
+              path.display(),
+              e
+            )
+          })?
+          .to_owned();
+        if plugins.intrinsics.contains_key(&name) {
+          return Err(format!(
+            "More than one plugin provides an intrinsic named {}: the second was in {}",
+            name,
+            path.display()
+          ));
+        }
+        let intrinsic = ForeignIntrinsic {
+          name: name.clone(),
+          raw: raw,
+        };
+        plugins.intrinsics.insert(name, Arc::new(intrinsic));
+      }
+    }
+    Ok(plugins)
+  }
+
+  pub fn intrinsic(&self, name: &str) -> Option<&Arc<Intrinsic>> {
+    self.intrinsics.get(name)
+  }
+}
+
+///
+/// The `AddIntrinsicFn` which is passed to a plugin's `RegisterFn`: `host` is the Vec that
+/// `Plugins::load` collects the plugin's intrinsics in.
+///
+unsafe extern "C" fn add_intrinsic(host: *mut c_void, intrinsic: RawIntrinsic) {
+  (*(host as *mut Vec<RawIntrinsic>)).push(intrinsic);
+}
+
+///
+/// An Intrinsic which is implemented by a plugin.
+///
+struct ForeignIntrinsic {
+  name: String,
+  raw: RawIntrinsic,
+}
+
+// The plugin interface requires that intrinsic instances are thread safe: see `plugin_api`.
+unsafe impl Send for ForeignIntrinsic {}
+unsafe impl Sync for ForeignIntrinsic {}
+
+impl Intrinsic for ForeignIntrinsic {
+  fn name(&self) -> &str {
+    &self.name
+  }
+
+  fn run(&self, files: &[InputFile]) -> Result<Vec<u8>, String> {
+    let paths = files
+      .iter()
+      .map(|file| {
+        file
+          .path
+          .to_str()
+          .ok_or_else(|| format!("Input path was not valid UTF8: {:?}", file.path))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    let raw_files: Vec<RawInputFile> = files
+      .iter()
+      .zip(paths)
+      .map(|(file, path)| RawInputFile {
+        path: RawSlice::new(path.as_bytes()),
+        content: RawSlice::new(file.content),
+      })
+      .collect();
+    unsafe {
+      let output = (self.raw.run)(self.raw.instance, raw_files.as_ptr(), raw_files.len());
+      // The output was allocated by the plugin, so copy it before the plugin frees it.
+      let bytes = slice::from_raw_parts(output.ptr, output.len).to_vec();
+      let is_error = output.is_error;
+      (self.raw.free_output)(output);
+      if is_error {
+        Err(String::from_utf8_lossy(&bytes).into_owned())
+      } else {
+        Ok(bytes)
+      }
+    }
+  }
+}
+
+///
+/// Opens the given library, validates its ABI version, and returns its registration function.
+///
+#[cfg(unix)]
+fn load_library(path: &Path) -> Result<RegisterFn, String> {
+  let path_str = path
+    .to_str()
+    .and_then(|path| CString::new(path).ok())
+    .ok_or_else(|| format!("Invalid path for plugin: {:?}", path))?;
+  let handle = unsafe { libc::dlopen(path_str.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+  if handle.is_null() {
+    return Err(format!(
+      "Failed to load plugin {}: {}",
+      path.display(),
+      dl_error()
+    ));
+  }
+
+  let version = symbol(handle, plugin_api::ABI_VERSION_SYMBOL)
+    .map_err(|e| format!("{} is not a plugin: {}", path.display(), e))?;
+  let version = unsafe { *(version as *const u32) };
+  if version != plugin_api::ABI_VERSION {
+    return Err(format!(
+      "Plugin {} was built for version {} of the plugin interface, but this is version {}.",
+      path.display(),
+      version,
+      plugin_api::ABI_VERSION
+    ));
+  }
+
+  let register = symbol(handle, plugin_api::REGISTER_SYMBOL)
+    .map_err(|e| format!("{} is not a plugin: {}", path.display(), e))?;
+  Ok(unsafe { ::std::mem::transmute::<*mut libc::c_void, RegisterFn>(register) })
+}
+
+#[cfg(windows)]
+fn load_library(path: &Path) -> Result<RegisterFn, String> {
+  Err(format!(
+    "Failed to load plugin {}: plugins are not supported on Windows.",
+    path.display()
+  ))
+}
+
+#[cfg(unix)]
+fn symbol(handle: *mut libc::c_void, name: &str) -> Result<*mut libc::c_void, String> {
+  let name = CString::new(name).map_err(|e| format!("{:?}", e))?;
+  let symbol = unsafe { libc::dlsym(handle, name.as_ptr()) };
+  if symbol.is_null() {
+    Err(dl_error())
+  } else {
+    Ok(symbol)
+  }
+}
+
+#[cfg(unix)]
+fn dl_error() -> String {
+  let error = unsafe { libc::dlerror() };
+  if error.is_null() {
+    "Unknown error".to_owned()
+  } else {
+    unsafe { CStr::from_ptr(error) }
+      .to_string_lossy()
+      .into_owned()
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::Plugins;
+  use plugin_api::InputFile;
+  use std::env;
+  use std::fs;
+  use std::path::{Path, PathBuf};
+
+  ///
+  /// Finds the `test_plugin` cdylib, which cargo builds (as a dev-dependency) alongside the test
+  /// binary.
+  ///
+  fn test_plugin_path() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    let deps_dir = exe.parent().unwrap();
+    let prefix = format!("{}test_plugin", env::consts::DLL_PREFIX);
+    vec![deps_dir, deps_dir.parent().unwrap()]
+      .into_iter()
+      .filter_map(|dir| fs::read_dir(dir).ok())
+      .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+      .map(|entry| entry.path())
+      .find(|path| {
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        file_name.starts_with(&prefix) && file_name.ends_with(env::consts::DLL_SUFFIX)
+      })
+      .unwrap_or_else(|| panic!("Could not find the test_plugin library near {:?}", exe))
+  }
+
+  fn files() -> Vec<InputFile<'static>> {
+    vec![
+      InputFile {
+        path: Path::new("a"),
+        content: b"abc",
+      },
+      InputFile {
+        path: Path::new("b/c"),
+        content: b"de",
+      },
+    ]
+  }
+
+  #[test]
+  fn load_and_run() {
+    let plugins = Plugins::load(&[test_plugin_path()]).unwrap();
+    let intrinsic = plugins.intrinsic("test_plugin_total_size").unwrap();
+    assert_eq!("test_plugin_total_size", intrinsic.name());
+    assert_eq!(Ok(b"5".to_vec()), intrinsic.run(&files()));
+    assert!(plugins.intrinsic("unknown").is_none());
+  }
+
+  #[test]
+  fn errors_are_returned() {
+    let plugins = Plugins::load(&[test_plugin_path()]).unwrap();
+    let intrinsic = plugins.intrinsic("test_plugin_fail").unwrap();
+    assert_eq!(Err("Failed for a, b/c".to_owned()), intrinsic.run(&files()));
+  }
+
+  #[test]
+  fn panics_are_caught() {
+    let plugins = Plugins::load(&[test_plugin_path()]).unwrap();
+    let intrinsic = plugins.intrinsic("test_plugin_panic").unwrap();
+    assert_eq!(
+      Err("Intrinsic test_plugin_panic panicked.".to_owned()),
+      intrinsic.run(&files())
+    );
+  }
+
+  #[test]
+  fn duplicate_names_are_an_error() {
+    let path = test_plugin_path();
+    let err = Plugins::load(&[path.clone(), path]).err().unwrap();
+    assert!(
+      err.contains("More than one plugin provides an intrinsic named"),
+      "Unexpected error: {}",
+      err
+    );
+  }
+
+  #[test]
+  fn non_plugins_are_an_error() {
+    let err = Plugins::load(&[env::current_exe().unwrap()]).err().unwrap();
+    assert!(err.contains("is not a plugin") || err.contains("Failed to load plugin"));
+  }
+}
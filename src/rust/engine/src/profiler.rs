@@ -0,0 +1,262 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once, ONCE_INIT};
+use std::thread;
+
+use backtrace;
+use libc;
+
+// The maximum number of samples in a profile: samples beyond this are dropped (and counted).
+const MAX_SAMPLES: usize = 100_000;
+
+const ITIMER_PROF: c_int = 2;
+
+extern "C" {
+  fn setitimer(which: c_int, new: *const libc::itimerval, old: *mut libc::itimerval) -> c_int;
+  #[cfg_attr(target_os = "linux", link_name = "__errno_location")]
+  #[cfg_attr(target_os = "macos", link_name = "__error")]
+  fn errno_location() -> *mut c_int;
+}
+
+///
+/// A fixed size buffer of the instruction pointers of interrupted threads, which is allocated
+/// before profiling starts because the signal handler that fills it must not allocate or lock.
+///
+/// The handler records nothing but the instruction pointer from its `ucontext`: unwinding the
+/// stack (with `_Unwind_Backtrace`) and symbolizing are not async-signal-safe, so the profile is
+/// flat (it attributes each sample to the function that was executing, but not to its callers),
+/// and symbols are resolved only once the profile has stopped.
+///
+struct Samples {
+  addresses: Vec<AtomicUsize>,
+  next: AtomicUsize,
+}
+
+impl Samples {
+  fn new() -> Samples {
+    Samples {
+      addresses: (0..MAX_SAMPLES).map(|_| AtomicUsize::new(0)).collect(),
+      next: AtomicUsize::new(0),
+    }
+  }
+
+  fn record(&self, address: usize) {
+    // Each sample claims a distinct slot, so concurrent handlers never write to the same one.
+    let index = self.next.fetch_add(1, Ordering::SeqCst);
+    if index < self.addresses.len() {
+      self.addresses[index].store(address, Ordering::SeqCst);
+    }
+  }
+
+  ///
+  /// Writes the recorded samples as a flat profile: one line per distinct function, followed by a
+  /// space and the number of samples which were taken while it was executing. Function names may
+  /// contain spaces themselves, so the count is the last field of the line.
+  ///
+  fn write_flat(self, path: &Path) -> Result<(), String> {
+    let recorded = ::std::cmp::min(self.next.load(Ordering::SeqCst), self.addresses.len());
+    let mut counts_by_address: HashMap<usize, usize> = HashMap::new();
+    for address in self.addresses.iter().take(recorded) {
+      let address = address.load(Ordering::SeqCst);
+      // Zero marks a sample for which the platform exposes no instruction pointer.
+      if address != 0 {
+        *counts_by_address.entry(address).or_insert(0) += 1;
+      }
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (address, count) in counts_by_address {
+      *counts.entry(symbol_name(address)).or_insert(0) += count;
+    }
+    let mut lines = counts
+      .into_iter()
+      .map(|(name, count)| format!("{} {}", name, count))
+      .collect::<Vec<_>>();
+    lines.sort();
+
+    File::create(path)
+      .and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        for line in &lines {
+          writeln!(writer, "{}", line)?;
+        }
+        writer.flush()
+      })
+      .map_err(|e| format!("Failed to write CPU profile to {}: {}", path.display(), e))
+  }
+}
+
+fn symbol_name(address: usize) -> String {
+  let mut name = None;
+  backtrace::resolve(address as *mut c_void, |symbol| {
+    if name.is_none() {
+      name = symbol.name().map(|n| n.to_string());
+    }
+  });
+  name.unwrap_or_else(|| format!("{:#x}", address))
+}
+
+///
+/// Returns the instruction pointer of the thread which was interrupted by a signal, from the
+/// `ucontext` passed to an SA_SIGINFO handler, or zero if it is not known for this platform.
+///
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn interrupted_address(ucontext: *mut c_void) -> usize {
+  let ucontext = ucontext as *const libc::ucontext_t;
+  (*ucontext).uc_mcontext.gregs[libc::REG_RIP as usize] as usize
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+unsafe fn interrupted_address(ucontext: *mut c_void) -> usize {
+  let ucontext = ucontext as *const libc::ucontext_t;
+  (*(*ucontext).uc_mcontext).__ss.__rip as usize
+}
+
+#[cfg(not(any(
+  all(target_os = "linux", target_arch = "x86_64"),
+  all(target_os = "macos", target_arch = "x86_64")
+)))]
+unsafe fn interrupted_address(_ucontext: *mut c_void) -> usize {
+  0
+}
+
+// The Samples of the running profile, or null if no profile is running.
+static SAMPLES: AtomicPtr<Samples> = AtomicPtr::new(ptr::null_mut());
+
+// The number of signal handlers that might be accessing SAMPLES.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALL_HANDLER: Once = ONCE_INIT;
+
+lazy_static! {
+  // Serializes starting and stopping profiles.
+  static ref PROFILING: Mutex<bool> = Mutex::new(false);
+}
+
+extern "C" fn sigprof_handler(_: c_int, _: *mut libc::siginfo_t, ucontext: *mut c_void) {
+  let errno = unsafe { *errno_location() };
+  IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+  let samples = SAMPLES.load(Ordering::SeqCst);
+  if !samples.is_null() {
+    unsafe { (*samples).record(interrupted_address(ucontext)) };
+  }
+  IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+  unsafe { *errno_location() = errno };
+}
+
+///
+/// Installs the SIGPROF handler. It is never uninstalled: the default action for SIGPROF is to
+/// terminate the process, and a signal might still be pending when a profile is stopped. While no
+/// profile is running, the handler does nothing.
+///
+fn install_handler() -> Result<(), String> {
+  let mut result = Ok(());
+  INSTALL_HANDLER.call_once(|| unsafe {
+    let mut action: libc::sigaction = ::std::mem::zeroed();
+    action.sa_sigaction = sigprof_handler as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_SIGINFO;
+    libc::sigemptyset(&mut action.sa_mask);
+    if libc::sigaction(libc::SIGPROF, &action, ptr::null_mut()) != 0 {
+      result = Err(format!(
+        "Failed to install SIGPROF handler: {}",
+        ::std::io::Error::last_os_error()
+      ));
+    }
+  });
+  result
+}
+
+fn set_timer(interval_micros: u64) -> Result<(), String> {
+  let interval = libc::timeval {
+    tv_sec: (interval_micros / 1_000_000) as libc::time_t,
+    tv_usec: (interval_micros % 1_000_000) as libc::suseconds_t,
+  };
+  let timer = libc::itimerval {
+    it_interval: interval,
+    it_value: interval,
+  };
+  if unsafe { setitimer(ITIMER_PROF, &timer, ptr::null_mut()) } == 0 {
+    Ok(())
+  } else {
+    Err(format!(
+      "Failed to set profiling timer: {}",
+      ::std::io::Error::last_os_error()
+    ))
+  }
+}
+
+///
+/// Starts sampling the functions executed by all threads of the process `frequency_hz` times per
+/// second of CPU time that they consume, until `stop` is called. Only the executing function is
+/// sampled, and not its callers.
+///
+/// Samples are taken in a SIGPROF handler (see `Samples`), so the engine does not need to be
+/// rebuilt with instrumentation to be profiled.
+///
+pub fn start(frequency_hz: u64) -> Result<(), String> {
+  if frequency_hz == 0 || frequency_hz > 1000 {
+    return Err(format!(
+      "The CPU profiling frequency must be between 1 and 1000 Hz: got {}.",
+      frequency_hz
+    ));
+  }
+  let mut profiling = PROFILING.lock().unwrap();
+  if *profiling {
+    return Err("A CPU profile is already running.".to_string());
+  }
+  install_handler()?;
+  SAMPLES.store(Box::into_raw(Box::new(Samples::new())), Ordering::SeqCst);
+  if let Err(e) = set_timer(1_000_000 / frequency_hz) {
+    drop(take_samples());
+    return Err(e);
+  }
+  *profiling = true;
+  Ok(())
+}
+
+///
+/// Stops the running profile, and writes it to the given path as a flat profile (see
+/// `Samples::write_flat`). Returns the number of samples that were recorded.
+///
+pub fn stop(path: &Path) -> Result<usize, String> {
+  let mut profiling = PROFILING.lock().unwrap();
+  if !*profiling {
+    return Err("No CPU profile is running.".to_string());
+  }
+  *profiling = false;
+  let timer_result = set_timer(0);
+  let samples = take_samples();
+  timer_result?;
+
+  let taken = samples.next.load(Ordering::SeqCst);
+  if taken > MAX_SAMPLES {
+    warn!(
+      "Dropped {} of {} CPU profile samples: consider profiling for a shorter window, or at a \
+       lower frequency.",
+      taken - MAX_SAMPLES,
+      taken
+    );
+  }
+  samples.write_flat(path)?;
+  Ok(::std::cmp::min(taken, MAX_SAMPLES))
+}
+
+///
+/// Detaches the Samples from the signal handler, and waits for any handler that is still recording
+/// into them to finish.
+///
+fn take_samples() -> Samples {
+  let samples = SAMPLES.swap(ptr::null_mut(), Ordering::SeqCst);
+  while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+    thread::yield_now();
+  }
+  *unsafe { Box::from_raw(samples) }
+}
@@ -0,0 +1,15 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+// CPU profiling samples stacks from a SIGPROF handler (see `profiler.rs`), which has no equivalent
+// on platforms other than unix: profiles can neither be started nor stopped there.
+
+use std::path::Path;
+
+pub fn start(_frequency_hz: u64) -> Result<(), String> {
+  Err("CPU profiling of the engine is not supported on this platform.".to_string())
+}
+
+pub fn stop(_path: &Path) -> Result<usize, String> {
+  Err("No CPU profile is running.".to_string())
+}
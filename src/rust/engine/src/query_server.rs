@@ -0,0 +1,351 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::fs::{self as stdfs, DirBuilder, Permissions};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use fs::{self, ResettablePool};
+use futures::sync::mpsc;
+use futures::{Future, Stream};
+use grpcio;
+use query_protos::{query, query_grpc};
+
+use core::{Failure, TypeConstraint, Value};
+use externs;
+use scheduler::{ExecutionRequest, RootCallback, RootResult, Scheduler, Session};
+
+///
+/// The name of the socket that the server binds in its socket directory. gRPC binds addresses of
+/// the form `host:port`, so the socket file is named for a "port" of 0 (see `socket_path`).
+///
+const SOCKET_NAME: &str = "query";
+
+///
+/// The number of requests per thread of the worker pool which may be accepted (whether running or
+/// waiting for a thread) before further requests are refused.
+///
+const MAX_QUEUED_QUERIES_PER_THREAD: usize = 4;
+
+///
+/// A gRPC server which computes products using a resident Scheduler on behalf of local clients
+/// (such as IDEs and editors), which would otherwise need to spawn a pants process per query.
+///
+/// Requests name a product and a series of subject specs, which are resolved to a TypeConstraint
+/// and subjects by a python handler (see `pants.engine.query.QueryHandler`). Each request is
+/// executed in a new Session, and a response is streamed to the client as each of its subjects
+/// completes.
+///
+/// The server listens on a unix socket which only the current user may connect to, and runs
+/// executions on a bounded pool of threads. Like the other threads of the engine, the server
+/// cannot survive a fork: it must be stopped by `pre_fork`, and restarted in the parent by
+/// `post_fork_in_parent`.
+///
+pub struct QueryServer {
+  service: QueryService,
+  // The directory containing the socket, if the server has been started (and not stopped).
+  socket_dir: Option<PathBuf>,
+  server: Option<grpcio::Server>,
+}
+
+impl QueryServer {
+  pub fn new(scheduler: Arc<Scheduler>, handler: Value) -> QueryServer {
+    let pool = ResettablePool::new(fs::threads::thread_name("query-"));
+    let max_queued = pool.threads() * MAX_QUEUED_QUERIES_PER_THREAD;
+    QueryServer {
+      service: QueryService {
+        scheduler: scheduler,
+        handler: handler,
+        pool: Arc::new(pool),
+        queued: Arc::new(AtomicUsize::new(0)),
+        max_queued: max_queued,
+      },
+      socket_dir: None,
+      server: None,
+    }
+  }
+
+  ///
+  /// Starts serving on a unix socket in the given directory (which is created if necessary, and
+  /// made accessible only to the current user), and returns the path of the socket.
+  ///
+  pub fn start(&mut self, socket_dir: &Path) -> Result<PathBuf, String> {
+    if self.socket_dir.is_some() {
+      return Err("The query server has already been started.".to_string());
+    }
+    DirBuilder::new()
+      .recursive(true)
+      .mode(0o700)
+      .create(socket_dir)
+      .and_then(|()| stdfs::set_permissions(socket_dir, Permissions::from_mode(0o700)))
+      .map_err(|e| {
+        format!(
+          "Failed to create the query server socket directory {:?}: {}",
+          socket_dir, e
+        )
+      })?;
+    self.bind(socket_dir)?;
+    self.socket_dir = Some(socket_dir.to_owned());
+    Ok(socket_path(socket_dir))
+  }
+
+  fn bind(&mut self, socket_dir: &Path) -> Result<(), String> {
+    let path = socket_path(socket_dir);
+    let host = socket_dir
+      .join(SOCKET_NAME)
+      .to_str()
+      .map(|dir| format!("unix:{}", dir))
+      .ok_or_else(|| format!("The query server socket path {:?} was not valid UTF8.", path))?;
+    // Remove any socket left behind by a previous server.
+    match stdfs::remove_file(&path) {
+      Ok(()) => {}
+      Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {}
+      Err(e) => return Err(format!("Failed to remove the stale socket {:?}: {}", path, e)),
+    }
+    let env = Arc::new(grpcio::Environment::new(1));
+    let mut server = grpcio::ServerBuilder::new(env)
+      .register_service(query_grpc::create_query(self.service.clone()))
+      .bind(host, 0)
+      .build()
+      .map_err(|e| format!("Failed to start the query server: {:?}", e))?;
+    // The directory is already private, but the socket should be too.
+    stdfs::set_permissions(&path, Permissions::from_mode(0o600))
+      .map_err(|e| format!("Failed to restrict access to the socket {:?}: {}", path, e))?;
+    server.start();
+    self.server = Some(server);
+    Ok(())
+  }
+
+  ///
+  /// Stops serving, and removes the socket. In-flight requests are cancelled, although executions
+  /// that they started will run to completion.
+  ///
+  pub fn stop(&mut self) {
+    // Dropping the server shuts it down.
+    self.server = None;
+    if let Some(socket_dir) = self.socket_dir.take() {
+      let _ = stdfs::remove_file(socket_path(&socket_dir));
+    }
+  }
+
+  ///
+  /// Stops serving (without forgetting the socket) and drops the worker pool before a fork. The
+  /// caller must ensure that no requests are executing, which the handler's `lock` allows.
+  ///
+  pub fn pre_fork(&mut self) {
+    self.server = None;
+    self.service.pool.reset();
+  }
+
+  ///
+  /// Resumes serving on the same socket in the parent after a fork.
+  ///
+  pub fn post_fork_in_parent(&mut self) -> Result<(), String> {
+    match self.socket_dir.clone() {
+      Some(ref socket_dir) if self.server.is_none() => self.bind(socket_dir),
+      _ => Ok(()),
+    }
+  }
+
+  ///
+  /// Discards (without dropping) the server and pool in a forked child: the child does not serve
+  /// queries, and the socket belongs to the parent.
+  ///
+  pub fn post_fork_in_child(&mut self) -> Result<(), String> {
+    mem::forget(self.server.take());
+    self.socket_dir = None;
+    self.service.pool.reset_in_child()
+  }
+}
+
+///
+/// The path of the socket that a server started in the given directory listens on.
+///
+fn socket_path(socket_dir: &Path) -> PathBuf {
+  socket_dir.join(format!("{}:0", SOCKET_NAME))
+}
+
+#[derive(Clone)]
+struct QueryService {
+  scheduler: Arc<Scheduler>,
+  handler: Value,
+  pool: Arc<ResettablePool>,
+  // The number of requests which have been accepted, but have not yet completed: see `Queued`.
+  queued: Arc<AtomicUsize>,
+  max_queued: usize,
+}
+
+impl QueryService {
+  ///
+  /// Uses the handler to resolve the product and subjects of the given request, and creates an
+  /// ExecutionRequest with one root per subject.
+  ///
+  fn resolve(&self, req: &query::ExecuteRequest) -> Result<ExecutionRequest, String> {
+    let specs = req
+      .get_subjects()
+      .iter()
+      .map(|spec| externs::store_utf8(spec))
+      .collect::<Vec<_>>();
+    let roots = externs::call_method(
+      &self.handler,
+      "resolve",
+      &[
+        externs::store_utf8(req.get_product()),
+        externs::store_tuple(&specs),
+      ],
+    ).map_err(|f| render_failure(&f))?;
+
+    let product =
+      TypeConstraint(externs::key_for(externs::project_ignoring_type(&roots, "product")));
+    let subjects = externs::project_multi(&roots, "subjects");
+    if subjects.len() != specs.len() {
+      return Err(format!(
+        "Expected the query handler to resolve {} subjects, but it resolved {}.",
+        specs.len(),
+        subjects.len()
+      ));
+    }
+    let mut request = ExecutionRequest::new();
+    for subject in subjects {
+      self
+        .scheduler
+        .add_root_select(&mut request, externs::key_for(subject), product)?;
+    }
+    Ok(request)
+  }
+
+  ///
+  /// Executes the given request in a Session which joins the current run (see `Session::joining`),
+  /// sending a response for each of its roots as it completes. Queries may arrive at any time, so
+  /// they must not reset the session-scoped state of a run which is executing.
+  ///
+  /// Executions hold the handler's `lock` for their duration, which allows pantsd to prevent a
+  /// fork while the Graph is in use.
+  ///
+  fn execute(
+    &self,
+    request: ExecutionRequest,
+    specs: Vec<String>,
+    sender: mpsc::UnboundedSender<query::ExecuteResponse>,
+  ) {
+    let total = specs.len();
+    let completed = AtomicUsize::new(0);
+    let handler = self.handler.clone();
+    let on_root: RootCallback = Arc::new(move |index: usize, result: &RootResult| {
+      let mut response = query::ExecuteResponse::new();
+      response.set_subject(specs[index].clone());
+      response.set_completed((completed.fetch_add(1, Ordering::SeqCst) + 1) as u32);
+      response.set_total(total as u32);
+      match render(&handler, result) {
+        Ok(value) => response.set_value(value),
+        Err(error) => response.set_error(error),
+      }
+      // If the client has gone away, the remaining responses are dropped.
+      let _ = sender.unbounded_send(response);
+    });
+
+    let lock = externs::project_ignoring_type(&self.handler, "lock");
+    if let Err(f) = externs::call_method(&lock, "acquire", &[]) {
+      warn!("Failed to acquire the query lock: {}", render_failure(&f));
+      return;
+    }
+    let session = Session::joining(&self.scheduler);
+    self.scheduler.execute_streaming(&request, &session, on_root);
+    if let Err(f) = externs::call_method(&lock, "release", &[]) {
+      warn!("Failed to release the query lock: {}", render_failure(&f));
+    }
+  }
+}
+
+impl query_grpc::Query for QueryService {
+  fn execute(
+    &self,
+    ctx: grpcio::RpcContext,
+    req: query::ExecuteRequest,
+    sink: grpcio::ServerStreamingSink<query::ExecuteResponse>,
+  ) {
+    let request = match self.resolve(&req) {
+      Ok(request) => request,
+      Err(e) => {
+        ctx.spawn(
+          sink
+            .fail(grpcio::RpcStatus::new(
+              grpcio::RpcStatusCode::InvalidArgument,
+              Some(e),
+            ))
+            .map_err(|e| debug!("Failed to report a query error: {:?}", e)),
+        );
+        return;
+      }
+    };
+
+    if self.queued.fetch_add(1, Ordering::SeqCst) >= self.max_queued {
+      self.queued.fetch_sub(1, Ordering::SeqCst);
+      ctx.spawn(
+        sink
+          .fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::ResourceExhausted,
+            Some("Too many queries are already queued: please retry later.".to_string()),
+          ))
+          .map_err(|e| debug!("Failed to report a query error: {:?}", e)),
+      );
+      return;
+    }
+    let queued = Queued(self.queued.clone());
+
+    // Executions block until they complete, so they run on the worker pool rather than on the
+    // server's completion queue.
+    let (sender, receiver) = mpsc::unbounded();
+    let service = self.clone();
+    let specs = req.get_subjects().to_vec();
+    ctx.spawn(self.pool.spawn_fn(move || {
+      service.execute(request, specs, sender);
+      mem::drop(queued);
+      Ok::<(), ()>(())
+    }));
+
+    ctx.spawn(
+      receiver
+        .map(|response| (response, grpcio::WriteFlags::default()))
+        .map_err(|()| grpcio::Error::RemoteStopped)
+        .forward(sink)
+        .map(|_| ())
+        .map_err(|e| debug!("Failed to send query responses: {:?}", e)),
+    );
+  }
+}
+
+///
+/// Renders the product for a root using the handler, or renders its failure.
+///
+fn render(handler: &Value, result: &RootResult) -> Result<String, String> {
+  match result {
+    &Ok(ref value) => externs::call_method(handler, "render", &[value.clone()])
+      .map(|rendered| externs::val_to_str(&rendered))
+      .map_err(|f| render_failure(&f)),
+    &Err(ref failure) => Err(render_failure(failure)),
+  }
+}
+
+///
+/// Counts a request as queued until it is dropped.
+///
+struct Queued(Arc<AtomicUsize>);
+
+impl Drop for Queued {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+fn render_failure(failure: &Failure) -> String {
+  match failure {
+    &Failure::Throw(ref exc, _, _, _) => externs::val_to_str(exc),
+    &Failure::Noop(ref noop) => format!("{:?}", noop),
+    &Failure::Invalidated => "Exhausted retries due to changed files.".to_string(),
+    &Failure::Cancelled(ref message) => message.clone(),
+  }
+}
@@ -3,8 +3,9 @@
 
 use std::collections::{hash_map, HashMap, HashSet};
 use std::io;
+use std::sync::Arc;
 
-use core::{Function, Key, TypeConstraint, TypeId, Value, ANY_TYPE};
+use core::{Key, TypeConstraint, TypeId, Value, ANY_TYPE};
 use externs;
 use selectors::{Get, Select};
 use tasks::{Intrinsic, Task, Tasks};
@@ -96,10 +97,26 @@ impl Entry {
   pub fn matches_subject_type(&self, actual_subject_type: TypeId) -> bool {
     match self {
       &Entry::SubjectIsProduct { ref subject_type } => *subject_type == actual_subject_type,
-      &Entry::WithDeps(ref r) => r.subject_type() == actual_subject_type,
+      &Entry::WithDeps(ref r) => {
+        r.subject_type() == actual_subject_type || self.is_subject_independent()
+      }
       &Entry::Singleton { .. } => true,
     }
   }
+
+  ///
+  /// True if this Entry computes its product without a subject, which is only true of rules which
+  /// were declared as providers: see `tasks::Provider`.
+  ///
+  pub fn is_subject_independent(&self) -> bool {
+    match self {
+      &Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
+        rule: Rule::Task(ref task),
+        ..
+      })) => task.provider,
+      _ => false,
+    }
+  }
 }
 
 #[derive(Eq, Hash, PartialEq, Clone, Debug)]
@@ -327,6 +344,7 @@ impl<'t> GraphMaker<'t> {
 
     if fulfillable {
       // All depedendencies were fulfillable: replace the placeholder with the computed RuleEdges.
+      edges.index_selects(entry.subject_type());
       rule_dependency_edges.insert(entry, edges);
       true
     } else {
@@ -404,15 +422,6 @@ fn to_val(type_constraint: TypeConstraint) -> Value {
   externs::val_for(&type_constraint.0)
 }
 
-fn to_val_from_func(func: &Function) -> Value {
-  externs::val_for(&func.0)
-}
-
-fn function_str(func: &Function) -> String {
-  let as_val = to_val_from_func(func);
-  val_name(&as_val)
-}
-
 pub fn type_str(type_id: TypeId) -> String {
   if type_id == ANY_TYPE {
     "Any".to_string()
@@ -421,10 +430,6 @@ pub fn type_str(type_id: TypeId) -> String {
   }
 }
 
-fn val_name(val: &Value) -> String {
-  externs::project_str(val, "__name__")
-}
-
 pub fn select_str(select: &Select) -> String {
   format!("Select({})", type_constraint_str(select.product)).to_string() // TODO variant key
 }
@@ -504,19 +509,83 @@ fn task_display(task: &Task) -> String {
   } else {
     "".to_string()
   };
-  let function_name = function_str(&&task.func);
   format!(
     "({}, {}, {}{})",
-    product, clause_portion, get_portion, function_name
+    product, clause_portion, get_portion, task.name
   ).to_string()
 }
 
+///
+/// Describes a Get which a running Task requested, but which the rule graph has no entries for:
+/// generally because the Task did not declare the Get, or declared it for a different subject
+/// type. Names the Task, the Gets that it did declare, and the rules which are registered for the
+/// requested product.
+///
+pub fn missing_get_str(tasks: &Tasks, task: &Task, get: &Get) -> String {
+  let declared = if task.gets.is_empty() {
+    "it declared no Gets".to_string()
+  } else {
+    format!(
+      "it declared: {}",
+      task
+        .gets
+        .iter()
+        .map(|g| get_str(g))
+        .collect::<Vec<_>>()
+        .join(", ")
+    )
+  };
+
+  let mut providers = Vec::new();
+  if let Some((value, _)) = tasks.gen_singleton(&get.product) {
+    providers.push(format!(
+      "Singleton({}, {})",
+      externs::key_to_str(value),
+      type_constraint_str(get.product)
+    ));
+  }
+  if let Some(provider) = tasks.gen_provider(&get.product) {
+    providers.push(task_display(provider));
+  }
+  for intrinsic in tasks.gen_intrinsics(&get.product).into_iter().flat_map(|i| i) {
+    providers.push(format!(
+      "({}, ({},), {:?})",
+      type_constraint_str(intrinsic.product),
+      type_constraint_str(intrinsic.input),
+      intrinsic.kind
+    ));
+  }
+  for task in tasks.gen_tasks(&get.product).into_iter().flat_map(|t| t) {
+    providers.push(task_display(task));
+  }
+  let registered = if providers.is_empty() {
+    format!(
+      "No rules are registered for {}.",
+      type_constraint_str(get.product)
+    )
+  } else {
+    format!(
+      "Rules registered for {}:\n  {}",
+      type_constraint_str(get.product),
+      providers.join("\n  ")
+    )
+  };
+
+  format!(
+    "{} requested {}, but {}, so the rule graph cannot satisfy it. {}",
+    task_display(task),
+    get_str(get),
+    declared,
+    registered
+  )
+}
+
 impl RuleGraph {
   pub fn new(tasks: &Tasks, root_subject_types: Vec<TypeId>) -> RuleGraph {
     GraphMaker::new(tasks, root_subject_types).full_graph()
   }
 
-  pub fn find_root_edges(&self, subject_type: TypeId, select: Select) -> Option<RuleEdges> {
+  pub fn find_root_edges(&self, subject_type: TypeId, select: Select) -> Option<&RuleEdges> {
     // TODO return Result instead
     let root = RootEntry {
       subject_type: subject_type,
@@ -526,7 +595,6 @@ impl RuleGraph {
     self
       .rule_dependency_edges
       .get(&EntryWithDeps::Root(root))
-      .cloned()
   }
 
   ///
@@ -544,9 +612,9 @@ impl RuleGraph {
   ///
   /// TODO: See rule_for_inner.
   ///
-  pub fn edges_for_inner(&self, entry: &Entry) -> Option<RuleEdges> {
+  pub fn edges_for_inner(&self, entry: &Entry) -> Option<&RuleEdges> {
     if let &Entry::WithDeps(ref e) = entry {
-      self.rule_dependency_edges.get(e).cloned()
+      self.rule_dependency_edges.get(e)
     } else {
       panic!("not an inner entry! {:?}", entry)
     }
@@ -686,6 +754,9 @@ impl RuleGraph {
 pub struct RuleEdges {
   dependencies: Entries,
   dependencies_by_select_key: HashMap<SelectKey, Entries>,
+  // The entries for each Select which match the subject type that the Select is applied to,
+  // precomputed when the graph is built: see `entries_for_select`.
+  select_entries_by_subject_type: HashMap<TypeId, HashMap<Select, Arc<Entries>>>,
 }
 
 impl RuleEdges {
@@ -693,6 +764,7 @@ impl RuleEdges {
     RuleEdges {
       dependencies: vec![],
       dependencies_by_select_key: HashMap::new(),
+      select_entries_by_subject_type: HashMap::new(),
     }
   }
 
@@ -704,6 +776,53 @@ impl RuleEdges {
       .unwrap_or_else(Vec::new)
   }
 
+  ///
+  /// The entries which might provide the given Select for a subject of the given type.
+  ///
+  /// Selects are created for every Task that runs, so for the subject type of the entry that owns
+  /// these edges the entries are a (shared) lookup, rather than being filtered for each Select.
+  ///
+  pub fn entries_for_select(&self, select: &Select, subject_type: TypeId) -> Arc<Entries> {
+    if let Some(entries) = self
+      .select_entries_by_subject_type
+      .get(&subject_type)
+      .and_then(|by_select| by_select.get(select))
+    {
+      return entries.clone();
+    }
+    Arc::new(Self::matching_subject_type(
+      self.entries_for(&SelectKey::JustSelect(select.clone())),
+      subject_type,
+    ))
+  }
+
+  fn matching_subject_type(entries: Entries, subject_type: TypeId) -> Entries {
+    entries
+      .into_iter()
+      .filter(|e| e.matches_subject_type(subject_type))
+      .collect()
+  }
+
+  ///
+  /// Precomputes the entries for each Select of the entry with the given subject type.
+  ///
+  fn index_selects(&mut self, subject_type: TypeId) {
+    let by_select = self
+      .dependencies_by_select_key
+      .iter()
+      .filter_map(|(select_key, entries)| match select_key {
+        &SelectKey::JustSelect(ref select) => Some((
+          select.clone(),
+          Arc::new(Self::matching_subject_type(entries.clone(), subject_type)),
+        )),
+        &SelectKey::JustGet(_) => None,
+      })
+      .collect();
+    self
+      .select_entries_by_subject_type
+      .insert(subject_type, by_select);
+  }
+
   pub fn is_empty(&self) -> bool {
     self.dependencies.is_empty()
   }
@@ -725,18 +844,30 @@ impl RuleEdges {
 }
 
 fn rhs(tasks: &Tasks, subject_type: TypeId, product_type: &TypeConstraint) -> Entries {
-  if externs::satisfied_by_type(product_type, subject_type) {
+  if subject_type != ANY_TYPE && externs::satisfied_by_type(product_type, subject_type) {
     // NB a matching subject is always picked first
     vec![Entry::new_subject_is_product(subject_type)]
-  } else if let Some(&(ref key, _)) = tasks.gen_singleton(product_type) {
+  } else if let Some((key, _)) = tasks.gen_singleton(product_type) {
     vec![Entry::new_singleton(key.clone(), product_type.clone())]
+  } else if let Some(provider) = tasks.gen_provider(product_type) {
+    // A provider is independent of the subject, so it is shared by all subject types (and its own
+    // dependencies are computed without a subject).
+    vec![Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
+      subject_type: ANY_TYPE,
+      rule: Rule::Task(provider.clone()),
+    }))]
+  } else if subject_type == ANY_TYPE {
+    // Without a subject (ie, for the Selects of a provider), only other providers can be used.
+    vec![]
   } else {
     let mut entries = Vec::new();
-    if let Some(matching_intrinsic) = tasks.gen_intrinsic(product_type) {
-      entries.push(Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
-        subject_type: subject_type,
-        rule: Rule::Intrinsic(matching_intrinsic.clone()),
-      })));
+    if let Some(matching_intrinsics) = tasks.gen_intrinsics(product_type) {
+      entries.extend(matching_intrinsics.iter().map(|intrinsic| {
+        Entry::WithDeps(EntryWithDeps::Inner(InnerEntry {
+          subject_type: subject_type,
+          rule: Rule::Intrinsic(intrinsic.clone()),
+        }))
+      }));
     }
     if let Some(matching_tasks) = tasks.gen_tasks(product_type) {
       entries.extend(matching_tasks.iter().map(|task_rule| {
@@ -1,20 +1,30 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::io;
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Future};
 use futures::sync::oneshot;
 
 use boxfuture::{BoxFuture, Boxable};
-use context::{Context, Core};
+use context::{Context, Core, ProcessResultCounters};
 use core::{Failure, Key, TypeConstraint, TypeId, Value, Variants};
-use fs::{self, GlobMatching, PosixFS};
-use graph::{EntryId, Graph, Node, NodeContext};
-use nodes::{NodeKey, Select, Tracer, TryInto, Visualizer};
+use fs::{self, GlobMatching, PoolCounters, PosixFS};
+use graph::{EntryId, Graph, InvalidationCounters, Node, NodeContext, TraceOptions};
+use nodes::{NodeKey, Select, SelectMultiple, Tracer, TryInto, Visualizer};
+use externs;
+use handles;
+use memory;
+use panics;
 use rule_graph;
 use selectors;
 
@@ -28,15 +38,96 @@ use selectors;
 pub struct Session {
   // The total size of the graph at Session-creation time.
   preceding_graph_size: usize,
+  // A snapshot of the Graph's invalidation counters, relative to which this Session's counts
+  // are reported. See `Session::new`.
+  invalidation_baseline: InvalidationCounters,
+  // A snapshot of the Core's ProcessResult counters, relative to which this Session's counts are
+  // reported.
+  process_result_baseline: ProcessResultCounters,
+  // Snapshots of the counters of the Core's pool for blocking IO and of its runtime, relative to
+  // which this Session's counts are reported.
+  fs_pool_baseline: PoolCounters,
+  runtime_baseline: PoolCounters,
+  created: Instant,
   // The set of roots that have been requested within this session.
   roots: Mutex<HashSet<Root>>,
+  // Whether this Session runs speculative work on behalf of an idle client: see `BackgroundPolicy`.
+  background: bool,
+  // A deadline for the Session, which bounds the RPCs that its executions make to a remote store:
+  // see `Context::store`.
+  deadline: Option<Instant>,
 }
 
 impl Session {
-  pub fn new(scheduler: &Scheduler) -> Session {
+  ///
+  /// Creates a Session for a run in the foreground, or for speculative work in the background.
+  ///
+  /// Session-scoped state is reset only when a foreground Session begins: background Sessions run
+  /// alongside foreground runs, and must not discard their memoized work or re-run their
+  /// uncacheable Nodes partway through.
+  ///
+  pub fn new(scheduler: &Scheduler, background: bool, timeout: Option<Duration>) -> Session {
+    Session::create(scheduler, background, timeout, !background)
+  }
+
+  ///
+  /// Creates a foreground Session which shares the session-scoped state of the current run rather
+  /// than resetting it, for requests (such as queries) which may arrive while a run is executing.
+  ///
+  pub fn joining(scheduler: &Scheduler) -> Session {
+    Session::create(scheduler, false, None, false)
+  }
+
+  fn reset_session_scoped(scheduler: &Scheduler) {
+    // Results of uncacheable rules (and failed results of processes which do not cache failures)
+    // are memoized only for the lifetime of a run, so they are cleared (dirtying their dependents)
+    // whenever a new run begins.
+    scheduler
+      .core
+      .graph
+      .invalidate_from_roots_with_results(|node, result| node.is_session_scoped(result));
+    // Likewise, glob expansions and the Snapshots captured from them are only shared within a
+    // run, and escaping symlinks are warned about once per run.
+    scheduler.core.glob_expansion_memo.clear();
+    scheduler.core.negative_match_filter.clear();
+    scheduler.core.snapshot_memo.clear();
+    scheduler.core.vfs.clear_link_escape_warnings();
+  }
+
+  fn create(
+    scheduler: &Scheduler,
+    background: bool,
+    timeout: Option<Duration>,
+    starts_run: bool,
+  ) -> Session {
+    if starts_run {
+      Session::reset_session_scoped(scheduler);
+    }
+    // Invalidation generally happens between runs (when files change), so invalidated counts are
+    // reported relative to the start of the previous run, while the outcomes of re-running
+    // invalidated Nodes are reported relative to this Session.
+    let current = scheduler.core.graph.invalidation_counters();
+    let previous = {
+      let mut run_counters = scheduler.session_invalidation_counters.lock().unwrap();
+      if starts_run {
+        mem::replace(&mut *run_counters, current.clone())
+      } else {
+        run_counters.clone()
+      }
+    };
     Session {
       preceding_graph_size: scheduler.core.graph.len(),
+      invalidation_baseline: InvalidationCounters {
+        invalidated: previous.invalidated,
+        ..current
+      },
+      process_result_baseline: scheduler.core.process_result_counters.lock().unwrap().clone(),
+      fs_pool_baseline: scheduler.core.fs_pool.counters(),
+      runtime_baseline: scheduler.core.runtime_instrumentation.counters(),
+      created: Instant::now(),
       roots: Mutex::new(HashSet::new()),
+      background: background,
+      deadline: timeout.map(|timeout| Instant::now() + timeout),
     }
   }
 
@@ -62,8 +153,8 @@ impl ExecutionRequest {
   }
 
   ///
-  /// Roots are limited to `Select`, which is known to produce a Value. This method
-  /// exists to satisfy Graph APIs which need instances of the NodeKey enum.
+  /// Roots are limited to `Select` and `SelectMultiple`, which are known to produce a Value. This
+  /// method exists to satisfy Graph APIs which need instances of the NodeKey enum.
   ///
   fn root_nodes(&self) -> Vec<NodeKey> {
     self.roots.iter().map(|r| r.clone().into()).collect()
@@ -75,27 +166,47 @@ impl ExecutionRequest {
 ///
 pub struct Scheduler {
   pub core: Arc<Core>,
+  // The Graph's invalidation counters as of the start of the most recent run: see `Session::new`.
+  session_invalidation_counters: Mutex<InvalidationCounters>,
+  // The time that a Node last completed, as of the most recent `trim_memory`.
+  trimmed_after_completion: Mutex<Option<Instant>>,
 }
 
 impl Scheduler {
   pub fn new(core: Core) -> Scheduler {
+    let core = Arc::new(core);
+    panics::register_core(&core);
     Scheduler {
-      core: Arc::new(core),
+      core: core,
+      session_invalidation_counters: Mutex::new(InvalidationCounters::default()),
+      trimmed_after_completion: Mutex::new(None),
     }
   }
 
-  pub fn visualize(&self, session: &Session, path: &Path) -> io::Result<()> {
-    self
-      .core
-      .graph
-      .visualize(Visualizer::default(), &session.root_nodes(), path)
+  pub fn visualize(
+    &self,
+    session: &Session,
+    min_duration: Duration,
+    path: &Path,
+  ) -> io::Result<()> {
+    self.core.graph.visualize(
+      Visualizer::default(),
+      &session.root_nodes(),
+      min_duration,
+      path,
+    )
   }
 
-  pub fn trace(&self, request: &ExecutionRequest, path: &Path) -> Result<(), String> {
+  pub fn trace(
+    &self,
+    request: &ExecutionRequest,
+    path: &Path,
+    options: &TraceOptions,
+  ) -> Result<(), String> {
     self
       .core
       .graph
-      .trace::<Tracer>(&request.root_nodes(), path)?;
+      .trace::<Tracer>(&request.root_nodes(), path, options)?;
     Ok(())
   }
 
@@ -109,9 +220,78 @@ impl Scheduler {
       subject.type_id().clone(),
       &selectors::Select::without_variant(product),
     )?;
-    request
-      .roots
-      .push(Select::new(product, subject, Variants::default(), &edges));
+    request.roots.push(Root::Select(Select::new(
+      product,
+      subject,
+      Variants::default(),
+      &edges,
+    )));
+    Ok(())
+  }
+
+  ///
+  /// Adds a root which selects the given product for each of the given subjects, in order.
+  ///
+  /// Equivalent to calling `add_root_select` for each subject, except that the rule graph is only
+  /// consulted once per type of subject, which dominates the cost of adding thousands of roots.
+  /// If any subject cannot be selected for, no roots are added.
+  ///
+  pub fn add_root_selects(
+    &self,
+    request: &mut ExecutionRequest,
+    subjects: Vec<Key>,
+    product: TypeConstraint,
+  ) -> Result<(), String> {
+    let selector = selectors::Select::without_variant(product);
+    let mut entries_by_type: HashMap<TypeId, Arc<rule_graph::Entries>> = HashMap::new();
+    let mut roots = Vec::with_capacity(subjects.len());
+    for subject in subjects {
+      let subject_type = subject.type_id().clone();
+      if !entries_by_type.contains_key(&subject_type) {
+        let edges = self.find_root_edges_or_update_rule_graph(subject_type, &selector)?;
+        entries_by_type.insert(
+          subject_type,
+          edges.entries_for_select(&selector, subject_type),
+        );
+      }
+      roots.push(Root::Select(Select::new_with_shared_entries(
+        selector.clone(),
+        subject,
+        Variants::default(),
+        entries_by_type[&subject_type].clone(),
+      )));
+    }
+    request.roots.extend(roots);
+    Ok(())
+  }
+
+  ///
+  /// Adds a root which selects all of the given products for the subject, and produces them as a
+  /// tuple in the given order.
+  ///
+  pub fn add_root_select_multiple(
+    &self,
+    request: &mut ExecutionRequest,
+    subject: Key,
+    products: Vec<TypeConstraint>,
+  ) -> Result<(), String> {
+    if products.is_empty() {
+      return Err("A root must select at least one product.".to_string());
+    }
+    let selects = products
+      .into_iter()
+      .map(|product| -> Result<Select, String> {
+        let edges = self.find_root_edges_or_update_rule_graph(
+          subject.type_id().clone(),
+          &selectors::Select::without_variant(product),
+        )?;
+        Ok(Select::new(product, subject.clone(), Variants::default(), &edges))
+      })
+      .collect::<Result<Vec<_>, String>>()?;
+    request.roots.push(Root::SelectMultiple(SelectMultiple {
+      subject: subject,
+      selects: selects,
+    }));
     Ok(())
   }
 
@@ -119,7 +299,7 @@ impl Scheduler {
     &self,
     subject_type: TypeId,
     select: &selectors::Select,
-  ) -> Result<rule_graph::RuleEdges, String> {
+  ) -> Result<&rule_graph::RuleEdges, String> {
     self
       .core
       .rule_graph
@@ -137,9 +317,47 @@ impl Scheduler {
   /// Invalidate the invalidation roots represented by the given Paths.
   ///
   pub fn invalidate(&self, paths: &HashSet<PathBuf>) -> usize {
+    self.invalidate_fs_subjects(|fs_subject| paths.contains(fs_subject))
+  }
+
+  ///
+  /// Invalidate the invalidation roots which are located under any of the given directory
+  /// prefixes. Prefixes are matched by path component, so `a/b` matches `a/b/c` but not `a/bc`.
+  ///
+  pub fn invalidate_prefixes(&self, prefixes: &[PathBuf]) -> usize {
+    self.invalidate_fs_subjects(|fs_subject| {
+      prefixes.iter().any(|prefix| fs_subject.starts_with(prefix))
+    })
+  }
+
+  ///
+  /// Invalidate all filesystem dependencies in the graph.
+  ///
+  pub fn invalidate_all_paths(&self) -> usize {
+    self.invalidate_fs_subjects(|_| true)
+  }
+
+  ///
+  /// Invalidate the filesystem dependencies which match the given predicate, along with any
+  /// Snapshots that were restored from the snapshot cache (which have no filesystem dependencies in
+  /// the graph). The snapshot cache is disabled until its next generation is set.
+  ///
+  fn invalidate_fs_subjects<P: Fn(&Path) -> bool>(&self, predicate: P) -> usize {
+    // Directories which were proven not to contain matches may no longer be empty, and files which
+    // were digested may have changed.
+    self.core.negative_match_filter.clear();
+    self.core.inode_digests.clear();
+    let restored = self
+      .core
+      .snapshot_cache
+      .as_ref()
+      .map(|cache| cache.invalidate())
+      .unwrap_or_default();
     let invalidation_result = self.core.graph.invalidate_from_roots(move |node| {
       if let Some(fs_subject) = node.fs_subject() {
-        paths.contains(fs_subject)
+        predicate(fs_subject)
+      } else if let Some(subject) = node.snapshot_subject() {
+        restored.contains(subject)
       } else {
         false
       }
@@ -149,34 +367,134 @@ impl Scheduler {
   }
 
   ///
-  /// Invalidate all filesystem dependencies in the graph.
+  /// Clear every Node in the graph (including those that are not filesystem dependencies), forcing
+  /// all of them to be recomputed the next time they are requested.
   ///
-  pub fn invalidate_all_paths(&self) -> usize {
-    let invalidation_result = self
-      .core
-      .graph
-      .invalidate_from_roots(|node| node.fs_subject().is_some());
-    // TODO: Expose.
+  pub fn invalidate_all(&self) -> usize {
+    self.core.negative_match_filter.clear();
+    self.core.inode_digests.clear();
+    if let Some(ref cache) = self.core.snapshot_cache {
+      cache.invalidate();
+    }
+    let invalidation_result = self.core.graph.invalidate_from_roots(|_| true);
     invalidation_result.cleared + invalidation_result.dirtied
   }
 
   ///
   /// Return Scheduler and per-Session metrics.
   ///
-  pub fn metrics(&self, session: &Session) -> HashMap<&str, i64> {
+  /// Invalidation counters are reported per Node type, as `invalidated_nodes.$type` (Nodes that
+  /// were cleared or dirtied), and `invalidated_nodes_changed.$type` and
+  /// `invalidated_nodes_unchanged.$type` (invalidated Nodes which, when re-run, did or did not
+  /// produce a different result). The breakdown of `heap_usage` is reported as `heap_bytes.$name`.
+  ///
+  /// Work which blocks on IO (including all access to the local Store) runs on a dedicated pool
+  /// (whose threads are named `io-pool-N`) rather than on the threads which drive futures (named
+  /// `cpu-pool-N`). For each pool, the number of functions or Nodes run there is reported as
+  /// `$pool.calls`, the time that they spent running as `fs_pool.blocked_ms` or
+  /// `cpu_pool.busy_ms`, the time that they spent queued waiting for a thread as `$pool.queued_ms`,
+  /// and the percentage of the capacity of the pool that was used over the life of the Session as
+  /// `$pool.utilization_pct`.
+  ///
+  pub fn metrics(&self, session: &Session) -> HashMap<String, i64> {
     let mut m = HashMap::new();
     m.insert(
-      "affected_file_count",
+      "affected_file_count".to_string(),
       self
         .core
         .graph
         .reachable_digest_count(&session.root_nodes()) as i64,
     );
-    m.insert("preceding_graph_size", session.preceding_graph_size as i64);
-    m.insert("resulting_graph_size", self.core.graph.len() as i64);
+    m.insert(
+      "preceding_graph_size".to_string(),
+      session.preceding_graph_size as i64,
+    );
+    m.insert(
+      "resulting_graph_size".to_string(),
+      self.core.graph.len() as i64,
+    );
+
+    let counters = self
+      .core
+      .graph
+      .invalidation_counters()
+      .since(&session.invalidation_baseline);
+    for (prefix, counts) in &[
+      ("invalidated_nodes", counters.invalidated),
+      ("invalidated_nodes_changed", counters.changed),
+      ("invalidated_nodes_unchanged", counters.unchanged),
+    ] {
+      for (type_name, count) in counts {
+        m.insert(format!("{}.{}", prefix, type_name), *count as i64);
+      }
+    }
+    let process_results = self
+      .core
+      .process_result_counters
+      .lock()
+      .unwrap()
+      .since(&session.process_result_baseline);
+    for (source, count) in process_results {
+      m.insert(format!("process_results.{}", source), count as i64);
+    }
+    let window = session.created.elapsed();
+    for &(pool, busy_name, counters, threads) in &[
+      (
+        "fs_pool",
+        "blocked_ms",
+        self
+          .core
+          .fs_pool
+          .counters()
+          .since(&session.fs_pool_baseline),
+        self.core.fs_pool.threads(),
+      ),
+      (
+        "cpu_pool",
+        "busy_ms",
+        self
+          .core
+          .runtime_instrumentation
+          .counters()
+          .since(&session.runtime_baseline),
+        self.core.runtime_threads,
+      ),
+    ] {
+      m.insert(format!("{}.calls", pool), counters.calls as i64);
+      m.insert(format!("{}.{}", pool, busy_name), millis(counters.busy));
+      m.insert(format!("{}.queued_ms", pool), millis(counters.queued));
+      m.insert(
+        format!("{}.utilization_pct", pool),
+        (counters.utilization(threads, window) * 100.0).round() as i64,
+      );
+    }
+    for (name, bytes) in self.heap_usage() {
+      m.insert(format!("heap_bytes.{}", name), bytes as i64);
+    }
     m
   }
 
+  ///
+  /// Returns estimates of the bytes of heap memory held by the engine, broken down as:
+  ///   `graph.entries`: the Entries of the Graph.
+  ///   `graph.results.$type`: the results of Nodes, by type of result.
+  ///   `store.$purpose`: the in-memory state of the Store.
+  ///   `interned.$table`: the interned Keys and paths, which are shared by all Schedulers.
+  ///
+  pub fn heap_usage(&self) -> Vec<(String, usize)> {
+    let graph = self.core.graph.heap_usage();
+    let mut usage = vec![("graph.entries".to_string(), graph.entries)];
+    for (result_type, bytes) in graph.results {
+      usage.push((format!("graph.results.{}", result_type), bytes));
+    }
+    for (purpose, bytes) in self.core.store.heap_usage() {
+      usage.push((format!("store.{}", purpose), bytes));
+    }
+    usage.push(("interned.keys".to_string(), externs::interns_heap_size()));
+    usage.push(("interned.paths".to_string(), fs::InternedPath::heap_size()));
+    usage
+  }
+
   ///
   /// Attempts to complete all of the given roots, retrying the entire set (up to `count`
   /// times) if any of them fail with `Failure::Invalidated`.
@@ -185,10 +503,17 @@ impl Scheduler {
   /// were (say by an automated process changing files under pants), we'd want to eventually
   /// give up.
   ///
+  /// If a `RootCallback` is provided, it is invoked with the result of each root as soon as that
+  /// result is final (ie, not a retryable `Failure::Invalidated`).
+  ///
+  /// Once `abandoned` is set, the roots are not retried: see `Scheduler::abandon`.
+  ///
   fn execute_helper(
     context: RootContext,
     roots: Vec<Root>,
     count: usize,
+    on_root: Option<RootCallback>,
+    abandoned: Arc<AtomicBool>,
   ) -> BoxFuture<Vec<Result<Value, Failure>>, ()> {
     let executor = context.core.runtime.get().executor();
     // Attempt all roots in parallel, failing fast to retry for `Invalidated`.
@@ -196,7 +521,9 @@ impl Scheduler {
       roots
         .clone()
         .into_iter()
-        .map(|root| {
+        .enumerate()
+        .map(|(index, root)| {
+          let on_root = on_root.clone();
           context
             .core
             .graph
@@ -211,12 +538,16 @@ impl Scheduler {
                   // Otherwise (if it is a success, some other type of Failure, or if we've run
                   // out of retries) recover to complete the join, which will cause the results to
                   // propagate to the user.
-                  debug!("Root {} completed.", NodeKey::Select(root).format());
-                  Ok(other.map(|res| {
+                  debug!("Root {} completed.", NodeKey::from(root).format());
+                  let result = other.map(|res| {
                     res
                       .try_into()
                       .unwrap_or_else(|_| panic!("A Node implementation was ambiguous."))
-                  }))
+                  });
+                  if let Some(on_root) = on_root {
+                    on_root(index, &result);
+                  }
+                  Ok(result)
                 }
               }
             })
@@ -227,7 +558,13 @@ impl Scheduler {
     // If the join failed (due to `Invalidated`, since that is the only error we propagate), retry
     // the entire set of roots.
     oneshot::spawn(
-      roots_res.or_else(move |_| Scheduler::execute_helper(context, roots, count - 1)),
+      roots_res.or_else(move |_| {
+        if abandoned.load(Ordering::SeqCst) {
+          future::err(()).to_boxed()
+        } else {
+          Scheduler::execute_helper(context, roots, count - 1, on_root, abandoned)
+        }
+      }),
       &executor,
     ).to_boxed()
   }
@@ -235,31 +572,294 @@ impl Scheduler {
   ///
   /// Compute the results for roots in the given request.
   ///
-  pub fn execute<'e>(
+  pub fn execute(
+    &self,
+    request: &ExecutionRequest,
+    session: &Session,
+  ) -> Vec<RootResult> {
+    self.execute_with_callback(request, session, None)
+  }
+
+  ///
+  /// Compute the results for roots in the given request, additionally invoking the given
+  /// callback with the index and result of each root as soon as it completes.
+  ///
+  /// Because the entire request is retried if any root is invalidated, a root might be computed
+  /// more than once: the callback is invoked at most once per root.
+  ///
+  pub fn execute_streaming(
+    &self,
+    request: &ExecutionRequest,
+    session: &Session,
+    on_root: RootCallback,
+  ) -> Vec<RootResult> {
+    let delivered = Mutex::new(HashSet::new());
+    let once_per_root: RootCallback = Arc::new(move |index: usize, result: &RootResult| {
+      if delivered.lock().unwrap().insert(index) {
+        on_root(index, result);
+      }
+    });
+    self.execute_with_callback(request, session, Some(once_per_root))
+  }
+
+  fn execute_with_callback(
     &self,
-    request: &'e ExecutionRequest,
+    request: &ExecutionRequest,
     session: &Session,
-  ) -> Vec<(&'e Key, &'e TypeConstraint, RootResult)> {
+    on_root: Option<RootCallback>,
+  ) -> Vec<RootResult> {
     // Bootstrap tasks for the roots, and then wait for all of them.
     debug!("Launching {} roots.", request.roots.len());
 
     session.extend(&request.roots);
+    let _running = self.core.running_execution(session.background);
+    if self.core.shutdown_deadline().is_some() {
+      let failure = Failure::Cancelled(SHUTDOWN_CANCELLATION.to_string());
+      return request.roots.iter().map(|_| Err(failure.clone())).collect();
+    }
 
     // Wait for all roots to complete. Failure here should be impossible, because each
     // individual Future in the join was (eventually) mapped into success.
     let context = RootContext {
       core: self.core.clone(),
+      deadline: session.deadline,
     };
-    let results = Scheduler::execute_helper(context, request.roots.clone(), 8)
-      .wait()
-      .expect("Execution failed.");
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let execution = Scheduler::execute_helper(
+      context,
+      request.roots.clone(),
+      8,
+      on_root,
+      abandoned.clone(),
+    );
+    self.wait_with_watchdog(
+      execution,
+      &request.roots,
+      &abandoned,
+      self.core.stall_policy.timeout,
+    )
+  }
+
+  ///
+  /// Waits for the given execution, while watching for stalls: ie, periods of at least `timeout`
+  /// during which Nodes were running, but none completed. Stalls are logged along with the running
+  /// Nodes and any in-flight remote operations, and if the StallPolicy says to, the execution is
+  /// then abandoned and all of its roots fail with `Failure::Cancelled`, describing the stall.
+  ///
+  /// The execution is likewise abandoned if the Scheduler is shut down and it does not complete
+  /// before the shutdown deadline.
+  ///
+  fn wait_with_watchdog(
+    &self,
+    execution: BoxFuture<Vec<RootResult>, ()>,
+    roots: &[Root],
+    abandoned: &AtomicBool,
+    timeout: Option<Duration>,
+  ) -> Vec<RootResult> {
+    let (sender, receiver) = mpsc::channel();
+    self
+      .core
+      .runtime
+      .get()
+      .executor()
+      .spawn(execution.then(move |res| {
+        // The receiver will have gone away if the execution was cancelled.
+        let _ = sender.send(res);
+        Ok(())
+      }));
+
+    let check_interval = Duration::from_millis(EXECUTION_CHECK_INTERVAL_MILLIS);
+    let start = Instant::now();
+    let mut last_report = start;
+    loop {
+      match receiver.recv_timeout(timeout.map_or(check_interval, |t| cmp::min(t, check_interval))) {
+        Ok(res) => return res.expect("Execution failed."),
+        Err(RecvTimeoutError::Disconnected) => panic!("Execution was dropped before completing."),
+        Err(RecvTimeoutError::Timeout) => {}
+      }
+
+      if let Some(deadline) = self.core.shutdown_deadline() {
+        if Instant::now() >= deadline {
+          warn!("Execution was cancelled because the scheduler is shutting down.");
+          return self.abandon(roots, abandoned, SHUTDOWN_CANCELLATION.to_string());
+        }
+      }
+      let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => continue,
+      };
+
+      // Progress is measured from the most recent of: the start of this execution, the most
+      // recent completion of a Node, or the most recent report of a stall.
+      let last_progress = self
+        .core
+        .graph
+        .last_completion()
+        .map(|completion| cmp::max(completion, last_report))
+        .unwrap_or(last_report);
+      if last_progress.elapsed() < timeout {
+        continue;
+      }
+      let running = self.core.graph.running_nodes();
+      if running.is_empty() {
+        continue;
+      }
 
-    request
-      .roots
+      last_report = Instant::now();
+      let stall = format!(
+        "{} nodes are running, but none have completed in the last {} seconds.\nRunning nodes:\n\
+         {}\nIn-flight remote operations:\n{}",
+        running.len(),
+        last_progress.elapsed().as_secs(),
+        running
+          .iter()
+          .map(|&(ref node, duration)| format!("  {} (running for {} s)", node, duration.as_secs()))
+          .collect::<Vec<_>>()
+          .join("\n"),
+        self
+          .core
+          .command_runner
+          .in_flight_operations()
+          .iter()
+          .map(|operation| format!("  {}", operation))
+          .collect::<Vec<_>>()
+          .join("\n")
+      );
+      warn!("Execution has stalled: {}", stall);
+
+      if self.core.stall_policy.cancel {
+        warn!(
+          "Execution was cancelled after stalling for {} seconds.",
+          timeout.as_secs()
+        );
+        return self.abandon(
+          roots,
+          abandoned,
+          format!(
+            "The request was cancelled before it completed, because it stalled: {}",
+            stall
+          ),
+        );
+      }
+    }
+  }
+
+  ///
+  /// Abandons an execution of the given roots: the running Nodes that they depend on are cancelled
+  /// (which stops their work, including any processes that they are running), and the roots are
+  /// not retried when their cancelled dependencies fail. Returns `Failure::Cancelled` with the
+  /// given message for each root.
+  ///
+  /// Other executions that were waiting on the cancelled Nodes retry them as they would if they
+  /// had been invalidated.
+  ///
+  fn abandon(&self, roots: &[Root], abandoned: &AtomicBool, message: String) -> Vec<RootResult> {
+    abandoned.store(true, Ordering::SeqCst);
+    let root_nodes = roots
       .iter()
-      .zip(results.into_iter())
-      .map(|(s, r)| (&s.subject, &s.selector.product, r))
-      .collect()
+      .map(|root| root.clone().into())
+      .collect::<Vec<NodeKey>>();
+    let cancelled = self.core.graph.cancel(&root_nodes);
+    debug!("Cancelled {} running nodes of an abandoned execution.", cancelled);
+    let failure = Failure::Cancelled(message);
+    roots.iter().map(|_| Err(failure.clone())).collect()
+  }
+
+  ///
+  /// Releases memory which was held to speed up the next execution, if the Scheduler has been idle
+  /// (ie, no execution has been running and no Node has completed) for at least `min_idle`, and has
+  /// done work since it was last trimmed:
+  ///   1. The results of evictable Nodes are evicted from the Graph: see `NodeKey::evictable`.
+  ///   2. The in-memory caches of the Store are released: see `Store::trim_memory`.
+  ///   3. The allocator is asked to return free memory to the OS.
+  ///
+  /// Returns a report of the memory that was released, or None if the Scheduler was not trimmed.
+  ///
+  pub fn trim_memory(&self, min_idle: Duration) -> Option<TrimReport> {
+    let last_completion = self.core.graph.last_completion()?;
+    if self.core.running_execution_count() > 0 || last_completion.elapsed() < min_idle {
+      return None;
+    }
+    {
+      let mut trimmed_after_completion = self.trimmed_after_completion.lock().unwrap();
+      if *trimmed_after_completion == Some(last_completion) {
+        return None;
+      }
+      *trimmed_after_completion = Some(last_completion);
+    }
+
+    let rss_before = memory::resident_set_bytes();
+    let evicted = self.core.graph.evict(NodeKey::evictable);
+    // The Values held by evicted Nodes are only released once their Handles are dropped.
+    let released_values = handles::drop_all_handles();
+    let store_bytes = self.core.store.trim_memory();
+    memory::trim_allocator();
+    let rss_after = memory::resident_set_bytes();
+
+    let report = TrimReport {
+      evicted_nodes: evicted.evicted,
+      released_values,
+      graph_bytes: evicted.bytes,
+      store_bytes,
+      rss_bytes: match (rss_before, rss_after) {
+        (Some(before), Some(after)) => Some(before.saturating_sub(after)),
+        _ => None,
+      },
+    };
+    info!("Trimmed memory after {:?} idle: {:?}", last_completion.elapsed(), report);
+    Some(report)
+  }
+
+  ///
+  /// Shuts down the Scheduler, so that its resources are released before (rather than by) the exit
+  /// of the process:
+  ///   1. Executions which begin after this call are cancelled immediately, and running executions
+  ///      are given until the timeout to complete before they are abandoned: see `abandon`.
+  ///   2. Processes which are still running are terminated, no further processes are launched, and
+  ///      remote execution channels are closed: see `CommandRunner::shutdown`.
+  ///   3. The Store is flushed to disk, and its remote channel is closed.
+  ///
+  /// The engine does not watch the filesystem itself, so there are no watchers to stop.
+  ///
+  /// Returns a report of the work that had to be abandoned, and of the processes that had to be
+  /// killed.
+  ///
+  pub fn shutdown(&self, timeout: Duration) -> ShutdownReport {
+    let deadline = Instant::now() + timeout;
+    self.core.begin_shutdown(deadline);
+    let wait_until = |deadline: Instant| {
+      while self.core.running_execution_count() > 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+      }
+      self.core.running_execution_count()
+    };
+
+    // Wait for running executions to drain, and then for any that did not to notice that they
+    // have been cancelled.
+    let cancelled_executions = wait_until(deadline);
+    if cancelled_executions > 0 {
+      wait_until(Instant::now() + Duration::from_millis(2 * EXECUTION_CHECK_INTERVAL_MILLIS));
+    }
+
+    let killed_processes = self.core.command_runner.shutdown();
+
+    // Give outputs which are being written through to the remote store as long again to upload.
+    let mut errors = Vec::new();
+    if let Err(e) = self.core.store.flush_uploads(Some(Instant::now() + timeout)) {
+      errors.push(e);
+    }
+    if let Err(e) = self.core.store.sync() {
+      errors.push(e);
+    }
+    self.core.store.close_remote();
+
+    let report = ShutdownReport {
+      cancelled_executions,
+      killed_processes,
+      errors,
+    };
+    info!("Scheduler shut down: {:?}", report);
+    report
   }
 
   pub fn capture_snapshot_from_arbitrary_root<P: AsRef<Path>>(
@@ -271,13 +871,19 @@ impl Scheduler {
     // the resultant Snapshot into the store and return it. This is important, because we're reading
     // things from arbitrary filepaths which we don't want to cache in the graph, as we don't watch
     // them for changes.
-    // We assume that this Snapshot is of an immutable piece of the filesystem.
-
-    let posix_fs = Arc::new(try_future!(PosixFS::new(
-      root_path,
-      self.core.fs_pool.clone(),
-      &[]
-    )));
+    // We assume that this Snapshot is of an immutable piece of the filesystem (such as a system
+    // toolchain), whose contents will be uploaded on demand if it is used as the input to a remote
+    // process execution.
+
+    let link_escape_behavior = self.core.vfs.link_escape_behavior();
+    let posix_fs = Arc::new(try_future!(
+      PosixFS::new(
+        root_path,
+        self.core.fs_pool.clone(),
+        &[],
+        self.core.vfs.special_file_behavior(),
+      ).map(|posix_fs| posix_fs.with_link_escape_behavior(link_escape_behavior))
+    ));
     let store = self.core.store.clone();
 
     posix_fs
@@ -305,10 +911,69 @@ impl Drop for Scheduler {
 ///
 /// Root requests are limited to Selectors that produce (python) Values.
 ///
-type Root = Select;
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Root {
+  Select(Select),
+  SelectMultiple(SelectMultiple),
+}
+
+impl From<Root> for NodeKey {
+  fn from(root: Root) -> Self {
+    match root {
+      Root::Select(s) => NodeKey::Select(s),
+      Root::SelectMultiple(s) => NodeKey::SelectMultiple(s),
+    }
+  }
+}
 
 pub type RootResult = Result<Value, Failure>;
 
+fn millis(duration: Duration) -> i64 {
+  (duration.as_secs() * 1000 + u64::from(duration.subsec_millis())) as i64
+}
+
+// How frequently (in milliseconds) `Scheduler::wait_with_watchdog` checks for stalls, and for
+// the deadline of a shutdown.
+const EXECUTION_CHECK_INTERVAL_MILLIS: u64 = 100;
+
+// The message of the Failure of the roots of executions which are cancelled by a shutdown.
+const SHUTDOWN_CANCELLATION: &str =
+  "The request was cancelled before it completed, because the scheduler is shutting down.";
+
+///
+/// What `Scheduler::shutdown` had to abandon or force in order to shut down.
+///
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ShutdownReport {
+  // The number of executions which were cancelled because they had not completed by the deadline.
+  pub cancelled_executions: usize,
+  // The ids of the processes which were killed because they did not exit gracefully.
+  pub killed_processes: Vec<u32>,
+  // Errors that were encountered while releasing resources.
+  pub errors: Vec<String>,
+}
+
+///
+/// The memory that `Scheduler::trim_memory` released.
+///
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct TrimReport {
+  // The number of Nodes whose results were evicted from the Graph.
+  pub evicted_nodes: usize,
+  // The number of Values (held by python) that were released.
+  pub released_values: usize,
+  // Estimates of the bytes of heap memory released from the Graph and from the Store.
+  pub graph_bytes: usize,
+  pub store_bytes: usize,
+  // The reduction in the resident set size of the process, if it could be measured.
+  pub rss_bytes: Option<usize>,
+}
+
+///
+/// A callback that receives the index (within its ExecutionRequest) and result of a root.
+///
+pub type RootCallback = Arc<Fn(usize, &RootResult) + Send + Sync>;
+
 ///
 /// NB: This basic wrapper exists to allow us to implement the `NodeContext` trait (which lives
 /// outside of this crate) for the `Arc` struct (which also lives outside our crate), which is not
@@ -317,13 +982,14 @@ pub type RootResult = Result<Value, Failure>;
 #[derive(Clone)]
 struct RootContext {
   core: Arc<Core>,
+  deadline: Option<Instant>,
 }
 
 impl NodeContext for RootContext {
   type Node = NodeKey;
 
   fn clone_for(&self, entry_id: EntryId) -> Context {
-    Context::new(entry_id, self.core.clone())
+    Context::new(entry_id, self.core.clone(), self.deadline)
   }
 
   fn graph(&self) -> &Graph<NodeKey> {
@@ -0,0 +1,278 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::Future;
+use git2::{self, Diff, DiffOptions, ObjectType, Oid, Repository};
+
+use boxfuture::{BoxFuture, Boxable};
+use context::Core;
+use fs::{self, Dir, DirectoryListing, File, Link, ListingFilter, Stat, StoreFileByDigest, VFS};
+use hashing::Digest;
+
+// Git file modes for tree entries.
+const MODE_TREE: i32 = 0o040000;
+const MODE_BLOB: i32 = 0o100644;
+const MODE_BLOB_GROUP_WRITABLE: i32 = 0o100664;
+const MODE_BLOB_EXECUTABLE: i32 = 0o100755;
+const MODE_LINK: i32 = 0o120000;
+
+///
+/// Computes the files which have changed since the given revision in the git repository containing
+/// the build root, relative to the build root. Files outside of the build root are ignored.
+///
+/// Equivalent to the union of:
+///   git diff --name-only <since>...HEAD
+///   git diff --name-only HEAD
+///   git ls-files --other --exclude-standard
+/// ie, the files changed on the current branch since it diverged from `since`, as well as any
+/// uncommitted (staged, unstaged or untracked) changes.
+///
+pub fn changed_files(build_root: &Path, since: &str) -> Result<Vec<PathBuf>, String> {
+  let repo = open(build_root)?;
+  let prefix = build_root_prefix(&repo, build_root)?;
+
+  let head = resolve_commit(&repo, "HEAD")?;
+  let since_commit = resolve_commit(&repo, since)?;
+  let merge_base = repo
+    .merge_base(since_commit, head)
+    .map_err(|e| format!("Failed to find a merge base of {} and HEAD: {}", since, e))?;
+
+  let head_tree = tree_for(&repo, head)?;
+  let merge_base_tree = tree_for(&repo, merge_base)?;
+
+  let mut options = DiffOptions::new();
+  options.include_untracked(true).recurse_untracked_dirs(true);
+  if !prefix.as_os_str().is_empty() {
+    options.pathspec(prefix.as_path());
+  }
+
+  let committed = repo
+    .diff_tree_to_tree(Some(&merge_base_tree), Some(&head_tree), Some(&mut options))
+    .map_err(|e| format!("Failed to diff {} against HEAD: {}", since, e))?;
+  let uncommitted = repo
+    .diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut options))
+    .map_err(|e| format!("Failed to diff HEAD against the working tree: {}", e))?;
+
+  let mut paths = BTreeSet::new();
+  collect_paths(&committed, &mut paths);
+  collect_paths(&uncommitted, &mut paths);
+  Ok(
+    paths
+      .into_iter()
+      .filter_map(|path| path.strip_prefix(&prefix).ok().map(|p| p.to_owned()))
+      .collect(),
+  )
+}
+
+///
+/// A VFS which reads directories, symlinks and files from a tree in the git object database,
+/// rather than from the working copy. Paths are relative to the build root, so globs which are
+/// expanded against a GitTreeFS match the same paths that they would have in a checkout of the
+/// tree.
+///
+/// Both this VFS and the file content that it stores are used to capture Snapshots of historical
+/// revisions without touching the working copy.
+///
+#[derive(Clone)]
+pub struct GitTreeFS {
+  core: Arc<Core>,
+  // A git2::Repository may be sent between threads, but not shared by them.
+  repo: Arc<Mutex<Repository>>,
+  tree: Oid,
+  // The path of the build root relative to the root of the tree.
+  prefix: PathBuf,
+}
+
+impl GitTreeFS {
+  ///
+  /// Creates a GitTreeFS for the tree of the given revision (or other tree-ish) in the git
+  /// repository containing the build root.
+  ///
+  pub fn new(core: Arc<Core>, revision: &str) -> Result<GitTreeFS, String> {
+    let repo = open(&core.build_root)?;
+    let prefix = build_root_prefix(&repo, &core.build_root)?;
+    let tree = repo
+      .revparse_single(revision)
+      .and_then(|object| object.peel(ObjectType::Tree))
+      .map(|tree| tree.id())
+      .map_err(|e| format!("Failed to resolve {} to a tree: {}", revision, e))?;
+    Ok(GitTreeFS {
+      core: core,
+      repo: Arc::new(Mutex::new(repo)),
+      tree: tree,
+      prefix: prefix,
+    })
+  }
+
+  fn scandir_sync(&self, dir: &Dir) -> Result<Vec<Stat>, String> {
+    let repo = self.repo.lock().unwrap();
+    let tree = self
+      .find_tree(&repo, &dir.0)
+      .map_err(|e| format!("Failed to read directory {:?}: {}", dir.0, e))?;
+    let odb = repo.odb().map_err(|e| e.to_string())?;
+    let mut stats = Vec::new();
+    for entry in tree.iter() {
+      let path = dir.0.join(fs::os::os_string_from_bytes(entry.name_bytes().to_vec()));
+      let stat = match entry.filemode() {
+        MODE_TREE => Stat::Dir(Dir(path.into())),
+        MODE_LINK => Stat::Link(Link(path.into())),
+        mode @ MODE_BLOB | mode @ MODE_BLOB_GROUP_WRITABLE | mode @ MODE_BLOB_EXECUTABLE => {
+          let (size, _) = odb
+            .read_header(entry.id())
+            .map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+          Stat::File(File {
+            path: path.into(),
+            is_executable: mode == MODE_BLOB_EXECUTABLE,
+            size: size as u64,
+          })
+        }
+        // Submodules (gitlinks) have no content in this repository.
+        _ => continue,
+      };
+      stats.push(stat);
+    }
+    stats.sort_by(|s1, s2| fs::path_order(s1.path(), s2.path()));
+    Ok(stats)
+  }
+
+  fn read_blob_sync(&self, path: &Path) -> Result<Bytes, String> {
+    let repo = self.repo.lock().unwrap();
+    let root = repo.find_tree(self.tree).map_err(|e| e.to_string())?;
+    root
+      .get_path(&self.prefix.join(path))
+      .and_then(|entry| repo.find_blob(entry.id()))
+      .map(|blob| Bytes::from(blob.content()))
+      .map_err(|e| format!("Failed to read {:?}: {}", path, e))
+  }
+
+  fn find_tree<'r>(
+    &self,
+    repo: &'r Repository,
+    path: &Path,
+  ) -> Result<git2::Tree<'r>, git2::Error> {
+    let root = repo.find_tree(self.tree)?;
+    let tree_path = self.prefix.join(path);
+    if tree_path.as_os_str().is_empty() {
+      Ok(root)
+    } else {
+      let entry = root.get_path(&tree_path)?;
+      repo.find_tree(entry.id())
+    }
+  }
+}
+
+impl VFS<String> for GitTreeFS {
+  fn read_link(&self, link: &Link) -> BoxFuture<PathBuf, String> {
+    let git_fs = self.clone();
+    let link = link.clone();
+    self
+      .core
+      .fs_pool
+      .spawn_fn(move || {
+        git_fs
+          .read_blob_sync(&link.0)
+          .map(|dest| PathBuf::from(fs::os::os_string_from_bytes(dest.to_vec())))
+      })
+      .to_boxed()
+  }
+
+  fn scandir_matching(
+    &self,
+    dir: Dir,
+    _filter: &ListingFilter,
+  ) -> BoxFuture<Arc<DirectoryListing>, String> {
+    // Trees are read from the repository in full.
+    let git_fs = self.clone();
+    self
+      .core
+      .fs_pool
+      .spawn_fn(move || {
+        git_fs
+          .scandir_sync(&dir)
+          .map(|stats| Arc::new(DirectoryListing(stats)))
+      })
+      .to_boxed()
+  }
+
+  fn is_ignored(&self, stat: &Stat) -> bool {
+    self.core.vfs.is_ignored(stat)
+  }
+
+  fn mk_error(msg: &str) -> String {
+    msg.to_owned()
+  }
+}
+
+impl StoreFileByDigest<String> for GitTreeFS {
+  fn store_by_digest(&self, file: File) -> BoxFuture<Digest, String> {
+    let git_fs = self.clone();
+    let store = self.core.store.clone();
+    self
+      .core
+      .fs_pool
+      .spawn_fn(move || git_fs.read_blob_sync(&file.path))
+      .and_then(move |content| store.store_file_bytes(content, true))
+      .to_boxed()
+  }
+}
+
+fn open(build_root: &Path) -> Result<Repository, String> {
+  Repository::discover(build_root)
+    .map_err(|e| format!("Failed to open a git repository for {:?}: {}", build_root, e))
+}
+
+///
+/// Returns the path of the build root relative to the working tree of the repository.
+///
+fn build_root_prefix(repo: &Repository, build_root: &Path) -> Result<PathBuf, String> {
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| format!("The git repository for {:?} is bare.", build_root))?;
+  // Either path might involve symlinks, so compare their canonical forms.
+  let canonicalize = |path: &Path| {
+    path
+      .canonicalize()
+      .map_err(|e| format!("Failed to canonicalize {:?}: {}", path, e))
+  };
+  let workdir = canonicalize(workdir)?;
+  let build_root = canonicalize(build_root)?;
+  build_root
+    .strip_prefix(&workdir)
+    .map(|p| p.to_owned())
+    .map_err(|_| {
+      format!(
+        "The build root {:?} is not within the git working tree {:?}",
+        build_root, workdir
+      )
+    })
+}
+
+fn resolve_commit(repo: &Repository, spec: &str) -> Result<Oid, String> {
+  repo
+    .revparse_single(spec)
+    .and_then(|object| object.peel(ObjectType::Commit))
+    .map(|commit| commit.id())
+    .map_err(|e| format!("Failed to resolve {} to a commit: {}", spec, e))
+}
+
+fn tree_for(repo: &Repository, commit: Oid) -> Result<git2::Tree, String> {
+  repo
+    .find_commit(commit)
+    .and_then(|commit| commit.tree())
+    .map_err(|e| format!("Failed to load the tree of commit {}: {}", commit, e))
+}
+
+fn collect_paths(diff: &Diff, paths: &mut BTreeSet<PathBuf>) {
+  for delta in diff.deltas() {
+    for path in &[delta.old_file().path(), delta.new_file().path()] {
+      if let Some(path) = path {
+        paths.insert(path.to_owned());
+      }
+    }
+  }
+}
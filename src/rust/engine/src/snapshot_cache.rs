@@ -0,0 +1,517 @@
+// Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+// Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use core::Key;
+use fs::{self, Dir, File, PathStat};
+use hashing::{Digest, Fingerprint, WriterHasher};
+use tempfile;
+
+const GENERATION_FILE: &str = "generation";
+const ENTRIES_DIR: &str = "entries";
+// The prefix of the directories that evicted entries are moved to while they are deleted.
+const EVICTED_PREFIX: &str = "evicted-";
+
+// The default bound on the total size of the entries of a SnapshotCache.
+const MAX_ENTRIES_BYTES: u64 = 64 * 1024 * 1024;
+
+///
+/// A cache of the Snapshots captured for PathGlobs, which persists across restarts of the process,
+/// so that a restarted daemon need not expand globs against the whole repository again.
+///
+/// Entries are keyed by a fingerprint of the PathGlobs and of a "generation": an opaque token
+/// provided by the filesystem watcher (such as a watchman clock) which changes whenever files under
+/// the build root might have changed. The cache is disabled until a generation is set, and again
+/// whenever files are invalidated, until the next generation is set. The generation is persisted
+/// alongside the entries, so that the next process can reuse it if the watcher reports that nothing
+/// has changed since.
+///
+/// As a cheap second check, each entry records the size, permissions and modification time of the
+/// files and directories in its Snapshot, which must be unchanged for the entry to be used.
+///
+/// Entries can only be used under the generation that they were stored under, so they are all
+/// evicted when files are invalidated. Between invalidations, the total size of the entries is
+/// bounded by evicting the oldest of them.
+///
+/// The layout of the cache directory is:
+///   generation: The last generation to be set.
+///   entries/<fingerprint>: The Digest and PathStats of a Snapshot, as NUL terminated fields.
+///   evicted-*: Entries which are being deleted.
+///
+pub struct SnapshotCache {
+  dir: PathBuf,
+  build_root: PathBuf,
+  // A fingerprint of the configuration which affects the expansion of globs.
+  salt: Fingerprint,
+  max_bytes: u64,
+  inner: Mutex<Inner>,
+}
+
+struct Inner {
+  generation: Option<String>,
+  // The subjects of the Snapshot Nodes whose results were restored from the cache.
+  restored: HashSet<Key>,
+  // The total size of the entries, once it has been measured by the first store.
+  entries_bytes: Option<u64>,
+}
+
+impl SnapshotCache {
+  pub fn new(dir: PathBuf, build_root: &Path, ignore_patterns: &[String]) -> SnapshotCache {
+    let build_root_bytes = fs::os::os_str_bytes(build_root.as_os_str());
+    let mut fields = vec![&*build_root_bytes];
+    fields.extend(ignore_patterns.iter().map(|pattern| pattern.as_bytes()));
+    SnapshotCache {
+      dir,
+      build_root: build_root.to_owned(),
+      salt: fingerprint_fields(&fields),
+      max_bytes: MAX_ENTRIES_BYTES,
+      inner: Mutex::new(Inner {
+        generation: None,
+        restored: HashSet::new(),
+        entries_bytes: None,
+      }),
+    }
+  }
+
+  ///
+  /// The generation that was last set (by this process or a previous one), if any.
+  ///
+  pub fn persisted_generation(&self) -> Option<String> {
+    let path = self.dir.join(GENERATION_FILE);
+    match ::std::fs::read_to_string(&path) {
+      Ok(generation) => Some(generation),
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+      Err(e) => {
+        warn!("Failed to read {}: {}", path.display(), e);
+        None
+      }
+    }
+  }
+
+  ///
+  /// The current generation, if the cache is enabled.
+  ///
+  pub fn generation(&self) -> Option<String> {
+    self.inner.lock().unwrap().generation.clone()
+  }
+
+  ///
+  /// Enables the cache under the given generation, and persists it. Failing to persist the
+  /// generation is logged: it only prevents the next process from reusing it.
+  ///
+  pub fn set_generation(&self, generation: String) {
+    if let Err(e) = self.write(Path::new(GENERATION_FILE), generation.as_bytes()) {
+      warn!("Failed to persist the snapshot cache generation: {}", e);
+    }
+    self.inner.lock().unwrap().generation = Some(generation);
+  }
+
+  ///
+  /// Disables the cache until the next generation is set, and returns the subjects of the Snapshot
+  /// Nodes which were restored from it. Restored Nodes have no filesystem dependencies in the
+  /// Graph, so they must be invalidated along with any files.
+  ///
+  /// If the cache was enabled, its entries (which were stored under the current generation, and so
+  /// cannot be used again) are evicted. They are deleted in the background.
+  ///
+  pub fn invalidate(&self) -> HashSet<Key> {
+    let mut inner = self.inner.lock().unwrap();
+    if inner.generation.take().is_some() {
+      if let Err(e) = self.evict_all() {
+        warn!("Failed to evict snapshot cache entries: {}", e);
+      }
+      inner.entries_bytes = Some(0);
+    }
+    mem::replace(&mut inner.restored, HashSet::new())
+  }
+
+  ///
+  /// Moves the entries aside, and deletes them on another thread.
+  ///
+  fn evict_all(&self) -> io::Result<()> {
+    let evicted = tempfile::Builder::new()
+      .prefix(EVICTED_PREFIX)
+      .tempdir_in(&self.dir)?;
+    match ::std::fs::rename(self.dir.join(ENTRIES_DIR), evicted.path().join(ENTRIES_DIR)) {
+      Ok(()) => {}
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e),
+    }
+    // Dropping the TempDir deletes it.
+    thread::spawn(move || drop(evicted));
+    Ok(())
+  }
+
+  ///
+  /// Records that the Snapshot Node for the given subject was restored from the cache.
+  ///
+  pub fn restored(&self, subject: Key) {
+    self.inner.lock().unwrap().restored.insert(subject);
+  }
+
+  ///
+  /// Computes the key of the entry for the given generation and PathGlobs.
+  ///
+  pub fn fingerprint(
+    &self,
+    generation: &str,
+    include: &[String],
+    exclude: &[String],
+    failure_behavior: &str,
+  ) -> Fingerprint {
+    let salt = self.salt.to_hex();
+    let include_len = include.len().to_string();
+    let mut fields = vec![salt.as_bytes(), generation.as_bytes(), include_len.as_bytes()];
+    fields.extend(include.iter().map(|glob| glob.as_bytes()));
+    fields.extend(exclude.iter().map(|glob| glob.as_bytes()));
+    fields.push(failure_behavior.as_bytes());
+    fingerprint_fields(&fields)
+  }
+
+  ///
+  /// Loads the entry with the given fingerprint, if there is one and none of the files and
+  /// directories in its Snapshot have changed since it was stored. Unusable entries are logged and
+  /// ignored.
+  ///
+  pub fn load(&self, fingerprint: Fingerprint) -> Option<fs::Snapshot> {
+    let path = self.entry_path(fingerprint);
+    let bytes = match ::std::fs::read(self.dir.join(&path)) {
+      Ok(bytes) => bytes,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return None,
+      Err(e) => {
+        warn!("Failed to read snapshot cache entry {}: {}", path.display(), e);
+        return None;
+      }
+    };
+    match self.decode(&bytes) {
+      Ok(snapshot) => snapshot,
+      Err(e) => {
+        warn!("Ignoring invalid snapshot cache entry {}: {}", path.display(), e);
+        None
+      }
+    }
+  }
+
+  ///
+  /// Stores the given Snapshot as the entry with the given fingerprint.
+  ///
+  pub fn store(&self, fingerprint: Fingerprint, snapshot: &fs::Snapshot) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    push_field(&mut bytes, snapshot.digest.0.to_hex().as_bytes());
+    push_field(&mut bytes, snapshot.digest.1.to_string().as_bytes());
+    for path_stat in &snapshot.path_stats {
+      let validator = self
+        .validator(path_stat)
+        .map_err(|e| format!("Failed to validate {}: {}", path_stat.path().display(), e))?;
+      match path_stat {
+        &PathStat::Dir { ref path, ref stat } => {
+          push_field(&mut bytes, b"d");
+          push_field(&mut bytes, &fs::os::os_str_bytes(path.as_os_str()));
+          push_field(&mut bytes, &fs::os::os_str_bytes(stat.0.as_os_str()));
+        }
+        &PathStat::File { ref path, ref stat } => {
+          push_field(&mut bytes, b"f");
+          push_field(&mut bytes, &fs::os::os_str_bytes(path.as_os_str()));
+          push_field(&mut bytes, &fs::os::os_str_bytes(stat.path.as_os_str()));
+          push_field(&mut bytes, if stat.is_executable { b"1" } else { b"0" });
+          push_field(&mut bytes, stat.size.to_string().as_bytes());
+        }
+      }
+      push_field(&mut bytes, validator.as_bytes());
+    }
+
+    let path = self.entry_path(fingerprint);
+    let mut inner = self.inner.lock().unwrap();
+    let entries_bytes = match inner.entries_bytes {
+      Some(entries_bytes) => entries_bytes,
+      None => self.measure_entries()?,
+    };
+    let replaced_bytes = ::std::fs::metadata(self.dir.join(&path))
+      .map(|metadata| metadata.len())
+      .unwrap_or(0);
+    self.write(&path, &bytes)?;
+    let mut entries_bytes = (entries_bytes + bytes.len() as u64).saturating_sub(replaced_bytes);
+    if entries_bytes > self.max_bytes {
+      entries_bytes = self.evict_oldest(entries_bytes, &path)?;
+    }
+    inner.entries_bytes = Some(entries_bytes);
+    Ok(())
+  }
+
+  ///
+  /// Measures the total size of the entries, and deletes any entries which a previous process
+  /// failed to finish evicting.
+  ///
+  fn measure_entries(&self) -> Result<u64, String> {
+    if let Ok(dir_entries) = ::std::fs::read_dir(&self.dir) {
+      for dir_entry in dir_entries.filter_map(|dir_entry| dir_entry.ok()) {
+        if dir_entry.file_name().to_string_lossy().starts_with(EVICTED_PREFIX) {
+          let _ = ::std::fs::remove_dir_all(dir_entry.path());
+        }
+      }
+    }
+    Ok(
+      self
+        .list_entries()?
+        .iter()
+        .map(|&(_, _, len)| len)
+        .sum(),
+    )
+  }
+
+  ///
+  /// Deletes the oldest entries (other than the given entry, which was just stored) until the
+  /// total size of the entries is within the bound. Returns the new total size.
+  ///
+  fn evict_oldest(&self, mut entries_bytes: u64, keep: &Path) -> Result<u64, String> {
+    let keep = self.dir.join(keep);
+    let mut entries = self.list_entries()?;
+    entries.sort();
+    for (_, path, len) in entries {
+      if entries_bytes <= self.max_bytes {
+        break;
+      }
+      if path == keep {
+        continue;
+      }
+      match ::std::fs::remove_file(&path) {
+        Ok(()) => entries_bytes = entries_bytes.saturating_sub(len),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+          entries_bytes = entries_bytes.saturating_sub(len)
+        }
+        Err(e) => return Err(format!("Failed to evict {}: {}", path.display(), e)),
+      }
+    }
+    Ok(entries_bytes)
+  }
+
+  ///
+  /// Lists the modification time, path and size of each entry.
+  ///
+  fn list_entries(&self) -> Result<Vec<(SystemTime, PathBuf, u64)>, String> {
+    let dir = self.dir.join(ENTRIES_DIR);
+    let dir_entries = match ::std::fs::read_dir(&dir) {
+      Ok(dir_entries) => dir_entries,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(format!("Failed to list {}: {}", dir.display(), e)),
+    };
+    let mut entries = Vec::new();
+    for dir_entry in dir_entries {
+      let entry = dir_entry.and_then(|dir_entry| {
+        let metadata = dir_entry.metadata()?;
+        Ok((metadata.modified()?, dir_entry.path(), metadata.len()))
+      });
+      match entry {
+        Ok(entry) => entries.push(entry),
+        // The entry was concurrently evicted.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to list {}: {}", dir.display(), e)),
+      }
+    }
+    Ok(entries)
+  }
+
+  fn decode(&self, bytes: &[u8]) -> Result<Option<fs::Snapshot>, String> {
+    // Every field is terminated by a NUL, so the last item of the split is empty.
+    let mut fields = bytes.split(|b| *b == 0);
+    let mut next = || fields.next().ok_or_else(|| "Truncated entry".to_owned());
+    let fingerprint = Fingerprint::from_hex_string(&utf8(next()?)?)?;
+    let size_bytes = parse_usize(next()?)?;
+    let mut path_stats = Vec::new();
+    loop {
+      let kind = next()?;
+      if kind.is_empty() {
+        break;
+      }
+      let path = PathBuf::from(fs::os::os_string_from_bytes(next()?.to_vec()));
+      let stat_path = PathBuf::from(fs::os::os_string_from_bytes(next()?.to_vec()));
+      let path_stat = match kind {
+        b"d" => PathStat::dir(path, Dir(stat_path.into())),
+        b"f" => {
+          let is_executable = next()? == b"1";
+          let size = parse_usize(next()?)? as u64;
+          PathStat::file(
+            path,
+            File {
+              path: stat_path.into(),
+              is_executable,
+              size,
+            },
+          )
+        }
+        _ => return Err(format!("Unrecognized kind of PathStat: {:?}", kind)),
+      };
+      let expected_validator = next()?;
+      match self.validator(&path_stat) {
+        Ok(ref validator) if validator.as_bytes() == expected_validator => {}
+        _ => return Ok(None),
+      }
+      path_stats.push(path_stat);
+    }
+    Ok(Some(fs::Snapshot {
+      digest: Digest(fingerprint, size_bytes),
+      path_stats,
+    }))
+  }
+
+  ///
+  /// Describes the current state of the file or directory underlying the given PathStat: if it
+  /// changes, then the PathStat (or the content of the file) might have changed.
+  ///
+  fn validator(&self, path_stat: &PathStat) -> io::Result<String> {
+    let stat_path = match path_stat {
+      &PathStat::Dir { ref stat, .. } => stat.0.as_path(),
+      &PathStat::File { ref stat, .. } => stat.path.as_path(),
+    };
+    let metadata = ::std::fs::metadata(self.build_root.join(stat_path))?;
+    let mtime = metadata
+      .modified()?
+      .duration_since(UNIX_EPOCH)
+      .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    if let &PathStat::File { ref stat, .. } = path_stat {
+      // The file was modified after it was captured.
+      if metadata.len() != stat.size {
+        return Err(io::Error::new(
+          io::ErrorKind::Other,
+          "file size changed since it was captured",
+        ));
+      }
+    }
+    Ok(format!(
+      "{}:{}:{}:{}:{}.{:09}",
+      metadata.is_dir(),
+      metadata.len(),
+      metadata.permissions().readonly(),
+      fs::os::is_executable(&metadata),
+      mtime.as_secs(),
+      mtime.subsec_nanos()
+    ))
+  }
+
+  fn entry_path(&self, fingerprint: Fingerprint) -> PathBuf {
+    Path::new(ENTRIES_DIR).join(fingerprint.to_hex())
+  }
+
+  ///
+  /// Writes a file in the cache directory atomically, so that concurrent readers never observe a
+  /// partial file.
+  ///
+  fn write(&self, relative_path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let path = self.dir.join(relative_path);
+    let dir = path.parent().unwrap_or(&self.dir).to_owned();
+    ::std::fs::create_dir_all(&dir)
+      .and_then(|()| tempfile::NamedTempFile::new_in(&dir))
+      .and_then(|mut file| {
+        file.write_all(bytes)?;
+        file.persist(&path).map_err(|e| e.error)
+      })
+      .map(|_| ())
+      .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+  }
+}
+
+fn fingerprint_fields(fields: &[&[u8]]) -> Fingerprint {
+  let mut bytes = Vec::new();
+  for field in fields {
+    push_field(&mut bytes, field);
+  }
+  let mut hasher = WriterHasher::new(io::sink());
+  hasher.write_all(&bytes).expect("Writing to a sink cannot fail");
+  hasher.finish()
+}
+
+fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+  bytes.extend_from_slice(field);
+  bytes.push(0);
+}
+
+fn utf8(field: &[u8]) -> Result<String, String> {
+  String::from_utf8(field.to_vec()).map_err(|e| format!("Invalid field {:?}: {}", field, e))
+}
+
+fn parse_usize(field: &[u8]) -> Result<usize, String> {
+  utf8(field)?
+    .parse()
+    .map_err(|e| format!("Invalid number {:?}: {}", field, e))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{SnapshotCache, ENTRIES_DIR};
+  use fs::{Snapshot, EMPTY_DIGEST};
+  use hashing::Fingerprint;
+  use std::path::Path;
+  use tempfile;
+
+  fn cache(dir: &Path, max_bytes: u64) -> SnapshotCache {
+    let mut cache = SnapshotCache::new(dir.join("cache"), dir, &[]);
+    cache.max_bytes = max_bytes;
+    cache.set_generation("1".to_owned());
+    cache
+  }
+
+  fn store(cache: &SnapshotCache, include: &str) {
+    let snapshot = Snapshot {
+      digest: EMPTY_DIGEST,
+      path_stats: vec![],
+    };
+    cache.store(fingerprint(cache, include), &snapshot).unwrap();
+  }
+
+  fn fingerprint(cache: &SnapshotCache, include: &str) -> Fingerprint {
+    cache.fingerprint("1", &[include.to_owned()], &[], "ignore")
+  }
+
+  fn entries_bytes(cache: &SnapshotCache) -> Option<u64> {
+    cache.inner.lock().unwrap().entries_bytes
+  }
+
+  #[test]
+  fn store_evicts_oldest_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let entry_bytes = {
+      let cache = cache(dir.path(), 1024);
+      store(&cache, "a");
+      entries_bytes(&cache).unwrap()
+    };
+    // Room for exactly two entries, including the one stored above.
+    let cache = cache(dir.path(), 2 * entry_bytes);
+    store(&cache, "b");
+    store(&cache, "c");
+
+    assert_eq!(cache.list_entries().unwrap().len(), 2);
+    assert_eq!(entries_bytes(&cache), Some(2 * entry_bytes));
+    assert!(cache.load(fingerprint(&cache, "c")).is_some());
+  }
+
+  #[test]
+  fn store_keeps_the_stored_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = cache(dir.path(), 0);
+    store(&cache, "a");
+    store(&cache, "b");
+
+    assert!(cache.load(fingerprint(&cache, "a")).is_none());
+    assert!(cache.load(fingerprint(&cache, "b")).is_some());
+  }
+
+  #[test]
+  fn invalidate_evicts_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = cache(dir.path(), 1024);
+    store(&cache, "a");
+
+    cache.invalidate();
+
+    assert!(cache.load(fingerprint(&cache, "a")).is_none());
+    assert!(!dir.path().join("cache").join(ENTRIES_DIR).exists());
+    assert_eq!(entries_bytes(&cache), Some(0));
+  }
+}
@@ -2,9 +2,11 @@
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use core::{Function, Key, TypeConstraint, TypeId, Value, FNV};
 use externs;
+use plugins::Plugins;
 use selectors::{Get, Select};
 use types::Types;
 
@@ -15,19 +17,42 @@ pub struct Task {
   pub gets: Vec<Get>,
   pub func: Function,
   pub cacheable: bool,
+  /// True if the Task provides its product independent of any subject: see `Provider`.
+  pub provider: bool,
+  /// A human-readable name for the Task, used when rendering it.
+  pub name: String,
+  /// An optional description of what the Task computes.
+  pub description: Option<String>,
 }
 
 ///
-/// Registry of native (rust) Intrinsic tasks, user (python) Tasks, and Singletons.
+/// The only source of a product which is independent of any subject: either a value which is
+/// fixed when it is registered (a singleton), or a Task (declared as a provider) which computes it
+/// inside the graph, lazily and at most once per Session. A provider Task may depend on other
+/// products, and is invalidated along with them, but its Selects may only be satisfied by other
+/// providers.
+///
+#[derive(Clone, Debug)]
+pub enum Provider {
+  Singleton(Key, Value),
+  Task(Task),
+}
+
+///
+/// Registry of native (rust) Intrinsic tasks, user (python) Tasks, and Providers.
 ///
 #[derive(Clone)]
 pub struct Tasks {
-  // output product type -> Intrinsic providing it
-  intrinsics: HashMap<TypeConstraint, Intrinsic, FNV>,
-  // Singleton Values to be returned for a given TypeConstraint.
-  singletons: HashMap<TypeConstraint, (Key, Value), FNV>,
+  // output product type -> list of Intrinsics providing it (from different input types)
+  intrinsics: HashMap<TypeConstraint, Vec<Intrinsic>, FNV>,
+  // The Provider (if any) of a given TypeConstraint, which is then its only source.
+  providers: HashMap<TypeConstraint, Provider, FNV>,
   // output product type -> list of tasks providing it
   tasks: HashMap<TypeConstraint, Vec<Task>, FNV>,
+  // Intrinsics implemented by plugins, indexed by `IntrinsicKind::Plugin`.
+  plugin_intrinsics: Vec<PluginIntrinsic>,
+  // The loaded plugins which implement the plugin intrinsics: see `load_plugins`.
+  plugins: Plugins,
   // Used during the construction of the tasks map.
   preparing: Option<Task>,
 }
@@ -41,21 +66,27 @@ pub struct Tasks {
 /// Also has a one-shot method for adding a singleton (which has no Selects):
 ///   1. singleton_add()
 ///
+/// A singleton is a constant Provider. A product which is the same for every subject, but which
+/// must be computed (possibly from other products), is instead registered as a provider Task by
+/// passing `provider` to task_begin().
+///
 /// (This protocol was original defined in a Builder, but that complicated the C lifecycle.)
 ///
 impl Tasks {
   pub fn new() -> Tasks {
     Tasks {
       intrinsics: HashMap::default(),
-      singletons: HashMap::default(),
+      providers: HashMap::default(),
       tasks: HashMap::default(),
+      plugin_intrinsics: Vec::new(),
+      plugins: Plugins::default(),
       preparing: None,
     }
   }
 
   pub fn all_product_types(&self) -> HashSet<TypeConstraint> {
     self
-      .singletons
+      .providers
       .keys()
       .chain(self.tasks.keys())
       .chain(self.intrinsics.keys())
@@ -64,14 +95,35 @@ impl Tasks {
   }
 
   pub fn all_tasks(&self) -> Vec<&Task> {
-    self.tasks.values().flat_map(|tasks| tasks).collect()
+    self
+      .tasks
+      .values()
+      .flat_map(|tasks| tasks)
+      .chain(self.providers.values().filter_map(|provider| match provider {
+        &Provider::Task(ref task) => Some(task),
+        &Provider::Singleton(..) => None,
+      }))
+      .collect()
   }
 
-  pub fn gen_singleton(&self, product: &TypeConstraint) -> Option<&(Key, Value)> {
-    self.singletons.get(product)
+  pub fn gen_singleton(&self, product: &TypeConstraint) -> Option<(&Key, &Value)> {
+    match self.providers.get(product) {
+      Some(&Provider::Singleton(ref key, ref value)) => Some((key, value)),
+      _ => None,
+    }
   }
 
-  pub fn gen_intrinsic(&self, product: &TypeConstraint) -> Option<&Intrinsic> {
+  ///
+  /// Returns the provider Task for the given product, if it has one: see `Provider`.
+  ///
+  pub fn gen_provider(&self, product: &TypeConstraint) -> Option<&Task> {
+    match self.providers.get(product) {
+      Some(&Provider::Task(ref task)) => Some(task),
+      _ => None,
+    }
+  }
+
+  pub fn gen_intrinsics(&self, product: &TypeConstraint) -> Option<&Vec<Intrinsic>> {
     self.intrinsics.get(product)
   }
 
@@ -79,8 +131,58 @@ impl Tasks {
     self.tasks.get(product)
   }
 
+  pub fn plugin_intrinsic(&self, index: usize) -> &PluginIntrinsic {
+    &self.plugin_intrinsics[index]
+  }
+
+  pub fn plugins(&self) -> &Plugins {
+    &self.plugins
+  }
+
+  ///
+  /// Binds the named plugin intrinsic (see `plugins::Plugins`) to a product, which is constructed
+  /// from the bytes that the intrinsic returns when it is run on the files of the input (which is
+  /// either a DirectoryDigest, or has a `directory_digest` field).
+  ///
+  pub fn plugin_intrinsic_add(
+    &mut self,
+    name: String,
+    product: TypeConstraint,
+    input: TypeConstraint,
+    constructor: Function,
+  ) {
+    self.plugin_intrinsics.push(PluginIntrinsic {
+      name,
+      product,
+      input,
+      constructor,
+    });
+  }
+
+  ///
+  /// Loads the given plugins, and validates that they provide all of the plugin intrinsics which
+  /// have been added. Called once, after all tasks have been added.
+  ///
+  pub fn load_plugins(&mut self, paths: &[PathBuf]) -> Result<(), String> {
+    let plugins = Plugins::load(paths)?;
+    let missing = self
+      .plugin_intrinsics
+      .iter()
+      .map(|plugin_intrinsic| plugin_intrinsic.name.as_str())
+      .filter(|name| plugins.intrinsic(name).is_none())
+      .collect::<Vec<_>>();
+    if !missing.is_empty() {
+      return Err(format!(
+        "No loaded plugin provides the intrinsic(s): {}",
+        missing.join(", ")
+      ));
+    }
+    self.plugins = plugins;
+    Ok(())
+  }
+
   pub fn intrinsics_set(&mut self, types: &Types) {
-    self.intrinsics = vec![
+    let intrinsics = vec![
       Intrinsic {
         kind: IntrinsicKind::Snapshot,
         product: types.snapshot,
@@ -96,38 +198,119 @@ impl Tasks {
         product: types.process_result,
         input: types.process_request,
       },
-    ].into_iter()
-      .map(|i| (i.product, i))
-      .collect();
+      Intrinsic {
+        kind: IntrinsicKind::ReadFileRange,
+        product: types.file_content,
+        input: types.file_range,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::FetchBinaryTool,
+        product: types.snapshot,
+        input: types.binary_tool_to_fetch,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::SnapshotAtRevision,
+        product: types.snapshot,
+        input: types.path_globs_at_revision,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::AddressNames,
+        product: types.address_names,
+        input: types.address_names_request,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::SourceImports,
+        product: types.source_imports,
+        input: types.source_imports_request,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::FileSizeSummary,
+        product: types.file_size_summary,
+        input: types.path_globs,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::ChangedFiles,
+        product: types.changed_files,
+        input: types.changed_files_request,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::Environment,
+        product: types.environment,
+        input: types.environment_request,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::Checksums,
+        product: types.snapshot,
+        input: types.checksums_request,
+      },
+      Intrinsic {
+        kind: IntrinsicKind::DigestEquality,
+        product: types.digest_equality,
+        input: types.digest_equality_request,
+      },
+    ];
+    let plugin_intrinsics = self
+      .plugin_intrinsics
+      .iter()
+      .enumerate()
+      .map(|(index, plugin_intrinsic)| Intrinsic {
+        kind: IntrinsicKind::Plugin(index),
+        product: plugin_intrinsic.product,
+        input: plugin_intrinsic.input,
+      })
+      .collect::<Vec<_>>();
+    self.intrinsics = HashMap::default();
+    for intrinsic in intrinsics.into_iter().chain(plugin_intrinsics) {
+      self
+        .intrinsics
+        .entry(intrinsic.product)
+        .or_insert_with(Vec::new)
+        .push(intrinsic);
+    }
   }
 
   pub fn singleton_add(&mut self, value: Value, product: TypeConstraint) {
-    if let Some(&(_, ref existing_value)) = self.singletons.get(&product) {
+    let key = externs::key_for(value.clone());
+    self.provider_add(product, Provider::Singleton(key, value));
+  }
+
+  fn provider_add(&mut self, product: TypeConstraint, provider: Provider) {
+    if let Some(existing) = self.providers.get(&product) {
       panic!(
-        "More than one singleton rule was installed for the product {:?}: {:?} vs {:?}",
-        product, existing_value, value,
+        "More than one provider (singleton or provider rule) was installed for the product {:?}: \
+         {:?} vs {:?}",
+        product, existing, provider,
       );
     }
-    self
-      .singletons
-      .insert(product, (externs::key_for(value.clone()), value));
+    self.providers.insert(product, provider);
   }
 
   ///
   /// The following methods define the Task registration lifecycle.
   ///
-  pub fn task_begin(&mut self, func: Function, product: TypeConstraint) {
+  pub fn task_begin(
+    &mut self,
+    func: Function,
+    product: TypeConstraint,
+    cacheable: bool,
+    provider: bool,
+    name: String,
+    description: Option<String>,
+  ) {
     assert!(
       self.preparing.is_none(),
       "Must `end()` the previous task creation before beginning a new one!"
     );
 
     self.preparing = Some(Task {
-      cacheable: true,
+      cacheable: cacheable,
+      provider: provider,
       product: product,
       clause: Vec::new(),
       gets: Vec::new(),
       func: func,
+      name: name,
+      description: description,
     });
   }
 
@@ -161,6 +344,12 @@ impl Tasks {
       .preparing
       .take()
       .expect("Must `begin()` a task creation before ending it!");
+    task.clause.shrink_to_fit();
+    task.gets.shrink_to_fit();
+    if task.provider {
+      self.provider_add(task.product, Provider::Task(task));
+      return;
+    }
     let tasks = self.tasks.entry(task.product).or_insert_with(Vec::new);
     assert!(
       !tasks.contains(&task),
@@ -169,8 +358,6 @@ impl Tasks {
       task.product,
       tasks,
     );
-    task.clause.shrink_to_fit();
-    task.gets.shrink_to_fit();
     tasks.push(task);
   }
 }
@@ -187,4 +374,28 @@ pub enum IntrinsicKind {
   Snapshot,
   FilesContent,
   ProcessExecution,
+  ReadFileRange,
+  FetchBinaryTool,
+  ChangedFiles,
+  Environment,
+  SnapshotAtRevision,
+  AddressNames,
+  SourceImports,
+  FileSizeSummary,
+  Checksums,
+  DigestEquality,
+  // An index into the plugin intrinsics of the Tasks.
+  Plugin(usize),
+}
+
+///
+/// An intrinsic implemented by a plugin, which computes its product from the Directory of its
+/// input.
+///
+#[derive(Clone, Debug)]
+pub struct PluginIntrinsic {
+  pub name: String,
+  pub product: TypeConstraint,
+  pub input: TypeConstraint,
+  pub constructor: Function,
 }
@@ -10,13 +10,38 @@ pub struct Types {
   pub construct_file: Function,
   pub construct_link: Function,
   pub construct_process_result: Function,
+  pub construct_lazy_bytes: Function,
+  pub construct_changed_files: Function,
+  pub construct_address_names: Function,
+  pub construct_source_imports: Function,
+  pub construct_file_size_summary: Function,
+  pub construct_environment: Function,
+  pub construct_digest_equality: Function,
+  pub construct_platform: Function,
   pub address: TypeConstraint,
   pub has_products: TypeConstraint,
   pub has_variants: TypeConstraint,
   pub path_globs: TypeConstraint,
   pub directory_digest: TypeConstraint,
   pub snapshot: TypeConstraint,
+  pub file_content: TypeConstraint,
   pub files_content: TypeConstraint,
+  pub file_range: TypeConstraint,
+  pub binary_tool_to_fetch: TypeConstraint,
+  pub changed_files_request: TypeConstraint,
+  pub changed_files: TypeConstraint,
+  pub path_globs_at_revision: TypeConstraint,
+  pub address_names_request: TypeConstraint,
+  pub address_names: TypeConstraint,
+  pub source_imports_request: TypeConstraint,
+  pub source_imports: TypeConstraint,
+  pub file_size_summary: TypeConstraint,
+  pub environment_request: TypeConstraint,
+  pub environment: TypeConstraint,
+  pub checksums_request: TypeConstraint,
+  pub digest_equality_request: TypeConstraint,
+  pub digest_equality: TypeConstraint,
+  pub platform: TypeConstraint,
   pub dir: TypeConstraint,
   pub file: TypeConstraint,
   pub link: TypeConstraint,
@@ -26,3 +51,124 @@ pub struct Types {
   pub string: TypeId,
   pub bytes: TypeId,
 }
+
+///
+/// A kind of value that the engine passes as a field to the constructor of an intrinsic product.
+///
+#[derive(Clone, Copy, Debug)]
+pub enum FieldKind {
+  Bytes,
+  Utf8,
+  Int,
+  Bool,
+  Tuple,
+  // A value created by one of the named constructors.
+  Product(&'static [&'static str]),
+}
+
+impl FieldKind {
+  pub fn name(&self) -> &'static str {
+    match self {
+      &FieldKind::Bytes => "bytes",
+      &FieldKind::Utf8 => "utf8",
+      &FieldKind::Int => "int",
+      &FieldKind::Bool => "bool",
+      &FieldKind::Tuple => "tuple",
+      &FieldKind::Product(_) => "product",
+    }
+  }
+}
+
+///
+/// The fields that the engine passes positionally to each constructor of an intrinsic product, in
+/// order, which must be kept in sync with the `store_*` methods in nodes.rs.
+///
+/// These are checked against the types that are passed as constructors when a Scheduler is
+/// created (see `Native.new_scheduler`), so that a change on either side of the FFI which is not
+/// made on the other fails fast, rather than constructing corrupt products.
+///
+pub const CONSTRUCTOR_SCHEMAS: &[(&str, &[(&str, FieldKind)])] = &[
+  (
+    "construct_directory_digest",
+    &[
+      ("fingerprint", FieldKind::Utf8),
+      ("serialized_bytes_length", FieldKind::Int),
+    ],
+  ),
+  (
+    "construct_snapshot",
+    &[
+      (
+        "directory_digest",
+        FieldKind::Product(&["construct_directory_digest"]),
+      ),
+      ("path_stats", FieldKind::Tuple),
+    ],
+  ),
+  (
+    "construct_file_content",
+    &[("path", FieldKind::Bytes), ("content", FieldKind::Bytes)],
+  ),
+  ("construct_files_content", &[("dependencies", FieldKind::Tuple)]),
+  (
+    "construct_path_stat",
+    &[
+      ("path", FieldKind::Bytes),
+      (
+        "stat",
+        FieldKind::Product(&["construct_dir", "construct_file"]),
+      ),
+    ],
+  ),
+  ("construct_dir", &[("path", FieldKind::Bytes)]),
+  (
+    "construct_file",
+    &[("path", FieldKind::Bytes), ("size", FieldKind::Int)],
+  ),
+  ("construct_link", &[("path", FieldKind::Bytes)]),
+  (
+    "construct_process_result",
+    &[
+      ("stdout", FieldKind::Product(&["construct_lazy_bytes"])),
+      ("stderr", FieldKind::Product(&["construct_lazy_bytes"])),
+      ("exit_code", FieldKind::Int),
+      (
+        "output_directory_digest",
+        FieldKind::Product(&["construct_directory_digest"]),
+      ),
+      ("stdout_overflowed", FieldKind::Bool),
+      ("stderr_overflowed", FieldKind::Bool),
+      ("source", FieldKind::Utf8),
+    ],
+  ),
+  (
+    "construct_lazy_bytes",
+    &[("hash", FieldKind::Utf8), ("size_bytes", FieldKind::Int)],
+  ),
+  ("construct_changed_files", &[("paths", FieldKind::Tuple)]),
+  (
+    "construct_address_names",
+    &[
+      ("namespace", FieldKind::Bytes),
+      ("names_by_path", FieldKind::Tuple),
+    ],
+  ),
+  (
+    "construct_source_imports",
+    &[("imports_by_path", FieldKind::Tuple)],
+  ),
+  (
+    "construct_file_size_summary",
+    &[("directories", FieldKind::Tuple)],
+  ),
+  ("construct_environment", &[("entries", FieldKind::Tuple)]),
+  ("construct_digest_equality", &[("equal", FieldKind::Bool)]),
+  (
+    "construct_platform",
+    &[
+      ("os", FieldKind::Utf8),
+      ("arch", FieldKind::Utf8),
+      ("libc", FieldKind::Utf8),
+    ],
+  ),
+];
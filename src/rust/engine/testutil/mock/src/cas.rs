@@ -1,3 +1,4 @@
+use std::any::Any;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -17,6 +18,8 @@ use testutil::data::{TestData, TestDirectory};
 pub struct StubCAS {
   server_transport: grpcio::Server,
   read_request_count: Arc<Mutex<usize>>,
+  find_missing_blobs_request_count: Arc<Mutex<usize>>,
+  batch_read_blobs_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
   pub blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
 }
@@ -48,16 +51,52 @@ impl StubCAS {
   pub fn with_unverified_content(
     chunk_size_bytes: i64,
     blobs: HashMap<Fingerprint, Bytes>,
+  ) -> StubCAS {
+    StubCAS::new(chunk_size_bytes, blobs, false, false)
+  }
+
+  ///
+  /// A StubCAS with the given content, which fails each blob of batched read requests (although
+  /// it answers individual read requests for them).
+  ///
+  pub fn with_failing_batch_reads(
+    files: Vec<TestData>,
+    directories: Vec<TestDirectory>,
+  ) -> StubCAS {
+    let blobs = files
+      .into_iter()
+      .map(|file| (file.fingerprint(), file.bytes()))
+      .chain(
+        directories
+          .into_iter()
+          .map(|directory| (directory.fingerprint(), directory.bytes())),
+      )
+      .collect();
+    StubCAS::new(1024, blobs, false, true)
+  }
+
+  fn new(
+    chunk_size_bytes: i64,
+    blobs: HashMap<Fingerprint, Bytes>,
+    hang_reads: bool,
+    fail_batch_reads: bool,
   ) -> StubCAS {
     let env = Arc::new(grpcio::Environment::new(1));
     let read_request_count = Arc::new(Mutex::new(0));
+    let find_missing_blobs_request_count = Arc::new(Mutex::new(0));
+    let batch_read_blobs_request_count = Arc::new(Mutex::new(0));
     let write_message_sizes = Arc::new(Mutex::new(Vec::new()));
     let blobs = Arc::new(Mutex::new(blobs));
     let responder = StubCASResponder {
       chunk_size_bytes: chunk_size_bytes,
       blobs: blobs.clone(),
       read_request_count: read_request_count.clone(),
+      find_missing_blobs_request_count: find_missing_blobs_request_count.clone(),
+      batch_read_blobs_request_count: batch_read_blobs_request_count.clone(),
       write_message_sizes: write_message_sizes.clone(),
+      hang_reads: hang_reads,
+      fail_batch_reads: fail_batch_reads,
+      hung_reads: Arc::new(Mutex::new(Vec::new())),
     };
     let mut server_transport = grpcio::ServerBuilder::new(env)
       .register_service(bazel_protos::bytestream_grpc::create_byte_stream(
@@ -74,6 +113,8 @@ impl StubCAS {
     StubCAS {
       server_transport,
       read_request_count,
+      find_missing_blobs_request_count,
+      batch_read_blobs_request_count,
       write_message_sizes,
       blobs,
     }
@@ -95,6 +136,13 @@ impl StubCAS {
     StubCAS::with_unverified_content(-1, HashMap::new())
   }
 
+  ///
+  /// A StubCAS which accepts read requests but never responds to them, as a hung server would.
+  ///
+  pub fn hanging() -> StubCAS {
+    StubCAS::new(1024, HashMap::new(), true, false)
+  }
+
   ///
   /// The address on which this server is listening over insecure HTTP transport.
   ///
@@ -106,6 +154,14 @@ impl StubCAS {
   pub fn read_request_count(&self) -> usize {
     self.read_request_count.lock().unwrap().clone()
   }
+
+  pub fn find_missing_blobs_request_count(&self) -> usize {
+    self.find_missing_blobs_request_count.lock().unwrap().clone()
+  }
+
+  pub fn batch_read_blobs_request_count(&self) -> usize {
+    self.batch_read_blobs_request_count.lock().unwrap().clone()
+  }
 }
 
 #[derive(Clone, Debug)]
@@ -113,7 +169,14 @@ pub struct StubCASResponder {
   chunk_size_bytes: i64,
   blobs: Arc<Mutex<HashMap<Fingerprint, Bytes>>>,
   pub read_request_count: Arc<Mutex<usize>>,
+  pub find_missing_blobs_request_count: Arc<Mutex<usize>>,
+  pub batch_read_blobs_request_count: Arc<Mutex<usize>>,
   pub write_message_sizes: Arc<Mutex<Vec<usize>>>,
+  hang_reads: bool,
+  fail_batch_reads: bool,
+  // The sinks of reads which are hanging, which are held (rather than dropped, which would fail
+  // the reads) until the server is dropped.
+  hung_reads: Arc<Mutex<Vec<Box<Any + Send>>>>,
 }
 
 impl StubCASResponder {
@@ -195,6 +258,10 @@ impl bazel_protos::bytestream_grpc::ByteStream for StubCASResponder {
       let mut request_count = self.read_request_count.lock().unwrap();
       *request_count = *request_count + 1;
     }
+    if self.hang_reads {
+      self.hung_reads.lock().unwrap().push(Box::new(sink));
+      return;
+    }
     match self.read_internal(&req) {
       Ok(response) => self.send(
         &ctx,
@@ -362,6 +429,10 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
     req: bazel_protos::remote_execution::FindMissingBlobsRequest,
     sink: grpcio::UnarySink<bazel_protos::remote_execution::FindMissingBlobsResponse>,
   ) {
+    {
+      let mut request_count = self.find_missing_blobs_request_count.lock().unwrap();
+      *request_count = *request_count + 1;
+    }
     if self.should_always_fail() {
       sink.fail(grpcio::RpcStatus::new(
         grpcio::RpcStatusCode::Internal,
@@ -392,6 +463,48 @@ impl bazel_protos::remote_execution_grpc::ContentAddressableStorage for StubCASR
       None,
     ));
   }
+  fn batch_read_blobs(
+    &self,
+    _ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::BatchReadBlobsRequest,
+    sink: grpcio::UnarySink<bazel_protos::remote_execution::BatchReadBlobsResponse>,
+  ) {
+    {
+      let mut request_count = self.batch_read_blobs_request_count.lock().unwrap();
+      *request_count = *request_count + 1;
+    }
+    if self.should_always_fail() {
+      sink.fail(grpcio::RpcStatus::new(
+        grpcio::RpcStatusCode::Internal,
+        Some("StubCAS is configured to always fail".to_owned()),
+      ));
+      return;
+    }
+    let blobs = self.blobs.lock().unwrap();
+    let mut response = bazel_protos::remote_execution::BatchReadBlobsResponse::new();
+    for digest in req.get_digests() {
+      let hashing_digest_result: Result<Digest, String> = digest.into();
+      let hashing_digest = hashing_digest_result.expect("Bad digest");
+      let mut blob_response =
+        bazel_protos::remote_execution::BatchReadBlobsResponse_Response::new();
+      blob_response.set_digest(digest.clone());
+      let mut status = bazel_protos::status::Status::new();
+      match blobs.get(&hashing_digest.0) {
+        Some(_) if self.fail_batch_reads => {
+          status.set_code(grpcio::RpcStatusCode::Unavailable as i32)
+        }
+        Some(bytes) => {
+          blob_response.set_data(bytes.clone());
+          status.set_code(grpcio::RpcStatusCode::Ok as i32);
+        }
+        None => status.set_code(grpcio::RpcStatusCode::NotFound as i32),
+      }
+      blob_response.set_status(status);
+      response.mut_responses().push(blob_response);
+    }
+    sink.success(response);
+  }
+
   fn get_tree(
     &self,
     _ctx: grpcio::RpcContext,
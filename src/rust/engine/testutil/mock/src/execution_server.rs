@@ -26,8 +26,8 @@ impl MockExecution {
   ///  * `name` - The name of the operation. It is assumed that all operation_responses use this
   ///             name.
   ///  * `execute_request` - The expected ExecuteRequest.
-  ///  * `operation_responses` - Vec of Operation response for Execution or GetOperation requests.
-  ///                            Will be returned in order.
+  ///  * `operation_responses` - Vec of Operation response for Execution, WaitExecution or
+  ///                            GetOperation requests. Will be returned in order.
   ///
   pub fn new(
     name: String,
@@ -43,8 +43,8 @@ impl MockExecution {
 }
 
 ///
-/// A server which will answer ExecuteRequest and GetOperation gRPC requests with pre-canned
-/// responses.
+/// A server which will answer ExecuteRequest, WaitExecution and GetOperation gRPC requests with
+/// pre-canned responses.
 ///
 pub struct TestServer {
   pub mock_responder: MockResponder,
@@ -55,13 +55,14 @@ impl TestServer {
   ///
   /// # Arguments
   /// * `mock_execution` - The canned responses to issue. Returns the MockExecution's
-  ///                      operation_responses in order to any ExecuteRequest or GetOperation
-  ///                      requests.
+  ///                      operation_responses in order to any ExecuteRequest, WaitExecution or
+  ///                      GetOperation requests.
   ///                      If an ExecuteRequest request is received which is not equal to this
   ///                      MockExecution's execute_request, an error will be returned.
-  ///                      If a GetOperation request is received whose name is not equal to this
-  ///                      MockExecution's name, or more requests are received than stub responses
-  ///                      are available for, an error will be returned.
+  ///                      If a WaitExecution request is received whose name is not equal to this
+  ///                      MockExecution's name, a NotFound error will be returned.
+  ///                      If more requests are received than stub responses are available for, an
+  ///                      error will be returned.
   pub fn new(mock_execution: MockExecution) -> TestServer {
     let mock_responder = MockResponder::new(mock_execution);
 
@@ -220,8 +221,8 @@ impl MockResponder {
 }
 
 impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
-  // We currently only support the one-shot "stream and disconnect" client behavior.
-  // If we start supporting the "stream updates" variant, we will need to do so here.
+  // Each stream is closed after sending a single Operation, so that a client must reconnect (using
+  // WaitExecution) to receive each subsequent Operation.
   fn execute(
     &self,
     ctx: grpcio::RpcContext,
@@ -247,11 +248,25 @@ impl bazel_protos::remote_execution_grpc::Execution for MockResponder {
 
   fn wait_execution(
     &self,
-    _ctx: grpcio::RpcContext,
-    _req: bazel_protos::remote_execution::WaitExecutionRequest,
-    _sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
+    ctx: grpcio::RpcContext,
+    req: bazel_protos::remote_execution::WaitExecutionRequest,
+    sink: grpcio::ServerStreamingSink<bazel_protos::operations::Operation>,
   ) {
-    unimplemented!()
+    self.log(req.clone());
+
+    if self.mock_execution.name != req.get_name() {
+      ctx.spawn(
+        sink
+          .fail(grpcio::RpcStatus::new(
+            grpcio::RpcStatusCode::NotFound,
+            Some(format!("Unknown operation {}", req.get_name())),
+          ))
+          .map_err(|_| ()),
+      );
+      return;
+    }
+
+    self.send_next_operation_stream(ctx, sink);
   }
 }
 
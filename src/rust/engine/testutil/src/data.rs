@@ -111,6 +111,20 @@ impl TestDirectory {
     TestDirectory { directory }
   }
 
+  // Directory structure:
+  //
+  // /cats/
+  pub fn containing_empty_dir() -> TestDirectory {
+    let mut directory = bazel_protos::remote_execution::Directory::new();
+    directory.mut_directories().push({
+      let mut subdir = bazel_protos::remote_execution::DirectoryNode::new();
+      subdir.set_name("cats".to_string());
+      subdir.set_digest((&TestDirectory::empty().digest()).into());
+      subdir
+    });
+    TestDirectory { directory }
+  }
+
   // Directory structure:
   //
   // /dnalor
@@ -27,11 +27,20 @@ class JavacCompileIntegration(BaseCompileIT):
         self.assert_success(pants_run)
 
   def test_basic_binary_hermetic(self):
+    self._assert_hermetic_compile({'execution_strategy': 'hermetic'})
+
+  def test_basic_binary_hermetic_without_replacement(self):
+    self._assert_hermetic_compile({
+      'execution_strategy': 'hermetic',
+      'hermetic_replace_classes_dir': False,
+    })
+
+  def _assert_hermetic_compile(self, javac_options):
     with temporary_dir() as cache_dir:
       config = {
         'cache.compile.javac': {'write_to': [cache_dir]},
         'jvm-platform': {'compiler': 'javac'},
-        'compile.javac': {'execution_strategy': 'hermetic'}
+        'compile.javac': javac_options,
       }
 
       with self.temporary_workdir() as workdir:
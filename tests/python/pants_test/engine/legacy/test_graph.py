@@ -156,6 +156,34 @@ class GraphInvalidationTest(GraphTestBase):
                            0,
                            'File {} did not invalidate any Nodes.'.format(filename))
 
+  def test_invalidate_prefixes(self):
+    with self.open_scheduler(['3rdparty/python::']) as (_, _, scheduler):
+      self.assertGreater(scheduler.invalidate_prefixes(['3rdparty']), 0)
+      # Prefixes match whole path components.
+      self.assertEquals(0, scheduler.invalidate_prefixes(['3rdparty/pyth']))
+
+  def test_invalidate_all(self):
+    with self.open_scheduler(['3rdparty/python::']) as (_, _, scheduler):
+      self.assertEquals(scheduler.node_count(), scheduler.invalidate_all())
+
+  def test_invalidation_metrics(self):
+    with self.open_scheduler(['3rdparty/python::']) as (_, _, scheduler):
+      invalidated_count = scheduler.invalidate_files(['3rdparty/python/BUILD'])
+      metrics = scheduler.metrics()
+      invalidated_by_type = {k: v for k, v in metrics.items() if k.startswith('invalidated_nodes.')}
+      self.assertIn('invalidated_nodes.Scandir', invalidated_by_type)
+      self.assertEquals(invalidated_count, sum(invalidated_by_type.values()))
+
+  def test_heap_usage(self):
+    with self.open_scheduler(['3rdparty/python::']) as (_, _, scheduler):
+      heap_usage = scheduler.heap_usage()
+      self.assertGreater(heap_usage['graph.entries'], 0)
+      self.assertGreater(heap_usage['graph.results.DirectoryListing'], 0)
+      self.assertGreater(heap_usage['interned.paths'], 0)
+      self.assertIn('store.clone_sources', heap_usage)
+      # The breakdown is also reported in the metrics of each run.
+      self.assertIn('heap_bytes.graph.entries', scheduler.metrics())
+
   def _ordering_test(self, spec, expected_sources=None):
     expected_sources = expected_sources or ['p', 'a', 'n', 't', 's', 'b', 'u', 'i', 'l', 'd']
     with self.open_scheduler([spec]) as (graph, _, _):
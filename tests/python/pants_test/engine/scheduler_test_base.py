@@ -47,7 +47,10 @@ class SchedulerTestBase(object):
                    rules=None,
                    project_tree=None,
                    work_dir=None,
-                   include_trace_on_error=True):
+                   include_trace_on_error=True,
+                   execution_options=None,
+                   fake_command_runner=False,
+                   background=False):
     """Creates a SchedulerSession for a Scheduler with the given Rules installed."""
     rules = rules or []
     work_dir = work_dir or self._create_work_dir()
@@ -56,9 +59,10 @@ class SchedulerTestBase(object):
                           project_tree,
                           work_dir,
                           rules,
-                          DEFAULT_EXECUTION_OPTIONS,
-                          include_trace_on_error=include_trace_on_error)
-    return scheduler.new_session()
+                          execution_options or DEFAULT_EXECUTION_OPTIONS,
+                          include_trace_on_error=include_trace_on_error,
+                          fake_command_runner=fake_command_runner)
+    return scheduler.new_session(background=background)
 
   def context_with_scheduler(self, scheduler, *args, **kwargs):
     return self.context(*args, scheduler=scheduler, **kwargs)
@@ -18,10 +18,13 @@ from pants.engine.build_files import (ResolvedTypeMismatchError, addresses_from_
 from pants.engine.fs import (DirectoryDigest, FileContent, FilesContent, Path, PathGlobs, Snapshot,
                              create_fs_rules)
 from pants.engine.legacy.structs import TargetAdaptor
-from pants.engine.mapper import AddressFamily, AddressMapper, ResolveError
+from pants.engine.mapper import (AddressFamily, AddressMapper, AddressNames, AddressNamesRequest,
+                                 ResolveError)
 from pants.engine.nodes import Return, Throw
 from pants.engine.parser import SymbolTable
+from pants.engine.rules import RootRule
 from pants.engine.struct import Struct, StructWithDeps
+from pants.util.dirutil import safe_file_dump
 from pants.util.objects import Exactly
 from pants_test.engine.examples.parsers import (JsonParser, PythonAssignmentsParser,
                                                 PythonCallbacksParser)
@@ -96,6 +99,57 @@ class AddressesFromAddressFamiliesTest(unittest.TestCase):
     self.assertEquals(targets.dependencies[0].spec, 'root:not_me')
 
 
+class AddressNamesTest(unittest.TestCase, SchedulerTestBase):
+  _symbols = ('java_library', 'jvm_binary', 'target')
+
+  def address_names(self, **build_files):
+    project_tree = self.mk_fs_tree()
+    for name, content in build_files.items():
+      safe_file_dump(os.path.join(project_tree.build_root, 'a', name), content)
+    scheduler = self.mk_scheduler(rules=create_fs_rules() + [RootRule(AddressNamesRequest)],
+                                  project_tree=project_tree)
+    snapshot = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                 PathGlobs(include=['a/BUILD*'])).value
+    request = AddressNamesRequest(snapshot.directory_digest, self._symbols)
+    return self.execute_expecting_one_result(scheduler, AddressNames, request).value
+
+  def test_address_names(self):
+    address_names = self.address_names(
+      BUILD="""
+# java_library(name='commented')
+java_library(
+  name = 'lib',
+  sources = globs('*.java', exclude=['''java_library(name="quoted")''']),
+  dependencies = [':bin'],
+)
+
+jvm_binary(name="bin", main=some.module.main(name='not-a-target'))
+""",
+      **{'BUILD.extra': "target(name='ex' 'tra')\n"})
+
+    self.assertEquals('a', address_names.namespace)
+    self.assertEquals((('a/BUILD', ('lib', 'bin')), ('a/BUILD.extra', ('extra',))),
+                      address_names.names_by_path)
+    self.assertEquals(['a:lib', 'a:bin', 'a:extra'],
+                      [a.spec for a in address_names.addresses])
+
+  def test_computed_name(self):
+    with self.assertRaises(Exception) as cm:
+      self.address_names(BUILD="for n in ['a', 'b']:\n  target(name=n)\n")
+    self.assertIn('names must be string literals', str(cm.exception))
+
+  def test_unknown_callable(self):
+    with self.assertRaises(Exception) as cm:
+      self.address_names(BUILD="target(name='a')\n\nmy_macro(name='b')\n")
+    self.assertIn('a/BUILD', str(cm.exception))
+    self.assertIn('Calls my_macro, which is not a known target type', str(cm.exception))
+
+  def test_duplicate_name(self):
+    with self.assertRaises(Exception) as cm:
+      self.address_names(BUILD="target(name='a')\n", **{'BUILD.extra': "target(name='a')\n"})
+    self.assertIn('A target with name "a" is already defined', str(cm.exception))
+
+
 class ApacheThriftConfiguration(StructWithDeps):
   # An example of a mixed-mode object - can be directly embedded without a name or else referenced
   # via address if both top-level and carrying a name.
@@ -9,8 +9,8 @@ import unittest
 from textwrap import dedent
 
 from pants.build_graph.address import Address
-from pants.engine.nodes import Return
-from pants.engine.rules import RootRule, TaskRule, rule
+from pants.engine.nodes import ErrorCategory, Return, Throw
+from pants.engine.rules import RootRule, SingletonRule, TaskRule, rule
 from pants.engine.selectors import Get, Select
 from pants.util.contextutil import temporary_dir
 from pants.util.objects import datatype
@@ -73,6 +73,18 @@ def nested_raise(x):
   fn_raises(x)
 
 
+def a_from_c(c):
+  return A()
+
+
+def d_from_b(b):
+  return D()
+
+
+def d_from_c(c):
+  return D()
+
+
 class Fib(datatype([('val', int)])): pass
 
 
@@ -84,6 +96,11 @@ def fib(n):
   yield Fib(x.val + y.val)
 
 
+def undeclared_get(b):
+  fib = yield Get(Fib, int(1))
+  yield A()
+
+
 class EngineTest(unittest.TestCase, SchedulerTestBase):
 
   assert_equal_with_printing = assert_equal_with_printing
@@ -102,6 +119,176 @@ class EngineTest(unittest.TestCase, SchedulerTestBase):
 
     self.assertEqual(55, fib_10.val)
 
+  def test_execute_streams_root_completions(self):
+    scheduler = self.mk_scheduler(rules=[fib, RootRule(int)])
+    request = scheduler.execution_request([Fib], [5, 10])
+
+    completed = []
+    result = scheduler.execute(request, on_root=lambda root, state: completed.append((root, state)))
+
+    self.assertEqual(2, len(completed))
+    self.assertEqual(set(result.root_products), set(completed))
+    self.assertIn(((10, Fib), Return(Fib(55))), completed)
+
+  def test_uncacheable_rule_runs_once_per_session(self):
+    calls = []
+
+    def count_calls(b):
+      calls.append(b)
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], count_calls, cacheable=False),
+    ]
+    session = self.mk_scheduler(rules=rules)
+    subject = B()
+
+    session.product_request(A, subjects=[subject])
+    session.product_request(A, subjects=[subject])
+    self.assertEqual(1, len(calls))
+
+    session._scheduler.new_session().product_request(A, subjects=[subject])
+    self.assertEqual(2, len(calls))
+
+  def test_background_session_does_not_reset_uncacheable_rules(self):
+    calls = []
+
+    def count_calls(b):
+      calls.append(b)
+      return A()
+
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], count_calls, cacheable=False),
+    ]
+    session = self.mk_scheduler(rules=rules)
+    subject = B()
+
+    session.product_request(A, subjects=[subject])
+    self.assertEqual(1, len(calls))
+
+    # A background session runs alongside the foreground session, so it shares its results.
+    session._scheduler.new_session(background=True).product_request(A, subjects=[subject])
+    session.product_request(A, subjects=[subject])
+    self.assertEqual(1, len(calls))
+
+  def test_equal_result_of_rerun_rule_does_not_rerun_dependents(self):
+    calls = []
+
+    def count_calls(fib):
+      calls.append(fib)
+      return A()
+
+    rules = [
+      RootRule(B),
+      # Each run produces a new Fib, which is equal to (but not identical to) the previous one.
+      TaskRule(Fib, [Select(B)], lambda b: Fib(1), cacheable=False),
+      TaskRule(A, [Select(Fib)], count_calls),
+    ]
+    session = self.mk_scheduler(rules=rules)
+    subject = B()
+
+    session.product_request(A, subjects=[subject])
+    self.assertEqual(1, len(calls))
+
+    # The uncacheable rule re-runs in a new session, but its result is unchanged.
+    session._scheduler.new_session().product_request(A, subjects=[subject])
+    self.assertEqual(1, len(calls))
+
+  def test_provider_rule_runs_once_per_session(self):
+    calls = []
+
+    def provide_c():
+      calls.append(None)
+      return C()
+
+    rules = [
+      RootRule(A),
+      RootRule(B),
+      TaskRule(C, [], provide_c, provider=True),
+      TaskRule(D, [Select(A), Select(C)], lambda a, c: D()),
+      TaskRule(D, [Select(B), Select(C)], lambda b, c: D()),
+    ]
+    session = self.mk_scheduler(rules=rules)
+
+    # The provider is shared between subjects of different types.
+    session.product_request(D, subjects=[A(), B()])
+    self.assertEqual(1, len(calls))
+
+    session._scheduler.new_session().product_request(D, subjects=[A()])
+    self.assertEqual(2, len(calls))
+
+  def test_provider_rule_selects_singletons(self):
+    rules = [
+      RootRule(B),
+      SingletonRule(A, A()),
+      TaskRule(C, [Select(A)], lambda a: C(), provider=True),
+      TaskRule(D, [Select(B), Select(C)], lambda b, c: D()),
+    ]
+    session = self.mk_scheduler(rules=rules)
+
+    d, = session.product_request(D, subjects=[B()])
+    self.assertIsInstance(d, D)
+
+  def test_provider_rule_cannot_select_subject_dependent_products(self):
+    # C can only be computed for a subject, so it is not shared with (or available to) providers.
+    rules = [
+      RootRule(B),
+      TaskRule(C, [Select(B)], lambda b: C()),
+      TaskRule(A, [Select(C)], lambda c: A(), provider=True),
+      TaskRule(D, [Select(B), Select(A)], lambda b, a: D()),
+    ]
+    with self.assertRaises(Exception) as cm:
+      self.mk_scheduler(rules=rules)
+    self.assertIn('Rules with errors', str(cm.exception))
+
+  def test_singleton_and_provider_for_a_product(self):
+    rules = [
+      RootRule(B),
+      SingletonRule(C, C()),
+      TaskRule(C, [], lambda: C(), provider=True),
+      TaskRule(D, [Select(B), Select(C)], lambda b, c: D()),
+    ]
+    with self.assertRaises(ValueError) as cm:
+      self.mk_scheduler(rules=rules)
+    self.assertIn('must be the only rule for its product', str(cm.exception))
+
+  def test_product_tuple_request(self):
+    calls = []
+
+    def c_from_b(b):
+      calls.append(b)
+      return C()
+
+    rules = [
+      RootRule(B),
+      TaskRule(C, [Select(B)], c_from_b),
+      TaskRule(A, [Select(C)], a_from_c),
+      TaskRule(D, [Select(C)], d_from_c),
+    ]
+    scheduler = self.mk_scheduler(rules=rules)
+
+    (a, d), = scheduler.product_tuple_request((A, D), subjects=[B()])
+
+    self.assertIsInstance(a, A)
+    self.assertIsInstance(d, D)
+    # The C that both products depend on is shared between them.
+    self.assertEqual(1, len(calls))
+
+  def test_product_tuple_request_fails_if_any_product_fails(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], nested_raise),
+      TaskRule(D, [Select(B)], d_from_b),
+    ]
+    scheduler = self.scheduler(rules, include_trace_on_error=False)
+
+    failed, = scheduler.product_tuple_request((D, A), subjects=[B()], keep_going=True)
+
+    self.assertIsInstance(failed, Throw)
+    self.assert_equal_with_printing('An exception for B', str(failed.exc))
+
   def test_no_include_trace_error_raises_boring_error(self):
     rules = [
       RootRule(B),
@@ -128,9 +315,80 @@ class EngineTest(unittest.TestCase, SchedulerTestBase):
 
     self.assert_equal_with_printing(dedent('''
       Multiple exceptions encountered:
+        Engine traceback:
+          in Select(<pants_test.engine.test_engine.B object at 0xEEEEEEEEE>, =A)
+          in Task(nested_raise, <pants_test.engine.test_engine.B object at 0xEEEEEEEEE>, =A)
         Exception: An exception for B
+        Engine traceback:
+          in Select(<pants_test.engine.test_engine.B object at 0xEEEEEEEEE>, =A)
+          in Task(nested_raise, <pants_test.engine.test_engine.B object at 0xEEEEEEEEE>, =A)
         Exception: An exception for B''').lstrip(),
-      str(cm.exception))
+      remove_locations_from_traceback(str(cm.exception)))
+
+  def test_trace_includes_task_description(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], nested_raise, description='Raising for a B'),
+    ]
+
+    scheduler = self.scheduler(rules, include_trace_on_error=False)
+
+    with self.assertRaises(Exception) as cm:
+      list(scheduler.product_request(A, subjects=[B(), B()]))
+
+    self.assertIn(
+      'in Task(nested_raise, <pants_test.engine.test_engine.B object at 0xEEEEEEEEE>, =A): '
+      'Raising for a B',
+      remove_locations_from_traceback(str(cm.exception)))
+
+  def test_keep_going_returns_per_root_failures(self):
+    rules = [
+      RootRule(B),
+      RootRule(C),
+      TaskRule(A, [Select(B)], nested_raise),
+      TaskRule(A, [Select(C)], a_from_c),
+    ]
+
+    scheduler = self.scheduler(rules, include_trace_on_error=True)
+
+    failed, succeeded = scheduler.product_request(A, subjects=[B(), C()], keep_going=True)
+
+    self.assertIsInstance(failed, Throw)
+    self.assert_equal_with_printing('An exception for B', str(failed.exc))
+    self.assertIsInstance(succeeded, A)
+
+  def test_throw_has_error_category(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], nested_raise),
+    ]
+
+    scheduler = self.scheduler(rules, include_trace_on_error=False)
+
+    failed, = scheduler.product_request(A, subjects=[B()], keep_going=True)
+
+    self.assertIsInstance(failed, Throw)
+    self.assertEquals(ErrorCategory.USER_RULE, failed.category)
+
+  def test_undeclared_get_fails_with_precise_error(self):
+    rules = [
+      fib,
+      RootRule(int),
+      RootRule(B),
+      TaskRule(A, [Select(B)], undeclared_get),
+    ]
+
+    scheduler = self.scheduler(rules, include_trace_on_error=False)
+
+    failed, = scheduler.product_request(A, subjects=[B()], keep_going=True)
+
+    self.assertIsInstance(failed, Throw)
+    self.assertEquals(ErrorCategory.USER_RULE, failed.category)
+    message = str(failed.exc)
+    self.assertIn('undeclared_get', message)
+    self.assertIn('requested Get(', message)
+    self.assertIn('it declared no Gets', message)
+    self.assertIn('fib)', message)
 
   def test_include_trace_error_raises_error_with_trace(self):
     rules = [
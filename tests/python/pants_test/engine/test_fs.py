@@ -4,22 +4,34 @@
 
 from __future__ import absolute_import, division, print_function, unicode_literals
 
+import hashlib
 import logging
 import os
+import socket
 import tarfile
+import time
 import unittest
 from builtins import str
 from contextlib import contextmanager
+from textwrap import dedent
 
 from future.utils import text_type
 
-from pants.base.project_tree import Dir, Link
-from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, DirectoryToMaterialize,
-                             FilesContent, PathGlobs, PathGlobsAndRoot, Snapshot, create_fs_rules)
+from pants.base.file_system_project_tree import FileSystemProjectTree
+from pants.base.project_tree import Dir, File, Link
+from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, BinaryToolToFetch, ChangedFiles,
+                             ChangedFilesRequest, ChecksumsRequest, Digest, DigestEquality,
+                             DigestEqualityRequest, DirectoryDigest, DirectoryToMaterialize,
+                             FileContent, FileRange, FileSizeSummary, FilesContent, PathGlobs,
+                             PathGlobsAndRoot, PathGlobsAtRevision, Snapshot, SourceImports,
+                             SourceImportsRequest, create_fs_rules)
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
 from pants.util.contextutil import temporary_dir
+from pants.util.dirutil import safe_file_dump
 from pants.util.meta import AbstractClass
 from pants_test.engine.scheduler_test_base import SchedulerTestBase
 from pants_test.test_base import TestBase
+from pants_test.testutils.git_util import initialize_repo
 
 
 class DirectoryListing(object):
@@ -127,6 +139,44 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
     self.assert_walk_files(['**/3.t*t'], ['a/3.txt', 'd.ln/3.txt'])
     self.assert_walk_files(['**/*.zzz'], [])
 
+  def test_snapshot_path_stats_sorted(self):
+    with self.mk_project_tree() as project_tree:
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      paths = ['d.ln/3.txt', '4.txt', 'a/b/1.txt', 'a/3.txt']
+      for globs in (paths, list(reversed(paths))):
+        snapshot = self.execute(scheduler, Snapshot, self.specs(globs))[0]
+        self.assertEquals(['4.txt', 'a/3.txt', 'a/b/1.txt', 'd.ln/3.txt'],
+                          [p.path for p in snapshot.path_stats])
+
+  def test_snapshot_file_sizes(self):
+    with self.mk_project_tree() as project_tree:
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      snapshot = self.execute(scheduler, Snapshot, self.specs(['a/b/*']))[0]
+      self.assertTrue(snapshot.file_stats)
+      for stat in snapshot.file_stats:
+        expected_size = os.path.getsize(os.path.join(project_tree.build_root, stat.path))
+        self.assertEquals(expected_size, stat.size)
+
+  def test_file_size_is_not_identity(self):
+    self.assertEquals(File('a/3.txt', 3), File('a/3.txt', 4))
+    self.assertEquals(hash(File('a/3.txt', 3)), hash(File('a/3.txt')))
+    self.assertNotEquals(File('a/3.txt', 3), File('a/4.txt', 3))
+
+  def test_file_size_summary(self):
+    with self.mk_project_tree(ignore_patterns=['/*.ln']) as project_tree:
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      summary = self.execute_expecting_one_result(scheduler, FileSizeSummary,
+                                                  self.specs(['**'])).value
+
+      def size(*paths):
+        return sum(os.path.getsize(os.path.join(project_tree.build_root, p)) for p in paths)
+
+      # Ignored files (beneath `c.ln` and `d.ln`) are not counted.
+      self.assertEquals((('', 5, size('4.txt', 'a/3.txt', 'a/4.txt.ln', 'a/b/1.txt', 'a/b/2')),
+                         ('a', 4, size('a/3.txt', 'a/4.txt.ln', 'a/b/1.txt', 'a/b/2')),
+                         ('a/b', 2, size('a/b/1.txt', 'a/b/2'))),
+                        summary.directories)
+
   def test_walk_single_star(self):
     self.assert_walk_files(['*'], ['4.txt'])
 
@@ -165,6 +215,12 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
                             'c.ln/1.txt',
                             'c.ln/2',],
                            ignore_patterns=['/*.ln', '!c.ln'])
+    # Ignore a directory, but whitelist a file within it.
+    self.assert_walk_files(['a/**'],
+                           ['a/3.txt',
+                            'a/4.txt.ln',
+                            'a/b/1.txt'],
+                           ignore_patterns=['/a/b/', '!a/b/1.txt'])
 
   def test_walk_recursive_trailing_doublestar(self):
     self.assert_walk_files(['a/**'], ['a/3.txt',
@@ -220,6 +276,26 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
   def test_files_content_symlink(self):
     self.assert_content(['c.ln/../3.txt'], {'c.ln/../3.txt': 'three\n'})
 
+  def assert_file_range(self, file_range, expected_content):
+    with self.mk_project_tree() as project_tree:
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      result = self.execute_expecting_one_result(scheduler, FileContent, file_range).value
+      self.assertEquals(FileContent(file_range.path, expected_content), result)
+
+  def test_file_range(self):
+    self.assert_file_range(FileRange('4.txt', 0, 2), b'fo')
+    self.assert_file_range(FileRange('4.txt', 2, 100), b'ur\n')
+    self.assert_file_range(FileRange('4.txt', 100, 1), b'')
+
+  def test_file_range_symlink(self):
+    self.assert_file_range(FileRange('a/4.txt.ln', 0, 4), b'four')
+
+  def test_file_range_missing(self):
+    with self.assertRaises(Exception):
+      self.assert_file_range(FileRange('missing.txt', 0, 4), b'')
+    with self.assertRaises(Exception):
+      self.assert_file_range(FileRange('a/b', 0, 4), b'')
+
   def test_files_digest_literal(self):
     self.assert_digest(['a/3.txt', '4.txt'], ['a/3.txt', '4.txt'])
 
@@ -312,6 +388,28 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
         scheduler.capture_snapshots((PathGlobsAndRoot(globs, text_type(os.path.join(temp_dir, "doesnotexist"))),))
       self.assertIn("doesnotexist", str(cm.exception))
 
+  def test_snapshot_from_outside_buildroot_relative_root(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())
+    globs = PathGlobs(("*",), ())
+    with self.assertRaises(Exception) as cm:
+      scheduler.capture_snapshots((PathGlobsAndRoot(globs, text_type("relative/root")),))
+    self.assertIn("absolute roots", str(cm.exception))
+
+  def test_snapshot_from_outside_buildroot_absolute_symlink(self):
+    with temporary_dir() as temp_dir:
+      temp_dir = os.path.realpath(temp_dir)
+      os.mkdir(os.path.join(temp_dir, "bin"))
+      with open(os.path.join(temp_dir, "bin", "roland"), "w") as f:
+        f.write("European Burmese")
+      os.symlink(os.path.join(temp_dir, "bin", "roland"), os.path.join(temp_dir, "roland"))
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      globs = PathGlobs(("roland",), ())
+      snapshot = scheduler.capture_snapshots((PathGlobsAndRoot(globs, text_type(temp_dir)),))[0]
+      self.assert_snapshot_equals(snapshot, ["roland"], DirectoryDigest(
+        text_type("63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16"),
+        80
+      ))
+
   def assert_snapshot_equals(self, snapshot, files, directory_digest):
     self.assertEquals([file.path for file in snapshot.files], files)
     self.assertEquals(snapshot.directory_digest, directory_digest)
@@ -359,6 +457,88 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
 
       self.assertEquals(both_snapshot.directory_digest, both_merged)
 
+  def test_merge_reapi_digests(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())
+    reapi_digest = EMPTY_DIRECTORY_DIGEST.digest
+    self.assertEquals(Digest(EMPTY_DIRECTORY_DIGEST.fingerprint, 0), reapi_digest)
+    self.assertEquals(EMPTY_DIRECTORY_DIGEST, DirectoryDigest.create(reapi_digest))
+    # Any value with REAPI `hash` and `size_bytes` fields is accepted as a digest.
+    self.assertEquals(EMPTY_DIRECTORY_DIGEST, scheduler.merge_directories((reapi_digest,)))
+
+  def test_merge_invalid_digest(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())
+    with self.assertRaisesRegexp(Exception, 'Invalid digest: hash .* was not 64 hex characters'):
+      scheduler.merge_directories((DirectoryDigest(text_type('not hex'), 0),))
+
+  def test_lease_and_release_digests(self):
+    with temporary_dir() as temp_dir:
+      with open(os.path.join(temp_dir, "roland"), "w") as f:
+        f.write("European Burmese")
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      (snapshot,) = scheduler.capture_snapshots((
+        PathGlobsAndRoot(PathGlobs(("roland",), ()), text_type(temp_dir)),
+      ))
+
+      scheduler.lease_digests((snapshot.directory_digest,), 60 * 60)
+      scheduler.release_digests((snapshot.directory_digest,))
+
+  def test_lease_unknown_digest(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())
+    unknown = DirectoryDigest(text_type('0' * 64), 80)
+    with self.assertRaisesRegexp(Exception, 'Unknown digest'):
+      scheduler.lease_digests((unknown,), 60)
+
+  def test_session_timeout_bounds_remote_store_calls(self):
+    # A remote store which accepts connections, but never responds to them.
+    server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    try:
+      server.bind(('127.0.0.1', 0))
+      server.listen(5)
+      scheduler = self.mk_scheduler(
+        rules=create_fs_rules(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+          remote_store_server='127.0.0.1:{}'.format(server.getsockname()[1]),
+          remote_store_chunk_upload_timeout_seconds=60,
+          remote_store_metadata_timeout_seconds=60,
+          remote_store_session_timeout_seconds=1,
+        ))
+      # The digest is not stored locally, so it is read from the remote store.
+      unknown = DirectoryDigest(text_type('0' * 64), 80)
+      start = time.time()
+      with self.assertRaisesRegexp(Exception, 'DeadlineExceeded'):
+        self.execute_expecting_one_result(scheduler, FilesContent, unknown)
+      self.assertLess(time.time() - start, 30)
+    finally:
+      server.close()
+
+  def test_session_timeout_failures_are_not_memoized_for_later_sessions(self):
+    # A remote store which accepts connections, but never responds to them.
+    server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    try:
+      server.bind(('127.0.0.1', 0))
+      server.listen(5)
+      session = self.mk_scheduler(
+        rules=create_fs_rules(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+          remote_store_server='127.0.0.1:{}'.format(server.getsockname()[1]),
+          remote_store_chunk_upload_timeout_seconds=60,
+          remote_store_metadata_timeout_seconds=3,
+          remote_store_session_timeout_seconds=1,
+        ))
+      unknown = DirectoryDigest(text_type('0' * 64), 80)
+      with self.assertRaisesRegexp(Exception, 'DeadlineExceeded'):
+        self.execute_expecting_one_result(session, FilesContent, unknown)
+
+      # A later session without a deadline re-runs the read rather than reusing the failure that
+      # the first session's deadline caused, so it waits for the (longer) RPC timeout.
+      start = time.time()
+      with self.assertRaises(Exception):
+        self.execute_expecting_one_result(
+          session._scheduler.new_session(timeout_seconds=0), FilesContent, unknown)
+      self.assertGreater(time.time() - start, 2)
+    finally:
+      server.close()
+
   def test_materialize_directories(self):
     # I tried passing in the digest of a file, but it didn't make it to the
     # rust code due to all of the checks we have in place (which is probably a good thing).
@@ -378,6 +558,386 @@ class FSTest(TestBase, SchedulerTestBase, AbstractClass):
         content = f.read()
         self.assertEquals(content, "European Burmese")
 
+  def test_materialize_directories_replace(self):
+    self.prime_store_with_roland_digest()
+
+    with temporary_dir() as temp_dir:
+      dir_path = os.path.join(temp_dir, "containing_roland")
+      os.mkdir(dir_path)
+      with open(os.path.join(dir_path, "stale"), 'w') as f:
+        f.write("stale")
+      digest = DirectoryDigest(
+        text_type("63949aa823baf765eff07b946050d76ec0033144c785a94d3ebd82baa931cd16"),
+        80
+      )
+      scheduler = self.mk_scheduler(rules=create_fs_rules())
+      scheduler.materialize_directories((DirectoryToMaterialize(text_type(dir_path), digest),),
+                                        replace=True)
+
+      self.assertEquals(["roland"], os.listdir(dir_path))
+      self.assertEquals(["containing_roland"], os.listdir(temp_dir))
+      with open(os.path.join(dir_path, "roland")) as f:
+        self.assertEquals("European Burmese", f.read())
+
+  def test_non_utf8_file_names(self):
+    name = b'caf\xe9.txt'
+    with self.mk_project_tree() as project_tree:
+      with open(os.path.join(project_tree.build_root.encode('utf-8'), name), 'wb') as f:
+        f.write(b'latin-1')
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      snapshot = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                   self.specs(['caf*'])).value
+      self.assertEquals([name], [f.path for f in snapshot.files])
+
+      files_content = self.execute_expecting_one_result(scheduler, FilesContent,
+                                                        snapshot.directory_digest).value
+      self.assertEquals([(name, b'latin-1')],
+                        [(f.path, f.content) for f in files_content.dependencies])
+
+      with temporary_dir() as temp_dir:
+        scheduler.materialize_directories(
+          (DirectoryToMaterialize(text_type(temp_dir), snapshot.directory_digest),))
+        with open(os.path.join(temp_dir.encode('utf-8'), name), 'rb') as f:
+          self.assertEquals(b'latin-1', f.read())
+
+  def test_special_files(self):
+    with self.mk_project_tree() as project_tree:
+      os.mkfifo(os.path.join(project_tree.build_root, 'a', 'fifo'))
+
+      scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+      snapshot = self.execute(scheduler, Snapshot, self.specs(['a/*']))[0]
+      self.assertEquals(['a/3.txt', 'a/4.txt.ln'], [f.path for f in snapshot.files])
+
+      scheduler = self.mk_scheduler(
+        rules=create_fs_rules(),
+        project_tree=project_tree,
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(special_file_behavior='error'))
+      with self.assertRaises(Exception) as cm:
+        self.execute(scheduler, Snapshot, self.specs(['a/*']))
+      self.assertIn('a/fifo', str(cm.exception))
+      self.assertIn('is a FIFO', str(cm.exception))
+
+  def test_escaping_links(self):
+    with self.mk_project_tree() as project_tree, temporary_dir() as outside_dir:
+      outside_file = os.path.join(os.path.realpath(outside_dir), 'host_file')
+      with open(outside_file, 'w') as f:
+        f.write('European Burmese')
+      os.symlink(outside_file, os.path.join(project_tree.build_root, 'a', 'escape'))
+
+      def snapshot_files(link_escape_behavior, spec='a/*'):
+        scheduler = self.mk_scheduler(
+          rules=create_fs_rules(),
+          project_tree=project_tree,
+          execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+            link_escape_behavior=link_escape_behavior))
+        snapshot = self.execute(scheduler, Snapshot, self.specs([spec]))[0]
+        return [f.path for f in snapshot.files]
+
+      self.assertEquals(['a/3.txt', 'a/4.txt.ln'], snapshot_files('warn'))
+      self.assertEquals(['a/3.txt', 'a/4.txt.ln', 'a/escape'], snapshot_files('follow'))
+      with self.assertRaises(Exception) as cm:
+        snapshot_files('error')
+      self.assertIn('a/escape', str(cm.exception))
+      self.assertIn('outside of the root', str(cm.exception))
+      # Globs which cannot match the link are unaffected by it.
+      self.assertEquals(['a/3.txt'], snapshot_files('error', 'a/*.txt'))
+
+  def fetch_binary_tool(self, path, content, urls, archive_type=None, size_bytes=None,
+                        timeout_secs=30):
+    with open(path, 'wb') as f:
+      f.write(content)
+    binary_tool_to_fetch = BinaryToolToFetch(
+      name='roland',
+      version='1.0',
+      platform='',
+      urls=urls,
+      fingerprint=hashlib.sha256(content).hexdigest(),
+      size_bytes=len(content) if size_bytes is None else size_bytes,
+      archive_type=archive_type,
+      timeout_secs=timeout_secs)
+    scheduler = self.mk_scheduler(rules=create_fs_rules())
+    return self.execute_expecting_one_result(scheduler, Snapshot, binary_tool_to_fetch).value
+
+  def test_fetch_binary_tool(self):
+    with temporary_dir() as temp_dir:
+      path = os.path.join(temp_dir, 'roland-1.0')
+      # The first url does not exist, so the second is used.
+      snapshot = self.fetch_binary_tool(path, b'European Burmese', [
+        'file://{}'.format(os.path.join(temp_dir, 'missing')),
+        'file://{}'.format(path),
+      ])
+      self.assertEquals(['roland'], [f.path for f in snapshot.files])
+
+  def test_fetch_binary_tool_wrong_digest(self):
+    with temporary_dir() as temp_dir:
+      path = os.path.join(temp_dir, 'roland-1.0')
+      with self.assertRaises(Exception) as cm:
+        self.fetch_binary_tool(path, b'European Burmese', ['file://{}'.format(path)], size_bytes=4)
+      self.assertIn('Failed to fetch roland 1.0 from any source', str(cm.exception))
+      self.assertIn('digest', str(cm.exception))
+
+  def test_fetch_binary_tool_timeout(self):
+    # A server which accepts connections, but never responds to them.
+    server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    try:
+      server.bind(('127.0.0.1', 0))
+      server.listen(5)
+      with temporary_dir() as temp_dir:
+        start = time.time()
+        with self.assertRaises(Exception) as cm:
+          self.fetch_binary_tool(os.path.join(temp_dir, 'roland-1.0'), b'European Burmese',
+                                 ['http://127.0.0.1:{}/roland'.format(server.getsockname()[1])],
+                                 timeout_secs=1)
+        self.assertIn('Failed to fetch roland 1.0 from any source', str(cm.exception))
+        self.assertLess(time.time() - start, 30)
+    finally:
+      server.close()
+
+  def test_session_timeout_failures_are_not_memoized_for_later_sessions(self):
+    # A remote store which accepts connections, but never responds to them.
+    server = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+    try:
+      server.bind(('127.0.0.1', 0))
+      server.listen(5)
+      session = self.mk_scheduler(
+        rules=create_fs_rules(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+          remote_store_server='127.0.0.1:{}'.format(server.getsockname()[1]),
+          remote_store_chunk_upload_timeout_seconds=60,
+          remote_store_metadata_timeout_seconds=3,
+          remote_store_session_timeout_seconds=1,
+        ))
+      unknown = DirectoryDigest(text_type('0' * 64), 80)
+      with self.assertRaisesRegexp(Exception, 'DeadlineExceeded'):
+        self.execute_expecting_one_result(session, FilesContent, unknown)
+
+      # A later session without a deadline re-runs the read rather than reusing the failure that
+      # the first session's deadline caused, so it waits for the (longer) RPC timeout.
+      start = time.time()
+      with self.assertRaises(Exception):
+        self.execute_expecting_one_result(
+          session._scheduler.new_session(timeout_seconds=0), FilesContent, unknown)
+      self.assertGreater(time.time() - start, 2)
+    finally:
+      server.close()
+
+  def test_fetch_binary_tool_tgz(self):
+    with temporary_dir() as temp_dir:
+      src_dir = os.path.join(temp_dir, 'src')
+      os.makedirs(os.path.join(src_dir, 'bin'))
+      with open(os.path.join(src_dir, 'bin', 'roland'), 'w') as f:
+        f.write('European Burmese')
+      os.symlink('bin/roland', os.path.join(src_dir, 'roland.ln'))
+      archive = os.path.join(temp_dir, 'roland.tgz')
+      with tarfile.open(archive, 'w:gz') as tar:
+        tar.add(src_dir, arcname='.')
+      with open(archive, 'rb') as f:
+        content = f.read()
+
+      snapshot = self.fetch_binary_tool(archive, content, ['file://{}'.format(archive)],
+                                        archive_type='tgz')
+      self.assertEquals(['bin/roland', 'roland.ln'], [f.path for f in snapshot.files])
+      self.assertEquals(['bin'], [d.path for d in snapshot.dirs])
+
+  def source_imports(self, language, **files):
+    project_tree = self.mk_fs_tree()
+    for name, content in files.items():
+      safe_file_dump(os.path.join(project_tree.build_root, name), content)
+    scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+    snapshot = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                 PathGlobs(include=['*'])).value
+    request = SourceImportsRequest(snapshot.directory_digest, text_type(language))
+    return self.execute_expecting_one_result(scheduler, SourceImports, request).value
+
+  def test_source_imports_python(self):
+    source_imports = self.source_imports(
+      'python',
+      **{
+        'a.py': 'import os\nfrom collections import OrderedDict\n',
+        'b.py': dedent("""\
+          # import commented
+          from __future__ import absolute_import
+          import os.path as p, sys
+
+          from . import sibling
+          from ..parent.module import (a,
+                                       b)
+
+          def f():
+            import pants.util.dirutil
+          """),
+      })
+    self.assertEquals((('a.py', ('os', 'collections')),
+                       ('b.py', ('__future__', 'os.path', 'sys', '.', '..parent.module',
+                                 'pants.util.dirutil'))),
+                      source_imports.imports_by_path)
+
+  def test_source_imports_jvm(self):
+    source_imports = self.source_imports(
+      'jvm',
+      **{
+        'A.java': dedent("""\
+          package org.pantsbuild;
+
+          import java.util.List;
+          import static org.junit.Assert.assertEquals;
+          import java.io.*;
+          """),
+        'B.scala': 'import scala.collection._\nimport org.pantsbuild.{A, B}\n',
+      })
+    self.assertEquals((('A.java', ('java.util.List', 'org.junit.Assert.assertEquals', 'java.io.*')),
+                       ('B.scala', ('scala.collection._', 'org.pantsbuild'))),
+                      source_imports.imports_by_path)
+
+  def test_source_imports_unknown_language(self):
+    with self.assertRaises(Exception) as cm:
+      self.source_imports('cobol', **{'A.cbl': ''})
+    self.assertIn('Unrecognized language: cobol', str(cm.exception))
+
+  def test_checksums(self):
+    project_tree = self.mk_fs_tree()
+    safe_file_dump(os.path.join(project_tree.build_root, 'roland'), 'European Burmese')
+    safe_file_dump(os.path.join(project_tree.build_root, 'cats', 'treats'), '')
+    scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+    snapshot = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                 PathGlobs(include=['**'])).value
+
+    checksums = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                  ChecksumsRequest(snapshot.directory_digest)).value
+    self.assertEquals(['SHA256SUMS'], [f.path for f in checksums.files])
+    files_content = self.execute_expecting_one_result(scheduler, FilesContent,
+                                                      checksums.directory_digest).value
+    self.assertEquals(
+      '{}  cats/treats\n{}  roland\n'.format(hashlib.sha256(b'').hexdigest(),
+                                            hashlib.sha256(b'European Burmese').hexdigest()),
+      files_content.dependencies[0].content.decode('utf-8'))
+
+  def test_digest_equality(self):
+    project_tree = self.mk_fs_tree()
+    safe_file_dump(os.path.join(project_tree.build_root, 'roland'), 'European Burmese')
+    scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+    globs = PathGlobs(include=['roland'])
+    digest = self.execute_expecting_one_result(scheduler, Snapshot, globs).value.directory_digest
+
+    def equal(actual):
+      request = DigestEqualityRequest(digest, actual)
+      return self.execute_expecting_one_result(scheduler, DigestEquality, request).value.equal
+
+    self.assertTrue(equal(digest))
+    self.assertFalse(equal(EMPTY_DIRECTORY_DIGEST))
+    self.assertTrue(equal(globs))
+    self.assertFalse(equal(PathGlobs(include=['missing'])))
+
+  def test_snapshot_cache(self):
+    project_tree = self.mk_fs_tree()
+    safe_file_dump(os.path.join(project_tree.build_root, 'a', '1.txt'), 'one')
+    safe_file_dump(os.path.join(project_tree.build_root, 'b', '2.py'), 'two')
+    with temporary_dir() as cache_dir:
+      execution_options = DEFAULT_EXECUTION_OPTIONS._replace(snapshot_cache_dir=cache_dir)
+
+      def restart(expected_persisted_generation, generation):
+        # Each Scheduler stands in for a restarted pantsd.
+        scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree,
+                                      execution_options=execution_options)
+        self.assertEquals(expected_persisted_generation,
+                          scheduler._scheduler.snapshot_cache_generation())
+        scheduler._scheduler.set_snapshot_cache_generation(generation)
+        return scheduler
+
+      def files(scheduler):
+        snapshot = self.execute_expecting_one_result(scheduler, Snapshot,
+                                                     PathGlobs(include=['**/*.txt'])).value
+        return [f.path for f in snapshot.files]
+
+      self.assertEquals(['a/1.txt'], files(restart(None, 'c:1')))
+
+      # A file is created in a directory which did not match: because the generation is unchanged,
+      # the persisted Snapshot is used.
+      safe_file_dump(os.path.join(project_tree.build_root, 'b', '3.txt'), 'three')
+      scheduler = restart('c:1', 'c:1')
+      self.assertEquals(['a/1.txt'], files(scheduler))
+
+      # Invalidating files disables the cache, and invalidates the restored Snapshot.
+      scheduler.invalidate_files(['b/3.txt'])
+      self.assertEquals(['a/1.txt', 'b/3.txt'], files(scheduler))
+
+      # A persisted Snapshot whose files have changed is not used.
+      scheduler = restart('c:1', 'c:2')
+      self.assertEquals(['a/1.txt', 'b/3.txt'], files(scheduler))
+      safe_file_dump(os.path.join(project_tree.build_root, 'a', '1.txt'), 'one again')
+      safe_file_dump(os.path.join(project_tree.build_root, 'b', '4.txt'), 'four')
+      self.assertEquals(['a/1.txt', 'b/3.txt', 'b/4.txt'], files(restart('c:2', 'c:2')))
+
+  def test_changed_files(self):
+    with temporary_dir() as worktree:
+      build_root = os.path.join(worktree, 'build_root')
+      safe_file_dump(os.path.join(worktree, '.gitignore'), 'ignored.txt\n')
+      safe_file_dump(os.path.join(worktree, 'outside.txt'), 'outside')
+      safe_file_dump(os.path.join(build_root, 'committed.txt'), 'committed')
+      safe_file_dump(os.path.join(build_root, 'deleted.txt'), 'deleted')
+      safe_file_dump(os.path.join(build_root, 'unchanged.txt'), 'unchanged')
+      with initialize_repo(worktree, gitdir=os.path.join(worktree, '.git')) as git:
+        base = git.commit_id
+        safe_file_dump(os.path.join(build_root, 'committed.txt'), 'committed again')
+        git.commit('Change a file.')
+
+        # Uncommitted changes, including untracked files (but not ignored files) are included.
+        os.unlink(os.path.join(build_root, 'deleted.txt'))
+        safe_file_dump(os.path.join(build_root, 'untracked', 'file.txt'), 'untracked')
+        safe_file_dump(os.path.join(build_root, 'ignored.txt'), 'ignored')
+        # But files outside of the buildroot are not.
+        safe_file_dump(os.path.join(worktree, 'outside.txt'), 'outside again')
+
+        project_tree = FileSystemProjectTree(build_root)
+        scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+        changed_files = self.execute_expecting_one_result(scheduler, ChangedFiles,
+                                                          ChangedFilesRequest(base)).value
+        self.assertEquals(('committed.txt', 'deleted.txt', 'untracked/file.txt'),
+                          changed_files.paths)
+
+        changed_files = self.execute_expecting_one_result(scheduler, ChangedFiles,
+                                                          ChangedFilesRequest('HEAD')).value
+        self.assertEquals(('deleted.txt', 'untracked/file.txt'), changed_files.paths)
+
+  def test_snapshot_at_revision(self):
+    with temporary_dir() as worktree:
+      build_root = os.path.join(worktree, 'build_root')
+      safe_file_dump(os.path.join(worktree, 'outside.txt'), 'outside')
+      safe_file_dump(os.path.join(build_root, 'a', 'roland.txt'), 'European Burmese')
+      safe_file_dump(os.path.join(build_root, 'a', 'deleted.txt'), 'deleted')
+      os.symlink('roland.txt', os.path.join(build_root, 'a', 'roland.ln'))
+      with initialize_repo(worktree, gitdir=os.path.join(worktree, '.git')) as git:
+        base = git.commit_id
+        # Neither committed nor uncommitted changes affect the Snapshot of the base revision.
+        safe_file_dump(os.path.join(build_root, 'a', 'roland.txt'), 'Abyssinian')
+        git.commit('Change a file.')
+        os.unlink(os.path.join(build_root, 'a', 'deleted.txt'))
+        safe_file_dump(os.path.join(build_root, 'a', 'untracked.txt'), 'untracked')
+
+        project_tree = FileSystemProjectTree(build_root)
+        scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+        path_globs = PathGlobsAtRevision(PathGlobs(include=['a/*']), base)
+        snapshot = self.execute_expecting_one_result(scheduler, Snapshot, path_globs).value
+        self.assertEquals(['a/deleted.txt', 'a/roland.ln', 'a/roland.txt'],
+                          [f.path for f in snapshot.files])
+
+        files_content = self.execute_expecting_one_result(scheduler, FilesContent,
+                                                          snapshot.directory_digest).value
+        self.assertEquals([('a/deleted.txt', b'deleted'),
+                           ('a/roland.ln', b'European Burmese'),
+                           ('a/roland.txt', b'European Burmese')],
+                          [(f.path, f.content) for f in files_content.dependencies])
+
+  def test_changed_files_unknown_revision(self):
+    with temporary_dir() as worktree:
+      safe_file_dump(os.path.join(worktree, 'file.txt'), 'file')
+      with initialize_repo(worktree, gitdir=os.path.join(worktree, '.git')):
+        project_tree = FileSystemProjectTree(worktree)
+        scheduler = self.mk_scheduler(rules=create_fs_rules(), project_tree=project_tree)
+        with self.assertRaises(Exception) as cm:
+          self.execute(scheduler, ChangedFiles, ChangedFilesRequest('not-a-revision'))
+        self.assertIn('Failed to resolve not-a-revision to a commit', str(cm.exception))
+
   def test_glob_match_error(self):
     with self.assertRaises(ValueError) as cm:
       self.assert_walk_files(PathGlobs(
@@ -6,18 +6,25 @@ from __future__ import absolute_import, division, print_function, unicode_litera
 
 import os
 import tarfile
+import threading
+import time
 import unittest
 
 from future.utils import text_type
 
 from pants.engine.fs import (EMPTY_DIRECTORY_DIGEST, DirectoryDigest, FileContent, FilesContent,
-                             PathGlobs, Snapshot, create_fs_rules)
-from pants.engine.isolated_process import (ExecuteProcessRequest, ExecuteProcessResult,
-                                           FallibleExecuteProcessResult, ProcessExecutionFailure,
+                             LazyBytes, PathGlobs, Snapshot, create_fs_rules)
+from pants.engine.isolated_process import (Environment, EnvironmentRequest,
+                                           ExecuteProcessRequest, ExecuteProcessRequestDigests,
+                                           ExecuteProcessResult, FallibleExecuteProcessResult,
+                                           Platform, ProcessExecutionFailure,
                                            create_process_rules)
 from pants.engine.rules import RootRule, rule
 from pants.engine.selectors import Get, Select
+from pants.option.global_options import DEFAULT_EXECUTION_OPTIONS
+from pants.util.contextutil import environment_as, temporary_dir
 from pants.util.objects import TypeCheckError, datatype
+from pants.util.osutil import get_normalized_os_name
 from pants_test.engine.scheduler_test_base import SchedulerTestBase
 
 
@@ -221,6 +228,12 @@ class ExecuteProcessRequestTest(SchedulerTestBase, unittest.TestCase):
         input_files='',
         output_files=(),
         output_directories=(),
+        immutable_input_directories=(),
+        max_output_bytes=0,
+        max_disk_bytes=0,
+        cache_failures=True,
+        container_image='',
+        execution_seed_env_var='',
         timeout_seconds=0.1,
         description=''
       )
@@ -230,6 +243,12 @@ class ExecuteProcessRequestTest(SchedulerTestBase, unittest.TestCase):
         input_files=3,
         output_files=(),
         output_directories=(),
+        immutable_input_directories=(),
+        max_output_bytes=0,
+        max_disk_bytes=0,
+        cache_failures=True,
+        container_image='',
+        execution_seed_env_var='',
         timeout_seconds=0.1,
         description=''
       )
@@ -240,6 +259,12 @@ class ExecuteProcessRequestTest(SchedulerTestBase, unittest.TestCase):
         input_files=EMPTY_DIRECTORY_DIGEST,
         output_files=("blah"),
         output_directories=(),
+        immutable_input_directories=(),
+        max_output_bytes=0,
+        max_disk_bytes=0,
+        cache_failures=True,
+        container_image='',
+        execution_seed_env_var='',
         timeout_seconds=0.1,
         description=''
       )
@@ -250,10 +275,68 @@ class ExecuteProcessRequestTest(SchedulerTestBase, unittest.TestCase):
         input_files=EMPTY_DIRECTORY_DIGEST,
         output_files=("blah"),
         output_directories=(),
+        immutable_input_directories=(),
+        max_output_bytes=0,
+        max_disk_bytes=0,
+        cache_failures=True,
+        container_image='',
+        execution_seed_env_var='',
         timeout_seconds=None,
         description=''
       )
 
+  def test_digests(self):
+    scheduler = self.mk_scheduler()
+
+    def digests(argv, description='echo', timeout_seconds=15, container_image=''):
+      return scheduler.execute_process_request_digests(
+        ExecuteProcessRequest.create_with_empty_snapshot(
+          argv=argv,
+          description=description,
+          output_files=('out',),
+          timeout_seconds=timeout_seconds,
+          container_image=container_image,
+        )
+      )
+
+    echo = digests(('/bin/echo', 'yo'))
+    self.assertIsInstance(echo, ExecuteProcessRequestDigests)
+    self.assertEquals(64, len(echo.action_digest.hash))
+    self.assertEquals(64, len(echo.command_digest.hash))
+    self.assertNotEquals(echo.action_digest, echo.command_digest)
+
+    # The description and timeout are not part of the cache key.
+    self.assertEquals(echo, digests(('/bin/echo', 'yo'), description='other', timeout_seconds=5))
+
+    # But the argv is.
+    echo_other = digests(('/bin/echo', 'other'))
+    self.assertNotEquals(echo.action_digest, echo_other.action_digest)
+    self.assertNotEquals(echo.command_digest, echo_other.command_digest)
+
+    # As is the container image.
+    echo_in_image = digests(('/bin/echo', 'yo'), container_image='docker://toolchain@sha256:abc')
+    self.assertNotEquals(echo.action_digest, echo_in_image.action_digest)
+
+  def test_digests_invalid_input_files(self):
+    scheduler = self.mk_scheduler()
+    request = ExecuteProcessRequest(
+      argv=('/bin/echo',),
+      env=(),
+      input_files=DirectoryDigest(text_type('abc'), 0),
+      output_files=(),
+      output_directories=(),
+      immutable_input_directories=(),
+      max_output_bytes=0,
+      max_disk_bytes=0,
+      cache_failures=True,
+      container_image='',
+      execution_seed_env_var='',
+      timeout_seconds=1,
+      description='',
+    )
+    with self.assertRaisesRegexp(Exception, 'Invalid digest: hash .*abc.* was not 64 hex'):
+      scheduler.execute_process_request_digests(request)
+
 
 class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
 
@@ -382,6 +465,151 @@ class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
     result = self.execute_expecting_one_result(scheduler, FallibleExecuteProcessResult, request).value
 
     self.assertEquals(result.exit_code, 1)
+    self.assertEquals(result.source, 'executed_locally')
+
+  def test_lazy_outputs(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      argv=("/bin/bash", "-c", "head -c 8192 /dev/zero; echo -n err >&2"),
+      description='lazy-outputs',
+    )
+
+    result = self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request).value
+
+    # Large outputs are held in the store until they are read.
+    self.assertIsInstance(result.stored_stdout, LazyBytes)
+    self.assertEquals(8192, result.stored_stdout.size_bytes)
+    self.assertEquals(b'\0' * 8192, result.stdout)
+    self.assertEquals(b'\0' * 8192, scheduler.load_bytes(result.stored_stdout))
+
+    with self.assertRaises(ValueError):
+      LazyBytes(result.stored_stdout.hash, 8192).read()
+
+    # But small outputs are not.
+    self.assertEquals(b'err', result.stored_stderr)
+    self.assertEquals(b'err', result.stderr)
+
+  def test_environment(self):
+    session = self.mk_scheduler_in_example_fs(())
+    request = EnvironmentRequest(['PANTS_TEST_ENVIRONMENT_SET', 'PANTS_TEST_ENVIRONMENT_UNSET'])
+
+    with environment_as(PANTS_TEST_ENVIRONMENT_SET='one'):
+      environment = self.execute_expecting_one_result(session, Environment, request).value
+    self.assertEquals(Environment([('PANTS_TEST_ENVIRONMENT_SET', 'one')]), environment)
+    self.assertEquals('one', environment.get('PANTS_TEST_ENVIRONMENT_SET'))
+    self.assertIsNone(environment.get('PANTS_TEST_ENVIRONMENT_UNSET'))
+
+    # The environment is re-read for each session.
+    with environment_as(PANTS_TEST_ENVIRONMENT_SET='two'):
+      session = session._scheduler.new_session()
+      environment = self.execute_expecting_one_result(session, Environment, request).value
+    self.assertEquals('two', environment.get('PANTS_TEST_ENVIRONMENT_SET'))
+
+  def test_max_output_bytes(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      argv=("/bin/bash", "-c", "echo -n European Burmese"),
+      description='truncated-cat',
+      max_output_bytes=8,
+    )
+
+    result = self.execute_expecting_one_result(scheduler, FallibleExecuteProcessResult, request).value
+
+    self.assertEquals(result.stdout, b'European\n[8 more bytes of output were truncated]\n')
+    self.assertTrue(result.stdout_overflowed)
+    self.assertFalse(result.stderr_overflowed)
+
+  def test_max_disk_bytes(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      argv=("/bin/bash", "-c", "head -c 2048 /dev/zero > out"),
+      description='oversized-write',
+      max_disk_bytes=1024,
+    )
+
+    with self.assertRaisesRegexp(Exception, 'exceeded its disk quota of 1024 bytes'):
+      self.execute_expecting_one_result(scheduler, FallibleExecuteProcessResult, request)
+
+  def test_execution_seed(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+
+    def seed(argv):
+      request = ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=("/bin/bash", "-c", "echo -n $SEED") + argv,
+        description='seed',
+        execution_seed_env_var='SEED',
+      )
+      result = self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request).value
+      return result.stdout
+
+    seed_one = seed(('one',))
+    self.assertTrue(seed_one.isdigit())
+    # The seed is stable for a given request, but differs between requests.
+    self.assertEquals(seed_one, seed(('one',)))
+    self.assertNotEquals(seed_one, seed(('two',)))
+
+  def test_cache_failures(self):
+    session = self.mk_scheduler_in_example_fs(())
+
+    with temporary_dir() as runs_dir:
+      def runs(exit_code):
+        # Each run appends to a file outside of the sandbox, so that reruns can be counted.
+        request = ExecuteProcessRequest.create_with_empty_snapshot(
+          argv=("/bin/bash", "-c", "echo >> {}/{}; exit {}".format(runs_dir, exit_code, exit_code)),
+          description='counted',
+          cache_failures=False,
+        )
+        for _ in range(2):
+          self.execute_expecting_one_result(
+            session._scheduler.new_session(), FallibleExecuteProcessResult, request)
+        with open(os.path.join(runs_dir, str(exit_code))) as f:
+          return len(f.readlines())
+
+      # Successful results are reused by the second session, but failures are not.
+      self.assertEquals(1, runs(0))
+      self.assertEquals(2, runs(1))
+
+  def test_engine_temp_dir(self):
+    with temporary_dir() as temp_dir:
+      scheduler = self.mk_scheduler(
+        rules=create_fs_rules() + create_process_rules(),
+        project_tree=self.mk_example_fs_tree(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(engine_temp_dir=temp_dir))
+
+      request = ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=("/bin/bash", "-c", "echo -n $PWD"),
+        description='pwd',
+      )
+      result = self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request).value
+
+      sandbox = result.stdout.decode('utf-8')
+      self.assertTrue(
+        os.path.realpath(sandbox).startswith(os.path.realpath(temp_dir)), sandbox)
+      self.assertFalse(os.path.exists(sandbox))
+
+  def test_workunit_events(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      argv=("/bin/bash", "-c", "echo -n hello; exit 3"),
+      description='noisy-exit',
+    )
+    self.execute_expecting_one_result(scheduler, FallibleExecuteProcessResult, request)
+
+    events, dropped = scheduler.workunit_events()
+    self.assertEquals(0, dropped)
+    self.assertEquals(['started', 'first_output', 'completed'], [e['kind'] for e in events])
+    self.assertEquals(1, len({e['id'] for e in events}))
+    self.assertEquals('noisy-exit', events[0]['description'])
+    self.assertEquals(EMPTY_DIRECTORY_DIGEST.fingerprint, events[0]['input_digest'][0])
+    self.assertEquals(3, events[2]['exit_code'])
+    self.assertEquals('executed_locally', events[2]['source'])
+    self.assertEquals(0, events[2]['disk_bytes_written'])
+    self.assertEquals(0, events[2]['cache_bytes_written'])
+    self.assertEquals(([], 0), scheduler.workunit_events())
 
   def test_non_fallible_failing_command_raises(self):
     scheduler = self.mk_scheduler_in_example_fs(())
@@ -395,6 +623,223 @@ class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
       self.execute_raising_throw(scheduler, ExecuteProcessResult, request)
     self.assertIn("process 'one-cat' failed with exit code 1.", str(cm.exception))
 
+  def test_record_and_replay(self):
+    def request(argv):
+      return ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=argv,
+        description='nondeterministic',
+        output_files=('out',),
+      )
+
+    recorded = request(('/bin/bash', '-c', 'echo -n $RANDOM$RANDOM | tee out'))
+    unrecorded = request(('/bin/bash', '-c', 'echo -n unrecorded | tee out'))
+
+    with temporary_dir() as archive_dir:
+      def execute(recorded_request, execution_options):
+        scheduler = self.mk_scheduler(
+          rules=create_fs_rules() + create_process_rules(),
+          project_tree=self.mk_example_fs_tree(),
+          execution_options=DEFAULT_EXECUTION_OPTIONS._replace(**execution_options))
+        result = self.execute_expecting_one_result(
+          scheduler, ExecuteProcessResult, recorded_request).value
+        files_content = self.execute_expecting_one_result(
+          scheduler, FilesContent, result.output_directory_digest).value
+        return result.stdout, files_content.dependencies, result.source
+
+      stdout, outputs, source = execute(recorded, dict(process_execution_record_dir=archive_dir))
+      self.assertEquals((FileContent('out', stdout),), outputs)
+      self.assertEquals('executed_locally', source)
+
+      # The replayed result is identical, despite the command being nondeterministic.
+      replay = dict(process_execution_replay_dir=archive_dir)
+      self.assertEquals((stdout, outputs, 'local_cache'), execute(recorded, replay))
+
+      with self.assertRaisesRegexp(Exception, 'does not contain a result for'):
+        execute(unrecorded, replay)
+
+  def test_explain_process_request_diff(self):
+    def request(env):
+      return ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=('/bin/bash', '-c', 'echo -n $LABEL'),
+        env=env,
+        description='labelled',
+      )
+
+    local = request({'LABEL': 'local'})
+    ci = request({'LABEL': 'ci', 'CI': 'true'})
+
+    with temporary_dir() as archive_dir:
+      scheduler = self.mk_scheduler(
+        rules=create_fs_rules() + create_process_rules(),
+        project_tree=self.mk_example_fs_tree(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+          process_execution_record_dir=archive_dir))
+      for req in (local, ci):
+        self.execute_expecting_one_result(scheduler, ExecuteProcessResult, req)
+
+      def fingerprint(req):
+        return scheduler.execute_process_request_digests(req).action_digest.hash
+
+      diff = scheduler.explain_process_request_diff(
+        archive_dir, fingerprint(local), archive_dir, fingerprint(ci))
+      self.assertIn('CI: (unset) -> "true"', diff)
+      self.assertIn('LABEL: "local" -> "ci"', diff)
+      self.assertNotIn('argv', diff)
+
+      with self.assertRaisesRegexp(Exception, 'does not contain a request'):
+        scheduler.explain_process_request_diff(
+          archive_dir, fingerprint(local), archive_dir, '0' * 64)
+
+  def test_read_only_remote_store_rejects_remote_execution(self):
+    with self.assertRaisesRegexp(ValueError, 'read-only remote store'):
+      self.mk_scheduler(
+        rules=create_fs_rules() + create_process_rules(),
+        project_tree=self.mk_example_fs_tree(),
+        execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+          remote_store_server='localhost:1',
+          remote_execution_server='localhost:2',
+          remote_store_write_through='never',
+        ))
+
+  def test_background_session(self):
+    # A background session is throttled to one process at a time, but otherwise executes normally.
+    scheduler = self.mk_scheduler(
+      rules=create_fs_rules() + create_process_rules(),
+      project_tree=self.mk_example_fs_tree(),
+      execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+        background_process_execution_parallelism=1,
+        background_io_parallelism=1,
+      ),
+      background=True)
+
+    requests = [
+      ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=('/bin/echo', '-n', str(i)),
+        description='echo {}'.format(i),
+      )
+      for i in range(4)
+    ]
+    result = scheduler.execute(scheduler.execution_request([ExecuteProcessResult], requests))
+    self.assertIsNone(result.error)
+    self.assertEquals([str(i).encode('utf-8') for i in range(4)],
+                      [state.value.stdout for _, state in result.root_products])
+
+  def test_shutdown(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+    request = ExecuteProcessRequest.create_with_empty_snapshot(
+      argv=('/bin/echo', '-n', 'hello'),
+      description='echo',
+    )
+    self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request)
+
+    # Nothing was running, so nothing had to be forced.
+    self.assertEquals(
+      {'cancelled_executions': 0, 'killed_processes': [], 'errors': []},
+      scheduler._scheduler.shutdown(timeout_seconds=1),
+    )
+
+    # Executions which begin after shutdown are cancelled.
+    with self.assertRaisesRegexp(Exception, 'cancelled before it completed'):
+      self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request)
+
+  def test_shutdown_abandons_running_executions(self):
+    scheduler = self.mk_scheduler_in_example_fs(())
+    with temporary_dir() as tmp_dir:
+      pid_file = os.path.join(tmp_dir, 'pid')
+      request = ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=('/bin/sh', '-c', 'echo $$ > {}; exec /bin/sleep 60'.format(pid_file)),
+        description='sleep',
+      )
+      results = []
+      execution = threading.Thread(target=lambda: results.append(
+        scheduler.execute(scheduler.execution_request([ExecuteProcessResult], [request]))))
+      execution.start()
+      wait_for_file(pid_file)
+      with open(pid_file) as f:
+        pid = int(f.read())
+
+      report = scheduler._scheduler.shutdown(timeout_seconds=0.5)
+      execution.join()
+      self.assertEquals(1, report['cancelled_executions'])
+      self.assertIn('because the scheduler is shutting down',
+                    str(results[0].root_products[0][1].exc))
+      # The abandoned execution's process was terminated, rather than left running.
+      assert_process_exits(self, pid)
+
+  def test_stalled_execution_is_cancelled(self):
+    scheduler = self.mk_scheduler(
+      rules=create_fs_rules() + create_process_rules(),
+      project_tree=self.mk_example_fs_tree(),
+      execution_options=DEFAULT_EXECUTION_OPTIONS._replace(
+        stall_timeout_seconds=1,
+        cancel_stalled_executions=True,
+      ))
+    with temporary_dir() as tmp_dir:
+      pid_file = os.path.join(tmp_dir, 'pid')
+      request = ExecuteProcessRequest.create_with_empty_snapshot(
+        argv=('/bin/sh', '-c', 'echo $$ > {}; exec /bin/sleep 60'.format(pid_file)),
+        description='stall',
+      )
+      with self.assertRaises(Exception) as cm:
+        self.execute_expecting_one_result(scheduler, ExecuteProcessResult, request)
+      # The exception explains the stall, including the Nodes which were running.
+      self.assertIn('cancelled before it completed, because it stalled', str(cm.exception))
+      self.assertIn('running for', str(cm.exception))
+
+      # The stalled process was terminated, rather than left running.
+      with open(pid_file) as f:
+        assert_process_exits(self, int(f.read()))
+
+  def test_fake_command_runner(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules() + create_process_rules(),
+                                  project_tree=self.mk_example_fs_tree(),
+                                  fake_command_runner=True)
+
+    def request(argv):
+      return ExecuteProcessRequest.create_with_empty_snapshot(argv=argv, description='fake')
+
+    canned = request(('/bin/does-not-exist', 'canned'))
+    scheduler.fake_process_result(
+      canned,
+      FallibleExecuteProcessResult(b'canned stdout', b'', 0, EMPTY_DIRECTORY_DIGEST),
+    )
+
+    result = self.execute_expecting_one_result(scheduler, ExecuteProcessResult, canned).value
+    self.assertEquals(b'canned stdout', result.stdout)
+    self.assertEquals('fake', result.source)
+    self.assertEquals(1, scheduler.metrics()['process_results.fake'])
+
+    # Requesting the result again is served from memory, and keeps its source.
+    result = self.execute_expecting_one_result(scheduler, ExecuteProcessResult, canned).value
+    self.assertEquals('fake', result.source)
+
+    # A request without a canned result fails without being retried, so it is invoked once.
+    unknown = request(('/bin/does-not-exist', 'unknown'))
+    with self.assertRaises(Exception) as cm:
+      self.execute_raising_throw(scheduler, ExecuteProcessResult, unknown)
+    self.assertIn('No canned result for', str(cm.exception))
+    self.assertNotIn('Transient failure', str(cm.exception))
+
+    self.assertEquals(
+      [(inv.argv, inv.description, inv.action_digest)
+       for inv in scheduler.fake_process_invocations()],
+      [(argv, 'fake', scheduler.execute_process_request_digests(request(argv)).action_digest)
+       for argv in [canned.argv, unknown.argv]],
+    )
+
+  def test_platform(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules() + create_process_rules(),
+                                  project_tree=self.mk_example_fs_tree())
+    request = ExecuteProcessRequest.create_with_empty_snapshot(argv=('true',), description='true')
+    platform = self.execute_expecting_one_result(scheduler, Platform, request).value
+    self.assertEquals(get_normalized_os_name(), platform.os)
+    self.assertIn(platform.libc, ('glibc', 'musl', 'system'))
+
+  def test_fake_command_runner_not_configured(self):
+    scheduler = self.mk_scheduler()
+    with self.assertRaisesRegexp(Exception, 'not created with a fake CommandRunner'):
+      scheduler.fake_process_invocations()
+
   def mk_example_fs_tree(self):
     fs_tree = self.mk_fs_tree(os.path.join(os.path.dirname(__file__), 'examples'))
     test_fs = os.path.join(fs_tree.build_root, 'fs_test')
@@ -405,3 +850,22 @@ class IsolatedProcessTest(SchedulerTestBase, unittest.TestCase):
   def mk_scheduler_in_example_fs(self, rules):
     rules = list(rules) + create_fs_rules() + create_process_rules()
     return self.mk_scheduler(rules=rules, project_tree=self.mk_example_fs_tree())
+
+
+def wait_for_file(path, timeout_seconds=10):
+  deadline = time.time() + timeout_seconds
+  while not os.path.exists(path) or os.path.getsize(path) == 0:
+    if time.time() > deadline:
+      raise AssertionError('Timed out waiting for {}'.format(path))
+    time.sleep(0.05)
+
+
+def assert_process_exits(test, pid, timeout_seconds=10):
+  deadline = time.time() + timeout_seconds
+  while time.time() < deadline:
+    try:
+      os.kill(pid, 0)
+    except OSError:
+      return
+    time.sleep(0.05)
+  test.fail('Process {} was still running after {} seconds.'.format(pid, timeout_seconds))
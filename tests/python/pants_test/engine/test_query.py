@@ -0,0 +1,71 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+import os
+import socket
+import stat
+import unittest
+
+from pants.base.specs import SingleAddress, Specs
+from pants.engine.fs import Snapshot, create_fs_rules
+from pants.engine.query import QueryError, QueryHandler
+from pants.engine.selectors import constraint_for
+from pants.util.contextutil import temporary_dir
+from pants_test.engine.scheduler_test_base import SchedulerTestBase
+
+
+class QueryHandlerTest(unittest.TestCase):
+
+  def test_resolve(self):
+    handler = QueryHandler('/build_root', [Snapshot])
+    roots = handler.resolve('Snapshot', ('a/b:c', 'd:d'))
+    self.assertEquals(constraint_for(Snapshot), roots.product)
+    self.assertEquals((Specs(dependencies=(SingleAddress('a/b', 'c'),)),
+                       Specs(dependencies=(SingleAddress('d', 'd'),))),
+                      roots.subjects)
+
+  def test_resolve_unknown_product(self):
+    handler = QueryHandler('/build_root', [Snapshot])
+    with self.assertRaisesRegexp(QueryError, 'Unrecognized product `Nope`: expected one of'):
+      handler.resolve('Nope', ('a/b:c',))
+
+
+class QueryServerTest(unittest.TestCase, SchedulerTestBase):
+
+  def _connect(self, socket_path):
+    sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    try:
+      sock.connect(socket_path)
+    finally:
+      sock.close()
+
+  def test_start_and_stop(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())._scheduler
+    server = scheduler.new_query_server(QueryHandler('/build_root', [Snapshot]))
+    with temporary_dir() as tmpdir:
+      socket_dir = os.path.join(tmpdir, 'query')
+      socket_path = server.start(socket_dir)
+      self.assertEquals(socket_dir, os.path.dirname(socket_path))
+      self.assertEquals(0o700, stat.S_IMODE(os.stat(socket_dir).st_mode))
+      self.assertEquals(0o600, stat.S_IMODE(os.stat(socket_path).st_mode))
+      self._connect(socket_path)
+
+      with self.assertRaisesRegexp(Exception, 'already been started'):
+        server.start(socket_dir)
+      server.stop()
+      self.assertFalse(os.path.exists(socket_path))
+
+  def test_restarted_after_fork(self):
+    scheduler = self.mk_scheduler(rules=create_fs_rules())._scheduler
+    server = scheduler.new_query_server(QueryHandler('/build_root', [Snapshot]))
+    with temporary_dir() as tmpdir:
+      socket_path = server.start(tmpdir)
+      scheduler.pre_fork()
+      with self.assertRaises(socket.error):
+        self._connect(socket_path)
+      scheduler.post_fork_in_parent()
+      self._connect(socket_path)
+      server.stop()
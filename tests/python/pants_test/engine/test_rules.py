@@ -8,11 +8,11 @@ import unittest
 from textwrap import dedent
 
 from pants.engine.build_files import create_graph_rules
-from pants.engine.fs import create_fs_rules
+from pants.engine.fs import DirectoryDigest, create_fs_rules
 from pants.engine.mapper import AddressMapper
-from pants.engine.rules import RootRule, RuleIndex, SingletonRule, TaskRule
+from pants.engine.rules import IntrinsicPluginRule, RootRule, RuleIndex, SingletonRule, TaskRule
 from pants.engine.selectors import Get, Select
-from pants.util.objects import Exactly
+from pants.util.objects import Exactly, datatype
 from pants_test.engine.examples.parsers import JsonParser
 from pants_test.engine.examples.planners import Goal
 from pants_test.engine.util import TargetTable, assert_equal_with_printing, create_scheduler
@@ -71,6 +71,22 @@ class RuleIndexTest(unittest.TestCase):
       str(cm.exception))
 
 
+class IntrinsicPluginRuleTest(unittest.TestCase):
+  class WithDigest(datatype(['directory_digest'])):
+    pass
+
+  def test_input_type_defaults_to_directory_digest(self):
+    self.assertEquals(DirectoryDigest, IntrinsicPluginRule(A, 'a').input_type)
+
+  def test_input_type_with_directory_digest_field(self):
+    rule = IntrinsicPluginRule(A, 'a', self.WithDigest)
+    self.assertEquals(self.WithDigest, rule.input_type)
+
+  def test_input_type_without_directory_digest_field(self):
+    with self.assertRaises(TypeError):
+      IntrinsicPluginRule(A, 'a', B)
+
+
 class RulesetValidatorTest(unittest.TestCase):
   def test_ruleset_with_missing_product_type(self):
     rules = _suba_root_rules + [TaskRule(A, [Select(B)], noop)]
@@ -190,6 +206,22 @@ class RuleGraphMakerTest(unittest.TestCase):
                          "(A, (Select(SubA),), noop) of SubA" -> {"SubjectIsProduct(SubA)"}
                      }""").strip(), fullgraph)
 
+  def test_full_graph_uses_rule_name(self):
+    rules = _suba_root_rules + [
+      TaskRule(Exactly(A), [Select(SubA)], noop, name='a_from_suba')
+    ]
+    fullgraph = self.create_full_graph(rules)
+
+    self.assert_equal_with_printing(dedent("""
+                     digraph {
+                       // root subject types: SubA
+                       // root entries
+                         "Select(A) for SubA" [color=blue]
+                         "Select(A) for SubA" -> {"(A, (Select(SubA),), a_from_suba) of SubA"}
+                       // internal entries
+                         "(A, (Select(SubA),), a_from_suba) of SubA" -> {"SubjectIsProduct(SubA)"}
+                     }""").strip(), fullgraph)
+
   def test_full_graph_for_planner_example(self):
     symbol_table = TargetTable()
     address_mapper = AddressMapper(JsonParser(symbol_table), '*.BUILD.json')
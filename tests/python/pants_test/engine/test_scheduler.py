@@ -8,14 +8,24 @@ import os
 import unittest
 from textwrap import dedent
 
+from future.utils import text_type
+
 from pants.base.cmd_line_spec_parser import CmdLineSpecParser
+from pants.base.project_tree import Dir, File, Link
 from pants.base.specs import Specs
 from pants.build_graph.address import Address
 from pants.engine.addressable import BuildFileAddresses
+from pants.engine.fs import (ChangedFiles, DigestEquality, DirectoryDigest, FileContent,
+                             FileSizeSummary, FilesContent, LazyBytes, Path, Snapshot,
+                             SourceImports)
+from pants.engine.isolated_process import Environment, FallibleExecuteProcessResult
+from pants.engine.mapper import AddressNames
 from pants.engine.nodes import Return, Throw
 from pants.engine.rules import RootRule, TaskRule
+from pants.engine.scheduler import Scheduler
 from pants.engine.selectors import Select, SelectVariant
 from pants.util.contextutil import temporary_dir
+from pants.util.objects import datatype
 from pants_test.engine.examples.planners import (ApacheThriftJavaConfiguration, Classpath, GenGoal,
                                                  Jar, ThriftSources, setup_json_scheduler)
 from pants_test.engine.util import (assert_equal_with_printing, create_scheduler, init_native,
@@ -87,6 +97,29 @@ class SchedulerTest(unittest.TestCase):
     root, = self.build(build_request)
     self.assert_root(root, self.guava, Classpath(creator='ivy_resolve'))
 
+  def test_cancelled_state(self):
+    # A root which was cancelled (state tag 5) is a Throw of the cancellation's explanation.
+    exc = Exception('The request was cancelled before it completed, because it stalled: ...')
+    state = Scheduler._to_state(5, (exc, (), 'cancellation'))
+    self.assertEquals(Throw(exc, (), 'cancellation'), state)
+
+  def test_trim_memory(self):
+    scheduler = self.scheduler._scheduler
+    build_request = self.request([Classpath], self.guava)
+    self.build(build_request)
+
+    # The scheduler has not been idle for long enough.
+    self.assertIsNone(scheduler.trim_memory(min_idle_seconds=3600))
+
+    report = scheduler.trim_memory(min_idle_seconds=0)
+    self.assertGreater(report['evicted_nodes'], 0)
+    # A scheduler is trimmed at most once per idle period.
+    self.assertIsNone(scheduler.trim_memory(min_idle_seconds=0))
+
+    # Evicted results are recomputed.
+    root, = self.build(build_request)
+    self.assert_root(root, self.guava, Classpath(creator='ivy_resolve'))
+
   @unittest.skip('Skipped to expedite landing #3821; see: #4027.')
   def test_compile_only_3rdparty_internal(self):
     build_request = self.request([Classpath], '3rdparty/jvm:guava')
@@ -237,6 +270,111 @@ class SchedulerTest(unittest.TestCase):
 
     self.assertIn('digraph', graphviz_output)
     self.assertIn(' -> ', graphviz_output)
+    self.assertIn(' ms', graphviz_output)
+
+  def test_scheduler_visualize_min_duration(self):
+    specs = self.parse_specs('3rdparty/jvm::')
+    build_request = self.request([BuildFileAddresses], specs)
+    self.build(build_request)
+
+    with temporary_dir() as td:
+      output_path = os.path.join(td, 'output.dot')
+      # No Node should take anywhere near this long.
+      self.scheduler.visualize_graph_to_file(output_path, min_duration_ms=24 * 60 * 60 * 1000)
+      with open(output_path, 'rb') as fh:
+        graphviz_output = fh.read().strip()
+
+    self.assertIn('digraph', graphviz_output)
+    self.assertNotIn(' -> ', graphviz_output)
+
+  def test_set_module_log_levels(self):
+    self._native.set_module_log_levels({'process_execution::remote': 'debug'})
+    # Removes the previous override.
+    self._native.set_module_log_levels({})
+
+    with self.assertRaises(ValueError):
+      self._native.set_module_log_levels({'fs': 'chatty'})
+
+  def test_dump_graphs(self):
+    self.build(self.request([Classpath], self.guava))
+    with temporary_dir() as td:
+      self._native.dump_graphs(td)
+      dumps = os.listdir(td)
+      self.assertTrue(dumps)
+      with open(os.path.join(td, dumps[0]), 'r') as f:
+        self.assertIn('Recently completed nodes', f.read())
+
+  def test_cpu_profile(self):
+    with temporary_dir() as td:
+      path = os.path.join(td, 'profile.txt')
+      with self._native.cpu_profiled(path, frequency_hz=1000):
+        # Burn some CPU in the engine: the profile samples native frames only.
+        for _ in range(10):
+          self.scheduler.invalidate_all_files()
+          self.build(self.request([Classpath], self.guava))
+      self.assertTrue(os.path.isfile(path))
+      with open(path, 'r') as f:
+        for line in f:
+          function, count = line.rsplit(' ', 1)
+          self.assertTrue(function)
+          self.assertGreater(int(count), 0)
+
+    with self.assertRaises(Exception):
+      self._native.stop_cpu_profile(path)
+    with self.assertRaises(Exception):
+      self._native.start_cpu_profile(frequency_hz=0)
+
+  def test_init_log_file(self):
+    with temporary_dir() as td:
+      path = os.path.join(td, 'logs', 'native-engine.log')
+      self._native.init_log_file(path, 1024, 60, 2, 'debug')
+      self.assertTrue(os.path.isfile(path))
+
+      with self.assertRaises(ValueError):
+        self._native.init_log_file(path, 1024, 60, 2, 'chatty')
+
+  def _constructors(self, **overrides):
+    constructors = {
+      'construct_directory_digest': DirectoryDigest,
+      'construct_snapshot': Snapshot,
+      'construct_file_content': FileContent,
+      'construct_files_content': FilesContent,
+      'construct_path_stat': Path,
+      'construct_dir': Dir,
+      'construct_file': File,
+      'construct_link': Link,
+      'construct_process_result': FallibleExecuteProcessResult,
+      'construct_lazy_bytes': LazyBytes,
+      'construct_changed_files': ChangedFiles,
+      'construct_address_names': AddressNames,
+      'construct_source_imports': SourceImports,
+      'construct_file_size_summary': FileSizeSummary,
+      'construct_environment': Environment,
+      'construct_digest_equality': DigestEquality,
+    }
+    constructors.update(overrides)
+    return constructors
+
+  def test_constructors_match_engine(self):
+    self._native._validate_constructors(self._constructors())
+
+  def test_constructor_field_drift(self):
+    class ReorderedFileContent(datatype(['content', 'path'])):
+      pass
+
+    with self.assertRaises(TypeError) as cm:
+      self._native._validate_constructors(
+        self._constructors(construct_file_content=ReorderedFileContent))
+    self.assertIn('construct_file_content (ReorderedFileContent)', str(cm.exception))
+
+  def test_constructor_type_drift(self):
+    class TextDigestEquality(datatype([('equal', text_type)])):
+      pass
+
+    with self.assertRaises(TypeError) as cm:
+      self._native._validate_constructors(
+        self._constructors(construct_digest_equality=TextDigestEquality))
+    self.assertIn("constrains field 'equal'", str(cm.exception))
 
 
 class A(object):
@@ -288,3 +426,66 @@ class SchedulerTraceTest(unittest.TestCase):
                                raise Exception('An exception for {}'.format(type(x).__name__))
                            Exception: An exception for B''').lstrip() + '\n\n', # Traces include two empty lines after.
                                trace)
+
+
+  def test_trace_options(self):
+    rules = [
+      RootRule(B),
+      TaskRule(A, [Select(B)], nested_raise)
+    ]
+
+    scheduler = create_scheduler(rules)
+    request = scheduler._native.new_execution_request()
+    scheduler.add_root_selection(request, B(), A)
+    scheduler.add_root_selection(request, B(), A)
+    session = scheduler.new_session()
+    scheduler._run_and_return_roots(session._session, request)
+
+    def computing(**kwargs):
+      return [line.strip() for line in scheduler.graph_trace(request, **kwargs)
+              if line.strip().startswith(('Computing', '('))]
+
+    self.assertEquals(4, len(computing()))
+    self.assertEquals(2, len(computing(first_failure_only=True)))
+
+    # The failing Task is rendered regardless of the filters.
+    only_tasks = computing(node_types=['Task'], first_failure_only=True)
+    self.assertEquals('(1 Nodes omitted)', only_tasks[0])
+    self.assertTrue(only_tasks[1].startswith('Computing Task(nested_raise'))
+    self.assertEquals(only_tasks, computing(max_depth=1, first_failure_only=True))
+
+
+class Twin(datatype(['name'])):
+  pass
+
+
+class Sibling(datatype(['name'])):
+  pass
+
+
+class Greeting(datatype(['text'])):
+  pass
+
+
+def greet_twin(twin):
+  return Greeting('twin {}'.format(twin.name))
+
+
+def greet_sibling(sibling):
+  return Greeting('sibling {}'.format(sibling.name))
+
+
+class RootSelectionTest(unittest.TestCase):
+
+  def test_batched_roots_of_mixed_subject_types_preserve_order(self):
+    rules = [
+      RootRule(Twin),
+      RootRule(Sibling),
+      TaskRule(Greeting, [Select(Twin)], greet_twin),
+      TaskRule(Greeting, [Select(Sibling)], greet_sibling),
+    ]
+    session = create_scheduler(rules).new_session()
+    subjects = [Twin('a'), Sibling('b'), Twin('c'), Sibling('d')]
+    self.assertEquals(
+      [Greeting('twin a'), Greeting('sibling b'), Greeting('twin c'), Greeting('sibling d')],
+      session.product_request(Greeting, subjects))
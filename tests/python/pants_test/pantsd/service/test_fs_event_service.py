@@ -47,6 +47,11 @@ class TestFSEventService(TestBase):
     self.assertIsInstance(self.service._handlers['test'], Watchman.EventHandler)
     self.assertIsInstance(self.service._handlers['test2'], Watchman.EventHandler)
 
+  def test_register_all_files_handler_since(self):
+    self.service.register_all_files_handler(lambda x: True, name='test3', since='c:1:2')
+    self.assertEquals('c:1:2', self.service._handlers['test3'].metadata['since'])
+    self.assertNotIn('since', self.service._handlers['test'].metadata)
+
   def test_register_handler_duplicate(self):
     with self.assertRaises(AssertionError):
       self.service.register_handler('test', 'test', lambda x: True)
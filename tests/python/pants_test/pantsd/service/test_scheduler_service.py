@@ -0,0 +1,86 @@
+# coding=utf-8
+# Copyright 2018 Pants project contributors (see CONTRIBUTORS.md).
+# Licensed under the Apache License, Version 2.0 (see LICENSE).
+
+from __future__ import absolute_import, division, print_function, unicode_literals
+
+import threading
+
+import mock
+
+from pants.pantsd.service.fs_event_service import FSEventService
+from pants.pantsd.service.scheduler_service import SchedulerService
+from pants_test.test_base import TestBase
+
+
+class TestSchedulerService(TestBase):
+
+  def setUp(self):
+    super(TestSchedulerService, self).setUp()
+    self.mock_fs_event_service = mock.create_autospec(FSEventService, spec_set=True)
+    self.mock_fs_event_service.PANTS_PID_SUBSCRIPTION_NAME = 'pantsd_pid'
+    self.mock_graph_helper = mock.Mock()
+    self.mock_scheduler = self.mock_graph_helper.scheduler
+    self.mock_scheduler.snapshot_cache_generation.return_value = None
+    self.service = SchedulerService(self.mock_fs_event_service,
+                                    self.mock_graph_helper,
+                                    '/build_root',
+                                    [],
+                                    None,
+                                    invalidation_batch_window=0)
+    self.service.setup(threading.RLock(), threading.RLock())
+
+  def _event(self, files, clock, subscription='all_files', is_fresh_instance=False):
+    return dict(subscription=subscription,
+                files=files,
+                clock=clock,
+                is_fresh_instance=is_fresh_instance)
+
+  def _enqueue(self, *events):
+    for event in events:
+      self.service._enqueue_fs_event(event)
+
+  def test_initial_event_is_ignored(self):
+    self._enqueue(self._event([b'a/BUILD', b'b/BUILD'], 'c:1', is_fresh_instance=True))
+    self.service._process_event_queue()
+
+    self.mock_scheduler.invalidate_files.assert_not_called()
+    self.mock_scheduler.set_snapshot_cache_generation.assert_called_once_with('c:1')
+
+  def test_queued_events_are_invalidated_together(self):
+    self._enqueue(self._event([], 'c:1', is_fresh_instance=True))
+    self.service._process_event_queue()
+
+    self._enqueue(self._event([b'a/BUILD', b'b/BUILD'], 'c:2'),
+                  self._event([b'b/BUILD'], 'c:3'),
+                  self._event([b'c/BUILD', b'a/BUILD'], 'c:4'))
+    self.service._process_event_queue()
+
+    self.mock_scheduler.invalidate_files.assert_called_once_with(
+      ['a/BUILD', 'b/BUILD', 'c/BUILD'])
+    # The generation is updated (after invalidating) to the clock of the last event.
+    self.assertEquals('c:4', self.mock_scheduler.set_snapshot_cache_generation.call_args[0][0])
+
+  def test_events_within_the_window_are_invalidated_together(self):
+    self.service._invalidation_batch_window = 1
+    self._enqueue(self._event([], 'c:1', is_fresh_instance=True))
+    self.service._process_event_queue()
+
+    self._enqueue(self._event([b'a/BUILD'], 'c:2'))
+    timer = threading.Timer(0.1, self._enqueue, args=[self._event([b'b/BUILD'], 'c:3')])
+    timer.start()
+    self.service._process_event_queue()
+    timer.join()
+
+    self.mock_scheduler.invalidate_files.assert_called_once_with(['a/BUILD', 'b/BUILD'])
+
+  def test_invalid_events_do_not_prevent_the_batch(self):
+    self._enqueue(self._event([], 'c:1', is_fresh_instance=True))
+    self.service._process_event_queue()
+
+    # An event without `is_fresh_instance`.
+    invalid_event = dict(subscription='all_files', files=[b'x/BUILD'])
+    self._enqueue(invalid_event, self._event([b'a/BUILD'], 'c:2'))
+    self.service._process_event_queue()
+
+    self.mock_scheduler.invalidate_files.assert_called_once_with(['a/BUILD'])
@@ -333,7 +333,9 @@ class TestBase(unittest.TestCase):
       self._scheduler.invalidate_all_files()
       # Eagerly free file handles, threads, connections, etc, held by the scheduler. In theory,
       # dropping the scheduler is equivalent, but it's easy for references to the scheduler to leak.
+      # No fork follows, so immediately release the locks that `pre_fork` holds.
       self._scheduler.pre_fork()
+      self._scheduler.post_fork_in_parent()
 
   @property
   def build_root(self):
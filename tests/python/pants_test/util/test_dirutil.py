@@ -6,6 +6,7 @@ from __future__ import absolute_import, division, print_function, unicode_litera
 
 import errno
 import os
+import stat
 import time
 import unittest
 from contextlib import contextmanager
@@ -373,6 +374,24 @@ class DirutilTest(unittest.TestCase):
       rm_rf(dir_name)
       self.assertFalse(os.path.exists(dir_name))
 
+  def test_rm_rf_read_only_dir(self):
+    with temporary_dir() as td:
+      read_only = os.path.join(td, 'read_only')
+      touch(os.path.join(read_only, 'nested', 'file'))
+      for path in (os.path.join(read_only, 'nested', 'file'), os.path.join(read_only, 'nested'),
+                   read_only):
+        os.chmod(path, stat.S_IRUSR | stat.S_IXUSR)
+      rm_rf(read_only)
+      self.assertFalse(os.path.exists(read_only))
+
+  def test_safe_rmtree_read_only_dir(self):
+    with temporary_dir() as td:
+      read_only = os.path.join(td, 'read_only')
+      touch(os.path.join(read_only, 'file'))
+      os.chmod(read_only, stat.S_IRUSR | stat.S_IXUSR)
+      safe_rmtree(read_only)
+      self.assertFalse(os.path.exists(read_only))
+
   def test_rm_rf_nonexistent(self, file_name='./non_existent_file'):
     with temporary_dir() as td, pushd(td):
       rm_rf(file_name)